@@ -0,0 +1,64 @@
+#!/bin/sh
+# Boot the OS under QEMU with a seeded raw disk attached to one of the storage
+# controllers, then show the start of the disk so the sectors the OS wrote can be
+# checked.
+#
+# Usage: scripts/qemu-disk.sh <boot.iso> [ide|ahci|virtio|virtio-legacy|virtio-modern]
+#
+# The image is booted from the CD drive (the secondary IDE master); the disk is the
+# primary IDE master, the first AHCI port or a virtio-blk device. Set DISK to keep
+# the disk image somewhere else, and TIMEOUT to change how many seconds the OS gets
+# before QEMU is stopped.
+
+set -eu
+
+if [ $# -lt 1 ] || [ $# -gt 2 ]; then
+    echo "usage: $0 <boot.iso> [ide|ahci|virtio|virtio-legacy|virtio-modern]" >&2
+    exit 2
+fi
+image=$1
+controller=${2:-ide}
+disk=${DISK:-disk.img}
+timeout=${TIMEOUT:-60}
+
+for tool in qemu-system-x86_64 qemu-img timeout xxd; do
+    if ! command -v "$tool" >/dev/null 2>&1; then
+        echo "$tool is not installed" >&2
+        exit 1
+    fi
+done
+if [ ! -f "$image" ]; then
+    echo "$image does not exist" >&2
+    exit 1
+fi
+
+unattached="-drive file=$disk,format=raw,if=none,id=disk0"
+case $controller in
+    ide) drive="-drive file=$disk,format=raw,if=ide,index=0" ;;
+    ahci) drive="$unattached -device ahci,id=ahci -device ide-hd,drive=disk0,bus=ahci.0" ;;
+    virtio) drive="-drive file=$disk,format=raw,if=virtio" ;;
+    virtio-legacy) drive="$unattached -device virtio-blk-pci,drive=disk0,disable-modern=on" ;;
+    virtio-modern) drive="$unattached -device virtio-blk-pci,drive=disk0,disable-legacy=on" ;;
+    *)
+        echo "unknown controller $controller" >&2
+        exit 2
+        ;;
+esac
+
+# A fresh disk whose first sector starts with a marker, so reads can be told apart
+# from an empty disk
+qemu-img create -q -f raw "$disk" 64M
+printf 'ATA TEST' | dd of="$disk" conv=notrunc status=none
+
+# The OS gets $timeout seconds; running until then is not a failure
+status=0
+# shellcheck disable=SC2086
+timeout "$timeout" qemu-system-x86_64 -m 256M -no-reboot -display none -serial stdio \
+    -cdrom "$image" -boot d $drive || status=$?
+if [ "$status" -ne 0 ] && [ "$status" -ne 124 ]; then
+    echo "qemu-system-x86_64 failed with status $status" >&2
+    exit "$status"
+fi
+
+echo "First sectors of $disk:"
+xxd -l 1024 "$disk"
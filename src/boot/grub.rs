@@ -4,3 +4,36 @@ pub fn init(boot_info: &BootInfo) {
     // TODO: Grub-specific initialization code
     println!("Grub initialized with boot information: {:?}", boot_info);
 }
+
+// Multiboot2 boot information starts with its total size and a reserved word, followed
+// by tags that each start with their type and size, on 8-byte boundaries. The last tag
+// has type 0.
+const MULTIBOOT2_TAG_END: u32 = 0;
+const MULTIBOOT2_TAG_ALIGN: usize = 8;
+
+// The boot information GRUB left at `address`, as passed in EBX
+//
+// # Safety
+// `address` must point to mapped Multiboot2 boot information
+pub unsafe fn multiboot2_info<'a>(address: usize) -> &'a [u8] {
+    let size = core::ptr::read_unaligned(address as *const u32) as usize;
+    core::slice::from_raw_parts(address as *const u8, size)
+}
+
+// Split the boot information into its tags, each including its type and size
+pub fn multiboot2_tags(info: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(info.get(offset..offset + 4)?.try_into().ok()?))
+    };
+    let total = u32_at(0).map_or(0, |size| (size as usize).min(info.len()));
+    let mut offset = 8;
+    core::iter::from_fn(move || {
+        let (kind, size) = (u32_at(offset)?, u32_at(offset + 4)? as usize);
+        if kind == MULTIBOOT2_TAG_END || size < 8 || offset + size > total {
+            return None;
+        }
+        let tag = &info[offset..offset + size];
+        offset += size.next_multiple_of(MULTIBOOT2_TAG_ALIGN);
+        Some(tag)
+    })
+}
@@ -1,3 +1,4 @@
+use crate::fs::vfs::{self, FileSystemError};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -6,9 +7,9 @@ pub struct Config {
     pub timezone: String,
     pub language: String,
     pub log_level: LogLevel,
+    pub keyboard_layout: String,
     // ... other configuration options ...
     // TODO: Add configuration options for:
-    // - Keyboard layout
     // - Timezone
     // - Language
     // - Log level
@@ -31,9 +32,9 @@ impl Default for Config {
             timezone: "UTC".to_string(),
             language: "en_US".to_string(),
             log_level: LogLevel::Info,
+            keyboard_layout: "us".to_string(),
             // ... set default values for other configuration options ...
             // TODO: Set default values for:
-            // - Keyboard layout
             // - Timezone
             // - Language
             // - Log level
@@ -47,9 +48,9 @@ impl Config {
         // Load configuration from file or other source
         // For example, using serde to deserialize from a JSON file
         let config_file_path = "/etc/my_os/config.json"; // TODO: Use a more appropriate path
-        // TODO: create a folder tree
-        let config_file = std::fs::File::open(config_file_path)?;
-        let config: Config = serde_json::from_reader(config_file)?;
+        // The file comes from the initramfs until a disk is mounted over it
+        let contents = vfs::vfs().read_file(config_file_path)?;
+        let config: Config = serde_json::from_slice(&contents)?;
 
         Ok(config)
     }
@@ -60,6 +61,7 @@ impl Config {
 #[derive(Debug)]
 pub enum ConfigError {
     Io(std::io::Error),
+    FileSystem(FileSystemError),
     Serde(serde_json::Error),
 }
 
@@ -69,6 +71,12 @@ impl From<std::io::Error> for ConfigError {
     }
 }
 
+impl From<FileSystemError> for ConfigError {
+    fn from(error: FileSystemError) -> Self {
+        ConfigError::FileSystem(error)
+    }
+}
+
 impl From<serde_json::Error> for ConfigError {
     fn from(error: serde_json::Error) -> Self {
         ConfigError::Serde(error)
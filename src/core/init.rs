@@ -1,10 +1,145 @@
 //! The initialization code for the operating system
 
+use crate::boot::grub;
 use crate::core::config::Config;
 use crate::core::errors::{OsError, OsResult};
+use crate::drivers::device::{self, DeviceClass, PLATFORM_BUS};
+use crate::drivers::gpu::FramebufferInfo;
+use crate::drivers::pci::{self, PortIoAccess, PCI_BUS};
+use crate::drivers::{ahci, console, gpu, keyboard, mouse, network, storage, virtio_blk, virtio_net};
+use crate::fs::initramfs::{self, BootModule};
+use crate::fs::procfs::ProcFs;
+use crate::fs::vfs::{self, FileSystemError, MountOptions};
+use crate::kernel::interrupts;
+use crate::net::{ip, loopback};
+use std::net::Ipv4Addr;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// What the boot loader handed over to the kernel
+pub enum BootInfo {
+    /// The address of the Multiboot2 boot information
+    Multiboot2(usize),
+    /// The GOP mode set when boot services were exited, if the firmware has one
+    Uefi(Option<GopMode>),
+}
+
+/// A graphics output protocol mode, as read from `EFI_GRAPHICS_OUTPUT_PROTOCOL_MODE`
+pub struct GopMode {
+    pub framebuffer_base: usize,
+    pub horizontal_resolution: usize,
+    pub vertical_resolution: usize,
+    pub pixels_per_scan_line: usize,
+    pub pixel_format: u32,
+    /// The red, green and blue masks of the bit mask format
+    pub pixel_masks: [u32; 3],
+}
 
 /// Initialize the operating system
-pub fn init(config: Config) -> OsResult<()> {
+///
+/// Always ends in an error for now: everything up to running `/init` is set up, but
+/// there is no program loader to run it with.
+///
+/// # Safety
+/// `boot` must describe what the boot loader actually passed, with the memory it
+/// points to still mapped and unused
+pub unsafe fn init(config: Config, boot: BootInfo) -> OsResult<()> {
     // TODO: Implement operating system initialization logic here
-    Ok(())
-}
\ No newline at end of file
+
+    // The framebuffer the boot loader set up shows the console until a display
+    // driver takes over the adapter
+    if let Some(info) = boot_framebuffer(&boot) {
+        gpu::add_boot_framebuffer(info);
+    }
+
+    // Enumerate the buses and bind drivers to the devices found
+    pci::init(Box::new(PortIoAccess::new())).map_err(|_| OsError::new("PCI initialization failed"))?;
+    PLATFORM_BUS.add_device("i8042", 0x60, 1);
+    PLATFORM_BUS.add_device("i8042-aux", 0x60, 12);
+    device::register_bus(&PCI_BUS);
+    device::register_bus(&PLATFORM_BUS);
+
+    // Drivers for specific devices come before the generic class drivers, which
+    // would otherwise bind to the same devices
+    device::register_driver(&virtio_net::VIRTIO_NET_PCI_DRIVER);
+    device::register_driver(&virtio_blk::VIRTIO_BLK_PCI_DRIVER);
+    device::register_driver(&network::NETWORK_PCI_DRIVER);
+    device::register_driver(&storage::STORAGE_PCI_DRIVER);
+    device::register_driver(&ahci::AHCI_PCI_DRIVER);
+    device::register_driver(&gpu::GPU_PCI_DRIVER);
+    device::register_driver(&keyboard::KEYBOARD_DRIVER);
+    device::register_driver(&mouse::MOUSE_DRIVER);
+    device::probe_all();
+    interrupts::register_interrupt_handlers();
+
+    // Show the kernel log on the display; without one it is kept in memory only
+    let _ = console::init();
+
+    // The root filesystem is the initramfs the boot loader passed, or an empty tmpfs
+    if let Some(module) = boot_module(&boot) {
+        initramfs::add_boot_module(module);
+    }
+    initramfs::init(vfs::vfs()).map_err(|_| OsError::new("Initramfs unpacking failed"))?;
+
+    // Kernel state is shown in /proc, which the initramfs may already have a directory for
+    match vfs::vfs().create_directory("/proc", 0o555) {
+        Ok(()) | Err(FileSystemError::AlreadyExists) => {}
+        Err(_) => return Err(OsError::new("Creating /proc failed")),
+    }
+    vfs::vfs()
+        .mount(ProcFs::new(vfs::vfs()), "proc", "/proc", MountOptions { read_only: true })
+        .map_err(|_| OsError::new("Mounting procfs failed"))?;
+
+    // Network drivers register their interfaces as they probe; loopback is always there
+    loopback::init().map_err(|_| OsError::new("Loopback interface initialization failed"))?;
+    ip::configure("lo", Ipv4Addr::LOCALHOST, 8).map_err(|_| OsError::new("Loopback address configuration failed"))?;
+
+    // Apply the configured keyboard layout to every keyboard found, with the keyboard
+    // interrupt held off while its driver is locked
+    if let Some(layout) = keyboard::layout_by_name(&config.keyboard_layout) {
+        for (_, input) in device::find_by_class(DeviceClass::Input) {
+            if let Some(keyboard) = input.as_any().downcast_ref::<keyboard::KeyboardDevice>() {
+                without_interrupts(|| keyboard.keyboard.lock().set_layout(layout));
+            }
+        }
+    }
+
+    // Booting ends by running /init as process 1. The kernel has no program loader or
+    // user mode to run it with yet, so boot stops here with an error instead of going
+    // on without it.
+    let path = initramfs::find_init(vfs::vfs()).map_err(|_| {
+        console::write_bytes(b"init: no executable /init in the initramfs\n");
+        OsError::new("No executable /init in the initramfs")
+    })?;
+    console::write_bytes(format!("init: cannot run {}: no program loader\n", path).as_bytes());
+    Err(OsError::new("Running /init needs a program loader, which the kernel does not have yet"))
+}
+
+// The framebuffer described by the boot information, if it is one the console can use
+unsafe fn boot_framebuffer(boot: &BootInfo) -> Option<FramebufferInfo> {
+    match boot {
+        BootInfo::Multiboot2(address) => {
+            grub::multiboot2_tags(grub::multiboot2_info(*address)).find_map(FramebufferInfo::from_multiboot2)
+        }
+        BootInfo::Uefi(mode) => {
+            let mode = mode.as_ref()?;
+            FramebufferInfo::from_gop(
+                mode.framebuffer_base,
+                mode.horizontal_resolution,
+                mode.vertical_resolution,
+                mode.pixels_per_scan_line,
+                mode.pixel_format,
+                mode.pixel_masks,
+            )
+        }
+    }
+}
+
+// The initramfs loaded next to the kernel; only Multiboot2 loaders pass one
+unsafe fn boot_module(boot: &BootInfo) -> Option<BootModule> {
+    match boot {
+        BootInfo::Multiboot2(address) => {
+            grub::multiboot2_tags(grub::multiboot2_info(*address)).find_map(BootModule::from_multiboot2)
+        }
+        BootInfo::Uefi(_) => None,
+    }
+}
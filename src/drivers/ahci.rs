@@ -0,0 +1,766 @@
+// AHCI SATA driver for x86_64 architecture
+// Commands are transferred by DMA through per-port command lists, queued with NCQ
+// when both the controller and the drive support it, and completed from the
+// controller's interrupt handler
+
+use crate::drivers::device::{self, Device, DeviceClass, DeviceError};
+use crate::drivers::dma::{DmaBuffer, Mmio};
+use crate::drivers::pci::{PciDevice, PciDeviceId, PciDriver};
+use crate::drivers::storage::{AtaError, CommandError, IdentifyData, SECTOR_SIZE};
+use crate::storage::block::{self, BlockDevice};
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, RwLock};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// Interrupt vector the controller signals completions on
+pub const AHCI_INTERRUPT_VECTOR: u8 = 45;
+
+// Generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+const HBA_CAP2: usize = 0x24;
+const HBA_BOHC: usize = 0x28;
+const HBA_SIZE: usize = 0x1100;
+
+const CAP_NCS_SHIFT: u32 = 8;
+const CAP_SNCQ: u32 = 1 << 30;
+const CAP_S64A: u32 = 1 << 31;
+const CAP2_BOH: u32 = 1 << 0;
+const BOHC_BOS: u32 = 1 << 0;
+const BOHC_OOS: u32 = 1 << 1;
+const GHC_HR: u32 = 1 << 0;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers, as offsets from the port's register block
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0C;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SCTL: usize = 0x2C;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+
+const PX_CMD_ST: u32 = 1 << 0;
+const PX_CMD_SUD: u32 = 1 << 1;
+const PX_CMD_POD: u32 = 1 << 2;
+const PX_CMD_FRE: u32 = 1 << 4;
+const PX_CMD_FR: u32 = 1 << 14;
+const PX_CMD_CR: u32 = 1 << 15;
+
+// Port interrupt bits: register, PIO setup, DMA setup and set device bits FIS
+// received, plus the error conditions that stop the command engine
+const PX_IS_DHRS: u32 = 1 << 0;
+const PX_IS_PSS: u32 = 1 << 1;
+const PX_IS_DSS: u32 = 1 << 2;
+const PX_IS_SDBS: u32 = 1 << 3;
+const PX_IS_IFS: u32 = 1 << 27;
+const PX_IS_HBDS: u32 = 1 << 28;
+const PX_IS_HBFS: u32 = 1 << 29;
+const PX_IS_TFES: u32 = 1 << 30;
+const PX_IS_ERRORS: u32 = PX_IS_IFS | PX_IS_HBDS | PX_IS_HBFS | PX_IS_TFES;
+const PX_IE_DEFAULT: u32 = PX_IS_DHRS | PX_IS_PSS | PX_IS_DSS | PX_IS_SDBS | PX_IS_ERRORS;
+
+const SSTS_DET_MASK: u32 = 0x0F;
+const SSTS_DET_PRESENT: u32 = 3;
+const SCTL_DET_INIT: u32 = 1;
+const SIG_ATA: u32 = 0x0000_0101;
+
+// Task file data register bits
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_DF: u32 = 0x20;
+const TFD_BSY: u32 = 0x80;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+// Device register value selecting LBA addressing
+const DEVICE_LBA: u8 = 0x40;
+
+// Commands
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+const CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// Per-port memory: a list of 32 command headers, the received FIS area, and one
+// command table per slot holding the command FIS and the PRDT
+const MAX_SLOTS: usize = 32;
+const COMMAND_HEADER_SIZE: usize = 32;
+const FIS_RECEIVE_SIZE: usize = 256;
+const COMMAND_TABLE_PRDT: usize = 0x80;
+const PRDT_ENTRY_SIZE: usize = 16;
+const PRDT_ENTRIES: usize = 8;
+const COMMAND_TABLE_SIZE: usize = COMMAND_TABLE_PRDT + PRDT_ENTRIES * PRDT_ENTRY_SIZE;
+const PRD_MAX_BYTES: usize = 4 * 1024 * 1024;
+const MAX_SECTORS_PER_COMMAND: usize = PRDT_ENTRIES * PRD_MAX_BYTES / SECTOR_SIZE;
+
+// Number of polls before a command or register change is considered hung
+const AHCI_TIMEOUT: usize = 10_000_000;
+
+// Polls of PxSSTS after the HBA reset before a port is taken to have nothing attached
+const LINK_TIMEOUT: usize = AHCI_TIMEOUT / 10;
+
+// Bind to AHCI controllers
+static AHCI_PCI_IDS: [PciDeviceId; 1] = [PciDeviceId::class_prog_if(0x01, 0x06, 0x01)];
+
+pub static AHCI_PCI_DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    id_table: &AHCI_PCI_IDS,
+    probe,
+};
+
+fn wait_for(regs: &Mmio, offset: usize, mask: u32, value: u32) -> Result<(), AtaError> {
+    for _ in 0..AHCI_TIMEOUT {
+        if regs.read32(offset) & mask == value {
+            return Ok(());
+        }
+    }
+    Err(AtaError::Timeout)
+}
+
+// Wait for a port's link to come back up after the HBA reset; DET reads 3 once a
+// device is present and communication with it is established
+fn link_up(regs: &Mmio) -> bool {
+    (0..LINK_TIMEOUT).any(|_| regs.read32(PX_SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT)
+}
+
+// A command to issue on a port
+struct Command<'a> {
+    command: u8,
+    lba: u64,
+    sectors: usize,
+    write: bool,
+    // Issue as a native queued command, tagged with its slot
+    queued: bool,
+    buffer: Option<&'a DmaBuffer>,
+}
+
+impl<'a> Command<'a> {
+    // Build the host-to-device register FIS
+    fn fis(&self, slot: usize) -> [u8; 20] {
+        // Queued commands carry the sector count in the features field and the tag in the count field
+        let (features, count, device) = if self.queued {
+            (self.sectors as u16, (slot as u16) << 3, DEVICE_LBA)
+        } else if self.command == CMD_IDENTIFY {
+            (0, 0, 0)
+        } else {
+            (0, self.sectors as u16, DEVICE_LBA)
+        };
+        let lba = self.lba.to_le_bytes();
+
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_REG_H2D;
+        // The C bit marks the FIS as a command rather than a device control update
+        fis[1] = 0x80;
+        fis[2] = self.command;
+        fis[3] = features as u8;
+        fis[4..7].copy_from_slice(&lba[0..3]);
+        fis[7] = device;
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[11] = (features >> 8) as u8;
+        fis[12] = count as u8;
+        fis[13] = (count >> 8) as u8;
+        fis
+    }
+}
+
+// Which command slots are in use; protected by a lock so that completions seen by
+// the interrupt handler and new submissions do not race. Threads only hold it with
+// interrupts disabled, so the handler never finds it held by the code it interrupted.
+struct SlotState {
+    // Number of slots commands may use
+    limit: usize,
+    allocated: u32,
+    issued: u32,
+    completed: u32,
+    failed: u32,
+    error: Option<AtaError>,
+}
+
+// The command list, received FIS area and command tables of one port
+struct CommandEngine {
+    regs: Mmio,
+    command_list: DmaBuffer,
+    fis_area: DmaBuffer,
+    tables: Vec<DmaBuffer>,
+    slots: Mutex<SlotState>,
+    // Set once completions are signalled by interrupts; until then waiters poll
+    interrupts: AtomicBool,
+    // Set when the interrupt handler found the slots locked by another core; the
+    // waiters then check for completions themselves
+    missed: AtomicBool,
+    // Set when an error stopped the port; the next submission restarts it
+    restart: AtomicBool,
+}
+
+// Fail every command in flight with `error`
+fn fail_issued(slots: &mut SlotState, error: AtaError) {
+    slots.failed |= slots.issued;
+    slots.completed |= slots.issued;
+    slots.issued = 0;
+    slots.error = Some(error);
+}
+
+impl CommandEngine {
+    fn new(regs: Mmio, slot_count: usize, addr64: bool) -> Result<CommandEngine, DeviceError> {
+        let command_list = DmaBuffer::new(MAX_SLOTS * COMMAND_HEADER_SIZE, 1024)?;
+        let fis_area = DmaBuffer::new(FIS_RECEIVE_SIZE, 256)?;
+        let tables = (0..slot_count)
+            .map(|_| DmaBuffer::new(COMMAND_TABLE_SIZE, 128))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Controllers without 64-bit addressing ignore the upper address registers
+        let high = command_list.phys_addr() >> 32 != 0
+            || fis_area.phys_addr() >> 32 != 0
+            || tables.iter().any(|table| table.phys_addr() >> 32 != 0);
+        if high && !addr64 {
+            return Err(DeviceError::NotSupported);
+        }
+
+        Ok(CommandEngine {
+            regs,
+            command_list,
+            fis_area,
+            tables,
+            slots: Mutex::new(SlotState {
+                limit: slot_count,
+                allocated: 0,
+                issued: 0,
+                completed: 0,
+                failed: 0,
+                error: None,
+            }),
+            interrupts: AtomicBool::new(false),
+            missed: AtomicBool::new(false),
+            restart: AtomicBool::new(false),
+        })
+    }
+
+    // Lock the slot state from a thread
+    fn with_slots<R>(&self, f: impl FnOnce(&mut SlotState) -> R) -> R {
+        without_interrupts(|| f(&mut self.slots.lock()))
+    }
+
+    // Stop the command engine; this also clears PxCI and PxSACT
+    fn stop(&self) -> Result<(), AtaError> {
+        let cmd = self.regs.read32(PX_CMD);
+        self.regs.write32(PX_CMD, cmd & !PX_CMD_ST);
+        wait_for(&self.regs, PX_CMD, PX_CMD_CR, 0)?;
+        let cmd = self.regs.read32(PX_CMD);
+        self.regs.write32(PX_CMD, cmd & !PX_CMD_FRE);
+        wait_for(&self.regs, PX_CMD, PX_CMD_FR, 0)
+    }
+
+    // Point the port at our memory and start processing commands
+    fn start(&self) -> Result<(), AtaError> {
+        self.stop()?;
+        let command_list = self.command_list.phys_addr();
+        let fis_area = self.fis_area.phys_addr();
+        self.regs.write32(PX_CLB, command_list as u32);
+        self.regs.write32(PX_CLBU, (command_list >> 32) as u32);
+        self.regs.write32(PX_FB, fis_area as u32);
+        self.regs.write32(PX_FBU, (fis_area >> 32) as u32);
+
+        let cmd = self.regs.read32(PX_CMD);
+        self.regs.write32(PX_CMD, cmd | PX_CMD_SUD | PX_CMD_POD | PX_CMD_FRE);
+        self.regs.write32(PX_SERR, !0);
+        self.regs.write32(PX_IS, !0);
+        self.regs.write32(PX_IE, PX_IE_DEFAULT);
+
+        wait_for(&self.regs, PX_TFD, TFD_BSY | TFD_DRQ, 0)?;
+        let cmd = self.regs.read32(PX_CMD);
+        self.regs.write32(PX_CMD, cmd | PX_CMD_ST);
+        Ok(())
+    }
+
+    // Reset the link with a COMRESET, for a device that stays busy after an error
+    fn reset_link(&self) -> Result<(), AtaError> {
+        self.regs.write32(PX_SCTL, SCTL_DET_INIT);
+        // DET must stay set for at least 1ms
+        for _ in 0..AHCI_TIMEOUT / 10 {
+            self.regs.read32(PX_SSTS);
+        }
+        self.regs.write32(PX_SCTL, 0);
+        wait_for(&self.regs, PX_SSTS, SSTS_DET_MASK, SSTS_DET_PRESENT)?;
+        self.regs.write32(PX_SERR, !0);
+        Ok(())
+    }
+
+    // Restart the port, resetting the link if a restart alone does not bring it back
+    fn restart_port(&self) -> Result<(), AtaError> {
+        self.start().or_else(|_| self.reset_link().and_then(|_| self.start()))
+    }
+
+    // Restart the port if an error stopped it
+    fn restart_if_needed(&self) -> Result<(), AtaError> {
+        if self.restart.swap(false, Ordering::AcqRel) && self.restart_port().is_err() {
+            self.restart.store(true, Ordering::Release);
+            return Err(AtaError::DeviceFault);
+        }
+        Ok(())
+    }
+
+    // Restart the port after a command hung, failing every command that was in flight
+    fn recover(&self, error: AtaError) {
+        let error = match self.restart_port() {
+            Ok(()) => error,
+            Err(_) => {
+                self.restart.store(true, Ordering::Release);
+                AtaError::DeviceFault
+            }
+        };
+        self.with_slots(|slots| fail_issued(slots, error));
+    }
+
+    // Check the port for finished commands, with the slot state locked so a command
+    // issued meanwhile is not mistaken for one that has finished. An error fails every
+    // command in flight and leaves the port stopped: restarting it takes too long for
+    // the interrupt handler, so the next submission does it.
+    fn complete(&self, slots: &mut SlotState) {
+        let status = self.regs.read32(PX_IS);
+        self.regs.write32(PX_IS, status);
+
+        if status & PX_IS_ERRORS != 0 {
+            let tfd = self.regs.read32(PX_TFD);
+            let error = if status & PX_IS_TFES != 0 && tfd & TFD_DF == 0 && tfd & TFD_ERR != 0 {
+                AtaError::Command(CommandError::from_register((tfd >> 8) as u8))
+            } else {
+                AtaError::DeviceFault
+            };
+            fail_issued(slots, error);
+            self.restart.store(true, Ordering::Release);
+            return;
+        }
+
+        let outstanding = self.regs.read32(PX_CI) | self.regs.read32(PX_SACT);
+        let finished = slots.issued & !outstanding;
+        slots.issued &= !finished;
+        slots.completed |= finished;
+    }
+
+    // Complete commands from the interrupt handler, which must not wait for the slot
+    // state; if another core holds it, the waiters pick the completions up instead
+    fn handle_interrupt(&self) {
+        match self.slots.try_lock() {
+            Some(mut slots) => self.complete(&mut slots),
+            None => self.missed.store(true, Ordering::Release),
+        }
+    }
+
+    // Check for completions from a waiter while interrupts are not in use, or after
+    // the interrupt handler missed some
+    fn poll_if_needed(&self) {
+        if !self.interrupts.load(Ordering::Acquire) || self.missed.swap(false, Ordering::AcqRel) {
+            self.with_slots(|slots| self.complete(slots));
+        }
+    }
+
+    fn slot_limit(&self) -> usize {
+        self.with_slots(|slots| slots.limit)
+    }
+
+    fn set_slot_limit(&self, limit: usize) {
+        self.with_slots(|slots| slots.limit = limit.clamp(1, self.tables.len()));
+    }
+
+    // Claim a free command slot, waiting for one if all are busy
+    fn allocate_slot(&self) -> Result<usize, AtaError> {
+        for _ in 0..AHCI_TIMEOUT {
+            let slot = self.with_slots(|slots| {
+                let slot = (0..slots.limit).find(|slot| slots.allocated & (1 << slot) == 0)?;
+                slots.allocated |= 1 << slot;
+                Some(slot)
+            });
+            if let Some(slot) = slot {
+                return Ok(slot);
+            }
+            self.poll_if_needed();
+        }
+        Err(AtaError::Timeout)
+    }
+
+    // Fill in a slot's command header and table and hand it to the controller
+    fn submit(&self, command: &Command) -> Result<usize, AtaError> {
+        self.restart_if_needed()?;
+        let slot = self.allocate_slot()?;
+        let table = &self.tables[slot];
+
+        for (i, byte) in command.fis(slot).iter().enumerate() {
+            table.write::<u8>(i, *byte);
+        }
+
+        let mut entries = 0;
+        if let Some(buffer) = command.buffer {
+            let len = command.sectors * SECTOR_SIZE;
+            for (i, offset) in (0..len).step_by(PRD_MAX_BYTES).enumerate() {
+                let address = buffer.phys_addr() + offset as u64;
+                let bytes = (len - offset).min(PRD_MAX_BYTES);
+                let entry = COMMAND_TABLE_PRDT + i * PRDT_ENTRY_SIZE;
+                table.write::<u32>(entry, address as u32);
+                table.write::<u32>(entry + 4, (address >> 32) as u32);
+                table.write::<u32>(entry + 8, 0);
+                // The byte count is stored minus one
+                table.write::<u32>(entry + 12, (bytes - 1) as u32);
+                entries += 1;
+            }
+        }
+
+        // Header: FIS length in dwords, direction and PRDT length, then the table address
+        let header = slot * COMMAND_HEADER_SIZE;
+        let flags = 5 | if command.write { 1 << 6 } else { 0 } | (entries << 16);
+        self.command_list.write::<u32>(header, flags);
+        self.command_list.write::<u32>(header + 4, 0);
+        self.command_list.write::<u32>(header + 8, table.phys_addr() as u32);
+        self.command_list.write::<u32>(header + 12, (table.phys_addr() >> 32) as u32);
+
+        // Issued under the lock, so the completion check cannot see the slot issued
+        // before the controller does
+        self.with_slots(|slots| {
+            if command.queued {
+                self.regs.write32(PX_SACT, 1 << slot);
+            }
+            slots.issued |= 1 << slot;
+            self.regs.write32(PX_CI, 1 << slot);
+        });
+        Ok(slot)
+    }
+
+    // Wait for a submitted command to finish and release its slot
+    fn wait(&self, slot: usize) -> Result<(), AtaError> {
+        let bit = 1u32 << slot;
+        for _ in 0..AHCI_TIMEOUT {
+            self.poll_if_needed();
+            let result = self.with_slots(|slots| {
+                if slots.completed & bit == 0 {
+                    return None;
+                }
+                slots.completed &= !bit;
+                slots.allocated &= !bit;
+                if slots.failed & bit != 0 {
+                    slots.failed &= !bit;
+                    return Some(Err(slots.error.unwrap_or(AtaError::DeviceFault)));
+                }
+                Some(Ok(()))
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+
+        // The command hung; restart the port, which aborts it, then release the slot
+        self.recover(AtaError::Timeout);
+        self.with_slots(|slots| {
+            slots.completed &= !bit;
+            slots.failed &= !bit;
+            slots.allocated &= !bit;
+        });
+        Err(AtaError::Timeout)
+    }
+
+    fn execute(&self, command: &Command) -> Result<(), AtaError> {
+        let slot = self.submit(command)?;
+        self.wait(slot)
+    }
+}
+
+// A SATA drive on an AHCI port, usable as a block device
+pub struct AhciPort {
+    index: usize,
+    engine: CommandEngine,
+    info: IdentifyData,
+    ncq: bool,
+    // Queued and non-queued commands must not be mixed on the link, so queued
+    // transfers share the port while other commands take it exclusively
+    exclusive: RwLock<()>,
+}
+
+impl AhciPort {
+    fn new(index: usize, regs: Mmio, slot_count: usize, controller_ncq: bool, addr64: bool) -> Result<AhciPort, DeviceError> {
+        let engine = CommandEngine::new(regs, slot_count, addr64)?;
+        engine.start()?;
+
+        let buffer = DmaBuffer::new(SECTOR_SIZE, 2)?;
+        engine.execute(&Command {
+            command: CMD_IDENTIFY,
+            lba: 0,
+            sectors: 1,
+            write: false,
+            queued: false,
+            buffer: Some(&buffer),
+        })?;
+        let mut words = [0u16; 256];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = buffer.read::<u16>(i * 2);
+        }
+        let info = IdentifyData::parse(&words);
+
+        let ncq = controller_ncq && info.ncq;
+        engine.set_slot_limit(if ncq { info.queue_depth } else { 1 });
+
+        Ok(AhciPort {
+            index,
+            engine,
+            info,
+            ncq,
+            exclusive: RwLock::new(()),
+        })
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn info(&self) -> &IdentifyData {
+        &self.info
+    }
+
+    pub fn ncq(&self) -> bool {
+        self.ncq
+    }
+
+    fn check_range(&self, start: u64, len: usize) -> Result<(), AtaError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AtaError::InvalidBuffer);
+        }
+        if start + (len / SECTOR_SIZE) as u64 > self.info.sectors {
+            return Err(AtaError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    // Split a transfer into commands and keep up to a queue's worth of them in flight.
+    // `fill` copies data into each command's buffer before a write and `drain` copies
+    // it out after a read.
+    fn transfer<F, D>(&self, start: u64, len: usize, write: bool, mut fill: F, mut drain: D) -> Result<(), block::Error>
+    where
+        F: FnMut(&mut DmaBuffer, core::ops::Range<usize>),
+        D: FnMut(&DmaBuffer, core::ops::Range<usize>),
+    {
+        self.check_range(start, len)?;
+        let command = match (self.ncq, write) {
+            (true, false) => CMD_READ_FPDMA_QUEUED,
+            (true, true) => CMD_WRITE_FPDMA_QUEUED,
+            (false, false) => CMD_READ_DMA_EXT,
+            (false, true) => CMD_WRITE_DMA_EXT,
+        };
+
+        let _shared = if self.ncq { Some(self.exclusive.read()) } else { None };
+        let _exclusive = if self.ncq { None } else { Some(self.exclusive.write()) };
+
+        let mut in_flight: VecDeque<(usize, DmaBuffer, core::ops::Range<usize>)> = VecDeque::new();
+        let mut result = Ok(());
+        let mut offset = 0;
+        while offset < len && result.is_ok() {
+            if in_flight.len() == self.engine.slot_limit() {
+                let (slot, buffer, range) = in_flight.pop_front().unwrap();
+                result = self.engine.wait(slot).map(|_| drain(&buffer, range));
+                continue;
+            }
+
+            let sectors = ((len - offset) / SECTOR_SIZE).min(MAX_SECTORS_PER_COMMAND);
+            let range = offset..offset + sectors * SECTOR_SIZE;
+            let mut buffer = DmaBuffer::new(range.len(), 2)?;
+            fill(&mut buffer, range.clone());
+            let submitted = self.engine.submit(&Command {
+                command,
+                lba: start + (offset / SECTOR_SIZE) as u64,
+                sectors,
+                write,
+                queued: self.ncq,
+                buffer: Some(&buffer),
+            });
+            match submitted {
+                Ok(slot) => in_flight.push_back((slot, buffer, range.clone())),
+                Err(error) => result = Err(error),
+            }
+            offset = range.end;
+        }
+
+        // Always collect the commands already issued, even after a failure
+        while let Some((slot, buffer, range)) = in_flight.pop_front() {
+            let waited = self.engine.wait(slot).map(|_| drain(&buffer, range));
+            result = result.and(waited);
+        }
+        Ok(result?)
+    }
+
+    pub fn read(&self, start: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        let len = buffer.len();
+        self.transfer(start, len, false, |_, _| {}, |dma, range| {
+            buffer[range].copy_from_slice(dma.as_slice());
+        })
+    }
+
+    // Write sectors and flush the drive cache so the data is on the media on return
+    pub fn write(&self, start: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        self.transfer(start, buffer.len(), true, |dma, range| {
+            dma.as_mut_slice().copy_from_slice(&buffer[range]);
+        }, |_, _| {})?;
+        self.flush()
+    }
+
+    pub fn flush(&self) -> Result<(), block::Error> {
+        let _exclusive = self.exclusive.write();
+        Ok(self.engine.execute(&Command {
+            command: CMD_FLUSH_CACHE_EXT,
+            lba: 0,
+            sectors: 0,
+            write: false,
+            queued: false,
+            buffer: None,
+        })?)
+    }
+}
+
+impl BlockDevice for AhciPort {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        self.read(start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        self.write(start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), block::Error> {
+        AhciPort::flush(self)
+    }
+}
+
+impl Device for AhciPort {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Block
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_block_device(&self) -> Option<&dyn BlockDevice> {
+        Some(self)
+    }
+
+    fn suspend(&self) -> Result<(), DeviceError> {
+        AhciPort::flush(self).map_err(|_| DeviceError::IoError)
+    }
+}
+
+// An AHCI host bus adapter; each attached drive is registered as a block device
+pub struct AhciController {
+    regs: Mmio,
+    pub ports: Vec<Arc<AhciPort>>,
+    pub disks: Vec<String>,
+}
+
+impl AhciController {
+    pub fn new(regs: Mmio) -> Result<AhciController, DeviceError> {
+        // Take the controller over from the firmware if it is still using it
+        if regs.read32(HBA_CAP2) & CAP2_BOH != 0 {
+            regs.write32(HBA_BOHC, regs.read32(HBA_BOHC) | BOHC_OOS);
+            wait_for(&regs, HBA_BOHC, BOHC_BOS, 0)?;
+        }
+
+        regs.write32(HBA_GHC, GHC_AE);
+        regs.write32(HBA_GHC, GHC_AE | GHC_HR);
+        wait_for(&regs, HBA_GHC, GHC_HR, 0)?;
+        regs.write32(HBA_GHC, GHC_AE);
+
+        let cap = regs.read32(HBA_CAP);
+        let slot_count = ((cap >> CAP_NCS_SHIFT) & 0x1F) as usize + 1;
+        let implemented = regs.read32(HBA_PI);
+
+        let mut ports = Vec::new();
+        for index in (0..MAX_SLOTS).filter(|index| implemented & (1 << index) != 0) {
+            let port_regs = regs.subregion(PORT_BASE + index * PORT_SIZE, PORT_SIZE);
+            // Spin the device up first: with staggered spin-up the link only comes up
+            // once it is
+            port_regs.write32(PX_CMD, port_regs.read32(PX_CMD) | PX_CMD_SUD | PX_CMD_POD);
+            // Only SATA disks with an established link are handled; ATAPI and port
+            // multipliers have other signatures
+            if !link_up(&port_regs) || port_regs.read32(PX_SIG) != SIG_ATA {
+                continue;
+            }
+            if let Ok(port) = AhciPort::new(index, port_regs, slot_count, cap & CAP_SNCQ != 0, cap & CAP_S64A != 0) {
+                ports.push(Arc::new(port));
+            }
+        }
+        regs.write32(HBA_IS, !0);
+
+        Ok(AhciController {
+            regs,
+            ports,
+            disks: Vec::new(),
+        })
+    }
+
+    // Switch the ports from polling to interrupt-driven completion
+    pub fn enable_interrupts(&self) {
+        for port in self.ports.iter() {
+            port.engine.interrupts.store(true, Ordering::Release);
+        }
+        self.regs.write32(HBA_GHC, self.regs.read32(HBA_GHC) | GHC_IE);
+    }
+
+    // Complete the commands of every port that raised an interrupt
+    pub fn handle_interrupt(&self) {
+        let pending = self.regs.read32(HBA_IS);
+        for port in self.ports.iter().filter(|port| pending & (1 << port.index) != 0) {
+            port.engine.handle_interrupt();
+        }
+        // The per-port status must be cleared before the global bit
+        self.regs.write32(HBA_IS, pending);
+    }
+}
+
+impl Device for AhciController {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Controller
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Probe an AHCI controller and register its drives
+fn probe(device: &PciDevice) -> Result<Arc<dyn Device>, DeviceError> {
+    // The HBA registers are behind BAR5 (ABAR)
+    let regs = Mmio::from_bar(&device.bar(5)?)?;
+    let regs = regs.subregion(0, HBA_SIZE);
+    device.enable();
+
+    let mut controller = AhciController::new(regs)?;
+    // Completions are signalled by MSI; without it the ports keep polling
+    if device.enable_msi(AHCI_INTERRUPT_VECTOR, 0).is_ok() {
+        controller.enable_interrupts();
+    }
+    controller.disks = controller
+        .ports
+        .iter()
+        .map(|port| device::add_device(port.clone()))
+        .collect();
+    Ok(Arc::new(controller))
+}
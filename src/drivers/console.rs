@@ -0,0 +1,282 @@
+// Framebuffer console
+// Draws a `lib::terminal::Terminal` on the display with the built-in bitmap font.
+// Everything written to the console also goes into a ring buffer, the kernel log, so
+// messages from before the display was found are replayed once the console starts.
+// When the whole screen scrolls, the pixels are moved with `Gpu::copy_rect` and only
+// the new lines are drawn.
+
+use crate::drivers::device::DeviceError;
+use crate::drivers::gpu::{self, Gpu, Rect};
+use crate::gui::utils::font::{self, Font};
+use crate::lib::terminal::{Terminal, PALETTE};
+use spin::Mutex;
+use std::collections::VecDeque;
+
+// The cursor blinks every this many timer ticks, half a second at 100 Hz
+pub const CURSOR_BLINK_TICKS: u32 = 50;
+// Bytes of output kept in the kernel log
+pub const LOG_SIZE: usize = 64 * 1024;
+// Displays at least this wide get the font at double size
+const HIDPI_WIDTH: usize = 2560;
+
+pub struct FramebufferConsole {
+    terminal: Terminal,
+    font: Font,
+    cell_width: usize,
+    cell_height: usize,
+    // The display size the terminal was laid out for, in pixels
+    display: (usize, usize),
+    // Whether the console owns the display; the GUI takes it over
+    active: bool,
+    redraw: bool,
+    blink_on: bool,
+    ticks: u32,
+    // Where the cursor was last drawn, as (row, column)
+    drawn_cursor: Option<(usize, usize)>,
+}
+
+impl FramebufferConsole {
+    // A console filling a `width` x `height` pixel display. Fails with `NotSupported`
+    // when the display cannot hold a single character.
+    pub fn new(width: usize, height: usize, font: Font) -> Result<FramebufferConsole, DeviceError> {
+        let cell_width = font.glyph(' ').advance as usize;
+        let cell_height = font.line_height() as usize;
+        if cell_width == 0 || cell_height == 0 || width < cell_width || height < cell_height {
+            return Err(DeviceError::NotSupported);
+        }
+        Ok(FramebufferConsole {
+            terminal: Terminal::new(width / cell_width, height / cell_height),
+            font,
+            cell_width,
+            cell_height,
+            display: (width, height),
+            active: true,
+            redraw: true,
+            blink_on: true,
+            ticks: 0,
+            drawn_cursor: None,
+        })
+    }
+
+    pub fn terminal(&self) -> &Terminal {
+        &self.terminal
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.terminal.write(bytes);
+        // Keep the cursor solid while output arrives
+        self.blink_on = true;
+        self.ticks = 0;
+    }
+
+    pub fn scroll_view(&mut self, lines: isize) {
+        self.terminal.scroll_view(lines);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // Stop or resume drawing, e.g. while the GUI is on screen; resuming redraws everything
+    pub fn set_active(&mut self, active: bool) {
+        if active && !self.active {
+            self.redraw = true;
+        }
+        self.active = active;
+    }
+
+    // Count a timer tick; returns whether the cursor changed and needs drawing
+    pub fn tick(&mut self) -> bool {
+        self.ticks += 1;
+        if self.ticks < CURSOR_BLINK_TICKS {
+            return false;
+        }
+        self.ticks = 0;
+        self.blink_on = !self.blink_on;
+        self.terminal.cursor_visible()
+    }
+
+    // The cursor to draw, hidden while blinking off or looking at the scrollback
+    fn cursor(&self) -> Option<(usize, usize)> {
+        if self.blink_on && self.terminal.cursor_visible() && self.terminal.view_offset() == 0 {
+            Some(self.terminal.cursor())
+        } else {
+            None
+        }
+    }
+
+    // Fit the terminal to the display after a mode change. Returns false while the
+    // display is too small for a single character, when nothing can be drawn.
+    fn fit_display(&mut self, gpu: &dyn Gpu) -> bool {
+        let mode = gpu.mode();
+        if (mode.width, mode.height) == self.display {
+            return true;
+        }
+        let (columns, rows) = (mode.width / self.cell_width, mode.height / self.cell_height);
+        if columns == 0 || rows == 0 {
+            return false;
+        }
+        self.terminal.resize(columns, rows);
+        self.display = (mode.width, mode.height);
+        self.drawn_cursor = None;
+        self.redraw = true;
+        true
+    }
+
+    // Bring the screen up to date with the terminal
+    pub fn render(&mut self, gpu: &mut dyn Gpu) {
+        if !self.active || !self.fit_display(gpu) {
+            return;
+        }
+        let rows = self.terminal.rows();
+        let damage = self.terminal.take_damage();
+        let mut dirty = vec![self.redraw; rows];
+        for row in damage.rows {
+            dirty[row] = true;
+        }
+
+        let (width, height) = (self.terminal.columns() * self.cell_width, rows * self.cell_height);
+        let mut moved = false;
+        if self.redraw {
+            gpu.fill_rect(Rect::new(0, 0, gpu.mode().width, gpu.mode().height), PALETTE[0]);
+        } else if damage.scrolled >= rows {
+            dirty.fill(true);
+        } else if damage.scrolled > 0 {
+            let shift = damage.scrolled * self.cell_height;
+            gpu.copy_rect(Rect::new(0, shift, width, height - shift), 0, 0);
+            moved = true;
+            // The cursor drawn before moved up with the text
+            self.drawn_cursor = self
+                .drawn_cursor
+                .and_then(|(row, column)| Some((row.checked_sub(damage.scrolled)?, column)));
+        }
+
+        let cursor = self.cursor();
+        if cursor != self.drawn_cursor {
+            for (row, _) in [cursor, self.drawn_cursor].into_iter().flatten() {
+                dirty[row] = true;
+            }
+        }
+        for row in (0..rows).filter(|row| dirty[*row]) {
+            self.draw_row(gpu, row, cursor);
+        }
+        self.drawn_cursor = cursor;
+
+        if self.redraw {
+            gpu.flush_all();
+        } else if moved {
+            gpu.flush(Rect::new(0, 0, width, height));
+        } else if let (Some(first), Some(last)) = (dirty.iter().position(|d| *d), dirty.iter().rposition(|d| *d)) {
+            let top = first * self.cell_height;
+            gpu.flush(Rect::new(0, top, width, (last + 1) * self.cell_height - top));
+        }
+        self.redraw = false;
+    }
+
+    fn draw_row(&self, gpu: &mut dyn Gpu, row: usize, cursor: Option<(usize, usize)>) {
+        let mode = gpu.mode();
+        let stride = mode.width;
+        // Cells are only drawn where they fit on the display and in the back buffer
+        let top = row * self.cell_height;
+        let columns = self.terminal.columns().min(mode.width / self.cell_width);
+        let framebuffer = gpu.framebuffer();
+        if top + self.cell_height > mode.height || (top + self.cell_height) * stride > framebuffer.len() {
+            return;
+        }
+        let ascent = self.font.ascent();
+        let underline = (ascent as usize + 1).min(self.cell_height - 1);
+        for column in 0..columns {
+            let cell = self.terminal.cell(row, column);
+            let (mut foreground, mut background) = cell.attributes.colors();
+            if cursor == Some((row, column)) {
+                core::mem::swap(&mut foreground, &mut background);
+            }
+            let glyph = self.font.glyph(cell.c);
+            let left = column * self.cell_width;
+            for y in 0..self.cell_height {
+                let line = (top + y) * stride + left;
+                let glyph_y = y as i32 - ascent - glyph.top;
+                for x in 0..self.cell_width {
+                    let glyph_x = x as i32 - glyph.left;
+                    let ink = glyph_x >= 0
+                        && glyph_y >= 0
+                        && (glyph_x as u32) < glyph.width
+                        && (glyph_y as u32) < glyph.height
+                        && glyph.coverage[glyph_y as usize * glyph.width as usize + glyph_x as usize] > 0;
+                    let ink = ink || (cell.attributes.underline && y == underline);
+                    framebuffer[line + x] = if ink { foreground } else { background };
+                }
+            }
+        }
+    }
+}
+
+static CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+static LOG: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+// Start the console on the first display, replaying the kernel log
+pub fn init() -> Result<(), DeviceError> {
+    let console = gpu::with_display(|gpu| -> Result<FramebufferConsole, DeviceError> {
+        let mode = gpu.mode();
+        let scale = if mode.width >= HIDPI_WIDTH { 2 } else { 1 };
+        let mut console = FramebufferConsole::new(mode.width, mode.height, Font::bitmap(font::console_face(), scale))?;
+        let log: Vec<u8> = LOG.lock().iter().copied().collect();
+        console.write(&log);
+        console.render(gpu);
+        Ok(console)
+    })
+    .ok_or(DeviceError::NotFound)??;
+    *CONSOLE.lock() = Some(console);
+    Ok(())
+}
+
+// Write to the kernel log and, once it is up, the screen
+pub fn write_bytes(bytes: &[u8]) {
+    {
+        let mut log = LOG.lock();
+        let overflow = (log.len() + bytes.len()).saturating_sub(LOG_SIZE).min(log.len());
+        log.drain(..overflow);
+        log.extend(&bytes[bytes.len().saturating_sub(LOG_SIZE)..]);
+    }
+    let mut console = CONSOLE.lock();
+    if let Some(console) = console.as_mut() {
+        console.write(bytes);
+        gpu::with_display(|gpu| console.render(gpu));
+    }
+}
+
+// The kernel log, oldest byte first
+pub fn log_contents() -> Vec<u8> {
+    LOG.lock().iter().copied().collect()
+}
+
+pub fn scroll_view(lines: isize) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.scroll_view(lines);
+        gpu::with_display(|gpu| console.render(gpu));
+    }
+}
+
+// Lines on the screen, e.g. to scroll back by a page
+pub fn rows() -> usize {
+    CONSOLE.lock().as_ref().map_or(0, |console| console.terminal().rows())
+}
+
+pub fn set_active(active: bool) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.set_active(active);
+        gpu::with_display(|gpu| console.render(gpu));
+    }
+}
+
+// Called from the timer interrupt to blink the cursor. The locks may already be held
+// by the code that was interrupted, in which case this tick is skipped.
+pub fn tick() {
+    if let Some(mut console) = CONSOLE.try_lock() {
+        if let Some(console) = console.as_mut() {
+            if console.tick() {
+                gpu::try_with_display(|gpu| console.render(gpu));
+            }
+        }
+    }
+}
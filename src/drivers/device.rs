@@ -0,0 +1,394 @@
+//! Device driver model
+//!
+//! Buses enumerate the hardware attached to them, drivers probe what they
+//! recognise and hand back a `Device`, and the device manager keeps the tree of
+//! bound devices under names like `net0` or `disk0` so the rest of the kernel can
+//! look devices up by name or class instead of constructing drivers itself.
+
+use crate::drivers::pci::{PciDevice, PciError};
+use crate::storage::block::BlockDevice;
+use core::any::Any;
+use spin::Mutex;
+use std::sync::Arc;
+
+/// The kind of service a device provides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Block,
+    Network,
+    Display,
+    Input,
+    Char,
+    Controller,
+}
+
+impl DeviceClass {
+    /// The prefix used when naming devices of this class
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            DeviceClass::Block => "disk",
+            DeviceClass::Network => "net",
+            DeviceClass::Display => "fb",
+            DeviceClass::Input => "input",
+            DeviceClass::Char => "tty",
+            DeviceClass::Controller => "ctrl",
+        }
+    }
+}
+
+/// Errors reported by drivers and the device manager
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceError {
+    NotFound,
+    NotSupported,
+    Busy,
+    Timeout,
+    IoError,
+    Pci(PciError),
+    ProbeFailed(&'static str),
+}
+
+impl From<PciError> for DeviceError {
+    fn from(error: PciError) -> Self {
+        DeviceError::Pci(error)
+    }
+}
+
+/// Power state of a bound device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Active,
+    Suspended,
+}
+
+/// A device instance created by a driver's probe function
+pub trait Device: Send + Sync {
+    /// The class the device is registered under
+    fn class(&self) -> DeviceClass;
+
+    /// Access the concrete device type, e.g. `as_any().downcast_ref::<KeyboardDevice>()`
+    fn as_any(&self) -> &dyn Any;
+
+    /// The block interface of a `Block` class device, used by filesystems to mount it
+    fn as_block_device(&self) -> Option<&dyn BlockDevice> {
+        None
+    }
+
+    /// Quiesce the device before the system sleeps
+    fn suspend(&self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    /// Restore the device after the system wakes up
+    fn resume(&self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+/// Hardware found on a bus, before any driver has bound to it
+#[derive(Debug, Clone)]
+pub enum BusDevice {
+    Pci(PciDevice),
+    Platform { name: &'static str, io_base: u16, irq: u8 },
+}
+
+impl BusDevice {
+    /// A stable location string used to tell bus devices apart, e.g. `pci/00:1f.2`
+    pub fn location(&self) -> String {
+        match self {
+            BusDevice::Pci(device) => format!(
+                "pci/{:04x}:{:02x}:{:02x}.{}",
+                device.address.segment, device.address.bus, device.address.device, device.address.function
+            ),
+            BusDevice::Platform { name, .. } => format!("platform/{}", name),
+        }
+    }
+}
+
+/// A driver that binds to bus devices
+pub trait Driver: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the driver recognises the device
+    fn matches(&self, device: &BusDevice) -> bool;
+
+    /// Initialise the hardware and return the device instance
+    fn probe(&self, device: &BusDevice) -> Result<Arc<dyn Device>, DeviceError>;
+
+    /// Release the hardware before the device is removed from the tree
+    fn remove(&self, _device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+/// A bus that can enumerate the devices attached to it
+pub trait Bus: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn enumerate(&self) -> Vec<BusDevice>;
+}
+
+/// Legacy devices at fixed addresses that cannot be discovered by probing
+pub struct PlatformBus {
+    devices: Mutex<Vec<BusDevice>>,
+}
+
+impl PlatformBus {
+    pub const fn new() -> PlatformBus {
+        PlatformBus {
+            devices: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add_device(&self, name: &'static str, io_base: u16, irq: u8) {
+        self.devices.lock().push(BusDevice::Platform { name, io_base, irq });
+    }
+}
+
+impl Bus for PlatformBus {
+    fn name(&self) -> &'static str {
+        "platform"
+    }
+
+    fn enumerate(&self) -> Vec<BusDevice> {
+        self.devices.lock().clone()
+    }
+}
+
+pub static PLATFORM_BUS: PlatformBus = PlatformBus::new();
+
+/// An entry in the device tree
+#[derive(Clone)]
+pub struct DeviceNode {
+    pub name: String,
+    pub class: DeviceClass,
+    pub location: Option<String>,
+    pub driver: Option<&'static dyn Driver>,
+    pub device: Arc<dyn Device>,
+    pub state: PowerState,
+}
+
+/// The registry of buses, drivers and bound devices
+pub struct DeviceManager {
+    buses: Vec<&'static dyn Bus>,
+    drivers: Vec<&'static dyn Driver>,
+    devices: Vec<DeviceNode>,
+}
+
+impl DeviceManager {
+    pub const fn new() -> DeviceManager {
+        DeviceManager {
+            buses: Vec::new(),
+            drivers: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    // Pick the lowest free index for the class, so names are reused after removal
+    fn next_name(&self, class: DeviceClass) -> String {
+        let prefix = class.prefix();
+        let mut index = 0;
+        loop {
+            let name = format!("{}{}", prefix, index);
+            if !self.devices.iter().any(|node| node.name == name) {
+                return name;
+            }
+            index += 1;
+        }
+    }
+
+    fn is_bound(&self, location: &str) -> bool {
+        self.devices
+            .iter()
+            .any(|node| node.location.as_deref() == Some(location))
+    }
+
+    fn insert(
+        &mut self,
+        device: Arc<dyn Device>,
+        location: Option<String>,
+        driver: Option<&'static dyn Driver>,
+    ) -> String {
+        let class = device.class();
+        let name = self.next_name(class);
+        self.devices.push(DeviceNode {
+            name: name.clone(),
+            class,
+            location,
+            driver,
+            device,
+            state: PowerState::Active,
+        });
+        name
+    }
+}
+
+static DEVICE_MANAGER: Mutex<DeviceManager> = Mutex::new(DeviceManager::new());
+
+// Register a bus; its devices are picked up by the next `probe_all`
+pub fn register_bus(bus: &'static dyn Bus) {
+    DEVICE_MANAGER.lock().buses.push(bus);
+}
+
+// Register a driver; it is matched against unbound devices by the next `probe_all`
+pub fn register_driver(driver: &'static dyn Driver) {
+    DEVICE_MANAGER.lock().drivers.push(driver);
+}
+
+// Enumerate every bus and bind each unbound device to the first driver whose probe succeeds.
+// Returns the names of the newly added devices.
+pub fn probe_all() -> Vec<String> {
+    // Probe functions may take a while and may themselves query the registry,
+    // so the lock is only held while reading and updating the tables.
+    let (buses, drivers) = {
+        let manager = DEVICE_MANAGER.lock();
+        (manager.buses.clone(), manager.drivers.clone())
+    };
+
+    let mut added = Vec::new();
+    for bus in buses {
+        for bus_device in bus.enumerate() {
+            let location = bus_device.location();
+            if DEVICE_MANAGER.lock().is_bound(&location) {
+                continue;
+            }
+            for driver in drivers.iter().filter(|driver| driver.matches(&bus_device)) {
+                if let Ok(device) = driver.probe(&bus_device) {
+                    let name = DEVICE_MANAGER
+                        .lock()
+                        .insert(device, Some(location.clone()), Some(*driver));
+                    added.push(name);
+                    break;
+                }
+            }
+        }
+    }
+    added
+}
+
+// Add a device that was not found on a bus, such as a loopback interface
+pub fn add_device(device: Arc<dyn Device>) -> String {
+    DEVICE_MANAGER.lock().insert(device, None, None)
+}
+
+// Remove a device from the tree, giving its driver a chance to release the hardware.
+// Fails with `Busy` while anyone else still holds a reference to the device. The
+// driver runs without the registry locked, so it may look other devices up.
+pub fn remove_device(name: &str) -> Result<(), DeviceError> {
+    let (device, driver) = {
+        let manager = DEVICE_MANAGER.lock();
+        let node = manager
+            .devices
+            .iter()
+            .find(|node| node.name == name)
+            .ok_or(DeviceError::NotFound)?;
+        if Arc::strong_count(&node.device) > 1 {
+            return Err(DeviceError::Busy);
+        }
+        (node.device.clone(), node.driver)
+    };
+
+    if let Some(driver) = driver {
+        driver.remove(&device)?;
+    }
+    DEVICE_MANAGER
+        .lock()
+        .devices
+        .retain(|node| !Arc::ptr_eq(&node.device, &device));
+    Ok(())
+}
+
+// Move the devices in `from` to `to`, calling `change` on each without the registry
+// locked, so that devices may look each other up while they suspend or resume
+fn set_power_state(
+    from: PowerState,
+    to: PowerState,
+    reverse: bool,
+    change: impl Fn(&dyn Device) -> Result<(), DeviceError>,
+) -> Result<(), DeviceError> {
+    let mut devices: Vec<Arc<dyn Device>> = DEVICE_MANAGER
+        .lock()
+        .devices
+        .iter()
+        .filter(|node| node.state == from)
+        .map(|node| node.device.clone())
+        .collect();
+    if reverse {
+        devices.reverse();
+    }
+
+    for device in devices {
+        change(device.as_ref())?;
+        if let Some(node) = DEVICE_MANAGER
+            .lock()
+            .devices
+            .iter_mut()
+            .find(|node| Arc::ptr_eq(&node.device, &device))
+        {
+            node.state = to;
+        }
+    }
+    Ok(())
+}
+
+// Suspend every active device, in reverse registration order
+pub fn suspend_all() -> Result<(), DeviceError> {
+    set_power_state(PowerState::Active, PowerState::Suspended, true, |device| device.suspend())
+}
+
+// Resume every suspended device, in registration order
+pub fn resume_all() -> Result<(), DeviceError> {
+    set_power_state(PowerState::Suspended, PowerState::Active, false, |device| device.resume())
+}
+
+// Look up a device by name, e.g. `disk0`
+pub fn find(name: &str) -> Option<Arc<dyn Device>> {
+    DEVICE_MANAGER
+        .lock()
+        .devices
+        .iter()
+        .find(|node| node.name == name)
+        .map(|node| node.device.clone())
+}
+
+// Return the name and device of every device in a class, in registration order
+pub fn find_by_class(class: DeviceClass) -> Vec<(String, Arc<dyn Device>)> {
+    DEVICE_MANAGER
+        .lock()
+        .devices
+        .iter()
+        .filter(|node| node.class == class)
+        .map(|node| (node.name.clone(), node.device.clone()))
+        .collect()
+}
+
+// Return the first device of a class, e.g. the default network card
+pub fn first_of_class(class: DeviceClass) -> Option<Arc<dyn Device>> {
+    find_by_class(class).into_iter().next().map(|(_, device)| device)
+}
+
+// Like `first_of_class`, but gives up if the registry is locked, for callers in
+// interrupt context that may have interrupted its holder
+pub fn try_first_of_class(class: DeviceClass) -> Option<Arc<dyn Device>> {
+    let manager = DEVICE_MANAGER.try_lock()?;
+    let node = manager.devices.iter().find(|node| node.class == class)?;
+    Some(node.device.clone())
+}
+
+// Like `find_by_class` without the names, but gives up if the registry is locked, for
+// interrupt handlers. The references are only meant to be held while handling.
+pub fn try_find_by_class(class: DeviceClass) -> Option<Vec<Arc<dyn Device>>> {
+    let manager = DEVICE_MANAGER.try_lock()?;
+    Some(
+        manager
+            .devices
+            .iter()
+            .filter(|node| node.class == class)
+            .map(|node| node.device.clone())
+            .collect(),
+    )
+}
+
+// Return a snapshot of the whole device tree
+pub fn device_tree() -> Vec<DeviceNode> {
+    DEVICE_MANAGER.lock().devices.clone()
+}
@@ -0,0 +1,136 @@
+//! Memory shared with bus-master devices
+//!
+//! The kernel runs with physical memory identity mapped, so a buffer's virtual
+//! address is also the address a device uses to reach it, and a memory BAR can be
+//! accessed at its physical address. `pci::map_bar` sets up the same identity
+//! mapping for page tables the kernel builds itself.
+
+use crate::drivers::device::DeviceError;
+use crate::drivers::pci::{Bar, PciError};
+use crate::mm::paging::PAGE_SIZE;
+use core::ptr::{self, NonNull};
+use std::alloc::{self, Layout};
+
+/// A zeroed, physically contiguous buffer a device can read and write
+pub struct DmaBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// The buffer is plain memory; synchronisation with the device is up to the driver
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    /// Allocate `size` bytes aligned to `align`; buffers larger than a page are page aligned
+    pub fn new(size: usize, align: usize) -> Result<DmaBuffer, DeviceError> {
+        let align = if size > PAGE_SIZE { align.max(PAGE_SIZE) } else { align };
+        let layout = Layout::from_size_align(size.max(1), align).map_err(|_| DeviceError::NotSupported)?;
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) }).ok_or(DeviceError::ProbeFailed("out of DMA memory"))?;
+        Ok(DmaBuffer { ptr, layout })
+    }
+
+    /// The address the device uses for the start of the buffer
+    pub fn phys_addr(&self) -> u64 {
+        self.ptr.as_ptr() as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len()) }
+    }
+
+    /// Read a value the device may have written
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= self.len());
+        unsafe { ptr::read_volatile(self.ptr.as_ptr().add(offset) as *const T) }
+    }
+
+    /// Write a value the device will read
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + core::mem::size_of::<T>() <= self.len());
+        unsafe { ptr::write_volatile(self.ptr.as_ptr().add(offset) as *mut T, value) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// A device's memory-mapped register window
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    base: usize,
+    size: usize,
+}
+
+impl Mmio {
+    /// # Safety
+    /// `base` must be the mapped address of `size` bytes of device registers
+    pub unsafe fn new(base: usize, size: usize) -> Mmio {
+        Mmio { base, size }
+    }
+
+    /// Access a memory BAR through the identity mapping
+    pub fn from_bar(bar: &Bar) -> Result<Mmio, DeviceError> {
+        match *bar {
+            Bar::Memory { address, size, .. } => Ok(Mmio {
+                base: address as usize,
+                size: size as usize,
+            }),
+            Bar::Io { .. } => Err(PciError::NoSuchBar.into()),
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= self.size);
+        unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        assert!(offset + 4 <= self.size);
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        assert!(offset + 2 <= self.size);
+        unsafe { ptr::read_volatile((self.base + offset) as *const u16) }
+    }
+
+    pub fn write16(&self, offset: usize, value: u16) {
+        assert!(offset + 2 <= self.size);
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u16, value) }
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        assert!(offset < self.size);
+        unsafe { ptr::read_volatile((self.base + offset) as *const u8) }
+    }
+
+    pub fn write8(&self, offset: usize, value: u8) {
+        assert!(offset < self.size);
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u8, value) }
+    }
+
+    /// A window onto part of the registers, e.g. one AHCI port
+    pub fn subregion(&self, offset: usize, size: usize) -> Mmio {
+        assert!(offset + size <= self.size);
+        Mmio {
+            base: self.base + offset,
+            size,
+        }
+    }
+}
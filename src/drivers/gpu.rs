@@ -1,33 +1,678 @@
-// TODO: Define the Gpu trait that will be implemented by all GPU drivers
-trait Gpu {
-    // TODO: Define functions for interacting with the GPU, such as initializing it, rendering graphics, etc.
+// Graphics driver for linear framebuffers
+// Vendor GPUs are not supported; instead the driver drives the framebuffer the
+// firmware or boot loader set up (UEFI GOP, Multiboot), and the Bochs VBE interface
+// of QEMU's standard VGA and bochs-display devices, which can also change modes.
+// Everything is drawn into a back buffer in system memory and copied to the screen
+// on flush, so the screen never shows a half-drawn frame.
+
+use crate::drivers::device::{self, Device, DeviceClass, DeviceError};
+use crate::drivers::dma::Mmio;
+use crate::drivers::mouse;
+use crate::drivers::pci::{Bar, PciDevice, PciDeviceId, PciDriver};
+use core::any::Any;
+use core::ptr;
+use spin::Mutex;
+use std::sync::Arc;
+use x86_64::instructions::port::Port;
+
+// PCI IDs of the QEMU/Bochs display adapters and VirtualBox's compatible one
+const PCI_VENDOR_BOCHS: u16 = 0x1234;
+const PCI_DEVICE_BOCHS_VGA: u16 = 0x1111;
+const PCI_VENDOR_VIRTUALBOX: u16 = 0x80EE;
+const PCI_DEVICE_VIRTUALBOX_VGA: u16 = 0xBEEF;
+
+// Bochs VBE (DISPI) interface, through I/O ports or at offset 0x500 of BAR2
+const VBE_DISPI_IOPORT_INDEX: u16 = 0x01CE;
+const VBE_DISPI_IOPORT_DATA: u16 = 0x01CF;
+const VBE_DISPI_MMIO_OFFSET: usize = 0x500;
+const VBE_VGA_MMIO_OFFSET: usize = 0x400;
+
+const VBE_DISPI_INDEX_ID: u16 = 0x0;
+const VBE_DISPI_INDEX_XRES: u16 = 0x1;
+const VBE_DISPI_INDEX_YRES: u16 = 0x2;
+const VBE_DISPI_INDEX_BPP: u16 = 0x3;
+const VBE_DISPI_INDEX_ENABLE: u16 = 0x4;
+const VBE_DISPI_INDEX_VIRT_WIDTH: u16 = 0x6;
+const VBE_DISPI_INDEX_VIRT_HEIGHT: u16 = 0x7;
+const VBE_DISPI_INDEX_X_OFFSET: u16 = 0x8;
+const VBE_DISPI_INDEX_Y_OFFSET: u16 = 0x9;
+
+const VBE_DISPI_ID0: u16 = 0xB0C0;
+const VBE_DISPI_ID5: u16 = 0xB0C5;
+const VBE_DISPI_ENABLED: u16 = 0x01;
+const VBE_DISPI_GETCAPS: u16 = 0x02;
+const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+
+// VGA attribute controller; writing 0x20 to its index register unblanks the screen
+const VGA_ATTRIBUTE_INDEX: u16 = 0x3C0;
+const VGA_INPUT_STATUS: u16 = 0x3DA;
+const VGA_ATTRIBUTE_PALETTE_ENABLE: u8 = 0x20;
+
+// Resolutions offered by the Bochs adapter, if they fit in video memory
+const BOCHS_MODES: [(usize, usize); 9] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 800),
+    (1280, 1024),
+    (1440, 900),
+    (1600, 900),
+    (1920, 1080),
+];
+const BOCHS_DEFAULT_MODE: (usize, usize) = (1024, 768);
+
+// Multiboot2 framebuffer tag
+const MULTIBOOT2_TAG_FRAMEBUFFER: u32 = 8;
+const MULTIBOOT2_FRAMEBUFFER_RGB: u8 = 1;
+
+// UEFI GOP pixel formats
+const GOP_RGB_RESERVED_8BIT: u32 = 0;
+const GOP_BGR_RESERVED_8BIT: u32 = 1;
+const GOP_BIT_MASK: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GpuError {
+    UnsupportedMode,
+    Device(DeviceError),
+}
+
+impl From<GpuError> for DeviceError {
+    fn from(error: GpuError) -> Self {
+        match error {
+            GpuError::UnsupportedMode => DeviceError::NotSupported,
+            GpuError::Device(error) => error,
+        }
+    }
 }
 
-// TODO: Implement the Gpu trait for a specific GPU driver, such as an Nvidia or AMD graphics card driver.
-struct NvidiaGpu {}
+impl From<DeviceError> for GpuError {
+    fn from(error: DeviceError) -> Self {
+        GpuError::Device(error)
+    }
+}
+
+// Position and width of a color channel within a pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel {
+    pub shift: u8,
+    pub size: u8,
+}
+
+impl Channel {
+    const fn new(shift: u8, size: u8) -> Channel {
+        Channel { shift, size }
+    }
+
+    fn from_mask(mask: u32) -> Option<Channel> {
+        if mask == 0 {
+            return None;
+        }
+        let shift = mask.trailing_zeros();
+        let size = (mask >> shift).trailing_ones();
+        // The mask must be one contiguous run of bits
+        if (mask >> shift) >> size != 0 {
+            return None;
+        }
+        Some(Channel::new(shift as u8, size as u8))
+    }
+
+    // Scale an 8-bit component to the channel and move it into place
+    fn encode(&self, value: u8) -> u32 {
+        let value = value as u32;
+        let scaled = if self.size >= 8 { value << (self.size - 8) } else { value >> (8 - self.size) };
+        scaled << self.shift
+    }
+}
 
-impl Gpu for NvidiaGpu {
-    // TODO: Implement the functions defined in the Gpu trait for Nvidia GPUs
+// Layout of a pixel in video memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bytes_per_pixel: usize,
+    pub red: Channel,
+    pub green: Channel,
+    pub blue: Channel,
 }
 
-struct AmdGpu {}
+impl PixelFormat {
+    // 0x00RRGGBB, the format of the back buffer
+    pub const XRGB8888: PixelFormat = PixelFormat::new(4, Channel::new(16, 8), Channel::new(8, 8), Channel::new(0, 8));
+    pub const XBGR8888: PixelFormat = PixelFormat::new(4, Channel::new(0, 8), Channel::new(8, 8), Channel::new(16, 8));
+    pub const RGB888: PixelFormat = PixelFormat::new(3, Channel::new(16, 8), Channel::new(8, 8), Channel::new(0, 8));
+    pub const RGB565: PixelFormat = PixelFormat::new(2, Channel::new(11, 5), Channel::new(5, 6), Channel::new(0, 5));
+
+    pub const fn new(bytes_per_pixel: usize, red: Channel, green: Channel, blue: Channel) -> PixelFormat {
+        PixelFormat {
+            bytes_per_pixel,
+            red,
+            green,
+            blue,
+        }
+    }
+
+    // Build a format from channel bit masks, as reported by GOP and VBE
+    pub fn from_masks(bits_per_pixel: u8, red: u32, green: u32, blue: u32) -> Option<PixelFormat> {
+        let bytes_per_pixel = (bits_per_pixel as usize).div_ceil(8);
+        if !(2..=4).contains(&bytes_per_pixel) {
+            return None;
+        }
+        Some(PixelFormat::new(
+            bytes_per_pixel,
+            Channel::from_mask(red)?,
+            Channel::from_mask(green)?,
+            Channel::from_mask(blue)?,
+        ))
+    }
 
-impl Gpu for AmdGpu {
-    // TODO: Implement the functions defined in the Gpu trait for AMD GPUs
+    // Convert a 0x00RRGGBB color to this format
+    pub fn encode(&self, color: u32) -> u32 {
+        let [blue, green, red, _] = color.to_le_bytes();
+        self.red.encode(red) | self.green.encode(green) | self.blue.encode(blue)
+    }
 }
 
-// TODO: Define a function for detecting the installed GPU and selecting the appropriate driver
-fn detect_gpu() -> Box<dyn Gpu> {
-    // TODO: Implement a method for detecting the installed GPU and selecting the appropriate driver.
-    // For example, check the vendor ID and device ID to determine if it's an Nvidia or AMD GPU, or
-    // query the system BIOS to determine the installed GPU.
-    // Then, return a Box<dyn Gpu> that contains the appropriate GPU driver implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
 }
 
-// TODO: Define a function for initializing the GPU and returning a handle to the driver
-fn init_gpu() -> Box<dyn Gpu> {
-    // TODO: Implement a method for initializing the GPU and returning a handle to the driver.
-    // This might involve querying the PCI bus to detect installed GPUs, initializing the video BIOS,
-    // setting up graphics modes, etc. Then, return a Box<dyn Gpu> that contains the appropriate GPU
-    // driver implementation.
+// A rectangle in screen coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    // The part of the rectangle inside a `width` x `height` screen, if any
+    pub fn clip(&self, width: usize, height: usize) -> Option<Rect> {
+        let right = (self.x + self.width).min(width);
+        let bottom = (self.y + self.height).min(height);
+        if self.x >= right || self.y >= bottom {
+            return None;
+        }
+        Some(Rect::new(self.x, self.y, right - self.x, bottom - self.y))
+    }
+}
+
+// A framebuffer in video memory, as described by the boot loader or set up by a driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub address: usize,
+    pub width: usize,
+    pub height: usize,
+    // Bytes from the start of one row to the next
+    pub pitch: usize,
+    pub format: PixelFormat,
+}
+
+impl FramebufferInfo {
+    // Parse a Multiboot2 framebuffer tag; only direct RGB framebuffers are supported
+    pub fn from_multiboot2(tag: &[u8]) -> Option<FramebufferInfo> {
+        let u32_at = |offset: usize| -> Option<u32> { Some(u32::from_le_bytes(tag.get(offset..offset + 4)?.try_into().ok()?)) };
+        if u32_at(0)? != MULTIBOOT2_TAG_FRAMEBUFFER || tag.len() < 38 {
+            return None;
+        }
+        if tag[29] != MULTIBOOT2_FRAMEBUFFER_RGB {
+            return None;
+        }
+        let address = u64::from_le_bytes(tag[8..16].try_into().ok()?) as usize;
+        let bits_per_pixel = tag[28];
+        let channel = |offset: usize| Channel::new(tag[offset], tag[offset + 1]);
+        Some(FramebufferInfo {
+            address,
+            pitch: u32_at(16)? as usize,
+            width: u32_at(20)? as usize,
+            height: u32_at(24)? as usize,
+            format: PixelFormat::new((bits_per_pixel as usize).div_ceil(8), channel(32), channel(34), channel(36)),
+        })
+    }
+
+    // Describe the framebuffer of the current GOP mode; `masks` are the red, green
+    // and blue masks, only used for the bit mask format
+    pub fn from_gop(
+        address: usize,
+        width: usize,
+        height: usize,
+        pixels_per_scan_line: usize,
+        pixel_format: u32,
+        masks: [u32; 3],
+    ) -> Option<FramebufferInfo> {
+        let format = match pixel_format {
+            GOP_RGB_RESERVED_8BIT => PixelFormat::XBGR8888,
+            GOP_BGR_RESERVED_8BIT => PixelFormat::XRGB8888,
+            GOP_BIT_MASK => {
+                let bits = 32 - (masks[0] | masks[1] | masks[2]).leading_zeros();
+                PixelFormat::from_masks(bits.max(16) as u8, masks[0], masks[1], masks[2])?
+            }
+            // Blt-only modes have no framebuffer
+            _ => return None,
+        };
+        Some(FramebufferInfo {
+            address,
+            width,
+            height,
+            pitch: pixels_per_scan_line * format.bytes_per_pixel,
+            format,
+        })
+    }
+
+    pub fn mode(&self) -> Mode {
+        Mode {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+}
+
+// Copy part of a back buffer to video memory, converting to the hardware format.
+// The caller guarantees `info` describes mapped video memory and `back` holds
+// `info.width * info.height` pixels.
+unsafe fn present(info: &FramebufferInfo, back: &[u32], rect: Rect) {
+    let Some(rect) = rect.clip(info.width, info.height) else {
+        return;
+    };
+    let format = info.format;
+    for y in rect.y..rect.y + rect.height {
+        let source = &back[y * info.width + rect.x..y * info.width + rect.x + rect.width];
+        let row = (info.address + y * info.pitch + rect.x * format.bytes_per_pixel) as *mut u8;
+        if format == PixelFormat::XRGB8888 {
+            ptr::copy_nonoverlapping(source.as_ptr() as *const u8, row, source.len() * 4);
+            continue;
+        }
+        for (i, color) in source.iter().enumerate() {
+            let pixel = format.encode(*color);
+            let target = row.add(i * format.bytes_per_pixel);
+            match format.bytes_per_pixel {
+                4 => ptr::write_unaligned(target as *mut u32, pixel),
+                3 => ptr::copy_nonoverlapping(pixel.to_le_bytes().as_ptr(), target, 3),
+                _ => ptr::write_unaligned(target as *mut u16, pixel as u16),
+            }
+        }
+    }
+}
+
+// Interface to a display. Drawing happens in a back buffer of 0x00RRGGBB pixels,
+// `mode().width` pixels per row, which `flush` copies to the screen.
+pub trait Gpu: Send {
+    // Modes `set_mode` accepts
+    fn modes(&self) -> Vec<Mode>;
+
+    // The video memory currently scanned out
+    fn info(&self) -> FramebufferInfo;
+
+    fn set_mode(&mut self, mode: Mode) -> Result<(), GpuError>;
+
+    // The back buffer
+    fn framebuffer(&mut self) -> &mut [u32];
+
+    // Copy part of the back buffer to the screen
+    fn flush(&mut self, rect: Rect);
+
+    fn mode(&self) -> Mode {
+        self.info().mode()
+    }
+
+    fn flush_all(&mut self) {
+        let mode = self.mode();
+        self.flush(Rect::new(0, 0, mode.width, mode.height));
+    }
+
+    fn fill_rect(&mut self, rect: Rect, color: u32) {
+        let mode = self.mode();
+        let Some(rect) = rect.clip(mode.width, mode.height) else {
+            return;
+        };
+        let buffer = self.framebuffer();
+        for y in rect.y..rect.y + rect.height {
+            buffer[y * mode.width + rect.x..y * mode.width + rect.x + rect.width].fill(color);
+        }
+    }
+
+    // Copy `width`-pixel rows from `pixels` to (x, y), clipped to the screen
+    fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[u32]) {
+        if width == 0 {
+            return;
+        }
+        let mode = self.mode();
+        let Some(rect) = Rect::new(x, y, width, pixels.len() / width).clip(mode.width, mode.height) else {
+            return;
+        };
+        let buffer = self.framebuffer();
+        for row in 0..rect.height {
+            let target = (rect.y + row) * mode.width + rect.x;
+            buffer[target..target + rect.width].copy_from_slice(&pixels[row * width..row * width + rect.width]);
+        }
+    }
+
+    // Move the pixels of `source` to (x, y), e.g. to scroll; the areas may overlap
+    fn copy_rect(&mut self, source: Rect, x: usize, y: usize) {
+        let mode = self.mode();
+        let Some(source) = source.clip(mode.width, mode.height) else {
+            return;
+        };
+        let Some(target) = Rect::new(x, y, source.width, source.height).clip(mode.width, mode.height) else {
+            return;
+        };
+        let buffer = self.framebuffer();
+        let rows: Vec<usize> = if target.y > source.y {
+            (0..target.height).rev().collect()
+        } else {
+            (0..target.height).collect()
+        };
+        for row in rows {
+            let from = (source.y + row) * mode.width + source.x;
+            let to = (target.y + row) * mode.width + target.x;
+            buffer.copy_within(from..from + target.width, to);
+        }
+    }
+}
+
+// The framebuffer handed over by the firmware; its mode is fixed
+pub struct LinearFramebuffer {
+    info: FramebufferInfo,
+    back: Vec<u32>,
+}
+
+impl LinearFramebuffer {
+    /// # Safety
+    /// `info` must describe mapped video memory that nothing else writes to
+    pub unsafe fn new(info: FramebufferInfo) -> LinearFramebuffer {
+        LinearFramebuffer {
+            back: vec![0; info.width * info.height],
+            info,
+        }
+    }
+}
+
+impl Gpu for LinearFramebuffer {
+    fn modes(&self) -> Vec<Mode> {
+        vec![self.info.mode()]
+    }
+
+    fn info(&self) -> FramebufferInfo {
+        self.info
+    }
+
+    fn set_mode(&mut self, mode: Mode) -> Result<(), GpuError> {
+        if mode != self.info.mode() {
+            return Err(GpuError::UnsupportedMode);
+        }
+        Ok(())
+    }
+
+    fn framebuffer(&mut self) -> &mut [u32] {
+        &mut self.back
+    }
+
+    fn flush(&mut self, rect: Rect) {
+        unsafe { present(&self.info, &self.back, rect) }
+    }
+}
+
+// How the DISPI registers are reached
+enum DispiRegs {
+    Ports,
+    Mmio(Mmio),
+}
+
+impl DispiRegs {
+    fn read(&self, index: u16) -> u16 {
+        match self {
+            DispiRegs::Ports => unsafe {
+                Port::<u16>::new(VBE_DISPI_IOPORT_INDEX).write(index);
+                Port::<u16>::new(VBE_DISPI_IOPORT_DATA).read()
+            },
+            DispiRegs::Mmio(regs) => regs.read16(VBE_DISPI_MMIO_OFFSET + index as usize * 2),
+        }
+    }
+
+    fn write(&self, index: u16, value: u16) {
+        match self {
+            DispiRegs::Ports => unsafe {
+                Port::<u16>::new(VBE_DISPI_IOPORT_INDEX).write(index);
+                Port::<u16>::new(VBE_DISPI_IOPORT_DATA).write(value);
+            },
+            DispiRegs::Mmio(regs) => regs.write16(VBE_DISPI_MMIO_OFFSET + index as usize * 2, value),
+        }
+    }
+
+    // Make sure the VGA attribute controller is not blanking the output
+    fn unblank(&self) {
+        match self {
+            DispiRegs::Ports => unsafe {
+                // Reading the input status resets the attribute index/data flip-flop
+                Port::<u8>::new(VGA_INPUT_STATUS).read();
+                Port::<u8>::new(VGA_ATTRIBUTE_INDEX).write(VGA_ATTRIBUTE_PALETTE_ENABLE);
+            },
+            // bochs-display has no VGA registers and ignores the write
+            DispiRegs::Mmio(regs) => regs.write8(VBE_VGA_MMIO_OFFSET, VGA_ATTRIBUTE_PALETTE_ENABLE),
+        }
+    }
+}
+
+// The Bochs VBE adapter: QEMU `-vga std`, `-device bochs-display` and VirtualBox VGA
+pub struct BochsGpu {
+    regs: DispiRegs,
+    vram: usize,
+    vram_size: usize,
+    max_width: usize,
+    max_height: usize,
+    info: FramebufferInfo,
+    back: Vec<u32>,
+}
+
+impl BochsGpu {
+    /// # Safety
+    /// `vram` must be the mapped address of the adapter's `vram_size` bytes of video memory
+    unsafe fn new(regs: DispiRegs, vram: usize, vram_size: usize) -> Result<BochsGpu, DeviceError> {
+        let id = regs.read(VBE_DISPI_INDEX_ID);
+        if !(VBE_DISPI_ID0..=VBE_DISPI_ID5).contains(&id) {
+            return Err(DeviceError::ProbeFailed("no Bochs VBE interface"));
+        }
+
+        // With GETCAPS set, the resolution registers report the maximum resolution
+        regs.write(VBE_DISPI_INDEX_ENABLE, VBE_DISPI_GETCAPS);
+        let max_width = regs.read(VBE_DISPI_INDEX_XRES) as usize;
+        let max_height = regs.read(VBE_DISPI_INDEX_YRES) as usize;
+        regs.write(VBE_DISPI_INDEX_ENABLE, 0);
+
+        let mut gpu = BochsGpu {
+            regs,
+            vram,
+            vram_size,
+            max_width,
+            max_height,
+            info: FramebufferInfo {
+                address: vram,
+                width: 0,
+                height: 0,
+                pitch: 0,
+                format: PixelFormat::XRGB8888,
+            },
+            back: Vec::new(),
+        };
+        let (width, height) = BOCHS_DEFAULT_MODE;
+        gpu.set_mode(Mode {
+            width,
+            height,
+            format: PixelFormat::XRGB8888,
+        })?;
+        Ok(gpu)
+    }
+
+    fn supports(&self, mode: &Mode) -> bool {
+        mode.format == PixelFormat::XRGB8888
+            && mode.width > 0
+            && mode.height > 0
+            && mode.width <= self.max_width
+            && mode.height <= self.max_height
+            && mode.width * mode.height * 4 <= self.vram_size
+    }
+}
+
+impl Gpu for BochsGpu {
+    fn modes(&self) -> Vec<Mode> {
+        BOCHS_MODES
+            .iter()
+            .map(|&(width, height)| Mode {
+                width,
+                height,
+                format: PixelFormat::XRGB8888,
+            })
+            .filter(|mode| self.supports(mode))
+            .collect()
+    }
+
+    fn info(&self) -> FramebufferInfo {
+        self.info
+    }
+
+    fn set_mode(&mut self, mode: Mode) -> Result<(), GpuError> {
+        if !self.supports(&mode) {
+            return Err(GpuError::UnsupportedMode);
+        }
+        let regs = &self.regs;
+        regs.write(VBE_DISPI_INDEX_ENABLE, 0);
+        regs.write(VBE_DISPI_INDEX_BPP, 32);
+        regs.write(VBE_DISPI_INDEX_XRES, mode.width as u16);
+        regs.write(VBE_DISPI_INDEX_YRES, mode.height as u16);
+        regs.write(VBE_DISPI_INDEX_VIRT_WIDTH, mode.width as u16);
+        regs.write(VBE_DISPI_INDEX_VIRT_HEIGHT, mode.height as u16);
+        regs.write(VBE_DISPI_INDEX_X_OFFSET, 0);
+        regs.write(VBE_DISPI_INDEX_Y_OFFSET, 0);
+        regs.write(VBE_DISPI_INDEX_ENABLE, VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED);
+        regs.unblank();
+
+        // The adapter may round the line length up
+        let virtual_width = regs.read(VBE_DISPI_INDEX_VIRT_WIDTH) as usize;
+        self.info = FramebufferInfo {
+            address: self.vram,
+            width: mode.width,
+            height: mode.height,
+            pitch: virtual_width.max(mode.width) * 4,
+            format: PixelFormat::XRGB8888,
+        };
+        self.back = vec![0; mode.width * mode.height];
+        self.flush_all();
+        Ok(())
+    }
+
+    fn framebuffer(&mut self) -> &mut [u32] {
+        &mut self.back
+    }
+
+    fn flush(&mut self, rect: Rect) {
+        unsafe { present(&self.info, &self.back, rect) }
+    }
+}
+
+// Bind to the Bochs-compatible display adapters
+static GPU_PCI_IDS: [PciDeviceId; 2] = [
+    PciDeviceId::device(PCI_VENDOR_BOCHS, PCI_DEVICE_BOCHS_VGA),
+    PciDeviceId::device(PCI_VENDOR_VIRTUALBOX, PCI_DEVICE_VIRTUALBOX_VGA),
+];
+
+pub static GPU_PCI_DRIVER: PciDriver = PciDriver {
+    name: "bochs-display",
+    id_table: &GPU_PCI_IDS,
+    probe,
+};
+
+// A bound GPU as registered in the device tree
+pub struct GpuDevice {
+    pub gpu: Mutex<Box<dyn Gpu>>,
+}
+
+impl Device for GpuDevice {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Display
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Register the framebuffer the boot loader set up, until a driver takes over the
+/// adapter behind it
+///
+/// # Safety
+/// `info` must describe mapped video memory that nothing else writes to
+pub unsafe fn add_boot_framebuffer(info: FramebufferInfo) -> String {
+    let gpu: Box<dyn Gpu> = Box::new(LinearFramebuffer::new(info));
+    mouse::set_screen_size(info.width as u32, info.height as u32);
+    device::add_device(Arc::new(GpuDevice { gpu: Mutex::new(gpu) }))
+}
+
+// Change the mode of the first display; the mouse cursor is kept on the new screen
+pub fn set_display_mode(mode: Mode) -> Result<(), GpuError> {
+    with_display(|gpu| gpu.set_mode(mode)).ok_or(GpuError::Device(DeviceError::NotFound))??;
+    mouse::set_screen_size(mode.width as u32, mode.height as u32);
+    Ok(())
+}
+
+// Run `f` with the first display, e.g. to draw a frame
+pub fn with_display<R>(f: impl FnOnce(&mut dyn Gpu) -> R) -> Option<R> {
+    let display = device::first_of_class(DeviceClass::Display)?;
+    let display = display.as_any().downcast_ref::<GpuDevice>()?;
+    let mut gpu = display.gpu.lock();
+    Some(f(gpu.as_mut()))
+}
+
+// Like `with_display`, but gives up instead of waiting if the display or the device
+// registry is in use, for callers in interrupt context
+pub fn try_with_display<R>(f: impl FnOnce(&mut dyn Gpu) -> R) -> Option<R> {
+    let display = device::try_first_of_class(DeviceClass::Display)?;
+    let display = display.as_any().downcast_ref::<GpuDevice>()?;
+    let mut gpu = display.gpu.try_lock()?;
+    Some(f(gpu.as_mut()))
+}
+
+fn probe(device: &PciDevice) -> Result<Arc<dyn Device>, DeviceError> {
+    let (vram, vram_size) = match device.bar(0)? {
+        Bar::Memory { address, size, .. } => (address as usize, size as usize),
+        Bar::Io { .. } => return Err(DeviceError::ProbeFailed("no framebuffer BAR")),
+    };
+    // Newer QEMU adapters expose the registers in BAR2; older ones only through ports
+    let regs = match device.bar(2) {
+        Ok(bar @ Bar::Memory { .. }) => DispiRegs::Mmio(Mmio::from_bar(&bar)?),
+        _ => DispiRegs::Ports,
+    };
+    device.enable();
+
+    // The boot framebuffer lives in the same video memory and stops being valid
+    // once the mode changes. The references from the lookup are dropped before
+    // removing it, as a device still in use cannot be removed.
+    let boot_framebuffers: Vec<String> = device::find_by_class(DeviceClass::Display)
+        .into_iter()
+        .filter(|(_, display)| {
+            display
+                .as_any()
+                .downcast_ref::<GpuDevice>()
+                .is_some_and(|display| (vram..vram + vram_size).contains(&display.gpu.lock().info().address))
+        })
+        .map(|(name, _)| name)
+        .collect();
+    for name in boot_framebuffers {
+        device::remove_device(&name)?;
+    }
+
+    let gpu: Box<dyn Gpu> = Box::new(unsafe { BochsGpu::new(regs, vram, vram_size)? });
+    let mode = gpu.mode();
+    mouse::set_screen_size(mode.width as u32, mode.height as u32);
+    Ok(Arc::new(GpuDevice { gpu: Mutex::new(gpu) }))
 }
@@ -1,44 +1,796 @@
 // Keyboard driver implementation for x86_64 architecture
 // Requires a PS/2 keyboard
 
-use x86_64::instructions::port::Port;
+use crate::drivers::device::{BusDevice, Device, DeviceClass, DeviceError, Driver};
+use crate::drivers::ps2::{Ps2Controller, Ps2Error, Ps2Port, PS2_ACK, PS2_RESEND};
+use crate::kernel::input::{self, InputEventKind, SourceId, SourceKind};
+use core::any::Any;
+use spin::Mutex;
+use std::sync::Arc;
 
-// PS/2 keyboard constants
-const PS2_DATA_PORT: u16 = 0x60;
-const PS2_STATUS_PORT: u16 = 0x64;
-const PS2_ACK: u8 = 0xFA;
+// Keyboard commands
+const KBD_SET_LEDS: u8 = 0xED;
+const KBD_SCANCODE_SET: u8 = 0xF0;
+const KBD_SET_TYPEMATIC: u8 = 0xF3;
+const KBD_ENABLE_SCANNING: u8 = 0xF4;
+
+// LED bits for KBD_SET_LEDS
+const LED_SCROLL_LOCK: u8 = 0x01;
+const LED_NUM_LOCK: u8 = 0x02;
+const LED_CAPS_LOCK: u8 = 0x04;
+
+// Scancode prefixes
+const SCANCODE_EXTENDED: u8 = 0xE0;
+const SCANCODE_PAUSE: u8 = 0xE1;
+const SCANCODE_SET2_RELEASE: u8 = 0xF0;
+
+// Internal key numbers are set 1 make codes; extended keys have the high bit set
+const KEY_EXTENDED: u8 = 0x80;
+
+// Default typematic settings: 500 ms delay, ~10.9 characters per second
+const DEFAULT_TYPEMATIC: u8 = 0x01 << 5 | 0x0B;
+
+// A key, after decoding the scancode and applying the keyboard layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keycode {
+    Char(char),
+    Enter,
+    Backspace,
+    Tab,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    F(u8),
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    AltGr,
+    LeftMeta,
+    RightMeta,
+    Menu,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    PrintScreen,
+    Pause,
+    Unknown(u8),
+}
+
+// Modifier and lock key state at the time of an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub meta: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        leds
+    }
+}
+
+// A key press or release
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub keycode: Keycode,
+    // Layout-independent key number (set 1 make code, 0x80 set for extended keys)
+    pub key: u8,
+    pub released: bool,
+    // Set for presses generated by typematic repeat while the key is held
+    pub repeat: bool,
+    pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    pub fn new(keycode: Keycode, released: bool) -> KeyEvent {
+        KeyEvent {
+            keycode,
+            key: 0,
+            released,
+            repeat: false,
+            modifiers: Modifiers::default(),
+        }
+    }
+}
+
+// Keep `buffer` holding the ASCII characters currently held down, in the order
+// they were pressed, like the key array of a HID boot-protocol report
+pub fn handle_key_event(buffer: &mut [u8], event: &mut KeyEvent) {
+    let byte = match event.keycode {
+        Keycode::Char(c) if c.is_ascii() => c as u8,
+        _ => return,
+    };
+
+    if event.released {
+        if let Some(position) = buffer.iter().position(|&held| held == byte) {
+            buffer.copy_within(position + 1.., position);
+            if let Some(last) = buffer.last_mut() {
+                *last = 0;
+            }
+        }
+    } else if !buffer.contains(&byte) {
+        if let Some(slot) = buffer.iter_mut().find(|held| **held == 0) {
+            *slot = byte;
+        }
+    }
+}
+
+// A keyboard layout: the characters produced by the printable keys.
+// Each row is indexed from its first set 1 make code; '\0' means no character.
+pub struct KeyboardLayout {
+    pub name: &'static str,
+    // [unshifted, shifted, AltGr] for each of the four printable rows
+    rows: [[&'static str; 3]; 4],
+    // [unshifted, shifted, AltGr] for the extra ISO key next to left shift
+    iso_key: [char; 3],
+    // Whether right Alt acts as AltGr rather than a second Alt
+    has_alt_gr: bool,
+}
+
+// First set 1 make code of each printable row
+const ROW_START: [u8; 4] = [0x02, 0x10, 0x1E, 0x2B];
+const ISO_KEY: u8 = 0x56;
+
+pub static LAYOUT_US: KeyboardLayout = KeyboardLayout {
+    name: "us",
+    rows: [
+        ["1234567890-=", "!@#$%^&*()_+", ""],
+        ["qwertyuiop[]", "QWERTYUIOP{}", ""],
+        ["asdfghjkl;'`", "ASDFGHJKL:\"~", ""],
+        ["\\zxcvbnm,./", "|ZXCVBNM<>?", ""],
+    ],
+    iso_key: ['\\', '|', '\0'],
+    has_alt_gr: false,
+};
+
+pub static LAYOUT_UK: KeyboardLayout = KeyboardLayout {
+    name: "uk",
+    rows: [
+        ["1234567890-=", "!\"£$%^&*()_+", "\0\0\0€"],
+        ["qwertyuiop[]", "QWERTYUIOP{}", "\0\0é\0\0\0úíó"],
+        ["asdfghjkl;'`", "ASDFGHJKL:@¬", "á\0\0\0\0\0\0\0\0\0\0¦"],
+        ["#zxcvbnm,./", "~ZXCVBNM<>?", ""],
+    ],
+    iso_key: ['\\', '|', '\0'],
+    has_alt_gr: true,
+};
+
+pub static LAYOUT_DE: KeyboardLayout = KeyboardLayout {
+    name: "de",
+    rows: [
+        ["1234567890ß´", "!\"§$%&/()=?`", "\0²³\0\0\0{[]}\\"],
+        ["qwertzuiopü+", "QWERTZUIOPÜ*", "@\0€\0\0\0\0\0\0\0\0~"],
+        ["asdfghjklöä^", "ASDFGHJKLÖÄ°", ""],
+        ["#yxcvbnm,.-", "'YXCVBNM;:_", "\0\0\0\0\0\0\0µ"],
+    ],
+    iso_key: ['<', '>', '|'],
+    has_alt_gr: true,
+};
+
+pub static LAYOUT_FR: KeyboardLayout = KeyboardLayout {
+    name: "fr",
+    rows: [
+        ["&é\"'(-è_çà)=", "1234567890°+", "\0~#{[|`\\^@]}"],
+        ["azertyuiop^$", "AZERTYUIOP¨£", "\0\0€\0\0\0\0\0\0\0\0¤"],
+        ["qsdfghjklmù²", "QSDFGHJKLM%\0", ""],
+        ["*wxcvbn,;:!", "µWXCVBN?./§", ""],
+    ],
+    iso_key: ['<', '>', '\0'],
+    has_alt_gr: true,
+};
+
+// Look up a built-in layout by name, e.g. from the `keyboard_layout` config option
+pub fn layout_by_name(name: &str) -> Option<&'static KeyboardLayout> {
+    [&LAYOUT_US, &LAYOUT_UK, &LAYOUT_DE, &LAYOUT_FR]
+        .into_iter()
+        .find(|layout| layout.name.eq_ignore_ascii_case(name))
+}
+
+impl KeyboardLayout {
+    // The character for a key number, or None if the key is not printable
+    fn character(&self, key: u8, modifiers: &Modifiers) -> Option<char> {
+        let column = if modifiers.alt_gr && self.has_alt_gr { 2 } else { 0 };
+        let lookup = |column: usize| -> Option<char> {
+            let c = if key == ISO_KEY {
+                self.iso_key[column]
+            } else {
+                let row = ROW_START.iter().rposition(|&start| key >= start)?;
+                self.rows[row][column].chars().nth((key - ROW_START[row]) as usize)?
+            };
+            if c == '\0' {
+                None
+            } else {
+                Some(c)
+            }
+        };
+
+        if column == 2 {
+            return lookup(2);
+        }
+
+        let unshifted = lookup(0)?;
+        // Caps lock only affects letters, and shift cancels it out
+        let mut shifted = modifiers.shift();
+        if modifiers.caps_lock && unshifted.is_alphabetic() {
+            shifted = !shifted;
+        }
+        if shifted {
+            lookup(1).or(Some(unshifted))
+        } else {
+            Some(unshifted)
+        }
+    }
+}
+
+// Map a key number to a layout-independent keycode, or None for printable keys
+fn special_key(key: u8, num_lock: bool) -> Option<Keycode> {
+    let keycode = match key {
+        0x01 => Keycode::Escape,
+        0x0E => Keycode::Backspace,
+        0x0F => Keycode::Tab,
+        0x1C => Keycode::Enter,
+        0x1D => Keycode::LeftCtrl,
+        0x2A => Keycode::LeftShift,
+        0x36 => Keycode::RightShift,
+        0x38 => Keycode::LeftAlt,
+        0x39 => Keycode::Char(' '),
+        0x3A => Keycode::CapsLock,
+        0x3B..=0x44 => Keycode::F(key - 0x3B + 1),
+        0x45 => Keycode::NumLock,
+        0x46 => Keycode::ScrollLock,
+        0x57 => Keycode::F(11),
+        0x58 => Keycode::F(12),
+
+        // Keypad
+        0x37 => Keycode::Char('*'),
+        0x4A => Keycode::Char('-'),
+        0x4E => Keycode::Char('+'),
+        0x47..=0x53 if key != 0x4A && key != 0x4E => {
+            if num_lock {
+                let digits = "789-456+1230.";
+                Keycode::Char(digits.chars().nth((key - 0x47) as usize).unwrap_or('?'))
+            } else {
+                match key {
+                    0x47 => Keycode::Home,
+                    0x48 => Keycode::Up,
+                    0x49 => Keycode::PageUp,
+                    0x4B => Keycode::Left,
+                    0x4D => Keycode::Right,
+                    0x4F => Keycode::End,
+                    0x50 => Keycode::Down,
+                    0x51 => Keycode::PageDown,
+                    0x52 => Keycode::Insert,
+                    0x53 => Keycode::Delete,
+                    _ => Keycode::Unknown(key),
+                }
+            }
+        }
+
+        // Extended keys
+        0x9C => Keycode::Enter,
+        0x9D => Keycode::RightCtrl,
+        0xB5 => Keycode::Char('/'),
+        0xB7 => Keycode::PrintScreen,
+        0xB8 => Keycode::AltGr,
+        0xC5 => Keycode::Pause,
+        0xC7 => Keycode::Home,
+        0xC8 => Keycode::Up,
+        0xC9 => Keycode::PageUp,
+        0xCB => Keycode::Left,
+        0xCD => Keycode::Right,
+        0xCF => Keycode::End,
+        0xD0 => Keycode::Down,
+        0xD1 => Keycode::PageDown,
+        0xD2 => Keycode::Insert,
+        0xD3 => Keycode::Delete,
+        0xDB => Keycode::LeftMeta,
+        0xDC => Keycode::RightMeta,
+        0xDD => Keycode::Menu,
+        _ => return None,
+    };
+    Some(keycode)
+}
+
+// Translate a scancode set 2 code (without prefixes) to its set 1 key number
+fn set2_to_key(code: u8, extended: bool) -> Option<u8> {
+    if extended {
+        let key = match code {
+            0x5A => 0x1C,
+            0x14 => 0x1D,
+            0x4A => 0x35,
+            0x7C => 0x37,
+            0x11 => 0x38,
+            0x6C => 0x47,
+            0x75 => 0x48,
+            0x7D => 0x49,
+            0x6B => 0x4B,
+            0x74 => 0x4D,
+            0x69 => 0x4F,
+            0x72 => 0x50,
+            0x7A => 0x51,
+            0x70 => 0x52,
+            0x71 => 0x53,
+            0x1F => 0x5B,
+            0x27 => 0x5C,
+            0x2F => 0x5D,
+            _ => return None,
+        };
+        return Some(key | KEY_EXTENDED);
+    }
+
+    let key = match code {
+        0x76 => 0x01,
+        0x16 => 0x02,
+        0x1E => 0x03,
+        0x26 => 0x04,
+        0x25 => 0x05,
+        0x2E => 0x06,
+        0x36 => 0x07,
+        0x3D => 0x08,
+        0x3E => 0x09,
+        0x46 => 0x0A,
+        0x45 => 0x0B,
+        0x4E => 0x0C,
+        0x55 => 0x0D,
+        0x66 => 0x0E,
+        0x0D => 0x0F,
+        0x15 => 0x10,
+        0x1D => 0x11,
+        0x24 => 0x12,
+        0x2D => 0x13,
+        0x2C => 0x14,
+        0x35 => 0x15,
+        0x3C => 0x16,
+        0x43 => 0x17,
+        0x44 => 0x18,
+        0x4D => 0x19,
+        0x54 => 0x1A,
+        0x5B => 0x1B,
+        0x5A => 0x1C,
+        0x14 => 0x1D,
+        0x1C => 0x1E,
+        0x1B => 0x1F,
+        0x23 => 0x20,
+        0x2B => 0x21,
+        0x34 => 0x22,
+        0x33 => 0x23,
+        0x3B => 0x24,
+        0x42 => 0x25,
+        0x4B => 0x26,
+        0x4C => 0x27,
+        0x52 => 0x28,
+        0x0E => 0x29,
+        0x12 => 0x2A,
+        0x5D => 0x2B,
+        0x1A => 0x2C,
+        0x22 => 0x2D,
+        0x21 => 0x2E,
+        0x2A => 0x2F,
+        0x32 => 0x30,
+        0x31 => 0x31,
+        0x3A => 0x32,
+        0x41 => 0x33,
+        0x49 => 0x34,
+        0x4A => 0x35,
+        0x59 => 0x36,
+        0x7C => 0x37,
+        0x11 => 0x38,
+        0x29 => 0x39,
+        0x58 => 0x3A,
+        0x05 => 0x3B,
+        0x06 => 0x3C,
+        0x04 => 0x3D,
+        0x0C => 0x3E,
+        0x03 => 0x3F,
+        0x0B => 0x40,
+        0x83 => 0x41,
+        0x0A => 0x42,
+        0x01 => 0x43,
+        0x09 => 0x44,
+        0x77 => 0x45,
+        0x7E => 0x46,
+        0x6C => 0x47,
+        0x75 => 0x48,
+        0x7D => 0x49,
+        0x7B => 0x4A,
+        0x6B => 0x4B,
+        0x73 => 0x4C,
+        0x74 => 0x4D,
+        0x79 => 0x4E,
+        0x69 => 0x4F,
+        0x72 => 0x50,
+        0x7A => 0x51,
+        0x70 => 0x52,
+        0x71 => 0x53,
+        0x61 => 0x56,
+        0x78 => 0x57,
+        0x07 => 0x58,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DecodeState {
+    Start,
+    Extended,
+    Release,
+    ExtendedRelease,
+    // Skipping the remaining bytes of the Pause sequence
+    Pause(u8),
+}
+
+// Turns a stream of scancode bytes into key numbers and press/release flags
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    state: DecodeState,
+}
+
+impl ScancodeDecoder {
+    pub fn new(set: ScancodeSet) -> ScancodeDecoder {
+        ScancodeDecoder {
+            set,
+            state: DecodeState::Start,
+        }
+    }
+
+    // Feed one byte; returns (key number, released) once a full scancode has arrived
+    pub fn feed(&mut self, byte: u8) -> Option<(u8, bool)> {
+        match self.set {
+            ScancodeSet::Set1 => self.feed_set1(byte),
+            ScancodeSet::Set2 => self.feed_set2(byte),
+        }
+    }
+
+    fn feed_set1(&mut self, byte: u8) -> Option<(u8, bool)> {
+        match (self.state, byte) {
+            // Pause has no break code of its own: the press ends the first half of the
+            // sequence and the release the second
+            (DecodeState::Pause(remaining), _) => self.pause_byte(remaining, 4),
+            (DecodeState::Start, SCANCODE_EXTENDED) => {
+                self.state = DecodeState::Extended;
+                None
+            }
+            // E1 1D 45 E1 9D C5
+            (DecodeState::Start, SCANCODE_PAUSE) => {
+                self.state = DecodeState::Pause(5);
+                None
+            }
+            (DecodeState::Extended, _) => {
+                self.state = DecodeState::Start;
+                let key = byte & 0x7F;
+                // Fake shifts surrounding Print Screen and the navigation keys
+                if key == 0x2A || key == 0x36 {
+                    return None;
+                }
+                Some((key | KEY_EXTENDED, byte & 0x80 != 0))
+            }
+            _ => {
+                self.state = DecodeState::Start;
+                Some((byte & 0x7F, byte & 0x80 != 0))
+            }
+        }
+    }
+
+    // One byte of the Pause sequence, with `remaining` bytes left including this one
+    // and the press due when `press_at` are left
+    fn pause_byte(&mut self, remaining: u8, press_at: u8) -> Option<(u8, bool)> {
+        self.state = if remaining > 1 {
+            DecodeState::Pause(remaining - 1)
+        } else {
+            DecodeState::Start
+        };
+        match remaining {
+            1 => Some((0x45 | KEY_EXTENDED, true)),
+            _ if remaining == press_at => Some((0x45 | KEY_EXTENDED, false)),
+            _ => None,
+        }
+    }
+
+    fn feed_set2(&mut self, byte: u8) -> Option<(u8, bool)> {
+        match (self.state, byte) {
+            // E1 14 77 E1 F0 14 F0 77
+            (DecodeState::Pause(remaining), _) => self.pause_byte(remaining, 6),
+            (DecodeState::Start, SCANCODE_PAUSE) => {
+                self.state = DecodeState::Pause(7);
+                None
+            }
+            (DecodeState::Start, SCANCODE_EXTENDED) => {
+                self.state = DecodeState::Extended;
+                None
+            }
+            (DecodeState::Start, SCANCODE_SET2_RELEASE) => {
+                self.state = DecodeState::Release;
+                None
+            }
+            (DecodeState::Extended, SCANCODE_SET2_RELEASE) => {
+                self.state = DecodeState::ExtendedRelease;
+                None
+            }
+            (DecodeState::Extended, _) | (DecodeState::ExtendedRelease, _) => {
+                let released = self.state == DecodeState::ExtendedRelease;
+                self.state = DecodeState::Start;
+                // Fake shifts surrounding Print Screen and the navigation keys
+                if byte == 0x12 || byte == 0x59 {
+                    return None;
+                }
+                set2_to_key(byte, true).map(|key| (key, released))
+            }
+            _ => {
+                let released = self.state == DecodeState::Release;
+                self.state = DecodeState::Start;
+                set2_to_key(byte, false).map(|key| (key, released))
+            }
+        }
+    }
+}
+
+// Layout and modifier handling, independent of the hardware
+pub struct KeyboardState {
+    decoder: ScancodeDecoder,
+    layout: &'static KeyboardLayout,
+    modifiers: Modifiers,
+    pressed: [bool; 256],
+}
+
+impl KeyboardState {
+    pub fn new(set: ScancodeSet, layout: &'static KeyboardLayout) -> KeyboardState {
+        KeyboardState {
+            decoder: ScancodeDecoder::new(set),
+            layout,
+            modifiers: Modifiers::default(),
+            pressed: [false; 256],
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    // Process one scancode byte. Returns the decoded event and whether the LEDs changed.
+    pub fn process(&mut self, byte: u8) -> (Option<KeyEvent>, bool) {
+        let (key, released) = match self.decoder.feed(byte) {
+            Some(decoded) => decoded,
+            None => return (None, false),
+        };
+
+        let repeat = !released && self.pressed[key as usize];
+        self.pressed[key as usize] = !released;
+
+        let keycode = special_key(key, self.modifiers.num_lock)
+            .or_else(|| self.layout.character(key, &self.modifiers).map(Keycode::Char))
+            .unwrap_or(Keycode::Unknown(key));
+
+        let mut leds_changed = false;
+        match keycode {
+            Keycode::LeftShift => self.modifiers.left_shift = !released,
+            Keycode::RightShift => self.modifiers.right_shift = !released,
+            Keycode::LeftCtrl => self.modifiers.left_ctrl = !released,
+            Keycode::RightCtrl => self.modifiers.right_ctrl = !released,
+            Keycode::LeftAlt => self.modifiers.alt = !released,
+            Keycode::AltGr if self.layout.has_alt_gr => self.modifiers.alt_gr = !released,
+            Keycode::AltGr => self.modifiers.alt = !released,
+            Keycode::LeftMeta | Keycode::RightMeta => self.modifiers.meta = !released,
+            // Lock keys toggle on the initial press only, not on typematic repeats
+            Keycode::CapsLock if !released && !repeat => {
+                self.modifiers.caps_lock = !self.modifiers.caps_lock;
+                leds_changed = true;
+            }
+            Keycode::NumLock if !released && !repeat => {
+                self.modifiers.num_lock = !self.modifiers.num_lock;
+                leds_changed = true;
+            }
+            Keycode::ScrollLock if !released && !repeat => {
+                self.modifiers.scroll_lock = !self.modifiers.scroll_lock;
+                leds_changed = true;
+            }
+            _ => {}
+        }
+
+        let event = KeyEvent {
+            keycode,
+            key,
+            released,
+            repeat,
+            modifiers: self.modifiers,
+        };
+        (Some(event), leds_changed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyboardError {
+    Controller(Ps2Error),
+}
+
+impl From<Ps2Error> for KeyboardError {
+    fn from(error: Ps2Error) -> Self {
+        KeyboardError::Controller(error)
+    }
+}
 
 // Keyboard driver struct
 pub struct Keyboard {
-    status_port: Port<u8>,
-    data_port: Port<u8>,
+    controller: Ps2Controller,
+    state: KeyboardState,
+    // LED byte waiting for the keyboard to acknowledge KBD_SET_LEDS
+    pending_leds: Option<u8>,
 }
 
 impl Keyboard {
     // Initialize the keyboard driver
     pub fn new() -> Keyboard {
         Keyboard {
-            status_port: Port::new(PS2_STATUS_PORT),
-            data_port: Port::new(PS2_DATA_PORT),
+            controller: Ps2Controller::new(),
+            state: KeyboardState::new(ScancodeSet::Set1, &LAYOUT_US),
+            pending_leds: None,
         }
     }
 
-    // Check if a key is currently pressed
-    fn is_key_pressed(&mut self) -> bool {
-        self.status_port.read() & 0x01 != 0
+    // Reset the controller and keyboard, select scancode set 2 and enable the IRQ
+    pub fn init(&mut self) -> Result<(), KeyboardError> {
+        self.controller.init()?;
+        self.controller.reset_device(Ps2Port::First)?;
+
+        // Fall back to set 1 through the controller's translation if set 2 is refused
+        let set = if self.controller.send(Ps2Port::First, KBD_SCANCODE_SET).is_ok()
+            && self.controller.send(Ps2Port::First, 2).is_ok()
+        {
+            ScancodeSet::Set2
+        } else {
+            self.controller.set_translation(true)?;
+            ScancodeSet::Set1
+        };
+        self.state = KeyboardState::new(set, self.state.layout);
+
+        self.set_typematic(DEFAULT_TYPEMATIC)?;
+        self.controller.send(Ps2Port::First, KBD_SET_LEDS)?;
+        self.controller.send(Ps2Port::First, self.state.modifiers.leds())?;
+        self.controller.send(Ps2Port::First, KBD_ENABLE_SCANNING)?;
+        self.controller.set_irq(Ps2Port::First, true)?;
+        Ok(())
     }
 
-    // Read a key press from the keyboard
-    pub fn read_key(&mut self) -> Option<u8> {
-        if self.is_key_pressed() {
-            let scancode = self.data_port.read();
+    // Select the keyboard layout used to translate keys to characters
+    pub fn set_layout(&mut self, layout: &'static KeyboardLayout) {
+        self.state.layout = layout;
+    }
 
-            // Send an ACK to the keyboard controller
-            self.data_port.write(PS2_ACK);
+    pub fn layout(&self) -> &'static KeyboardLayout {
+        self.state.layout
+    }
 
-            Some(scancode)
-        } else {
-            None
+    // Set the typematic byte: bits 5-6 select the delay, bits 0-4 the repeat rate
+    pub fn set_typematic(&mut self, typematic: u8) -> Result<(), KeyboardError> {
+        self.controller.send(Ps2Port::First, KBD_SET_TYPEMATIC)?;
+        self.controller.send(Ps2Port::First, typematic & 0x7F)?;
+        Ok(())
+    }
+
+    // Process a byte received from the keyboard, e.g. from the IRQ 1 handler
+    pub fn process_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        // Responses to commands are not scancodes
+        if byte == PS2_ACK {
+            if let Some(leds) = self.pending_leds.take() {
+                let _ = self.controller.write_device(Ps2Port::First, leds);
+            }
+            return None;
         }
+        if byte == PS2_RESEND {
+            return None;
+        }
+
+        let (event, leds_changed) = self.state.process(byte);
+        if leds_changed {
+            // The LED byte is sent once the keyboard acknowledges the command
+            self.pending_leds = Some(self.state.modifiers.leds());
+            let _ = self.controller.write_device(Ps2Port::First, KBD_SET_LEDS);
+        }
+        event
+    }
+
+    // Read a key event from the keyboard, if a byte is waiting
+    pub fn read_key(&mut self) -> Option<KeyEvent> {
+        while self.controller.has_data() && !self.controller.has_aux_data() {
+            let byte = self.controller.read_data_unchecked();
+            if let Some(event) = self.process_byte(byte) {
+                return Some(event);
+            }
+        }
+        None
     }
 }
+
+// A bound keyboard as registered in the device tree
+pub struct KeyboardDevice {
+    pub keyboard: Mutex<Keyboard>,
+    pub source: SourceId,
+}
+
+impl KeyboardDevice {
+    // Drain the keyboard and report its events to the input layer, from the IRQ 1 handler
+    pub fn handle_interrupt(&self) {
+        let mut keyboard = self.keyboard.lock();
+        while let Some(event) = keyboard.read_key() {
+            input::report(self.source, InputEventKind::Key(event));
+        }
+    }
+}
+
+impl Device for KeyboardDevice {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Input
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Driver for the keyboard behind the i8042 PS/2 controller on the platform bus
+pub struct KeyboardDriver;
+
+impl Driver for KeyboardDriver {
+    fn name(&self) -> &'static str {
+        "ps2-keyboard"
+    }
+
+    fn matches(&self, device: &BusDevice) -> bool {
+        matches!(device, BusDevice::Platform { name: "i8042", .. })
+    }
+
+    fn probe(&self, _device: &BusDevice) -> Result<Arc<dyn Device>, DeviceError> {
+        let mut keyboard = Keyboard::new();
+        keyboard
+            .init()
+            .map_err(|_| DeviceError::ProbeFailed("PS/2 keyboard initialization failed"))?;
+        Ok(Arc::new(KeyboardDevice {
+            keyboard: Mutex::new(keyboard),
+            source: input::register_source("PS/2 keyboard", SourceKind::Keyboard),
+        }))
+    }
+}
+
+pub static KEYBOARD_DRIVER: KeyboardDriver = KeyboardDriver;
@@ -0,0 +1,372 @@
+// Mouse driver implementation for x86_64 architecture
+// Supports PS/2 mice (including IntelliMouse wheel and 5-button extensions)
+// and USB HID boot-protocol reports
+
+use crate::drivers::device::{self, BusDevice, Device, DeviceClass, DeviceError, Driver};
+use crate::drivers::gpu;
+use crate::drivers::ps2::{Ps2Controller, Ps2Error, Ps2Port, PS2_ACK};
+use crate::gui::event::{EventType, MouseButton, MouseEvent};
+use crate::kernel::input::{self, InputEventKind, SourceId, SourceKind};
+use core::any::Any;
+use spin::Mutex;
+use std::sync::Arc;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// Mouse commands
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_GET_ID: u8 = 0xF2;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+
+// Device IDs reported by MOUSE_GET_ID
+const MOUSE_ID_STANDARD: u8 = 0x00;
+const MOUSE_ID_WHEEL: u8 = 0x03;
+const MOUSE_ID_FIVE_BUTTON: u8 = 0x04;
+
+// Bits of the first packet byte
+const PACKET_LEFT: u8 = 0x01;
+const PACKET_RIGHT: u8 = 0x02;
+const PACKET_MIDDLE: u8 = 0x04;
+const PACKET_ALWAYS_ONE: u8 = 0x08;
+const PACKET_X_SIGN: u8 = 0x10;
+const PACKET_Y_SIGN: u8 = 0x20;
+const PACKET_X_OVERFLOW: u8 = 0x40;
+const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+// Button state bits, shared by PS/2 packets and HID reports
+const BUTTON_LEFT: u8 = 0x01;
+const BUTTON_RIGHT: u8 = 0x02;
+const BUTTON_MIDDLE: u8 = 0x04;
+const BUTTON_BACK: u8 = 0x08;
+const BUTTON_FORWARD: u8 = 0x10;
+
+// Framebuffer size assumed until the display driver reports the real one
+const DEFAULT_WIDTH: u32 = 800;
+const DEFAULT_HEIGHT: u32 = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MouseType {
+    Standard,
+    Wheel,
+    FiveButton,
+}
+
+impl MouseType {
+    fn packet_size(&self) -> usize {
+        match self {
+            MouseType::Standard => 3,
+            MouseType::Wheel | MouseType::FiveButton => 4,
+        }
+    }
+}
+
+// Absolute cursor position and button state built up from relative reports
+pub struct MouseState {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    buttons: u8,
+}
+
+impl MouseState {
+    pub fn new(width: u32, height: u32) -> MouseState {
+        MouseState {
+            x: width as i32 / 2,
+            y: height as i32 / 2,
+            width,
+            height,
+            buttons: 0,
+        }
+    }
+
+    pub fn position(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    // Change the area the cursor is clamped to, e.g. after a mode set
+    pub fn set_bounds(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.clamp();
+    }
+
+    fn clamp(&mut self) {
+        self.x = self.x.clamp(0, self.width.saturating_sub(1) as i32);
+        self.y = self.y.clamp(0, self.height.saturating_sub(1) as i32);
+    }
+
+    // Apply a relative report. `dy` is positive downwards, in screen orientation.
+    // Returns the events it produces, in the order they should be delivered.
+    pub fn apply(&mut self, dx: i32, dy: i32, buttons: u8, wheel: i8) -> Vec<MouseEvent> {
+        let mut events = Vec::new();
+
+        if dx != 0 || dy != 0 {
+            let (old_x, old_y) = (self.x, self.y);
+            self.x += dx;
+            self.y += dy;
+            self.clamp();
+            if (self.x, self.y) != (old_x, old_y) {
+                events.push(self.event(EventType::MouseMove, None, 0));
+            }
+        }
+
+        let changed = self.buttons ^ buttons;
+        for (bit, button) in [
+            (BUTTON_LEFT, MouseButton::Left),
+            (BUTTON_RIGHT, MouseButton::Right),
+            (BUTTON_MIDDLE, MouseButton::Middle),
+            (BUTTON_BACK, MouseButton::Back),
+            (BUTTON_FORWARD, MouseButton::Forward),
+        ] {
+            if changed & bit != 0 {
+                let event_type = if buttons & bit != 0 {
+                    EventType::MouseDown
+                } else {
+                    EventType::MouseUp
+                };
+                events.push(self.event(event_type, Some(button), 0));
+            }
+        }
+        self.buttons = buttons;
+
+        if wheel != 0 {
+            events.push(self.event(EventType::MouseWheel, None, wheel));
+        }
+
+        events
+    }
+
+    fn event(&self, event_type: EventType, button: Option<MouseButton>, wheel: i8) -> MouseEvent {
+        MouseEvent {
+            event_type,
+            x: self.x,
+            y: self.y,
+            button,
+            wheel,
+        }
+    }
+}
+
+// Decode a complete PS/2 packet into (dx, dy, buttons, wheel) in screen orientation.
+// Returns None for packets that overflowed or lost synchronisation.
+pub fn decode_packet(packet: &[u8], mouse_type: MouseType) -> Option<(i32, i32, u8, i8)> {
+    let flags = packet[0];
+    if flags & PACKET_ALWAYS_ONE == 0 || flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+        return None;
+    }
+
+    // 9-bit two's complement movement; PS/2 Y grows upwards
+    let mut dx = packet[1] as i32;
+    if flags & PACKET_X_SIGN != 0 {
+        dx -= 0x100;
+    }
+    let mut dy = packet[2] as i32;
+    if flags & PACKET_Y_SIGN != 0 {
+        dy -= 0x100;
+    }
+
+    let mut buttons = flags & (PACKET_LEFT | PACKET_RIGHT | PACKET_MIDDLE);
+    let wheel = match mouse_type {
+        MouseType::Standard => 0,
+        // The whole fourth byte is the wheel movement
+        MouseType::Wheel => packet[3] as i8,
+        // Low nibble is a 4-bit signed wheel movement, bits 4-5 are buttons 4 and 5
+        MouseType::FiveButton => {
+            if packet[3] & 0x10 != 0 {
+                buttons |= BUTTON_BACK;
+            }
+            if packet[3] & 0x20 != 0 {
+                buttons |= BUTTON_FORWARD;
+            }
+            ((packet[3] << 4) as i8) >> 4
+        }
+    };
+
+    // PS/2 reports the wheel turned away from the user as negative; it is positive in
+    // `MouseEvent::wheel`, as in HID reports
+    Some((dx, -dy, buttons, wheel.wrapping_neg()))
+}
+
+// PS/2 mouse driver struct
+pub struct Mouse {
+    controller: Ps2Controller,
+    mouse_type: MouseType,
+    packet: [u8; 4],
+    packet_len: usize,
+    state: MouseState,
+}
+
+impl Mouse {
+    pub fn new() -> Mouse {
+        Mouse {
+            controller: Ps2Controller::new(),
+            mouse_type: MouseType::Standard,
+            packet: [0; 4],
+            packet_len: 0,
+            state: MouseState::new(DEFAULT_WIDTH, DEFAULT_HEIGHT),
+        }
+    }
+
+    // Reset the mouse, detect wheel support and enable reporting on IRQ 12.
+    // The controller must already have been initialised by the keyboard driver.
+    pub fn init(&mut self) -> Result<(), Ps2Error> {
+        self.controller.reset_device(Ps2Port::Second)?;
+        // A mouse sends its device ID after the self-test result
+        let _ = self.controller.read_data();
+        self.controller.send(Ps2Port::Second, MOUSE_SET_DEFAULTS)?;
+
+        self.mouse_type = self.detect_type()?;
+
+        self.controller.send(Ps2Port::Second, MOUSE_ENABLE_REPORTING)?;
+        self.controller.set_irq(Ps2Port::Second, true)?;
+        Ok(())
+    }
+
+    // The IntelliMouse "magic knock": particular sample rate sequences switch the
+    // mouse into 4-byte mode, which it confirms by changing its device ID
+    fn detect_type(&mut self) -> Result<MouseType, Ps2Error> {
+        self.set_sample_rates(&[200, 100, 80])?;
+        if self.device_id()? != MOUSE_ID_WHEEL {
+            return Ok(MouseType::Standard);
+        }
+
+        self.set_sample_rates(&[200, 200, 80])?;
+        if self.device_id()? == MOUSE_ID_FIVE_BUTTON {
+            return Ok(MouseType::FiveButton);
+        }
+        Ok(MouseType::Wheel)
+    }
+
+    fn set_sample_rates(&mut self, rates: &[u8]) -> Result<(), Ps2Error> {
+        for &rate in rates {
+            self.controller.send(Ps2Port::Second, MOUSE_SET_SAMPLE_RATE)?;
+            self.controller.send(Ps2Port::Second, rate)?;
+        }
+        Ok(())
+    }
+
+    fn device_id(&mut self) -> Result<u8, Ps2Error> {
+        self.controller.send(Ps2Port::Second, MOUSE_GET_ID)?;
+        let id = self.controller.read_data()?;
+        Ok(if id == MOUSE_ID_WHEEL || id == MOUSE_ID_FIVE_BUTTON {
+            id
+        } else {
+            MOUSE_ID_STANDARD
+        })
+    }
+
+    pub fn mouse_type(&self) -> MouseType {
+        self.mouse_type
+    }
+
+    pub fn state(&mut self) -> &mut MouseState {
+        &mut self.state
+    }
+
+    // Process a byte received from the mouse, e.g. from the IRQ 12 handler.
+    // Returns the events for a completed packet.
+    pub fn process_byte(&mut self, byte: u8) -> Vec<MouseEvent> {
+        // Resynchronise on the always-one bit of the first byte
+        if self.packet_len == 0 && (byte & PACKET_ALWAYS_ONE == 0 || byte == PS2_ACK) {
+            return Vec::new();
+        }
+
+        self.packet[self.packet_len] = byte;
+        self.packet_len += 1;
+        if self.packet_len < self.mouse_type.packet_size() {
+            return Vec::new();
+        }
+        self.packet_len = 0;
+
+        match decode_packet(&self.packet, self.mouse_type) {
+            Some((dx, dy, buttons, wheel)) => self.state.apply(dx, dy, buttons, wheel),
+            None => Vec::new(),
+        }
+    }
+
+    // Drain waiting mouse bytes and return the resulting events
+    pub fn poll(&mut self) -> Vec<MouseEvent> {
+        let mut events = Vec::new();
+        while self.controller.has_aux_data() {
+            let byte = self.controller.read_data_unchecked();
+            events.extend(self.process_byte(byte));
+        }
+        events
+    }
+}
+
+// Process a USB HID boot-protocol mouse report: buttons, dx, dy and an optional wheel byte
+pub fn handle_hid_report(state: &mut MouseState, report: &[u8]) -> Vec<MouseEvent> {
+    if report.len() < 3 {
+        return Vec::new();
+    }
+    let buttons = report[0] & (BUTTON_LEFT | BUTTON_RIGHT | BUTTON_MIDDLE | BUTTON_BACK | BUTTON_FORWARD);
+    let wheel = report.get(3).map_or(0, |&wheel| wheel as i8);
+    state.apply(report[1] as i8 as i32, report[2] as i8 as i32, buttons, wheel)
+}
+
+// A bound mouse as registered in the device tree
+pub struct MouseDevice {
+    pub mouse: Mutex<Mouse>,
+    pub source: SourceId,
+}
+
+impl MouseDevice {
+    // Drain the mouse and report its events to the input layer, from the IRQ 12 handler
+    pub fn handle_interrupt(&self) {
+        for event in self.mouse.lock().poll() {
+            input::report(self.source, InputEventKind::Mouse(event));
+        }
+    }
+}
+
+impl Device for MouseDevice {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Input
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Driver for the mouse on the second port of the i8042 PS/2 controller
+pub struct MouseDriver;
+
+impl Driver for MouseDriver {
+    fn name(&self) -> &'static str {
+        "ps2-mouse"
+    }
+
+    fn matches(&self, device: &BusDevice) -> bool {
+        matches!(device, BusDevice::Platform { name: "i8042-aux", .. })
+    }
+
+    fn probe(&self, _device: &BusDevice) -> Result<Arc<dyn Device>, DeviceError> {
+        let mut mouse = Mouse::new();
+        mouse
+            .init()
+            .map_err(|_| DeviceError::ProbeFailed("PS/2 mouse initialization failed"))?;
+        if let Some(mode) = gpu::with_display(|gpu| gpu.mode()) {
+            mouse.state().set_bounds(mode.width as u32, mode.height as u32);
+        }
+        Ok(Arc::new(MouseDevice {
+            mouse: Mutex::new(mouse),
+            source: input::register_source("PS/2 mouse", SourceKind::Mouse),
+        }))
+    }
+}
+
+pub static MOUSE_DRIVER: MouseDriver = MouseDriver;
+
+// Clamp the cursor of every mouse to a new screen size; called by the display
+// driver whenever the mode changes. The mouse interrupt is held off while the
+// mouse is locked, as its handler takes the same lock.
+pub fn set_screen_size(width: u32, height: u32) {
+    for (_, input) in device::find_by_class(DeviceClass::Input) {
+        if let Some(mouse) = input.as_any().downcast_ref::<MouseDevice>() {
+            without_interrupts(|| mouse.mouse.lock().state().set_bounds(width, height));
+        }
+    }
+}
@@ -1,49 +1,507 @@
 // Network driver implementation for x86_64 architecture
-// Requires a compatible network interface card (NIC)
+// Supports the Intel 8254x (e1000) family, as emulated by QEMU and VirtualBox.
+// Frames move through RX and TX descriptor rings in DMA memory; registers are
+// accessed through the memory BAR.
 
-use x86_64::instructions::port::{Port, PortWriteOnly};
+use crate::drivers::device::{Device, DeviceClass, DeviceError};
+use crate::drivers::dma::{DmaBuffer, Mmio};
+use crate::drivers::pci::{PciDevice, PciDeviceId, PciDriver};
+use crate::net::interface::{
+    self, InterfaceState, InterfaceStats, MacAddress, NetError, NetInterface, ReceiveCallback, ETHERNET_MTU,
+};
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use std::sync::Arc;
 
-// Network card constants
-const NIC_DATA_PORT: u16 = 0x300;
-const NIC_COMMAND_PORT: u16 = 0x301;
+pub const PCI_VENDOR_INTEL: u16 = 0x8086;
 
-// Network driver struct
-pub struct Network {
-    data_port: Port<u8>,
-    command_port: PortWriteOnly<u8>,
+// Interrupt vector the card signals on, through MSI or the routed legacy IRQ
+pub const E1000_INTERRUPT_VECTOR: u8 = 47;
+
+// Registers
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00C0;
+const REG_IMS: usize = 0x00D0;
+const REG_IMC: usize = 0x00D8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL0: usize = 0x5400;
+const REG_RAH0: usize = 0x5404;
+const REGS_SIZE: usize = 0x20000;
+
+const MTA_REGISTERS: usize = 128;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_SHIFT: u32 = 6;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+// Recommended inter-packet gap for copper
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RAH_AV: u32 = 1 << 31;
+
+// Interrupt causes
+const ICR_TXDW: u32 = 1 << 0;
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+
+// Descriptor fields
+const DESC_SIZE: usize = 16;
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+
+const RX_RING_SIZE: usize = 32;
+const TX_RING_SIZE: usize = 32;
+// Receive buffers match the default RCTL buffer size
+const RX_BUFFER_SIZE: usize = 2048;
+
+// Number of polls before a reset or EEPROM read is considered hung
+const E1000_TIMEOUT: usize = 1_000_000;
+
+// Bind to the 82540EM and the closely related 82545EM
+static NETWORK_PCI_IDS: [PciDeviceId; 2] = [
+    PciDeviceId::device(PCI_VENDOR_INTEL, 0x100E),
+    PciDeviceId::device(PCI_VENDOR_INTEL, 0x100F),
+];
+
+pub static NETWORK_PCI_DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    id_table: &NETWORK_PCI_IDS,
+    probe,
+};
+
+// The receive ring and the buffer behind each descriptor
+struct RxRing {
+    descriptors: DmaBuffer,
+    buffers: Vec<DmaBuffer>,
+    next: usize,
+}
+
+impl RxRing {
+    // Give every buffer back to the card behind a clean descriptor
+    fn reset(&mut self) {
+        for (i, buffer) in self.buffers.iter().enumerate() {
+            self.descriptors.write::<u64>(i * DESC_SIZE, buffer.phys_addr());
+            self.descriptors.write::<u64>(i * DESC_SIZE + 8, 0);
+        }
+        self.next = 0;
+    }
+}
+
+// The transmit ring; a slot's buffer is kept until the card reports it sent
+struct TxRing {
+    descriptors: DmaBuffer,
+    buffers: Vec<Option<DmaBuffer>>,
+    next: usize,
+    clean: usize,
+}
+
+impl TxRing {
+    // Empty the ring, dropping frames that were queued but not sent
+    fn reset(&mut self) {
+        for i in 0..TX_RING_SIZE {
+            self.descriptors.write::<u64>(i * DESC_SIZE, 0);
+            self.descriptors.write::<u64>(i * DESC_SIZE + 8, 0);
+        }
+        self.buffers.iter_mut().for_each(|buffer| *buffer = None);
+        self.next = 0;
+        self.clean = 0;
+    }
 }
 
-impl Network {
-    // Initialize the network driver
-    pub fn new() -> Network {
-        Network {
-            data_port: Port::new(NIC_DATA_PORT),
-            command_port: PortWriteOnly::new(NIC_COMMAND_PORT),
+// Receive filtering state, applied to RCTL and the multicast table
+struct Filter {
+    promiscuous: bool,
+    all_multicast: bool,
+    multicast: Vec<[u8; 6]>,
+}
+
+pub struct E1000 {
+    regs: Mmio,
+    rx: Mutex<RxRing>,
+    tx: Mutex<TxRing>,
+    filter: Mutex<Filter>,
+    state: InterfaceState,
+    interrupts: bool,
+    // Set by the interrupt handler when the rings have work to collect
+    pending: AtomicBool,
+    mac: MacAddress,
+}
+
+impl E1000 {
+    pub fn new(regs: Mmio, interrupts: bool) -> Result<E1000, DeviceError> {
+        // Mask interrupts around the reset, as the datasheet requires
+        regs.write32(REG_IMC, !0);
+        regs.write32(REG_CTRL, regs.read32(REG_CTRL) | CTRL_RST);
+        (0..E1000_TIMEOUT)
+            .find(|_| regs.read32(REG_CTRL) & CTRL_RST == 0)
+            .ok_or(DeviceError::Timeout)?;
+        regs.write32(REG_IMC, !0);
+        regs.read32(REG_ICR);
+
+        let mac = read_mac(&regs);
+
+        let rx_descriptors = DmaBuffer::new(RX_RING_SIZE * DESC_SIZE, 128)?;
+        let rx_buffers = (0..RX_RING_SIZE)
+            .map(|_| DmaBuffer::new(RX_BUFFER_SIZE, 16))
+            .collect::<Result<Vec<_>, _>>()?;
+        let tx_descriptors = DmaBuffer::new(TX_RING_SIZE * DESC_SIZE, 128)?;
+
+        let nic = E1000 {
+            regs,
+            rx: Mutex::new(RxRing {
+                descriptors: rx_descriptors,
+                buffers: rx_buffers,
+                next: 0,
+            }),
+            tx: Mutex::new(TxRing {
+                descriptors: tx_descriptors,
+                buffers: (0..TX_RING_SIZE).map(|_| None).collect(),
+                next: 0,
+                clean: 0,
+            }),
+            filter: Mutex::new(Filter {
+                promiscuous: false,
+                all_multicast: false,
+                multicast: Vec::new(),
+            }),
+            state: InterfaceState::new(),
+            interrupts,
+            pending: AtomicBool::new(false),
+            mac,
+        };
+        nic.configure();
+        Ok(nic)
+    }
+
+    // Program the address filter and rings, then bring the link up. The rings start
+    // over from empty, as the card's head and tail pointers are reset with them.
+    fn configure(&self) {
+        let regs = &self.regs;
+        regs.write32(REG_RAL0, u32::from_le_bytes([self.mac[0], self.mac[1], self.mac[2], self.mac[3]]));
+        regs.write32(REG_RAH0, u16::from_le_bytes([self.mac[4], self.mac[5]]) as u32 | RAH_AV);
+
+        {
+            let mut rx = self.rx.lock();
+            rx.reset();
+            let address = rx.descriptors.phys_addr();
+            regs.write32(REG_RDBAL, address as u32);
+            regs.write32(REG_RDBAH, (address >> 32) as u32);
+            regs.write32(REG_RDLEN, (RX_RING_SIZE * DESC_SIZE) as u32);
+            regs.write32(REG_RDH, 0);
+            // Every descriptor but one is handed to the card; head == tail means full
+            regs.write32(REG_RDT, (RX_RING_SIZE - 1) as u32);
+        }
+        {
+            let mut tx = self.tx.lock();
+            tx.reset();
+            let address = tx.descriptors.phys_addr();
+            regs.write32(REG_TDBAL, address as u32);
+            regs.write32(REG_TDBAH, (address >> 32) as u32);
+            regs.write32(REG_TDLEN, (TX_RING_SIZE * DESC_SIZE) as u32);
+            regs.write32(REG_TDH, 0);
+            regs.write32(REG_TDT, 0);
+        }
+        regs.write32(REG_TIPG, TIPG_DEFAULT);
+        regs.write32(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        // Receiving is enabled last, once the card knows where the ring is
+        self.apply_filter();
+
+        regs.write32(REG_CTRL, regs.read32(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+        if self.interrupts {
+            regs.write32(REG_IMS, ICR_TXDW | ICR_LSC | ICR_RXDMT0 | ICR_RXO | ICR_RXT0);
         }
     }
 
-    // Read data from the network card
-    pub fn read(&mut self, buffer: &mut [u8]) {
-        for byte in buffer {
-            *byte = self.data_port.read();
+    // Negotiated link speed in Mbit/s, if the link is up
+    pub fn link_speed(&self) -> Option<u32> {
+        if !self.link_up() {
+            return None;
         }
+        Some(match (self.regs.read32(REG_STATUS) >> STATUS_SPEED_SHIFT) & 0x3 {
+            0 => 10,
+            1 => 100,
+            _ => 1000,
+        })
     }
 
-    // Write data to the network card
-    pub fn write(&mut self, buffer: &[u8]) {
-        for byte in buffer {
-            self.data_port.write(*byte);
+    // Accept every frame on the wire, not just those addressed to us
+    pub fn set_promiscuous(&self, enabled: bool) {
+        self.filter.lock().promiscuous = enabled;
+        self.apply_filter();
+    }
+
+    // Accept every multicast frame instead of only the subscribed groups
+    pub fn set_all_multicast(&self, enabled: bool) {
+        self.filter.lock().all_multicast = enabled;
+        self.apply_filter();
+    }
+
+    pub fn add_multicast(&self, address: [u8; 6]) {
+        let mut filter = self.filter.lock();
+        if !filter.multicast.contains(&address) {
+            filter.multicast.push(address);
         }
+        drop(filter);
+        self.apply_filter();
     }
 
-    // Reset the network card
-    pub fn reset(&mut self) {
-        self.command_port.write(0x80);
-        self.command_port.write(0x00);
+    pub fn remove_multicast(&self, address: [u8; 6]) {
+        self.filter.lock().multicast.retain(|group| *group != address);
+        self.apply_filter();
     }
 
-    // Configure the network card
-    pub fn configure(&mut self) {
-        // Set up the network card here
+    // Rebuild the multicast hash table and the receive control register
+    fn apply_filter(&self) {
+        let filter = self.filter.lock();
+        let mut table = [0u32; MTA_REGISTERS];
+        for address in filter.multicast.iter() {
+            let hash = multicast_hash(address);
+            table[hash >> 5] |= 1 << (hash & 0x1F);
+        }
+        for (i, bits) in table.iter().enumerate() {
+            self.regs.write32(REG_MTA + i * 4, *bits);
+        }
+
+        let mut rctl = RCTL_EN | RCTL_BAM | RCTL_SECRC;
+        if filter.promiscuous {
+            rctl |= RCTL_UPE | RCTL_MPE;
+        } else if filter.all_multicast {
+            rctl |= RCTL_MPE;
+        }
+        self.regs.write32(REG_RCTL, rctl);
+    }
+
+    // Acknowledge the interrupt causes and note that the rings have work. The rings
+    // are left to the next poll, as the code interrupted may hold their locks and the
+    // stack must not be entered from an interrupt handler. Returns whether the link
+    // status changed.
+    pub fn handle_interrupt(&self) -> bool {
+        let cause = self.regs.read32(REG_ICR);
+        self.pending.store(true, Ordering::Release);
+        cause & ICR_LSC != 0
     }
+
+    // Return completed receive descriptors to the card and deliver their frames;
+    // called by poll
+    fn receive_frames(&self) {
+        let mut frames = Vec::new();
+        let mut rx = self.rx.lock();
+        let mut frame = Vec::new();
+        loop {
+            let desc = rx.next * DESC_SIZE;
+            let status = rx.descriptors.read::<u8>(desc + 12);
+            if status & RX_STATUS_DD == 0 {
+                break;
+            }
+            let len = rx.descriptors.read::<u16>(desc + 8) as usize;
+            let errors = rx.descriptors.read::<u8>(desc + 13);
+            frame.extend_from_slice(&rx.buffers[rx.next].as_slice()[..len.min(RX_BUFFER_SIZE)]);
+
+            // Frames longer than a buffer span descriptors; only the last has EOP set
+            if status & RX_STATUS_EOP != 0 {
+                let frame = core::mem::take(&mut frame);
+                if errors == 0 {
+                    frames.push(frame);
+                } else {
+                    self.state.dropped();
+                }
+            }
+
+            rx.descriptors.write::<u8>(desc + 12, 0);
+            let tail = rx.next;
+            rx.next = (rx.next + 1) % RX_RING_SIZE;
+            self.regs.write32(REG_RDT, tail as u32);
+        }
+        drop(rx);
+
+        // Deliver with the ring unlocked, since the stack may answer right away
+        for frame in frames {
+            self.state.deliver(frame);
+        }
+    }
+
+    // Release the buffers of frames the card has sent
+    fn reclaim_tx(&self) {
+        let mut tx = self.tx.lock();
+        while tx.clean != tx.next {
+            let desc = tx.clean * DESC_SIZE;
+            if tx.descriptors.read::<u8>(desc + 12) & TX_STATUS_DD == 0 {
+                break;
+            }
+            let clean = tx.clean;
+            tx.buffers[clean] = None;
+            tx.clean = (clean + 1) % TX_RING_SIZE;
+        }
+    }
+
+    // Queue a frame for transmission; fails with `Busy` when the ring is full
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        interface::check_frame(self, frame)?;
+        self.reclaim_tx();
+
+        let mut buffer = DmaBuffer::new(frame.len(), 16)?;
+        buffer.as_mut_slice().copy_from_slice(frame);
+
+        let mut tx = self.tx.lock();
+        let next = (tx.next + 1) % TX_RING_SIZE;
+        if next == tx.clean {
+            return Err(NetError::Busy);
+        }
+        let slot = tx.next;
+        let desc = slot * DESC_SIZE;
+        tx.descriptors.write::<u64>(desc, buffer.phys_addr());
+        tx.descriptors.write::<u16>(desc + 8, frame.len() as u16);
+        tx.descriptors.write::<u8>(desc + 10, 0);
+        tx.descriptors.write::<u8>(desc + 11, TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS);
+        tx.descriptors.write::<u8>(desc + 12, 0);
+        tx.buffers[slot] = Some(buffer);
+        tx.next = next;
+        self.regs.write32(REG_TDT, next as u32);
+        Ok(())
+    }
+}
+
+impl NetInterface for E1000 {
+    fn mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.regs.read32(REG_STATUS) & STATUS_LU != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        let result = self.transmit(frame);
+        self.state.sent(&result, frame.len());
+        result
+    }
+
+    fn set_receive_callback(&self, callback: Option<ReceiveCallback>) {
+        self.state.set_callback(callback);
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        self.poll();
+        self.state.take()
+    }
+
+    // Without interrupts the rings are checked on every poll
+    fn poll(&self) {
+        if !self.interrupts || self.pending.swap(false, Ordering::AcqRel) {
+            self.receive_frames();
+            self.reclaim_tx();
+        }
+    }
+
+    fn stats(&self) -> InterfaceStats {
+        self.state.stats()
+    }
+}
+
+impl Device for E1000 {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Network
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn suspend(&self) -> Result<(), DeviceError> {
+        self.regs.write32(REG_IMC, !0);
+        self.regs.write32(REG_RCTL, 0);
+        self.regs.write32(REG_TCTL, 0);
+        Ok(())
+    }
+
+    fn resume(&self) -> Result<(), DeviceError> {
+        self.configure();
+        Ok(())
+    }
+}
+
+// Read the MAC address from the EEPROM, falling back to the receive address
+// registers the firmware may have programmed
+fn read_mac(regs: &Mmio) -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    for word in 0..3 {
+        match read_eeprom(regs, word) {
+            Some(value) => mac[word as usize * 2..word as usize * 2 + 2].copy_from_slice(&value.to_le_bytes()),
+            None => {
+                let low = regs.read32(REG_RAL0).to_le_bytes();
+                let high = regs.read32(REG_RAH0).to_le_bytes();
+                return [low[0], low[1], low[2], low[3], high[0], high[1]];
+            }
+        }
+    }
+    mac
+}
+
+fn read_eeprom(regs: &Mmio, word: u8) -> Option<u16> {
+    regs.write32(REG_EERD, (word as u32) << 8 | EERD_START);
+    for _ in 0..E1000_TIMEOUT {
+        let value = regs.read32(REG_EERD);
+        if value & EERD_DONE != 0 {
+            return Some((value >> 16) as u16);
+        }
+    }
+    None
+}
+
+// Index into the 4096-bit multicast table, using bits 47:36 of the address
+fn multicast_hash(address: &[u8; 6]) -> usize {
+    ((address[4] as usize >> 4) | (address[5] as usize) << 4) & 0xFFF
+}
+
+// Probe an e1000 card
+fn probe(device: &PciDevice) -> Result<Arc<dyn Device>, DeviceError> {
+    let regs = Mmio::from_bar(&device.bar(0)?)?.subregion(0, REGS_SIZE);
+    device.enable();
+    let interrupts = device.enable_msi(E1000_INTERRUPT_VECTOR, 0).is_ok();
+    let nic = Arc::new(E1000::new(regs, interrupts)?);
+    interface::add_ethernet(nic.clone());
+    Ok(nic)
 }
@@ -0,0 +1,690 @@
+// PCI/PCIe bus driver implementation for x86_64 architecture
+// Supports legacy port I/O configuration access and PCIe ECAM
+
+use crate::drivers::device::{Bus, BusDevice, Device, DeviceError, Driver};
+use crate::mm::memory::Frame;
+use crate::mm::paging::{PageTableManager, PAGE_SIZE};
+use core::ptr;
+use spin::Mutex;
+use std::sync::Arc;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+// Configuration mechanism #1 ports
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+// Configuration space register offsets
+const PCI_VENDOR_ID: u16 = 0x00;
+const PCI_DEVICE_ID: u16 = 0x02;
+const PCI_COMMAND: u16 = 0x04;
+const PCI_STATUS: u16 = 0x06;
+const PCI_REVISION_ID: u16 = 0x08;
+const PCI_PROG_IF: u16 = 0x09;
+const PCI_SUBCLASS: u16 = 0x0A;
+const PCI_CLASS: u16 = 0x0B;
+const PCI_HEADER_TYPE: u16 = 0x0E;
+const PCI_BAR0: u16 = 0x10;
+const PCI_SECONDARY_BUS: u16 = 0x19;
+const PCI_CAPABILITIES_PTR: u16 = 0x34;
+const PCI_INTERRUPT_LINE: u16 = 0x3C;
+const PCI_INTERRUPT_PIN: u16 = 0x3D;
+
+// Command register bits
+pub const PCI_COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const PCI_COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+// Status register bits
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+// Capability IDs
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_VENDOR: u8 = 0x09;
+pub const PCI_CAP_ID_PCIE: u8 = 0x10;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+// Header types
+const PCI_HEADER_TYPE_NORMAL: u8 = 0x00;
+const PCI_HEADER_TYPE_BRIDGE: u8 = 0x01;
+const PCI_HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+
+// Class codes used while scanning
+const PCI_CLASS_BRIDGE: u8 = 0x06;
+const PCI_SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+// Local APIC message address used for MSI/MSI-X
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+// Sentinel for "no device" in the vendor ID register
+const PCI_VENDOR_NONE: u16 = 0xFFFF;
+
+const PCI_MAX_DEVICES: u8 = 32;
+const PCI_MAX_FUNCTIONS: u8 = 8;
+
+/// Errors reported by the PCI subsystem
+#[derive(Debug, Clone, PartialEq)]
+pub enum PciError {
+    NotInitialized,
+    NoSuchBar,
+    NoCapability,
+    InvalidVector,
+    MapFailed,
+    ProbeFailed(&'static str),
+}
+
+/// The bus/device/function triple identifying a PCI function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress {
+            segment: 0,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+/// A way of reaching PCI configuration space
+pub trait ConfigAccess: Send {
+    fn read32(&mut self, address: PciAddress, offset: u16) -> u32;
+    fn write32(&mut self, address: PciAddress, offset: u16, value: u32);
+
+    fn read16(&mut self, address: PciAddress, offset: u16) -> u16 {
+        (self.read32(address, offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+    }
+
+    fn read8(&mut self, address: PciAddress, offset: u16) -> u8 {
+        (self.read32(address, offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+    }
+
+    fn write16(&mut self, address: PciAddress, offset: u16, value: u16) {
+        let shift = (offset & 0x2) * 8;
+        let old = self.read32(address, offset & !0x3);
+        let new = (old & !(0xFFFF << shift)) | ((value as u32) << shift);
+        self.write32(address, offset & !0x3, new);
+    }
+
+    fn write8(&mut self, address: PciAddress, offset: u16, value: u8) {
+        let shift = (offset & 0x3) * 8;
+        let old = self.read32(address, offset & !0x3);
+        let new = (old & !(0xFF << shift)) | ((value as u32) << shift);
+        self.write32(address, offset & !0x3, new);
+    }
+}
+
+/// Legacy configuration mechanism #1 through ports 0xCF8/0xCFC.
+/// Only the first 256 bytes of configuration space are reachable.
+pub struct PortIoAccess {
+    address_port: Port<u32>,
+    data_port: Port<u32>,
+}
+
+impl PortIoAccess {
+    pub fn new() -> PortIoAccess {
+        PortIoAccess {
+            address_port: Port::new(PCI_CONFIG_ADDRESS),
+            data_port: Port::new(PCI_CONFIG_DATA),
+        }
+    }
+
+    fn select(&mut self, address: PciAddress, offset: u16) {
+        let value = 0x8000_0000
+            | (address.bus as u32) << 16
+            | (address.device as u32) << 11
+            | (address.function as u32) << 8
+            | (offset as u32 & 0xFC);
+        unsafe {
+            self.address_port.write(value);
+        }
+    }
+}
+
+impl ConfigAccess for PortIoAccess {
+    fn read32(&mut self, address: PciAddress, offset: u16) -> u32 {
+        if offset >= 0x100 {
+            return 0xFFFF_FFFF;
+        }
+        self.select(address, offset);
+        unsafe { self.data_port.read() }
+    }
+
+    fn write32(&mut self, address: PciAddress, offset: u16, value: u32) {
+        if offset >= 0x100 {
+            return;
+        }
+        self.select(address, offset);
+        unsafe {
+            self.data_port.write(value);
+        }
+    }
+}
+
+/// PCIe enhanced configuration access through a memory-mapped ECAM window.
+/// `base` is the virtual address the MCFG region has been mapped at.
+pub struct EcamAccess {
+    base: usize,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl EcamAccess {
+    pub fn new(base: usize, start_bus: u8, end_bus: u8) -> EcamAccess {
+        EcamAccess {
+            base,
+            start_bus,
+            end_bus,
+        }
+    }
+
+    fn register(&self, address: PciAddress, offset: u16) -> Option<usize> {
+        if address.bus < self.start_bus || address.bus > self.end_bus || offset >= 0x1000 {
+            return None;
+        }
+        let bus = (address.bus - self.start_bus) as usize;
+        Some(
+            self.base
+                + (bus << 20)
+                + ((address.device as usize) << 15)
+                + ((address.function as usize) << 12)
+                + (offset as usize & 0xFFC),
+        )
+    }
+}
+
+impl ConfigAccess for EcamAccess {
+    fn read32(&mut self, address: PciAddress, offset: u16) -> u32 {
+        match self.register(address, offset) {
+            Some(register) => unsafe { ptr::read_volatile(register as *const u32) },
+            None => 0xFFFF_FFFF,
+        }
+    }
+
+    fn write32(&mut self, address: PciAddress, offset: u16, value: u32) {
+        if let Some(register) = self.register(address, offset) {
+            unsafe { ptr::write_volatile(register as *mut u32, value) }
+        }
+    }
+}
+
+/// A decoded base address register
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+/// A PCI function discovered while scanning the bus
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    pub fn read_config32(&self, offset: u16) -> u32 {
+        with_config(|access| access.read32(self.address, offset)).unwrap_or(0xFFFF_FFFF)
+    }
+
+    pub fn read_config16(&self, offset: u16) -> u16 {
+        with_config(|access| access.read16(self.address, offset)).unwrap_or(0xFFFF)
+    }
+
+    pub fn read_config8(&self, offset: u16) -> u8 {
+        with_config(|access| access.read8(self.address, offset)).unwrap_or(0xFF)
+    }
+
+    pub fn write_config32(&self, offset: u16, value: u32) {
+        with_config(|access| access.write32(self.address, offset, value));
+    }
+
+    pub fn write_config16(&self, offset: u16, value: u16) {
+        with_config(|access| access.write16(self.address, offset, value));
+    }
+
+    pub fn write_config8(&self, offset: u16, value: u8) {
+        with_config(|access| access.write8(self.address, offset, value));
+    }
+
+    // Enable memory/IO decoding and bus mastering so the device can DMA
+    pub fn enable(&self) {
+        let command = self.read_config16(PCI_COMMAND);
+        self.write_config16(
+            PCI_COMMAND,
+            command | PCI_COMMAND_IO_SPACE | PCI_COMMAND_MEMORY_SPACE | PCI_COMMAND_BUS_MASTER,
+        );
+    }
+
+    pub fn bar(&self, index: usize) -> Result<Bar, PciError> {
+        self.bars.get(index).copied().flatten().ok_or(PciError::NoSuchBar)
+    }
+
+    // Walk the capability list and return the offset of the first capability with the given ID
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().find(|&(cap_id, _)| cap_id == id).map(|(_, offset)| offset)
+    }
+
+    // Iterate over (capability ID, offset) pairs
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let mut offset = if self.read_config16(PCI_STATUS) & PCI_STATUS_CAP_LIST != 0 {
+            (self.read_config8(PCI_CAPABILITIES_PTR) & 0xFC) as u16
+        } else {
+            0
+        };
+        // Guard against malformed, looping capability lists
+        let mut remaining = 48;
+        core::iter::from_fn(move || {
+            if offset == 0 || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let id = self.read_config8(offset);
+            let current = offset;
+            offset = (self.read_config8(offset + 1) & 0xFC) as u16;
+            Some((id, current))
+        })
+    }
+
+    // Route a single MSI vector to the given local APIC
+    pub fn enable_msi(&self, vector: u8, apic_id: u8) -> Result<(), PciError> {
+        if vector < 32 {
+            return Err(PciError::InvalidVector);
+        }
+        let cap = self.find_capability(PCI_CAP_ID_MSI).ok_or(PciError::NoCapability)?;
+        let control = self.read_config16(cap + 2);
+        let is_64bit = control & (1 << 7) != 0;
+
+        self.write_config32(cap + 4, MSI_ADDRESS_BASE | (apic_id as u32) << 12);
+        if is_64bit {
+            self.write_config32(cap + 8, 0);
+            self.write_config16(cap + 12, vector as u16);
+        } else {
+            self.write_config16(cap + 8, vector as u16);
+        }
+
+        // Request a single message and enable MSI
+        let control = (control & !(0x7 << 4)) | 1;
+        self.write_config16(cap + 2, control);
+        self.disable_intx();
+        Ok(())
+    }
+
+    // Return the MSI-X table BAR index, offset within it and number of entries
+    pub fn msix_table(&self) -> Option<(usize, u32, u16)> {
+        let cap = self.find_capability(PCI_CAP_ID_MSIX)?;
+        let control = self.read_config16(cap + 2);
+        let table = self.read_config32(cap + 4);
+        Some(((table & 0x7) as usize, table & !0x7, (control & 0x7FF) + 1))
+    }
+
+    // Program MSI-X entries and enable MSI-X. `table` is the virtual address of the
+    // MSI-X table, i.e. the mapped BAR from `msix_table` plus its offset.
+    pub fn enable_msix(&self, table: usize, vectors: &[(u16, u8)], apic_id: u8) -> Result<(), PciError> {
+        let cap = self.find_capability(PCI_CAP_ID_MSIX).ok_or(PciError::NoCapability)?;
+        let control = self.read_config16(cap + 2);
+        let entries = (control & 0x7FF) + 1;
+
+        // Mask the whole function while the table is being written
+        self.write_config16(cap + 2, control | 1 << 14 | 1 << 15);
+        for &(index, vector) in vectors {
+            if index >= entries || vector < 32 {
+                self.write_config16(cap + 2, control);
+                return Err(PciError::InvalidVector);
+            }
+            let entry = (table + index as usize * 16) as *mut u32;
+            unsafe {
+                ptr::write_volatile(entry, MSI_ADDRESS_BASE | (apic_id as u32) << 12);
+                ptr::write_volatile(entry.add(1), 0);
+                ptr::write_volatile(entry.add(2), vector as u32);
+                ptr::write_volatile(entry.add(3), 0);
+            }
+        }
+        self.write_config16(cap + 2, (control | 1 << 15) & !(1 << 14));
+        self.disable_intx();
+        Ok(())
+    }
+
+    fn disable_intx(&self) {
+        let command = self.read_config16(PCI_COMMAND);
+        self.write_config16(PCI_COMMAND, command | PCI_COMMAND_INTX_DISABLE);
+    }
+}
+
+// Identity-map a memory BAR as uncached MMIO and return its virtual address
+pub fn map_bar(
+    bar: &Bar,
+    page_table: &mut PageTableManager,
+    allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, PciError> {
+    let (address, size) = match *bar {
+        Bar::Memory { address, size, .. } => (address, size),
+        Bar::Io { .. } => return Err(PciError::MapFailed),
+    };
+
+    let start = address & !(PAGE_SIZE as u64 - 1);
+    let end = address + size;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    for page_address in (start..end).step_by(PAGE_SIZE) {
+        let page = Page::containing_address(VirtAddr::new(page_address));
+        let frame = Frame::from_start_address(page_address as usize);
+        match page_table.map_to(page, frame, flags, allocator) {
+            Ok(()) | Err("Page already mapped") => {}
+            Err(_) => return Err(PciError::MapFailed),
+        }
+    }
+
+    Ok(address as usize)
+}
+
+/// Matches devices by vendor/device ID and/or class code. `None` matches anything.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDeviceId {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciDeviceId {
+    pub const fn device(vendor_id: u16, device_id: u16) -> PciDeviceId {
+        PciDeviceId {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    pub const fn vendor_class(vendor_id: u16, class: u8) -> PciDeviceId {
+        PciDeviceId {
+            vendor_id: Some(vendor_id),
+            device_id: None,
+            class: Some(class),
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> PciDeviceId {
+        PciDeviceId {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    pub const fn class_prog_if(class: u8, subclass: u8, prog_if: u8) -> PciDeviceId {
+        PciDeviceId {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: Some(prog_if),
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |id| id == device.vendor_id)
+            && self.device_id.map_or(true, |id| id == device.device_id)
+            && self.class.map_or(true, |class| class == device.class)
+            && self.subclass.map_or(true, |subclass| subclass == device.subclass)
+            && self.prog_if.map_or(true, |prog_if| prog_if == device.prog_if)
+    }
+}
+
+/// A driver that can bind to PCI functions
+pub struct PciDriver {
+    pub name: &'static str,
+    pub id_table: &'static [PciDeviceId],
+    pub probe: fn(&PciDevice) -> Result<Arc<dyn Device>, DeviceError>,
+}
+
+impl Driver for PciDriver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn matches(&self, device: &BusDevice) -> bool {
+        match device {
+            BusDevice::Pci(device) => self.id_table.iter().any(|id| id.matches(device)),
+            _ => false,
+        }
+    }
+
+    fn probe(&self, device: &BusDevice) -> Result<Arc<dyn Device>, DeviceError> {
+        match device {
+            BusDevice::Pci(device) => (self.probe)(device),
+            _ => Err(DeviceError::NotSupported),
+        }
+    }
+}
+
+/// The PCI bus as seen by the device model
+pub struct PciBus;
+
+impl Bus for PciBus {
+    fn name(&self) -> &'static str {
+        "pci"
+    }
+
+    fn enumerate(&self) -> Vec<BusDevice> {
+        devices().into_iter().map(BusDevice::Pci).collect()
+    }
+}
+
+pub static PCI_BUS: PciBus = PciBus;
+
+// Configuration space accessor selected at boot
+static CONFIG_ACCESS: Mutex<Option<Box<dyn ConfigAccess>>> = Mutex::new(None);
+
+// Devices found by the last scan
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+fn with_config<R>(f: impl FnOnce(&mut dyn ConfigAccess) -> R) -> Option<R> {
+    let mut access = CONFIG_ACCESS.lock();
+    access.as_mut().map(|access| f(access.as_mut()))
+}
+
+// Initialize the PCI subsystem and scan every bus. Drivers are bound by the device manager.
+pub fn init(access: Box<dyn ConfigAccess>) -> Result<(), PciError> {
+    *CONFIG_ACCESS.lock() = Some(access);
+    let devices = scan()?;
+    *DEVICES.lock() = devices;
+    Ok(())
+}
+
+// Return a snapshot of the devices found on the bus
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+// Scan the whole hierarchy starting at the host bridge(s)
+pub fn scan() -> Result<Vec<PciDevice>, PciError> {
+    let mut devices = Vec::new();
+    let host = with_config(|access| access.read8(PciAddress::new(0, 0, 0), PCI_HEADER_TYPE))
+        .ok_or(PciError::NotInitialized)?;
+
+    if host & PCI_HEADER_TYPE_MULTIFUNCTION == 0 {
+        scan_bus(0, &mut devices);
+    } else {
+        // Multiple host controllers: function N handles bus N
+        for function in 0..PCI_MAX_FUNCTIONS {
+            let address = PciAddress::new(0, 0, function);
+            let vendor = with_config(|access| access.read16(address, PCI_VENDOR_ID)).unwrap_or(PCI_VENDOR_NONE);
+            if vendor != PCI_VENDOR_NONE {
+                scan_bus(function, &mut devices);
+            }
+        }
+    }
+
+    Ok(devices)
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..PCI_MAX_DEVICES {
+        scan_slot(bus, device, devices);
+    }
+}
+
+fn scan_slot(bus: u8, device: u8, devices: &mut Vec<PciDevice>) {
+    let address = PciAddress::new(bus, device, 0);
+    let vendor = with_config(|access| access.read16(address, PCI_VENDOR_ID)).unwrap_or(PCI_VENDOR_NONE);
+    if vendor == PCI_VENDOR_NONE {
+        return;
+    }
+
+    scan_function(address, devices);
+    let header_type = with_config(|access| access.read8(address, PCI_HEADER_TYPE)).unwrap_or(0);
+    if header_type & PCI_HEADER_TYPE_MULTIFUNCTION != 0 {
+        for function in 1..PCI_MAX_FUNCTIONS {
+            let address = PciAddress::new(bus, device, function);
+            let vendor = with_config(|access| access.read16(address, PCI_VENDOR_ID)).unwrap_or(PCI_VENDOR_NONE);
+            if vendor != PCI_VENDOR_NONE {
+                scan_function(address, devices);
+            }
+        }
+    }
+}
+
+fn scan_function(address: PciAddress, devices: &mut Vec<PciDevice>) {
+    let device = match with_config(|access| read_function(access, address)) {
+        Some(device) => device,
+        None => return,
+    };
+
+    // Recurse into the secondary bus behind PCI-to-PCI bridges
+    let is_bridge = device.class == PCI_CLASS_BRIDGE
+        && device.subclass == PCI_SUBCLASS_PCI_BRIDGE
+        && device.header_type & 0x7F == PCI_HEADER_TYPE_BRIDGE;
+    devices.push(device);
+
+    if is_bridge {
+        let secondary = with_config(|access| access.read8(address, PCI_SECONDARY_BUS)).unwrap_or(0);
+        if secondary > address.bus {
+            scan_bus(secondary, devices);
+        }
+    }
+}
+
+fn read_function(access: &mut dyn ConfigAccess, address: PciAddress) -> PciDevice {
+    let header_type = access.read8(address, PCI_HEADER_TYPE);
+    let bar_count = match header_type & 0x7F {
+        PCI_HEADER_TYPE_NORMAL => 6,
+        PCI_HEADER_TYPE_BRIDGE => 2,
+        _ => 0,
+    };
+
+    PciDevice {
+        address,
+        vendor_id: access.read16(address, PCI_VENDOR_ID),
+        device_id: access.read16(address, PCI_DEVICE_ID),
+        revision: access.read8(address, PCI_REVISION_ID),
+        class: access.read8(address, PCI_CLASS),
+        subclass: access.read8(address, PCI_SUBCLASS),
+        prog_if: access.read8(address, PCI_PROG_IF),
+        header_type,
+        interrupt_line: access.read8(address, PCI_INTERRUPT_LINE),
+        interrupt_pin: access.read8(address, PCI_INTERRUPT_PIN),
+        bars: read_bars(access, address, bar_count),
+    }
+}
+
+// Decode and size the BARs of a function
+fn read_bars(access: &mut dyn ConfigAccess, address: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+
+    // Stop the device decoding while the BARs hold all-ones
+    let command = access.read16(address, PCI_COMMAND);
+    access.write16(address, PCI_COMMAND, command & !(PCI_COMMAND_IO_SPACE | PCI_COMMAND_MEMORY_SPACE));
+
+    let mut index = 0;
+    while index < count {
+        let offset = PCI_BAR0 + index as u16 * 4;
+        let original = access.read32(address, offset);
+        access.write32(address, offset, 0xFFFF_FFFF);
+        let mask = access.read32(address, offset);
+        access.write32(address, offset, original);
+
+        if mask == 0 {
+            index += 1;
+            continue;
+        }
+
+        if original & 0x1 != 0 {
+            let size = (!(mask & !0x3)).wrapping_add(1) & 0xFFFF;
+            bars[index] = Some(Bar::Io {
+                port: (original & !0x3) as u16,
+                size,
+            });
+            index += 1;
+            continue;
+        }
+
+        let prefetchable = original & 0x8 != 0;
+        let is_64bit = (original >> 1) & 0x3 == 0x2;
+        let mut base = (original & !0xF) as u64;
+        let mut size_mask = (mask & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+
+        if is_64bit && index + 1 < count {
+            let high_offset = offset + 4;
+            let original_high = access.read32(address, high_offset);
+            access.write32(address, high_offset, 0xFFFF_FFFF);
+            let mask_high = access.read32(address, high_offset);
+            access.write32(address, high_offset, original_high);
+
+            base |= (original_high as u64) << 32;
+            size_mask = (size_mask & 0xFFFF_FFFF) | (mask_high as u64) << 32;
+        }
+
+        bars[index] = Some(Bar::Memory {
+            address: base,
+            size: (!size_mask).wrapping_add(1),
+            prefetchable,
+            is_64bit,
+        });
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    access.write16(address, PCI_COMMAND, command);
+    bars
+}
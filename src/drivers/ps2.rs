@@ -0,0 +1,236 @@
+// PS/2 (i8042) controller implementation for x86_64 architecture
+// Shared by the PS/2 keyboard and mouse drivers
+
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+// Controller ports
+pub const PS2_DATA_PORT: u16 = 0x60;
+pub const PS2_STATUS_PORT: u16 = 0x64;
+pub const PS2_COMMAND_PORT: u16 = 0x64;
+
+// Status register bits
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+const STATUS_AUX_DATA: u8 = 0x20;
+
+// Controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+const CMD_WRITE_PORT2: u8 = 0xD4;
+
+// Configuration byte bits
+const CONFIG_PORT1_IRQ: u8 = 0x01;
+const CONFIG_PORT2_IRQ: u8 = 0x02;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 0x20;
+const CONFIG_TRANSLATION: u8 = 0x40;
+
+// Device responses
+pub const PS2_ACK: u8 = 0xFA;
+pub const PS2_RESEND: u8 = 0xFE;
+pub const PS2_SELF_TEST_PASSED: u8 = 0xAA;
+const CONTROLLER_SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Number of status polls before giving up on the controller
+const PS2_TIMEOUT: usize = 100_000;
+const PS2_RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ps2Error {
+    Timeout,
+    ControllerSelfTestFailed,
+    PortTestFailed(Ps2Port),
+    DeviceSelfTestFailed,
+    NoAck(u8),
+    NoSecondPort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+pub struct Ps2Controller {
+    data_port: Port<u8>,
+    status_port: Port<u8>,
+    command_port: PortWriteOnly<u8>,
+}
+
+impl Ps2Controller {
+    pub fn new() -> Ps2Controller {
+        Ps2Controller {
+            data_port: Port::new(PS2_DATA_PORT),
+            status_port: Port::new(PS2_STATUS_PORT),
+            command_port: PortWriteOnly::new(PS2_COMMAND_PORT),
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status_port.read() }
+    }
+
+    // Check whether a byte is waiting in the output buffer
+    pub fn has_data(&mut self) -> bool {
+        self.status() & STATUS_OUTPUT_FULL != 0
+    }
+
+    // Check whether the waiting byte came from the second (auxiliary) port
+    pub fn has_aux_data(&mut self) -> bool {
+        self.status() & (STATUS_OUTPUT_FULL | STATUS_AUX_DATA) == STATUS_OUTPUT_FULL | STATUS_AUX_DATA
+    }
+
+    // Read a byte without waiting; the caller must have checked `has_data`
+    pub fn read_data_unchecked(&mut self) -> u8 {
+        unsafe { self.data_port.read() }
+    }
+
+    // Wait for a byte in the output buffer and read it
+    pub fn read_data(&mut self) -> Result<u8, Ps2Error> {
+        for _ in 0..PS2_TIMEOUT {
+            if self.has_data() {
+                return Ok(self.read_data_unchecked());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..PS2_TIMEOUT {
+            if self.status() & STATUS_INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn write_data(&mut self, value: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.data_port.write(value) };
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.command_port.write(command) };
+        Ok(())
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.command(CMD_READ_CONFIG)?;
+        self.read_data()
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(CMD_WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    // Drop anything left in the output buffer
+    pub fn flush(&mut self) {
+        for _ in 0..16 {
+            if !self.has_data() {
+                break;
+            }
+            self.read_data_unchecked();
+        }
+    }
+
+    // Reset and test the controller, leaving both ports enabled with interrupts
+    // and scancode translation off. Returns whether a second port is present.
+    pub fn init(&mut self) -> Result<bool, Ps2Error> {
+        self.command(CMD_DISABLE_PORT1)?;
+        self.command(CMD_DISABLE_PORT2)?;
+        self.flush();
+
+        let mut config = self.read_config()?;
+        config &= !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        self.command(CMD_SELF_TEST)?;
+        if self.read_data()? != CONTROLLER_SELF_TEST_PASSED {
+            return Err(Ps2Error::ControllerSelfTestFailed);
+        }
+        // Some controllers reset the configuration byte during the self test
+        self.write_config(config)?;
+
+        // A dual-channel controller clears the port 2 clock-disable bit once it is enabled
+        self.command(CMD_ENABLE_PORT2)?;
+        let has_second_port = self.read_config()? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+        self.command(CMD_DISABLE_PORT2)?;
+
+        self.command(CMD_TEST_PORT1)?;
+        if self.read_data()? != PORT_TEST_PASSED {
+            return Err(Ps2Error::PortTestFailed(Ps2Port::First));
+        }
+        if has_second_port {
+            self.command(CMD_TEST_PORT2)?;
+            if self.read_data()? != PORT_TEST_PASSED {
+                return Err(Ps2Error::PortTestFailed(Ps2Port::Second));
+            }
+        }
+
+        self.command(CMD_ENABLE_PORT1)?;
+        if has_second_port {
+            self.command(CMD_ENABLE_PORT2)?;
+        }
+        Ok(has_second_port)
+    }
+
+    // Enable or disable the interrupt for a port
+    pub fn set_irq(&mut self, port: Ps2Port, enabled: bool) -> Result<(), Ps2Error> {
+        let bit = match port {
+            Ps2Port::First => CONFIG_PORT1_IRQ,
+            Ps2Port::Second => CONFIG_PORT2_IRQ,
+        };
+        let config = self.read_config()?;
+        self.write_config(if enabled { config | bit } else { config & !bit })
+    }
+
+    // Enable or disable the controller's set 2 to set 1 scancode translation
+    pub fn set_translation(&mut self, enabled: bool) -> Result<(), Ps2Error> {
+        let config = self.read_config()?;
+        self.write_config(if enabled {
+            config | CONFIG_TRANSLATION
+        } else {
+            config & !CONFIG_TRANSLATION
+        })
+    }
+
+    // Write a byte to the device on a port without waiting for a response
+    pub fn write_device(&mut self, port: Ps2Port, value: u8) -> Result<(), Ps2Error> {
+        if port == Ps2Port::Second {
+            self.command(CMD_WRITE_PORT2)?;
+        }
+        self.write_data(value)
+    }
+
+    // Send a byte to a device and wait for it to be acknowledged, resending on request
+    pub fn send(&mut self, port: Ps2Port, value: u8) -> Result<(), Ps2Error> {
+        for _ in 0..PS2_RETRIES {
+            self.write_device(port, value)?;
+            match self.read_data()? {
+                PS2_ACK => return Ok(()),
+                PS2_RESEND => continue,
+                _ => return Err(Ps2Error::NoAck(value)),
+            }
+        }
+        Err(Ps2Error::NoAck(value))
+    }
+
+    // Reset a device and wait for its self-test result
+    pub fn reset_device(&mut self, port: Ps2Port) -> Result<(), Ps2Error> {
+        self.send(port, 0xFF)?;
+        if self.read_data()? != PS2_SELF_TEST_PASSED {
+            return Err(Ps2Error::DeviceSelfTestFailed);
+        }
+        Ok(())
+    }
+}
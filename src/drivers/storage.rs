@@ -1,60 +1,622 @@
-// Storage driver implementation for x86_64 architecture
-// Requires a compatible storage device
+// ATA storage driver for x86_64 architecture
+// Drives are accessed with polled PIO transfers using LBA28 or LBA48 addressing
 
-use x86_64::instructions::port::{Port, PortWriteOnly};
+use crate::drivers::device::{self, Device, DeviceClass, DeviceError};
+use crate::drivers::pci::{Bar, PciDevice, PciDeviceId, PciDriver, PciError};
+use crate::storage::block::{self, BlockDevice};
+use core::any::Any;
+use spin::Mutex;
+use std::sync::Arc;
+use x86_64::instructions::port::Port;
 
-// Storage device constants
-const STORAGE_DATA_PORT: u16 = 0x1F0;
-const STORAGE_COMMAND_PORT: u16 = 0x1F7;
+pub const SECTOR_SIZE: usize = 512;
 
-// Storage driver struct
-pub struct Storage {
-    data_port: Port<u8>,
-    command_port: PortWriteOnly<u8>,
+// Legacy (compatibility mode) channel ports
+pub const PRIMARY_IO_BASE: u16 = 0x1F0;
+pub const PRIMARY_CONTROL_BASE: u16 = 0x3F6;
+pub const SECONDARY_IO_BASE: u16 = 0x170;
+pub const SECONDARY_CONTROL_BASE: u16 = 0x376;
+
+// Task file registers, as offsets from the I/O base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_FEATURES: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE_HEAD: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+// Status register bits
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+// Error register bits
+const ERROR_AMNF: u8 = 0x01;
+const ERROR_TKZNF: u8 = 0x02;
+const ERROR_ABRT: u8 = 0x04;
+const ERROR_MCR: u8 = 0x08;
+const ERROR_IDNF: u8 = 0x10;
+const ERROR_MC: u8 = 0x20;
+const ERROR_UNC: u8 = 0x40;
+const ERROR_BBK: u8 = 0x80;
+
+// Device control register bits
+const CONTROL_NIEN: u8 = 0x02;
+const CONTROL_SRST: u8 = 0x04;
+
+// Commands
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_FLUSH_CACHE: u8 = 0xE7;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// Largest transfer a single command can describe
+const LBA28_MAX_SECTORS: usize = 256;
+const LBA48_MAX_SECTORS: usize = 65536;
+const LBA28_LIMIT: u64 = 1 << 28;
+
+// Number of status polls before a command is considered hung
+const ATA_TIMEOUT: usize = 1_000_000;
+
+// Bind to PCI IDE controllers
+static STORAGE_PCI_IDS: [PciDeviceId; 1] = [PciDeviceId::class(0x01, 0x01)];
+
+pub static STORAGE_PCI_DRIVER: PciDriver = PciDriver {
+    name: "ata",
+    id_table: &STORAGE_PCI_IDS,
+    probe,
+};
+
+// The reason a command failed, decoded from the error register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    AddressMarkNotFound,
+    Track0NotFound,
+    Aborted,
+    MediaChangeRequest,
+    IdNotFound,
+    MediaChanged,
+    Uncorrectable,
+    BadBlock,
+    Unknown(u8),
+}
+
+impl CommandError {
+    // Decode the error register, reporting the most specific bit that is set
+    pub fn from_register(error: u8) -> CommandError {
+        if error & ERROR_BBK != 0 {
+            CommandError::BadBlock
+        } else if error & ERROR_UNC != 0 {
+            CommandError::Uncorrectable
+        } else if error & ERROR_IDNF != 0 {
+            CommandError::IdNotFound
+        } else if error & ERROR_AMNF != 0 {
+            CommandError::AddressMarkNotFound
+        } else if error & ERROR_TKZNF != 0 {
+            CommandError::Track0NotFound
+        } else if error & ERROR_MC != 0 {
+            CommandError::MediaChanged
+        } else if error & ERROR_MCR != 0 {
+            CommandError::MediaChangeRequest
+        } else if error & ERROR_ABRT != 0 {
+            CommandError::Aborted
+        } else {
+            CommandError::Unknown(error)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    // Nothing answered on the selected drive
+    NoDevice,
+    // The drive is ATAPI or SATA and does not speak the ATA PIO protocol
+    NotAta,
+    Timeout,
+    DeviceFault,
+    OutOfRange,
+    InvalidBuffer,
+    Command(CommandError),
+}
+
+impl From<AtaError> for DeviceError {
+    fn from(error: AtaError) -> Self {
+        match error {
+            AtaError::NoDevice | AtaError::NotAta => DeviceError::NotFound,
+            AtaError::Timeout => DeviceError::Timeout,
+            _ => DeviceError::IoError,
+        }
+    }
+}
+
+impl From<AtaError> for block::Error {
+    fn from(error: AtaError) -> Self {
+        match error {
+            AtaError::OutOfRange => block::Error::InvalidBlockId,
+            AtaError::InvalidBuffer => block::Error::InvalidBufferSize,
+            _ => block::Error::Device(error.into()),
+        }
+    }
+}
+
+// A drive position on a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+impl Drive {
+    fn select_bit(&self) -> u8 {
+        match self {
+            Drive::Master => 0x00,
+            Drive::Slave => 0x10,
+        }
+    }
+}
+
+// The parts of the IDENTIFY DEVICE data the driver uses
+#[derive(Debug, Clone)]
+pub struct IdentifyData {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub lba48: bool,
+    pub sectors: u64,
+    pub flush_cache_ext: bool,
+    // Native command queuing support and the number of commands the drive accepts
+    pub ncq: bool,
+    pub queue_depth: usize,
+}
+
+impl IdentifyData {
+    pub fn parse(words: &[u16; 256]) -> IdentifyData {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let lba28_sectors = words[60] as u64 | (words[61] as u64) << 16;
+        let lba48_sectors = (0..4).fold(0u64, |sectors, i| sectors | (words[100 + i] as u64) << (16 * i));
+
+        IdentifyData {
+            model: identify_string(&words[27..47]),
+            serial: identify_string(&words[10..20]),
+            firmware: identify_string(&words[23..27]),
+            lba48,
+            sectors: if lba48 && lba48_sectors != 0 { lba48_sectors } else { lba28_sectors },
+            flush_cache_ext: words[83] & (1 << 13) != 0,
+            ncq: words[76] & (1 << 8) != 0,
+            queue_depth: (words[75] & 0x1F) as usize + 1,
+        }
+    }
+}
+
+// IDENTIFY strings store two characters per word, high byte first, padded with spaces
+fn identify_string(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+// One ATA channel with up to two drives sharing its task file
+pub struct AtaChannel {
+    io_base: u16,
+    control_base: u16,
+    selected: Option<Drive>,
 }
 
-impl Storage {
-    // Initialize the storage driver
-    pub fn new() -> Storage {
-        Storage {
-            data_port: Port::new(STORAGE_DATA_PORT),
-            command_port: PortWriteOnly::new(STORAGE_COMMAND_PORT),
+impl AtaChannel {
+    pub fn new(io_base: u16, control_base: u16) -> AtaChannel {
+        AtaChannel {
+            io_base,
+            control_base,
+            selected: None,
+        }
+    }
+
+    pub fn primary() -> AtaChannel {
+        AtaChannel::new(PRIMARY_IO_BASE, PRIMARY_CONTROL_BASE)
+    }
+
+    pub fn secondary() -> AtaChannel {
+        AtaChannel::new(SECONDARY_IO_BASE, SECONDARY_CONTROL_BASE)
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base + reg).read() }
+    }
+
+    fn write_reg(&mut self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base + reg).write(value) }
+    }
+
+    fn read_data(&self) -> u16 {
+        unsafe { Port::<u16>::new(self.io_base + REG_DATA).read() }
+    }
+
+    fn write_data(&mut self, value: u16) {
+        unsafe { Port::<u16>::new(self.io_base + REG_DATA).write(value) }
+    }
+
+    // Reading the alternate status register does not acknowledge interrupts
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control_base).read() }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        unsafe { Port::<u8>::new(self.control_base).write(value) }
+    }
+
+    // Each status read takes roughly 100ns, so four reads give the drive the 400ns
+    // it needs to put a valid status on the bus after a select or command
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    // Reset both drives on the channel and leave interrupts disabled, since the
+    // driver polls for completion
+    pub fn soft_reset(&mut self) -> Result<(), AtaError> {
+        self.write_control(CONTROL_SRST | CONTROL_NIEN);
+        self.delay_400ns();
+        self.write_control(CONTROL_NIEN);
+        self.delay_400ns();
+        self.selected = None;
+        self.wait_not_busy()
+    }
+
+    // Select a drive for the following commands
+    pub fn select(&mut self, drive: Drive) -> Result<(), AtaError> {
+        self.select_with_head(drive, 0)
+    }
+
+    fn select_with_head(&mut self, drive: Drive, head: u8) -> Result<(), AtaError> {
+        // The LBA bits in the drive/head register change with every LBA28 command,
+        // so the register is always written, but the delay is only needed on a switch
+        self.wait_not_busy()?;
+        self.write_reg(REG_DRIVE_HEAD, 0xE0 | drive.select_bit() | (head & 0x0F));
+        if self.selected != Some(drive) {
+            self.delay_400ns();
+            self.selected = Some(drive);
+        }
+        Ok(())
+    }
+
+    fn wait_not_busy(&self) -> Result<(), AtaError> {
+        for _ in 0..ATA_TIMEOUT {
+            if self.alt_status() & STATUS_BSY == 0 {
+                return Ok(());
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    // Wait until the drive is ready to transfer a sector, or report why it is not
+    fn wait_drq(&self) -> Result<(), AtaError> {
+        for _ in 0..ATA_TIMEOUT {
+            let status = self.alt_status();
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            self.check_status(status)?;
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    // Wait for a non-data command to complete
+    fn wait_complete(&self) -> Result<(), AtaError> {
+        self.wait_not_busy()?;
+        // Reading the regular status register also acknowledges a pending interrupt
+        self.check_status(self.read_reg(REG_STATUS))
+    }
+
+    fn check_status(&self, status: u8) -> Result<(), AtaError> {
+        decode_status(status, || self.read_reg(REG_ERROR))
+    }
+
+    // Run IDENTIFY DEVICE on a drive
+    pub fn identify(&mut self, drive: Drive) -> Result<IdentifyData, AtaError> {
+        // A floating bus reads as all ones when no drive is attached to the channel
+        if self.alt_status() == 0xFF {
+            return Err(AtaError::NoDevice);
+        }
+        self.select(drive)?;
+        self.write_reg(REG_SECTOR_COUNT, 0);
+        self.write_reg(REG_LBA_LOW, 0);
+        self.write_reg(REG_LBA_MID, 0);
+        self.write_reg(REG_LBA_HIGH, 0);
+        self.write_reg(REG_COMMAND, CMD_IDENTIFY);
+        self.delay_400ns();
+
+        if self.read_reg(REG_STATUS) == 0 {
+            return Err(AtaError::NoDevice);
+        }
+        self.wait_not_busy()?;
+
+        // ATAPI and SATA devices abort IDENTIFY and leave their signature in the LBA registers
+        if self.read_reg(REG_LBA_MID) != 0 || self.read_reg(REG_LBA_HIGH) != 0 {
+            return Err(AtaError::NotAta);
+        }
+        self.wait_drq()?;
+
+        let mut words = [0u16; 256];
+        for word in words.iter_mut() {
+            *word = self.read_data();
         }
+        Ok(IdentifyData::parse(&words))
     }
 
-    // Read data from the storage device
-    pub fn read(&mut self, buffer: &mut [u8]) {
-        for sector in buffer.chunks_exact_mut(512) {
-            self.read_sector(sector);
+    // Load the task file for a transfer of `count` sectors at `lba`
+    fn setup_transfer(&mut self, drive: Drive, lba: u64, count: usize, lba48: bool) -> Result<(), AtaError> {
+        if lba48 {
+            self.select_with_head(drive, 0)?;
+            // The high-order bytes are written first; each register is a two-deep FIFO
+            self.write_reg(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write_reg(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write_reg(REG_LBA_MID, (lba >> 32) as u8);
+            self.write_reg(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select_with_head(drive, (lba >> 24) as u8)?;
+            self.write_reg(REG_FEATURES, 0);
         }
+        // A count of 0 means 256 sectors for LBA28 and 65536 for LBA48
+        self.write_reg(REG_SECTOR_COUNT, count as u8);
+        self.write_reg(REG_LBA_LOW, lba as u8);
+        self.write_reg(REG_LBA_MID, (lba >> 8) as u8);
+        self.write_reg(REG_LBA_HIGH, (lba >> 16) as u8);
+        Ok(())
     }
 
-    // Write data to the storage device
-    pub fn write(&mut self, buffer: &[u8]) {
-        for sector in buffer.chunks_exact(512) {
-            self.write_sector(sector);
+    // Read whole sectors in a single command; `buffer` holds at most 256 (LBA28)
+    // or 65536 (LBA48) sectors
+    pub fn read_sectors(&mut self, drive: Drive, lba: u64, buffer: &mut [u8], lba48: bool) -> Result<(), AtaError> {
+        let count = check_transfer(lba, buffer.len(), lba48)?;
+        self.setup_transfer(drive, lba, count, lba48)?;
+        self.write_reg(REG_COMMAND, if lba48 { CMD_READ_SECTORS_EXT } else { CMD_READ_SECTORS });
+        self.delay_400ns();
+
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            self.wait_drq()?;
+            for bytes in sector.chunks_exact_mut(2) {
+                bytes.copy_from_slice(&self.read_data().to_le_bytes());
+            }
+            self.delay_400ns();
+        }
+        self.wait_complete()
+    }
+
+    // Write whole sectors in a single command, with the same limits as `read_sectors`.
+    // The data may still sit in the drive's write cache until `flush_cache` is called.
+    pub fn write_sectors(&mut self, drive: Drive, lba: u64, buffer: &[u8], lba48: bool) -> Result<(), AtaError> {
+        let count = check_transfer(lba, buffer.len(), lba48)?;
+        self.setup_transfer(drive, lba, count, lba48)?;
+        self.write_reg(REG_COMMAND, if lba48 { CMD_WRITE_SECTORS_EXT } else { CMD_WRITE_SECTORS });
+        self.delay_400ns();
+
+        for sector in buffer.chunks_exact(SECTOR_SIZE) {
+            self.wait_drq()?;
+            for bytes in sector.chunks_exact(2) {
+                self.write_data(u16::from_le_bytes([bytes[0], bytes[1]]));
+            }
+            self.delay_400ns();
         }
+        self.wait_complete()
+    }
+
+    // Write the drive's cache out to the media
+    pub fn flush_cache(&mut self, drive: Drive, ext: bool) -> Result<(), AtaError> {
+        self.select(drive)?;
+        self.write_reg(REG_COMMAND, if ext { CMD_FLUSH_CACHE_EXT } else { CMD_FLUSH_CACHE });
+        self.delay_400ns();
+        self.wait_complete()
+    }
+}
+
+// Turn a status register value into the error it reports; the error register is
+// only read when the status says it is valid
+pub fn decode_status(status: u8, error: impl FnOnce() -> u8) -> Result<(), AtaError> {
+    if status & STATUS_DF != 0 {
+        Err(AtaError::DeviceFault)
+    } else if status & STATUS_ERR != 0 {
+        Err(AtaError::Command(CommandError::from_register(error())))
+    } else {
+        Ok(())
+    }
+}
+
+// Validate a transfer and return its sector count
+pub fn check_transfer(lba: u64, len: usize, lba48: bool) -> Result<usize, AtaError> {
+    if len == 0 || len % SECTOR_SIZE != 0 {
+        return Err(AtaError::InvalidBuffer);
+    }
+    let count = len / SECTOR_SIZE;
+    let (max_sectors, limit) = if lba48 {
+        (LBA48_MAX_SECTORS, 1 << 48)
+    } else {
+        (LBA28_MAX_SECTORS, LBA28_LIMIT)
+    };
+    if count > max_sectors {
+        return Err(AtaError::InvalidBuffer);
     }
+    if lba + count as u64 > limit {
+        return Err(AtaError::OutOfRange);
+    }
+    Ok(count)
+}
 
-    // Read a sector from the storage device
-    fn read_sector(&mut self, sector: &mut [u8]) {
-        // Issue a read command to the storage device
-        self.command_port.write(0x20);
+// A drive on an ATA channel, usable as a block device
+pub struct AtaDrive {
+    channel: Arc<Mutex<AtaChannel>>,
+    drive: Drive,
+    info: IdentifyData,
+}
+
+impl AtaDrive {
+    // Identify the drive at `drive` on the channel
+    pub fn open(channel: Arc<Mutex<AtaChannel>>, drive: Drive) -> Result<AtaDrive, AtaError> {
+        let info = channel.lock().identify(drive)?;
+        Ok(AtaDrive { channel, drive, info })
+    }
 
-        // Read the sector data
-        for byte in sector.iter_mut() {
-            *byte = self.data_port.read();
+    pub fn drive(&self) -> Drive {
+        self.drive
+    }
+
+    pub fn info(&self) -> &IdentifyData {
+        &self.info
+    }
+
+    // Split a request into commands, using LBA28 where it reaches and LBA48 beyond
+    fn transfer<F>(&self, start: u64, len: usize, mut op: F) -> Result<(), AtaError>
+    where
+        F: FnMut(&mut AtaChannel, u64, core::ops::Range<usize>, bool) -> Result<(), AtaError>,
+    {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AtaError::InvalidBuffer);
+        }
+        let count = (len / SECTOR_SIZE) as u64;
+        if start + count > self.info.sectors {
+            return Err(AtaError::OutOfRange);
         }
+
+        let mut channel = self.channel.lock();
+        let mut offset = 0;
+        while offset < len {
+            let lba = start + (offset / SECTOR_SIZE) as u64;
+            let remaining = (len - offset) / SECTOR_SIZE;
+            let chunk = remaining.min(LBA28_MAX_SECTORS);
+            let lba48 = self.info.lba48 && (lba + chunk as u64 > LBA28_LIMIT || remaining > LBA28_MAX_SECTORS);
+            let chunk = if lba48 { remaining.min(LBA48_MAX_SECTORS) } else { chunk };
+            let end = offset + chunk * SECTOR_SIZE;
+            op(&mut channel, lba, offset..end, lba48)?;
+            offset = end;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, start: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let drive = self.drive;
+        self.transfer(start, buffer.len(), |channel, lba, range, lba48| {
+            channel.read_sectors(drive, lba, &mut buffer[range], lba48)
+        })
     }
 
-    // Write a sector to the storage device
-    fn write_sector(&mut self, sector: &[u8]) {
-        // Issue a write command to the storage device
-        self.command_port.write(0x30);
+    // Write sectors and flush the drive cache so the data is on the media on return
+    pub fn write(&self, start: u64, buffer: &[u8]) -> Result<(), AtaError> {
+        let drive = self.drive;
+        self.transfer(start, buffer.len(), |channel, lba, range, lba48| {
+            channel.write_sectors(drive, lba, &buffer[range], lba48)
+        })?;
+        self.flush()
+    }
+
+    pub fn flush(&self) -> Result<(), AtaError> {
+        self.channel.lock().flush_cache(self.drive, self.info.flush_cache_ext)
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        Ok(self.read(start, buffer)?)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        Ok(self.write(start, buffer)?)
+    }
+
+    fn flush(&self) -> Result<(), block::Error> {
+        Ok(AtaDrive::flush(self)?)
+    }
+}
+
+impl Device for AtaDrive {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Block
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 
-        // Write the sector data
-        for byte in sector.iter() {
-            self.data_port.write(*byte);
+    fn as_block_device(&self) -> Option<&dyn BlockDevice> {
+        Some(self)
+    }
+
+    fn suspend(&self) -> Result<(), DeviceError> {
+        Ok(AtaDrive::flush(self)?)
+    }
+}
+
+// A PCI IDE controller; its drives are registered as separate block devices
+pub struct IdeController {
+    pub channels: [Arc<Mutex<AtaChannel>>; 2],
+    pub disks: Vec<String>,
+}
+
+impl IdeController {
+    // Reset both channels and register every ATA drive found on them
+    pub fn new(primary: AtaChannel, secondary: AtaChannel) -> IdeController {
+        let channels = [Arc::new(Mutex::new(primary)), Arc::new(Mutex::new(secondary))];
+        let mut disks = Vec::new();
+        for channel in channels.iter() {
+            if channel.lock().soft_reset().is_err() {
+                continue;
+            }
+            for drive in [Drive::Master, Drive::Slave] {
+                if let Ok(disk) = AtaDrive::open(channel.clone(), drive) {
+                    disks.push(device::add_device(Arc::new(disk)));
+                }
+            }
         }
+        IdeController { channels, disks }
+    }
+}
+
+impl Device for IdeController {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Controller
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Find the ports of a channel; bits 0 and 2 of the programming interface select
+// native-PCI mode for the primary and secondary channel, otherwise the legacy
+// ports are used and the channel's BARs are unused
+fn channel_ports(device: &PciDevice, native: bool, bar: usize, io_base: u16, control_base: u16) -> Result<AtaChannel, DeviceError> {
+    if !native {
+        return Ok(AtaChannel::new(io_base, control_base));
+    }
+    match (device.bar(bar)?, device.bar(bar + 1)?) {
+        // The control BAR covers four ports; the device control register is the third
+        (Bar::Io { port: io, .. }, Bar::Io { port: control, .. }) => Ok(AtaChannel::new(io, control + 2)),
+        _ => Err(PciError::NoSuchBar.into()),
+    }
+}
+
+// Probe a PCI IDE controller and register its drives
+fn probe(device: &PciDevice) -> Result<Arc<dyn Device>, DeviceError> {
+    let primary = channel_ports(device, device.prog_if & 0x01 != 0, 0, PRIMARY_IO_BASE, PRIMARY_CONTROL_BASE)?;
+    let secondary = channel_ports(device, device.prog_if & 0x04 != 0, 2, SECONDARY_IO_BASE, SECONDARY_CONTROL_BASE)?;
+
+    device.enable();
+    Ok(Arc::new(IdeController::new(primary, secondary)))
 }
@@ -0,0 +1,565 @@
+//! Virtio transport and split virtqueues
+//!
+//! Virtio devices are found on PCI with vendor 0x1AF4. Transitional devices
+//! (IDs 0x1000-0x103F) expose the legacy I/O port register block in BAR0; modern
+//! devices describe their register blocks with vendor-specific PCI capabilities.
+//! Both are driven through the `Transport` trait, so the device drivers in
+//! `virtio_blk` and `virtio_net` work unchanged on either.
+
+use crate::drivers::device::DeviceError;
+use crate::drivers::dma::{DmaBuffer, Mmio};
+use crate::drivers::pci::{Bar, PciDevice, PciError, PCI_CAP_ID_VENDOR};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::instructions::port::Port;
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+// Interrupt vector all virtio queues signal completions on
+pub const VIRTIO_INTERRUPT_VECTOR: u8 = 46;
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+// Device-independent feature bits
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Legacy register block, as offsets from the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+const LEGACY_CONFIG_MSIX_VECTOR: u16 = 0x14;
+const LEGACY_QUEUE_MSIX_VECTOR: u16 = 0x16;
+// Device configuration follows the common registers, after the MSI-X vector
+// registers when MSI-X is enabled
+const LEGACY_CONFIG: u16 = 0x14;
+const LEGACY_CONFIG_MSIX: u16 = 0x18;
+
+// Modern capability types
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Modern common configuration layout
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Written to an MSI-X vector register to leave the source without an interrupt
+const NO_VECTOR: u16 = 0xFFFF;
+
+// Descriptor flags
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+// Used ring flag asking the driver not to notify the device
+const USED_F_NO_NOTIFY: u16 = 1;
+
+// Legacy queues are laid out with the used ring on its own page
+const QUEUE_ALIGN: usize = 4096;
+// Largest queue the drivers set up on modern devices
+const MAX_QUEUE_SIZE: u16 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    // The device does not implement the queue
+    NoQueue,
+    // Not enough free descriptors for the request
+    QueueFull,
+    // The device did not accept the negotiated features
+    FeaturesRejected,
+    // The device reported an error for a request
+    RequestFailed(u8),
+}
+
+impl From<VirtioError> for DeviceError {
+    fn from(error: VirtioError) -> Self {
+        match error {
+            VirtioError::NoQueue | VirtioError::FeaturesRejected => DeviceError::NotSupported,
+            VirtioError::QueueFull => DeviceError::Busy,
+            VirtioError::RequestFailed(_) => DeviceError::IoError,
+        }
+    }
+}
+
+/// Access to a virtio device's registers, independent of how they are exposed
+pub trait Transport: Send + Sync {
+    fn device_features(&self) -> u64;
+    fn set_driver_features(&self, features: u64);
+    fn status(&self) -> u8;
+    fn set_status(&self, status: u8);
+
+    /// The largest size supported for a queue, or 0 if the queue does not exist
+    fn max_queue_size(&self, queue: u16) -> u16;
+
+    /// Whether queue sizes are fixed by the device, as they are on legacy devices
+    fn fixed_queue_size(&self) -> bool;
+
+    /// Tell the device where a queue lives and enable it
+    fn setup_queue(&self, queue: &Virtqueue, msix_vector: Option<u16>);
+
+    /// Route configuration change interrupts to an MSI-X table entry
+    fn set_config_vector(&self, msix_vector: Option<u16>);
+
+    fn notify(&self, queue: u16);
+
+    /// Read and acknowledge the interrupt status; needed for INTx interrupts only
+    fn read_isr(&self) -> u8;
+
+    fn read_config8(&self, offset: usize) -> u8;
+
+    fn read_config16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.read_config8(offset), self.read_config8(offset + 1)])
+    }
+
+    fn read_config32(&self, offset: usize) -> u32 {
+        self.read_config16(offset) as u32 | (self.read_config16(offset + 2) as u32) << 16
+    }
+
+    fn read_config64(&self, offset: usize) -> u64 {
+        self.read_config32(offset) as u64 | (self.read_config32(offset + 4) as u64) << 32
+    }
+
+    /// Reset the device, then acknowledge it and negotiate features: the result is
+    /// the intersection of `wanted` and what the device offers
+    fn begin_init(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.set_status(0);
+        while self.status() != 0 {}
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let features = self.device_features() & wanted;
+        self.set_driver_features(features);
+        // Legacy devices have no FEATURES_OK handshake
+        if features & VIRTIO_F_VERSION_1 != 0 {
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Mark the device live once its queues are set up
+    fn finish_init(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+}
+
+/// A transitional device driven through the legacy I/O port registers
+pub struct LegacyTransport {
+    io_base: u16,
+    msix: bool,
+}
+
+impl LegacyTransport {
+    // `msix` says whether MSI-X is enabled, which moves the device configuration
+    pub fn new(io_base: u16, msix: bool) -> LegacyTransport {
+        LegacyTransport { io_base, msix }
+    }
+
+    fn read8(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base + reg).read() }
+    }
+
+    fn write8(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base + reg).write(value) }
+    }
+
+    fn read16(&self, reg: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.io_base + reg).read() }
+    }
+
+    fn write16(&self, reg: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.io_base + reg).write(value) }
+    }
+
+    fn read32(&self, reg: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.io_base + reg).read() }
+    }
+
+    fn write32(&self, reg: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.io_base + reg).write(value) }
+    }
+}
+
+impl Transport for LegacyTransport {
+    // Legacy devices only have the low 32 feature bits
+    fn device_features(&self) -> u64 {
+        self.read32(LEGACY_DEVICE_FEATURES) as u64
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write32(LEGACY_DRIVER_FEATURES, features as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.read8(LEGACY_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write8(LEGACY_DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        self.write16(LEGACY_QUEUE_SELECT, queue);
+        self.read16(LEGACY_QUEUE_SIZE)
+    }
+
+    fn fixed_queue_size(&self) -> bool {
+        true
+    }
+
+    fn setup_queue(&self, queue: &Virtqueue, msix_vector: Option<u16>) {
+        self.write16(LEGACY_QUEUE_SELECT, queue.index());
+        if self.msix {
+            self.write16(LEGACY_QUEUE_MSIX_VECTOR, msix_vector.unwrap_or(NO_VECTOR));
+        }
+        self.write32(LEGACY_QUEUE_PFN, (queue.desc_addr() / QUEUE_ALIGN as u64) as u32);
+    }
+
+    fn set_config_vector(&self, msix_vector: Option<u16>) {
+        if self.msix {
+            self.write16(LEGACY_CONFIG_MSIX_VECTOR, msix_vector.unwrap_or(NO_VECTOR));
+        }
+    }
+
+    fn notify(&self, queue: u16) {
+        self.write16(LEGACY_QUEUE_NOTIFY, queue);
+    }
+
+    fn read_isr(&self) -> u8 {
+        self.read8(LEGACY_ISR_STATUS)
+    }
+
+    fn read_config8(&self, offset: usize) -> u8 {
+        let base = if self.msix { LEGACY_CONFIG_MSIX } else { LEGACY_CONFIG };
+        self.read8(base + offset as u16)
+    }
+}
+
+/// A device driven through the register blocks described by its virtio PCI capabilities
+pub struct ModernTransport {
+    common: Mmio,
+    notify: Mmio,
+    notify_multiplier: u32,
+    isr: Mmio,
+    device: Option<Mmio>,
+}
+
+impl ModernTransport {
+    // Find the register blocks from the vendor-specific capabilities
+    pub fn from_pci(device: &PciDevice) -> Result<ModernTransport, DeviceError> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_cfg = None;
+
+        for (id, cap) in device.capabilities() {
+            if id != PCI_CAP_ID_VENDOR {
+                continue;
+            }
+            let cfg_type = device.read_config8(cap + 3);
+            let bar = device.read_config8(cap + 4) as usize;
+            let offset = device.read_config32(cap + 8) as usize;
+            let length = device.read_config32(cap + 12) as usize;
+            // Use the first capability of each type, as the specification recommends
+            let region = || -> Result<Mmio, DeviceError> {
+                Ok(Mmio::from_bar(&device.bar(bar)?)?.subregion(offset, length))
+            };
+            match cfg_type {
+                CAP_COMMON_CFG if common.is_none() => common = Some(region()?),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = Some((region()?, device.read_config32(cap + 16)));
+                }
+                CAP_ISR_CFG if isr.is_none() => isr = Some(region()?),
+                CAP_DEVICE_CFG if device_cfg.is_none() => device_cfg = Some(region()?),
+                _ => {}
+            }
+        }
+
+        let (notify, notify_multiplier) = notify.ok_or(PciError::NoCapability)?;
+        Ok(ModernTransport {
+            common: common.ok_or(PciError::NoCapability)?,
+            notify,
+            notify_multiplier,
+            isr: isr.ok_or(PciError::NoCapability)?,
+            device: device_cfg,
+        })
+    }
+}
+
+impl Transport for ModernTransport {
+    fn device_features(&self) -> u64 {
+        self.common.write32(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let low = self.common.read32(COMMON_DEVICE_FEATURE) as u64;
+        self.common.write32(COMMON_DEVICE_FEATURE_SELECT, 1);
+        let high = self.common.read32(COMMON_DEVICE_FEATURE) as u64;
+        low | high << 32
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.common.write32(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.common.write32(COMMON_DRIVER_FEATURE, features as u32);
+        self.common.write32(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.common.write32(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.common.read8(COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.common.write8(COMMON_DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        self.common.write16(COMMON_QUEUE_SELECT, queue);
+        self.common.read16(COMMON_QUEUE_SIZE)
+    }
+
+    fn fixed_queue_size(&self) -> bool {
+        false
+    }
+
+    fn setup_queue(&self, queue: &Virtqueue, msix_vector: Option<u16>) {
+        let write64 = |offset: usize, value: u64| {
+            self.common.write32(offset, value as u32);
+            self.common.write32(offset + 4, (value >> 32) as u32);
+        };
+        self.common.write16(COMMON_QUEUE_SELECT, queue.index());
+        self.common.write16(COMMON_QUEUE_SIZE, queue.size());
+        self.common.write16(COMMON_QUEUE_MSIX_VECTOR, msix_vector.unwrap_or(NO_VECTOR));
+        write64(COMMON_QUEUE_DESC, queue.desc_addr());
+        write64(COMMON_QUEUE_DRIVER, queue.avail_addr());
+        write64(COMMON_QUEUE_DEVICE, queue.used_addr());
+        self.common.write16(COMMON_QUEUE_ENABLE, 1);
+    }
+
+    fn set_config_vector(&self, msix_vector: Option<u16>) {
+        self.common.write16(COMMON_MSIX_CONFIG, msix_vector.unwrap_or(NO_VECTOR));
+    }
+
+    fn notify(&self, queue: u16) {
+        self.common.write16(COMMON_QUEUE_SELECT, queue);
+        let offset = self.common.read16(COMMON_QUEUE_NOTIFY_OFF) as usize;
+        self.notify.write16(offset * self.notify_multiplier as usize, queue);
+    }
+
+    fn read_isr(&self) -> u8 {
+        self.isr.read8(0)
+    }
+
+    fn read_config8(&self, offset: usize) -> u8 {
+        self.device.map_or(0, |device| device.read8(offset))
+    }
+}
+
+// Bind the transport for a virtio PCI device and route its interrupts. Returns the
+// transport and whether completions will be signalled by MSI-X; without MSI-X the
+// drivers poll their queues.
+pub fn transport_for(device: &PciDevice) -> Result<(Box<dyn Transport>, bool), DeviceError> {
+    device.enable();
+    let msix = enable_msix(device);
+    let transport: Box<dyn Transport> = match ModernTransport::from_pci(device) {
+        Ok(modern) => Box::new(modern),
+        Err(_) => match device.bar(0)? {
+            Bar::Io { port, .. } => Box::new(LegacyTransport::new(port, msix)),
+            Bar::Memory { .. } => return Err(PciError::NoSuchBar.into()),
+        },
+    };
+    Ok((transport, msix))
+}
+
+// Route MSI-X table entry 0, which every queue uses, to the shared virtio vector
+fn enable_msix(device: &PciDevice) -> bool {
+    let table = device.msix_table().and_then(|(bir, offset, _)| {
+        let bar = device.bar(bir).ok()?;
+        Some(Mmio::from_bar(&bar).ok()?.base() + offset as usize)
+    });
+    match table {
+        Some(table) => device.enable_msix(table, &[(0, VIRTIO_INTERRUPT_VECTOR)], 0).is_ok(),
+        None => false,
+    }
+}
+
+// Which MSI-X entry a queue or the configuration interrupt should use
+pub fn vector_entry(msix: bool) -> Option<u16> {
+    if msix {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+/// A split virtqueue: a descriptor table, the driver's available ring and the
+/// device's used ring, laid out contiguously the way legacy devices require
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used_idx: u16,
+    // Length of the descriptor chain starting at each head, so it can be freed
+    chain_len: Vec<u16>,
+}
+
+impl Virtqueue {
+    // Create queue `index` with the size the transport allows
+    pub fn new(transport: &dyn Transport, index: u16) -> Result<Virtqueue, DeviceError> {
+        let max = transport.max_queue_size(index);
+        if max == 0 {
+            return Err(VirtioError::NoQueue.into());
+        }
+        let size = if transport.fixed_queue_size() { max } else { max.min(MAX_QUEUE_SIZE) };
+        let n = size as usize;
+
+        let avail_offset = 16 * n;
+        let used_offset = align_up(avail_offset + 6 + 2 * n, QUEUE_ALIGN);
+        let total = used_offset + align_up(6 + 8 * n, QUEUE_ALIGN);
+        let memory = DmaBuffer::new(total, QUEUE_ALIGN)?;
+
+        // Chain every descriptor into the free list
+        for i in 0..size {
+            memory.write::<u16>(16 * i as usize + 14, (i + 1) % size);
+        }
+
+        Ok(Virtqueue {
+            index,
+            size,
+            memory,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+            chain_len: vec![0; n],
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    pub fn desc_addr(&self) -> u64 {
+        self.memory.phys_addr()
+    }
+
+    pub fn avail_addr(&self) -> u64 {
+        self.memory.phys_addr() + self.avail_offset as u64
+    }
+
+    pub fn used_addr(&self) -> u64 {
+        self.memory.phys_addr() + self.used_offset as u64
+    }
+
+    // Chain the buffers into descriptors and make the chain available to the device.
+    // `readable` buffers are read by the device and precede the `writable` ones it
+    // fills in. Returns the head descriptor, which identifies the request on completion.
+    pub fn add(&mut self, readable: &[(u64, u32)], writable: &[(u64, u32)]) -> Result<u16, VirtioError> {
+        let count = (readable.len() + writable.len()) as u16;
+        if count == 0 || count > self.num_free {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        let buffers = readable.iter().map(|b| (b, 0)).chain(writable.iter().map(|b| (b, DESC_F_WRITE)));
+        for (i, (&(address, len), flags)) in buffers.enumerate() {
+            let desc = 16 * index as usize;
+            let next = self.memory.read::<u16>(desc + 14);
+            let last = i as u16 + 1 == count;
+            self.memory.write::<u64>(desc, address);
+            self.memory.write::<u32>(desc + 8, len);
+            self.memory.write::<u16>(desc + 12, if last { flags } else { flags | DESC_F_NEXT });
+            if last {
+                self.free_head = next;
+            } else {
+                index = next;
+            }
+        }
+        self.num_free -= count;
+        self.chain_len[head as usize] = count;
+
+        // Publish the chain, then the new index, so the device never sees a half-written entry
+        let slot = self.avail_offset + 4 + 2 * (self.avail_idx % self.size) as usize;
+        self.memory.write::<u16>(slot, head);
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.memory.write::<u16>(self.avail_offset + 2, self.avail_idx);
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    // Whether the device wants to be notified about new buffers
+    pub fn should_notify(&self) -> bool {
+        self.memory.read::<u16>(self.used_offset) & USED_F_NO_NOTIFY == 0
+    }
+
+    // Take the next completed chain off the used ring, returning its head and the
+    // number of bytes the device wrote. The chain stays allocated, so the head cannot
+    // be handed out again, until it is passed to `free`.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        let used_idx = self.memory.read::<u16>(self.used_offset + 2);
+        if used_idx == self.last_used_idx {
+            return None;
+        }
+
+        let elem = self.used_offset + 4 + 8 * (self.last_used_idx % self.size) as usize;
+        let head = self.memory.read::<u32>(elem) as u16;
+        let len = self.memory.read::<u32>(elem + 4);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        Some((head, len))
+    }
+
+    // Return a completed chain to the free list
+    pub fn free(&mut self, head: u16) {
+        let count = self.chain_len[head as usize];
+        let mut tail = head;
+        for _ in 1..count {
+            tail = self.memory.read::<u16>(16 * tail as usize + 14);
+        }
+        self.memory.write::<u16>(16 * tail as usize + 14, self.free_head);
+        self.free_head = head;
+        self.num_free += count;
+        self.chain_len[head as usize] = 0;
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
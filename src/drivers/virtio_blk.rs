@@ -0,0 +1,368 @@
+// virtio-blk driver
+// Requests are a three-part descriptor chain: the request header, the data, and a
+// status byte the device writes when it completes the request
+
+use crate::drivers::device::{Device, DeviceClass, DeviceError};
+use crate::drivers::dma::DmaBuffer;
+use crate::drivers::pci::{PciDevice, PciDeviceId, PciDriver};
+use crate::drivers::virtio::{self, Transport, VirtioError, Virtqueue, VIRTIO_F_VERSION_1, VIRTIO_VENDOR_ID};
+use crate::storage::block::{self, BlockDevice};
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Feature bits
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Features the driver asks for
+const FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH;
+
+// Device configuration layout
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SIZE_MAX: usize = 8;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+// Request status values
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// The header is 16 bytes and the status byte follows it in the same buffer
+const HEADER_SIZE: usize = 16;
+
+// Largest data buffer per request unless the device asks for smaller ones
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+// Number of polls before a request is considered hung
+const VIRTIO_TIMEOUT: usize = 10_000_000;
+
+// Bind to legacy and modern virtio block devices
+static VIRTIO_BLK_PCI_IDS: [PciDeviceId; 2] = [
+    PciDeviceId::device(VIRTIO_VENDOR_ID, 0x1001),
+    PciDeviceId::device(VIRTIO_VENDOR_ID, 0x1042),
+];
+
+pub static VIRTIO_BLK_PCI_DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    id_table: &VIRTIO_BLK_PCI_IDS,
+    probe,
+};
+
+// A request that has been handed to the device
+struct Request {
+    head: u16,
+    // The queue generation the request was issued on
+    generation: u64,
+    // Header followed by the status byte
+    header: DmaBuffer,
+    data: Option<DmaBuffer>,
+    range: core::ops::Range<usize>,
+}
+
+pub struct VirtioBlk {
+    transport: Box<dyn Transport>,
+    queue: Mutex<Virtqueue>,
+    // Which request heads the device has completed
+    completed: Mutex<Vec<bool>>,
+    // Bumped whenever the device is restarted, which abandons every request in flight
+    generation: AtomicU64,
+    // Set when a restart fails; the device then rejects every request
+    failed: AtomicBool,
+    // Set by the interrupt handler when the device has used buffers to collect
+    pending: AtomicBool,
+    interrupts: bool,
+    capacity: u64,
+    read_only: bool,
+    flush: bool,
+    max_request: usize,
+}
+
+impl VirtioBlk {
+    pub fn new(transport: Box<dyn Transport>, interrupts: bool) -> Result<VirtioBlk, DeviceError> {
+        let (features, queue) = start(transport.as_ref(), interrupts)?;
+
+        let max_request = if features & VIRTIO_BLK_F_SIZE_MAX != 0 {
+            let size_max = transport.read_config32(CONFIG_SIZE_MAX) as usize;
+            (size_max / SECTOR_SIZE * SECTOR_SIZE).clamp(SECTOR_SIZE, MAX_REQUEST_BYTES)
+        } else {
+            MAX_REQUEST_BYTES
+        };
+
+        Ok(VirtioBlk {
+            capacity: transport.read_config64(CONFIG_CAPACITY),
+            completed: Mutex::new(vec![false; queue.size() as usize]),
+            queue: Mutex::new(queue),
+            generation: AtomicU64::new(0),
+            failed: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+            transport,
+            interrupts,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            flush: features & VIRTIO_BLK_F_FLUSH != 0,
+            max_request,
+        })
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Note that requests have finished. The handler leaves the queue to the waiters,
+    // which hold its lock with interrupts enabled.
+    pub fn handle_interrupt(&self) {
+        self.pending.store(true, Ordering::Release);
+    }
+
+    // Record every request the device has finished; called by waiters after an
+    // interrupt, or on every check when interrupts are not in use
+    fn collect_used(&self) {
+        if self.interrupts && !self.pending.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let mut queue = self.queue.lock();
+        let mut completed = self.completed.lock();
+        while let Some((head, _)) = queue.pop_used() {
+            completed[head as usize] = true;
+        }
+    }
+
+    // Queue a request; fails with `Busy` when there are not enough free descriptors,
+    // handing the data buffer back so the request can be retried
+    fn submit(&self, kind: u32, sector: u64, data: Option<DmaBuffer>, range: core::ops::Range<usize>) -> Result<Request, (DeviceError, Option<DmaBuffer>)> {
+        if self.failed.load(Ordering::Acquire) {
+            return Err((DeviceError::IoError, data));
+        }
+        let header = match DmaBuffer::new(HEADER_SIZE + 1, 8) {
+            Ok(header) => header,
+            Err(error) => return Err((error, data)),
+        };
+        header.write::<u32>(0, kind);
+        header.write::<u64>(8, sector);
+        // Anything but OK, so a request the device never touched does not look successful
+        header.write::<u8>(HEADER_SIZE, 0xFF);
+
+        let header_part = (header.phys_addr(), HEADER_SIZE as u32);
+        let status_part = (header.phys_addr() + HEADER_SIZE as u64, 1);
+        let data_part = data.as_ref().map(|data| (data.phys_addr(), data.len() as u32));
+
+        let mut queue = self.queue.lock();
+        let head = match (kind, data_part) {
+            (VIRTIO_BLK_T_OUT, Some(data)) => queue.add(&[header_part, data], &[status_part]),
+            (_, Some(data)) => queue.add(&[header_part], &[data, status_part]),
+            (_, None) => queue.add(&[header_part], &[status_part]),
+        };
+        let head = match head {
+            Ok(head) => head,
+            Err(error) => return Err((error.into(), data)),
+        };
+        if queue.should_notify() {
+            self.transport.notify(queue.index());
+        }
+        let generation = self.generation.load(Ordering::Acquire);
+        Ok(Request { head, generation, header, data, range })
+    }
+
+    // Wait for a request to complete and check the status the device wrote
+    fn wait(&self, request: &Request) -> Result<(), DeviceError> {
+        for _ in 0..VIRTIO_TIMEOUT {
+            self.collect_used();
+            let mut queue = self.queue.lock();
+            let mut completed = self.completed.lock();
+            // The device was restarted since the request was issued, so it will never complete
+            if self.generation.load(Ordering::Acquire) != request.generation {
+                return Err(DeviceError::IoError);
+            }
+            if core::mem::replace(&mut completed[request.head as usize], false) {
+                queue.free(request.head);
+                return match request.header.read::<u8>(HEADER_SIZE) {
+                    VIRTIO_BLK_S_OK => Ok(()),
+                    status => Err(VirtioError::RequestFailed(status).into()),
+                };
+            }
+        }
+        self.restart();
+        Err(DeviceError::Timeout)
+    }
+
+    // Recover from a hung request. Resetting the device stops it from using the buffers
+    // of any request in flight; those requests fail when waited for, and the queue is set
+    // up afresh. A device that cannot be started again is failed for good.
+    fn restart(&self) {
+        let mut queue = self.queue.lock();
+        let mut completed = self.completed.lock();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        match start(self.transport.as_ref(), self.interrupts) {
+            Ok((_, restarted)) => {
+                *completed = vec![false; restarted.size() as usize];
+                *queue = restarted;
+            }
+            Err(_) => {
+                self.transport.set_status(0);
+                self.failed.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    // Split a transfer into requests and keep as many in flight as the queue holds
+    fn transfer<F, D>(&self, kind: u32, start: u64, len: usize, mut fill: F, mut drain: D) -> Result<(), block::Error>
+    where
+        F: FnMut(&mut DmaBuffer, core::ops::Range<usize>),
+        D: FnMut(&DmaBuffer, core::ops::Range<usize>),
+    {
+        if len % SECTOR_SIZE != 0 {
+            return Err(block::Error::InvalidBufferSize);
+        }
+        if start + (len / SECTOR_SIZE) as u64 > self.capacity {
+            return Err(block::Error::InvalidBlockId);
+        }
+
+        let mut in_flight: VecDeque<Request> = VecDeque::new();
+        let mut result: Result<(), DeviceError> = Ok(());
+        let mut offset = 0;
+        let mut pending: Option<DmaBuffer> = None;
+        let mut spins = 0;
+        while offset < len && result.is_ok() {
+            let range = offset..(offset + self.max_request).min(len);
+            let data = match pending.take() {
+                Some(data) => data,
+                None => {
+                    let mut data = DmaBuffer::new(range.len(), SECTOR_SIZE)?;
+                    fill(&mut data, range.clone());
+                    data
+                }
+            };
+
+            let sector = start + (offset / SECTOR_SIZE) as u64;
+            match self.submit(kind, sector, Some(data), range.clone()) {
+                Ok(request) => {
+                    in_flight.push_back(request);
+                    offset = range.end;
+                }
+                // Out of descriptors: finish the oldest request of ours, or wait for
+                // other users of the queue if none are ours
+                Err((DeviceError::Busy, data)) => {
+                    pending = data;
+                    match in_flight.pop_front() {
+                        Some(request) => {
+                            result = self.wait(&request);
+                            if result.is_ok() {
+                                drain(request.data.as_ref().unwrap(), request.range.clone());
+                            }
+                        }
+                        None if spins < VIRTIO_TIMEOUT => {
+                            spins += 1;
+                            self.collect_used();
+                        }
+                        None => result = Err(DeviceError::Timeout),
+                    }
+                }
+                Err((error, _)) => result = Err(error),
+            }
+        }
+
+        // Always collect the requests already issued, even after a failure
+        while let Some(request) = in_flight.pop_front() {
+            let waited = self.wait(&request);
+            if waited.is_ok() {
+                drain(request.data.as_ref().unwrap(), request.range.clone());
+            }
+            result = result.and(waited);
+        }
+        Ok(result?)
+    }
+
+    pub fn read(&self, start: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        self.transfer(VIRTIO_BLK_T_IN, start, buffer.len(), |_, _| {}, |data, range| {
+            buffer[range].copy_from_slice(data.as_slice());
+        })
+    }
+
+    pub fn write(&self, start: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        if self.read_only {
+            return Err(block::Error::ReadOnly);
+        }
+        self.transfer(VIRTIO_BLK_T_OUT, start, buffer.len(), |data, range| {
+            data.as_mut_slice().copy_from_slice(&buffer[range]);
+        }, |_, _| {})?;
+        self.flush()
+    }
+
+    // Ask the device to make completed writes durable; without the flush feature the
+    // device writes through and there is nothing to do
+    pub fn flush(&self) -> Result<(), block::Error> {
+        if !self.flush {
+            return Ok(());
+        }
+        for _ in 0..VIRTIO_TIMEOUT {
+            match self.submit(VIRTIO_BLK_T_FLUSH, 0, None, 0..0) {
+                Ok(request) => return Ok(self.wait(&request)?),
+                Err((DeviceError::Busy, _)) => self.collect_used(),
+                Err((error, _)) => return Err(error.into()),
+            }
+        }
+        Err(block::Error::Device(DeviceError::Timeout))
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        self.read(start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        self.write(start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), block::Error> {
+        VirtioBlk::flush(self)
+    }
+}
+
+impl Device for VirtioBlk {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Block
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_block_device(&self) -> Option<&dyn BlockDevice> {
+        Some(self)
+    }
+
+    fn suspend(&self) -> Result<(), DeviceError> {
+        VirtioBlk::flush(self).map_err(|_| DeviceError::IoError)
+    }
+}
+
+// Reset the device, negotiate features and set up the request queue
+fn start(transport: &dyn Transport, interrupts: bool) -> Result<(u64, Virtqueue), DeviceError> {
+    let features = transport.begin_init(FEATURES)?;
+    let queue = Virtqueue::new(transport, 0)?;
+    transport.setup_queue(&queue, virtio::vector_entry(interrupts));
+    transport.set_config_vector(None);
+    transport.finish_init();
+    Ok((features, queue))
+}
+
+fn probe(device: &PciDevice) -> Result<Arc<dyn Device>, DeviceError> {
+    let (transport, interrupts) = virtio::transport_for(device)?;
+    Ok(Arc::new(VirtioBlk::new(transport, interrupts)?))
+}
@@ -0,0 +1,256 @@
+// virtio-net driver
+// Queue 0 receives and queue 1 transmits; every frame is preceded by a virtio-net
+// header, which is left zeroed since no offloads are negotiated
+
+use crate::drivers::device::{Device, DeviceClass, DeviceError};
+use crate::drivers::dma::DmaBuffer;
+use crate::drivers::pci::{PciDevice, PciDeviceId, PciDriver};
+use crate::drivers::virtio::{self, Transport, Virtqueue, VIRTIO_F_VERSION_1, VIRTIO_VENDOR_ID};
+use crate::net::interface::{
+    self, InterfaceState, InterfaceStats, MacAddress, NetError, NetInterface, ReceiveCallback, ETHERNET_MTU,
+};
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use std::sync::Arc;
+
+// Feature bits
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+// Device configuration layout
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// Header length; version 1 devices always include the `num_buffers` field
+const LEGACY_HEADER_LEN: usize = 10;
+const HEADER_LEN: usize = 12;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+// Largest Ethernet frame without the FCS
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+// Used when the device does not provide a MAC address: locally administered
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+// Bind to legacy and modern virtio network devices
+static VIRTIO_NET_PCI_IDS: [PciDeviceId; 2] = [
+    PciDeviceId::device(VIRTIO_VENDOR_ID, 0x1000),
+    PciDeviceId::device(VIRTIO_VENDOR_ID, 0x1041),
+];
+
+pub static VIRTIO_NET_PCI_DRIVER: PciDriver = PciDriver {
+    name: "virtio-net",
+    id_table: &VIRTIO_NET_PCI_IDS,
+    probe,
+};
+
+// A queue together with the buffers the device currently owns, by head descriptor
+struct BufferQueue {
+    queue: Virtqueue,
+    buffers: Vec<Option<DmaBuffer>>,
+}
+
+impl BufferQueue {
+    fn new(queue: Virtqueue) -> BufferQueue {
+        let size = queue.size() as usize;
+        BufferQueue {
+            queue,
+            buffers: (0..size).map(|_| None).collect(),
+        }
+    }
+}
+
+pub struct VirtioNet {
+    transport: Box<dyn Transport>,
+    rx: Mutex<BufferQueue>,
+    tx: Mutex<BufferQueue>,
+    state: InterfaceState,
+    interrupts: bool,
+    // Set by the interrupt handler when the device has used buffers to collect
+    pending: AtomicBool,
+    mac: MacAddress,
+    header_len: usize,
+    link_status: bool,
+}
+
+impl VirtioNet {
+    pub fn new(transport: Box<dyn Transport>, interrupts: bool) -> Result<VirtioNet, DeviceError> {
+        let features = transport.begin_init(VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS)?;
+
+        let rx = Virtqueue::new(transport.as_ref(), RX_QUEUE)?;
+        let tx = Virtqueue::new(transport.as_ref(), TX_QUEUE)?;
+        transport.setup_queue(&rx, virtio::vector_entry(interrupts));
+        transport.setup_queue(&tx, virtio::vector_entry(interrupts));
+        transport.set_config_vector(None);
+
+        let mac = if features & VIRTIO_NET_F_MAC != 0 {
+            let mut mac = [0u8; 6];
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.read_config8(CONFIG_MAC + i);
+            }
+            mac
+        } else {
+            DEFAULT_MAC
+        };
+
+        let net = VirtioNet {
+            rx: Mutex::new(BufferQueue::new(rx)),
+            tx: Mutex::new(BufferQueue::new(tx)),
+            state: InterfaceState::new(),
+            interrupts,
+            pending: AtomicBool::new(false),
+            mac,
+            header_len: if features & VIRTIO_F_VERSION_1 != 0 { HEADER_LEN } else { LEGACY_HEADER_LEN },
+            link_status: features & VIRTIO_NET_F_STATUS != 0,
+            transport,
+        };
+
+        // The device may start receiving as soon as it is live, so post the receive
+        // buffers first
+        {
+            let mut rx = net.rx.lock();
+            while rx.queue.num_free() > 0 {
+                net.post_rx_buffer(&mut rx)?;
+            }
+        }
+        net.transport.finish_init();
+        net.transport.notify(RX_QUEUE);
+        Ok(net)
+    }
+
+    fn post_rx_buffer(&self, rx: &mut BufferQueue) -> Result<(), DeviceError> {
+        let buffer = DmaBuffer::new(self.header_len + MAX_FRAME_SIZE, 2)?;
+        let head = rx.queue.add(&[], &[(buffer.phys_addr(), buffer.len() as u32)])?;
+        rx.buffers[head as usize] = Some(buffer);
+        Ok(())
+    }
+
+    // Note that the device has used buffers. Frames are delivered by the next poll
+    // rather than here, as the stack must not be entered from an interrupt handler.
+    pub fn handle_interrupt(&self) {
+        self.pending.store(true, Ordering::Release);
+    }
+
+    // Collect received frames and reclaim sent buffers; called by poll
+    fn service(&self) {
+        let mut frames = Vec::new();
+        {
+            let mut rx = self.rx.lock();
+            let mut reposted = false;
+            while let Some((head, len)) = rx.queue.pop_used() {
+                let buffer = rx.buffers[head as usize].take();
+                rx.queue.free(head);
+                if let Some(buffer) = buffer {
+                    let len = (len as usize).min(buffer.len());
+                    if len > self.header_len {
+                        frames.push(buffer.as_slice()[self.header_len..len].to_vec());
+                    } else {
+                        self.state.dropped();
+                    }
+                }
+                reposted |= self.post_rx_buffer(&mut rx).is_ok();
+            }
+            if reposted && rx.queue.should_notify() {
+                self.transport.notify(RX_QUEUE);
+            }
+        }
+
+        self.reclaim_tx();
+
+        // Deliver with the queues unlocked, since the stack may answer right away
+        for frame in frames {
+            self.state.deliver(frame);
+        }
+    }
+
+    // Free the buffers of frames the device has sent
+    fn reclaim_tx(&self) {
+        let mut tx = self.tx.lock();
+        while let Some((head, _)) = tx.queue.pop_used() {
+            tx.buffers[head as usize] = None;
+            tx.queue.free(head);
+        }
+    }
+
+    // Queue a frame for transmission; the device owns the copy until it completes
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        interface::check_frame(self, frame)?;
+        self.reclaim_tx();
+
+        let mut buffer = DmaBuffer::new(self.header_len + frame.len(), 2)?;
+        buffer.as_mut_slice()[self.header_len..].copy_from_slice(frame);
+
+        let mut tx = self.tx.lock();
+        let head = tx
+            .queue
+            .add(&[(buffer.phys_addr(), buffer.len() as u32)], &[])
+            .map_err(DeviceError::from)?;
+        tx.buffers[head as usize] = Some(buffer);
+        if tx.queue.should_notify() {
+            self.transport.notify(TX_QUEUE);
+        }
+        Ok(())
+    }
+}
+
+impl NetInterface for VirtioNet {
+    fn mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        !self.link_status || self.transport.read_config16(CONFIG_STATUS) & VIRTIO_NET_S_LINK_UP != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        let result = self.transmit(frame);
+        self.state.sent(&result, frame.len());
+        result
+    }
+
+    fn set_receive_callback(&self, callback: Option<ReceiveCallback>) {
+        self.state.set_callback(callback);
+    }
+
+    // Take the next received frame, without the virtio-net header
+    fn receive(&self) -> Option<Vec<u8>> {
+        self.poll();
+        self.state.take()
+    }
+
+    // Without interrupts the rings are checked on every poll
+    fn poll(&self) {
+        if !self.interrupts || self.pending.swap(false, Ordering::AcqRel) {
+            self.service();
+        }
+    }
+
+    fn stats(&self) -> InterfaceStats {
+        self.state.stats()
+    }
+}
+
+impl Device for VirtioNet {
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Network
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<Arc<dyn Device>, DeviceError> {
+    let (transport, interrupts) = virtio::transport_for(device)?;
+    let net = Arc::new(VirtioNet::new(transport, interrupts)?);
+    interface::add_ethernet(net.clone());
+    Ok(net)
+}
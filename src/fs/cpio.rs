@@ -0,0 +1,292 @@
+//! cpio archives
+//!
+//! The "newc" format of `cpio -H newc`, which Linux uses for initramfs images: each
+//! entry is a 110-byte header of ASCII hex fields, then the name and the data, each
+//! padded to 4 bytes. An archive ends with an entry named `TRAILER!!!`, and several
+//! archives may follow each other, separated by zero padding.
+
+use crate::fs::vfs::{FileSystemError, FileType, SetAttributes, Vfs};
+use core::time::Duration;
+use std::collections::BTreeMap;
+
+pub const NEWC_MAGIC: &[u8; 6] = b"070701";
+/// The same format with a checksum of the data in the `check` field
+pub const NEWC_CRC_MAGIC: &[u8; 6] = b"070702";
+
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpioError {
+    Truncated,
+    BadMagic,
+    BadHeader,
+    /// A name that is not UTF-8, not terminated, or leaves the destination with `..`
+    /// or through a symlink
+    BadName,
+    Checksum,
+    FileSystem(FileSystemError),
+}
+
+impl From<FileSystemError> for CpioError {
+    fn from(error: FileSystemError) -> Self {
+        CpioError::FileSystem(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub inode: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub links: u32,
+    /// Modification time in seconds since the Unix epoch
+    pub modified: u32,
+    /// Major and minor number of the device the file was archived from
+    pub device: (u32, u32),
+    /// Major and minor number of a device file
+    pub rdev: (u32, u32),
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn file_type(&self) -> Option<FileType> {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn permissions(&self) -> u16 {
+        (self.mode & 0o7777) as u16
+    }
+}
+
+/// The entries of one or more concatenated archives, without their trailers
+pub struct Archive<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(data: &'a [u8]) -> Archive<'a> {
+        Archive { data, position: 0 }
+    }
+
+    fn field(header: &[u8], index: usize) -> Result<u32, CpioError> {
+        let digits = &header[6 + index * 8..6 + (index + 1) * 8];
+        core::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or(CpioError::BadHeader)
+    }
+
+    fn read_entry(&mut self) -> Result<Entry<'a>, CpioError> {
+        let header = self.data.get(self.position..self.position + HEADER_LEN).ok_or(CpioError::Truncated)?;
+        let magic = &header[..6];
+        if magic != NEWC_MAGIC && magic != NEWC_CRC_MAGIC {
+            return Err(CpioError::BadMagic);
+        }
+        let field = |index| Archive::field(header, index);
+        let name_size = field(11)? as usize;
+        let data_size = field(6)? as usize;
+
+        // The name includes its terminating NUL
+        let name_start = self.position + HEADER_LEN;
+        let name = self.data.get(name_start..name_start + name_size).ok_or(CpioError::Truncated)?;
+        let name = match name.split_last() {
+            Some((0, name)) => core::str::from_utf8(name).map_err(|_| CpioError::BadName)?,
+            _ => return Err(CpioError::BadName),
+        };
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = self.data.get(data_start..data_start + data_size).ok_or(CpioError::Truncated)?;
+        if magic == NEWC_CRC_MAGIC && data.iter().fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32)) != field(12)? {
+            return Err(CpioError::Checksum);
+        }
+        self.position = (data_start + data_size).next_multiple_of(4);
+
+        Ok(Entry {
+            name,
+            inode: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            links: field(4)?,
+            modified: field(5)?,
+            device: (field(7)?, field(8)?),
+            rdev: (field(9)?, field(10)?),
+            data,
+        })
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Skip the padding after a trailer
+            while self.data.get(self.position) == Some(&0) {
+                self.position += 1;
+            }
+            if self.position >= self.data.len() {
+                return None;
+            }
+            match self.read_entry() {
+                Ok(entry) if entry.name == TRAILER => continue,
+                Ok(entry) => return Some(Ok(entry)),
+                Err(error) => {
+                    // Nothing after a broken header can be trusted
+                    self.position = self.data.len();
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// Unpack an archive into the directory `destination`, replacing files that are
+/// already there, and return the number of entries. Ownership, permissions and
+/// modification times are kept; device numbers are not. Symlinks are unpacked as
+/// they are, but later entries are only written through them while they stay
+/// inside the destination.
+pub fn unpack(archive: &[u8], vfs: &Vfs, destination: &str) -> Result<usize, CpioError> {
+    let root = vfs.canonicalize(destination)?;
+    // The first path of each multiply linked file; newc stores the data with the last link
+    let mut linked: BTreeMap<(u32, u32, u32), String> = BTreeMap::new();
+    // Directory times are set at the end, after their entries stop changing them
+    let mut directories = Vec::new();
+    let mut count = 0;
+
+    for entry in Archive::new(archive) {
+        let entry = entry?;
+        let name = entry.name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        if name.split('/').any(|component| component == "..") {
+            return Err(CpioError::BadName);
+        }
+        let path = format!("{}/{}", destination.trim_end_matches('/'), name);
+        let file_type = entry.file_type().ok_or(CpioError::BadHeader)?;
+        check_parents(vfs, &root, destination, name)?;
+        if let Some(parent) = path.rfind('/').map(|index| &path[..index]).filter(|parent| !parent.is_empty()) {
+            create_directories(vfs, parent)?;
+        }
+        // An entry replaces a symlink at its path rather than writing through it
+        if vfs.symlink_metadata(&path).is_ok_and(|metadata| metadata.is_symlink()) {
+            vfs.remove_file(&path)?;
+        }
+
+        match file_type {
+            FileType::Directory => {
+                match vfs.create_directory(&path, entry.permissions()) {
+                    Ok(()) | Err(FileSystemError::AlreadyExists) => {}
+                    Err(error) => return Err(error.into()),
+                }
+                directories.push((path.clone(), entry.modified));
+            }
+            FileType::Regular if entry.links > 1 => {
+                let key = (entry.device.0, entry.device.1, entry.inode);
+                match linked.get(&key) {
+                    Some(first) => {
+                        remove_existing(vfs, &path)?;
+                        vfs.link(first, &path)?;
+                    }
+                    None => {
+                        vfs.write_file(&path, &[])?;
+                        linked.insert(key, path.clone());
+                    }
+                }
+                if !entry.data.is_empty() {
+                    vfs.write_file(&path, entry.data)?;
+                }
+            }
+            FileType::Regular => vfs.write_file(&path, entry.data)?,
+            FileType::Symlink => {
+                let target = core::str::from_utf8(entry.data).map_err(|_| CpioError::BadName)?;
+                remove_existing(vfs, &path)?;
+                vfs.symlink(target, &path)?;
+            }
+            special => {
+                remove_existing(vfs, &path)?;
+                vfs.make_node(&path, special, entry.permissions())?;
+            }
+        }
+
+        // Changing a symlink's attributes would change its target's instead
+        if file_type != FileType::Symlink {
+            vfs.set_attributes(&path, &attributes(&entry, file_type != FileType::Directory))?;
+        }
+        count += 1;
+    }
+
+    for (path, modified) in directories.into_iter().rev() {
+        let changes = SetAttributes {
+            modified: Some(Duration::from_secs(modified as u64)),
+            ..SetAttributes::default()
+        };
+        vfs.set_attributes(&path, &changes)?;
+    }
+    Ok(count)
+}
+
+fn attributes(entry: &Entry, with_time: bool) -> SetAttributes {
+    SetAttributes {
+        permissions: Some(entry.permissions()),
+        uid: Some(entry.uid),
+        gid: Some(entry.gid),
+        accessed: None,
+        modified: with_time.then(|| Duration::from_secs(entry.modified as u64)),
+    }
+}
+
+// Check that the parents of `name` that exist under `destination` resolve inside
+// `root`, the canonical destination, as an earlier entry may have made one a symlink
+fn check_parents(vfs: &Vfs, root: &str, destination: &str, name: &str) -> Result<(), CpioError> {
+    let Some((parents, _)) = name.rsplit_once('/') else {
+        return Ok(());
+    };
+    let mut path = destination.trim_end_matches('/').to_string();
+    for component in parents.split('/').filter(|component| !component.is_empty() && *component != ".") {
+        path.push('/');
+        path.push_str(component);
+        match vfs.symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_symlink() => {
+                // A dangling symlink cannot be checked, so it is refused too
+                let target = vfs.canonicalize(&path).map_err(|_| CpioError::BadName)?;
+                let inside = target.strip_prefix(root).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+                if root != "/" && !inside {
+                    return Err(CpioError::BadName);
+                }
+            }
+            Ok(_) => {}
+            Err(FileSystemError::NotFound) => break,
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(())
+}
+
+// Create `path` and its missing parents, for archives that leave directories out
+fn create_directories(vfs: &Vfs, path: &str) -> Result<(), FileSystemError> {
+    match vfs.metadata(path) {
+        Ok(metadata) if metadata.is_dir() => return Ok(()),
+        Ok(_) => return Err(FileSystemError::NotADirectory),
+        Err(FileSystemError::NotFound) => {}
+        Err(error) => return Err(error),
+    }
+    if let Some(parent) = path.rfind('/').map(|index| &path[..index]).filter(|parent| !parent.is_empty()) {
+        create_directories(vfs, parent)?;
+    }
+    vfs.create_directory(path, 0o755)
+}
+
+// Make way for a link or special file, as a later entry replaces an earlier one
+fn remove_existing(vfs: &Vfs, path: &str) -> Result<(), FileSystemError> {
+    match vfs.symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => vfs.remove_directory(path),
+        Ok(_) => vfs.remove_file(path),
+        Err(FileSystemError::NotFound) => Ok(()),
+        Err(error) => Err(error),
+    }
+}
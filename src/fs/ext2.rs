@@ -1,459 +1,1868 @@
-// Ext2 file system driver implementation for x86_64 architecture
-// Requires a compatible storage device
-
-use crate::storage::Storage;
-use core::mem::size_of;
-
-// File system constants
-const EXT2_BLOCK_SIZE: usize = 1024;
-const EXT2_INODE_SIZE: usize = 128;
-const EXT2_SUPERBLOCK_OFFSET: u64 = 1024;
-const EXT2_BLOCK_GROUP_OFFSET: u64 = 2048;
-
-// File system driver struct
-pub struct Ext2 {
-    storage: Storage,
-    block_size: usize,
-    block_group_size: usize,
+//! ext2
+//!
+//! The second extended filesystem, read and written directly on a `BlockDevice`.
+//! A volume is split into block groups, each with a bitmap of its blocks, a bitmap
+//! of its inodes and a table of the inodes themselves. A file finds its blocks
+//! through twelve direct pointers followed by a single, a double and a triple
+//! indirect block. Nothing is cached: every change is on the device when the call
+//! returns, except that the volume is only marked clean again by `sync`.
+//!
+//! ext3 and ext4 volumes are read too. Their journal is replayed on mount, and
+//! extent trees, 64-bit block numbers, HTree indexes and metadata checksums are
+//! understood (see `fs::ext4`), but a volume using any of them is mounted read-only.
+
+use crate::fs::ext4::{self, DxEntry, DxRoot, ExtentNode};
+use crate::fs::jbd2;
+use crate::fs::vfs::{
+    check_name, DirectoryEntry, FileSystem, FileSystemError, FileSystemResult, FileSystemStats, FileType, Inode,
+    InodeId, Metadata, SetAttributes, MAX_NAME_LEN, PERMISSION_MASK,
+};
+use crate::storage::block::BlockDevice;
+use crate::util::time;
+use core::any::Any;
+use core::time::Duration;
+use spin::Mutex;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+/// Inode of the root directory
+pub const ROOT_INODE: u32 = 2;
+
+// Superblock state: unmounted cleanly
+const STATE_VALID: u16 = 1;
+
+// Features a driver may ignore
+const COMPAT_HAS_JOURNAL: u32 = 0x0004;
+const COMPAT_DIR_INDEX: u32 = 0x0020;
+
+// Features a driver must know to read the volume
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_EXTENTS: u32 = 0x0040;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_SUPPORTED: u32 =
+    INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_EXTENTS | INCOMPAT_64BIT | INCOMPAT_FLEX_BG | INCOMPAT_CSUM_SEED;
+// Of those, the ones this driver can also keep up to date
+const INCOMPAT_WRITABLE: u32 = INCOMPAT_FILETYPE;
+
+// Features a driver must know to write to the volume
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+const RO_COMPAT_HUGE_FILE: u32 = 0x0008;
+const RO_COMPAT_GDT_CSUM: u32 = 0x0010;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+/// Superblock flag: HTree hashes treat names as unsigned bytes
+const FLAG_UNSIGNED_HASH: u32 = 0x0002;
+
+/// The metadata checksum type of CRC32c
+const CHECKSUM_CRC32C: u8 = 1;
+
+// Revision 0 volumes have no fields for these
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+// Group descriptors of 64-bit volumes are at least this large, to hold the high halves
+const GROUP_DESCRIPTOR_SIZE_64BIT: usize = 64;
+
+// An inode's block pointers: twelve direct, then three indirect of growing depth
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_BLOCKS: [(usize, u32); 3] = [(12, 1), (13, 2), (14, 3)];
+
+/// Symlink targets shorter than this are kept in the block pointers
+const FAST_SYMLINK_MAX: usize = 60;
+
+/// A directory with an HTree index, which this driver does not keep up to date
+const INDEX_FLAG: u32 = 0x1000;
+/// A file whose space is counted in blocks rather than sectors
+const HUGE_FILE_FLAG: u32 = 0x40000;
+/// A file mapped by an extent tree in place of block pointers
+const EXTENTS_FLAG: u32 = 0x80000;
+
+// HTree indexes below the root that Linux allows without `largedir`
+const MAX_INDEX_LEVELS: u8 = 1;
+
+/// Most hard links an inode may have
+const LINK_MAX: u16 = 32000;
+
+// Offsets of an inode time in seconds, and of its nanoseconds and epoch bits in
+// the extra space of large inodes
+const ACCESSED: (usize, usize) = (8, 140);
+const CHANGED: (usize, usize) = (12, 132);
+const MODIFIED: (usize, usize) = (16, 136);
+const CREATED: (usize, usize) = (144, 148);
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+struct Superblock {
+    // Written back whole, keeping the fields this driver does not use
+    raw: Vec<u8>,
+    inodes_count: u32,
+    blocks_count: u64,
+    free_blocks: u64,
+    free_inodes: u32,
+    first_data_block: u32,
+    block_size: u64,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    state: u16,
     inode_size: usize,
+    first_inode: u32,
+    compat: u32,
+    incompat: u32,
+    ro_compat: u32,
+    group_descriptor_size: usize,
+    /// Size of the fields past the first 128 bytes of new inodes
+    extra_inode_size: u16,
+    journal_inode: u32,
+    hash_seed: [u32; 4],
+    flags: u32,
+    /// Seed of the metadata checksums, on volumes that have them
+    checksum_seed: Option<u32>,
 }
 
-impl Ext2 {
-    // Initialize the file system driver
-    pub fn new(storage: Storage) -> Ext2 {
-        let superblock = read_superblock(&storage);
-        let block_size = EXT2_BLOCK_SIZE << superblock.log_block_size;
-        let block_group_size = block_size * superblock.blocks_per_group as usize;
-        let inode_size = EXT2_INODE_SIZE;
+impl Superblock {
+    fn parse(raw: Vec<u8>) -> FileSystemResult<Superblock> {
+        if read_u16(&raw, 56) != MAGIC {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let log_block_size = read_u32(&raw, 24);
+        if log_block_size > 6 {
+            return Err(FileSystemError::Corrupted("block size"));
+        }
+        let block_size = 1024u64 << log_block_size;
+        let (inode_size, first_inode) = match read_u32(&raw, 76) {
+            0 => (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INODE),
+            _ => (read_u16(&raw, 88) as usize, read_u32(&raw, 84)),
+        };
+        if inode_size < GOOD_OLD_INODE_SIZE || !inode_size.is_power_of_two() || inode_size as u64 > block_size {
+            return Err(FileSystemError::Corrupted("inode size"));
+        }
+        let extra_inode_size = match read_u16(&raw, 0x15E) {
+            _ if inode_size == GOOD_OLD_INODE_SIZE => 0,
+            want if want >= 4 && want as usize <= inode_size - GOOD_OLD_INODE_SIZE => want,
+            _ => 32.min(inode_size - GOOD_OLD_INODE_SIZE) as u16,
+        };
+        let (incompat, ro_compat) = (read_u32(&raw, 96), read_u32(&raw, 100));
+
+        // 64-bit volumes keep the high halves of block counts further on
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let high = |offset| if is_64bit { (read_u32(&raw, offset) as u64) << 32 } else { 0 };
+        let (blocks_high, free_blocks_high) = (high(0x150), high(0x158));
+        let group_descriptor_size = match read_u16(&raw, 0xFE) as usize {
+            _ if !is_64bit => GROUP_DESCRIPTOR_SIZE,
+            size if size >= GROUP_DESCRIPTOR_SIZE_64BIT && size.is_power_of_two() && size as u64 <= block_size => size,
+            _ => return Err(FileSystemError::Corrupted("group descriptor size")),
+        };
+
+        let checksum_seed = if ro_compat & RO_COMPAT_METADATA_CSUM != 0 {
+            if raw[0x175] != CHECKSUM_CRC32C {
+                return Err(FileSystemError::NotSupported);
+            }
+            if ext4::crc32c(!0, &raw[..0x3FC]) != read_u32(&raw, 0x3FC) {
+                return Err(FileSystemError::Corrupted("superblock checksum"));
+            }
+            Some(match incompat & INCOMPAT_CSUM_SEED {
+                0 => ext4::crc32c(!0, &raw[0x68..0x78]),
+                _ => read_u32(&raw, 0x270),
+            })
+        } else {
+            None
+        };
 
-        Ext2 {
-            storage,
+        let superblock = Superblock {
+            inodes_count: read_u32(&raw, 0),
+            blocks_count: read_u32(&raw, 4) as u64 | blocks_high,
+            free_blocks: read_u32(&raw, 12) as u64 | free_blocks_high,
+            free_inodes: read_u32(&raw, 16),
+            first_data_block: read_u32(&raw, 20),
             block_size,
-            block_group_size,
+            blocks_per_group: read_u32(&raw, 32),
+            inodes_per_group: read_u32(&raw, 40),
+            state: read_u16(&raw, 58),
             inode_size,
+            first_inode,
+            compat: read_u32(&raw, 92),
+            incompat,
+            ro_compat,
+            group_descriptor_size,
+            extra_inode_size,
+            journal_inode: read_u32(&raw, 0xE0),
+            hash_seed: [0, 1, 2, 3].map(|word| read_u32(&raw, 0xEC + word * 4)),
+            flags: read_u32(&raw, 0x160),
+            checksum_seed,
+            raw,
+        };
+        let bits_per_block = block_size as u32 * 8;
+        if superblock.blocks_per_group == 0
+            || superblock.blocks_per_group > bits_per_block
+            || superblock.inodes_per_group == 0
+            || superblock.inodes_per_group > bits_per_block
+            || superblock.first_data_block as u64 >= superblock.blocks_count
+            || superblock.inodes_count as u64 > superblock.group_count() as u64 * superblock.inodes_per_group as u64
+        {
+            return Err(FileSystemError::Corrupted("superblock geometry"));
         }
+        Ok(superblock)
     }
 
-    // Read data from a file in the file system
-    pub fn read_file(&mut self, path: &str, buffer: &mut [u8]) {
-        // Find the inode for the file
-        let inode = find_inode(path, &self.storage, &self.block_group_size);
+    fn group_count(&self) -> u32 {
+        (self.blocks_count - self.first_data_block as u64).div_ceil(self.blocks_per_group as u64) as u32
+    }
 
-        // Read the data blocks for the inode
-        let mut block_buffer = [0; EXT2_BLOCK_SIZE];
-        let mut position = 0;
-        for block_index in &inode.block_pointers {
-            if *block_index == 0 {
-                break;
-            }
-            self.storage.read_block(*block_index as u64, &mut block_buffer);
-            let bytes_to_read = buffer.len() - position;
-            let bytes_from_block = bytes_to_read.min(EXT2_BLOCK_SIZE);
-            buffer[position..position + bytes_from_block].copy_from_slice(&block_buffer[0..bytes_from_block]);
-            position += bytes_from_block;
+    /// What kind of volume this is, by the newest features it uses
+    fn kind(&self) -> &'static str {
+        if self.incompat & !(INCOMPAT_FILETYPE | INCOMPAT_RECOVER) != 0 || self.ro_compat & !RO_COMPAT_SUPPORTED != 0 {
+            "ext4"
+        } else if self.compat & COMPAT_HAS_JOURNAL != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        }
+    }
+
+    /// The superblock as written to the device
+    fn encode(&mut self) -> &[u8] {
+        write_u32(&mut self.raw, 12, self.free_blocks as u32);
+        write_u32(&mut self.raw, 16, self.free_inodes);
+        write_u16(&mut self.raw, 58, self.state);
+        write_u32(&mut self.raw, 96, self.incompat);
+        write_u32(&mut self.raw, 100, self.ro_compat);
+        if self.incompat & INCOMPAT_64BIT != 0 {
+            write_u32(&mut self.raw, 0x158, (self.free_blocks >> 32) as u32);
         }
+        if self.checksum_seed.is_some() {
+            let checksum = ext4::crc32c(!0, &self.raw[..0x3FC]);
+            write_u32(&mut self.raw, 0x3FC, checksum);
+        }
+        &self.raw
     }
 }
 
-// Superblock struct for the ext2 file system
-#[repr(C, packed)]
-struct Superblock {
-    _unused: u32,
-    inodes_count: u32,
-    blocks_count: u32,
-    _unused2: u32,
-    free_blocks_count: u32,
-    free_inodes_count: u32,
-    _unused3: u32,
-    _unused4: u32,
-    first_data_block: u32,
-    log_block_size: u32,
-    _unused5: u32,
-    blocks_per_group: u32,
-    _unused6: [u32; 8],
+struct GroupDescriptor {
+    raw: Vec<u8>,
+    block_bitmap: u64,
+    inode_bitmap: u64,
+    inode_table: u64,
+    free_blocks: u32,
+    free_inodes: u32,
+    directories: u32,
 }
 
-// Read the superblock from the storage device
-fn read_superblock(storage: &Storage) -> Superblock {
-    let mut superblock = Superblock {
-        _unused: 0,
-        inodes_count: 0,
-        blocks_count: 0,
-        _unused2: 0,
-        free_blocks_count: 0,
-        free_inodes_count: 0,
-        _unused3: 0,
-        _unused4: 0,
-        first_data_block: 0,
-        log_block_size: 0,
-        _unused5: 0,
-        blocks_per_group: 0,
-        _unused6: [0; 8],
-    };
-
-    let superblock_buffer = &mut [0; EXT2_BLOCK_SIZE];
-    storage.read_block(EXT2_SUPERBLOCK_OFFSET / EXT2_BLOCK_SIZE,superblock_buffer);
-    unsafe {
-        let superblock_ptr = superblock_buffer.as_mut Superblock;
-        superblock = *superblock_ptr;
-    }
-
-    superblock
+impl GroupDescriptor {
+    // Descriptors larger than 32 bytes hold the high halves of each field
+    fn parse(raw: &[u8]) -> GroupDescriptor {
+        let wide = raw.len() >= GROUP_DESCRIPTOR_SIZE_64BIT;
+        let block = |offset| read_u32(raw, offset) as u64 | if wide { (read_u32(raw, offset + 0x20) as u64) << 32 } else { 0 };
+        let count = |offset| read_u16(raw, offset) as u32 | if wide { (read_u16(raw, offset + 0x20) as u32) << 16 } else { 0 };
+        GroupDescriptor {
+            block_bitmap: block(0),
+            inode_bitmap: block(4),
+            inode_table: block(8),
+            free_blocks: count(12),
+            free_inodes: count(14),
+            directories: count(16),
+            raw: raw.to_vec(),
+        }
+    }
+
+    fn encode(&mut self) -> &[u8] {
+        for (offset, count) in [(12, self.free_blocks), (14, self.free_inodes), (16, self.directories)] {
+            write_u16(&mut self.raw, offset, count as u16);
+            if self.raw.len() >= GROUP_DESCRIPTOR_SIZE_64BIT {
+                write_u16(&mut self.raw, offset + 0x20, (count >> 16) as u16);
+            }
+        }
+        &self.raw
+    }
+
+    /// Whether the descriptor of group `group` matches its checksum, if the volume keeps them
+    fn checksum_ok(&self, superblock: &Superblock, group: u32) -> bool {
+        let (before, after) = (&self.raw[..0x1E], &self.raw[0x20..]);
+        let expected = if let Some(seed) = superblock.checksum_seed {
+            let crc = ext4::crc32c(ext4::crc32c(seed, &group.to_le_bytes()), before);
+            ext4::crc32c(ext4::crc32c(crc, &[0; 2]), after) as u16
+        } else if superblock.ro_compat & RO_COMPAT_GDT_CSUM != 0 {
+            let crc = ext4::crc16(!0, &superblock.raw[0x68..0x78]);
+            ext4::crc16(ext4::crc16(ext4::crc16(crc, &group.to_le_bytes()), before), after)
+        } else {
+            return true;
+        };
+        expected == read_u16(&self.raw, 0x1E)
+    }
 }
 
-// Inode struct for the ext2 file system
-#[repr(C)]
-struct Inode {
-    mode: u16,
-    uid: u16,
-    size: u32,
-    access_time: u32,
-    create_time: u32,
-    modify_time: u32,
-    delete_time: u32,
-    gid: u16,
-    links_count: u16,
-    blocks_count: u32,
-    flags: u32,
-    osd1: u32,
-    block_pointers: [u32; 15],
-    generation: u32,
-    extended_attributes: u32,
-    size_high: u32,
-    _unused: u32,
+/// An inode as stored in the inode table
+struct DiskInode {
+    id: u32,
+    raw: Vec<u8>,
 }
 
-// Find the inode for a given file path
-fn find_inode(path: &str, storage: &Storage, block_group_size: &usize) -> Inode {
-    let path_parts = path.split('/');
-    let mut current_inode = read_inode(2, storage, block_group_size); /* Root directory inode */
+impl DiskInode {
+    fn new(id: u32, size: usize, extra_size: u16) -> DiskInode {
+        let mut inode = DiskInode { id, raw: vec![0; size] };
+        if size > GOOD_OLD_INODE_SIZE {
+            write_u16(&mut inode.raw, 128, extra_size);
+        }
+        inode
+    }
+
+    fn mode(&self) -> u16 {
+        read_u16(&self.raw, 0)
+    }
+
+    fn file_type(&self) -> FileSystemResult<FileType> {
+        FileType::from_mode(self.mode() as u32).ok_or(FileSystemError::Corrupted("inode mode"))
+    }
+
+    fn set_permissions(&mut self, permissions: u16) {
+        let mode = self.mode() & !PERMISSION_MASK | permissions & PERMISSION_MASK;
+        write_u16(&mut self.raw, 0, mode);
+    }
+
+    // User and group IDs keep their high halves in the OS dependent fields
+    fn uid(&self) -> u32 {
+        read_u16(&self.raw, 2) as u32 | (read_u16(&self.raw, 120) as u32) << 16
+    }
+
+    fn set_uid(&mut self, uid: u32) {
+        write_u16(&mut self.raw, 2, uid as u16);
+        write_u16(&mut self.raw, 120, (uid >> 16) as u16);
+    }
+
+    fn gid(&self) -> u32 {
+        read_u16(&self.raw, 24) as u32 | (read_u16(&self.raw, 122) as u32) << 16
+    }
+
+    fn set_gid(&mut self, gid: u32) {
+        write_u16(&mut self.raw, 24, gid as u16);
+        write_u16(&mut self.raw, 122, (gid >> 16) as u16);
+    }
+
+    fn is_regular(&self) -> bool {
+        self.file_type() == Ok(FileType::Regular)
+    }
+
+    // Only regular files use the high half of the size
+    fn size(&self) -> u64 {
+        let high = if self.is_regular() { read_u32(&self.raw, 108) as u64 } else { 0 };
+        read_u32(&self.raw, 4) as u64 | high << 32
+    }
 
-    for part in path_parts {
-        if part.is_empty() {
-            continue;
+    fn set_size(&mut self, size: u64) {
+        write_u32(&mut self.raw, 4, size as u32);
+        if self.is_regular() {
+            write_u32(&mut self.raw, 108, (size >> 32) as u32);
         }
+    }
 
-        let directory_entries = read_directory_entries(&current_inode, storage);
-        let directory_entry = directory_entries
-            .iter()
-            .find(|entry| entry.name == part)
-            .expect("File not found");
+    fn links(&self) -> u16 {
+        read_u16(&self.raw, 26)
+    }
 
-        current_inode = read_inode(directory_entry.inode, storage, block_group_size);
+    fn set_links(&mut self, links: u16) {
+        write_u16(&mut self.raw, 26, links);
     }
 
-    current_inode
-}
+    /// Space allocated, in 512-byte units unless the inode has `HUGE_FILE_FLAG`
+    fn sectors(&self) -> u64 {
+        read_u32(&self.raw, 28) as u64 | (read_u16(&self.raw, 116) as u64) << 32
+    }
 
-// Read the inode from the storage device
-fn read_inode(inode_number: u32, storage: &Storage, block_group_size: &usize) -> Inode {
-    let inode_size = size_of::<Inode>();
-    let block_size = *block_group_size;
-    let block_number = (inode_number - 1) / (block_size as u32 / inode_size as u32);
-    let inode_offset = ((inode_number - 1) % (block_size as u32 / inode_size as u32)) as usize * inode_size;
-    let block_offset = block_number as usize * block_size;
-    let mut block_buffer = [0; EXT2_BLOCK_SIZE];
-    storage.read_block(EXT2_BLOCK_GROUP_OFFSET + block_offset as u64, &mut block_buffer);
-    let inode_buffer = &mut [0; 128];
-    inode_buffer.copy_from_slice(&block_buffer[inode_offset..inode_offset + inode_size]);
-    unsafe {
-        let inode_ptr = inode_buffer.as_mut_ptr() as *mut Inode;
-        *inode_ptr
+    fn set_sectors(&mut self, sectors: u64) {
+        write_u32(&mut self.raw, 28, sectors as u32);
+        write_u16(&mut self.raw, 116, (sectors >> 32) as u16);
+    }
+
+    fn flags(&self) -> u32 {
+        read_u32(&self.raw, 32)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        write_u32(&mut self.raw, 32, flags);
+    }
+
+    fn deleted(&self) -> u32 {
+        read_u32(&self.raw, 20)
+    }
+
+    fn set_deleted(&mut self, time: u32) {
+        write_u32(&mut self.raw, 20, time);
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        read_u32(&self.raw, 40 + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        write_u32(&mut self.raw, 40 + index * 4, block);
+    }
+
+    /// Block of extended attributes
+    fn attribute_block(&self) -> u32 {
+        read_u32(&self.raw, 104)
+    }
+
+    fn set_attribute_block(&mut self, block: u32) {
+        write_u32(&mut self.raw, 104, block);
+    }
+
+    // Whether the fields up to `end` are present
+    fn has_field(&self, end: usize) -> bool {
+        end <= GOOD_OLD_INODE_SIZE
+            || self.raw.len() > GOOD_OLD_INODE_SIZE && GOOD_OLD_INODE_SIZE + read_u16(&self.raw, 128) as usize >= end
+    }
+
+    fn time(&self, (seconds, extra): (usize, usize)) -> Option<Duration> {
+        if !self.has_field(seconds + 4) {
+            return None;
+        }
+        // Seconds are signed, with two more bits of epoch in large inodes
+        let mut secs = read_u32(&self.raw, seconds) as i32 as i64;
+        let mut nanos = 0;
+        if self.has_field(extra + 4) {
+            let extra = read_u32(&self.raw, extra);
+            secs += ((extra & 3) as i64) << 32;
+            nanos = (extra >> 2).min(999_999_999);
+        }
+        Some(Duration::new(secs.max(0) as u64, nanos))
     }
-}
 
-let inode_buffer = &mut [0; 128];
-inode_buffer.copy_from_slice(&block_buffer[inode_offset..inode_offset + inode_size]);
-unsafe {
-    let inode_ptr = inode_buffer.as_mut_ptr() as *mut Inode;
-    *inode_ptr
+    fn set_time(&mut self, (seconds, extra): (usize, usize), time: Duration) {
+        if !self.has_field(seconds + 4) {
+            return;
+        }
+        let secs = time.as_secs() as i64;
+        write_u32(&mut self.raw, seconds, secs as u32);
+        if self.has_field(extra + 4) {
+            let epoch = ((secs - secs as i32 as i64) >> 32) as u32 & 3;
+            write_u32(&mut self.raw, extra, epoch | time.subsec_nanos() << 2);
+        }
+    }
+
+    // Update the modification and change times
+    fn touch(&mut self) {
+        let now = time::current_time();
+        self.set_time(MODIFIED, now);
+        self.set_time(CHANGED, now);
+    }
+
+    fn uses_extents(&self) -> bool {
+        self.flags() & EXTENTS_FLAG != 0
+    }
+
+    fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let attribute_sectors = if self.attribute_block() != 0 { block_size / 512 } else { 0 };
+        self.sectors() == attribute_sectors
+    }
+
+    /// Whether the block pointers point to blocks, rather than holding a symlink
+    /// target or a device number
+    fn has_blocks(&self, block_size: u64) -> bool {
+        match self.file_type() {
+            Ok(FileType::Regular | FileType::Directory) => true,
+            Ok(FileType::Symlink) => !self.is_fast_symlink(block_size),
+            _ => false,
+        }
+    }
 }
 
-// Directory entry struct for the ext2 file system
-#[repr(C)]
-struct DirectoryEntry {
+/// A directory entry as stored in a directory block
+struct RawEntry {
+    /// Offset in the block
+    offset: usize,
     inode: u32,
-    size: u16,
-    name_len: u8,
+    /// Bytes up to the next entry
+    length: usize,
+    name: Vec<u8>,
     file_type: u8,
-    name: [u8; 256],
 }
 
-// Read directory entries from a directory inode
-fn read_directory_entries(inode: &Inode, storage: &Storage) -> Vec<DirectoryEntry> {
-    let mut directory_entries = Vec::new();
-    let mut block_buffer = [0; EXT2_BLOCK_SIZE];
-    for block_index in &inode.block_pointers {
-        if *block_index == 0 {
-            break;
+// Directory entry type codes
+fn type_code(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Regular => 1,
+        FileType::Directory => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::Fifo => 5,
+        FileType::Socket => 6,
+        FileType::Symlink => 7,
+    }
+}
+
+fn file_type_of_code(code: u8) -> Option<FileType> {
+    match code {
+        1 => Some(FileType::Regular),
+        2 => Some(FileType::Directory),
+        3 => Some(FileType::CharDevice),
+        4 => Some(FileType::BlockDevice),
+        5 => Some(FileType::Fifo),
+        6 => Some(FileType::Socket),
+        7 => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+/// Space an entry with a name of `name_len` bytes needs
+fn entry_length(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+
+// A record length of 64 KiB does not fit its 16 bits
+fn decode_record_length(length: u16) -> usize {
+    match length {
+        0 | 0xFFFF => 0x10000,
+        length => length as usize,
+    }
+}
+
+fn encode_record_length(length: usize) -> u16 {
+    length.min(0xFFFF) as u16
+}
+
+/// The entries of a directory block, including unused ones
+fn parse_entries(block: &[u8]) -> FileSystemResult<Vec<RawEntry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        if block.len() - offset < 8 {
+            return Err(FileSystemError::Corrupted("directory entry"));
         }
-        storage.read_block(*block_index as u64, &mut block_buffer);
-        let mut position = 0;
-        while position < EXT2_BLOCK_SIZE {
-            let directory_entry_buffer = &mut [0; 264];
-            directory_entry_buffer.copy_from_slice(&block_buffer[position..position + 264]);
-            let directory_entry = unsafe { *(directory_entry_buffer as *const DirectoryEntry) };
-            directory_entries.push(*directory_entry);
-            position += directory_entry.size as usize;
+        let length = decode_record_length(read_u16(block, offset + 4));
+        let name_len = block[offset + 6] as usize;
+        if length < 8 || !length.is_multiple_of(4) || length > block.len() - offset || 8 + name_len > length {
+            return Err(FileSystemError::Corrupted("directory entry"));
         }
+        entries.push(RawEntry {
+            offset,
+            inode: read_u32(block, offset),
+            length,
+            name: block[offset + 8..offset + 8 + name_len].to_vec(),
+            file_type: block[offset + 7],
+        });
+        offset += length;
     }
+    Ok(entries)
+}
+
+fn write_entry(block: &mut [u8], offset: usize, length: usize, inode: u32, name: &[u8], type_code: u8) {
+    write_u32(block, offset, inode);
+    write_u16(block, offset + 4, encode_record_length(length));
+    block[offset + 6] = name.len() as u8;
+    block[offset + 7] = type_code;
+    block[offset + 8..offset + 8 + name.len()].copy_from_slice(name);
+}
 
-    directory_entries
+fn is_dot(name: &[u8]) -> bool {
+    name == b"." || name == b".."
 }
 
-// Read a file from the storage device
-pub fn read_file(path: &str, storage: &Storage, block_group_size: usize) -> String {
-    let inode = find_inode(path, storage, &block_group_size);
-    let mut content = String::new();
-    let mut block_buffer = [0; EXT2_BLOCK_SIZE];
-    for block_index in &inode.block_pointers {
-        if *block_index == 0 {
-            break;
+struct State {
+    superblock: Superblock,
+    groups: Vec<GroupDescriptor>,
+}
+
+pub struct Ext2FileSystem {
+    this: Weak<Ext2FileSystem>,
+    device: Arc<dyn BlockDevice>,
+    name: &'static str,
+    read_only: bool,
+    block_size: u64,
+    state: Mutex<State>,
+    // One inode object per inode in use, so the last to close an unlinked file frees it
+    inodes: Mutex<BTreeMap<u32, Weak<Ext2Inode>>>,
+}
+
+impl Ext2FileSystem {
+    /// Mount the volume on `device`. Volumes with features this driver does not
+    /// know are refused, or mounted read-only if the features only matter to writes.
+    /// A journal left to recover is replayed first, even for a read-only mount.
+    pub fn mount(device: Arc<dyn BlockDevice>, read_only: bool) -> FileSystemResult<Arc<Ext2FileSystem>> {
+        let mut raw = vec![0; SUPERBLOCK_SIZE];
+        device.read_at(SUPERBLOCK_OFFSET, &mut raw)?;
+        let superblock = Superblock::parse(raw)?;
+        if superblock.incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FileSystemError::NotSupported);
+        }
+        let recover = superblock.incompat & INCOMPAT_RECOVER != 0;
+        let writable = !recover
+            && superblock.incompat & !INCOMPAT_WRITABLE == 0
+            && superblock.ro_compat & !RO_COMPAT_SUPPORTED == 0;
+        let block_size = superblock.block_size;
+        let volume_size = superblock.blocks_count.checked_mul(block_size);
+        if volume_size.is_none_or(|size| size > device.block_count() * device.block_size() as u64) {
+            return Err(FileSystemError::Corrupted("volume larger than the device"));
+        }
+
+        // The group descriptor table follows the superblock's block
+        let descriptor_size = superblock.group_descriptor_size;
+        let mut table = vec![0; superblock.group_count() as usize * descriptor_size];
+        device.read_at((superblock.first_data_block as u64 + 1) * block_size, &mut table)?;
+        let groups: Vec<GroupDescriptor> = table.chunks(descriptor_size).map(GroupDescriptor::parse).collect();
+        let inode_table_blocks = (superblock.inodes_per_group as u64 * superblock.inode_size as u64).div_ceil(block_size);
+        for (index, group) in groups.iter().enumerate() {
+            if group.block_bitmap >= superblock.blocks_count
+                || group.inode_bitmap >= superblock.blocks_count
+                || group.inode_table + inode_table_blocks > superblock.blocks_count
+            {
+                return Err(FileSystemError::Corrupted("group descriptor"));
+            }
+            if !group.checksum_ok(&superblock, index as u32) {
+                return Err(FileSystemError::Corrupted("group descriptor checksum"));
+            }
+        }
+
+        let filesystem = Arc::new_cyclic(|this| Ext2FileSystem {
+            this: this.clone(),
+            device: device.clone(),
+            name: superblock.kind(),
+            read_only: read_only || !writable,
+            block_size,
+            state: Mutex::new(State { superblock, groups }),
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+        if recover {
+            filesystem.replay_journal()?;
+            drop(filesystem);
+            return Self::mount(device, read_only);
+        }
+        let mut state = filesystem.state.lock();
+        if filesystem.read_inode(&state, ROOT_INODE)?.file_type()? != FileType::Directory {
+            return Err(FileSystemError::Corrupted("root is not a directory"));
+        }
+        if !filesystem.read_only {
+            // Until `sync`, a crash leaves the volume to be checked
+            let superblock = &mut state.superblock;
+            superblock.state &= !STATE_VALID;
+            let mounts = read_u16(&superblock.raw, 52);
+            write_u32(&mut superblock.raw, 44, time::current_time().as_secs() as u32);
+            write_u16(&mut superblock.raw, 52, mounts.wrapping_add(1));
+            filesystem.write_superblock(&mut state)?;
         }
-        storage.read_block(*block_index as u64, &mut block_buffer);
-        content.push_str(str::from_utf8(&block_buffer).unwrap());
+        drop(state);
+        Ok(filesystem)
     }
 
-    content
-}
+    /// Replay the journal of a volume that was not unmounted cleanly, and mark the
+    /// volume as recovered
+    fn replay_journal(&self) -> FileSystemResult<()> {
+        let state = self.state.lock();
+        let superblock = &state.superblock;
+        if superblock.compat & COMPAT_HAS_JOURNAL == 0 || superblock.journal_inode == 0 {
+            return Err(FileSystemError::Corrupted("journal inode"));
+        }
+        let journal = self.read_inode(&state, superblock.journal_inode)?;
+        let blocks = (0..journal.size() / self.block_size)
+            .map(|index| match self.map_block(&state, &journal, index)? {
+                0 => Err(FileSystemError::Corrupted("hole in the journal")),
+                block => Ok(block),
+            })
+            .collect::<FileSystemResult<Vec<u64>>>()?;
+        drop(state);
+        jbd2::recover(self.device.as_ref(), self.block_size, &blocks)?;
+
+        // The journal may have held the superblock itself
+        let mut raw = vec![0; SUPERBLOCK_SIZE];
+        self.device.read_at(SUPERBLOCK_OFFSET, &mut raw)?;
+        let mut superblock = Superblock::parse(raw)?;
+        superblock.incompat &= !INCOMPAT_RECOVER;
+        self.device.write_at(SUPERBLOCK_OFFSET, superblock.encode())?;
+        Ok(self.device.flush()?)
+    }
+
+    fn this(&self) -> Arc<Ext2FileSystem> {
+        self.this.upgrade().expect("a mounted filesystem is alive")
+    }
+
+    /// The inode object of inode `id`
+    fn inode(&self, id: u32) -> Arc<Ext2Inode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&id).and_then(Weak::upgrade) {
+            return inode;
+        }
+        inodes.retain(|_, inode| inode.strong_count() > 0);
+        let inode = Arc::new(Ext2Inode { fs: self.this(), id });
+        inodes.insert(id, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Whether an inode object of inode `id` is still held, e.g. by an open file
+    fn is_open(&self, id: u32) -> bool {
+        self.inodes.lock().get(&id).is_some_and(|inode| inode.strong_count() > 0)
+    }
+
+    /// Lock the filesystem for a change, marking the volume as not clean
+    fn lock_for_write(&self) -> FileSystemResult<spin::MutexGuard<'_, State>> {
+        if self.read_only {
+            return Err(FileSystemError::ReadOnly);
+        }
+        let mut state = self.state.lock();
+        if state.superblock.state & STATE_VALID != 0 {
+            state.superblock.state &= !STATE_VALID;
+            self.write_superblock(&mut state)?;
+        }
+        Ok(state)
+    }
+
+    fn write_superblock(&self, state: &mut State) -> FileSystemResult<()> {
+        Ok(self.device.write_at(SUPERBLOCK_OFFSET, state.superblock.encode())?)
+    }
+
+    fn write_group(&self, state: &mut State, group: usize) -> FileSystemResult<()> {
+        let table = (state.superblock.first_data_block as u64 + 1) * self.block_size;
+        let offset = table + (group * state.superblock.group_descriptor_size) as u64;
+        Ok(self.device.write_at(offset, state.groups[group].encode())?)
+    }
+
+    fn check_block(&self, state: &State, block: u64) -> FileSystemResult<()> {
+        let superblock = &state.superblock;
+        if block < superblock.first_data_block as u64 || block >= superblock.blocks_count {
+            return Err(FileSystemError::Corrupted("block number out of range"));
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, state: &State, block: u64) -> FileSystemResult<Vec<u8>> {
+        self.check_block(state, block)?;
+        let mut data = vec![0; self.block_size as usize];
+        self.device.read_at(block * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&self, state: &State, block: u64, data: &[u8]) -> FileSystemResult<()> {
+        self.check_block(state, block)?;
+        Ok(self.device.write_at(block * self.block_size, data)?)
+    }
+
+    // Byte offset of inode `id` in its group's inode table
+    fn inode_offset(&self, state: &State, id: u32) -> FileSystemResult<u64> {
+        let superblock = &state.superblock;
+        if id == 0 || id > superblock.inodes_count {
+            return Err(FileSystemError::Corrupted("inode number out of range"));
+        }
+        let group = ((id - 1) / superblock.inodes_per_group) as usize;
+        let index = ((id - 1) % superblock.inodes_per_group) as u64;
+        Ok(state.groups[group].inode_table * self.block_size + index * superblock.inode_size as u64)
+    }
+
+    fn read_inode(&self, state: &State, id: u32) -> FileSystemResult<DiskInode> {
+        let mut raw = vec![0; state.superblock.inode_size];
+        self.device.read_at(self.inode_offset(state, id)?, &mut raw)?;
+        let inode = DiskInode { id, raw };
+        if let Some(seed) = self.checksum_seed(state, &inode) {
+            if !ext4::inode_checksum_ok(&inode.raw, seed) {
+                return Err(FileSystemError::Corrupted("inode checksum"));
+            }
+        }
+        Ok(inode)
+    }
+
+    /// Seed of the checksums of an inode and the blocks it owns, on volumes with
+    /// metadata checksums
+    fn checksum_seed(&self, state: &State, inode: &DiskInode) -> Option<u32> {
+        let seed = ext4::crc32c(state.superblock.checksum_seed?, &inode.id.to_le_bytes());
+        Some(ext4::crc32c(seed, &inode.raw[100..104]))
+    }
+
+    fn write_inode(&self, state: &State, id: u32, inode: &DiskInode) -> FileSystemResult<()> {
+        Ok(self.device.write_at(self.inode_offset(state, id)?, &inode.raw)?)
+    }
 
-// Write a file to the storage device
-pub fn write_file(path: &str, content: &str, storage: &mut Storage, block_group_size: usize) {
-    let mut parts = path.rsplitn(2, '/');
-    let file_name = parts.next().unwrap();
-    let directory_path = parts.next().unwrap_or("");
-    let directory_inode = find_inode(directory_path, storage, &block_group_size);
-    let directory_entries = read_directory_entries(&directory_inode, storage);
-    let mut existing_entry: Option<&DirectoryEntry> = None;
-    for entry in directory_entries.iter() {
-        let name = str::from_utf8(&entry.name[..entry.name_len as usize]).unwrap();
-        if name == file_name {
-            existing_entry = Some(entry);
-            break;
-        }
-    }
-
-    if let Some(entry) = existing_entry {
-        let inode = read_inode(entry.inode, storage, &block_group_size);
-        let mut block_buffer = [0; EXT2_BLOCK_SIZE];
-        let mut block_index = 0;
-        for block_pointer in inode.block_pointers.iter() {
-            if *block_pointer == 0 {
+    /// Inode `id`, which must be a directory
+    fn directory(&self, state: &State, id: u32) -> FileSystemResult<DiskInode> {
+        let inode = self.read_inode(state, id)?;
+        if inode.file_type()? != FileType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+        Ok(inode)
+    }
+
+    fn metadata(&self, id: u32, inode: &DiskInode) -> FileSystemResult<Metadata> {
+        let file_type = inode.file_type()?;
+        let mut metadata = Metadata::new(id as InodeId, file_type, inode.mode());
+        metadata.uid = inode.uid();
+        metadata.gid = inode.gid();
+        metadata.size = inode.size();
+        metadata.links = inode.links() as u32;
+        metadata.blocks = match inode.flags() & HUGE_FILE_FLAG {
+            0 => inode.sectors(),
+            _ => inode.sectors() * (self.block_size / 512),
+        };
+        metadata.block_size = self.block_size as u32;
+        if matches!(file_type, FileType::CharDevice | FileType::BlockDevice) {
+            // Linux's old encoding in the first pointer, or its new one in the second
+            metadata.device = match inode.block(0) {
+                0 => inode.block(1) as u64,
+                old => old as u64,
+            };
+        }
+        metadata.accessed = inode.time(ACCESSED).unwrap_or_default();
+        metadata.modified = inode.time(MODIFIED).unwrap_or_default();
+        metadata.changed = inode.time(CHANGED).unwrap_or_default();
+        metadata.created = inode.time(CREATED);
+        Ok(metadata)
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    /// The block pointer of the inode leading to block `index` of a file, and the
+    /// index into each level of indirect blocks below it
+    fn block_path(&self, index: u64) -> FileSystemResult<(usize, Vec<u64>)> {
+        if index < DIRECT_BLOCKS {
+            return Ok((index as usize, Vec::new()));
+        }
+        let pointers = self.pointers_per_block();
+        let mut first = DIRECT_BLOCKS;
+        for (slot, depth) in INDIRECT_BLOCKS {
+            let span = pointers.pow(depth);
+            if index < first + span {
+                let relative = index - first;
+                let path = (0..depth).rev().map(|level| relative / pointers.pow(level) % pointers).collect();
+                return Ok((slot, path));
+            }
+            first += span;
+        }
+        Err(FileSystemError::InvalidArgument)
+    }
+
+    fn read_pointer(&self, state: &State, block: u32, index: u64) -> FileSystemResult<u32> {
+        self.check_block(state, block.into())?;
+        let mut pointer = [0; 4];
+        self.device.read_at(block as u64 * self.block_size + index * 4, &mut pointer)?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    fn write_pointer(&self, state: &State, block: u32, index: u64, pointer: u32) -> FileSystemResult<()> {
+        self.check_block(state, block.into())?;
+        Ok(self.device.write_at(block as u64 * self.block_size + index * 4, &pointer.to_le_bytes())?)
+    }
+
+    /// The block holding block `index` of a file, or 0 for a hole
+    fn map_block(&self, state: &State, inode: &DiskInode, index: u64) -> FileSystemResult<u64> {
+        if inode.uses_extents() {
+            return self.map_extent(state, inode, index);
+        }
+        let (slot, path) = self.block_path(index)?;
+        let mut block = inode.block(slot);
+        for index in path {
+            if block == 0 {
                 break;
             }
-            block_index += 1;
-            storage.read_block(*block_pointer as u64, &mut block_buffer);
-            let mut position = 0;
-            while position < EXT2_BLOCK_SIZE {
-                let size = min(EXT2_BLOCK_SIZE - position, content.len());
-                block_buffer[position..position + size].copy_from_slice(&content[..size].as_bytes());
-                content = &content[size..];
-                position += size;
-                if content.is_empty() {
-                    break;
+            block = self.read_pointer(state, block, index)?;
+        }
+        Ok(block.into())
+    }
+
+    /// The block holding block `index` of a file mapped by an extent tree, or 0 for
+    /// a hole or an uninitialized extent
+    fn map_extent(&self, state: &State, inode: &DiskInode, index: u64) -> FileSystemResult<u64> {
+        let Ok(index) = u32::try_from(index) else {
+            return Ok(0);
+        };
+        // The root of the tree takes the place of the block pointers
+        let mut node = ExtentNode::parse(&inode.raw[40..100])?;
+        loop {
+            let (depth, children) = match node {
+                ExtentNode::Leaf(extents) => {
+                    let extent = extents.iter().filter(|extent| extent.initialized).find_map(|extent| extent.map(index));
+                    return Ok(extent.unwrap_or(0));
+                }
+                ExtentNode::Index { depth, children } => (depth, children),
+            };
+            // The last child starting at or before the block
+            let Some(&(_, block)) = children.iter().rev().find(|(first, _)| *first <= index) else {
+                return Ok(0);
+            };
+            let data = self.read_block(state, block)?;
+            if let Some(seed) = self.checksum_seed(state, inode) {
+                if !ext4::extent_block_checksum_ok(&data, seed) {
+                    return Err(FileSystemError::Corrupted("extent block checksum"));
                 }
             }
-            storage.write_block(*block_pointer as u64, &block_buffer);
-            block_buffer = [0; EXT2_BLOCK_SIZE];
-        }
-        if !content.is_empty() {
-            let new_block = storage.allocate_block();
-            block_index += 1;
-            inode.block_pointers[block_index] = new_block as u32;
-            storage.write_inode(entry.inode, &inode, &block_group_size);
-            let mut position = 0;
-            while position < EXT2_BLOCK_SIZE {
-                let size = min(EXT2_BLOCK_SIZE - position, content.len());
-                block_buffer[position..position + size].copy_from_slice(&content[..size].as_bytes());
-                content = &content[size..];
-                position += size;
-                if content.is_empty() {
+            node = ExtentNode::parse(&data)?;
+            if node.depth() + 1 != depth {
+                return Err(FileSystemError::Corrupted("extent tree depth"));
+            }
+        }
+    }
+
+    /// The block holding block `index` of a file, allocating it and the indirect
+    /// blocks leading to it if needed. The caller writes back the inode.
+    fn map_or_allocate(&self, state: &mut State, id: u32, inode: &mut DiskInode, index: u64) -> FileSystemResult<u32> {
+        if inode.uses_extents() {
+            return Err(FileSystemError::NotSupported);
+        }
+        let (slot, path) = self.block_path(index)?;
+        let goal = (id - 1) / state.superblock.inodes_per_group;
+        let mut block = inode.block(slot);
+        if block == 0 {
+            block = self.allocate_block(state, goal, inode)?;
+            inode.set_block(slot, block);
+        }
+        for index in path {
+            let mut next = self.read_pointer(state, block, index)?;
+            if next == 0 {
+                next = self.allocate_block(state, goal, inode)?;
+                self.write_pointer(state, block, index, next)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Set the first clear bit from `start` in the first `count` bits of a bitmap
+    fn claim_bit(&self, state: &State, bitmap: u64, start: u32, count: u32) -> FileSystemResult<Option<u32>> {
+        let mut data = self.read_block(state, bitmap)?;
+        let Some(bit) = (start..count).find(|bit| data[*bit as usize / 8] & 1 << (bit % 8) == 0) else {
+            return Ok(None);
+        };
+        data[bit as usize / 8] |= 1 << (bit % 8);
+        self.write_block(state, bitmap, &data)?;
+        Ok(Some(bit))
+    }
+
+    /// Clear a bit of a bitmap; returns whether it was set
+    fn release_bit(&self, state: &State, bitmap: u64, bit: u32) -> FileSystemResult<bool> {
+        let mut data = self.read_block(state, bitmap)?;
+        let (byte, mask) = (bit as usize / 8, 1 << (bit % 8));
+        if data[byte] & mask == 0 {
+            return Ok(false);
+        }
+        data[byte] &= !mask;
+        self.write_block(state, bitmap, &data)?;
+        Ok(true)
+    }
+
+    /// Allocate a zeroed block, preferably in group `goal`, and count it against `inode`
+    fn allocate_block(&self, state: &mut State, goal: u32, inode: &mut DiskInode) -> FileSystemResult<u32> {
+        let group_count = state.groups.len() as u32;
+        for group in (0..group_count).map(|offset| (goal + offset) % group_count) {
+            if state.groups[group as usize].free_blocks == 0 {
+                continue;
+            }
+            let superblock = &state.superblock;
+            let first = superblock.first_data_block + group * superblock.blocks_per_group;
+            let count = (superblock.blocks_count - first as u64).min(superblock.blocks_per_group as u64) as u32;
+            let Some(bit) = self.claim_bit(state, state.groups[group as usize].block_bitmap, 0, count)? else {
+                continue;
+            };
+            state.groups[group as usize].free_blocks -= 1;
+            state.superblock.free_blocks = state.superblock.free_blocks.saturating_sub(1);
+            self.write_group(state, group as usize)?;
+            self.write_superblock(state)?;
+            let block = first + bit;
+            self.write_block(state, block.into(), &vec![0; self.block_size as usize])?;
+            inode.set_sectors(inode.sectors() + self.block_size / 512);
+            return Ok(block);
+        }
+        Err(FileSystemError::NoSpace)
+    }
+
+    fn free_block(&self, state: &mut State, block: u32, inode: &mut DiskInode) -> FileSystemResult<()> {
+        self.check_block(state, block.into())?;
+        let superblock = &state.superblock;
+        let group = (block - superblock.first_data_block) / superblock.blocks_per_group;
+        let bit = (block - superblock.first_data_block) % superblock.blocks_per_group;
+        if self.release_bit(state, state.groups[group as usize].block_bitmap, bit)? {
+            state.groups[group as usize].free_blocks += 1;
+            state.superblock.free_blocks += 1;
+            self.write_group(state, group as usize)?;
+            self.write_superblock(state)?;
+        }
+        inode.set_sectors(inode.sectors().saturating_sub(self.block_size / 512));
+        Ok(())
+    }
+
+    /// Allocate an inode, preferably in the group of `parent`
+    fn allocate_inode(&self, state: &mut State, parent: u32, directory: bool) -> FileSystemResult<u32> {
+        let group_count = state.groups.len() as u32;
+        let goal = (parent - 1) / state.superblock.inodes_per_group;
+        for group in (0..group_count).map(|offset| (goal + offset) % group_count) {
+            if state.groups[group as usize].free_inodes == 0 {
+                continue;
+            }
+            let superblock = &state.superblock;
+            let first = group * superblock.inodes_per_group + 1;
+            let count = superblock.inodes_per_group.min(superblock.inodes_count + 1 - first);
+            // Inodes below the first are reserved, e.g. for the root and the journal
+            let start = superblock.first_inode.saturating_sub(first);
+            let bitmap = state.groups[group as usize].inode_bitmap;
+            let Some(bit) = self.claim_bit(state, bitmap, start, count)? else {
+                continue;
+            };
+            let descriptor = &mut state.groups[group as usize];
+            descriptor.free_inodes -= 1;
+            if directory {
+                descriptor.directories += 1;
+            }
+            state.superblock.free_inodes = state.superblock.free_inodes.saturating_sub(1);
+            self.write_group(state, group as usize)?;
+            self.write_superblock(state)?;
+            return Ok(first + bit);
+        }
+        Err(FileSystemError::NoSpace)
+    }
+
+    /// Free an inode that has no links left, and everything it holds
+    fn free_inode(&self, state: &mut State, id: u32) -> FileSystemResult<()> {
+        let mut inode = self.read_inode(state, id)?;
+        let directory = inode.file_type() == Ok(FileType::Directory);
+        if inode.has_blocks(self.block_size) {
+            self.free_blocks_from(state, &mut inode, 0)?;
+        }
+        self.release_attributes(state, &mut inode)?;
+        inode.set_size(0);
+        inode.set_deleted(time::current_time().as_secs() as u32);
+        self.write_inode(state, id, &inode)?;
+
+        let superblock = &state.superblock;
+        let group = (id - 1) / superblock.inodes_per_group;
+        let bit = (id - 1) % superblock.inodes_per_group;
+        if self.release_bit(state, state.groups[group as usize].inode_bitmap, bit)? {
+            let descriptor = &mut state.groups[group as usize];
+            descriptor.free_inodes += 1;
+            if directory {
+                descriptor.directories = descriptor.directories.saturating_sub(1);
+            }
+            state.superblock.free_inodes += 1;
+            self.write_group(state, group as usize)?;
+            self.write_superblock(state)?;
+        }
+        Ok(())
+    }
+
+    // Drop the inode's reference to its extended attribute block, which inodes with
+    // the same attributes may share
+    fn release_attributes(&self, state: &mut State, inode: &mut DiskInode) -> FileSystemResult<()> {
+        let block = inode.attribute_block();
+        if block == 0 {
+            return Ok(());
+        }
+        let mut data = self.read_block(state, block.into())?;
+        let references = read_u32(&data, 4);
+        if references > 1 {
+            write_u32(&mut data, 4, references - 1);
+            self.write_block(state, block.into(), &data)?;
+            inode.set_sectors(inode.sectors().saturating_sub(self.block_size / 512));
+        } else {
+            self.free_block(state, block, inode)?;
+        }
+        inode.set_attribute_block(0);
+        Ok(())
+    }
+
+    /// Free the blocks of a file past the first `keep`
+    fn free_blocks_from(&self, state: &mut State, inode: &mut DiskInode, keep: u64) -> FileSystemResult<()> {
+        if inode.uses_extents() {
+            return Err(FileSystemError::NotSupported);
+        }
+        for slot in keep.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            let block = inode.block(slot as usize);
+            if block != 0 {
+                self.free_block(state, block, inode)?;
+                inode.set_block(slot as usize, 0);
+            }
+        }
+        let mut first = DIRECT_BLOCKS;
+        for (slot, depth) in INDIRECT_BLOCKS {
+            let block = inode.block(slot);
+            if block != 0 && self.free_branch(state, inode, block, depth, keep.saturating_sub(first))? {
+                inode.set_block(slot, 0);
+            }
+            first += self.pointers_per_block().pow(depth);
+        }
+        Ok(())
+    }
+
+    /// Free the blocks below an indirect block of `depth` levels past the first
+    /// `keep`, and the indirect block itself if none are kept; returns whether it was
+    fn free_branch(&self, state: &mut State, inode: &mut DiskInode, block: u32, depth: u32, keep: u64) -> FileSystemResult<bool> {
+        let pointers = self.pointers_per_block();
+        // Blocks of the file below each pointer of this block
+        let span = pointers.pow(depth - 1);
+        if keep >= pointers * span {
+            return Ok(false);
+        }
+        let mut data = self.read_block(state, block.into())?;
+        let mut changed = false;
+        for index in keep / span..pointers {
+            let child = read_u32(&data, index as usize * 4);
+            if child == 0 {
+                continue;
+            }
+            let freed = match depth {
+                1 => {
+                    self.free_block(state, child, inode)?;
+                    true
+                }
+                _ => self.free_branch(state, inode, child, depth - 1, keep.saturating_sub(index * span))?,
+            };
+            if freed {
+                write_u32(&mut data, index as usize * 4, 0);
+                changed = true;
+            }
+        }
+        if keep == 0 {
+            self.free_block(state, block, inode)?;
+            return Ok(true);
+        }
+        if changed {
+            self.write_block(state, block.into(), &data)?;
+        }
+        Ok(false)
+    }
+
+    fn read_data(&self, state: &State, inode: &DiskInode, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = (size - offset).min(buffer.len() as u64) as usize;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let length = ((self.block_size - within) as usize).min(count - done);
+            let chunk = &mut buffer[done..done + length];
+            match self.map_block(state, inode, position / self.block_size)? {
+                0 => chunk.fill(0),
+                block => {
+                    self.check_block(state, block)?;
+                    self.device.read_at(block * self.block_size + within, chunk)?;
+                }
+            }
+            done += length;
+        }
+        Ok(count)
+    }
+
+    /// Write to a file, allocating blocks as needed; returns how much was written
+    /// before the volume filled up. The caller writes back the inode.
+    fn write_data(&self, state: &mut State, id: u32, inode: &mut DiskInode, offset: u64, buffer: &[u8]) -> FileSystemResult<usize> {
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let length = ((self.block_size - within) as usize).min(buffer.len() - done);
+            let block = match self.map_or_allocate(state, id, inode, position / self.block_size) {
+                Ok(block) => block,
+                Err(error) => {
+                    result = Err(error);
                     break;
                 }
+            };
+            if let Err(error) = self.device.write_at(block as u64 * self.block_size + within, &buffer[done..done + length]) {
+                result = Err(error.into());
+                break;
             }
-            storage.write_block(new_block as u64, &block_buffer);
+            done += length;
         }
-    } else {
-        let mut new_entry = DirectoryEntry {
-            inode: 0,
-            size: 0,
-            name_len: 0,
-            file_type: 0,
-            name: [0; 256],
+        let end = offset + done as u64;
+        if end > inode.size() {
+            inode.set_size(end);
+            if end > i32::MAX as u64 && state.superblock.ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+                state.superblock.ro_compat |= RO_COMPAT_LARGE_FILE;
+                self.write_superblock(state)?;
+            }
+        }
+        match result {
+            Err(error) if done == 0 => Err(error),
+            _ => Ok(done),
+        }
+    }
+
+    // Zero the rest of the last block past `size`, so a later extension reads zeros
+    fn zero_tail(&self, state: &State, inode: &DiskInode, size: u64) -> FileSystemResult<()> {
+        let within = size % self.block_size;
+        if within == 0 {
+            return Ok(());
+        }
+        match self.map_block(state, inode, size / self.block_size)? {
+            0 => Ok(()),
+            block => {
+                let zeros = vec![0; (self.block_size - within) as usize];
+                self.check_block(state, block)?;
+                Ok(self.device.write_at(block * self.block_size + within, &zeros)?)
+            }
+        }
+    }
+
+    fn read_directory_block(&self, state: &State, directory: &DiskInode, index: u64) -> FileSystemResult<(u64, Vec<u8>)> {
+        let block = match self.map_block(state, directory, index)? {
+            0 => return Err(FileSystemError::Corrupted("hole in a directory")),
+            block => block,
+        };
+        let data = self.read_block(state, block)?;
+        if let Some(seed) = self.checksum_seed(state, directory) {
+            if !ext4::directory_block_checksum_ok(&data, seed) {
+                return Err(FileSystemError::Corrupted("directory block checksum"));
+            }
+        }
+        Ok((block, data))
+    }
+
+    /// All entries of a directory in use, including `.` and `..`
+    fn list_entries(&self, state: &State, directory: &DiskInode) -> FileSystemResult<Vec<RawEntry>> {
+        let mut entries = Vec::new();
+        for index in 0..directory.size() / self.block_size {
+            let (_, data) = self.read_directory_block(state, directory, index)?;
+            entries.extend(parse_entries(&data)?.into_iter().filter(|entry| entry.inode != 0));
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(&self, state: &State, directory: &DiskInode, name: &[u8]) -> FileSystemResult<Option<RawEntry>> {
+        let blocks = match self.indexed_blocks(state, directory, name)? {
+            Some(blocks) => blocks,
+            None => (0..directory.size() / self.block_size).collect(),
+        };
+        for index in blocks {
+            let (_, data) = self.read_directory_block(state, directory, index)?;
+            if let Some(entry) = parse_entries(&data)?.into_iter().find(|entry| entry.inode != 0 && entry.name == name) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The blocks of a directory that may hold `name`, found through its HTree
+    /// index, or `None` if it has no index this driver can use
+    fn indexed_blocks(&self, state: &State, directory: &DiskInode, name: &[u8]) -> FileSystemResult<Option<Vec<u64>>> {
+        let superblock = &state.superblock;
+        // `.` and `..` are always at the start of the first block, ahead of the index
+        if directory.flags() & INDEX_FLAG == 0 || superblock.compat & COMPAT_DIR_INDEX == 0 || is_dot(name) {
+            return Ok(None);
+        }
+        let seed = self.checksum_seed(state, directory);
+        let (_, data) = self.read_directory_block(state, directory, 0)?;
+        let root = DxRoot::parse(&data, seed)?;
+        let version = match root.hash_version {
+            version if version <= ext4::HASH_TEA && superblock.flags & FLAG_UNSIGNED_HASH != 0 => version + ext4::HASH_UNSIGNED,
+            version => version,
         };
-        let new_inode = storage.allocate_inode();
-        let mut inode = Inode {
-            mode: 0o100644,
-            uid: 0,
-            size: 0,
-            atime: 0,
-            ctime: 0,
-            mtime: 0,
-            dtime: 0,
-            gid: 0,
-            links_count: 1,
-            block_pointers: [0; 15],
-            flags: 0,
-            osd1: 0,
-            generation: 0,
-            file_acl: 0,
-            size_high: 0,
-            fragment_block: 0,
-            osd2: [0; 2],
+        let Some(hash) = ext4::dx_hash(name, version, superblock.hash_seed) else {
+            return Ok(None);
+        };
+        if root.levels > MAX_INDEX_LEVELS {
+            return Ok(None);
+        }
+        let block_count = directory.size() / self.block_size;
+        let read_node = |entry: &DxEntry| -> FileSystemResult<Vec<DxEntry>> {
+            if entry.block as u64 >= block_count {
+                return Err(FileSystemError::Corrupted("directory index"));
+            }
+            let (_, data) = self.read_directory_block(state, directory, entry.block as u64)?;
+            ext4::parse_dx_node(&data, seed)
         };
-        let new_block = storage.allocate_block();
 
-        let mut position = 0;
-        while position < new_entry.name.len() {
-            let byte = file_name.as_bytes()[position];
-            new_entry.name[position] = byte;
-            if byte == 0 {
-                break;
+        // Down the tree to the last entry whose hash is not above the name's
+        let mut path = Vec::new();
+        let mut entries = root.entries;
+        loop {
+            let position = entries.partition_point(|entry| entry.hash <= hash).saturating_sub(1);
+            let below = (path.len() < root.levels as usize).then(|| read_node(&entries[position])).transpose()?;
+            path.push((entries, position));
+            match below {
+                Some(node) => entries = node,
+                None => break,
             }
-            position += 1;
-        }
-        new_entry.name_len = position as u8;
-        new_entry.inode = new_inode;
-        new_entry.size = (8 + new_entry.name_len as u32 + 3) & !3;
-        new_entry.file_type = 1; // regular file
-
-        inode.block_pointers[0] = new_block as u32;
-        inode.size = content.len() as u32;
-        let mut position = 0;
-        while position < EXT2_BLOCK_SIZE {
-            let size = min(EXT2_BLOCK_SIZE - position, content.len());
-            block_buffer[position..position + size].copy_from_slice(&content[..size].as_bytes());
-            content = &content[size..];
-            position += size;
-            if content.is_empty() {
+        }
+
+        // Names with the same hash may continue into the following leaves, which
+        // then start with that hash
+        let mut blocks = Vec::new();
+        loop {
+            let (entries, position) = path.last().unwrap();
+            let block = entries[*position].block as u64;
+            if block >= block_count {
+                return Err(FileSystemError::Corrupted("directory index"));
+            }
+            blocks.push(block);
+            let Some(level) = path.iter().rposition(|(entries, position)| position + 1 < entries.len()) else {
+                break;
+            };
+            path.truncate(level + 1);
+            path[level].1 += 1;
+            let (entries, position) = &path[level];
+            if entries[*position].hash & !1 != hash {
                 break;
             }
+            while path.len() <= root.levels as usize {
+                let (entries, position) = path.last().unwrap();
+                let node = read_node(&entries[*position])?;
+                path.push((node, 0));
+            }
         }
-        storage.write_block(new_block as u64, &block_buffer);
-        storage.write_inode(new_inode, &inode, &block_group_size);
+        Ok(Some(blocks))
+    }
+
+    fn is_empty_directory(&self, state: &State, directory: &DiskInode) -> FileSystemResult<bool> {
+        Ok(self.list_entries(state, directory)?.iter().all(|entry| is_dot(&entry.name)))
+    }
 
-        directory_entries.push(new_entry);
-        let mut directory_buffer = [0; EXT2_BLOCK_SIZE];
-        let mut position = 0;
-        for entry in directory_entries.iter() {
-            directory_buffer[position..position + 8].copy_from_slice(&entry.inode.to_le_bytes());
-            directory_buffer[position + 4..position + 8].copy_from_slice(&entry.size.to_le_bytes());
-            directory_buffer[position + 8..position + 9].copy_from_slice(&[entry.name_len]);
-            directory_buffer[position + 9..position + 10].copy_from_slice(&[entry.file_type]);
-            directory_buffer[position + 10..position + 8 + entry.name_len as usize].copy_from_slice(&entry.name[..entry.name_len as usize]);
-            position += (8 + entry.name_len as usize + 3) & !3;
+    // The type code of a new entry, if directory entries have them on this volume
+    fn entry_type(&self, state: &State, file_type: FileType) -> u8 {
+        match state.superblock.incompat & INCOMPAT_FILETYPE {
+            0 => 0,
+            _ => type_code(file_type),
         }
-        storage.write_block(directory_inode.block_pointers[0] as u64, &directory_buffer);
     }
-}
 
-// Allocate a block and write it with zeros
-fn allocate_block(storage: &mut Storage) -> u32 {
-    let block = storage.allocate_block();
-    let mut buffer = [0; EXT2_BLOCK_SIZE];
-    storage.write_block(block as u64, &buffer);
-    block
-}
+    fn entry_file_type(&self, state: &State, entry: &RawEntry) -> FileSystemResult<FileType> {
+        match file_type_of_code(entry.file_type) {
+            Some(file_type) if state.superblock.incompat & INCOMPAT_FILETYPE != 0 => Ok(file_type),
+            _ => self.read_inode(state, entry.inode)?.file_type(),
+        }
+    }
 
-// Find the inode corresponding to a file or directory
-fn find_inode(path: &str, storage: &Storage, block_group_size: &usize) -> Inode {
-    let mut inode = read_inode(EXT2_ROOT_INODE, storage, block_group_size);
-    let mut components = path.split('/').filter(|c| !c.is_empty());
-    while let Some(component) = components.next() {
-        let directory_entries = read_directory_entries(&inode, storage);
-        let mut found = false;
-        for entry in directory_entries.iter() {
-            let name = str::from_utf8(&entry.name[..entry.name_len
-                as usize])
-                .unwrap();
-            if name == component {
-                inode = read_inode(entry.inode, storage, block_group_size);
-                found = true;
-                break;
+    // Note a change to a directory's entries, which also invalidates any HTree index
+    fn directory_changed(&self, state: &State, id: u32, directory: &mut DiskInode) -> FileSystemResult<()> {
+        directory.set_flags(directory.flags() & !INDEX_FLAG);
+        directory.touch();
+        self.write_inode(state, id, directory)
+    }
+
+    /// Add an entry to a directory, in the first gap large enough or a new block
+    fn add_entry(&self, state: &mut State, directory_id: u32, name: &str, id: u32, file_type: FileType) -> FileSystemResult<()> {
+        let mut directory = self.directory(state, directory_id)?;
+        let name = name.as_bytes();
+        let needed = entry_length(name.len());
+        let type_code = self.entry_type(state, file_type);
+        for index in 0..directory.size() / self.block_size {
+            let (block, mut data) = self.read_directory_block(state, &directory, index)?;
+            for entry in parse_entries(&data)? {
+                let used = if entry.inode == 0 { 0 } else { entry_length(entry.name.len()) };
+                if entry.length - used < needed {
+                    continue;
+                }
+                // Split the space after an entry in use, or take over an unused one
+                if used > 0 {
+                    write_u16(&mut data, entry.offset + 4, encode_record_length(used));
+                }
+                write_entry(&mut data, entry.offset + used, entry.length - used, id, name, type_code);
+                self.write_block(state, block, &data)?;
+                return self.directory_changed(state, directory_id, &mut directory);
+            }
+        }
+
+        let index = directory.size() / self.block_size;
+        let result = self.map_or_allocate(state, directory_id, &mut directory, index);
+        let block = match result {
+            Ok(block) => block,
+            Err(error) => {
+                // Indirect blocks may have been allocated
+                self.write_inode(state, directory_id, &directory)?;
+                return Err(error);
+            }
+        };
+        let mut data = vec![0; self.block_size as usize];
+        write_entry(&mut data, 0, self.block_size as usize, id, name, type_code);
+        self.write_block(state, block.into(), &data)?;
+        directory.set_size((index + 1) * self.block_size);
+        self.directory_changed(state, directory_id, &mut directory)
+    }
+
+    /// Remove an entry from a directory, merging its space into the entry before it
+    fn remove_entry(&self, state: &mut State, directory_id: u32, name: &str) -> FileSystemResult<u32> {
+        let mut directory = self.directory(state, directory_id)?;
+        for index in 0..directory.size() / self.block_size {
+            let (block, mut data) = self.read_directory_block(state, &directory, index)?;
+            let entries = parse_entries(&data)?;
+            let Some(position) = entries.iter().position(|entry| entry.inode != 0 && entry.name == name.as_bytes()) else {
+                continue;
+            };
+            let entry = &entries[position];
+            match position.checked_sub(1).map(|previous| &entries[previous]) {
+                Some(previous) => {
+                    write_u16(&mut data, previous.offset + 4, encode_record_length(previous.length + entry.length))
+                }
+                None => write_u32(&mut data, entry.offset, 0),
+            }
+            self.write_block(state, block, &data)?;
+            self.directory_changed(state, directory_id, &mut directory)?;
+            return Ok(entry.inode);
+        }
+        Err(FileSystemError::NotFound)
+    }
+
+    /// Point an existing entry of a directory at another inode
+    fn replace_entry(&self, state: &mut State, directory_id: u32, name: &str, id: u32, file_type: FileType) -> FileSystemResult<()> {
+        let mut directory = self.directory(state, directory_id)?;
+        for index in 0..directory.size() / self.block_size {
+            let (block, mut data) = self.read_directory_block(state, &directory, index)?;
+            let entries = parse_entries(&data)?;
+            if let Some(entry) = entries.iter().find(|entry| entry.inode != 0 && entry.name == name.as_bytes()) {
+                write_u32(&mut data, entry.offset, id);
+                data[entry.offset + 7] = self.entry_type(state, file_type);
+                self.write_block(state, block, &data)?;
+                return self.directory_changed(state, directory_id, &mut directory);
             }
         }
-        if !found {
-            panic!("File not found: {}", path);
+        Err(FileSystemError::NotFound)
+    }
+
+    fn add_links(&self, state: &State, id: u32, count: i32) -> FileSystemResult<()> {
+        let mut inode = self.read_inode(state, id)?;
+        let links = inode.links() as i32 + count;
+        if links > LINK_MAX as i32 {
+            return Err(FileSystemError::TooManyLinks);
+        }
+        inode.set_links(links.max(0) as u16);
+        inode.set_time(CHANGED, time::current_time());
+        self.write_inode(state, id, &inode)
+    }
+
+    /// Take away the link from `parent` to inode `id`, freeing the inode when
+    /// nothing refers to it any more
+    fn drop_link(&self, state: &mut State, parent: u32, id: u32) -> FileSystemResult<()> {
+        let mut inode = self.read_inode(state, id)?;
+        if inode.file_type()? == FileType::Directory {
+            // Its `.` goes with it, and its `..` no longer links the parent
+            inode.set_links(0);
+            self.add_links(state, parent, -1)?;
+        } else {
+            inode.set_links(inode.links().saturating_sub(1));
+        }
+        inode.set_time(CHANGED, time::current_time());
+        self.write_inode(state, id, &inode)?;
+        // An open file is freed when it is closed
+        if inode.links() == 0 && !self.is_open(id) {
+            self.free_inode(state, id)?;
+        }
+        Ok(())
+    }
+
+    /// A new inode of `file_type`, with every time set to now
+    fn new_inode(&self, state: &State, id: u32, file_type: FileType, permissions: u16) -> DiskInode {
+        let superblock = &state.superblock;
+        let mut inode = DiskInode::new(id, superblock.inode_size, superblock.extra_inode_size);
+        write_u16(&mut inode.raw, 0, file_type.mode_bits() as u16 | permissions & PERMISSION_MASK);
+        inode.set_links(1);
+        let now = time::current_time();
+        for field in [ACCESSED, CHANGED, MODIFIED, CREATED] {
+            inode.set_time(field, now);
+        }
+        inode
+    }
+
+    /// Allocate and write an inode for a new entry of directory `parent`
+    fn create_inode(&self, state: &mut State, parent: u32, file_type: FileType, permissions: u16, target: &[u8]) -> FileSystemResult<u32> {
+        let directory = file_type == FileType::Directory;
+        let id = self.allocate_inode(state, parent, directory)?;
+        let mut inode = self.new_inode(state, id, file_type, permissions);
+        let result = match file_type {
+            FileType::Directory => {
+                // `.` and `..`, the first covering only itself
+                inode.set_links(2);
+                self.map_or_allocate(state, id, &mut inode, 0).and_then(|block| {
+                    let mut data = vec![0; self.block_size as usize];
+                    let type_code = self.entry_type(state, FileType::Directory);
+                    write_entry(&mut data, 0, 12, id, b".", type_code);
+                    write_entry(&mut data, 12, self.block_size as usize - 12, parent, b"..", type_code);
+                    inode.set_size(self.block_size);
+                    self.write_block(state, block.into(), &data)
+                })
+            }
+            FileType::Symlink if target.len() < FAST_SYMLINK_MAX => {
+                inode.raw[40..40 + target.len()].copy_from_slice(target);
+                inode.set_size(target.len() as u64);
+                Ok(())
+            }
+            FileType::Symlink => self.write_data(state, id, &mut inode, 0, target).map(|_| ()),
+            _ => Ok(()),
+        };
+        self.write_inode(state, id, &inode)?;
+        if let Err(error) = result {
+            inode.set_links(0);
+            self.write_inode(state, id, &inode)?;
+            self.free_inode(state, id)?;
+            return Err(error);
         }
+        Ok(id)
+    }
+}
+
+impl FileSystem for Ext2FileSystem {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn root(&self) -> FileSystemResult<Arc<dyn Inode>> {
+        Ok(self.inode(ROOT_INODE))
+    }
+
+    fn stats(&self) -> FileSystemResult<FileSystemStats> {
+        let state = self.state.lock();
+        let superblock = &state.superblock;
+        Ok(FileSystemStats {
+            block_size: self.block_size as u32,
+            blocks: superblock.blocks_count,
+            free_blocks: superblock.free_blocks,
+            inodes: superblock.inodes_count as u64,
+            free_inodes: superblock.free_inodes as u64,
+            max_name_len: MAX_NAME_LEN as u32,
+        })
     }
 
-    inode
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn sync(&self) -> FileSystemResult<()> {
+        if !self.read_only {
+            let mut state = self.state.lock();
+            state.superblock.state |= STATE_VALID;
+            write_u32(&mut state.superblock.raw, 48, time::current_time().as_secs() as u32);
+            self.write_superblock(&mut state)?;
+        }
+        Ok(self.device.flush()?)
+    }
 }
 
-// Read an inode from disk
-fn read_inode(inode_number: u32, storage: &Storage, block_group_size: &usize) -> Inode {
-    let block_group = (inode_number - 1) / (*block_group_size as u32);
-    let offset = (inode_number - 1) % (*block_group_size as u32);
-    let block_group_desc = storage.read_block_group_descriptor(block_group);
-    let block_size = 1024 << block_group_desc.log_block_size;
-    let mut buffer = vec![0; INODE_SIZE];
-    let inode_table_block = block_group_desc.inode_table_address + (offset as u64 * INODE_SIZE as u64 / block_size);
-    let inode_table_offset = (offset as usize * INODE_SIZE) % block_size as usize;
-    storage.read(inode_table_block as usize * block_size as usize + inode_table_offset, &mut buffer);
-
-    let mut inode = Inode::default();
-    let mut cursor = Cursor::new(&buffer[..]);
-    cursor.read_exact(&mut inode.mode.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.uid.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.size.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.atime.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.ctime.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.mtime.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.dtime.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.gid.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.links_count.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.block_pointers[..]).unwrap();
-    cursor.read_exact(&mut inode.flags.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.osd1.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.generation.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.file_acl.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.size_high.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.fragment_block.to_le_bytes()).unwrap();
-    cursor.read_exact(&mut inode.osd2[..]).unwrap();
-
-    inode
+/// An inode of an ext2 volume; its state lives on the device
+pub struct Ext2Inode {
+    fs: Arc<Ext2FileSystem>,
+    id: u32,
 }
 
-// Read the directory entries from a directory inode
-fn read_directory_entries(inode: &Inode, storage: &Storage) -> Vec<DirectoryEntry> {
-    let mut directory_entries = Vec::new();
-    let mut directory_buffer = [0; EXT2_BLOCK_SIZE];
-    let block_size = EXT2_BLOCK_SIZE as u64;
-    let mut position = 0;
-    while position < inode.size {
-        let block = position / block_size;
-        let block_offset = position % block_size;
-        storage.read_block(inode.block_pointers[block as usize] as u64, &mut directory_buffer);
-        while position < inode.size && (position % block_size) + 8 <= block_size {
-            let mut entry = DirectoryEntry::default();
-            entry.inode = u32::from_le_bytes([
-                directory_buffer[position],
-                directory_buffer[position + 1],
-                directory_buffer[position + 2],
-                directory_buffer[position + 3],
-            ]);
-            entry.size = u32::from_le_bytes([
-                                                directory_buffer[position + 4],
-                                                directory_buffer[position + 5],
-                                                directory_buffer[position + 6],
-                                                directory_buffer[position + 7],
-                                            ]);
-            entry.name_len = directory_buffer[position + 8];
-            entry.file_type = directory_buffer[position + 7];
-            let name_len = entry.name_len as usize;
-            let mut name_buffer = vec![0; name_len];
-            for i in 0..name_len {
-                name_buffer[i] = directory_buffer[position + 8 + i];
-            }
-            entry.name = String::from_utf8(name_buffer).unwrap();
-
-            if entry.inode != 0 {
-                directory_entries.push(entry);
-            }
-
-            position += entry.size as u64;
-        }
-    }
-
-    directory_entries
+impl Ext2Inode {
+    /// The ext2 inode behind `inode`, if it is on the same volume
+    fn same_filesystem<'a>(&self, inode: &'a Arc<dyn Inode>) -> FileSystemResult<&'a Ext2Inode> {
+        match inode.as_any().downcast_ref::<Ext2Inode>() {
+            Some(inode) if Arc::ptr_eq(&inode.fs, &self.fs) => Ok(inode),
+            _ => Err(FileSystemError::CrossDevice),
+        }
+    }
+
+    /// Add an entry for a new inode to this directory
+    fn create_entry(&self, name: &str, file_type: FileType, permissions: u16, target: &[u8]) -> FileSystemResult<Arc<dyn Inode>> {
+        check_name(name)?;
+        let fs = &self.fs;
+        let mut state = fs.lock_for_write()?;
+        let directory = fs.directory(&state, self.id)?;
+        // A removed directory stays usable while open, but gets no new entries
+        if directory.links() == 0 {
+            return Err(FileSystemError::NotFound);
+        }
+        if fs.find_entry(&state, &directory, name.as_bytes())?.is_some() {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        if file_type == FileType::Directory && directory.links() >= LINK_MAX {
+            return Err(FileSystemError::TooManyLinks);
+        }
+        let id = fs.create_inode(&mut state, self.id, file_type, permissions, target)?;
+        if let Err(error) = fs.add_entry(&mut state, self.id, name, id, file_type) {
+            fs.add_links(&state, id, -2)?;
+            fs.free_inode(&mut state, id)?;
+            return Err(error);
+        }
+        if file_type == FileType::Directory {
+            fs.add_links(&state, self.id, 1)?;
+        }
+        drop(state);
+        Ok(fs.inode(id))
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        // An inode unlinked while open is freed once the last user lets go of it
+        if self.fs.read_only {
+            return;
+        }
+        let mut state = self.fs.state.lock();
+        if let Ok(inode) = self.fs.read_inode(&state, self.id) {
+            if inode.links() == 0 && inode.mode() != 0 && inode.deleted() == 0 {
+                let _ = self.fs.free_inode(&mut state, self.id);
+            }
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        let state = self.fs.state.lock();
+        let inode = self.fs.read_inode(&state, self.id)?;
+        self.fs.metadata(self.id, &inode)
+    }
+
+    fn set_attributes(&self, changes: &SetAttributes) -> FileSystemResult<()> {
+        let state = self.fs.lock_for_write()?;
+        let mut inode = self.fs.read_inode(&state, self.id)?;
+        if let Some(permissions) = changes.permissions {
+            inode.set_permissions(permissions);
+        }
+        if let Some(uid) = changes.uid {
+            inode.set_uid(uid);
+        }
+        if let Some(gid) = changes.gid {
+            inode.set_gid(gid);
+        }
+        if let Some(accessed) = changes.accessed {
+            inode.set_time(ACCESSED, accessed);
+        }
+        if let Some(modified) = changes.modified {
+            inode.set_time(MODIFIED, modified);
+        }
+        inode.set_time(CHANGED, time::current_time());
+        self.fs.write_inode(&state, self.id, &inode)
+    }
+
+    // Reads do not update the access time, as if mounted with `noatime`
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        let state = self.fs.state.lock();
+        let inode = self.fs.read_inode(&state, self.id)?;
+        match inode.file_type()? {
+            FileType::Regular => self.fs.read_data(&state, &inode, offset, buffer),
+            FileType::Directory => Err(FileSystemError::IsADirectory),
+            _ => Err(FileSystemError::InvalidArgument),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FileSystemResult<usize> {
+        let mut state = self.fs.lock_for_write()?;
+        let mut inode = self.fs.read_inode(&state, self.id)?;
+        match inode.file_type()? {
+            FileType::Regular => {}
+            FileType::Directory => return Err(FileSystemError::IsADirectory),
+            _ => return Err(FileSystemError::InvalidArgument),
+        }
+        offset.checked_add(buffer.len() as u64).ok_or(FileSystemError::InvalidArgument)?;
+        let result = self.fs.write_data(&mut state, self.id, &mut inode, offset, buffer);
+        inode.touch();
+        self.fs.write_inode(&state, self.id, &inode)?;
+        result
+    }
+
+    fn truncate(&self, size: u64) -> FileSystemResult<()> {
+        let mut state = self.fs.lock_for_write()?;
+        let mut inode = self.fs.read_inode(&state, self.id)?;
+        match inode.file_type()? {
+            FileType::Regular => {}
+            FileType::Directory => return Err(FileSystemError::IsADirectory),
+            _ => return Err(FileSystemError::InvalidArgument),
+        }
+        if size < inode.size() {
+            self.fs.free_blocks_from(&mut state, &mut inode, size.div_ceil(self.fs.block_size))?;
+            self.fs.zero_tail(&state, &inode, size)?;
+        } else if size > 0 {
+            // Extending leaves a hole, which reads as zeros, but the block pointers
+            // must be able to reach its end
+            self.fs.block_path((size - 1) / self.fs.block_size)?;
+        }
+        inode.set_size(size);
+        inode.touch();
+        self.fs.write_inode(&state, self.id, &inode)
+    }
+
+    fn lookup(&self, name: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        let state = self.fs.state.lock();
+        let directory = self.fs.directory(&state, self.id)?;
+        let entry = self.fs.find_entry(&state, &directory, name.as_bytes())?.ok_or(FileSystemError::NotFound)?;
+        drop(state);
+        Ok(self.fs.inode(entry.inode))
+    }
+
+    fn read_directory(&self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        let state = self.fs.state.lock();
+        let directory = self.fs.directory(&state, self.id)?;
+        self.fs
+            .list_entries(&state, &directory)?
+            .into_iter()
+            .filter(|entry| !is_dot(&entry.name))
+            .map(|entry| {
+                Ok(DirectoryEntry {
+                    file_type: self.fs.entry_file_type(&state, &entry)?,
+                    name: String::from_utf8_lossy(&entry.name).into_owned(),
+                    inode: entry.inode as InodeId,
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, name: &str, file_type: FileType, permissions: u16) -> FileSystemResult<Arc<dyn Inode>> {
+        if file_type == FileType::Symlink {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        self.create_entry(name, file_type, permissions, &[])
+    }
+
+    fn link(&self, name: &str, target: &Arc<dyn Inode>) -> FileSystemResult<()> {
+        check_name(name)?;
+        let target = self.same_filesystem(target)?;
+        let fs = &self.fs;
+        let mut state = fs.lock_for_write()?;
+        let directory = fs.directory(&state, self.id)?;
+        let inode = fs.read_inode(&state, target.id)?;
+        let file_type = inode.file_type()?;
+        if file_type == FileType::Directory {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        if directory.links() == 0 || inode.links() == 0 {
+            return Err(FileSystemError::NotFound);
+        }
+        if fs.find_entry(&state, &directory, name.as_bytes())?.is_some() {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        fs.add_links(&state, target.id, 1)?;
+        if let Err(error) = fs.add_entry(&mut state, self.id, name, target.id, file_type) {
+            fs.add_links(&state, target.id, -1)?;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> FileSystemResult<()> {
+        let fs = &self.fs;
+        let mut state = fs.lock_for_write()?;
+        let directory = fs.directory(&state, self.id)?;
+        let entry = fs.find_entry(&state, &directory, name.as_bytes())?.ok_or(FileSystemError::NotFound)?;
+        if fs.read_inode(&state, entry.inode)?.file_type()? == FileType::Directory {
+            return Err(FileSystemError::IsADirectory);
+        }
+        fs.remove_entry(&mut state, self.id, name)?;
+        fs.drop_link(&mut state, self.id, entry.inode)
+    }
+
+    fn remove_directory(&self, name: &str) -> FileSystemResult<()> {
+        if is_dot(name.as_bytes()) {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let fs = &self.fs;
+        let mut state = fs.lock_for_write()?;
+        let directory = fs.directory(&state, self.id)?;
+        let entry = fs.find_entry(&state, &directory, name.as_bytes())?.ok_or(FileSystemError::NotFound)?;
+        let removed = fs.directory(&state, entry.inode)?;
+        if !fs.is_empty_directory(&state, &removed)? {
+            return Err(FileSystemError::DirectoryNotEmpty);
+        }
+        fs.remove_entry(&mut state, self.id, name)?;
+        fs.drop_link(&mut state, self.id, entry.inode)
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> FileSystemResult<()> {
+        check_name(new_name)?;
+        let new_parent = self.same_filesystem(new_parent)?.id;
+        let fs = &self.fs;
+        let mut state = fs.lock_for_write()?;
+        let old_directory = fs.directory(&state, self.id)?;
+        let new_directory = fs.directory(&state, new_parent)?;
+        if new_directory.links() == 0 {
+            return Err(FileSystemError::NotFound);
+        }
+        let entry = fs.find_entry(&state, &old_directory, old_name.as_bytes())?;
+        let id = match entry {
+            Some(entry) if !is_dot(&entry.name) => entry.inode,
+            Some(_) => return Err(FileSystemError::InvalidArgument),
+            None => return Err(FileSystemError::NotFound),
+        };
+        let file_type = fs.read_inode(&state, id)?.file_type()?;
+        let directory = file_type == FileType::Directory;
+        let moves_directory = directory && new_parent != self.id;
+
+        // A directory cannot move into itself or its own subdirectories
+        if moves_directory {
+            let mut ancestor = new_parent;
+            while ancestor != ROOT_INODE {
+                if ancestor == id {
+                    return Err(FileSystemError::InvalidArgument);
+                }
+                let inode = fs.directory(&state, ancestor)?;
+                ancestor = fs.find_entry(&state, &inode, b"..")?.ok_or(FileSystemError::Corrupted("missing .."))?.inode;
+            }
+            if new_directory.links() >= LINK_MAX {
+                return Err(FileSystemError::TooManyLinks);
+            }
+        }
+
+        // Whatever is at the new name is replaced, if it is compatible
+        match fs.find_entry(&state, &new_directory, new_name.as_bytes())? {
+            Some(existing) if existing.inode == id => return Ok(()),
+            Some(existing) => {
+                let replaced = fs.read_inode(&state, existing.inode)?;
+                match (directory, replaced.file_type()? == FileType::Directory) {
+                    (true, true) if !fs.is_empty_directory(&state, &replaced)? => {
+                        return Err(FileSystemError::DirectoryNotEmpty)
+                    }
+                    (true, false) => return Err(FileSystemError::NotADirectory),
+                    (false, true) => return Err(FileSystemError::IsADirectory),
+                    _ => {}
+                }
+                fs.replace_entry(&mut state, new_parent, new_name, id, file_type)?;
+                fs.drop_link(&mut state, new_parent, existing.inode)?;
+            }
+            None => fs.add_entry(&mut state, new_parent, new_name, id, file_type)?,
+        }
+        fs.remove_entry(&mut state, self.id, old_name)?;
+
+        if moves_directory {
+            fs.replace_entry(&mut state, id, "..", new_parent, FileType::Directory)?;
+            fs.add_links(&state, self.id, -1)?;
+            fs.add_links(&state, new_parent, 1)?;
+        }
+        // Only the change time of the moved inode changes
+        fs.add_links(&state, id, 0)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        if target.is_empty() {
+            return Err(FileSystemError::NotFound);
+        }
+        if target.len() >= self.fs.block_size as usize {
+            return Err(FileSystemError::NameTooLong);
+        }
+        self.create_entry(name, FileType::Symlink, 0o777, target.as_bytes())
+    }
+
+    fn read_link(&self) -> FileSystemResult<String> {
+        let state = self.fs.state.lock();
+        let inode = self.fs.read_inode(&state, self.id)?;
+        if inode.file_type()? != FileType::Symlink {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let size = inode.size() as usize;
+        let target = if inode.is_fast_symlink(self.fs.block_size) {
+            inode.raw.get(40..40 + size).ok_or(FileSystemError::Corrupted("symlink length"))?.to_vec()
+        } else {
+            let mut target = vec![0; size];
+            self.fs.read_data(&state, &inode, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| FileSystemError::Corrupted("symlink target"))
+    }
+
+    fn sync(&self) -> FileSystemResult<()> {
+        Ok(self.fs.device.flush()?)
+    }
 }
@@ -0,0 +1,384 @@
+//! ext4 structures
+//!
+//! The parts of ext4 the ext2 driver needs to read newer volumes: extent trees,
+//! which map a file in runs of blocks rather than one pointer per block; HTree
+//! indexes, which find a directory entry from a hash of its name; and the CRC32c
+//! checksums that guard metadata on volumes with `metadata_csum`.
+
+use crate::fs::vfs::{FileSystemError, FileSystemResult};
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+const EXTENT_HEADER_SIZE: usize = 12;
+const EXTENT_ENTRY_SIZE: usize = 12;
+
+/// Deepest extent tree Linux builds
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+// Extents longer than this are uninitialized: allocated, but reading as zeros
+const MAX_INITIALIZED_LENGTH: u16 = 32768;
+
+// HTree hash versions, with the unsigned variants three above the signed ones
+pub const HASH_LEGACY: u8 = 0;
+pub const HASH_HALF_MD4: u8 = 1;
+pub const HASH_TEA: u8 = 2;
+pub const HASH_UNSIGNED: u8 = 3;
+
+// Offset of the entry count in an index node below the root, past an empty entry
+const DX_NODE_COUNT_OFFSET: usize = 8;
+const DX_ENTRY_SIZE: usize = 8;
+
+/// Bits of an index entry holding the directory block
+const DX_BLOCK_MASK: u32 = 0x0FFF_FFFF;
+
+// The first hash of a name past the last one
+const HTREE_EOF: u32 = 0x7FFF_FFFF;
+
+// Marks the fake directory entry holding a leaf block's checksum
+const DIRENT_TAIL_SIZE: usize = 12;
+const DIRENT_TAIL_TYPE: u8 = 0xDE;
+
+// Where an inode keeps the two halves of its checksum
+const INODE_CHECKSUM_LO: usize = 0x7C;
+const INODE_CHECKSUM_HI: usize = 0x82;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// CRC32c as ext4 and JBD2 use it: the caller picks the seed, and nothing is inverted
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0x82F6_3B78 & mask);
+        }
+    }
+    crc
+}
+
+/// CRC16 of the older `gdt_csum` group descriptor checksums
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xA001 & mask);
+        }
+    }
+    crc
+}
+
+/// A run of blocks of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// First block of the file it covers
+    pub logical: u32,
+    pub length: u32,
+    /// First block on the volume
+    pub start: u64,
+    /// Uninitialized extents are allocated but read as zeros
+    pub initialized: bool,
+}
+
+impl Extent {
+    /// The block on the volume holding block `index` of the file, if this extent covers it
+    pub fn map(&self, index: u32) -> Option<u64> {
+        let offset = index.checked_sub(self.logical)?;
+        (offset < self.length).then_some(self.start + offset as u64)
+    }
+}
+
+/// A node of an extent tree, either in the inode's block pointers or a block of its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentNode {
+    /// A node `depth` levels above the leaves: the first file block below each
+    /// child, and the block holding the child
+    Index { depth: u16, children: Vec<(u32, u64)> },
+    Leaf(Vec<Extent>),
+}
+
+impl ExtentNode {
+    pub fn parse(data: &[u8]) -> FileSystemResult<ExtentNode> {
+        if data.len() < EXTENT_HEADER_SIZE || read_u16(data, 0) != EXTENT_MAGIC {
+            return Err(FileSystemError::Corrupted("extent header"));
+        }
+        let (count, max, depth) = (read_u16(data, 2) as usize, read_u16(data, 4) as usize, read_u16(data, 6));
+        if count > max || EXTENT_HEADER_SIZE + max * EXTENT_ENTRY_SIZE > data.len() || depth > MAX_EXTENT_DEPTH {
+            return Err(FileSystemError::Corrupted("extent header"));
+        }
+        let entries = (0..count).map(|index| &data[EXTENT_HEADER_SIZE + index * EXTENT_ENTRY_SIZE..][..EXTENT_ENTRY_SIZE]);
+        if depth == 0 {
+            let extents = entries
+                .map(|entry| {
+                    let length = read_u16(entry, 4);
+                    let initialized = length <= MAX_INITIALIZED_LENGTH;
+                    Extent {
+                        logical: read_u32(entry, 0),
+                        length: if initialized { length } else { length - MAX_INITIALIZED_LENGTH } as u32,
+                        start: (read_u16(entry, 6) as u64) << 32 | read_u32(entry, 8) as u64,
+                        initialized,
+                    }
+                })
+                .collect();
+            return Ok(ExtentNode::Leaf(extents));
+        }
+        let children = entries
+            .map(|entry| (read_u32(entry, 0), (read_u16(entry, 8) as u64) << 32 | read_u32(entry, 4) as u64))
+            .collect();
+        Ok(ExtentNode::Index { depth, children })
+    }
+
+    pub fn depth(&self) -> u16 {
+        match self {
+            ExtentNode::Index { depth, .. } => *depth,
+            ExtentNode::Leaf(_) => 0,
+        }
+    }
+}
+
+/// Whether the checksum after the last possible entry of an extent block matches
+pub fn extent_block_checksum_ok(block: &[u8], seed: u32) -> bool {
+    let end = EXTENT_HEADER_SIZE + read_u16(block, 4) as usize * EXTENT_ENTRY_SIZE;
+    end + 4 <= block.len() && crc32c(seed, &block[..end]) == read_u32(block, end)
+}
+
+/// Whether the checksum in a directory leaf block's tail matches; blocks without
+/// a tail, such as index blocks, have nothing to check here
+pub fn directory_block_checksum_ok(block: &[u8], seed: u32) -> bool {
+    let tail = block.len() - DIRENT_TAIL_SIZE;
+    let is_tail = read_u32(block, tail) == 0
+        && read_u16(block, tail + 4) as usize == DIRENT_TAIL_SIZE
+        && block[tail + 6] == 0
+        && block[tail + 7] == DIRENT_TAIL_TYPE;
+    !is_tail || crc32c(seed, &block[..tail]) == read_u32(block, tail + 8)
+}
+
+/// Whether an inode's checksum matches. All-zero inodes have never been written.
+pub fn inode_checksum_ok(raw: &[u8], seed: u32) -> bool {
+    if raw.iter().all(|byte| *byte == 0) {
+        return true;
+    }
+    let zero = [0; 2];
+    let mut crc = crc32c(seed, &raw[..INODE_CHECKSUM_LO]);
+    crc = crc32c(crc, &zero);
+    crc = crc32c(crc, &raw[INODE_CHECKSUM_LO + 2..GOOD_OLD_INODE_SIZE]);
+    let mut stored = read_u16(raw, INODE_CHECKSUM_LO) as u32;
+    // The high half only exists if the extra fields reach it
+    let has_high = raw.len() > GOOD_OLD_INODE_SIZE && GOOD_OLD_INODE_SIZE + read_u16(raw, 128) as usize >= INODE_CHECKSUM_HI + 2;
+    if raw.len() > GOOD_OLD_INODE_SIZE {
+        crc = crc32c(crc, &raw[GOOD_OLD_INODE_SIZE..INODE_CHECKSUM_HI]);
+        let mut offset = INODE_CHECKSUM_HI;
+        if has_high {
+            crc = crc32c(crc, &zero);
+            stored |= (read_u16(raw, INODE_CHECKSUM_HI) as u32) << 16;
+            offset += 2;
+        }
+        crc = crc32c(crc, &raw[offset..]);
+    }
+    if !has_high {
+        crc &= 0xFFFF;
+    }
+    crc == stored
+}
+
+/// The hash of a directory entry name under hash version `version`, or `None`
+/// for versions this driver does not know
+pub fn dx_hash(name: &[u8], version: u8, seed: [u32; 4]) -> Option<u32> {
+    // An all-zero seed means the MD4 initial values
+    let mut buf = match seed {
+        [0, 0, 0, 0] => [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476],
+        seed => seed,
+    };
+    if version > HASH_TEA + HASH_UNSIGNED {
+        return None;
+    }
+    let signed = version < HASH_UNSIGNED;
+    let hash = match version % HASH_UNSIGNED {
+        HASH_LEGACY => legacy_hash(name, signed),
+        HASH_HALF_MD4 => {
+            for chunk in (0..name.len()).step_by(32) {
+                half_md4_transform(&mut buf, &hash_input(&name[chunk..], signed));
+            }
+            buf[1]
+        }
+        _ => {
+            for chunk in (0..name.len()).step_by(16) {
+                tea_transform(&mut buf, &hash_input(&name[chunk..], signed));
+            }
+            buf[0]
+        }
+    };
+    // The lowest bit marks collisions continuing in the next block
+    Some(match hash & !1 {
+        hash if hash == HTREE_EOF << 1 => (HTREE_EOF - 1) << 1,
+        hash => hash,
+    })
+}
+
+// Bytes of a name as a signed or unsigned char, the way the hashes were first written in C
+fn hash_byte(byte: u8, signed: bool) -> u32 {
+    if signed {
+        byte as i8 as i32 as u32
+    } else {
+        byte as u32
+    }
+}
+
+fn legacy_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12A3_FE2Du32, 0x37AB_E8F9u32);
+    for byte in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ hash_byte(*byte, signed).wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7FFF_FFFF);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+// Pack the rest of a name into `N` words, padding with its length
+fn hash_input<const N: usize>(rest: &[u8], signed: bool) -> [u32; N] {
+    let mut pad = rest.len() as u32 | (rest.len() as u32) << 8;
+    pad |= pad << 16;
+    let mut words = [pad; N];
+    let mut value = pad;
+    let bytes = &rest[..rest.len().min(N * 4)];
+    for (index, byte) in bytes.iter().enumerate() {
+        value = hash_byte(*byte, signed).wrapping_add(value << 8);
+        if index % 4 == 3 {
+            words[index / 4] = value;
+            value = pad;
+        }
+    }
+    if !bytes.len().is_multiple_of(4) {
+        words[bytes.len() / 4] = value;
+    }
+    words
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9E37_79B9;
+    let (mut b0, mut b1, mut sum) = (buf[0], buf[1], 0u32);
+    let [a, b, c, d] = *input;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add((b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b));
+        b1 = b1.wrapping_add((b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d));
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+// MD4 with its rounds cut short, as ext3 first used it
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    // Each round's function, additive constant, and input word and shift for each step
+    type Round = (fn(u32, u32, u32) -> u32, u32, [(usize, u32); 8]);
+    let rounds: [Round; 3] = [
+        (f, 0, [(0, 3), (1, 7), (2, 11), (3, 19), (4, 3), (5, 7), (6, 11), (7, 19)]),
+        (g, K2, [(1, 3), (3, 5), (5, 9), (7, 13), (0, 3), (2, 5), (4, 9), (6, 13)]),
+        (h, K3, [(3, 3), (7, 9), (2, 11), (6, 15), (1, 3), (5, 9), (0, 11), (4, 15)]),
+    ];
+    for (function, constant, steps) in rounds {
+        for (step, (word, shift)) in steps.into_iter().enumerate() {
+            let x = input[word].wrapping_add(constant);
+            // Each step updates the next of a, d, c, b in turn
+            let (target, p, q, r) = match step % 4 {
+                0 => (&mut a, b, c, d),
+                1 => (&mut d, a, b, c),
+                2 => (&mut c, d, a, b),
+                _ => (&mut b, c, d, a),
+            };
+            *target = target.wrapping_add(function(p, q, r)).wrapping_add(x).rotate_left(shift);
+        }
+    }
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+/// An entry of an HTree index: the lowest hash of the names below it, and the
+/// directory block it leads to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DxEntry {
+    pub hash: u32,
+    pub block: u32,
+}
+
+/// The top of an HTree index, in the first block of an indexed directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DxRoot {
+    pub hash_version: u8,
+    /// Levels of index blocks below the root
+    pub levels: u8,
+    pub entries: Vec<DxEntry>,
+}
+
+impl DxRoot {
+    /// Parse the root from the directory's first block, checking it against
+    /// `seed` on volumes with metadata checksums
+    pub fn parse(block: &[u8], seed: Option<u32>) -> FileSystemResult<DxRoot> {
+        // The index follows the `.` and `..` entries
+        let info_length = block[29] as usize;
+        if read_u32(block, 24) != 0 || info_length < 8 || block[31] & 1 != 0 {
+            return Err(FileSystemError::Corrupted("directory index"));
+        }
+        Ok(DxRoot {
+            hash_version: block[28],
+            levels: block[30],
+            entries: parse_dx_entries(block, 24 + info_length, seed)?,
+        })
+    }
+}
+
+/// The entries of an index block below the root
+pub fn parse_dx_node(block: &[u8], seed: Option<u32>) -> FileSystemResult<Vec<DxEntry>> {
+    // An unused entry spanning the block hides the index from directory scans
+    if read_u32(block, 0) != 0 {
+        return Err(FileSystemError::Corrupted("directory index"));
+    }
+    parse_dx_entries(block, DX_NODE_COUNT_OFFSET, seed)
+}
+
+// The first entry keeps the limit and count where the others have their hash
+fn parse_dx_entries(block: &[u8], offset: usize, seed: Option<u32>) -> FileSystemResult<Vec<DxEntry>> {
+    let (limit, count) = (read_u16(block, offset) as usize, read_u16(block, offset + 2) as usize);
+    if count == 0 || count > limit || offset + limit * DX_ENTRY_SIZE > block.len() {
+        return Err(FileSystemError::Corrupted("directory index"));
+    }
+    if let Some(seed) = seed {
+        // The tail after the last possible entry covers the entries in use
+        let tail = offset + limit * DX_ENTRY_SIZE;
+        if tail + 8 > block.len() {
+            return Err(FileSystemError::Corrupted("directory index"));
+        }
+        let mut crc = crc32c(seed, &block[..offset + count * DX_ENTRY_SIZE]);
+        crc = crc32c(crc, &block[tail..tail + 4]);
+        crc = crc32c(crc, &[0; 4]);
+        if crc != read_u32(block, tail + 4) {
+            return Err(FileSystemError::Corrupted("directory index checksum"));
+        }
+    }
+    Ok((0..count)
+        .map(|index| {
+            let entry = offset + index * DX_ENTRY_SIZE;
+            DxEntry {
+                hash: if index == 0 { 0 } else { read_u32(block, entry) },
+                block: read_u32(block, entry + 4) & DX_BLOCK_MASK,
+            }
+        })
+        .collect())
+}
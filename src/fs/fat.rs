@@ -1,20 +1,1621 @@
-use crate::filesystem::{FileSystem, FileSystemError, DirectoryEntry};
+//! FAT
+//!
+//! The File Allocation Table filesystem of DOS and Windows in its 12, 16 and 32-bit
+//! variants, as found on EFI system partitions and removable media. A volume is a
+//! boot sector holding the BIOS parameter block, copies of the FAT, and clusters of
+//! data; the FAT links each cluster of a file or directory to the next. FAT12 and
+//! FAT16 keep the root directory in a fixed area before the clusters. Names that do
+//! not fit 8.3 are kept in VFAT long name entries ahead of a generated short alias.
+//!
+//! The first FAT is kept in memory and written to every copy as each change
+//! completes; everything else is on the device when the call returns. FAT has no
+//! owners, permissions or links, so files belong to root, only the write bits
+//! follow the read-only attribute, and hard links and symlinks are not supported.
+
+use crate::fs::vfs::{
+    check_name, DirectoryEntry, FileSystem, FileSystemError, FileSystemResult, FileSystemStats, FileType, Inode,
+    InodeId, Metadata, SetAttributes, MAX_NAME_LEN,
+};
+use crate::storage::block::BlockDevice;
+use crate::util::time;
+use core::any::Any;
+use core::ops::Range;
+use core::time::Duration;
+use spin::Mutex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Weak};
+
+const BOOT_SECTOR_SIZE: usize = 512;
+
+// The FAT type follows from the number of clusters alone
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+
+/// Number of the first cluster of the data area
+const FIRST_CLUSTER: u32 = 2;
+
+/// Inode number of the root directory, which has no directory entry
+const ROOT_INODE: InodeId = 1;
+
+// Boot sector state flag, as Linux uses it: mounted and not yet synced
+const STATE_DIRTY: u8 = 0x01;
+// FAT32 extended flags: only the FAT numbered in the low bits is in use
+const NO_MIRRORING: u16 = 0x0080;
+
+// FSInfo sector signatures, and the value of a count or hint left unknown
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const ENTRY_SIZE: usize = 32;
+/// Directories other than the FAT12 and FAT16 root hold at most 65536 entries
+const MAX_DIRECTORY_SIZE: usize = 65536 * ENTRY_SIZE;
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+// First byte of a directory entry: no entries follow, or this one was deleted
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED: u8 = 0xE5;
+// Stands for 0xE5 as the first byte of a short name
+const ESCAPED_DELETED: u8 = 0x05;
+
+const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// The attributes of a long name entry, which older systems skip as a volume label
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+// Case flags of a short entry, set by Windows NT for 8.3 names in lower case
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+const DOT: &[u8; 11] = b".          ";
+const DOT_DOT: &[u8; 11] = b"..         ";
+
+// A long name entry holds 13 UTF-16 units at these offsets; the first entry on
+// disk carries the last part of the name and this flag on its sequence number
+const LONG_NAME_UNITS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LAST_LONG_ENTRY: u8 = 0x40;
+const MAX_LONG_ENTRIES: usize = MAX_NAME_LEN.div_ceil(13);
+
+/// Characters long names cannot have, besides control characters
+const INVALID_CHARACTERS: &str = "\"*/:<>?\\|";
+/// Characters short names can have besides letters and digits
+const SHORT_NAME_PUNCTUATION: &[u8] = b"$%'-_@~`!(){}^#&";
+
+// Times are kept from 1980 to 2107, in two-second steps
+const FIRST_TIME: u64 = 315_532_800;
+const LAST_TIME: u64 = 4_354_819_198;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// The FAT value that ends a chain, as written
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Whether a FAT value ends a chain; any of the top eight does
+    fn is_end_of_chain(self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+}
+
+/// Where things are on the volume, from the BIOS parameter block
+struct Layout {
+    fat_type: FatType,
+    sector_size: u64,
+    cluster_size: u64,
+    total_sectors: u64,
+    /// Byte offset of the first FAT, and the size of each copy
+    fat_offset: u64,
+    fat_size: u64,
+    fat_count: u32,
+    /// The copy in use when FAT32 mirroring is off
+    active_fat: Option<u32>,
+    /// Byte offset and size of the FAT12 and FAT16 root directory
+    root_offset: u64,
+    root_size: u64,
+    data_offset: u64,
+    cluster_count: u32,
+    /// First cluster of the FAT32 root directory
+    root_cluster: u32,
+    fsinfo_sector: u64,
+    /// Offset of the state byte in the boot sector
+    state_offset: usize,
+}
+
+impl Layout {
+    fn parse(boot: &[u8]) -> FileSystemResult<Layout> {
+        let sector_size = read_u16(boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = read_u16(boot, 14) as u64;
+        let fat_count = boot[16] as u32;
+        let root_entries = read_u16(boot, 17) as u64;
+        let total_sectors = match read_u16(boot, 19) {
+            0 => read_u32(boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match read_u16(boot, 22) {
+            0 => read_u32(boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(FileSystemError::InvalidArgument);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size);
+        let data_sector = reserved_sectors + fat_count as u64 * fat_sectors + root_sectors;
+        if data_sector >= total_sectors {
+            return Err(FileSystemError::Corrupted("no data area"));
+        }
+        let cluster_count = ((total_sectors - data_sector) / sectors_per_cluster).min(0x0FFF_FFF5) as u32;
+        let fat_type = match cluster_count {
+            0 => return Err(FileSystemError::Corrupted("no clusters")),
+            count if count < FAT16_MIN_CLUSTERS => FatType::Fat12,
+            count if count < FAT32_MIN_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        if fat_sectors * sector_size * 8 / bits < cluster_count as u64 + FIRST_CLUSTER as u64 {
+            return Err(FileSystemError::Corrupted("FAT too small"));
+        }
+
+        let mut layout = Layout {
+            fat_type,
+            sector_size,
+            cluster_size: sector_size * sectors_per_cluster,
+            total_sectors,
+            fat_offset: reserved_sectors * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fat_count,
+            active_fat: None,
+            root_offset: (reserved_sectors + fat_count as u64 * fat_sectors) * sector_size,
+            root_size: root_sectors * sector_size,
+            data_offset: data_sector * sector_size,
+            cluster_count,
+            root_cluster: 0,
+            fsinfo_sector: 0,
+            state_offset: 37,
+        };
+        if fat_type == FatType::Fat32 {
+            if root_entries != 0 || read_u16(boot, 22) != 0 {
+                return Err(FileSystemError::Corrupted("FAT32 parameter block"));
+            }
+            if read_u16(boot, 42) != 0 {
+                return Err(FileSystemError::NotSupported);
+            }
+            let flags = read_u16(boot, 40);
+            if flags & NO_MIRRORING != 0 {
+                let active = (flags & 0x0F) as u32;
+                if active >= fat_count {
+                    return Err(FileSystemError::Corrupted("active FAT"));
+                }
+                layout.active_fat = Some(active);
+            }
+            layout.root_cluster = read_u32(boot, 44);
+            if !layout.is_cluster(layout.root_cluster) {
+                return Err(FileSystemError::Corrupted("root cluster"));
+            }
+            layout.fsinfo_sector = read_u16(boot, 48) as u64;
+            layout.state_offset = 65;
+        } else if root_entries == 0 {
+            return Err(FileSystemError::Corrupted("no root directory"));
+        }
+        Ok(layout)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size
+    }
+
+    /// The copies of the FAT that are kept up to date
+    fn fat_copies(&self) -> Range<u32> {
+        match self.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.fat_count,
+        }
+    }
+}
+
+// Days since 1970 of a date in the proleptic Gregorian calendar, and back
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// A FAT date, time and count of 10 ms as time since the epoch. FAT keeps local
+/// time without a zone; it is taken as UTC.
+fn decode_time(date: u16, time: u16, hundredths: u8) -> Option<Duration> {
+    let (month, day) = ((date >> 5) as u32 & 0x0F, date as u32 & 0x1F);
+    if month == 0 || month > 12 || day == 0 {
+        return None;
+    }
+    let days = days_from_civil(1980 + (date >> 9) as i64, month, day);
+    let seconds = (time >> 11) as u64 * 3600 + ((time >> 5) & 0x3F) as u64 * 60 + (time & 0x1F) as u64 * 2;
+    Some(Duration::from_secs(days as u64 * 86400 + seconds) + Duration::from_millis(hundredths.min(199) as u64 * 10))
+}
+
+/// A time since the epoch as a FAT date, time and count of 10 ms
+fn encode_time(time: Duration) -> (u16, u16, u8) {
+    let seconds = time.as_secs().clamp(FIRST_TIME, LAST_TIME);
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let of_day = seconds % 86400;
+    let date = ((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let clock = ((of_day / 3600) as u16) << 11 | ((of_day / 60 % 60) as u16) << 5 | (of_day % 60 / 2) as u16;
+    let hundredths = (of_day % 2 * 100) as u8 + (time.subsec_millis() / 10) as u8;
+    (date, clock, hundredths)
+}
+
+/// The checksum of a short name that its long name entries carry
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn is_short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || SHORT_NAME_PUNCTUATION.contains(&byte)
+}
+
+/// Whether two names are the same to FAT, which ignores case
+fn same_name(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_uppercase).eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// A short directory entry: the 8.3 name, attributes, times, first cluster and size
+#[derive(Clone)]
+struct ShortEntry {
+    raw: [u8; ENTRY_SIZE],
+}
+
+impl ShortEntry {
+    /// An entry with the given attributes, created now
+    fn new(attributes: u8) -> ShortEntry {
+        let mut entry = ShortEntry { raw: [0; ENTRY_SIZE] };
+        entry.raw[11] = attributes;
+        let (date, clock, hundredths) = encode_time(time::current_time());
+        entry.raw[13] = hundredths;
+        write_u16(&mut entry.raw, 14, clock);
+        write_u16(&mut entry.raw, 16, date);
+        write_u16(&mut entry.raw, 18, date);
+        write_u16(&mut entry.raw, 22, clock);
+        write_u16(&mut entry.raw, 24, date);
+        entry
+    }
+
+    fn short_name(&self) -> &[u8] {
+        &self.raw[..11]
+    }
+
+    fn is_dot(&self) -> bool {
+        self.short_name() == DOT || self.short_name() == DOT_DOT
+    }
+
+    /// The 8.3 name as shown, with the case flags applied. Bytes outside ASCII are
+    /// in an OEM code page and are taken as Latin-1.
+    fn display_name(&self) -> String {
+        let part = |range: Range<usize>, lower: u8| -> String {
+            let mut bytes = self.raw[range.clone()].to_vec();
+            while bytes.last() == Some(&b' ') {
+                bytes.pop();
+            }
+            if range.start == 0 && bytes.first() == Some(&ESCAPED_DELETED) {
+                bytes[0] = DELETED;
+            }
+            if self.raw[12] & lower != 0 {
+                bytes.make_ascii_lowercase();
+            }
+            bytes.into_iter().map(char::from).collect()
+        };
+        let base = part(0..8, LOWER_CASE_BASE);
+        match part(8..11, LOWER_CASE_EXTENSION) {
+            extension if extension.is_empty() => base,
+            extension => format!("{}.{}", base, extension),
+        }
+    }
+
+    fn attributes(&self) -> u8 {
+        self.raw[11]
+    }
+
+    fn is_directory(&self) -> bool {
+        self.attributes() & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    /// The first cluster, or 0 if there are none. The high half is only used by
+    /// FAT32; older volumes may hold other data there.
+    fn first_cluster(&self, fat_type: FatType) -> u32 {
+        let high = match fat_type {
+            FatType::Fat32 => read_u16(&self.raw, 20) as u32,
+            _ => 0,
+        };
+        high << 16 | read_u16(&self.raw, 26) as u32
+    }
+
+    fn set_first_cluster(&mut self, cluster: u32) {
+        write_u16(&mut self.raw, 20, (cluster >> 16) as u16);
+        write_u16(&mut self.raw, 26, cluster as u16);
+    }
+
+    fn size(&self) -> u64 {
+        read_u32(&self.raw, 28) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        write_u32(&mut self.raw, 28, size as u32);
+    }
+
+    fn created(&self) -> Option<Duration> {
+        decode_time(read_u16(&self.raw, 16), read_u16(&self.raw, 14), self.raw[13])
+    }
+
+    fn accessed(&self) -> Option<Duration> {
+        decode_time(read_u16(&self.raw, 18), 0, 0)
+    }
+
+    fn set_accessed(&mut self, time: Duration) {
+        write_u16(&mut self.raw, 18, encode_time(time).0);
+    }
+
+    fn modified(&self) -> Option<Duration> {
+        decode_time(read_u16(&self.raw, 24), read_u16(&self.raw, 22), 0)
+    }
+
+    fn set_modified(&mut self, time: Duration) {
+        let (date, clock, _) = encode_time(time);
+        write_u16(&mut self.raw, 22, clock);
+        write_u16(&mut self.raw, 24, date);
+    }
+
+    /// Note a change to the contents, which also marks the file for backup
+    fn touch(&mut self) {
+        self.set_modified(time::current_time());
+        self.raw[11] |= ATTRIBUTE_ARCHIVE;
+    }
+}
+
+// A long name being put together from its entries, last part first
+struct LongName {
+    start: usize,
+    checksum: u8,
+    // Sequence number of the entry read last; the next must be one lower
+    sequence: u8,
+    units: Vec<u16>,
+}
+
+impl LongName {
+    /// Add the long name entry `slot` at `offset` to `long`, or start a new name;
+    /// `None` if the entry does not continue a name
+    fn next(long: Option<LongName>, slot: &[u8], offset: usize) -> Option<LongName> {
+        let sequence = slot[0] & !LAST_LONG_ENTRY;
+        if sequence == 0 || sequence as usize > MAX_LONG_ENTRIES {
+            return None;
+        }
+        let mut long = match slot[0] & LAST_LONG_ENTRY {
+            0 => long?,
+            _ => LongName {
+                start: offset,
+                checksum: slot[13],
+                sequence: sequence + 1,
+                units: vec![0; sequence as usize * 13],
+            },
+        };
+        if sequence + 1 != long.sequence || slot[13] != long.checksum {
+            return None;
+        }
+        long.sequence = sequence;
+        let start = (sequence as usize - 1) * 13;
+        for (unit, &at) in long.units[start..start + 13].iter_mut().zip(LONG_NAME_UNITS.iter()) {
+            *unit = read_u16(slot, at);
+        }
+        Some(long)
+    }
+
+    fn name(&self) -> String {
+        let end = self.units.iter().position(|&unit| unit == 0).unwrap_or(self.units.len());
+        char::decode_utf16(self.units[..end].iter().copied())
+            .map(|unit| unit.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+}
+
+/// The long name entries for a name, in the order they go on disk
+fn long_name_entries(units: &[u16], checksum: u8) -> Vec<u8> {
+    let mut padded = units.to_vec();
+    if !padded.len().is_multiple_of(13) {
+        padded.push(0);
+        padded.resize(padded.len().div_ceil(13) * 13, 0xFFFF);
+    }
+    let count = padded.len() / 13;
+    let mut entries = vec![0; count * ENTRY_SIZE];
+    for (slot, sequence) in entries.chunks_exact_mut(ENTRY_SIZE).zip((1..=count).rev()) {
+        slot[0] = sequence as u8 | if sequence == count { LAST_LONG_ENTRY } else { 0 };
+        slot[11] = ATTRIBUTE_LONG_NAME;
+        slot[13] = checksum;
+        for (&unit, &at) in padded[(sequence - 1) * 13..sequence * 13].iter().zip(LONG_NAME_UNITS.iter()) {
+            write_u16(slot, at, unit);
+        }
+    }
+    entries
+}
+
+/// An entry of a directory, with the offsets of its first slot (long name entries
+/// included) and of its short entry
+#[derive(Clone)]
+struct FoundEntry {
+    start: usize,
+    offset: usize,
+    name: String,
+    entry: ShortEntry,
+}
+
+/// The entries of a directory's data, skipping deleted entries and volume labels.
+/// Long names whose entries are out of order or do not match the short entry's
+/// checksum are ignored, as other systems leave them behind.
+fn parse_entries(data: &[u8]) -> Vec<FoundEntry> {
+    let mut entries = Vec::new();
+    let mut long = None;
+    for (index, slot) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        let offset = index * ENTRY_SIZE;
+        match slot[0] {
+            END_OF_DIRECTORY => break,
+            DELETED => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        if slot[11] & 0x3F == ATTRIBUTE_LONG_NAME {
+            long = LongName::next(long.take(), slot, offset);
+            continue;
+        }
+        if slot[11] & ATTRIBUTE_VOLUME_ID != 0 {
+            long = None;
+            continue;
+        }
+        let entry = ShortEntry { raw: slot.try_into().unwrap() };
+        let long = long
+            .take()
+            .filter(|long| long.sequence == 1 && long.checksum == short_name_checksum(slot));
+        let (start, name) = match long {
+            Some(long) => (long.start, long.name()),
+            None => (offset, entry.display_name()),
+        };
+        entries.push(FoundEntry { start, offset, name, entry });
+    }
+    entries
+}
+
+/// The entry called `name`, by its long name or its short alias
+fn find_entry<'a>(entries: &'a [FoundEntry], name: &str) -> Option<&'a FoundEntry> {
+    entries
+        .iter()
+        .filter(|found| !found.entry.is_dot())
+        .find(|found| same_name(&found.name, name) || same_name(&found.entry.display_name(), name))
+}
+
+/// The UTF-16 form of a name for a new entry
+fn check_long_name(name: &str) -> FileSystemResult<Vec<u16>> {
+    check_name(name)?;
+    if name.chars().any(|c| c < ' ' || INVALID_CHARACTERS.contains(c)) || name.ends_with(['.', ' ']) {
+        return Err(FileSystemError::InvalidArgument);
+    }
+    let units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() > MAX_NAME_LEN {
+        return Err(FileSystemError::NameTooLong);
+    }
+    Ok(units)
+}
+
+/// The short name and case flags of a name that is already 8.3, in one case per
+/// part, so that it needs no long name
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || base.contains('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for (part, start, lower_case) in [(base, 0, LOWER_CASE_BASE), (extension, 8, LOWER_CASE_EXTENSION)] {
+        let bytes = part.as_bytes();
+        if !bytes.iter().all(|&byte| is_short_name_byte(byte)) {
+            return None;
+        }
+        match (bytes.iter().any(u8::is_ascii_lowercase), bytes.iter().any(u8::is_ascii_uppercase)) {
+            (true, true) => return None,
+            (true, false) => case |= lower_case,
+            _ => {}
+        }
+        short[start..start + bytes.len()].copy_from_slice(&part.to_ascii_uppercase().into_bytes());
+    }
+    Some((short, case))
+}
+
+/// A short alias for a long name: its letters and digits in upper case, other
+/// characters as `_`, cut to 8.3 and given the first `~N` tail not `taken`
+fn short_alias(name: &str, taken: impl Fn(&[u8]) -> bool) -> FileSystemResult<[u8; 11]> {
+    let name = name.trim_start_matches('.');
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let convert = |part: &str, length: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.is_ascii() && is_short_name_byte(c as u8) {
+                true => c.to_ascii_uppercase() as u8,
+                false => b'_',
+            })
+            .take(length)
+            .collect()
+    };
+    let base = match convert(base, 8) {
+        base if base.is_empty() => b"_".to_vec(),
+        base => base,
+    };
+    let extension = convert(extension, 3);
+    for number in 1..1_000_000 {
+        let tail = format!("~{}", number);
+        let kept = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..kept].copy_from_slice(&base[..kept]);
+        short[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + extension.len()].copy_from_slice(&extension);
+        if !taken(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FileSystemError::NoSpace)
+}
+
+/// Where a short entry is: the first cluster of its directory (0 for the FAT12 and
+/// FAT16 root) and its byte offset in the directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    directory: u32,
+    offset: u32,
+}
+
+/// What an inode object stands for
+#[derive(Clone)]
+enum Node {
+    Root,
+    Entry(Location),
+    /// A file or directory removed while open, which keeps its clusters until closed
+    Removed { inode: InodeId, entry: ShortEntry },
+}
+
+/// The data of a directory, read whole
+struct Directory {
+    first_cluster: u32,
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+}
+
+struct State {
+    /// The FAT in use, as on the device
+    fat: Vec<u8>,
+    // Sectors of the FAT changed since they were last written
+    dirty_sectors: BTreeSet<u64>,
+    free_clusters: u32,
+    // Where to look for a free cluster first
+    next_free: u32,
+    // Whether the boot sector says the volume was synced
+    clean: bool,
+}
+
+// An inode object in use, and its node, which moves with its entry
+struct OpenInode {
+    inode: Weak<FatInode>,
+    node: Arc<Mutex<Node>>,
+}
 
-#[derive(Debug)]
 pub struct FatFileSystem {
-    // filesystem-specific data
+    this: Weak<FatFileSystem>,
+    device: Arc<dyn BlockDevice>,
+    read_only: bool,
+    layout: Layout,
+    /// Whether the FSInfo sector is valid and kept up to date
+    has_fsinfo: bool,
+    state: Mutex<State>,
+    // One inode object per entry in use, so that a file removed while open keeps
+    // its clusters until the last user lets go of it
+    inodes: Mutex<BTreeMap<Location, OpenInode>>,
+}
+
+impl FatFileSystem {
+    /// Mount the FAT volume on `device`. The free cluster count is taken from the
+    /// FAT itself; the FSInfo sector is only trusted for where to allocate next.
+    pub fn mount(device: Arc<dyn BlockDevice>, read_only: bool) -> FileSystemResult<Arc<FatFileSystem>> {
+        let mut boot = vec![0; BOOT_SECTOR_SIZE];
+        device.read_at(0, &mut boot)?;
+        let layout = Layout::parse(&boot)?;
+        let volume_size = layout.total_sectors.checked_mul(layout.sector_size);
+        if volume_size.is_none_or(|size| size > device.block_count() * device.block_size() as u64) {
+            return Err(FileSystemError::Corrupted("volume larger than the device"));
+        }
+
+        let mut fat = vec![0; layout.fat_size as usize];
+        let active = layout.fat_copies().start as u64;
+        device.read_at(layout.fat_offset + active * layout.fat_size, &mut fat)?;
+        let mut state = State {
+            fat,
+            dirty_sectors: BTreeSet::new(),
+            free_clusters: 0,
+            next_free: FIRST_CLUSTER,
+            clean: boot[layout.state_offset] & STATE_DIRTY == 0,
+        };
+
+        let mut has_fsinfo = false;
+        let reserved_sectors = layout.fat_offset / layout.sector_size;
+        if layout.fat_type == FatType::Fat32 && layout.fsinfo_sector != 0 && layout.fsinfo_sector < reserved_sectors {
+            let mut fsinfo = vec![0; layout.sector_size as usize];
+            device.read_at(layout.fsinfo_sector * layout.sector_size, &mut fsinfo)?;
+            has_fsinfo = read_u32(&fsinfo, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32(&fsinfo, 484) == FSINFO_STRUCT_SIGNATURE
+                && read_u32(&fsinfo, 508) == FSINFO_TRAIL_SIGNATURE;
+            if has_fsinfo && layout.is_cluster(read_u32(&fsinfo, 492)) {
+                state.next_free = read_u32(&fsinfo, 492);
+            }
+        }
+
+        let filesystem = Arc::new_cyclic(|this| FatFileSystem {
+            this: this.clone(),
+            device,
+            read_only,
+            layout,
+            has_fsinfo,
+            state: Mutex::new(state),
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+        let mut state = filesystem.state.lock();
+        let clusters = FIRST_CLUSTER..FIRST_CLUSTER + filesystem.layout.cluster_count;
+        state.free_clusters = clusters.filter(|&cluster| filesystem.fat_entry(&state, cluster) == 0).count() as u32;
+        if !read_only {
+            // Until `sync`, a crash leaves the volume to be checked
+            filesystem.set_clean(&mut state, false)?;
+        }
+        drop(state);
+        Ok(filesystem)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.layout.fat_type
+    }
+
+    fn this(&self) -> Arc<FatFileSystem> {
+        self.this.upgrade().expect("a mounted filesystem is alive")
+    }
+
+    /// The inode object of the entry at `location`
+    fn inode(&self, location: Location) -> Arc<FatInode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&location).and_then(|open| open.inode.upgrade()) {
+            return inode;
+        }
+        inodes.retain(|_, open| open.inode.strong_count() > 0);
+        let node = Arc::new(Mutex::new(Node::Entry(location)));
+        let inode = Arc::new(FatInode { fs: self.this(), node: node.clone() });
+        inodes.insert(location, OpenInode { inode: Arc::downgrade(&inode), node });
+        inode
+    }
+
+    /// Tell the inode object of the entry at `from`, if one is in use, that the
+    /// entry is now `to`. Returns whether there was one.
+    fn entry_moved(&self, from: Location, to: Node) -> bool {
+        let mut inodes = self.inodes.lock();
+        let open = match inodes.remove(&from) {
+            Some(open) if open.inode.strong_count() > 0 => open,
+            _ => return false,
+        };
+        *open.node.lock() = to.clone();
+        if let Node::Entry(location) = to {
+            inodes.insert(location, open);
+        }
+        true
+    }
+
+    /// Run `change` with the filesystem locked, marking the volume as in use, then
+    /// write the parts of the FAT it changed to every copy
+    fn modify<T>(&self, change: impl FnOnce(&mut State) -> FileSystemResult<T>) -> FileSystemResult<T> {
+        if self.read_only {
+            return Err(FileSystemError::ReadOnly);
+        }
+        let mut state = self.state.lock();
+        if state.clean {
+            self.set_clean(&mut state, false)?;
+        }
+        let result = change(&mut state);
+        self.write_fat(&mut state)?;
+        result
+    }
+
+    fn set_clean(&self, state: &mut State, clean: bool) -> FileSystemResult<()> {
+        let offset = self.layout.state_offset as u64;
+        let mut flags = [0];
+        self.device.read_at(offset, &mut flags)?;
+        match clean {
+            true => flags[0] &= !STATE_DIRTY,
+            false => flags[0] |= STATE_DIRTY,
+        }
+        self.device.write_at(offset, &flags)?;
+        state.clean = clean;
+        Ok(())
+    }
+
+    fn write_fat(&self, state: &mut State) -> FileSystemResult<()> {
+        let sector_size = self.layout.sector_size;
+        for sector in core::mem::take(&mut state.dirty_sectors) {
+            let start = (sector * sector_size) as usize;
+            let data = &state.fat[start..start + sector_size as usize];
+            for copy in self.layout.fat_copies() {
+                let offset = self.layout.fat_offset + copy as u64 * self.layout.fat_size + start as u64;
+                self.device.write_at(offset, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fat_entry(&self, state: &State, cluster: u32) -> u32 {
+        let cluster = cluster as usize;
+        match self.layout.fat_type {
+            FatType::Fat12 => {
+                let pair = read_u16(&state.fat, cluster * 3 / 2) as u32;
+                if cluster % 2 == 1 { pair >> 4 } else { pair & 0x0FFF }
+            }
+            FatType::Fat16 => read_u16(&state.fat, cluster * 2) as u32,
+            FatType::Fat32 => read_u32(&state.fat, cluster * 4) & 0x0FFF_FFFF,
+        }
+    }
+
+    fn set_fat_entry(&self, state: &mut State, cluster: u32, value: u32) {
+        let cluster = cluster as usize;
+        let (offset, length) = match self.layout.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster * 3 / 2;
+                let pair = read_u16(&state.fat, offset);
+                let pair = match cluster % 2 {
+                    1 => (pair & 0x000F) | (value as u16) << 4,
+                    _ => (pair & 0xF000) | (value as u16 & 0x0FFF),
+                };
+                write_u16(&mut state.fat, offset, pair);
+                (offset, 2)
+            }
+            FatType::Fat16 => {
+                write_u16(&mut state.fat, cluster * 2, value as u16);
+                (cluster * 2, 2)
+            }
+            FatType::Fat32 => {
+                // The top four bits are reserved and kept
+                let old = read_u32(&state.fat, cluster * 4);
+                write_u32(&mut state.fat, cluster * 4, (old & 0xF000_0000) | (value & 0x0FFF_FFFF));
+                (cluster * 4, 4)
+            }
+        };
+        let sector_size = self.layout.sector_size as usize;
+        state.dirty_sectors.insert((offset / sector_size) as u64);
+        state.dirty_sectors.insert(((offset + length - 1) / sector_size) as u64);
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end
+    fn next_cluster(&self, state: &State, cluster: u32) -> FileSystemResult<Option<u32>> {
+        match self.fat_entry(state, cluster) {
+            next if self.layout.fat_type.is_end_of_chain(next) => Ok(None),
+            next if self.layout.is_cluster(next) => Ok(Some(next)),
+            _ => Err(FileSystemError::Corrupted("cluster chain")),
+        }
+    }
+
+    /// The clusters of the chain starting at `first`, which is 0 for no clusters
+    fn chain(&self, state: &State, first: u32) -> FileSystemResult<Vec<u32>> {
+        let mut clusters = Vec::new();
+        if first == 0 {
+            return Ok(clusters);
+        }
+        if !self.layout.is_cluster(first) {
+            return Err(FileSystemError::Corrupted("first cluster"));
+        }
+        let mut next = Some(first);
+        while let Some(cluster) = next {
+            if clusters.len() >= self.layout.cluster_count as usize {
+                return Err(FileSystemError::Corrupted("cluster chain loops"));
+            }
+            clusters.push(cluster);
+            next = self.next_cluster(state, cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Take a free cluster, filled with zeros, and link it after `previous` unless
+    /// that is 0
+    fn allocate_cluster(&self, state: &mut State, previous: u32) -> FileSystemResult<u32> {
+        let count = self.layout.cluster_count;
+        let start = match self.layout.is_cluster(state.next_free) {
+            true => state.next_free - FIRST_CLUSTER,
+            false => 0,
+        };
+        let cluster = (0..count)
+            .map(|index| (start + index) % count + FIRST_CLUSTER)
+            .find(|&cluster| self.fat_entry(state, cluster) == 0)
+            .ok_or(FileSystemError::NoSpace)?;
+        self.device.write_at(self.layout.cluster_offset(cluster), &vec![0; self.layout.cluster_size as usize])?;
+        self.set_fat_entry(state, cluster, self.layout.fat_type.end_of_chain());
+        if previous != 0 {
+            self.set_fat_entry(state, previous, cluster);
+        }
+        state.free_clusters -= 1;
+        state.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// Free the clusters of a chain from index `keep` on, ending it before them
+    fn free_clusters_from(&self, state: &mut State, clusters: &[u32], keep: usize) -> FileSystemResult<()> {
+        if keep > 0 {
+            self.set_fat_entry(state, clusters[keep - 1], self.layout.fat_type.end_of_chain());
+        }
+        for &cluster in &clusters[keep..] {
+            self.set_fat_entry(state, cluster, 0);
+            state.free_clusters += 1;
+        }
+        Ok(())
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> FileSystemResult<()> {
+        let clusters = self.chain(state, first)?;
+        self.free_clusters_from(state, &clusters, 0)
+    }
+
+    /// The first cluster of the root directory, or 0 for the fixed FAT12 and FAT16 one
+    fn root_directory(&self) -> u32 {
+        self.layout.root_cluster
+    }
+
+    /// What `..` holds for a parent directory: the root is always 0
+    fn parent_reference(&self, directory: u32) -> u32 {
+        if directory == self.root_directory() { 0 } else { directory }
+    }
+
+    // The device ranges holding `length` bytes at `offset` of the data in `clusters`,
+    // with adjacent clusters merged
+    fn data_ranges(&self, clusters: &[u32], offset: u64, length: usize) -> FileSystemResult<Vec<(u64, Range<usize>)>> {
+        let cluster_size = self.layout.cluster_size;
+        let mut ranges: Vec<(u64, Range<usize>)> = Vec::new();
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / cluster_size) as usize)
+                .ok_or(FileSystemError::Corrupted("file larger than its clusters"))?;
+            let within = position % cluster_size;
+            let count = ((cluster_size - within) as usize).min(length - done);
+            let start = self.layout.cluster_offset(cluster) + within;
+            match ranges.last_mut() {
+                Some((previous, range)) if *previous + range.len() as u64 == start => range.end += count,
+                _ => ranges.push((start, done..done + count)),
+            }
+            done += count;
+        }
+        Ok(ranges)
+    }
+
+    fn read_data(&self, clusters: &[u32], offset: u64, buffer: &mut [u8]) -> FileSystemResult<()> {
+        for (start, range) in self.data_ranges(clusters, offset, buffer.len())? {
+            self.device.read_at(start, &mut buffer[range])?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&self, clusters: &[u32], offset: u64, buffer: &[u8]) -> FileSystemResult<()> {
+        for (start, range) in self.data_ranges(clusters, offset, buffer.len())? {
+            self.device.write_at(start, &buffer[range])?;
+        }
+        Ok(())
+    }
+
+    /// Give a file the clusters `size` bytes need, freeing the rest, and zero its
+    /// data from the old size up to `zero_end`: truncating left it in place, and
+    /// FAT files have no holes. Returns the clusters.
+    fn resize(&self, state: &mut State, entry: &mut ShortEntry, size: u64, zero_end: u64) -> FileSystemResult<Vec<u32>> {
+        let cluster_size = self.layout.cluster_size;
+        let mut clusters = self.chain(state, entry.first_cluster(self.layout.fat_type))?;
+        let needed = size.div_ceil(cluster_size) as usize;
+        if needed < clusters.len() {
+            self.free_clusters_from(state, &clusters, needed)?;
+            clusters.truncate(needed);
+            if needed == 0 {
+                entry.set_first_cluster(0);
+            }
+        }
+        // New clusters come zeroed; only the old last one may hold stale bytes
+        let old_size = entry.size();
+        let zero_end = zero_end.min(clusters.len() as u64 * cluster_size);
+        if zero_end > old_size {
+            self.write_data(&clusters, old_size, &vec![0; (zero_end - old_size) as usize])?;
+        }
+        while clusters.len() < needed {
+            let cluster = self.allocate_cluster(state, clusters.last().copied().unwrap_or(0))?;
+            if clusters.is_empty() {
+                entry.set_first_cluster(cluster);
+            }
+            clusters.push(cluster);
+        }
+        entry.set_size(size);
+        Ok(clusters)
+    }
+
+    fn read_directory_data(&self, state: &State, first_cluster: u32) -> FileSystemResult<Directory> {
+        if first_cluster == 0 {
+            let mut data = vec![0; self.layout.root_size as usize];
+            self.device.read_at(self.layout.root_offset, &mut data)?;
+            return Ok(Directory { first_cluster, clusters: Vec::new(), data });
+        }
+        let clusters = self.chain(state, first_cluster)?;
+        let cluster_size = self.layout.cluster_size as usize;
+        if clusters.len() * cluster_size > MAX_DIRECTORY_SIZE {
+            return Err(FileSystemError::Corrupted("directory too large"));
+        }
+        let mut data = vec![0; clusters.len() * cluster_size];
+        self.read_data(&clusters, 0, &mut data)?;
+        Ok(Directory { first_cluster, clusters, data })
+    }
+
+    /// Write `bytes` at `offset` of a directory's data
+    fn write_directory(&self, directory: &mut Directory, offset: usize, bytes: &[u8]) -> FileSystemResult<()> {
+        directory.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        if directory.first_cluster == 0 {
+            return Ok(self.device.write_at(self.layout.root_offset + offset as u64, bytes)?);
+        }
+        self.write_data(&directory.clusters, offset as u64, bytes)
+    }
+
+    /// Byte offset on the device of the short entry at `location`
+    fn entry_offset(&self, state: &State, location: Location) -> FileSystemResult<u64> {
+        let offset = location.offset as u64;
+        if location.directory == 0 {
+            return Ok(self.layout.root_offset + offset);
+        }
+        let cluster_size = self.layout.cluster_size;
+        let mut cluster = location.directory;
+        for _ in 0..offset / cluster_size {
+            cluster = self
+                .next_cluster(state, cluster)?
+                .ok_or(FileSystemError::Corrupted("entry past the end of its directory"))?;
+        }
+        Ok(self.layout.cluster_offset(cluster) + offset % cluster_size)
+    }
+
+    fn read_entry(&self, state: &State, location: Location) -> FileSystemResult<ShortEntry> {
+        let mut entry = ShortEntry { raw: [0; ENTRY_SIZE] };
+        self.device.read_at(self.entry_offset(state, location)?, &mut entry.raw)?;
+        Ok(entry)
+    }
+
+    /// The directory entry behind `node`, or `None` for the root directory
+    fn load(&self, state: &State, node: &Node) -> FileSystemResult<Option<ShortEntry>> {
+        match node {
+            Node::Root => Ok(None),
+            Node::Entry(location) => Ok(Some(self.read_entry(state, *location)?)),
+            Node::Removed { entry, .. } => Ok(Some(entry.clone())),
+        }
+    }
+
+    fn store(&self, state: &State, node: &mut Node, entry: ShortEntry) -> FileSystemResult<()> {
+        match node {
+            Node::Root => Err(FileSystemError::NotSupported),
+            Node::Entry(location) => Ok(self.device.write_at(self.entry_offset(state, *location)?, &entry.raw)?),
+            Node::Removed { entry: removed, .. } => {
+                *removed = entry;
+                Ok(())
+            }
+        }
+    }
+
+    /// The entry of a regular file
+    fn file(&self, state: &State, node: &Node) -> FileSystemResult<ShortEntry> {
+        match self.load(state, node)? {
+            Some(entry) if !entry.is_directory() => Ok(entry),
+            _ => Err(FileSystemError::IsADirectory),
+        }
+    }
+
+    /// First cluster of a directory, or 0 for the fixed root
+    fn directory_cluster(&self, state: &State, node: &Node) -> FileSystemResult<u32> {
+        match self.load(state, node)? {
+            None => Ok(self.root_directory()),
+            Some(entry) if !entry.is_directory() => Err(FileSystemError::NotADirectory),
+            Some(entry) => match entry.first_cluster(self.layout.fat_type) {
+                0 => Err(FileSystemError::Corrupted("directory without clusters")),
+                cluster => Ok(cluster),
+            },
+        }
+    }
+
+    fn inode_number(&self, state: &State, location: Location) -> FileSystemResult<InodeId> {
+        Ok(self.entry_offset(state, location)? / ENTRY_SIZE as u64)
+    }
+
+    fn metadata(&self, state: &State, node: &Node) -> FileSystemResult<Metadata> {
+        let entry = self.load(state, node)?;
+        let inode = match node {
+            Node::Root => ROOT_INODE,
+            Node::Entry(location) => self.inode_number(state, *location)?,
+            Node::Removed { inode, .. } => *inode,
+        };
+        let directory = entry.as_ref().is_none_or(ShortEntry::is_directory);
+        let read_only = entry.as_ref().is_some_and(|entry| entry.attributes() & ATTRIBUTE_READ_ONLY != 0);
+        let (file_type, permissions) = match directory {
+            true => (FileType::Directory, 0o755),
+            false => (FileType::Regular, 0o644),
+        };
+        let mut metadata = Metadata::new(inode, file_type, if read_only { permissions & !0o222 } else { permissions });
+        let allocated = if directory {
+            let data = self.read_directory_data(state, self.directory_cluster(state, node)?)?;
+            let subdirectories = parse_entries(&data.data)
+                .iter()
+                .filter(|found| found.entry.is_directory() && !found.entry.is_dot())
+                .count();
+            metadata.links = 2 + subdirectories as u32;
+            metadata.size = data.data.len() as u64;
+            match data.first_cluster {
+                0 => 0,
+                _ => data.data.len() as u64,
+            }
+        } else {
+            let entry = entry.as_ref().unwrap();
+            metadata.size = entry.size();
+            self.chain(state, entry.first_cluster(self.layout.fat_type))?.len() as u64 * self.layout.cluster_size
+        };
+        if matches!(node, Node::Removed { .. }) {
+            metadata.links = 0;
+        }
+        metadata.blocks = allocated / 512;
+        metadata.block_size = self.layout.cluster_size as u32;
+        if let Some(entry) = entry {
+            metadata.modified = entry.modified().unwrap_or_default();
+            metadata.changed = metadata.modified;
+            metadata.accessed = entry.accessed().unwrap_or(metadata.modified);
+            metadata.created = entry.created();
+        }
+        Ok(metadata)
+    }
+
+    /// Offset of `count` free slots in a row in a directory, growing it if needed.
+    /// Slots past the end marker are free whatever they hold, so a marker is kept
+    /// after slots taken from there.
+    fn free_slots(&self, state: &mut State, directory: &mut Directory, count: usize) -> FileSystemResult<usize> {
+        let slots = directory.data.len() / ENTRY_SIZE;
+        let end = (0..slots).find(|&slot| directory.data[slot * ENTRY_SIZE] == END_OF_DIRECTORY).unwrap_or(slots);
+        let mut run = 0;
+        for slot in 0..slots {
+            run = match slot >= end || directory.data[slot * ENTRY_SIZE] == DELETED {
+                true => run + 1,
+                false => 0,
+            };
+            if run == count {
+                let after = (slot + 1) * ENTRY_SIZE;
+                if slot >= end && slot + 1 < slots && directory.data[after] != END_OF_DIRECTORY {
+                    self.write_directory(directory, after, &[END_OF_DIRECTORY])?;
+                }
+                return Ok((slot + 1 - count) * ENTRY_SIZE);
+            }
+        }
+
+        // The fixed root cannot grow
+        let cluster_size = self.layout.cluster_size as usize;
+        let more = ((count - run) * ENTRY_SIZE).div_ceil(cluster_size);
+        if directory.first_cluster == 0 || directory.data.len() + more * cluster_size > MAX_DIRECTORY_SIZE {
+            return Err(FileSystemError::NoSpace);
+        }
+        if more as u32 > state.free_clusters {
+            return Err(FileSystemError::NoSpace);
+        }
+        for _ in 0..more {
+            let cluster = self.allocate_cluster(state, *directory.clusters.last().unwrap())?;
+            directory.clusters.push(cluster);
+            directory.data.resize(directory.data.len() + cluster_size, 0);
+        }
+        Ok((slots - run) * ENTRY_SIZE)
+    }
+
+    /// Add `entry` to a directory under `name`, with a long name if it is not 8.3.
+    /// Returns where its short entry went.
+    fn add_entry(&self, state: &mut State, directory: &mut Directory, name: &str, mut entry: ShortEntry) -> FileSystemResult<Location> {
+        let units = check_long_name(name)?;
+        let (short, case, long) = match exact_short_name(name) {
+            Some((short, case)) => (short, case, None),
+            None => {
+                let entries = parse_entries(&directory.data);
+                let short = short_alias(name, |short| entries.iter().any(|found| found.entry.short_name() == short))?;
+                (short, 0, Some(units))
+            }
+        };
+        entry.raw[..11].copy_from_slice(&short);
+        entry.raw[12] = case;
+        let mut slots = match long {
+            Some(units) => long_name_entries(&units, short_name_checksum(&short)),
+            None => Vec::new(),
+        };
+        slots.extend_from_slice(&entry.raw);
+        let offset = self.free_slots(state, directory, slots.len() / ENTRY_SIZE)?;
+        self.write_directory(directory, offset, &slots)?;
+        Ok(Location {
+            directory: directory.first_cluster,
+            offset: (offset + slots.len() - ENTRY_SIZE) as u32,
+        })
+    }
+
+    /// Mark the slots of an entry, long name included, as deleted
+    fn remove_entry(&self, directory: &mut Directory, found: &FoundEntry) -> FileSystemResult<()> {
+        for offset in (found.start..=found.offset).step_by(ENTRY_SIZE) {
+            self.write_directory(directory, offset, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// Let go of the clusters of an entry that was removed, unless it is open
+    fn release(&self, state: &mut State, location: Location, entry: ShortEntry) -> FileSystemResult<()> {
+        let removed = Node::Removed {
+            inode: self.inode_number(state, location)?,
+            entry: entry.clone(),
+        };
+        if !self.entry_moved(location, removed) {
+            self.free_chain(state, entry.first_cluster(self.layout.fat_type))?;
+        }
+        Ok(())
+    }
+
+    fn is_empty_directory(&self, state: &State, entry: &ShortEntry) -> FileSystemResult<bool> {
+        let directory = self.read_directory_data(state, entry.first_cluster(self.layout.fat_type))?;
+        Ok(parse_entries(&directory.data).iter().all(|found| found.entry.is_dot()))
+    }
+
+    /// The first cluster of the parent of a directory other than the root
+    fn parent_directory(&self, state: &State, directory: u32) -> FileSystemResult<u32> {
+        let data = self.read_directory_data(state, directory)?;
+        let found = parse_entries(&data.data)
+            .into_iter()
+            .find(|found| found.entry.short_name() == DOT_DOT)
+            .ok_or(FileSystemError::Corrupted("missing .."))?;
+        match found.entry.first_cluster(self.layout.fat_type) {
+            0 => Ok(self.root_directory()),
+            cluster => Ok(cluster),
+        }
+    }
 }
 
 impl FileSystem for FatFileSystem {
-    fn read_directory(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FileSystemError> {
-        // implementation for reading a directory in FAT filesystem
-        // returns a vector of directory entries on success, or a FileSystemError on failure
-        unimplemented!()
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> FileSystemResult<Arc<dyn Inode>> {
+        Ok(Arc::new(FatInode {
+            fs: self.this(),
+            node: Arc::new(Mutex::new(Node::Root)),
+        }))
+    }
+
+    fn stats(&self) -> FileSystemResult<FileSystemStats> {
+        let state = self.state.lock();
+        Ok(FileSystemStats {
+            block_size: self.layout.cluster_size as u32,
+            blocks: self.layout.cluster_count as u64,
+            free_blocks: state.free_clusters as u64,
+            inodes: 0,
+            free_inodes: 0,
+            max_name_len: MAX_NAME_LEN as u32,
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn sync(&self) -> FileSystemResult<()> {
+        if !self.read_only {
+            let mut state = self.state.lock();
+            self.write_fat(&mut state)?;
+            if self.has_fsinfo {
+                let offset = self.layout.fsinfo_sector * self.layout.sector_size;
+                let mut counts = [0; 8];
+                write_u32(&mut counts, 0, state.free_clusters);
+                let next_free = match self.layout.is_cluster(state.next_free) {
+                    true => state.next_free,
+                    false => FSINFO_UNKNOWN,
+                };
+                write_u32(&mut counts, 4, next_free);
+                self.device.write_at(offset + 488, &counts)?;
+            }
+            self.set_clean(&mut state, true)?;
+        }
+        Ok(self.device.flush()?)
+    }
+}
+
+/// A file or directory of a FAT volume: the root, or what a directory entry describes
+pub struct FatInode {
+    fs: Arc<FatFileSystem>,
+    node: Arc<Mutex<Node>>,
+}
+
+impl FatInode {
+    fn node(&self) -> Node {
+        self.node.lock().clone()
+    }
+
+    /// The FAT inode behind `inode`, if it is on the same volume
+    fn same_filesystem<'a>(&self, inode: &'a Arc<dyn Inode>) -> FileSystemResult<&'a FatInode> {
+        match inode.as_any().downcast_ref::<FatInode>() {
+            Some(inode) if Arc::ptr_eq(&inode.fs, &self.fs) => Ok(inode),
+            _ => Err(FileSystemError::CrossDevice),
+        }
+    }
+
+    /// The data of this directory. A removed directory stays readable while open,
+    /// but gets no new entries.
+    fn directory(&self, state: &State, adding: bool) -> FileSystemResult<Directory> {
+        let node = self.node();
+        let first_cluster = self.fs.directory_cluster(state, &node)?;
+        if adding && matches!(node, Node::Removed { .. }) {
+            return Err(FileSystemError::NotFound);
+        }
+        self.fs.read_directory_data(state, first_cluster)
+    }
+
+    fn store(&self, state: &State, entry: ShortEntry) -> FileSystemResult<()> {
+        self.fs.store(state, &mut self.node.lock(), entry)
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        // A file removed while open is freed once the last user lets go of it
+        let first_cluster = match &*self.node.lock() {
+            Node::Removed { entry, .. } => entry.first_cluster(self.fs.layout.fat_type),
+            _ => return,
+        };
+        let _ = self.fs.modify(|state| self.fs.free_chain(state, first_cluster));
+    }
+}
+
+impl Inode for FatInode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        let state = self.fs.state.lock();
+        self.fs.metadata(&state, &self.node())
+    }
+
+    fn set_attributes(&self, changes: &SetAttributes) -> FileSystemResult<()> {
+        // Files belong to root, and only the write bits can change
+        if changes.uid.is_some_and(|uid| uid != 0) || changes.gid.is_some_and(|gid| gid != 0) {
+            return Err(FileSystemError::NotSupported);
+        }
+        self.fs.modify(|state| {
+            let mut entry = self.fs.load(state, &self.node())?.ok_or(FileSystemError::NotSupported)?;
+            if let Some(permissions) = changes.permissions {
+                match permissions & 0o222 {
+                    0 => entry.raw[11] |= ATTRIBUTE_READ_ONLY,
+                    _ => entry.raw[11] &= !ATTRIBUTE_READ_ONLY,
+                }
+            }
+            if let Some(accessed) = changes.accessed {
+                entry.set_accessed(accessed);
+            }
+            if let Some(modified) = changes.modified {
+                entry.set_modified(modified);
+            }
+            self.store(state, entry)
+        })
+    }
+
+    // Reads do not update the access date, as if mounted with `noatime`
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        let state = self.fs.state.lock();
+        let entry = self.fs.file(&state, &self.node())?;
+        if offset >= entry.size() {
+            return Ok(0);
+        }
+        let length = buffer.len().min((entry.size() - offset) as usize);
+        let clusters = self.fs.chain(&state, entry.first_cluster(self.fs.layout.fat_type))?;
+        self.fs.read_data(&clusters, offset, &mut buffer[..length])?;
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FileSystemResult<usize> {
+        let end = offset.checked_add(buffer.len() as u64).ok_or(FileSystemError::InvalidArgument)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let fs = &self.fs;
+        fs.modify(|state| {
+            let mut entry = fs.file(state, &self.node())?;
+            let cluster_size = fs.layout.cluster_size;
+            let mut clusters = fs.chain(state, entry.first_cluster(fs.layout.fat_type))?;
+
+            // A full volume takes what fits
+            let room = (clusters.len() as u64 + state.free_clusters as u64) * cluster_size;
+            if offset >= room && !buffer.is_empty() {
+                return Err(FileSystemError::NoSpace);
+            }
+            let buffer = &buffer[..buffer.len().min((room.max(offset) - offset) as usize)];
+            let end = offset + buffer.len() as u64;
+            if end > entry.size() {
+                clusters = fs.resize(state, &mut entry, end, offset)?;
+            }
+            let result = fs.write_data(&clusters, offset, buffer);
+            entry.touch();
+            self.store(state, entry)?;
+            result.map(|_| buffer.len())
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FileSystemResult<()> {
+        if size > MAX_FILE_SIZE {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let fs = &self.fs;
+        fs.modify(|state| {
+            let mut entry = fs.file(state, &self.node())?;
+            let clusters = fs.chain(state, entry.first_cluster(fs.layout.fat_type))?.len() as u64;
+            if size.div_ceil(fs.layout.cluster_size) > clusters + state.free_clusters as u64 {
+                return Err(FileSystemError::NoSpace);
+            }
+            fs.resize(state, &mut entry, size, size)?;
+            entry.touch();
+            self.store(state, entry)
+        })
+    }
+
+    fn lookup(&self, name: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        let state = self.fs.state.lock();
+        let directory = self.directory(&state, false)?;
+        let entries = parse_entries(&directory.data);
+        let found = find_entry(&entries, name).ok_or(FileSystemError::NotFound)?;
+        let location = Location {
+            directory: directory.first_cluster,
+            offset: found.offset as u32,
+        };
+        drop(state);
+        Ok(self.fs.inode(location))
+    }
+
+    fn read_directory(&self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        let state = self.fs.state.lock();
+        let directory = self.directory(&state, false)?;
+        parse_entries(&directory.data)
+            .into_iter()
+            .filter(|found| !found.entry.is_dot())
+            .map(|found| {
+                let location = Location {
+                    directory: directory.first_cluster,
+                    offset: found.offset as u32,
+                };
+                Ok(DirectoryEntry {
+                    inode: self.fs.inode_number(&state, location)?,
+                    file_type: if found.entry.is_directory() { FileType::Directory } else { FileType::Regular },
+                    name: found.name,
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, name: &str, file_type: FileType, permissions: u16) -> FileSystemResult<Arc<dyn Inode>> {
+        let attributes = match file_type {
+            FileType::Regular => ATTRIBUTE_ARCHIVE,
+            FileType::Directory => ATTRIBUTE_DIRECTORY,
+            _ => return Err(FileSystemError::NotSupported),
+        };
+        check_long_name(name)?;
+        let fs = &self.fs;
+        let location = fs.modify(|state| {
+            let mut directory = self.directory(state, true)?;
+            if find_entry(&parse_entries(&directory.data), name).is_some() {
+                return Err(FileSystemError::AlreadyExists);
+            }
+            let mut entry = ShortEntry::new(attributes);
+            if permissions & 0o222 == 0 {
+                entry.raw[11] |= ATTRIBUTE_READ_ONLY;
+            }
+            if file_type != FileType::Directory {
+                return fs.add_entry(state, &mut directory, name, entry);
+            }
+
+            // A new directory starts with `.` and `..`
+            let cluster = fs.allocate_cluster(state, 0)?;
+            entry.set_first_cluster(cluster);
+            let mut dots = [entry.clone(), entry.clone()];
+            dots[0].raw[..11].copy_from_slice(DOT);
+            dots[1].raw[..11].copy_from_slice(DOT_DOT);
+            dots[1].set_first_cluster(fs.parent_reference(directory.first_cluster));
+            let result = fs
+                .device
+                .write_at(fs.layout.cluster_offset(cluster), &[dots[0].raw, dots[1].raw].concat())
+                .map_err(FileSystemError::from)
+                .and_then(|_| fs.add_entry(state, &mut directory, name, entry));
+            if result.is_err() {
+                fs.free_chain(state, cluster)?;
+            }
+            result
+        })?;
+        Ok(fs.inode(location))
+    }
+
+    fn unlink(&self, name: &str) -> FileSystemResult<()> {
+        let fs = &self.fs;
+        fs.modify(|state| {
+            let mut directory = self.directory(state, false)?;
+            let entries = parse_entries(&directory.data);
+            let found = find_entry(&entries, name).ok_or(FileSystemError::NotFound)?;
+            if found.entry.is_directory() {
+                return Err(FileSystemError::IsADirectory);
+            }
+            fs.remove_entry(&mut directory, found)?;
+            let location = Location {
+                directory: directory.first_cluster,
+                offset: found.offset as u32,
+            };
+            fs.release(state, location, found.entry.clone())
+        })
+    }
+
+    fn remove_directory(&self, name: &str) -> FileSystemResult<()> {
+        if name == "." || name == ".." {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let fs = &self.fs;
+        fs.modify(|state| {
+            let mut directory = self.directory(state, false)?;
+            let entries = parse_entries(&directory.data);
+            let found = find_entry(&entries, name).ok_or(FileSystemError::NotFound)?;
+            if !found.entry.is_directory() {
+                return Err(FileSystemError::NotADirectory);
+            }
+            if !fs.is_empty_directory(state, &found.entry)? {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+            fs.remove_entry(&mut directory, found)?;
+            let location = Location {
+                directory: directory.first_cluster,
+                offset: found.offset as u32,
+            };
+            fs.release(state, location, found.entry.clone())
+        })
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> FileSystemResult<()> {
+        check_long_name(new_name)?;
+        let new_parent = self.same_filesystem(new_parent)?;
+        let fs = &self.fs;
+        let fat_type = fs.layout.fat_type;
+        fs.modify(|state| {
+            let old_directory = self.directory(state, false)?;
+            let mut new_directory = new_parent.directory(state, true)?;
+            let found = find_entry(&parse_entries(&old_directory.data), old_name)
+                .cloned()
+                .ok_or(FileSystemError::NotFound)?;
+            let old_location = Location {
+                directory: old_directory.first_cluster,
+                offset: found.offset as u32,
+            };
+            let directory = found.entry.is_directory();
+            let moves_directory = directory && new_directory.first_cluster != old_directory.first_cluster;
+
+            // A directory cannot move into itself or its own subdirectories
+            if moves_directory {
+                let moved = found.entry.first_cluster(fat_type);
+                let mut ancestor = new_directory.first_cluster;
+                for _ in 0..fs.layout.cluster_count {
+                    if ancestor == moved {
+                        return Err(FileSystemError::InvalidArgument);
+                    }
+                    if ancestor == fs.root_directory() {
+                        break;
+                    }
+                    ancestor = fs.parent_directory(state, ancestor)?;
+                }
+            }
+
+            // Whatever is at the new name is replaced, if it is compatible. The
+            // entry itself may be there, when only the case of its name changes.
+            let existing = find_entry(&parse_entries(&new_directory.data), new_name)
+                .filter(|existing| {
+                    existing.offset != found.offset || new_directory.first_cluster != old_directory.first_cluster
+                })
+                .cloned();
+            if let Some(existing) = &existing {
+                match (directory, existing.entry.is_directory()) {
+                    (true, true) if !fs.is_empty_directory(state, &existing.entry)? => {
+                        return Err(FileSystemError::DirectoryNotEmpty)
+                    }
+                    (true, false) => return Err(FileSystemError::NotADirectory),
+                    (false, true) => return Err(FileSystemError::IsADirectory),
+                    _ => {}
+                }
+            }
+
+            let new_location = fs.add_entry(state, &mut new_directory, new_name, found.entry.clone())?;
+            if let Some(existing) = existing {
+                fs.remove_entry(&mut new_directory, &existing)?;
+                let location = Location {
+                    directory: new_directory.first_cluster,
+                    offset: existing.offset as u32,
+                };
+                fs.release(state, location, existing.entry)?;
+            }
+            let mut old_directory = fs.read_directory_data(state, old_directory.first_cluster)?;
+            fs.remove_entry(&mut old_directory, &found)?;
+            fs.entry_moved(old_location, Node::Entry(new_location));
+
+            if moves_directory {
+                let mut moved = fs.read_directory_data(state, found.entry.first_cluster(fat_type))?;
+                let dot_dot = parse_entries(&moved.data)
+                    .into_iter()
+                    .find(|found| found.entry.short_name() == DOT_DOT)
+                    .ok_or(FileSystemError::Corrupted("missing .."))?;
+                let mut entry = dot_dot.entry;
+                entry.set_first_cluster(fs.parent_reference(new_directory.first_cluster));
+                fs.write_directory(&mut moved, dot_dot.offset, &entry.raw)?;
+            }
+            Ok(())
+        })
     }
 
-    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FileSystemError> {
-        // implementation for reading a file in FAT filesystem
-        // returns the contents of the file as a byte vector on success, or a FileSystemError on failure
-        unimplemented!()
+    fn sync(&self) -> FileSystemResult<()> {
+        Ok(self.fs.device.flush()?)
     }
 }
@@ -0,0 +1,90 @@
+//! Initramfs
+//!
+//! The boot loader loads a cpio archive next to the kernel as a module. It is
+//! unpacked into a tmpfs mounted as the root, so the kernel has its configuration
+//! and `/init` before any disk driver is up.
+
+use crate::fs::cpio::{self, CpioError};
+use crate::fs::tmpfs::TmpFs;
+use crate::fs::vfs::{FileSystemError, MountOptions, Vfs};
+use spin::Mutex;
+use std::sync::Arc;
+
+/// Multiboot2 tag describing a module loaded by the boot loader
+const MULTIBOOT2_TAG_MODULE: u32 = 3;
+
+/// The program started as the first process
+pub const INIT_PATH: &str = "/init";
+
+// Any of the execute permission bits
+const EXECUTE_PERMISSIONS: u16 = 0o111;
+
+/// A file the boot loader placed in physical memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootModule {
+    pub start: usize,
+    pub end: usize,
+    pub command_line: String,
+}
+
+impl BootModule {
+    /// Parse a Multiboot2 module tag
+    pub fn from_multiboot2(tag: &[u8]) -> Option<BootModule> {
+        let u32_at = |offset: usize| -> Option<u32> { Some(u32::from_le_bytes(tag.get(offset..offset + 4)?.try_into().ok()?)) };
+        if u32_at(0)? != MULTIBOOT2_TAG_MODULE {
+            return None;
+        }
+        let size = (u32_at(4)? as usize).min(tag.len());
+        let (start, end) = (u32_at(8)? as usize, u32_at(12)? as usize);
+        if end < start {
+            return None;
+        }
+        // The command line is NUL terminated
+        let command_line = tag.get(16..size)?;
+        let length = command_line.iter().position(|byte| *byte == 0).unwrap_or(command_line.len());
+        Some(BootModule {
+            start,
+            end,
+            command_line: String::from_utf8_lossy(&command_line[..length]).into_owned(),
+        })
+    }
+}
+
+static BOOT_MODULE: Mutex<Option<BootModule>> = Mutex::new(None);
+
+/// Register the initramfs the boot loader loaded, to be unpacked by `init`
+///
+/// # Safety
+///
+/// The module must stay mapped at its address, and otherwise unused, until `init` runs.
+pub unsafe fn add_boot_module(module: BootModule) {
+    *BOOT_MODULE.lock() = Some(module);
+}
+
+/// Mount a tmpfs as the root and unpack the boot module into it, if there is one
+pub fn init(vfs: &Vfs) -> Result<usize, CpioError> {
+    let module = BOOT_MODULE.lock().take();
+    let archive = module.as_ref().map(|module| {
+        // SAFETY: `add_boot_module` requires the module to stay mapped and unused
+        unsafe { core::slice::from_raw_parts(module.start as *const u8, module.end - module.start) }
+    });
+    mount_root(vfs, archive)
+}
+
+/// Mount a tmpfs at `/` and unpack `archive` into it; returns the number of entries
+pub fn mount_root(vfs: &Vfs, archive: Option<&[u8]>) -> Result<usize, CpioError> {
+    vfs.mount(Arc::new(TmpFs::new()), "rootfs", "/", MountOptions::default())?;
+    match archive {
+        Some(archive) => cpio::unpack(archive, vfs, "/"),
+        None => Ok(0),
+    }
+}
+
+/// The path of the init program, if it is an executable file
+pub fn find_init(vfs: &Vfs) -> Result<String, FileSystemError> {
+    let metadata = vfs.metadata(INIT_PATH)?;
+    if !metadata.is_file() || metadata.permissions & EXECUTE_PERMISSIONS == 0 {
+        return Err(FileSystemError::PermissionDenied);
+    }
+    vfs.canonicalize(INIT_PATH)
+}
@@ -0,0 +1,724 @@
+//! ISO 9660
+//!
+//! The CD-ROM filesystem, read-only, so the system can read its own install media.
+//! A volume starts with volume descriptors from sector 16: the primary one gives the
+//! root directory and the path table, which lists every directory with its parent.
+//! A directory is a series of records, one per file, that never cross a logical
+//! block. ISO 9660 names are short and in upper case, so two extensions add better
+//! ones: Rock Ridge keeps POSIX names, modes, owners, times and symlinks in the
+//! system use area of each record, and Joliet adds a second directory tree with
+//! names in UCS-2 behind a supplementary volume descriptor.
+//!
+//! Rock Ridge is used when the root carries it, then Joliet, then plain names, which
+//! are shown in lower case as Linux shows them. Joliet and plain names match in any
+//! case. Without Rock Ridge, files belong to root and are readable by everyone.
+//! Interleaved files cannot be read.
+
+use crate::fs::vfs::{
+    DirectoryEntry, FileSystem, FileSystemError, FileSystemResult, FileSystemStats, FileType, Inode, InodeId,
+    Metadata, MAX_NAME_LEN,
+};
+use crate::storage::block::BlockDevice;
+use core::any::Any;
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+
+const SECTOR_SIZE: u64 = 2048;
+/// The first 16 sectors are left to the system, for boot code and the like
+const FIRST_DESCRIPTOR: u64 = 16;
+/// More descriptors than this are taken for a missing terminator
+const MAX_DESCRIPTORS: u64 = 64;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+
+// Volume descriptor types
+const PRIMARY_DESCRIPTOR: u8 = 1;
+const SUPPLEMENTARY_DESCRIPTOR: u8 = 2;
+const TERMINATOR: u8 = 255;
+/// Escape sequences of a Joliet descriptor, for UCS-2 levels 1, 2 and 3
+const JOLIET_ESCAPES: [&[u8; 3]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+// Longest names, in characters, of Joliet and of ISO 9660 levels 2 and 3
+const JOLIET_MAX_NAME_LEN: u32 = 64;
+const ISO9660_MAX_NAME_LEN: u32 = 31;
+
+/// The shortest directory record, with a one-byte name
+const MIN_RECORD_LEN: usize = 34;
+/// Larger directories are taken for corruption
+const MAX_DIRECTORY_SIZE: u64 = 16 << 20;
+
+// Directory record flags
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_ASSOCIATED: u8 = 0x04;
+/// The file goes on in the next record, which has the same name
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+// Names of the records of a directory itself and of its parent
+const DOT: &[u8] = &[0];
+const DOT_DOT: &[u8] = &[1];
+
+/// Check bytes of the SUSP indicator in the root's `.` record
+const SUSP_CHECK: [u8; 2] = [0xBE, 0xEF];
+/// More continuation areas than this for one record are taken for a loop
+const MAX_CONTINUATIONS: usize = 16;
+
+// Rock Ridge name and symlink component flags
+const CONTINUE: u8 = 0x01;
+const CURRENT: u8 = 0x02;
+const PARENT: u8 = 0x04;
+const ROOT: u8 = 0x08;
+
+// Rock Ridge timestamps, in the order they follow these flags
+const TIME_CREATION: u8 = 0x01;
+const TIME_MODIFY: u8 = 0x02;
+const TIME_ACCESS: u8 = 0x04;
+const TIME_ATTRIBUTES: u8 = 0x08;
+const TIME_KINDS: u8 = 7;
+/// Timestamps in the 17-byte format of volume descriptors rather than 7 bytes
+const TIME_LONG_FORM: u8 = 0x80;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+// Days since 1970 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// A local date and time, with its offset from UTC in 15-minute steps, as time since
+/// the epoch
+fn decode_date(year: i64, date: [u32; 5], offset: u8, fraction: Duration) -> Option<Duration> {
+    let [month, day, hour, minute, second] = date;
+    if !(1..=12).contains(&month) || day == 0 || day > 31 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86400 + (hour * 3600 + minute * 60 + second) as i64
+        - offset as i8 as i64 * 900;
+    u64::try_from(seconds).ok().map(|seconds| Duration::from_secs(seconds) + fraction)
+}
+
+/// The 7-byte time of a directory record: years since 1900, month, day, hour,
+/// minute, second and offset
+fn decode_short_time(raw: &[u8]) -> Option<Duration> {
+    let date = [raw[1], raw[2], raw[3], raw[4], raw[5]].map(u32::from);
+    decode_date(1900 + raw[0] as i64, date, raw[6], Duration::ZERO)
+}
+
+/// The 17-byte time of a volume descriptor: "YYYYMMDDHHMMSScc" in ASCII, then
+/// the offset
+fn decode_long_time(raw: &[u8]) -> Option<Duration> {
+    let digits = core::str::from_utf8(&raw[..16]).ok().filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))?;
+    let field = |range: core::ops::Range<usize>| digits[range].parse::<u32>().unwrap();
+    let date = [field(4..6), field(6..8), field(8..10), field(10..12), field(12..14)];
+    let hundredths = Duration::from_millis(field(14..16) as u64 * 10);
+    decode_date(field(0..4) as i64, date, raw[16], hundredths)
+}
+
+/// The extension whose names and attributes a volume is read with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    Plain,
+    Joliet,
+    RockRidge,
+}
+
+/// A directory record: one file, or one part of a file in several extents
+#[derive(Debug, Clone)]
+struct Record {
+    /// Where the record is on the device
+    position: u64,
+    flags: u8,
+    /// The parts of the file's data, as first block and length in bytes
+    extents: Vec<(u64, u64)>,
+    identifier: Vec<u8>,
+    recorded: Option<Duration>,
+    interleaved: bool,
+    system_use: Vec<u8>,
+}
+
+impl Record {
+    fn parse(raw: &[u8], position: u64) -> FileSystemResult<Record> {
+        let length = raw[0] as usize;
+        let name_length = raw.get(32).copied().unwrap_or(0) as usize;
+        if length < MIN_RECORD_LEN || length > raw.len() || 33 + name_length > length || name_length == 0 {
+            return Err(FileSystemError::Corrupted("bad directory record"));
+        }
+        // The name is padded to an even length; data follows any extended
+        // attribute record at the start of the extent
+        let system_use = (33 + name_length + (1 - name_length % 2)).min(length);
+        Ok(Record {
+            position,
+            flags: raw[25],
+            extents: vec![(read_u32(raw, 2) as u64 + raw[1] as u64, read_u32(raw, 10) as u64)],
+            identifier: raw[33..33 + name_length].to_vec(),
+            recorded: decode_short_time(&raw[18..25]),
+            interleaved: raw[26] != 0 || raw[27] != 0,
+            system_use: raw[system_use..length].to_vec(),
+        })
+    }
+
+    fn is_directory(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    fn size(&self) -> u64 {
+        self.extents.iter().map(|&(_, length)| length).sum()
+    }
+}
+
+/// What the Rock Ridge entries of a record say
+#[derive(Debug, Clone, Default)]
+struct RockRidge {
+    name: Option<Vec<u8>>,
+    /// Mode, links, owner and group
+    attributes: Option<[u32; 4]>,
+    /// Major and minor number of a device file
+    device: Option<(u32, u32)>,
+    symlink: Option<String>,
+    created: Option<Duration>,
+    modified: Option<Duration>,
+    accessed: Option<Duration>,
+    changed: Option<Duration>,
+    /// Where a directory moved away for being too deep now is
+    child: Option<u64>,
+    /// Whether this is such a directory, in its new place
+    relocated: bool,
+}
+
+impl RockRidge {
+    /// Take in the components of an SL entry, joining them to the target so far
+    fn add_symlink(&mut self, mut components: &[u8], continued: &mut bool) -> FileSystemResult<()> {
+        let target = self.symlink.get_or_insert_with(String::new);
+        while !components.is_empty() {
+            let flags = components[0];
+            let length = *components.get(1).ok_or(FileSystemError::Corrupted("bad symlink"))? as usize;
+            let content = components.get(2..2 + length).ok_or(FileSystemError::Corrupted("bad symlink"))?;
+            if !*continued && !target.is_empty() && !target.ends_with('/') {
+                target.push('/');
+            }
+            match flags & (CURRENT | PARENT | ROOT) {
+                0 => target.push_str(&String::from_utf8_lossy(content)),
+                CURRENT => target.push('.'),
+                PARENT => target.push_str(".."),
+                _ => target.push('/'),
+            }
+            *continued = flags & CONTINUE != 0;
+            components = &components[2 + length..];
+        }
+        Ok(())
+    }
+
+    fn add_times(&mut self, entry: &[u8]) {
+        let flags = entry[4];
+        let size = if flags & TIME_LONG_FORM != 0 { 17 } else { 7 };
+        let mut at = 5;
+        for kind in 0..TIME_KINDS {
+            let bit = 1 << kind;
+            if flags & bit == 0 {
+                continue;
+            }
+            let Some(raw) = entry.get(at..at + size) else { return };
+            let time = if size == 17 { decode_long_time(raw) } else { decode_short_time(raw) };
+            match bit {
+                TIME_CREATION => self.created = time,
+                TIME_MODIFY => self.modified = time,
+                TIME_ACCESS => self.accessed = time,
+                TIME_ATTRIBUTES => self.changed = time,
+                _ => {}
+            }
+            at += size;
+        }
+    }
+}
+
+/// What a directory holds under a name
+struct Entry {
+    name: String,
+    record: Record,
+    rock_ridge: RockRidge,
+}
+
+/// The volume descriptor the volume is read through
+struct Descriptor {
+    root: Record,
+    path_table: (u64, u64),
+}
+
+impl Descriptor {
+    fn parse(raw: &[u8], position: u64) -> FileSystemResult<Descriptor> {
+        Ok(Descriptor {
+            root: Record::parse(&raw[156..190], position + 156)?,
+            path_table: (read_u32(raw, 140) as u64, read_u32(raw, 132) as u64),
+        })
+    }
+}
+
+pub struct Iso9660FileSystem {
+    this: Weak<Iso9660FileSystem>,
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    blocks: u64,
+    extension: Extension,
+    /// Bytes to skip at the start of every system use area, from the SUSP indicator
+    skip: usize,
+    /// The `.` record of the root directory
+    root: Record,
+    /// Number of subdirectories of each directory by its first block, from the path table
+    subdirectories: BTreeMap<u64, u32>,
+}
+
+impl Iso9660FileSystem {
+    /// Mount the ISO 9660 volume on `device`, which is always read-only
+    pub fn mount(device: Arc<dyn BlockDevice>) -> FileSystemResult<Arc<Iso9660FileSystem>> {
+        let mut primary = None;
+        let mut joliet = None;
+        for sector in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            let mut descriptor = vec![0; SECTOR_SIZE as usize];
+            device.read_at(sector * SECTOR_SIZE, &mut descriptor)?;
+            if &descriptor[1..6] != STANDARD_ID {
+                return Err(match sector {
+                    FIRST_DESCRIPTOR => FileSystemError::InvalidArgument,
+                    _ => FileSystemError::Corrupted("bad volume descriptor"),
+                });
+            }
+            match descriptor[0] {
+                PRIMARY_DESCRIPTOR if primary.is_none() => primary = Some((sector, descriptor)),
+                SUPPLEMENTARY_DESCRIPTOR if JOLIET_ESCAPES.iter().any(|escape| descriptor[88..91] == escape[..]) => {
+                    joliet.get_or_insert((sector, descriptor));
+                }
+                TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let (sector, primary) = primary.ok_or(FileSystemError::Corrupted("no primary volume descriptor"))?;
+
+        let block_size = read_u16(&primary, 128) as u64;
+        if !block_size.is_power_of_two() || !(512..=SECTOR_SIZE).contains(&block_size) {
+            return Err(FileSystemError::Corrupted("bad logical block size"));
+        }
+        let blocks = read_u32(&primary, 80) as u64;
+        if blocks * block_size > device.block_count() * device.block_size() as u64 {
+            return Err(FileSystemError::Corrupted("volume larger than the device"));
+        }
+        let mut filesystem = Iso9660FileSystem {
+            this: Weak::new(),
+            device,
+            block_size,
+            blocks,
+            extension: Extension::Plain,
+            skip: 0,
+            root: Descriptor::parse(&primary, sector * SECTOR_SIZE)?.root,
+            subdirectories: BTreeMap::new(),
+        };
+
+        // Rock Ridge announces itself with a SUSP indicator at the start of the
+        // system use area of the root's `.` record
+        let root = filesystem.first_record(filesystem.root.extents[0].0)?;
+        let rock_ridge = match &root.system_use[..] {
+            [b'S', b'P', 7, _, check_0, check_1, skip, ..] if [*check_0, *check_1] == SUSP_CHECK => Some(*skip),
+            _ => None,
+        };
+        let (sector, descriptor) = match (rock_ridge, joliet) {
+            (Some(skip), _) => {
+                filesystem.extension = Extension::RockRidge;
+                filesystem.skip = skip as usize;
+                (sector, primary)
+            }
+            (None, Some(joliet)) => {
+                filesystem.extension = Extension::Joliet;
+                joliet
+            }
+            (None, None) => (sector, primary),
+        };
+        let descriptor = Descriptor::parse(&descriptor, sector * SECTOR_SIZE)?;
+        filesystem.root = filesystem.first_record(descriptor.root.extents[0].0)?;
+        filesystem.root.extents = descriptor.root.extents;
+        filesystem.subdirectories = filesystem.read_path_table(descriptor.path_table)?;
+        Ok(Arc::new_cyclic(|this| Iso9660FileSystem { this: this.clone(), ..filesystem }))
+    }
+
+    pub fn extension(&self) -> Extension {
+        self.extension
+    }
+
+    fn this(&self) -> Arc<Iso9660FileSystem> {
+        self.this.upgrade().expect("a mounted filesystem is alive")
+    }
+
+    /// Read `buffer.len()` bytes of the volume from `offset` of block `block`
+    fn read(&self, block: u64, offset: u64, buffer: &mut [u8]) -> FileSystemResult<()> {
+        let start = block * self.block_size + offset;
+        if block >= self.blocks || start + buffer.len() as u64 > self.blocks * self.block_size {
+            return Err(FileSystemError::Corrupted("extent outside the volume"));
+        }
+        Ok(self.device.read_at(start, buffer)?)
+    }
+
+    /// The `.` record at the start of the directory at `block`
+    fn first_record(&self, block: u64) -> FileSystemResult<Record> {
+        let mut data = vec![0; self.block_size as usize];
+        self.read(block, 0, &mut data)?;
+        let record = Record::parse(&data, block * self.block_size)?;
+        if record.identifier != DOT || !record.is_directory() {
+            return Err(FileSystemError::Corrupted("directory does not start with `.`"));
+        }
+        Ok(record)
+    }
+
+    /// Count the subdirectories of each directory in the path table at `location`,
+    /// checking that each directory comes after its parent
+    fn read_path_table(&self, (location, size): (u64, u64)) -> FileSystemResult<BTreeMap<u64, u32>> {
+        if size > MAX_DIRECTORY_SIZE {
+            return Err(FileSystemError::Corrupted("bad path table"));
+        }
+        let mut table = vec![0; size as usize];
+        self.read(location, 0, &mut table)?;
+        let mut directories = Vec::new();
+        let mut subdirectories = BTreeMap::new();
+        let mut at = 0;
+        while at + 8 <= table.len() {
+            let name_length = table[at] as usize;
+            let parent = read_u16(&table, at + 6) as usize;
+            let number = directories.len() + 1;
+            if name_length == 0 || parent == 0 || parent > number || (parent == number && number != 1) {
+                return Err(FileSystemError::Corrupted("bad path table"));
+            }
+            directories.push(read_u32(&table, at + 2) as u64 + table[at + 1] as u64);
+            if number != 1 {
+                *subdirectories.entry(directories[parent - 1]).or_insert(0) += 1;
+            }
+            at += 8 + name_length + name_length % 2;
+        }
+        if directories.first() != Some(&self.root.extents[0].0) {
+            return Err(FileSystemError::Corrupted("path table does not start at the root"));
+        }
+        Ok(subdirectories)
+    }
+
+    /// The records of a directory, with the parts of a file in several extents
+    /// joined into its first record
+    fn records(&self, directory: &Record) -> FileSystemResult<Vec<Record>> {
+        let (block, size) = directory.extents[0];
+        if size > MAX_DIRECTORY_SIZE {
+            return Err(FileSystemError::Corrupted("directory too large"));
+        }
+        let mut data = vec![0; size as usize];
+        self.read(block, 0, &mut data)?;
+        let mut records: Vec<Record> = Vec::new();
+        for (index, chunk) in data.chunks(self.block_size as usize).enumerate() {
+            let mut at = 0;
+            // Records do not cross blocks; the rest of a block after the last is zeros
+            while at < chunk.len() && chunk[at] != 0 {
+                let position = (block + index as u64) * self.block_size + at as u64;
+                let record = Record::parse(&chunk[at..], position)?;
+                at += chunk[at] as usize;
+                match records.last_mut() {
+                    Some(previous) if previous.flags & FLAG_MULTI_EXTENT != 0 => {
+                        if previous.identifier != record.identifier {
+                            return Err(FileSystemError::Corrupted("file extents under different names"));
+                        }
+                        previous.extents.extend(record.extents);
+                        previous.flags = record.flags;
+                    }
+                    _ => records.push(record),
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Read the Rock Ridge entries of `record`, following continuation areas
+    fn rock_ridge(&self, record: &Record) -> FileSystemResult<RockRidge> {
+        let mut rock_ridge = RockRidge::default();
+        if self.extension != Extension::RockRidge {
+            return Ok(rock_ridge);
+        }
+        let mut area = record.system_use.get(self.skip..).unwrap_or_default().to_vec();
+        let mut continued = false;
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut at = 0;
+            while at + 4 <= area.len() {
+                let length = area[at + 2] as usize;
+                if length < 4 || at + length > area.len() {
+                    break;
+                }
+                let entry = &area[at..at + length];
+                match (&entry[..2], length) {
+                    (b"ST", _) => break,
+                    (b"CE", 28..) => {
+                        let (block, offset) = (read_u32(entry, 4) as u64, read_u32(entry, 12) as u64);
+                        continuation = Some((block, offset, read_u32(entry, 20)));
+                    }
+                    (b"PX", 36..) => {
+                        rock_ridge.attributes =
+                            Some([read_u32(entry, 4), read_u32(entry, 12), read_u32(entry, 20), read_u32(entry, 28)])
+                    }
+                    (b"PN", 20..) => rock_ridge.device = Some((read_u32(entry, 4), read_u32(entry, 12))),
+                    (b"NM", 5..) if entry[4] & (CURRENT | PARENT) == 0 => {
+                        rock_ridge.name.get_or_insert_with(Vec::new).extend_from_slice(&entry[5..])
+                    }
+                    (b"SL", 5..) => rock_ridge.add_symlink(&entry[5..], &mut continued)?,
+                    (b"TF", 5..) => rock_ridge.add_times(entry),
+                    (b"CL", 12..) => rock_ridge.child = Some(read_u32(entry, 4) as u64),
+                    (b"RE", _) => rock_ridge.relocated = true,
+                    _ => {}
+                }
+                at += length;
+            }
+            let Some((block, offset, length)) = continuation else {
+                return Ok(rock_ridge);
+            };
+            if offset + length as u64 > self.block_size {
+                return Err(FileSystemError::Corrupted("bad continuation area"));
+            }
+            area = vec![0; length as usize];
+            self.read(block, offset, &mut area)?;
+        }
+        Err(FileSystemError::Corrupted("too many continuation areas"))
+    }
+
+    /// The name of a record without its version, or in Joliet, the UCS-2 name
+    fn name(&self, record: &Record, rock_ridge: &RockRidge) -> String {
+        let identifier = &record.identifier;
+        let name = match (self.extension, &rock_ridge.name) {
+            (Extension::RockRidge, Some(name)) => return String::from_utf8_lossy(name).into_owned(),
+            (Extension::Joliet, _) => {
+                let units: Vec<u16> =
+                    identifier.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]])).collect();
+                String::from_utf16_lossy(&units)
+            }
+            _ => String::from_utf8_lossy(identifier).to_ascii_lowercase(),
+        };
+        let name = name.split_once(';').map_or(&name[..], |(name, _)| name);
+        match self.extension {
+            Extension::Joliet => name.to_string(),
+            _ => name.strip_suffix('.').unwrap_or(name).to_string(),
+        }
+    }
+
+    fn same_name(&self, a: &str, b: &str) -> bool {
+        match self.extension {
+            Extension::RockRidge => a == b,
+            _ => a.chars().flat_map(char::to_uppercase).eq(b.chars().flat_map(char::to_uppercase)),
+        }
+    }
+
+    /// The files of a directory. A directory moved away for being too deep is in
+    /// the entry that points to it, not in its new place.
+    fn entries(&self, directory: &Record) -> FileSystemResult<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for record in self.records(directory)? {
+            if record.identifier == DOT || record.identifier == DOT_DOT || record.flags & FLAG_ASSOCIATED != 0 {
+                continue;
+            }
+            let rock_ridge = self.rock_ridge(&record)?;
+            if rock_ridge.relocated {
+                continue;
+            }
+            let name = self.name(&record, &rock_ridge);
+            let (record, rock_ridge) = match rock_ridge.child {
+                Some(block) => {
+                    let moved = self.first_record(block)?;
+                    let rock_ridge = self.rock_ridge(&moved)?;
+                    (moved, rock_ridge)
+                }
+                None => (record, rock_ridge),
+            };
+            entries.push(Entry { name, record, rock_ridge });
+        }
+        Ok(entries)
+    }
+
+    /// A directory is numbered by its first block, which its `.` record and the
+    /// record in its parent share, and a file by where its record is
+    fn inode_number(&self, record: &Record) -> InodeId {
+        match record.is_directory() {
+            true => record.extents[0].0 * self.block_size,
+            false => record.position,
+        }
+    }
+
+    fn file_type(&self, record: &Record, rock_ridge: &RockRidge) -> FileSystemResult<FileType> {
+        match rock_ridge.attributes {
+            Some([mode, ..]) => FileType::from_mode(mode).ok_or(FileSystemError::Corrupted("bad file mode")),
+            None if record.is_directory() => Ok(FileType::Directory),
+            None => Ok(FileType::Regular),
+        }
+    }
+
+    fn metadata(&self, record: &Record, rock_ridge: &RockRidge) -> FileSystemResult<Metadata> {
+        let file_type = self.file_type(record, rock_ridge)?;
+        let permissions = match (rock_ridge.attributes, file_type) {
+            (Some([mode, ..]), _) => mode as u16,
+            (None, FileType::Directory) => 0o555,
+            (None, _) => 0o444,
+        };
+        let mut metadata = Metadata::new(self.inode_number(record), file_type, permissions);
+        match rock_ridge.attributes {
+            Some([_, links, uid, gid]) => {
+                metadata.links = links;
+                metadata.uid = uid;
+                metadata.gid = gid;
+            }
+            None if file_type == FileType::Directory => {
+                let first_block = record.extents[0].0;
+                metadata.links = 2 + self.subdirectories.get(&first_block).copied().unwrap_or(0);
+            }
+            None => {}
+        }
+        match (file_type, &rock_ridge.symlink) {
+            (FileType::Symlink, Some(target)) => metadata.size = target.len() as u64,
+            (FileType::Regular | FileType::Directory, _) => {
+                metadata.size = record.size();
+                let blocks: u64 = record.extents.iter().map(|&(_, length)| length.div_ceil(self.block_size)).sum();
+                metadata.blocks = blocks * self.block_size / 512;
+            }
+            _ => {}
+        }
+        metadata.block_size = self.block_size as u32;
+        if let (FileType::CharDevice | FileType::BlockDevice, Some((major, minor))) = (file_type, rock_ridge.device) {
+            // Linux's encoding, or its old one when there is only a minor number
+            metadata.device = match major {
+                0 => minor as u64,
+                _ => (minor as u64 & 0xFF) | (major as u64) << 8 | (minor as u64 & !0xFF) << 12,
+            };
+        }
+        let recorded = record.recorded.unwrap_or_default();
+        metadata.modified = rock_ridge.modified.unwrap_or(recorded);
+        metadata.accessed = rock_ridge.accessed.unwrap_or(metadata.modified);
+        metadata.changed = rock_ridge.changed.unwrap_or(metadata.modified);
+        metadata.created = rock_ridge.created;
+        Ok(metadata)
+    }
+
+    fn inode(&self, record: Record, rock_ridge: RockRidge) -> Arc<Iso9660Inode> {
+        Arc::new(Iso9660Inode { fs: self.this(), record, rock_ridge })
+    }
+}
+
+impl FileSystem for Iso9660FileSystem {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn root(&self) -> FileSystemResult<Arc<dyn Inode>> {
+        Ok(self.inode(self.root.clone(), self.rock_ridge(&self.root)?))
+    }
+
+    fn stats(&self) -> FileSystemResult<FileSystemStats> {
+        Ok(FileSystemStats {
+            block_size: self.block_size as u32,
+            blocks: self.blocks,
+            free_blocks: 0,
+            inodes: 0,
+            free_inodes: 0,
+            max_name_len: match self.extension {
+                Extension::RockRidge => MAX_NAME_LEN as u32,
+                Extension::Joliet => JOLIET_MAX_NAME_LEN,
+                Extension::Plain => ISO9660_MAX_NAME_LEN,
+            },
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// A file or directory of an ISO 9660 volume, by its directory record
+pub struct Iso9660Inode {
+    fs: Arc<Iso9660FileSystem>,
+    record: Record,
+    rock_ridge: RockRidge,
+}
+
+impl Iso9660Inode {
+    fn file_type(&self) -> FileSystemResult<FileType> {
+        self.fs.file_type(&self.record, &self.rock_ridge)
+    }
+
+    fn entries(&self) -> FileSystemResult<Vec<Entry>> {
+        match self.file_type()? {
+            FileType::Directory => self.fs.entries(&self.record),
+            _ => Err(FileSystemError::NotADirectory),
+        }
+    }
+}
+
+impl Inode for Iso9660Inode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        self.fs.metadata(&self.record, &self.rock_ridge)
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        match self.file_type()? {
+            FileType::Regular => {}
+            FileType::Directory => return Err(FileSystemError::IsADirectory),
+            _ => return Err(FileSystemError::InvalidArgument),
+        }
+        if self.record.interleaved {
+            return Err(FileSystemError::NotSupported);
+        }
+        let size = self.record.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let length = buffer.len().min((size - offset) as usize);
+        let mut done = 0;
+        let mut start = 0;
+        for &(block, extent_length) in &self.record.extents {
+            let position = offset + done as u64;
+            if done < length && position < start + extent_length {
+                let count = (start + extent_length - position).min((length - done) as u64) as usize;
+                self.fs.read(block, position - start, &mut buffer[done..done + count])?;
+                done += count;
+            }
+            start += extent_length;
+        }
+        Ok(length)
+    }
+
+    fn lookup(&self, name: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        let entry = self
+            .entries()?
+            .into_iter()
+            .find(|entry| self.fs.same_name(&entry.name, name))
+            .ok_or(FileSystemError::NotFound)?;
+        Ok(self.fs.inode(entry.record, entry.rock_ridge))
+    }
+
+    fn read_directory(&self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        self.entries()?
+            .into_iter()
+            .map(|entry| {
+                Ok(DirectoryEntry {
+                    inode: self.fs.inode_number(&entry.record),
+                    file_type: self.fs.file_type(&entry.record, &entry.rock_ridge)?,
+                    name: entry.name,
+                })
+            })
+            .collect()
+    }
+
+    fn read_link(&self) -> FileSystemResult<String> {
+        match (self.file_type()?, &self.rock_ridge.symlink) {
+            (FileType::Symlink, Some(target)) => Ok(target.clone()),
+            _ => Err(FileSystemError::InvalidArgument),
+        }
+    }
+}
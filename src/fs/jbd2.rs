@@ -0,0 +1,309 @@
+//! JBD2 journal recovery
+//!
+//! ext3 and ext4 write metadata changes to a journal and commit them there before
+//! putting them in place. After a crash the journal may hold committed
+//! transactions that never reached their final place; replaying them in order
+//! brings the volume back to a consistent state. Only recovery is here: this
+//! kernel writes through to the device and never adds to a journal. Journal
+//! fields are big-endian, unlike the rest of ext2.
+
+use crate::fs::ext4::crc32c;
+use crate::fs::vfs::{FileSystemError, FileSystemResult};
+use crate::storage::block::BlockDevice;
+use std::collections::BTreeMap;
+
+const MAGIC: u32 = 0xC03B_3998;
+
+// Block types
+const DESCRIPTOR_BLOCK: u32 = 1;
+const COMMIT_BLOCK: u32 = 2;
+const SUPERBLOCK_V1: u32 = 3;
+const SUPERBLOCK_V2: u32 = 4;
+const REVOKE_BLOCK: u32 = 5;
+
+// Incompatible features of version 2 journals
+const INCOMPAT_REVOKE: u32 = 0x01;
+const INCOMPAT_64BIT: u32 = 0x02;
+const INCOMPAT_ASYNC_COMMIT: u32 = 0x04;
+const INCOMPAT_CSUM_V2: u32 = 0x08;
+const INCOMPAT_CSUM_V3: u32 = 0x10;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_REVOKE | INCOMPAT_64BIT | INCOMPAT_ASYNC_COMMIT | INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3;
+
+// Descriptor tag flags
+const TAG_ESCAPED: u32 = 0x1;
+const TAG_SAME_UUID: u32 = 0x2;
+const TAG_LAST: u32 = 0x8;
+
+/// The checksum type of CRC32c
+const CHECKSUM_CRC32C: u8 = 4;
+
+const HEADER_SIZE: usize = 12;
+const SUPERBLOCK_SIZE: usize = 1024;
+const UUID_SIZE: usize = 16;
+
+/// What a replay did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Committed transactions found in the journal
+    pub transactions: u32,
+    /// Blocks written to their place on the volume
+    pub blocks: u32,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// A block logged by a transaction, to be copied to `target`
+struct Tag {
+    target: u64,
+    flags: u32,
+    checksum: u32,
+}
+
+/// A committed transaction
+struct Transaction {
+    sequence: u32,
+    /// Each logged block with the journal block holding its new contents
+    blocks: Vec<(Tag, u32)>,
+    revoked: Vec<u64>,
+}
+
+struct Journal<'a> {
+    device: &'a dyn BlockDevice,
+    block_size: u64,
+    /// The volume block of each journal block
+    blocks: &'a [u64],
+    /// First journal block past the superblock, where the log wraps around to
+    first: u32,
+    incompat: u32,
+    checksum_seed: Option<u32>,
+}
+
+impl Journal<'_> {
+    fn read(&self, index: u32) -> FileSystemResult<Vec<u8>> {
+        let block = *self.blocks.get(index as usize).ok_or(FileSystemError::Corrupted("journal block out of range"))?;
+        let mut data = vec![0; self.block_size as usize];
+        self.device.read_at(block * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    fn next(&self, index: u32) -> u32 {
+        match index + 1 {
+            next if next as usize >= self.blocks.len() => self.first,
+            next => next,
+        }
+    }
+
+    fn has(&self, feature: u32) -> bool {
+        self.incompat & feature != 0
+    }
+
+    fn tag_size(&self) -> usize {
+        if self.has(INCOMPAT_CSUM_V3) {
+            return 16;
+        }
+        let size = if self.has(INCOMPAT_CSUM_V2) { 14 } else { 12 };
+        if self.has(INCOMPAT_64BIT) {
+            size
+        } else {
+            size - 4
+        }
+    }
+
+    /// Whether the checksum in a descriptor or revoke block's last four bytes matches
+    fn tail_ok(&self, data: &[u8]) -> bool {
+        let Some(seed) = self.checksum_seed else {
+            return true;
+        };
+        let tail = data.len() - 4;
+        let mut copy = data.to_vec();
+        copy[tail..].fill(0);
+        crc32c(seed, &copy) == read_u32(data, tail)
+    }
+
+    fn commit_ok(&self, data: &[u8]) -> bool {
+        let Some(seed) = self.checksum_seed else {
+            return true;
+        };
+        let mut copy = data.to_vec();
+        copy[16..20].fill(0);
+        crc32c(seed, &copy) == read_u32(data, 16)
+    }
+
+    // A logged block's checksum covers the transaction's sequence number too
+    fn block_ok(&self, tag: &Tag, sequence: u32, data: &[u8]) -> bool {
+        let Some(seed) = self.checksum_seed else {
+            return true;
+        };
+        let crc = crc32c(crc32c(seed, &sequence.to_be_bytes()), data);
+        match self.has(INCOMPAT_CSUM_V3) {
+            true => crc == tag.checksum,
+            false => crc & 0xFFFF == tag.checksum,
+        }
+    }
+
+    fn parse_tags(&self, data: &[u8]) -> Vec<Tag> {
+        let end = data.len() - if self.checksum_seed.is_some() { 4 } else { 0 };
+        let size = self.tag_size();
+        let mut tags = Vec::new();
+        let mut offset = HEADER_SIZE;
+        while offset + size <= end {
+            let low = read_u32(data, offset) as u64;
+            let (flags, checksum) = match self.has(INCOMPAT_CSUM_V3) {
+                true => (read_u32(data, offset + 4), read_u32(data, offset + 12)),
+                false => (read_u16(data, offset + 6) as u32, read_u16(data, offset + 4) as u32),
+            };
+            let high = if self.has(INCOMPAT_64BIT) { read_u32(data, offset + 8) as u64 } else { 0 };
+            tags.push(Tag { target: high << 32 | low, flags, checksum });
+            offset += size;
+            // The first tag is followed by the journal's UUID
+            if flags & TAG_SAME_UUID == 0 {
+                offset += UUID_SIZE;
+            }
+            if flags & TAG_LAST != 0 {
+                break;
+            }
+        }
+        tags
+    }
+
+    fn parse_revoked(&self, data: &[u8]) -> FileSystemResult<Vec<u64>> {
+        let size = if self.has(INCOMPAT_64BIT) { 8 } else { 4 };
+        let used = read_u32(data, HEADER_SIZE) as usize;
+        if used < HEADER_SIZE + 4 || used > data.len() {
+            return Err(FileSystemError::Corrupted("journal revoke block"));
+        }
+        Ok(data[HEADER_SIZE + 4..used]
+            .chunks_exact(size)
+            .map(|record| match size {
+                8 => (read_u32(record, 0) as u64) << 32 | read_u32(record, 4) as u64,
+                _ => read_u32(record, 0) as u64,
+            })
+            .collect())
+    }
+
+    /// The committed transactions from `start`, stopping at the first block that
+    /// does not continue the log; an uncommitted transaction at the end is dropped
+    fn scan(&self, start: u32, mut sequence: u32) -> FileSystemResult<Vec<Transaction>> {
+        let mut transactions = Vec::new();
+        let mut current = Transaction { sequence, blocks: Vec::new(), revoked: Vec::new() };
+        let mut index = start;
+        // Each step reads at least one block, so a sound log ends within one lap
+        for _ in 0..self.blocks.len() {
+            let data = self.read(index)?;
+            if read_u32(&data, 0) != MAGIC || read_u32(&data, 8) != sequence {
+                break;
+            }
+            match read_u32(&data, 4) {
+                DESCRIPTOR_BLOCK if self.tail_ok(&data) => {
+                    for tag in self.parse_tags(&data) {
+                        index = self.next(index);
+                        current.blocks.push((tag, index));
+                    }
+                }
+                REVOKE_BLOCK if self.tail_ok(&data) => current.revoked.extend(self.parse_revoked(&data)?),
+                COMMIT_BLOCK if self.commit_ok(&data) => {
+                    sequence = sequence.wrapping_add(1);
+                    let next = Transaction { sequence, blocks: Vec::new(), revoked: Vec::new() };
+                    transactions.push(core::mem::replace(&mut current, next));
+                }
+                _ => break,
+            }
+            index = self.next(index);
+        }
+        Ok(transactions)
+    }
+}
+
+/// Replay the journal whose blocks, in order, are the volume blocks `blocks`,
+/// and mark it empty
+pub fn recover(device: &dyn BlockDevice, block_size: u64, blocks: &[u64]) -> FileSystemResult<Recovery> {
+    if blocks.len() < 2 || block_size < SUPERBLOCK_SIZE as u64 {
+        return Err(FileSystemError::Corrupted("journal size"));
+    }
+    let mut journal = Journal { device, block_size, blocks, first: 1, incompat: 0, checksum_seed: None };
+    let mut superblock = journal.read(0)?;
+    let version = read_u32(&superblock, 4);
+    if read_u32(&superblock, 0) != MAGIC || !matches!(version, SUPERBLOCK_V1 | SUPERBLOCK_V2) {
+        return Err(FileSystemError::Corrupted("journal superblock"));
+    }
+    if read_u32(&superblock, 12) as u64 != block_size {
+        return Err(FileSystemError::NotSupported);
+    }
+    let length = (read_u32(&superblock, 16) as usize).min(blocks.len());
+    journal.blocks = &blocks[..length];
+    journal.first = read_u32(&superblock, 20);
+    if journal.first == 0 || journal.first as usize >= length {
+        return Err(FileSystemError::Corrupted("journal superblock"));
+    }
+    if version == SUPERBLOCK_V2 {
+        journal.incompat = read_u32(&superblock, 40);
+        if journal.incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FileSystemError::NotSupported);
+        }
+        if journal.has(INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3) {
+            if superblock[0x50] != CHECKSUM_CRC32C || superblock_checksum(&superblock) != read_u32(&superblock, 0xFC) {
+                return Err(FileSystemError::Corrupted("journal superblock checksum"));
+            }
+            journal.checksum_seed = Some(crc32c(!0, &superblock[0x30..0x30 + UUID_SIZE]));
+        }
+    }
+    let start = read_u32(&superblock, 28);
+    if start == 0 {
+        return Ok(Recovery::default());
+    }
+    let transactions = journal.scan(start, read_u32(&superblock, 24))?;
+
+    // A block revoked by a transaction is not replayed from that one or any before it
+    let mut revoked: BTreeMap<u64, u32> = BTreeMap::new();
+    for transaction in &transactions {
+        for block in &transaction.revoked {
+            revoked.insert(*block, transaction.sequence);
+        }
+    }
+    let mut recovery = Recovery { transactions: transactions.len() as u32, blocks: 0 };
+    for transaction in &transactions {
+        for (tag, index) in &transaction.blocks {
+            if revoked.get(&tag.target).is_some_and(|sequence| sequence.wrapping_sub(transaction.sequence) as i32 >= 0) {
+                continue;
+            }
+            let mut data = journal.read(*index)?;
+            if !journal.block_ok(tag, transaction.sequence, &data) {
+                continue;
+            }
+            // Blocks that happened to start with the magic number were logged with it cleared
+            if tag.flags & TAG_ESCAPED != 0 {
+                write_u32(&mut data, 0, MAGIC);
+            }
+            device.write_at(tag.target * block_size, &data)?;
+            recovery.blocks += 1;
+        }
+    }
+
+    // The log is empty, and the next transaction follows the last replayed one
+    let next = transactions.last().map_or(read_u32(&superblock, 24), |last| last.sequence.wrapping_add(1));
+    write_u32(&mut superblock, 24, next);
+    write_u32(&mut superblock, 28, 0);
+    if journal.checksum_seed.is_some() {
+        let checksum = superblock_checksum(&superblock);
+        write_u32(&mut superblock, 0xFC, checksum);
+    }
+    device.write_at(blocks[0] * block_size, &superblock[..SUPERBLOCK_SIZE])?;
+    device.flush()?;
+    Ok(recovery)
+}
+
+fn superblock_checksum(superblock: &[u8]) -> u32 {
+    let mut copy = superblock[..SUPERBLOCK_SIZE].to_vec();
+    copy[0xFC..0x100].fill(0);
+    crc32c(!0, &copy)
+}
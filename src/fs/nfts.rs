@@ -1,48 +1,910 @@
-//! NTFS driver implementation
+//! NTFS
+//!
+//! The filesystem of Windows NT and its successors, read-only, for getting data off
+//! the Windows side of a dual-boot disk. Everything on an NTFS volume is a file
+//! described by a record of the master file table, itself the file `$MFT` whose
+//! first record the boot sector points to. A record is a list of attributes: the
+//! file's times, its names, its data, and for a directory a B+ tree indexing the
+//! names in it. Small attributes are resident in the record; larger ones live in
+//! runs of clusters listed in the record. Records and index blocks carry update
+//! sequence fixups, which are checked and undone on reading.
+//!
+//! Names are UTF-16 and looked up without regard to case, through the volume's own
+//! `$UpCase` table. Files belong to root and only the write bits follow the
+//! read-only attribute. Compressed and encrypted data cannot be read, and named
+//! streams and reparse points are not looked at.
 
-use super::{FileSystem, FileSystemError, FileSystemResult, Metadata, OpenOptions, ReadWriteSeek};
+use crate::fs::vfs::{
+    DirectoryEntry, FileSystem, FileSystemError, FileSystemResult, FileSystemStats, FileType, Inode, InodeId,
+    Metadata, MAX_NAME_LEN,
+};
+use crate::storage::block::BlockDevice;
+use core::any::Any;
+use core::cmp::Ordering;
+use core::time::Duration;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Weak};
+
+const BOOT_SECTOR_SIZE: usize = 512;
+const OEM_ID: &[u8; 8] = b"NTFS    ";
+
+const FILE_MAGIC: &[u8; 4] = b"FILE";
+const INDEX_MAGIC: &[u8; 4] = b"INDX";
+/// Update sequence fixups protect the end of every 512 bytes, whatever the sector size
+const FIXUP_STRIDE: usize = 512;
+
+// Records of the system files this driver reads
+const MFT_RECORD: u64 = 0;
+const ROOT_RECORD: u64 = 5;
+const BITMAP_RECORD: u64 = 6;
+const UPCASE_RECORD: u64 = 10;
+/// Records below this hold the volume's own metadata files, which are not listed
+const FIRST_USER_RECORD: u64 = 16;
+
+// A file reference is a record number with the record's sequence number on top
+const RECORD_NUMBER_MASK: u64 = 0xFFFF_FFFF_FFFF;
+const SEQUENCE_SHIFT: u32 = 48;
+
+// Record flags
+const RECORD_IN_USE: u16 = 0x0001;
+const RECORD_DIRECTORY: u16 = 0x0002;
+
+// Attribute types
+const STANDARD_INFORMATION: u32 = 0x10;
+const ATTRIBUTE_LIST: u32 = 0x20;
+const FILE_NAME: u32 = 0x30;
+const DATA: u32 = 0x80;
+const INDEX_ROOT: u32 = 0x90;
+const INDEX_ALLOCATION: u32 = 0xA0;
+const BITMAP: u32 = 0xB0;
+const END_OF_ATTRIBUTES: u32 = 0xFFFF_FFFF;
+
+// Attribute flags
+const ATTRIBUTE_COMPRESSED: u16 = 0x0001;
+const ATTRIBUTE_ENCRYPTED: u16 = 0x4000;
+
+/// The name of the attributes holding a directory's index of file names
+const DIRECTORY_INDEX: &str = "$I30";
+
+// Index entry flags
+const ENTRY_HAS_SUBNODE: u16 = 0x01;
+const ENTRY_LAST: u16 = 0x02;
+/// Deeper trees than this are taken for loops
+const MAX_INDEX_DEPTH: usize = 32;
+
+/// Namespace of a file name that is only the 8.3 alias of another name
+const NAMESPACE_DOS: u8 = 2;
+/// File name flag of a directory
+const FILE_NAME_DIRECTORY: u32 = 0x1000_0000;
+/// File attribute kept in $STANDARD_INFORMATION
+const FILE_ATTRIBUTE_READ_ONLY: u32 = 0x01;
+
+/// The $UpCase table maps every UTF-16 unit
+const UPCASE_SIZE: usize = 65536 * 2;
+
+/// Times count 100 ns intervals from 1601, this many of them before 1970
+const UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn decode_time(time: u64) -> Duration {
+    Duration::from_nanos(time.saturating_sub(UNIX_EPOCH).saturating_mul(100))
+}
+
+/// Sizes of things on the volume, from the boot sector
+struct Layout {
+    cluster_size: u64,
+    clusters: u64,
+    mft_cluster: u64,
+    record_size: u64,
+}
+
+impl Layout {
+    fn parse(boot: &[u8]) -> FileSystemResult<Layout> {
+        if &boot[3..11] != OEM_ID || boot[510..512] != [0x55, 0xAA] {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let sector_size = read_u16(boot, 11) as u64;
+        if !(256..=4096).contains(&sector_size) || !sector_size.is_power_of_two() {
+            return Err(FileSystemError::Corrupted("bad sector size"));
+        }
+        // Past 128, the sectors per cluster are given as a negative power of two
+        let sectors_per_cluster = match boot[13] {
+            0 => return Err(FileSystemError::Corrupted("bad cluster size")),
+            count @ 1..=0x80 => count as u64,
+            shift @ 0xF4.. => 1 << (256 - shift as u32),
+            _ => return Err(FileSystemError::Corrupted("bad cluster size")),
+        };
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err(FileSystemError::Corrupted("bad cluster size"));
+        }
+        let cluster_size = sector_size * sectors_per_cluster;
+
+        // Likewise, a record smaller than a cluster has its size given as a negative
+        // power of two
+        let record_size = match boot[64] as i8 {
+            count @ 1.. => count as u64 * cluster_size,
+            shift @ -20..=-9 => 1 << -shift,
+            _ => return Err(FileSystemError::Corrupted("bad record size")),
+        };
+        if !record_size.is_power_of_two() || record_size < FIXUP_STRIDE as u64 || record_size > 1 << 20 {
+            return Err(FileSystemError::Corrupted("bad record size"));
+        }
+
+        let clusters = read_u64(boot, 40) / sectors_per_cluster;
+        let mft_cluster = read_u64(boot, 48);
+        if mft_cluster >= clusters {
+            return Err(FileSystemError::Corrupted("MFT outside the volume"));
+        }
+        Ok(Layout { cluster_size, clusters, mft_cluster, record_size })
+    }
+}
+
+/// Check and undo the update sequence fixups of a record or index block: on
+/// writing, the last two bytes of every 512 were saved in the update sequence
+/// array and replaced by the update sequence number, so a torn write shows
+fn apply_fixups(block: &mut [u8], magic: &[u8; 4]) -> FileSystemResult<()> {
+    if &block[..4] != magic {
+        return Err(FileSystemError::Corrupted("bad record magic"));
+    }
+    let offset = read_u16(block, 4) as usize;
+    let count = read_u16(block, 6) as usize;
+    if count != block.len() / FIXUP_STRIDE + 1 || offset + 2 * count > block.len() {
+        return Err(FileSystemError::Corrupted("bad update sequence"));
+    }
+    let number = [block[offset], block[offset + 1]];
+    for stride in 1..count {
+        let end = stride * FIXUP_STRIDE;
+        if block[end - 2..end] != number {
+            return Err(FileSystemError::Corrupted("update sequence mismatch"));
+        }
+        let saved = offset + 2 * stride;
+        let original = [block[saved], block[saved + 1]];
+        block[end - 2..end].copy_from_slice(&original);
+    }
+    Ok(())
+}
+
+/// Clusters of a non-resident attribute from virtual cluster `vcn` on, at `lcn`
+/// on the volume, or sparse and reading as zeros when `lcn` is `None`
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    vcn: u64,
+    length: u64,
+    lcn: Option<u64>,
+}
+
+/// Decode a mapping pairs array: for each run a header byte giving the sizes of
+/// its length and of its start, stored relative to the start of the run before
+fn parse_runs(data: &[u8], first_vcn: u64) -> FileSystemResult<Vec<Run>> {
+    let corrupted = FileSystemError::Corrupted("bad data runs");
+    let mut runs = Vec::new();
+    let mut vcn = first_vcn;
+    let mut lcn: i64 = 0;
+    let mut at = 0;
+    loop {
+        let header = *data.get(at).ok_or(corrupted.clone())?;
+        if header == 0 {
+            return Ok(runs);
+        }
+        let length_size = (header & 0x0F) as usize;
+        let offset_size = (header >> 4) as usize;
+        if length_size == 0 || length_size > 8 || offset_size > 8 || at + 1 + length_size + offset_size > data.len() {
+            return Err(corrupted);
+        }
+        let length_bytes = &data[at + 1..at + 1 + length_size];
+        let length = length_bytes.iter().rev().fold(0u64, |value, &byte| value << 8 | byte as u64);
+        let offset_bytes = &data[at + 1 + length_size..at + 1 + length_size + offset_size];
+        at += 1 + length_size + offset_size;
+        if length == 0 || length > i64::MAX as u64 {
+            return Err(corrupted);
+        }
+
+        // A run without a start is sparse
+        let run_lcn = match offset_bytes.last() {
+            None => None,
+            Some(&high) => {
+                let fill = if high & 0x80 != 0 { 0xFF } else { 0 };
+                let mut bytes = [fill; 8];
+                bytes[..offset_size].copy_from_slice(offset_bytes);
+                lcn = lcn.checked_add(i64::from_le_bytes(bytes)).filter(|&lcn| lcn >= 0).ok_or(corrupted.clone())?;
+                Some(lcn as u64)
+            }
+        };
+        runs.push(Run { vcn, length, lcn: run_lcn });
+        vcn = vcn.checked_add(length).ok_or(corrupted.clone())?;
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Resident(Vec<u8>),
+    NonResident {
+        /// The first virtual cluster this part of the attribute maps
+        first_vcn: u64,
+        runs: Vec<Run>,
+        size: u64,
+        /// Bytes past this were never written and read as zeros
+        initialized: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Attribute {
+    kind: u32,
+    name: String,
+    flags: u16,
+    value: Value,
+}
+
+impl Attribute {
+    fn size(&self) -> u64 {
+        match &self.value {
+            Value::Resident(data) => data.len() as u64,
+            Value::NonResident { size, .. } => *size,
+        }
+    }
+
+    /// The clusters of the attribute on the volume, not counting sparse runs
+    fn clusters(&self) -> u64 {
+        match &self.value {
+            Value::Resident(_) => 0,
+            Value::NonResident { runs, .. } => runs.iter().filter(|run| run.lcn.is_some()).map(|run| run.length).sum(),
+        }
+    }
+
+    fn resident(&self) -> FileSystemResult<&[u8]> {
+        match &self.value {
+            Value::Resident(data) => Ok(data),
+            Value::NonResident { .. } => Err(FileSystemError::Corrupted("attribute should be resident")),
+        }
+    }
+}
+
+/// One record of the MFT
+struct Record {
+    sequence: u16,
+    flags: u16,
+    /// The base record of an extension record, zero for a base record
+    base: u64,
+    attributes: Vec<Attribute>,
+}
+
+impl Record {
+    fn parse(mut data: Vec<u8>, number: u64) -> FileSystemResult<Record> {
+        apply_fixups(&mut data, FILE_MAGIC)?;
+        let flags = read_u16(&data, 22);
+        if flags & RECORD_IN_USE == 0 {
+            return Err(FileSystemError::Corrupted("reference to a free record"));
+        }
+        // NTFS 3.1 records say which they are, after an update sequence array that
+        // starts at 0x30 rather than 0x2A
+        if read_u16(&data, 4) >= 0x30 && read_u32(&data, 44) as u64 != number & 0xFFFF_FFFF {
+            return Err(FileSystemError::Corrupted("record in the wrong place"));
+        }
+        let used = (read_u32(&data, 24) as usize).min(data.len());
+
+        let corrupted = FileSystemError::Corrupted("bad attribute");
+        let mut attributes = Vec::new();
+        let mut at = read_u16(&data, 20) as usize;
+        loop {
+            if at + 4 > used {
+                return Err(corrupted);
+            }
+            let kind = read_u32(&data, at);
+            if kind == END_OF_ATTRIBUTES {
+                break;
+            }
+            if at + 16 > used {
+                return Err(corrupted);
+            }
+            let length = read_u32(&data, at + 4) as usize;
+            if length < 24 || at + length > used {
+                return Err(corrupted);
+            }
+            let attribute = &data[at..at + length];
+            let name_length = attribute[9] as usize;
+            let name_offset = read_u16(attribute, 10) as usize;
+            if name_offset + 2 * name_length > length {
+                return Err(corrupted);
+            }
+            let name = utf16(&attribute[name_offset..name_offset + 2 * name_length]);
+            let value = match attribute[8] {
+                0 => {
+                    let value_length = read_u32(attribute, 16) as usize;
+                    let value_offset = read_u16(attribute, 20) as usize;
+                    if value_offset + value_length > length {
+                        return Err(corrupted);
+                    }
+                    Value::Resident(attribute[value_offset..value_offset + value_length].to_vec())
+                }
+                _ => {
+                    if length < 64 {
+                        return Err(corrupted);
+                    }
+                    let runs_offset = read_u16(attribute, 32) as usize;
+                    if runs_offset >= length {
+                        return Err(corrupted);
+                    }
+                    let first_vcn = read_u64(attribute, 16);
+                    Value::NonResident {
+                        first_vcn,
+                        runs: parse_runs(&attribute[runs_offset..], first_vcn)?,
+                        size: read_u64(attribute, 48),
+                        initialized: read_u64(attribute, 56),
+                    }
+                }
+            };
+            attributes.push(Attribute {
+                kind,
+                name: String::from_utf16_lossy(&name),
+                flags: read_u16(attribute, 12),
+                value,
+            });
+            at += length;
+        }
+        Ok(Record {
+            sequence: read_u16(&data, 16),
+            flags,
+            base: read_u64(&data, 32) & RECORD_NUMBER_MASK,
+            attributes,
+        })
+    }
+}
+
+fn utf16(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect()
+}
+
+/// A file: its base record, with the attributes of its extension records added
+/// and each non-resident attribute joined from its parts
+struct File {
+    number: u64,
+    record: Record,
+    attributes: Vec<Attribute>,
+}
+
+impl File {
+    fn attribute(&self, kind: u32, name: &str) -> Option<&Attribute> {
+        self.attributes.iter().find(|attribute| attribute.kind == kind && attribute.name == name)
+    }
+
+    fn is_directory(&self) -> bool {
+        self.record.flags & RECORD_DIRECTORY != 0
+    }
+
+    fn names(&self) -> impl Iterator<Item = FileSystemResult<FileName>> + '_ {
+        self.attributes
+            .iter()
+            .filter(|attribute| attribute.kind == FILE_NAME)
+            .map(|attribute| FileName::parse(attribute.resident()?))
+    }
+}
+
+/// A $FILE_NAME attribute, or the key of a directory index entry
+struct FileName {
+    units: Vec<u16>,
+    namespace: u8,
+    flags: u32,
+}
+
+impl FileName {
+    fn parse(value: &[u8]) -> FileSystemResult<FileName> {
+        if value.len() < 66 || value.len() < 66 + 2 * value[64] as usize {
+            return Err(FileSystemError::Corrupted("bad file name"));
+        }
+        Ok(FileName {
+            units: utf16(&value[66..66 + 2 * value[64] as usize]),
+            namespace: value[65],
+            flags: read_u32(value, 56),
+        })
+    }
+}
+
+/// An entry of a directory index node
+struct IndexEntry {
+    reference: u64,
+    /// The $FILE_NAME of the file, absent in the last entry of a node
+    key: Option<FileName>,
+    /// The node of the entries that sort before this one
+    subnode: Option<u64>,
+}
+
+/// The entries of the index node whose header is at `header` in `block`
+fn parse_index_node(block: &[u8], header: usize) -> FileSystemResult<Vec<IndexEntry>> {
+    let corrupted = FileSystemError::Corrupted("bad index node");
+    if header + 16 > block.len() {
+        return Err(corrupted);
+    }
+    let mut at = header + read_u32(block, header) as usize;
+    let end = header + read_u32(block, header + 4) as usize;
+    if end > block.len() {
+        return Err(corrupted);
+    }
+    let mut entries = Vec::new();
+    loop {
+        if at + 16 > end {
+            return Err(corrupted);
+        }
+        let length = read_u16(block, at + 8) as usize;
+        let key_length = read_u16(block, at + 10) as usize;
+        let flags = read_u16(block, at + 12);
+        if length < 16 || at + length > end || 16 + key_length > length {
+            return Err(corrupted);
+        }
+        let subnode = match flags & ENTRY_HAS_SUBNODE {
+            0 => None,
+            _ if length < 24 + key_length => return Err(corrupted),
+            _ => Some(read_u64(block, at + length - 8)),
+        };
+        let last = flags & ENTRY_LAST != 0;
+        let key = match last {
+            true => None,
+            false => Some(FileName::parse(&block[at + 16..at + 16 + key_length])?),
+        };
+        entries.push(IndexEntry { reference: read_u64(block, at), key, subnode });
+        if last {
+            return Ok(entries);
+        }
+        at += length;
+    }
+}
+
+/// A directory's index: the root node, kept in the directory's record, and the
+/// blocks of the nodes below it
+struct Index<'a> {
+    root: &'a [u8],
+    allocation: Option<&'a Attribute>,
+    block_size: u64,
+}
 
 pub struct NTFSFileSystem {
-    // TODO: Define NTFS specific data structures
+    this: Weak<NTFSFileSystem>,
+    device: Arc<dyn BlockDevice>,
+    layout: Layout,
+    /// Where the MFT lies on the volume, and how large it is
+    mft_runs: Vec<Run>,
+    mft_size: u64,
+    upcase: Vec<u16>,
 }
 
 impl NTFSFileSystem {
-    pub fn new() -> Self {
-        // TODO: Implement NTFS file system initialization
-        Self {
-            // TODO: Initialize NTFS specific data structures
+    /// Mount the NTFS volume on `device`, which is always read-only
+    pub fn mount(device: Arc<dyn BlockDevice>) -> FileSystemResult<Arc<NTFSFileSystem>> {
+        let mut boot = vec![0; BOOT_SECTOR_SIZE];
+        device.read_at(0, &mut boot)?;
+        let layout = Layout::parse(&boot)?;
+        let volume_size = layout.clusters.checked_mul(layout.cluster_size);
+        if volume_size.is_none_or(|size| size > device.block_count() * device.block_size() as u64) {
+            return Err(FileSystemError::Corrupted("volume larger than the device"));
+        }
+
+        // The first record of the MFT maps at least the start of the MFT, which
+        // holds any extension records of $MFT itself
+        let mut mft_record = vec![0; layout.record_size as usize];
+        device.read_at(layout.mft_cluster * layout.cluster_size, &mut mft_record)?;
+        let record = Record::parse(mft_record, MFT_RECORD)?;
+        let (mft_runs, mft_size) = match record.attributes.iter().find(|a| a.kind == DATA && a.name.is_empty()) {
+            Some(Attribute { value: Value::NonResident { runs, size, .. }, .. }) => (runs.clone(), *size),
+            _ => return Err(FileSystemError::Corrupted("bad $MFT")),
+        };
+        let mut filesystem = NTFSFileSystem {
+            this: Weak::new(),
+            device,
+            layout,
+            mft_runs,
+            mft_size,
+            upcase: Vec::new(),
+        };
+        let mft = filesystem.file(MFT_RECORD)?;
+        match mft.attribute(DATA, "") {
+            Some(Attribute { value: Value::NonResident { runs, .. }, .. }) => filesystem.mft_runs = runs.clone(),
+            _ => return Err(FileSystemError::Corrupted("bad $MFT")),
+        }
+
+        let upcase = filesystem.file(UPCASE_RECORD)?;
+        let upcase = upcase.attribute(DATA, "").ok_or(FileSystemError::Corrupted("no $UpCase"))?;
+        if upcase.size() != UPCASE_SIZE as u64 {
+            return Err(FileSystemError::Corrupted("bad $UpCase"));
+        }
+        let mut table = vec![0; UPCASE_SIZE];
+        filesystem.read_attribute(upcase, 0, &mut table)?;
+        filesystem.upcase = utf16(&table);
+
+        if !filesystem.file(ROOT_RECORD)?.is_directory() {
+            return Err(FileSystemError::Corrupted("root is not a directory"));
+        }
+        Ok(Arc::new_cyclic(|this| NTFSFileSystem { this: this.clone(), ..filesystem }))
+    }
+
+    fn this(&self) -> Arc<NTFSFileSystem> {
+        self.this.upgrade().expect("a mounted filesystem is alive")
+    }
+
+    fn record(&self, number: u64) -> FileSystemResult<Record> {
+        let record_size = self.layout.record_size;
+        let offset = number.checked_mul(record_size).filter(|&offset| offset + record_size <= self.mft_size);
+        let offset = offset.ok_or(FileSystemError::Corrupted("record past the end of the MFT"))?;
+        let mut data = vec![0; record_size as usize];
+        self.read_runs(&self.mft_runs, offset, &mut data)?;
+        Record::parse(data, number)
+    }
+
+    /// The file at record `number`, gathered from its base and extension records
+    fn file(&self, number: u64) -> FileSystemResult<File> {
+        let record = self.record(number)?;
+        if record.base != 0 {
+            return Err(FileSystemError::Corrupted("reference to an extension record"));
+        }
+        let mut attributes = record.attributes.clone();
+
+        // An attribute list names the records holding the rest of the attributes
+        if let Some(list) = attributes.iter().find(|attribute| attribute.kind == ATTRIBUTE_LIST) {
+            let mut data = vec![0; list.size().min(1 << 20) as usize];
+            self.read_attribute(list, 0, &mut data)?;
+            let mut extensions = BTreeSet::new();
+            let mut at = 0;
+            while at + 26 <= data.len() {
+                let length = read_u16(&data, at + 4) as usize;
+                if length < 26 {
+                    return Err(FileSystemError::Corrupted("bad attribute list"));
+                }
+                extensions.insert(read_u64(&data, at + 16) & RECORD_NUMBER_MASK);
+                at += length;
+            }
+            extensions.remove(&number);
+            for extension in extensions {
+                let extension = self.record(extension)?;
+                if extension.base != number {
+                    return Err(FileSystemError::Corrupted("extension record of another file"));
+                }
+                attributes.extend(extension.attributes);
+            }
+        }
+
+        // Join the parts of each non-resident attribute, in order, into the first
+        let mut joined: Vec<Attribute> = Vec::new();
+        attributes.sort_by_key(|attribute| match attribute.value {
+            Value::Resident(_) => 0,
+            Value::NonResident { first_vcn, .. } => first_vcn,
+        });
+        for attribute in attributes {
+            let first = joined.iter_mut().find(|other| {
+                other.kind == attribute.kind
+                    && other.name == attribute.name
+                    && matches!(other.value, Value::NonResident { .. })
+            });
+            match (first, attribute.value) {
+                (Some(first), Value::NonResident { first_vcn, runs: more, .. }) if first_vcn > 0 => {
+                    if let Value::NonResident { runs, .. } = &mut first.value {
+                        if runs.last().map_or(0, |run| run.vcn + run.length) != first_vcn {
+                            return Err(FileSystemError::Corrupted("attribute parts do not meet"));
+                        }
+                        runs.extend(more);
+                    }
+                }
+                (_, value) => joined.push(Attribute { value, ..attribute }),
+            }
+        }
+        Ok(File { number, record, attributes: joined })
+    }
+
+    /// The file a directory entry refers to, if the reference is not stale
+    fn referenced_file(&self, reference: u64) -> FileSystemResult<File> {
+        let file = self.file(reference & RECORD_NUMBER_MASK)?;
+        let sequence = (reference >> SEQUENCE_SHIFT) as u16;
+        if sequence != 0 && sequence != file.record.sequence {
+            return Err(FileSystemError::Corrupted("stale file reference"));
+        }
+        Ok(file)
+    }
+
+    /// Read the bytes at `offset` of the clusters mapped by `runs`. The runs come
+    /// from the disk, so their byte positions are computed with checked arithmetic.
+    fn read_runs(&self, runs: &[Run], offset: u64, buffer: &mut [u8]) -> FileSystemResult<()> {
+        let outside = FileSystemError::Corrupted("run outside the volume");
+        let cluster_size = self.layout.cluster_size;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset.checked_add(done as u64).ok_or(outside.clone())?;
+            let vcn = position / cluster_size;
+            let run = runs
+                .iter()
+                .find(|run| vcn >= run.vcn && vcn - run.vcn < run.length)
+                .ok_or(FileSystemError::Corrupted("attribute shorter than its size"))?;
+            // The run holds `vcn`, so its start is at or before `position`
+            let within = position - run.vcn * cluster_size;
+            let run_size = run.length.checked_mul(cluster_size).ok_or(outside.clone())?;
+            let count = (run_size - within).min((buffer.len() - done) as u64) as usize;
+            let part = &mut buffer[done..done + count];
+            match run.lcn {
+                None => part.fill(0),
+                Some(lcn) => {
+                    if lcn.checked_add(run.length).is_none_or(|end| end > self.layout.clusters) {
+                        return Err(outside);
+                    }
+                    let start = lcn.checked_mul(cluster_size).and_then(|start| start.checked_add(within));
+                    self.device.read_at(start.ok_or(outside.clone())?, part)?;
+                }
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Read the value of `attribute` at `offset`, returning how much there was
+    fn read_attribute(&self, attribute: &Attribute, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        if offset >= attribute.size() {
+            return Ok(0);
+        }
+        let length = buffer.len().min((attribute.size() - offset) as usize);
+        match &attribute.value {
+            Value::Resident(data) => buffer[..length].copy_from_slice(&data[offset as usize..offset as usize + length]),
+            Value::NonResident { runs, initialized, .. } => {
+                if attribute.flags & (ATTRIBUTE_COMPRESSED | ATTRIBUTE_ENCRYPTED) != 0 {
+                    return Err(FileSystemError::NotSupported);
+                }
+                let valid = length.min(initialized.saturating_sub(offset) as usize);
+                self.read_runs(runs, offset, &mut buffer[..valid])?;
+                buffer[valid..length].fill(0);
+            }
         }
+        Ok(length)
+    }
+
+    fn index<'a>(&self, directory: &'a File) -> FileSystemResult<Index<'a>> {
+        if !directory.is_directory() {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let root = directory.attribute(INDEX_ROOT, DIRECTORY_INDEX).ok_or(FileSystemError::Corrupted("no index"))?;
+        let root = root.resident()?;
+        if root.len() < 32 || read_u32(root, 0) != FILE_NAME {
+            return Err(FileSystemError::Corrupted("bad index root"));
+        }
+        let block_size = read_u32(root, 8) as u64;
+        if !block_size.is_power_of_two() || block_size < FIXUP_STRIDE as u64 || block_size > 1 << 20 {
+            return Err(FileSystemError::Corrupted("bad index block size"));
+        }
+        Ok(Index {
+            root,
+            allocation: directory.attribute(INDEX_ALLOCATION, DIRECTORY_INDEX),
+            block_size,
+        })
+    }
+
+    /// The entries of the index node at virtual cluster `vcn`. Blocks smaller than
+    /// a cluster are numbered in 512-byte units instead.
+    fn index_node(&self, index: &Index, vcn: u64) -> FileSystemResult<Vec<IndexEntry>> {
+        let allocation = index.allocation.ok_or(FileSystemError::Corrupted("index node without blocks"))?;
+        let unit = match index.block_size >= self.layout.cluster_size {
+            true => self.layout.cluster_size,
+            false => 512,
+        };
+        let offset = vcn.checked_mul(unit).ok_or(FileSystemError::Corrupted("bad index node"))?;
+        let mut block = vec![0; index.block_size as usize];
+        if self.read_attribute(allocation, offset, &mut block)? != block.len() {
+            return Err(FileSystemError::Corrupted("index node past the end of the index"));
+        }
+        apply_fixups(&mut block, INDEX_MAGIC)?;
+        if read_u64(&block, 16) != vcn {
+            return Err(FileSystemError::Corrupted("index node in the wrong place"));
+        }
+        parse_index_node(&block, 24)
+    }
+
+    fn upcase(&self, units: &[u16]) -> Vec<u16> {
+        units.iter().map(|&unit| self.upcase[unit as usize]).collect()
+    }
+
+    /// Find `name` in a directory by going down its index, whose entries sort by
+    /// their names in upper case
+    fn find(&self, directory: &File, name: &str) -> FileSystemResult<Option<u64>> {
+        let index = self.index(directory)?;
+        let key = self.upcase(&name.encode_utf16().collect::<Vec<_>>());
+        let mut entries = parse_index_node(index.root, 16)?;
+        for _ in 0..MAX_INDEX_DEPTH {
+            let mut next = None;
+            for entry in &entries {
+                let order = match &entry.key {
+                    None => Ordering::Less,
+                    Some(file_name) => key.cmp(&self.upcase(&file_name.units)),
+                };
+                match order {
+                    Ordering::Equal => return Ok(Some(entry.reference)),
+                    Ordering::Greater => continue,
+                    Ordering::Less => {
+                        next = entry.subnode;
+                        break;
+                    }
+                }
+            }
+            match next {
+                Some(vcn) => entries = self.index_node(&index, vcn)?,
+                None => return Ok(None),
+            }
+        }
+        Err(FileSystemError::Corrupted("index too deep"))
+    }
+
+    /// Every entry of a directory's index, in order
+    fn list(
+        &self,
+        index: &Index,
+        entries: Vec<IndexEntry>,
+        depth: usize,
+        all: &mut Vec<IndexEntry>,
+    ) -> FileSystemResult<()> {
+        if depth == MAX_INDEX_DEPTH {
+            return Err(FileSystemError::Corrupted("index too deep"));
+        }
+        for entry in entries {
+            if let Some(vcn) = entry.subnode {
+                self.list(index, self.index_node(index, vcn)?, depth + 1, all)?;
+            }
+            if entry.key.is_some() {
+                all.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, file: &File) -> FileSystemResult<Metadata> {
+        let information = file
+            .attribute(STANDARD_INFORMATION, "")
+            .ok_or(FileSystemError::Corrupted("no standard information"))?
+            .resident()?;
+        if information.len() < 48 {
+            return Err(FileSystemError::Corrupted("bad standard information"));
+        }
+        let (file_type, permissions) = match file.is_directory() {
+            true => (FileType::Directory, 0o755),
+            false => (FileType::Regular, 0o644),
+        };
+        let read_only = read_u32(information, 32) & FILE_ATTRIBUTE_READ_ONLY != 0;
+        let mut metadata =
+            Metadata::new(file.number, file_type, if read_only { permissions & !0o222 } else { permissions });
+
+        // A long name and its 8.3 alias may be separate names of one link
+        let mut links = 0;
+        for name in file.names() {
+            if name?.namespace != NAMESPACE_DOS {
+                links += 1;
+            }
+        }
+        metadata.links = links.max(1);
+        let contents = match file.is_directory() {
+            true => file.attribute(INDEX_ALLOCATION, DIRECTORY_INDEX),
+            false => file.attribute(DATA, ""),
+        };
+        if let Some(contents) = contents {
+            metadata.size = contents.size();
+            metadata.blocks = contents.clusters() * self.layout.cluster_size / 512;
+        }
+        metadata.block_size = self.layout.cluster_size as u32;
+        metadata.created = Some(decode_time(read_u64(information, 0)));
+        metadata.modified = decode_time(read_u64(information, 8));
+        metadata.changed = decode_time(read_u64(information, 16));
+        metadata.accessed = decode_time(read_u64(information, 24));
+        Ok(metadata)
+    }
+
+    /// Count the clear bits among the first `count` of a bitmap attribute
+    fn count_free(&self, bitmap: &Attribute, count: u64) -> FileSystemResult<u64> {
+        let mut bits = vec![0; count.div_ceil(8) as usize];
+        if self.read_attribute(bitmap, 0, &mut bits)? != bits.len() {
+            return Err(FileSystemError::Corrupted("bitmap too small"));
+        }
+        let used: u64 = bits.iter().map(|byte| byte.count_ones() as u64).sum();
+        let past_end = (bits.len() as u64 * 8 - count) as u32;
+        let used_past_end = (bits.last().copied().unwrap_or(0) as u32 >> (8 - past_end)).count_ones() as u64;
+        Ok(count - (used - used_past_end))
     }
 }
 
 impl FileSystem for NTFSFileSystem {
-    fn metadata(&self, path: &str) -> FileSystemResult<Metadata> {
-        // TODO: Implement metadata retrieval for NTFS file system
-        unimplemented!()
+    fn name(&self) -> &'static str {
+        "ntfs"
     }
 
-    fn open(&self, path: &str, options: &OpenOptions) -> FileSystemResult<Box<dyn ReadWriteSeek>> {
-        // TODO: Implement file open operation for NTFS file system
-        unimplemented!()
+    fn root(&self) -> FileSystemResult<Arc<dyn Inode>> {
+        Ok(Arc::new(NTFSInode { fs: self.this(), number: ROOT_RECORD }))
     }
 
-    fn create(&self, path: &str) -> FileSystemResult<Box<dyn ReadWriteSeek>> {
-        // TODO: Implement file creation operation for NTFS file system
-        unimplemented!()
+    fn stats(&self) -> FileSystemResult<FileSystemStats> {
+        let bitmap = self.file(BITMAP_RECORD)?;
+        let bitmap = bitmap.attribute(DATA, "").ok_or(FileSystemError::Corrupted("no $Bitmap"))?;
+        let records = self.mft_size / self.layout.record_size;
+        let mft = self.file(MFT_RECORD)?;
+        let mft_bitmap = mft.attribute(BITMAP, "").ok_or(FileSystemError::Corrupted("no MFT bitmap"))?;
+        Ok(FileSystemStats {
+            block_size: self.layout.cluster_size as u32,
+            blocks: self.layout.clusters,
+            free_blocks: self.count_free(bitmap, self.layout.clusters)?,
+            inodes: records,
+            free_inodes: self.count_free(mft_bitmap, records)?,
+            max_name_len: MAX_NAME_LEN as u32,
+        })
     }
 
-    fn remove(&self, path: &str) -> FileSystemResult<()> {
-        // TODO: Implement file removal operation for NTFS file system
-        unimplemented!()
+    fn read_only(&self) -> bool {
+        true
     }
+}
+
+/// A file or directory of an NTFS volume, by its record in the MFT
+pub struct NTFSInode {
+    fs: Arc<NTFSFileSystem>,
+    number: u64,
+}
 
-    fn create_dir(&self, path: &str) -> FileSystemResult<()> {
-        // TODO: Implement directory creation operation for NTFS file system
-        unimplemented!()
+impl NTFSInode {
+    fn file(&self) -> FileSystemResult<File> {
+        self.fs.file(self.number)
+    }
+}
+
+impl Inode for NTFSInode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        self.fs.metadata(&self.file()?)
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        let file = self.file()?;
+        if file.is_directory() {
+            return Err(FileSystemError::IsADirectory);
+        }
+        match file.attribute(DATA, "") {
+            Some(data) => self.fs.read_attribute(data, offset, buffer),
+            None => Ok(0),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(FileSystemError::NameTooLong);
+        }
+        let reference = self.fs.find(&self.file()?, name)?.ok_or(FileSystemError::NotFound)?;
+        let file = self.fs.referenced_file(reference)?;
+        Ok(Arc::new(NTFSInode { fs: self.fs.clone(), number: file.number }))
     }
 
-    fn remove_dir(&self, path: &str) -> FileSystemResult<()> {
-        // TODO: Implement directory removal operation for NTFS file system
-        unimplemented!()
+    fn read_directory(&self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        let file = self.file()?;
+        let index = self.fs.index(&file)?;
+        let mut entries = Vec::new();
+        self.fs.list(&index, parse_index_node(index.root, 16)?, 0, &mut entries)?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| {
+                let inode: InodeId = entry.reference & RECORD_NUMBER_MASK;
+                let key = entry.key?;
+                // 8.3 aliases repeat a name, and the metadata files are not for listing
+                if key.namespace == NAMESPACE_DOS || inode < FIRST_USER_RECORD {
+                    return None;
+                }
+                Some(DirectoryEntry {
+                    name: String::from_utf16_lossy(&key.units),
+                    inode,
+                    file_type: match key.flags & FILE_NAME_DIRECTORY {
+                        0 => FileType::Regular,
+                        _ => FileType::Directory,
+                    },
+                })
+            })
+            .collect())
     }
 }
@@ -0,0 +1,416 @@
+//! procfs
+//!
+//! A synthetic filesystem, mounted at `/proc`, that shows kernel state as text files.
+//! Nothing is stored: each read formats the process table, the memory and interrupt
+//! counters, the mount table or the sockets afresh, so a file read in several pieces
+//! may mix two snapshots, as on Linux. Every process has a directory named by its ID
+//! with `status`, `maps`, `cmdline` and an `fd` directory holding a symlink per open
+//! descriptor to the file behind it; `self` links to the directory of the process
+//! that looks. Nothing can be written.
+
+use crate::fs::vfs::{
+    DirectoryEntry, FileSystem, FileSystemError, FileSystemResult, FileType, Inode, InodeId, Metadata, Vfs,
+};
+use crate::kernel::interrupts;
+use crate::mm::allocator;
+use crate::net::interface;
+use crate::net::socket_table::{self, Protocol};
+use crate::process::process::{self, Process, ProcessId, ProcessState};
+use crate::util::time;
+use core::any::Any;
+use std::sync::{Arc, Weak};
+
+/// Files that do not belong to a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KernelFile {
+    Interrupts,
+    Meminfo,
+    Mounts,
+    Uptime,
+    NetDev,
+    NetTcp,
+    NetUdp,
+}
+
+const ROOT_FILES: [(&str, KernelFile); 4] = [
+    ("interrupts", KernelFile::Interrupts),
+    ("meminfo", KernelFile::Meminfo),
+    ("mounts", KernelFile::Mounts),
+    ("uptime", KernelFile::Uptime),
+];
+
+const NET_FILES: [(&str, KernelFile); 3] =
+    [("dev", KernelFile::NetDev), ("tcp", KernelFile::NetTcp), ("udp", KernelFile::NetUdp)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProcessFile {
+    Cmdline,
+    Maps,
+    Status,
+}
+
+const PROCESS_FILES: [(&str, ProcessFile); 3] =
+    [("cmdline", ProcessFile::Cmdline), ("maps", ProcessFile::Maps), ("status", ProcessFile::Status)];
+
+/// What an inode shows; a process's inodes vanish with the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Node {
+    Root,
+    /// `self`
+    SelfLink,
+    /// `net`
+    Net,
+    Kernel(KernelFile),
+    Process(ProcessId),
+    ProcessFile(ProcessId, ProcessFile),
+    /// `fd` of a process
+    Descriptors(ProcessId),
+    Descriptor(ProcessId, u32),
+}
+
+impl Node {
+    fn id(&self) -> InodeId {
+        // Each process gets the numbers from (ID + 1) * 2^32, its descriptors from 32 up
+        let process_base = |id: ProcessId| (id as u64 + 1) << 32;
+        match *self {
+            Node::Root => 1,
+            Node::SelfLink => 2,
+            Node::Net => 3,
+            Node::Kernel(file) => 16 + file as u64,
+            Node::Process(id) => process_base(id),
+            Node::ProcessFile(id, file) => process_base(id) + 1 + file as u64,
+            Node::Descriptors(id) => process_base(id) + 16,
+            Node::Descriptor(id, fd) => process_base(id) + 32 + fd as u64,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::Root | Node::Net | Node::Process(_) | Node::Descriptors(_) => FileType::Directory,
+            Node::SelfLink | Node::Descriptor(..) => FileType::Symlink,
+            Node::Kernel(_) | Node::ProcessFile(..) => FileType::Regular,
+        }
+    }
+
+    fn entry(&self, name: &str) -> DirectoryEntry {
+        DirectoryEntry {
+            name: String::from(name),
+            inode: self.id(),
+            file_type: self.file_type(),
+        }
+    }
+}
+
+pub struct ProcFs {
+    this: Weak<ProcFs>,
+    /// Whose mount table `mounts` lists and whose current process `self` points to
+    vfs: &'static Vfs,
+}
+
+impl ProcFs {
+    pub fn new(vfs: &'static Vfs) -> Arc<ProcFs> {
+        Arc::new_cyclic(|this| ProcFs { this: this.clone(), vfs })
+    }
+
+    fn this(&self) -> Arc<ProcFs> {
+        self.this.upgrade().expect("a mounted filesystem is alive")
+    }
+
+    fn inode(&self, node: Node) -> Arc<dyn Inode> {
+        Arc::new(ProcInode { fs: self.this(), node })
+    }
+
+    fn kernel_file(&self, file: KernelFile) -> String {
+        match file {
+            KernelFile::Interrupts => interrupts_file(),
+            KernelFile::Meminfo => meminfo_file(),
+            KernelFile::Mounts => self.mounts_file(),
+            KernelFile::Uptime => {
+                let uptime = interrupts::uptime();
+                format!("{}.{:02}\n", uptime.as_secs(), uptime.subsec_millis() / 10)
+            }
+            KernelFile::NetDev => net_dev_file(),
+            KernelFile::NetTcp => sockets_file(Protocol::Tcp),
+            KernelFile::NetUdp => sockets_file(Protocol::Udp),
+        }
+    }
+
+    /// One line per mount, as in fstab: source, path, type, options and two zeros
+    fn mounts_file(&self) -> String {
+        self.vfs
+            .mounts()
+            .iter()
+            .map(|mount| {
+                let options = if mount.options.read_only { "ro" } else { "rw" };
+                format!("{} {} {} {} 0 0\n", escape(&mount.source), escape(&mount.path), mount.filesystem, options)
+            })
+            .collect()
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> FileSystemResult<Arc<dyn Inode>> {
+        Ok(self.inode(Node::Root))
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Escape the characters that separate fields and lines of `mounts`, in octal as Linux does
+fn escape(field: &str) -> String {
+    let mut escaped = String::new();
+    for c in field.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => escaped.push_str(&format!("\\{:03o}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn interrupts_file() -> String {
+    let mut text = format!("{:5}{:>10}\n", "", "CPU0");
+    for interrupt in interrupts::interrupt_counts() {
+        text.push_str(&format!("{:>3}: {:>10}  {}\n", interrupt.vector, interrupt.count, interrupt.name));
+    }
+    text
+}
+
+fn meminfo_file() -> String {
+    let stats = allocator::memory_stats();
+    [("MemTotal:", stats.total), ("MemFree:", stats.free()), ("MemUsed:", stats.used)]
+        .iter()
+        .map(|(label, bytes)| format!("{:<15}{:>9} kB\n", label, bytes / 1024))
+        .collect()
+}
+
+fn net_dev_file() -> String {
+    let mut text = format!(
+        "{:>6} {:>12} {:>10} {:>8} {:>12} {:>10} {:>8}\n",
+        "face", "rx bytes", "packets", "drop", "tx bytes", "packets", "errs"
+    );
+    for (name, interface) in interface::interfaces() {
+        let stats = interface.stats();
+        text.push_str(&format!(
+            "{:>6} {:>12} {:>10} {:>8} {:>12} {:>10} {:>8}\n",
+            format!("{}:", name),
+            stats.rx_bytes,
+            stats.rx_packets,
+            stats.rx_dropped,
+            stats.tx_bytes,
+            stats.tx_packets,
+            stats.tx_errors
+        ));
+    }
+    text
+}
+
+fn sockets_file(protocol: Protocol) -> String {
+    let row = |local: &str, remote: &str, state: &str, send: &str, receive: &str, owner: &str| {
+        format!("{:<22} {:<22} {:<12} {:>6} {:>6} {:>5}\n", local, remote, state, send, receive, owner)
+    };
+    let mut text = row("Local Address", "Remote Address", "State", "Send-Q", "Recv-Q", "PID");
+    for socket in socket_table::sockets(protocol) {
+        let remote = socket.remote.map_or(String::from("*:*"), |remote| remote.to_string());
+        text.push_str(&row(
+            &socket.local.to_string(),
+            &remote,
+            socket.state,
+            &socket.send_queue.to_string(),
+            &socket.receive_queue.to_string(),
+            &socket.owner.to_string(),
+        ));
+    }
+    text
+}
+
+fn process_file(process: &Process, file: ProcessFile) -> Vec<u8> {
+    match file {
+        // The arguments, each followed by a NUL
+        ProcessFile::Cmdline => process.arguments.iter().flat_map(|argument| argument.bytes().chain([0])).collect(),
+        ProcessFile::Maps => {
+            let mut text = String::new();
+            for region in &process.regions {
+                let flag = |set: bool, flag: char| if set { flag } else { '-' };
+                let line = format!(
+                    "{:08x}-{:08x} {}{}{} {}",
+                    region.start,
+                    region.end,
+                    flag(region.read, 'r'),
+                    flag(region.write, 'w'),
+                    flag(region.execute, 'x'),
+                    region.name
+                );
+                text.push_str(line.trim_end());
+                text.push('\n');
+            }
+            text.into_bytes()
+        }
+        ProcessFile::Status => {
+            let state = match process.state {
+                ProcessState::Running => "R (running)",
+                ProcessState::Ready => "R (ready)",
+                ProcessState::Blocked => "S (sleeping)",
+                ProcessState::Zombie => "Z (zombie)",
+            };
+            format!(
+                "Name:\t{}\nState:\t{}\nPid:\t{}\nPPid:\t{}\nVmSize:\t{:>8} kB\nFDSize:\t{}\n",
+                process.name,
+                state,
+                process.id,
+                process.parent,
+                process.virtual_size() / 1024,
+                process.files.len()
+            )
+            .into_bytes()
+        }
+    }
+}
+
+/// The process with ID `id`, which fails lookups once it has been reaped
+fn find_process(id: ProcessId) -> FileSystemResult<Process> {
+    process::find_process(id).ok_or(FileSystemError::NotFound)
+}
+
+fn find_file<T: Copy>(files: &[(&str, T)], name: &str) -> Option<T> {
+    files.iter().find(|(file_name, _)| *file_name == name).map(|&(_, file)| file)
+}
+
+/// A process ID or descriptor number as a name, without the leading zeros or sign `parse` would accept
+fn parse_number(name: &str) -> Option<u32> {
+    let number: u32 = name.parse().ok()?;
+    (number.to_string() == name).then_some(number)
+}
+
+pub struct ProcInode {
+    fs: Arc<ProcFs>,
+    node: Node,
+}
+
+impl ProcInode {
+    /// The text of a regular file, generated now
+    fn contents(&self) -> FileSystemResult<Vec<u8>> {
+        match self.node {
+            Node::Kernel(file) => Ok(self.fs.kernel_file(file).into_bytes()),
+            Node::ProcessFile(id, file) => Ok(process_file(&find_process(id)?, file)),
+            _ => Err(FileSystemError::IsADirectory),
+        }
+    }
+}
+
+impl Inode for ProcInode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        if let Node::Process(id) | Node::Descriptors(id) = self.node {
+            find_process(id)?;
+        }
+        let mut metadata = match self.node.file_type() {
+            FileType::Directory => Metadata::new(self.node.id(), FileType::Directory, 0o555),
+            FileType::Symlink => Metadata::new(self.node.id(), FileType::Symlink, 0o777),
+            file_type => Metadata::new(self.node.id(), file_type, 0o444),
+        };
+        metadata.size = match self.node.file_type() {
+            FileType::Directory => 0,
+            FileType::Symlink => self.read_link()?.len() as u64,
+            _ => self.contents()?.len() as u64,
+        };
+        metadata.links = match self.node {
+            Node::Root => 3 + process::processes().len() as u32,
+            // `fd`
+            Node::Process(_) => 3,
+            Node::Net | Node::Descriptors(_) => 2,
+            _ => 1,
+        };
+        let now = time::current_time();
+        metadata.accessed = now;
+        metadata.modified = now;
+        metadata.changed = now;
+        Ok(metadata)
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        if self.node.file_type() == FileType::Symlink {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let contents = self.contents()?;
+        if offset >= contents.len() as u64 {
+            return Ok(0);
+        }
+        let length = buffer.len().min(contents.len() - offset as usize);
+        buffer[..length].copy_from_slice(&contents[offset as usize..offset as usize + length]);
+        Ok(length)
+    }
+
+    fn lookup(&self, name: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        let node = match self.node {
+            Node::Root => match name {
+                "self" => Some(Node::SelfLink),
+                "net" => Some(Node::Net),
+                name => find_file(&ROOT_FILES, name).map(Node::Kernel).or_else(|| {
+                    parse_number(name).filter(|&id| process::find_process(id).is_some()).map(Node::Process)
+                }),
+            },
+            Node::Net => find_file(&NET_FILES, name).map(Node::Kernel),
+            Node::Process(id) => {
+                find_process(id)?;
+                match name {
+                    "fd" => Some(Node::Descriptors(id)),
+                    name => find_file(&PROCESS_FILES, name).map(|file| Node::ProcessFile(id, file)),
+                }
+            }
+            Node::Descriptors(id) => {
+                let files = find_process(id)?.files;
+                parse_number(name).filter(|fd| files.contains_key(fd)).map(|fd| Node::Descriptor(id, fd))
+            }
+            _ => return Err(FileSystemError::NotADirectory),
+        };
+        Ok(self.fs.inode(node.ok_or(FileSystemError::NotFound)?))
+    }
+
+    fn read_directory(&self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        let entries = match self.node {
+            Node::Root => {
+                let mut entries: Vec<DirectoryEntry> =
+                    ROOT_FILES.iter().map(|&(name, file)| Node::Kernel(file).entry(name)).collect();
+                entries.push(Node::Net.entry("net"));
+                entries.push(Node::SelfLink.entry("self"));
+                for process in process::processes() {
+                    entries.push(Node::Process(process.id).entry(&process.id.to_string()));
+                }
+                entries
+            }
+            Node::Net => NET_FILES.iter().map(|&(name, file)| Node::Kernel(file).entry(name)).collect(),
+            Node::Process(id) => {
+                find_process(id)?;
+                let mut entries: Vec<DirectoryEntry> =
+                    PROCESS_FILES.iter().map(|&(name, file)| Node::ProcessFile(id, file).entry(name)).collect();
+                entries.push(Node::Descriptors(id).entry("fd"));
+                entries
+            }
+            Node::Descriptors(id) => find_process(id)?
+                .files
+                .keys()
+                .map(|&fd| Node::Descriptor(id, fd).entry(&fd.to_string()))
+                .collect(),
+            _ => return Err(FileSystemError::NotADirectory),
+        };
+        Ok(entries)
+    }
+
+    fn read_link(&self) -> FileSystemResult<String> {
+        match self.node {
+            Node::SelfLink => Ok(self.fs.vfs.current_process().to_string()),
+            Node::Descriptor(id, fd) => find_process(id)?.files.get(&fd).cloned().ok_or(FileSystemError::NotFound),
+            _ => Err(FileSystemError::InvalidArgument),
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! tmpfs
+//!
+//! A filesystem kept entirely in memory, used for `/tmp` and as the root until disks
+//! are up. Inodes are reference counted, so an unlinked file stays readable through
+//! the handles still open on it and its memory is freed when the last one closes.
+
+use crate::fs::vfs::{
+    check_name, DirectoryEntry, FileSystem, FileSystemError, FileSystemResult, FileSystemStats, FileType, Inode,
+    InodeId, Metadata, SetAttributes, MAX_NAME_LEN, PERMISSION_MASK,
+};
+use crate::util::time;
+use core::any::Any;
+use spin::Mutex;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+
+/// Block size reported in `stat` and `statfs`; memory is not allocated in blocks
+const TMPFS_BLOCK_SIZE: u64 = 4096;
+
+/// Memory and inodes in use, against the optional size limit
+struct Usage {
+    bytes: u64,
+    inodes: u64,
+    limit: Option<u64>,
+    // Inode numbers only grow, so a number is never reused while a handle may refer to it
+    last_inode: InodeId,
+}
+
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        TmpFs::create(None)
+    }
+
+    /// A tmpfs whose files may hold at most `bytes` in total
+    pub fn with_size_limit(bytes: u64) -> TmpFs {
+        TmpFs::create(Some(bytes))
+    }
+
+    fn create(limit: Option<u64>) -> TmpFs {
+        let usage = Arc::new(Mutex::new(Usage {
+            bytes: 0,
+            inodes: 0,
+            limit,
+            last_inode: 1,
+        }));
+        let root = TmpInode::allocate(&usage, 1, FileType::Directory, 0o755, None);
+        TmpFs { root, usage }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        TmpFs::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> FileSystemResult<Arc<dyn Inode>> {
+        Ok(self.root.clone())
+    }
+
+    fn stats(&self) -> FileSystemResult<FileSystemStats> {
+        let usage = self.usage.lock();
+        let used_blocks = usage.bytes.div_ceil(TMPFS_BLOCK_SIZE);
+        let blocks = usage.limit.map_or(used_blocks, |limit| limit / TMPFS_BLOCK_SIZE);
+        Ok(FileSystemStats {
+            block_size: TMPFS_BLOCK_SIZE as u32,
+            blocks,
+            free_blocks: blocks.saturating_sub(used_blocks),
+            inodes: usage.inodes,
+            free_inodes: 0,
+            max_name_len: MAX_NAME_LEN as u32,
+        })
+    }
+}
+
+enum Content {
+    File(Vec<u8>),
+    Directory {
+        entries: BTreeMap<String, Arc<TmpInode>>,
+        parent: Weak<TmpInode>,
+    },
+    Symlink(String),
+    // Device files, FIFOs and sockets have no content of their own
+    Special,
+}
+
+struct State {
+    metadata: Metadata,
+    content: Content,
+}
+
+pub struct TmpInode {
+    // A reference to the inode itself, to add it to directories through `&self`
+    this: Weak<TmpInode>,
+    usage: Arc<Mutex<Usage>>,
+    state: Mutex<State>,
+}
+
+impl TmpInode {
+    fn allocate(
+        usage: &Arc<Mutex<Usage>>,
+        id: InodeId,
+        file_type: FileType,
+        permissions: u16,
+        parent: Option<&Arc<TmpInode>>,
+    ) -> Arc<TmpInode> {
+        let now = time::current_time();
+        let mut metadata = Metadata::new(id, file_type, permissions);
+        metadata.block_size = TMPFS_BLOCK_SIZE as u32;
+        metadata.accessed = now;
+        metadata.modified = now;
+        metadata.changed = now;
+        metadata.created = Some(now);
+        let content = match file_type {
+            FileType::Regular => Content::File(Vec::new()),
+            FileType::Directory => {
+                // A directory is linked from its parent and from its own `.`
+                metadata.links = 2;
+                Content::Directory {
+                    entries: BTreeMap::new(),
+                    parent: parent.map_or_else(Weak::new, Arc::downgrade),
+                }
+            }
+            FileType::Symlink => Content::Symlink(String::new()),
+            _ => Content::Special,
+        };
+        usage.lock().inodes += 1;
+        Arc::new_cyclic(|this| TmpInode {
+            this: this.clone(),
+            usage: usage.clone(),
+            state: Mutex::new(State { metadata, content }),
+        })
+    }
+
+    fn this(&self) -> Arc<TmpInode> {
+        self.this.upgrade().expect("an inode in use is alive")
+    }
+
+    fn is_directory(&self) -> bool {
+        matches!(self.state.lock().content, Content::Directory { .. })
+    }
+
+    /// The tmpfs inode behind `inode`, if it is on the same filesystem
+    fn same_filesystem(&self, inode: &Arc<dyn Inode>) -> FileSystemResult<Arc<TmpInode>> {
+        match inode.as_any().downcast_ref::<TmpInode>() {
+            Some(inode) if Arc::ptr_eq(&inode.usage, &self.usage) => Ok(inode.this()),
+            _ => Err(FileSystemError::CrossDevice),
+        }
+    }
+
+    /// The entry `name` of this directory
+    fn entry(&self, name: &str) -> FileSystemResult<Option<Arc<TmpInode>>> {
+        match &self.state.lock().content {
+            Content::Directory { entries, .. } => Ok(entries.get(name).cloned()),
+            _ => Err(FileSystemError::NotADirectory),
+        }
+    }
+
+    fn parent(&self) -> Option<Arc<TmpInode>> {
+        match &self.state.lock().content {
+            Content::Directory { parent, .. } => parent.upgrade(),
+            _ => None,
+        }
+    }
+
+    // Add an entry, updating the directory's times and, for subdirectories, its link count
+    fn insert(&self, name: &str, inode: Arc<TmpInode>) -> FileSystemResult<()> {
+        let directory = inode.is_directory();
+        let mut state = self.state.lock();
+        let State { metadata, content } = &mut *state;
+        let Content::Directory { entries, .. } = content else {
+            return Err(FileSystemError::NotADirectory);
+        };
+        if entries.contains_key(name) {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        entries.insert(String::from(name), inode);
+        if directory {
+            metadata.links += 1;
+        }
+        touch(metadata);
+        Ok(())
+    }
+
+    // Remove an entry, undoing what `insert` did
+    fn remove(&self, name: &str) -> FileSystemResult<Arc<TmpInode>> {
+        let mut state = self.state.lock();
+        let State { metadata, content } = &mut *state;
+        let Content::Directory { entries, .. } = content else {
+            return Err(FileSystemError::NotADirectory);
+        };
+        let inode = entries.remove(name).ok_or(FileSystemError::NotFound)?;
+        if inode.is_directory() {
+            metadata.links -= 1;
+        }
+        touch(metadata);
+        Ok(inode)
+    }
+
+    // Note that the inode lost a link
+    fn unlinked(&self) {
+        let mut state = self.state.lock();
+        state.metadata.links = state.metadata.links.saturating_sub(1);
+        state.metadata.changed = time::current_time();
+    }
+
+    fn create_entry(&self, name: &str, file_type: FileType, permissions: u16) -> FileSystemResult<Arc<TmpInode>> {
+        check_name(name)?;
+        if self.entry(name)?.is_some() {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        let id = {
+            let mut usage = self.usage.lock();
+            usage.last_inode += 1;
+            usage.last_inode
+        };
+        let this = self.this();
+        let inode = TmpInode::allocate(&self.usage, id, file_type, permissions, Some(&this));
+        self.insert(name, inode.clone())?;
+        Ok(inode)
+    }
+
+    /// Grow the memory in use by up to `bytes`; returns how much the limit allows
+    fn reserve(&self, bytes: u64) -> u64 {
+        let mut usage = self.usage.lock();
+        let granted = match usage.limit {
+            Some(limit) => bytes.min(limit.saturating_sub(usage.bytes)),
+            None => bytes,
+        };
+        usage.bytes += granted;
+        granted
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut usage = self.usage.lock();
+        usage.bytes = usage.bytes.saturating_sub(bytes);
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        let bytes = match &self.state.get_mut().content {
+            Content::File(data) => data.len() as u64,
+            _ => 0,
+        };
+        let mut usage = self.usage.lock();
+        usage.bytes = usage.bytes.saturating_sub(bytes);
+        usage.inodes = usage.inodes.saturating_sub(1);
+    }
+}
+
+// Update the modification and change times
+fn touch(metadata: &mut Metadata) {
+    let now = time::current_time();
+    metadata.modified = now;
+    metadata.changed = now;
+}
+
+impl Inode for TmpInode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metadata(&self) -> FileSystemResult<Metadata> {
+        let state = self.state.lock();
+        let mut metadata = state.metadata.clone();
+        metadata.size = match &state.content {
+            Content::File(data) => data.len() as u64,
+            Content::Directory { entries, .. } => entries.len() as u64,
+            Content::Symlink(target) => target.len() as u64,
+            Content::Special => 0,
+        };
+        metadata.blocks = match &state.content {
+            Content::File(_) => metadata.size.div_ceil(512),
+            _ => 0,
+        };
+        Ok(metadata)
+    }
+
+    fn set_attributes(&self, changes: &SetAttributes) -> FileSystemResult<()> {
+        let mut state = self.state.lock();
+        let metadata = &mut state.metadata;
+        if let Some(permissions) = changes.permissions {
+            metadata.permissions = permissions & PERMISSION_MASK;
+        }
+        if let Some(uid) = changes.uid {
+            metadata.uid = uid;
+        }
+        if let Some(gid) = changes.gid {
+            metadata.gid = gid;
+        }
+        if let Some(accessed) = changes.accessed {
+            metadata.accessed = accessed;
+        }
+        if let Some(modified) = changes.modified {
+            metadata.modified = modified;
+        }
+        metadata.changed = time::current_time();
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        let mut state = self.state.lock();
+        let Content::File(data) = &state.content else {
+            return Err(match state.content {
+                Content::Directory { .. } => FileSystemError::IsADirectory,
+                _ => FileSystemError::InvalidArgument,
+            });
+        };
+        let start = offset.min(data.len() as u64) as usize;
+        let count = buffer.len().min(data.len() - start);
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        state.metadata.accessed = time::current_time();
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FileSystemResult<usize> {
+        let mut state = self.state.lock();
+        let State { metadata, content } = &mut *state;
+        let Content::File(data) = content else {
+            return Err(match content {
+                Content::Directory { .. } => FileSystemError::IsADirectory,
+                _ => FileSystemError::InvalidArgument,
+            });
+        };
+        let end = offset.checked_add(buffer.len() as u64).ok_or(FileSystemError::InvalidArgument)?;
+        let growth = end.saturating_sub(data.len() as u64);
+        let granted = self.reserve(growth);
+        // Near the size limit, write what still fits
+        let count = buffer.len() - (growth - granted).min(buffer.len() as u64) as usize;
+        if count == 0 && !buffer.is_empty() {
+            self.release(granted);
+            return Err(FileSystemError::NoSpace);
+        }
+        let end = offset as usize + count;
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(&buffer[..count]);
+        touch(metadata);
+        Ok(count)
+    }
+
+    fn truncate(&self, size: u64) -> FileSystemResult<()> {
+        let mut state = self.state.lock();
+        let State { metadata, content } = &mut *state;
+        let Content::File(data) = content else {
+            return Err(FileSystemError::IsADirectory);
+        };
+        let length = data.len() as u64;
+        if size > length {
+            let granted = self.reserve(size - length);
+            if granted < size - length {
+                self.release(granted);
+                return Err(FileSystemError::NoSpace);
+            }
+        } else {
+            self.release(length - size);
+        }
+        data.resize(size as usize, 0);
+        data.shrink_to_fit();
+        touch(metadata);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        match self.entry(name)? {
+            Some(inode) => Ok(inode),
+            None => Err(FileSystemError::NotFound),
+        }
+    }
+
+    fn read_directory(&self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        let entries: Vec<(String, Arc<TmpInode>)> = match &self.state.lock().content {
+            Content::Directory { entries, .. } => entries.iter().map(|(name, inode)| (name.clone(), inode.clone())).collect(),
+            _ => return Err(FileSystemError::NotADirectory),
+        };
+        entries
+            .into_iter()
+            .map(|(name, inode)| {
+                let state = inode.state.lock();
+                Ok(DirectoryEntry {
+                    name,
+                    inode: state.metadata.inode,
+                    file_type: state.metadata.file_type,
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, name: &str, file_type: FileType, permissions: u16) -> FileSystemResult<Arc<dyn Inode>> {
+        if file_type == FileType::Symlink {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        Ok(self.create_entry(name, file_type, permissions)?)
+    }
+
+    fn link(&self, name: &str, target: &Arc<dyn Inode>) -> FileSystemResult<()> {
+        check_name(name)?;
+        let target = self.same_filesystem(target)?;
+        if target.is_directory() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        self.insert(name, target.clone())?;
+        let mut state = target.state.lock();
+        state.metadata.links += 1;
+        state.metadata.changed = time::current_time();
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> FileSystemResult<()> {
+        match self.entry(name)? {
+            Some(inode) if inode.is_directory() => Err(FileSystemError::IsADirectory),
+            Some(_) => {
+                self.remove(name)?.unlinked();
+                Ok(())
+            }
+            None => Err(FileSystemError::NotFound),
+        }
+    }
+
+    fn remove_directory(&self, name: &str) -> FileSystemResult<()> {
+        let inode = self.entry(name)?.ok_or(FileSystemError::NotFound)?;
+        match &inode.state.lock().content {
+            Content::Directory { entries, .. } if !entries.is_empty() => return Err(FileSystemError::DirectoryNotEmpty),
+            Content::Directory { .. } => {}
+            _ => return Err(FileSystemError::NotADirectory),
+        }
+        self.remove(name)?;
+        let mut state = inode.state.lock();
+        state.metadata.links = 0;
+        state.metadata.changed = time::current_time();
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> FileSystemResult<()> {
+        check_name(new_name)?;
+        let new_parent = self.same_filesystem(new_parent)?;
+        let inode = self.entry(old_name)?.ok_or(FileSystemError::NotFound)?;
+        let directory = inode.is_directory();
+
+        // A directory cannot move into itself or its own subdirectories
+        if directory {
+            let mut ancestor = Some(new_parent.clone());
+            while let Some(current) = ancestor {
+                if Arc::ptr_eq(&current, &inode) {
+                    return Err(FileSystemError::InvalidArgument);
+                }
+                ancestor = current.parent();
+            }
+        }
+
+        // Whatever is at the new name is replaced, if it is compatible
+        if let Some(existing) = new_parent.entry(new_name)? {
+            if Arc::ptr_eq(&existing, &inode) {
+                return Ok(());
+            }
+            match (directory, existing.is_directory()) {
+                (true, true) => new_parent.remove_directory(new_name)?,
+                (true, false) => return Err(FileSystemError::NotADirectory),
+                (false, true) => return Err(FileSystemError::IsADirectory),
+                (false, false) => new_parent.unlink(new_name)?,
+            }
+        }
+
+        self.remove(old_name)?;
+        new_parent.insert(new_name, inode.clone())?;
+        let mut state = inode.state.lock();
+        if let Content::Directory { parent, .. } = &mut state.content {
+            *parent = Arc::downgrade(&new_parent);
+        }
+        state.metadata.changed = time::current_time();
+        Ok(())
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        if target.is_empty() {
+            return Err(FileSystemError::NotFound);
+        }
+        let inode = self.create_entry(name, FileType::Symlink, 0o777)?;
+        inode.state.lock().content = Content::Symlink(String::from(target));
+        Ok(inode)
+    }
+
+    fn read_link(&self) -> FileSystemResult<String> {
+        match &self.state.lock().content {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(FileSystemError::InvalidArgument),
+        }
+    }
+}
@@ -1,42 +1,1009 @@
-use crate::filesystem::{FileSystem, DirectoryEntry, FileSystemError};
+//! Virtual filesystem
+//!
+//! Every filesystem driver implements the same three traits: a `FileSystem` is a
+//! volume with a root directory, an `Inode` is a file, directory or symlink on it,
+//! and a `FileHandle` is an open file with its own position. The `Vfs` keeps the
+//! mount table and resolves paths through it, so callers never deal with a
+//! particular driver.
 
-#[derive(Debug)]
+use crate::drivers::device::DeviceError;
+use crate::process::process::ProcessId;
+use crate::storage::block;
+use core::any::Any;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::SeekFrom;
+use std::sync::Arc;
+
+/// Identifies an inode within its filesystem
+pub type InodeId = u64;
+
+pub type FileSystemResult<T> = Result<T, FileSystemError>;
+
+/// Longest name of a directory entry, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+
+// File type bits of a Unix mode
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_SOCKET: u32 = 0o140000;
+const MODE_SYMLINK: u32 = 0o120000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_BLOCK_DEVICE: u32 = 0o060000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_CHAR_DEVICE: u32 = 0o020000;
+const MODE_FIFO: u32 = 0o010000;
+
+/// Permission bits of a mode, including setuid, setgid and sticky
+pub const PERMISSION_MASK: u16 = 0o7777;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileSystemError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    PermissionDenied,
+    ReadOnly,
+    InvalidPath,
+    NameTooLong,
+    NoSpace,
+    /// Too many symlinks were followed while resolving a path
+    TooManyLinks,
+    /// The operation would link or move a file to another filesystem
+    CrossDevice,
+    Busy,
+    InvalidArgument,
+    NotSupported,
+    /// The on-disk structures are inconsistent
+    Corrupted(&'static str),
+    Device(DeviceError),
+    Io(std::io::ErrorKind),
+}
+
+impl From<DeviceError> for FileSystemError {
+    fn from(error: DeviceError) -> Self {
+        FileSystemError::Device(error)
+    }
+}
+
+impl From<block::Error> for FileSystemError {
+    fn from(error: block::Error) -> Self {
+        match error {
+            block::Error::ReadOnly => FileSystemError::ReadOnly,
+            block::Error::InvalidBlockId | block::Error::InvalidBufferSize => {
+                FileSystemError::Io(std::io::ErrorKind::InvalidInput)
+            }
+            block::Error::Io(error) => FileSystemError::Io(error.kind()),
+            block::Error::Device(error) => FileSystemError::Device(error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+impl FileType {
+    /// The type from the file type bits of a Unix mode
+    pub fn from_mode(mode: u32) -> Option<FileType> {
+        match mode & MODE_TYPE_MASK {
+            MODE_REGULAR => Some(FileType::Regular),
+            MODE_DIRECTORY => Some(FileType::Directory),
+            MODE_SYMLINK => Some(FileType::Symlink),
+            MODE_CHAR_DEVICE => Some(FileType::CharDevice),
+            MODE_BLOCK_DEVICE => Some(FileType::BlockDevice),
+            MODE_FIFO => Some(FileType::Fifo),
+            MODE_SOCKET => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// The file type bits of a Unix mode
+    pub fn mode_bits(&self) -> u32 {
+        match self {
+            FileType::Regular => MODE_REGULAR,
+            FileType::Directory => MODE_DIRECTORY,
+            FileType::Symlink => MODE_SYMLINK,
+            FileType::CharDevice => MODE_CHAR_DEVICE,
+            FileType::BlockDevice => MODE_BLOCK_DEVICE,
+            FileType::Fifo => MODE_FIFO,
+            FileType::Socket => MODE_SOCKET,
+        }
+    }
+}
+
+/// What `stat` reports about an inode. Times are since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub inode: InodeId,
+    pub file_type: FileType,
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// Number of hard links
+    pub links: u32,
+    /// Space allocated, in 512-byte units
+    pub blocks: u64,
+    /// Preferred size of reads and writes
+    pub block_size: u32,
+    /// Device number of a character or block device file
+    pub device: u64,
+    pub accessed: Duration,
+    pub modified: Duration,
+    /// When the inode itself last changed
+    pub changed: Duration,
+    pub created: Option<Duration>,
+}
+
+impl Metadata {
+    /// Metadata with one link, owned by root, of size zero and with all times at the epoch
+    pub fn new(inode: InodeId, file_type: FileType, permissions: u16) -> Metadata {
+        Metadata {
+            inode,
+            file_type,
+            permissions: permissions & PERMISSION_MASK,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            links: 1,
+            blocks: 0,
+            block_size: block::BLOCK_SIZE as u32,
+            device: 0,
+            accessed: Duration::ZERO,
+            modified: Duration::ZERO,
+            changed: Duration::ZERO,
+            created: None,
+        }
+    }
+
+    /// The file type and permissions as a Unix mode
+    pub fn mode(&self) -> u32 {
+        self.file_type.mode_bits() | self.permissions as u32
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::Regular
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+}
+
+/// Changes made by `Inode::set_attributes`; fields left `None` are kept
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetAttributes {
+    pub permissions: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub accessed: Option<Duration>,
+    pub modified: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub inode: InodeId,
+    pub file_type: FileType,
+}
+
+/// Space and inode usage of a filesystem
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileSystemStats {
+    pub block_size: u32,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub inodes: u64,
+    pub free_inodes: u64,
+    pub max_name_len: u32,
+}
+
+/// How a file is opened, built like `std::fs::OpenOptions`:
+/// `OpenOptions::new().write(true).create(true)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Every write goes to the end of the file
+    pub append: bool,
+    /// Empty the file when it is opened for writing
+    pub truncate: bool,
+    /// Create the file if it does not exist
+    pub create: bool,
+    /// Create the file, failing if it already exists
+    pub create_new: bool,
+    /// Permissions of a created file
+    pub permissions: u16,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            permissions: 0o644,
+        }
+    }
+
+    pub fn read(mut self, read: bool) -> OpenOptions {
+        self.read = read;
+        self
+    }
+
+    pub fn write(mut self, write: bool) -> OpenOptions {
+        self.write = write;
+        self
+    }
+
+    pub fn append(mut self, append: bool) -> OpenOptions {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(mut self, truncate: bool) -> OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(mut self, create: bool) -> OpenOptions {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(mut self, create_new: bool) -> OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn permissions(mut self, permissions: u16) -> OpenOptions {
+        self.permissions = permissions & PERMISSION_MASK;
+        self
+    }
+
+    fn writes(&self) -> bool {
+        self.write || self.append
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions::new()
+    }
+}
+
+/// A mounted volume
+pub trait FileSystem: Send + Sync {
+    /// Name of the driver, e.g. `ext2`, as shown in the mount table
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> FileSystemResult<Arc<dyn Inode>>;
+
+    fn stats(&self) -> FileSystemResult<FileSystemStats> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Whether the driver or the volume only allows reading
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Write back everything cached for the filesystem
+    fn sync(&self) -> FileSystemResult<()> {
+        Ok(())
+    }
+}
+
+/// A file, directory or symlink. Operations a driver does not support return
+/// `NotSupported`, and directory operations on other inodes `NotADirectory`.
+pub trait Inode: Send + Sync {
+    /// Access the concrete inode type, e.g. to check that `rename` stays on one filesystem
+    fn as_any(&self) -> &dyn Any;
+
+    fn metadata(&self) -> FileSystemResult<Metadata>;
+
+    fn set_attributes(&self, _changes: &SetAttributes) -> FileSystemResult<()> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Read from a byte offset; returns fewer bytes than asked for at the end of the file
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FileSystemResult<usize> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Write at a byte offset, extending the file if needed
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> FileSystemResult<usize> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Shorten the file, or extend it with zeros
+    fn truncate(&self, _size: u64) -> FileSystemResult<()> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// The entry `name` of a directory
+    fn lookup(&self, _name: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        Err(FileSystemError::NotADirectory)
+    }
+
+    /// All entries of a directory, without `.` and `..`
+    fn read_directory(&self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        Err(FileSystemError::NotADirectory)
+    }
+
+    /// Create a file or directory in a directory
+    fn create(&self, _name: &str, _file_type: FileType, _permissions: u16) -> FileSystemResult<Arc<dyn Inode>> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Add a hard link to `target`, which must be on the same filesystem
+    fn link(&self, _name: &str, _target: &Arc<dyn Inode>) -> FileSystemResult<()> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Remove a directory entry that is not a directory
+    fn unlink(&self, _name: &str) -> FileSystemResult<()> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Remove an empty directory
+    fn remove_directory(&self, _name: &str) -> FileSystemResult<()> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Move the entry `old_name` to `new_name` in `new_parent`, a directory on the
+    /// same filesystem, replacing what is there
+    fn rename(&self, _old_name: &str, _new_parent: &Arc<dyn Inode>, _new_name: &str) -> FileSystemResult<()> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Create a symlink pointing to `target`
+    fn symlink(&self, _name: &str, _target: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// The target of a symlink
+    fn read_link(&self) -> FileSystemResult<String> {
+        Err(FileSystemError::InvalidArgument)
+    }
+
+    /// Write back the inode and its data
+    fn sync(&self) -> FileSystemResult<()> {
+        Ok(())
+    }
+}
+
+/// An open file
+pub trait FileHandle: Send + Sync {
+    fn read(&mut self, buffer: &mut [u8]) -> FileSystemResult<usize>;
+
+    fn write(&mut self, buffer: &[u8]) -> FileSystemResult<usize>;
+
+    /// Move the position; returns the new position from the start of the file
+    fn seek(&mut self, position: SeekFrom) -> FileSystemResult<u64>;
+
+    fn truncate(&mut self, size: u64) -> FileSystemResult<()>;
+
+    fn stat(&self) -> FileSystemResult<Metadata>;
+
+    fn read_directory(&mut self) -> FileSystemResult<Vec<DirectoryEntry>>;
+
+    fn sync(&mut self) -> FileSystemResult<()> {
+        Ok(())
+    }
+
+    fn inode(&self) -> &Arc<dyn Inode>;
+}
+
+/// The file handle of an inode opened through the VFS
+pub struct File {
+    inode: Arc<dyn Inode>,
+    // Keeps the mount the file was opened on busy
+    mount: Option<Arc<Mount>>,
+    options: OpenOptions,
+    position: u64,
+}
+
+impl File {
+    pub fn new(inode: Arc<dyn Inode>, options: OpenOptions) -> File {
+        File {
+            inode,
+            mount: None,
+            options,
+            position: 0,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read until the end of the file
+    pub fn read_to_end(&mut self) -> FileSystemResult<Vec<u8>> {
+        let mut contents = Vec::new();
+        let mut buffer = vec![0; block::BLOCK_SIZE];
+        loop {
+            let count = self.read(&mut buffer)?;
+            if count == 0 {
+                return Ok(contents);
+            }
+            contents.extend_from_slice(&buffer[..count]);
+        }
+    }
+
+    /// Write all of `buffer`
+    pub fn write_all(&mut self, mut buffer: &[u8]) -> FileSystemResult<()> {
+        while !buffer.is_empty() {
+            let count = self.write(buffer)?;
+            if count == 0 {
+                return Err(FileSystemError::NoSpace);
+            }
+            buffer = &buffer[count..];
+        }
+        Ok(())
+    }
+}
+
+impl FileHandle for File {
+    fn read(&mut self, buffer: &mut [u8]) -> FileSystemResult<usize> {
+        if !self.options.read {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let count = self.inode.read_at(self.position, buffer)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> FileSystemResult<usize> {
+        if !self.options.writes() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        if self.options.append {
+            self.position = self.inode.metadata()?.size;
+        }
+        let count = self.inode.write_at(self.position, buffer)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> FileSystemResult<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.inode.metadata()?.size.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(FileSystemError::InvalidArgument)?;
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self, size: u64) -> FileSystemResult<()> {
+        if !self.options.writes() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        self.inode.truncate(size)
+    }
+
+    fn stat(&self) -> FileSystemResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn read_directory(&mut self) -> FileSystemResult<Vec<DirectoryEntry>> {
+        self.inode.read_directory()
+    }
+
+    fn sync(&mut self) -> FileSystemResult<()> {
+        self.inode.sync()
+    }
+
+    fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+}
+
+/// Split a path into its parent directory and final name
+fn split_parent(path: &str) -> FileSystemResult<(&str, &str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(index) => (&trimmed[..index], &trimmed[index + 1..]),
+        None => ("", trimmed),
+    };
+    check_name(name)?;
+    Ok((parent, name))
+}
+
+/// Whether `name` can be the name of a new directory entry
+pub fn check_name(name: &str) -> FileSystemResult<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(FileSystemError::InvalidPath);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(FileSystemError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// `name` inside the directory at the absolute path `directory`
+fn join(directory: &str, name: &str) -> String {
+    if directory == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", directory, name)
+    }
+}
+
+/// The parent of an absolute path without `.`, `..` or repeated slashes
+fn parent_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(index) => &path[..index],
+    }
+}
+
+/// Flags of a mount
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    pub read_only: bool,
+}
+
+/// A filesystem, or a directory of one for bind mounts, attached at a path
+struct Mount {
+    path: String,
+    /// Device or directory the filesystem came from, e.g. `disk0` or `none`
+    source: String,
+    filesystem: Arc<dyn FileSystem>,
+    root: Arc<dyn Inode>,
+    options: MountOptions,
+}
+
+impl Mount {
+    fn read_only(&self) -> bool {
+        self.options.read_only || self.filesystem.read_only()
+    }
+}
+
+/// An entry of the mount table, as listed by `Vfs::mounts`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub path: String,
+    pub source: String,
+    pub filesystem: &'static str,
+    pub options: MountOptions,
+}
+
+/// An inode reached through the mount table, with the canonical path it was found at
+#[derive(Clone)]
+struct Location {
+    mount: Arc<Mount>,
+    inode: Arc<dyn Inode>,
+    path: String,
+}
+
+/// Symlinks followed while resolving one path before giving up, as in Linux
+pub const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// Process 0 is the kernel
+pub const KERNEL_PROCESS: ProcessId = 0;
+
+/// The mount table and the working directory of every process. Relative paths are
+/// resolved against the working directory of the current process, which the
+/// scheduler sets with `set_current_process`.
 pub struct Vfs {
-    file_systems: Vec<Box<dyn FileSystem>>,
+    // In mount order, so a mount is listed after the one it is mounted on
+    mounts: Mutex<Vec<Arc<Mount>>>,
+    working_directories: Mutex<BTreeMap<ProcessId, Location>>,
+    current_process: AtomicU32,
 }
 
 impl Vfs {
     pub fn new() -> Vfs {
         Vfs {
-            file_systems: Vec::new(),
+            mounts: Mutex::new(Vec::new()),
+            working_directories: Mutex::new(BTreeMap::new()),
+            current_process: AtomicU32::new(KERNEL_PROCESS),
         }
     }
 
-    pub fn mount_filesystem(&mut self, filesystem: Box<dyn FileSystem>) {
-        self.file_systems.push(filesystem);
+    /// Attach `filesystem` at `target`, which must be an existing directory; the
+    /// first mount must be the root, `/`
+    pub fn mount(
+        &self,
+        filesystem: Arc<dyn FileSystem>,
+        source: &str,
+        target: &str,
+        options: MountOptions,
+    ) -> FileSystemResult<()> {
+        let root = filesystem.root()?;
+        self.attach(filesystem, root, source, target, options)
+    }
+
+    /// Make the directory at `source` also appear at `target`
+    pub fn bind_mount(&self, source: &str, target: &str, options: MountOptions) -> FileSystemResult<()> {
+        let source = self.resolve(source, true)?;
+        if !source.inode.metadata()?.is_dir() {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let options = MountOptions {
+            read_only: options.read_only || source.mount.options.read_only,
+        };
+        self.attach(source.mount.filesystem.clone(), source.inode, &source.path, target, options)
     }
 
-    pub fn read_directory(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FileSystemError> {
-        for fs in &mut self.file_systems {
-            match fs.read_directory(path) {
-                Ok(entries) => {
-                    return Ok(entries);
-                },
-                Err(_) => continue,
+    fn attach(
+        &self,
+        filesystem: Arc<dyn FileSystem>,
+        root: Arc<dyn Inode>,
+        source: &str,
+        target: &str,
+        options: MountOptions,
+    ) -> FileSystemResult<()> {
+        let path = if self.mounts.lock().is_empty() {
+            if target != "/" {
+                return Err(FileSystemError::NotFound);
             }
+            String::from("/")
+        } else {
+            let target = self.resolve(target, true)?;
+            if !target.inode.metadata()?.is_dir() {
+                return Err(FileSystemError::NotADirectory);
+            }
+            target.path
+        };
+        let mut mounts = self.mounts.lock();
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(FileSystemError::Busy);
+        }
+        mounts.push(Arc::new(Mount {
+            path,
+            source: String::from(source),
+            filesystem,
+            root,
+            options,
+        }));
+        Ok(())
+    }
+
+    /// Detach the filesystem mounted at `target`. Fails with `Busy` while files are
+    /// open on it, a process works in it, or something else is mounted inside it.
+    pub fn unmount(&self, target: &str) -> FileSystemResult<()> {
+        let path = self.resolve(target, true)?.path;
+        let mut mounts = self.mounts.lock();
+        let index = mounts.iter().position(|mount| mount.path == path).ok_or(FileSystemError::InvalidArgument)?;
+        let nested = mounts.iter().any(|mount| {
+            mount.path.len() > path.len()
+                && mount.path.starts_with(&path)
+                && (path == "/" || mount.path.as_bytes()[path.len()] == b'/')
+        });
+        // The table holds the only reference to an idle mount
+        if nested || Arc::strong_count(&mounts[index]) > 1 {
+            return Err(FileSystemError::Busy);
         }
-        Err(FileSystemError::FileNotFound)
+        let mount = mounts.remove(index);
+        drop(mounts);
+        mount.filesystem.sync()
     }
 
-    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FileSystemError> {
-        for fs in &mut self.file_systems {
-            match fs.read_file(path) {
-                Ok(contents) => {
-                    return Ok(contents);
-                },
-                Err(_) => continue,
+    pub fn mounts(&self) -> Vec<MountInfo> {
+        self.mounts
+            .lock()
+            .iter()
+            .map(|mount| MountInfo {
+                path: mount.path.clone(),
+                source: mount.source.clone(),
+                filesystem: mount.filesystem.name(),
+                options: mount.options,
+            })
+            .collect()
+    }
+
+    /// Write back every mounted filesystem
+    pub fn sync(&self) -> FileSystemResult<()> {
+        let mounts = self.mounts.lock().clone();
+        for mount in mounts {
+            mount.filesystem.sync()?;
+        }
+        Ok(())
+    }
+
+    fn mount_at(&self, path: &str) -> Option<Arc<Mount>> {
+        self.mounts.lock().iter().rev().find(|mount| mount.path == path).cloned()
+    }
+
+    fn root(&self) -> FileSystemResult<Location> {
+        let mount = self.mount_at("/").ok_or(FileSystemError::NotFound)?;
+        Ok(Location {
+            inode: mount.root.clone(),
+            mount,
+            path: String::from("/"),
+        })
+    }
+
+    /// The working directory of the current process; the root until it changes it
+    fn working_location(&self) -> FileSystemResult<Location> {
+        let process = self.current_process.load(Ordering::Relaxed);
+        match self.working_directories.lock().get(&process) {
+            Some(location) => Ok(location.clone()),
+            None => self.root(),
+        }
+    }
+
+    // Resolve `path` to a location, following symlinks in all components but the
+    // last unless `follow` is set
+    fn resolve(&self, path: &str, follow: bool) -> FileSystemResult<Location> {
+        if path.is_empty() {
+            return Err(FileSystemError::NotFound);
+        }
+        let mut current = if path.starts_with('/') { self.root()? } else { self.working_location()? };
+        let mut remaining: VecDeque<String> = path.split('/').map(String::from).collect();
+        let mut follows = 0;
+        while let Some(component) = remaining.pop_front() {
+            match component.as_str() {
+                "" | "." => {}
+                ".." => {
+                    // Canonical paths have no symlinks, so the parent is found by name
+                    if current.path != "/" {
+                        let parent = String::from(parent_path(&current.path));
+                        current = self.resolve(&parent, true)?;
+                    }
+                }
+                name => {
+                    let path = join(&current.path, name);
+                    let next = match self.mount_at(&path) {
+                        Some(mount) => Location {
+                            inode: mount.root.clone(),
+                            mount,
+                            path,
+                        },
+                        None => Location {
+                            inode: current.inode.lookup(name)?,
+                            mount: current.mount.clone(),
+                            path,
+                        },
+                    };
+                    let last = remaining.iter().all(|component| component.is_empty());
+                    if (follow || !last) && next.inode.metadata()?.is_symlink() {
+                        follows += 1;
+                        if follows > MAX_SYMLINK_FOLLOWS {
+                            return Err(FileSystemError::TooManyLinks);
+                        }
+                        let target = next.inode.read_link()?;
+                        if target.starts_with('/') {
+                            current = self.root()?;
+                        }
+                        for component in target.split('/').rev() {
+                            remaining.push_front(String::from(component));
+                        }
+                        continue;
+                    }
+                    current = next;
+                }
             }
         }
-        Err(FileSystemError::FileNotFound)
+        Ok(current)
     }
+
+    // The directory a new entry at `path` goes in, and the entry's name
+    fn resolve_parent<'a>(&self, path: &'a str) -> FileSystemResult<(Location, &'a str)> {
+        let (parent, name) = split_parent(path)?;
+        let parent = if parent.is_empty() { self.working_location()? } else { self.resolve(parent, true)? };
+        Ok((parent, name))
+    }
+
+    // The parent of an entry about to be changed, which must be writable and not a mount point
+    fn resolve_parent_for_change<'a>(&self, path: &'a str) -> FileSystemResult<(Location, &'a str)> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if parent.mount.read_only() {
+            return Err(FileSystemError::ReadOnly);
+        }
+        if self.mount_at(&join(&parent.path, name)).is_some() {
+            return Err(FileSystemError::Busy);
+        }
+        Ok((parent, name))
+    }
+
+    /// The inode at `path`, following symlinks
+    pub fn lookup(&self, path: &str) -> FileSystemResult<Arc<dyn Inode>> {
+        Ok(self.resolve(path, true)?.inode)
+    }
+
+    /// The absolute path of `path` without `.`, `..` or symlinks
+    pub fn canonicalize(&self, path: &str) -> FileSystemResult<String> {
+        Ok(self.resolve(path, true)?.path)
+    }
+
+    /// Switch the process whose working directory relative paths start from
+    pub fn set_current_process(&self, process: ProcessId) {
+        self.current_process.store(process, Ordering::Relaxed);
+    }
+
+    pub fn current_process(&self) -> ProcessId {
+        self.current_process.load(Ordering::Relaxed)
+    }
+
+    /// Change the working directory of the current process
+    pub fn change_directory(&self, path: &str) -> FileSystemResult<()> {
+        let location = self.resolve(path, true)?;
+        if !location.inode.metadata()?.is_dir() {
+            return Err(FileSystemError::NotADirectory);
+        }
+        self.working_directories.lock().insert(self.current_process(), location);
+        Ok(())
+    }
+
+    /// The working directory of the current process
+    pub fn working_directory(&self) -> FileSystemResult<String> {
+        Ok(self.working_location()?.path)
+    }
+
+    /// Give a new process the working directory of its parent
+    pub fn fork_process(&self, parent: ProcessId, child: ProcessId) {
+        let mut working_directories = self.working_directories.lock();
+        if let Some(location) = working_directories.get(&parent).cloned() {
+            working_directories.insert(child, location);
+        }
+    }
+
+    /// Forget an exited process, releasing its working directory
+    pub fn exit_process(&self, process: ProcessId) {
+        self.working_directories.lock().remove(&process);
+    }
+
+    pub fn open(&self, path: &str, options: &OpenOptions) -> FileSystemResult<Box<dyn FileHandle>> {
+        Ok(Box::new(self.open_file(path, options)?))
+    }
+
+    fn open_file(&self, path: &str, options: &OpenOptions) -> FileSystemResult<File> {
+        let location = match self.resolve(path, true) {
+            Ok(_) if options.create_new => return Err(FileSystemError::AlreadyExists),
+            Ok(location) => location,
+            Err(FileSystemError::NotFound) if options.create || options.create_new => {
+                let (parent, name) = self.resolve_parent_for_change(path)?;
+                Location {
+                    inode: parent.inode.create(name, FileType::Regular, options.permissions)?,
+                    path: join(&parent.path, name),
+                    mount: parent.mount,
+                }
+            }
+            Err(error) => return Err(error),
+        };
+        if options.writes() {
+            if location.mount.read_only() {
+                return Err(FileSystemError::ReadOnly);
+            }
+            if location.inode.metadata()?.is_dir() {
+                return Err(FileSystemError::IsADirectory);
+            }
+            if options.truncate {
+                location.inode.truncate(0)?;
+            }
+        }
+        Ok(File {
+            inode: location.inode,
+            mount: Some(location.mount),
+            options: *options,
+            position: 0,
+        })
+    }
+
+    /// Metadata of the file at `path`, following symlinks
+    pub fn metadata(&self, path: &str) -> FileSystemResult<Metadata> {
+        self.resolve(path, true)?.inode.metadata()
+    }
+
+    /// Metadata of the file at `path`, or of the symlink itself if it is one
+    pub fn symlink_metadata(&self, path: &str) -> FileSystemResult<Metadata> {
+        self.resolve(path, false)?.inode.metadata()
+    }
+
+    pub fn read_file(&self, path: &str) -> FileSystemResult<Vec<u8>> {
+        let inode = self.lookup(path)?;
+        if inode.metadata()?.is_dir() {
+            return Err(FileSystemError::IsADirectory);
+        }
+        File::new(inode, OpenOptions::new().read(true)).read_to_end()
+    }
+
+    /// Replace the contents of a file, creating it if needed
+    pub fn write_file(&self, path: &str, contents: &[u8]) -> FileSystemResult<()> {
+        self.open_file(path, &OpenOptions::new().write(true).create(true).truncate(true))?.write_all(contents)
+    }
+
+    pub fn read_directory(&self, path: &str) -> FileSystemResult<Vec<DirectoryEntry>> {
+        self.lookup(path)?.read_directory()
+    }
+
+    pub fn create_directory(&self, path: &str, permissions: u16) -> FileSystemResult<()> {
+        let (parent, name) = self.resolve_parent_for_change(path)?;
+        parent.inode.create(name, FileType::Directory, permissions)?;
+        Ok(())
+    }
+
+    /// Create a device file, FIFO or socket
+    pub fn make_node(&self, path: &str, file_type: FileType, permissions: u16) -> FileSystemResult<()> {
+        if matches!(file_type, FileType::Regular | FileType::Directory | FileType::Symlink) {
+            return Err(FileSystemError::InvalidArgument);
+        }
+        let (parent, name) = self.resolve_parent_for_change(path)?;
+        parent.inode.create(name, file_type, permissions)?;
+        Ok(())
+    }
+
+    pub fn remove_file(&self, path: &str) -> FileSystemResult<()> {
+        let (parent, name) = self.resolve_parent_for_change(path)?;
+        parent.inode.unlink(name)
+    }
+
+    pub fn remove_directory(&self, path: &str) -> FileSystemResult<()> {
+        let (parent, name) = self.resolve_parent_for_change(path)?;
+        parent.inode.remove_directory(name)
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> FileSystemResult<()> {
+        let (old_parent, old_name) = self.resolve_parent_for_change(from)?;
+        let (new_parent, new_name) = self.resolve_parent_for_change(to)?;
+        if !Arc::ptr_eq(&old_parent.mount, &new_parent.mount) {
+            return Err(FileSystemError::CrossDevice);
+        }
+        old_parent.inode.rename(old_name, &new_parent.inode, new_name)
+    }
+
+    /// Create a hard link at `link` to the file at `target`
+    pub fn link(&self, target: &str, link: &str) -> FileSystemResult<()> {
+        let target = self.resolve(target, false)?;
+        let (parent, name) = self.resolve_parent_for_change(link)?;
+        if !Arc::ptr_eq(&target.mount, &parent.mount) {
+            return Err(FileSystemError::CrossDevice);
+        }
+        parent.inode.link(name, &target.inode)
+    }
+
+    /// Create a symlink at `link` pointing to `target`
+    pub fn symlink(&self, target: &str, link: &str) -> FileSystemResult<()> {
+        let (parent, name) = self.resolve_parent_for_change(link)?;
+        parent.inode.symlink(name, target)?;
+        Ok(())
+    }
+
+    pub fn read_link(&self, path: &str) -> FileSystemResult<String> {
+        self.resolve(path, false)?.inode.read_link()
+    }
+
+    pub fn set_attributes(&self, path: &str, changes: &SetAttributes) -> FileSystemResult<()> {
+        let location = self.resolve(path, true)?;
+        if location.mount.read_only() {
+            return Err(FileSystemError::ReadOnly);
+        }
+        location.inode.set_attributes(changes)
+    }
+}
+
+impl Default for Vfs {
+    fn default() -> Self {
+        Vfs::new()
+    }
+}
+
+static VFS: Once<Vfs> = Once::new();
+
+/// The kernel's filesystem tree
+pub fn vfs() -> &'static Vfs {
+    VFS.call_once(Vfs::new)
 }
@@ -1,7 +1,38 @@
-use crate::gui::event::{Event, Key};
-use crate::gui::layout::{Constraint, Direction, Layout, Margin, Size};
-use crate::gui::style::{Color, Style};
-use crate::gui::{Context, Rect};
+use crate::drivers::keyboard::{KeyEvent, Keycode};
+use crate::gui::context::Context;
+use crate::gui::utils::color::{Color, BLACK, WHITE};
+use crate::gui::utils::font::DEFAULT_FONT_SIZE;
+use crate::gui::window::{Align, Rect};
+
+// Height of the line under the cursor
+const CURSOR_HEIGHT: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextBoxStyle {
+    pub background_color: Color,
+    pub focused_background_color: Color,
+    pub text_color: Color,
+    pub placeholder_color: Color,
+    pub border_color: Color,
+    pub border_width: u32,
+    pub padding: u32,
+    pub font_size: u32,
+}
+
+impl Default for TextBoxStyle {
+    fn default() -> Self {
+        TextBoxStyle {
+            background_color: WHITE,
+            focused_background_color: Color::new(240, 246, 255),
+            text_color: BLACK,
+            placeholder_color: Color::new(128, 128, 128),
+            border_color: Color::dark_gray(),
+            border_width: 1,
+            padding: 4,
+            font_size: DEFAULT_FONT_SIZE,
+        }
+    }
+}
 
 pub struct TextBox {
     id: String,
@@ -10,7 +41,7 @@ pub struct TextBox {
     cursor_pos: usize,
     focused: bool,
     on_change: Option<Box<dyn Fn(&str)>>,
-    style: Style,
+    style: TextBoxStyle,
 }
 
 impl TextBox {
@@ -22,12 +53,13 @@ impl TextBox {
             cursor_pos: 0,
             focused: false,
             on_change: None,
-            style: Style::default(),
+            style: TextBoxStyle::default(),
         }
     }
 
     pub fn value(mut self, value: &str) -> Self {
         self.value = value.to_string();
+        self.cursor_pos = self.value.len();
         self
     }
 
@@ -41,148 +73,133 @@ impl TextBox {
         self
     }
 
-    pub fn style(mut self, style: Style) -> Self {
+    pub fn style(mut self, style: TextBoxStyle) -> Self {
         self.style = style;
         self
     }
 
-    pub fn handle_event(&mut self, event: &Event) -> bool {
-        if !self.focused {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn text(&self) -> &str {
+        &self.value
+    }
+
+    // Edit the text for a key press; returns whether the text box used the event
+    pub fn handle_event(&mut self, event: &KeyEvent) -> bool {
+        if !self.focused || event.released {
             return false;
         }
 
-        match event {
-            Event::Key(Key::Backspace) => {
-                if self.cursor_pos > 0 {
-                    self.value.remove(self.cursor_pos - 1);
-                    self.cursor_pos -= 1;
+        let changed = match event.keycode {
+            Keycode::Backspace => match self.value[..self.cursor_pos].chars().next_back() {
+                Some(c) => {
+                    self.cursor_pos -= c.len_utf8();
+                    self.value.remove(self.cursor_pos);
+                    true
                 }
-                true
-            }
-            Event::Key(Key::Delete) => {
+                None => false,
+            },
+            Keycode::Delete => {
                 if self.cursor_pos < self.value.len() {
                     self.value.remove(self.cursor_pos);
+                    true
+                } else {
+                    false
                 }
-                true
             }
-            Event::Key(Key::Left) => {
-                if self.cursor_pos > 0 {
-                    self.cursor_pos -= 1;
+            Keycode::Left => {
+                if let Some(c) = self.value[..self.cursor_pos].chars().next_back() {
+                    self.cursor_pos -= c.len_utf8();
                 }
-                true
+                false
             }
-            Event::Key(Key::Right) => {
-                if self.cursor_pos < self.value.len() {
-                    self.cursor_pos += 1;
+            Keycode::Right => {
+                if let Some(c) = self.value[self.cursor_pos..].chars().next() {
+                    self.cursor_pos += c.len_utf8();
                 }
-                true
+                false
+            }
+            Keycode::Home => {
+                self.cursor_pos = 0;
+                false
             }
-            Event::Key(Key::Char(c)) => {
-                self.value.insert(self.cursor_pos, *c);
-                self.cursor_pos += 1;
+            Keycode::End => {
+                self.cursor_pos = self.value.len();
+                false
+            }
+            Keycode::Char(c) if !c.is_control() => {
+                self.value.insert(self.cursor_pos, c);
+                self.cursor_pos += c.len_utf8();
                 true
             }
-            _ => false,
+            _ => return false,
+        };
+
+        if changed {
+            if let Some(on_change) = &self.on_change {
+                on_change(&self.value);
+            }
         }
+        true
     }
 
     pub fn set_focus(&mut self, focused: bool) {
         self.focused = focused;
     }
 
-    pub fn layout(&mut self, context: &mut Context, constraints: Constraints) -> Size {
-        let padding = self.style.padding.unwrap_or_default();
-        let margin = self.style.margin.unwrap_or_default();
-        let width = constraints.width.unwrap_or_else(|| {
-            context
-                .get_text_size(&self.placeholder, &self.style)
-                .width
-        });
-        let height = constraints.height.unwrap_or_else(|| {
-            context
-                .get_text_size(&self.placeholder, &self.style)
-                .height
-        });
-
-        let mut layout = Layout::new(width, height);
-        layout
-            .add_child(
-                Margin::new(
-                    padding.left,
-                    padding.top,
-                    padding.right,
-                    padding.bottom,
-                    Label::new(&self.value, &self.style),
-                ),
-                0.0,
-                Direction::LeftToRight,
-            )
-            .add_child(
-                Margin::new(0, 0, 0, 0, Label::new(&self.placeholder, &self.style)),
-                0.0,
-                Direction::LeftToRight,
-            );
-
-        let size = layout.layout(context, &constraints);
-        self.style.size = Some(size);
-
-        size.inflate(padding.left + padding.right, padding.top + padding.bottom)
-            .inflate(margin.left + margin.right, margin.top + margin.bottom)
+    // The size that fits the text or the placeholder, whichever is wider, on one line
+    pub fn preferred_size(&self, context: &mut Context) -> (u32, u32) {
+        let font = context.get_font(self.style.font_size);
+        let (value_width, _) = context.get_text_size(&self.value, &font);
+        let (placeholder_width, _) = context.get_text_size(&self.placeholder, &font);
+        let inset = 2 * (self.style.border_width + self.style.padding);
+        (
+            value_width.max(placeholder_width) + inset,
+            font.line_height() + CURSOR_HEIGHT + inset,
+        )
     }
 
-    pub fn draw(&self, context: &mut Context, clip: Rect) {
-        let padding = self.style.padding.unwrap_or_default();
-        let margin = self.style.margin.unwrap_or_default();
-        let size = self.style.size.unwrap_or_default();
-
-        let mut rect = Rect::new(
-            margin.left as f32,
-            margin.top as f32,
-            size.width as f32,
-            size.height as f32,
-        );
-        rect = rect.clip(clip);
-
+    pub fn draw(&self, context: &mut Context, rect: Rect) {
+        let style = &self.style;
         let background_color = if self.focused {
-            self.style.active_background_color.unwrap_or(self.style.background_color)
+            style.focused_background_color
         } else {
-            self.style.background_color
+            style.background_color
         };
-        context.draw_rect(rect, background_color);
+        context.fill_rect(rect, background_color);
+        if style.border_width > 0 {
+            context.stroke_rect(rect, style.border_width, style.border_color);
+        }
 
-        let mut label_rect = Rect::new(
-            (padding.left + self.cursor_pos * 8) as f32,
-            padding.top as f32,
-            0.0,
-            0.0,
-        );
-        label_rect = label_rect.clip(rect);
-        let label_clip = Rect::new(
-            label_rect.x - padding.left as f32,
-            label_rect.y - padding.top as f32,
-            rect.width - padding.left as f32 - padding.right as f32,
-            rect.height - padding.top as f32 - padding.bottom as f32,
+        let inset = style.border_width + style.padding;
+        let inner = Rect::new(
+            rect.x + inset as i32,
+            rect.y + inset as i32,
+            rect.width.saturating_sub(2 * inset),
+            rect.height.saturating_sub(2 * inset),
         );
-        Label::new(&self.value, &self.style).draw(context, label_clip);
-
+        let font = context.get_font(style.font_size);
         if self.value.is_empty() {
-            let placeholder_clip = Rect::new(
-                padding.left as f32,
-                padding.top as f32,
-                rect.width - padding.left as f32 - padding.right as f32,
-                rect.height - padding.top as f32 - padding.bottom as f32,
-            );
-            Label::new(&self.placeholder, &self.style.placeholder_style()).draw(context, placeholder_clip);
+            context.draw_text(&self.placeholder, &font, inner, style.placeholder_color, Align::Left);
+        } else {
+            context.draw_text(&self.value, &font, inner, style.text_color, Align::Left);
         }
 
+        // The cursor sits after the text before it, whatever the widths of its glyphs
         if self.focused {
-            let line_rect = Rect::new(
-                (padding.left + self.cursor_pos * 8) as f32,
-                rect.height - padding.bottom as f32 - 2.0,
-                8.0,
-                2.0,
+            let (cursor_x, _) = context.get_text_size(&self.value[..self.cursor_pos], &font);
+            let cursor_width = font.glyph(' ').advance.ceil().max(1.0) as u32;
+            let cursor = Rect::new(
+                inner.x + cursor_x as i32,
+                inner.y + inner.height as i32 - CURSOR_HEIGHT as i32,
+                cursor_width,
+                CURSOR_HEIGHT,
             );
-            context.draw_rect(line_rect, self.style.color.unwrap_or_default());
+            context.push_clip(inner);
+            context.fill_rect(cursor, style.text_color);
+            context.pop_clip();
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,491 @@
+// Software rasterizer
+// A `Context` draws into an ARGB pixel buffer in memory and knows nothing about the
+// display, so widgets render the same way on screen and in headless tests; `present`
+// copies the result to a GPU. Coordinates are whole pixels. Curved edges are
+// antialiased by sampling each pixel on a 4x4 grid, in integer arithmetic so the
+// output is exactly reproducible. Text is drawn from the glyph masks of a `Font`.
+
+use crate::drivers::gpu::{self, Gpu};
+use crate::gui::utils::color::{Color, TRANSPARENT};
+use crate::gui::utils::font::{Font, FontLibrary};
+use crate::gui::utils::image::Bitmap;
+use crate::gui::window::{Align, Rect};
+use std::sync::Arc;
+
+// Samples per pixel along each axis, and the factor coordinates are scaled by so
+// every sample position is an integer: samples sit at odd multiples of 1/SCALE
+const SUBSAMPLES: i64 = 4;
+const SCALE: i64 = 2 * SUBSAMPLES;
+const FULL_COVERAGE: u32 = (SUBSAMPLES * SUBSAMPLES) as u32;
+
+// A half-open pixel rectangle, used for clipping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bounds {
+    left: i64,
+    top: i64,
+    right: i64,
+    bottom: i64,
+}
+
+impl Bounds {
+    fn from_rect(rect: Rect) -> Bounds {
+        Bounds {
+            left: rect.x as i64,
+            top: rect.y as i64,
+            right: rect.x as i64 + rect.width as i64,
+            bottom: rect.y as i64 + rect.height as i64,
+        }
+    }
+
+    fn intersect(&self, other: &Bounds) -> Bounds {
+        let left = self.left.max(other.left);
+        let top = self.top.max(other.top);
+        Bounds {
+            left,
+            top,
+            right: self.right.min(other.right).max(left),
+            bottom: self.bottom.min(other.bottom).max(top),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.left >= self.right || self.top >= self.bottom
+    }
+
+    fn to_rect(self) -> Rect {
+        Rect::new(self.left as i32, self.top as i32, (self.right - self.left) as u32, (self.bottom - self.top) as u32)
+    }
+}
+
+pub struct Context {
+    width: u32,
+    height: u32,
+    pixels: Vec<u32>,
+    clip_stack: Vec<Bounds>,
+    fonts: FontLibrary,
+}
+
+impl Context {
+    /// Creates a fully transparent `width` x `height` canvas.
+    pub fn new(width: u32, height: u32) -> Context {
+        Context {
+            width,
+            height,
+            pixels: vec![TRANSPARENT.to_argb(); width as usize * height as usize],
+            clip_stack: Vec::new(),
+            fonts: FontLibrary::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The canvas as 0xAARRGGBB pixels, row by row.
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: i32, y: i32) -> Option<Color> {
+        if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+            return None;
+        }
+        Some(Color::from_argb(self.pixels[y as usize * self.width as usize + x as usize]))
+    }
+
+    /// The canvas as RGBA bytes, e.g. for `png::encode`.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|pixel| {
+                let color = Color::from_argb(*pixel);
+                [color.r, color.g, color.b, color.a]
+            })
+            .collect()
+    }
+
+    /// Restricts drawing to `rect` within the current clip rect until the matching `pop_clip`.
+    pub fn push_clip(&mut self, rect: Rect) {
+        let clip = self.clip().intersect(&Bounds::from_rect(rect));
+        self.clip_stack.push(clip);
+    }
+
+    pub fn pop_clip(&mut self) {
+        self.clip_stack.pop();
+    }
+
+    /// The area drawing is currently restricted to.
+    pub fn clip_rect(&self) -> Rect {
+        self.clip().to_rect()
+    }
+
+    fn clip(&self) -> Bounds {
+        self.clip_stack.last().copied().unwrap_or(Bounds {
+            left: 0,
+            top: 0,
+            right: self.width as i64,
+            bottom: self.height as i64,
+        })
+    }
+
+    /// Replaces every pixel inside the clip rect with `color`, without blending.
+    pub fn clear(&mut self, color: Color) {
+        let clip = self.clip();
+        for y in clip.top..clip.bottom {
+            let row = y as usize * self.width as usize;
+            self.pixels[row + clip.left as usize..row + clip.right as usize].fill(color.to_argb());
+        }
+    }
+
+    // Blend `color` into one pixel, with `coverage` out of FULL_COVERAGE samples;
+    // the caller has clipped the coordinates
+    fn blend_pixel(&mut self, x: i64, y: i64, color: Color, coverage: u32) {
+        let index = y as usize * self.width as usize + x as usize;
+        let alpha = color.a as u32 * coverage / FULL_COVERAGE;
+        self.pixels[index] = blend(self.pixels[index], color, alpha);
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let area = self.clip().intersect(&Bounds::from_rect(rect));
+        if color.a == 255 {
+            for y in area.top..area.bottom {
+                let row = y as usize * self.width as usize;
+                self.pixels[row + area.left as usize..row + area.right as usize].fill(color.to_argb());
+            }
+            return;
+        }
+        for y in area.top..area.bottom {
+            for x in area.left..area.right {
+                self.blend_pixel(x, y, color, FULL_COVERAGE);
+            }
+        }
+    }
+
+    /// Draws a border of `thickness` pixels along the inside of `rect`.
+    pub fn stroke_rect(&mut self, rect: Rect, thickness: u32, color: Color) {
+        if thickness == 0 {
+            return;
+        }
+        if thickness * 2 >= rect.width || thickness * 2 >= rect.height {
+            self.fill_rect(rect, color);
+            return;
+        }
+        let inner_height = rect.height - thickness * 2;
+        let right = rect.x + (rect.width - thickness) as i32;
+        let bottom = rect.y + (rect.height - thickness) as i32;
+        let middle = rect.y + thickness as i32;
+        // The sides stop short of the top and bottom edges so no pixel is blended twice
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, thickness), color);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, thickness), color);
+        self.fill_rect(Rect::new(rect.x, middle, thickness, inner_height), color);
+        self.fill_rect(Rect::new(right, middle, thickness, inner_height), color);
+    }
+
+    /// Draws a one pixel wide line between two pixels, both included.
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
+        let clip = self.clip();
+        let (mut x, mut y) = (x0 as i64, y0 as i64);
+        let (x1, y1) = (x1 as i64, y1 as i64);
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let step_x = if x < x1 { 1 } else { -1 };
+        let step_y = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        loop {
+            if x >= clip.left && x < clip.right && y >= clip.top && y < clip.bottom {
+                self.blend_pixel(x, y, color, FULL_COVERAGE);
+            }
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    pub fn fill_rounded_rect(&mut self, rect: Rect, radius: u32, color: Color) {
+        let outer = RoundedRect::new(rect, radius);
+        self.fill_shape(Bounds::from_rect(rect), color, |x, y| outer.contains(x, y), |_, _| false);
+    }
+
+    /// Draws a border of `thickness` pixels along the inside of a rounded rect.
+    pub fn stroke_rounded_rect(&mut self, rect: Rect, radius: u32, thickness: u32, color: Color) {
+        let outer = RoundedRect::new(rect, radius);
+        let inner = outer.inset(thickness);
+        self.fill_shape(Bounds::from_rect(rect), color, |x, y| outer.contains(x, y), |x, y| inner.contains(x, y));
+    }
+
+    /// Fills the circle of `radius` pixels around the center of pixel (`cx`, `cy`).
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: u32, color: Color) {
+        let outer = Circle::new(cx, cy, radius);
+        self.fill_shape(outer.bounds(), color, |x, y| outer.contains(x, y), |_, _| false);
+    }
+
+    /// Draws a ring of `thickness` pixels along the inside of a circle.
+    pub fn stroke_circle(&mut self, cx: i32, cy: i32, radius: u32, thickness: u32, color: Color) {
+        let outer = Circle::new(cx, cy, radius);
+        let inner = Circle::new(cx, cy, radius.saturating_sub(thickness));
+        let hollow = thickness < radius;
+        self.fill_shape(outer.bounds(), color, |x, y| outer.contains(x, y), |x, y| hollow && inner.contains(x, y));
+    }
+
+    // Fill the samples inside `outer` but not inside `inner`, for pixels within
+    // `bounds`. Both shapes are convex and `inner` lies within `outer`.
+    fn fill_shape(
+        &mut self,
+        bounds: Bounds,
+        color: Color,
+        outer: impl Fn(i64, i64) -> bool,
+        inner: impl Fn(i64, i64) -> bool,
+    ) {
+        let area = self.clip().intersect(&bounds);
+        for y in area.top..area.bottom {
+            for x in area.left..area.right {
+                let coverage = coverage(x, y, &outer) - coverage(x, y, &inner);
+                if coverage > 0 {
+                    self.blend_pixel(x, y, color, coverage);
+                }
+            }
+        }
+    }
+
+    /// Draws `image` with its top left corner at (`x`, `y`), blending by its alpha.
+    pub fn draw_image(&mut self, image: &Bitmap, x: i32, y: i32) {
+        let rect = Rect::new(x, y, image.width, image.height);
+        let area = self.clip().intersect(&Bounds::from_rect(rect));
+        for py in area.top..area.bottom {
+            for px in area.left..area.right {
+                let color = image.pixel((px - x as i64) as u32, (py - y as i64) as u32);
+                self.blend_pixel(px, py, color, FULL_COVERAGE);
+            }
+        }
+    }
+
+    /// Draws `image` stretched to fill `rect`, picking the nearest source pixel.
+    pub fn draw_image_scaled(&mut self, image: &Bitmap, rect: Rect) {
+        if image.width == 0 || image.height == 0 {
+            return;
+        }
+        let area = self.clip().intersect(&Bounds::from_rect(rect));
+        for py in area.top..area.bottom {
+            let source_y = (py - rect.y as i64) * image.height as i64 / rect.height as i64;
+            for px in area.left..area.right {
+                let source_x = (px - rect.x as i64) * image.width as i64 / rect.width as i64;
+                let color = image.pixel(source_x as u32, source_y as u32);
+                self.blend_pixel(px, py, color, FULL_COVERAGE);
+            }
+        }
+    }
+
+    /// The UI font at `size` pixels. Fonts are kept per size, so their glyph caches are reused.
+    pub fn get_font(&mut self, size: u32) -> Arc<Font> {
+        self.fonts.get(size)
+    }
+
+    pub fn fonts(&mut self) -> &mut FontLibrary {
+        &mut self.fonts
+    }
+
+    /// The width of the longest line of `text` and the height of all its lines.
+    pub fn get_text_size(&self, text: &str, font: &Font) -> (u32, u32) {
+        font.measure(text)
+    }
+
+    /// Draws `text` inside `rect`, one line per `\n`, each line aligned on its own.
+    /// Lines start at the top of `rect`; anything outside it is clipped.
+    pub fn draw_text(&mut self, text: &str, font: &Font, rect: Rect, color: Color, align: Align) {
+        self.push_clip(rect);
+        let clip = self.clip();
+        let mut baseline = rect.y as i64 + font.ascent() as i64;
+        for line in text.split('\n') {
+            if baseline - font.ascent() as i64 >= clip.bottom {
+                break;
+            }
+            let (glyphs, width) = font.layout_line(line);
+            let start = match align {
+                Align::Left => rect.x as f32,
+                Align::Center => rect.x as f32 + (rect.width as f32 - width) / 2.0,
+                Align::Right => rect.x as f32 + rect.width as f32 - width,
+            };
+            for (glyph, pen) in glyphs {
+                let left = (start + pen).round() as i64 + glyph.left as i64;
+                let top = baseline + glyph.top as i64;
+                let area = clip.intersect(&Bounds {
+                    left,
+                    top,
+                    right: left + glyph.width as i64,
+                    bottom: top + glyph.height as i64,
+                });
+                for y in area.top..area.bottom {
+                    for x in area.left..area.right {
+                        let index = (y - top) as usize * glyph.width as usize + (x - left) as usize;
+                        let alpha = color.a as u32 * glyph.coverage[index] as u32 / 255;
+                        let pixel = y as usize * self.width as usize + x as usize;
+                        self.pixels[pixel] = blend(self.pixels[pixel], color, alpha);
+                    }
+                }
+            }
+            baseline += font.line_height() as i64;
+        }
+        self.pop_clip();
+    }
+
+    /// Copies the canvas to the GPU's back buffer at (`x`, `y`) and flushes it to the screen.
+    /// Alpha is dropped: the canvas is expected to be opaque.
+    pub fn present(&self, gpu: &mut dyn Gpu, x: usize, y: usize) {
+        let opaque: Vec<u32> = self.pixels.iter().map(|pixel| pixel & 0x00FF_FFFF).collect();
+        gpu.blit(x, y, self.width as usize, &opaque);
+        gpu.flush(gpu::Rect::new(x, y, self.width as usize, self.height as usize));
+    }
+}
+
+// Number of samples of pixel (x, y) inside a convex shape
+fn coverage(x: i64, y: i64, inside: &impl Fn(i64, i64) -> bool) -> u32 {
+    let left = x * SCALE + 1;
+    let top = y * SCALE + 1;
+    let right = left + SCALE - 2;
+    let bottom = top + SCALE - 2;
+    // A convex shape that contains the corner samples contains all the others
+    if inside(left, top) && inside(right, top) && inside(left, bottom) && inside(right, bottom) {
+        return FULL_COVERAGE;
+    }
+    let mut count = 0;
+    for sy in 0..SUBSAMPLES {
+        for sx in 0..SUBSAMPLES {
+            if inside(left + 2 * sx, top + 2 * sy) {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+// Composite `color` over an ARGB pixel with alpha `source_alpha`, which the caller
+// has scaled by the pixel's coverage
+fn blend(dst: u32, color: Color, source_alpha: u32) -> u32 {
+    if source_alpha == 0 {
+        return dst;
+    }
+    if source_alpha == 255 {
+        return color.to_argb();
+    }
+    let dst = Color::from_argb(dst);
+    // Weights scaled by 255, so the division happens once at the end
+    let dst_weight = dst.a as u32 * (255 - source_alpha);
+    let total = source_alpha * 255 + dst_weight;
+    let mix = |source: u8, target: u8| {
+        ((source as u32 * source_alpha * 255 + target as u32 * dst_weight + total / 2) / total) as u8
+    };
+    Color::rgba(
+        mix(color.r, dst.r),
+        mix(color.g, dst.g),
+        mix(color.b, dst.b),
+        ((total + 127) / 255) as u8,
+    )
+    .to_argb()
+}
+
+// A circle in sample coordinates
+struct Circle {
+    cx: i64,
+    cy: i64,
+    radius: i64,
+    pixel_bounds: Bounds,
+}
+
+impl Circle {
+    fn new(cx: i32, cy: i32, radius: u32) -> Circle {
+        let (cx, cy, radius) = (cx as i64, cy as i64, radius as i64);
+        Circle {
+            // The center of pixel (cx, cy)
+            cx: cx * SCALE + SCALE / 2,
+            cy: cy * SCALE + SCALE / 2,
+            radius: radius * SCALE,
+            pixel_bounds: Bounds {
+                left: cx - radius,
+                top: cy - radius,
+                right: cx + radius + 1,
+                bottom: cy + radius + 1,
+            },
+        }
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.pixel_bounds
+    }
+
+    fn contains(&self, x: i64, y: i64) -> bool {
+        let (dx, dy) = (x - self.cx, y - self.cy);
+        dx * dx + dy * dy <= self.radius * self.radius
+    }
+}
+
+// A rectangle with circular corners, in sample coordinates
+struct RoundedRect {
+    left: i64,
+    top: i64,
+    right: i64,
+    bottom: i64,
+    radius: i64,
+}
+
+impl RoundedRect {
+    fn new(rect: Rect, radius: u32) -> RoundedRect {
+        // The corners cannot be larger than half the shorter side
+        let radius = radius.min(rect.width / 2).min(rect.height / 2);
+        let bounds = Bounds::from_rect(rect);
+        RoundedRect {
+            left: bounds.left * SCALE,
+            top: bounds.top * SCALE,
+            right: bounds.right * SCALE,
+            bottom: bounds.bottom * SCALE,
+            radius: radius as i64 * SCALE,
+        }
+    }
+
+    // The shape `thickness` pixels further in, with correspondingly smaller corners
+    fn inset(&self, thickness: u32) -> RoundedRect {
+        let inset = thickness as i64 * SCALE;
+        RoundedRect {
+            left: self.left + inset,
+            top: self.top + inset,
+            right: (self.right - inset).max(self.left + inset),
+            bottom: (self.bottom - inset).max(self.top + inset),
+            radius: (self.radius - inset).max(0),
+        }
+    }
+
+    fn contains(&self, x: i64, y: i64) -> bool {
+        if x < self.left || x >= self.right || y < self.top || y >= self.bottom {
+            return false;
+        }
+        // Samples never land exactly on a corner center, which is a multiple of SCALE
+        let cx = if x < self.left + self.radius {
+            self.left + self.radius
+        } else if x > self.right - self.radius {
+            self.right - self.radius
+        } else {
+            return true;
+        };
+        let cy = if y < self.top + self.radius {
+            self.top + self.radius
+        } else if y > self.bottom - self.radius {
+            self.bottom - self.radius
+        } else {
+            return true;
+        };
+        let (dx, dy) = (x - cx, y - cy);
+        dx * dx + dy * dy <= self.radius * self.radius
+    }
+}
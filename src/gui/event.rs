@@ -1,10 +1,16 @@
 use crate::components::Component;
+use crate::drivers::keyboard::KeyEvent;
+use crate::kernel::input::{self, EventFilter, InputEventKind, SubscriberId};
+use spin::Mutex;
+use std::collections::VecDeque;
 
 /// Represents the type of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventType {
     MouseDown,
     MouseUp,
     MouseMove,
+    MouseWheel,
     KeyDown,
     KeyUp,
 }
@@ -19,3 +25,94 @@ pub struct Event {
 pub trait EventHandler {
     fn handle_event(&mut self, event: Event);
 }
+
+/// A mouse button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+/// A mouse event in screen coordinates, before it has been routed to a component.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MouseEvent {
+    pub event_type: EventType,
+    pub x: i32,
+    pub y: i32,
+    /// The button that changed, for `MouseDown` and `MouseUp`.
+    pub button: Option<MouseButton>,
+    /// Wheel movement for `MouseWheel`; positive values are the wheel turned away
+    /// from the user, scrolling up.
+    pub wheel: i8,
+}
+
+/// Input waiting to be dispatched by the GUI.
+pub enum InputEvent {
+    Mouse(MouseEvent),
+    Key(KeyEvent),
+}
+
+/// The maximum number of events kept before the oldest are dropped.
+const EVENT_QUEUE_CAPACITY: usize = 256;
+
+/// Queue of input events produced by drivers and consumed by the GUI.
+pub struct EventQueue {
+    events: VecDeque<InputEvent>,
+}
+
+impl EventQueue {
+    pub const fn new() -> Self {
+        EventQueue {
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Adds an event, dropping the oldest one if the queue is full.
+    pub fn push(&mut self, event: InputEvent) {
+        if self.events.len() == EVENT_QUEUE_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Removes and returns the oldest event.
+    pub fn pop(&mut self) -> Option<InputEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+/// The GUI's global event queue.
+pub static EVENT_QUEUE: Mutex<EventQueue> = Mutex::new(EventQueue::new());
+
+/// Posts an event to the GUI event queue.
+pub fn post_event(event: InputEvent) {
+    EVENT_QUEUE.lock().push(event);
+}
+
+/// Takes the next event from the GUI event queue.
+pub fn poll_event() -> Option<InputEvent> {
+    EVENT_QUEUE.lock().pop()
+}
+
+/// Subscribes the GUI to keyboard and mouse events from the kernel input layer.
+pub fn subscribe_input() -> SubscriberId {
+    input::subscribe(EventFilter::all())
+}
+
+/// Moves pending events from the kernel input layer into the GUI event queue.
+pub fn pump_input(subscriber: SubscriberId) {
+    while let Some(event) = input::read(subscriber) {
+        let event = match event.kind {
+            InputEventKind::Key(key) => InputEvent::Key(key),
+            InputEventKind::Mouse(mouse) => InputEvent::Mouse(mouse),
+        };
+        post_event(event);
+    }
+}
@@ -0,0 +1,246 @@
+// BMP decoding
+// Windows and OS/2 bitmaps: 1, 4 and 8-bit palette images, uncompressed or RLE
+// compressed, and 16, 24 and 32-bit true color with default or explicit channel masks.
+
+use crate::gui::utils::color::Color;
+use crate::gui::utils::image::{Bitmap, ImageError, MAX_IMAGE_PIXELS};
+
+pub const SIGNATURE: &[u8] = b"BM";
+
+const FILE_HEADER_SIZE: usize = 14;
+// BITMAPCOREHEADER, from OS/2, with 16-bit sizes and 3-byte palette entries
+const CORE_HEADER_SIZE: usize = 12;
+// BITMAPINFOHEADER, and the versions after it that add fields at the end
+const INFO_HEADER_SIZE: usize = 40;
+// BITMAPV3INFOHEADER and later store an alpha mask after the color masks
+const V3_HEADER_SIZE: usize = 56;
+
+// Compression
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ImageError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ImageError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ImageError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ImageError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A color channel given by a bit mask, e.g. 0x7C00 for the red of 16-bit 5-5-5 pixels
+#[derive(Debug, Clone, Copy)]
+struct Mask {
+    mask: u32,
+    shift: u32,
+    max: u32,
+}
+
+impl Mask {
+    fn new(mask: u32) -> Mask {
+        let shift = if mask == 0 { 0 } else { mask.trailing_zeros() };
+        Mask {
+            mask,
+            shift,
+            max: mask >> shift,
+        }
+    }
+
+    // The channel of `pixel` scaled to 8 bits, or `missing` if there is no mask
+    fn extract(&self, pixel: u32, missing: u8) -> u8 {
+        if self.max == 0 {
+            return missing;
+        }
+        (((pixel & self.mask) >> self.shift) as u64 * 255 / self.max as u64) as u8
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Bitmap, ImageError> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(ImageError::UnknownFormat);
+    }
+    let pixel_offset = u32_at(data, 10)? as usize;
+    let header_size = u32_at(data, FILE_HEADER_SIZE)? as usize;
+    let info = FILE_HEADER_SIZE;
+
+    let (width, height, bit_count, compression) = if header_size == CORE_HEADER_SIZE {
+        (u16_at(data, info + 4)? as i64, u16_at(data, info + 6)? as i16 as i64, u16_at(data, info + 10)?, BI_RGB)
+    } else if header_size >= INFO_HEADER_SIZE {
+        (
+            u32_at(data, info + 4)? as i32 as i64,
+            u32_at(data, info + 8)? as i32 as i64,
+            u16_at(data, info + 14)?,
+            u32_at(data, info + 16)?,
+        )
+    } else {
+        return Err(ImageError::Unsupported("BMP header version"));
+    };
+    // Rows are stored bottom up, unless the height is negative
+    let top_down = height < 0;
+    let (width, height) = (width, height.abs());
+    if width <= 0 || height == 0 {
+        return Err(ImageError::Malformed("BMP size"));
+    }
+    let (width, height) = (width as usize, height as usize);
+    if width.saturating_mul(height) > MAX_IMAGE_PIXELS {
+        return Err(ImageError::TooLarge);
+    }
+
+    // Palette entries follow the header: BGR for OS/2, BGRX otherwise
+    let palette = if bit_count <= 8 {
+        let entry_size = if header_size == CORE_HEADER_SIZE { 3 } else { 4 };
+        let used = if header_size >= INFO_HEADER_SIZE { u32_at(data, info + 32)? as usize } else { 0 };
+        let count = if used == 0 || used > 1 << bit_count { 1 << bit_count } else { used };
+        let start = info + header_size;
+        let table = data.get(start..start + count * entry_size).ok_or(ImageError::Truncated)?;
+        table.chunks(entry_size).map(|bgr| Color::new(bgr[2], bgr[1], bgr[0])).collect()
+    } else {
+        Vec::new()
+    };
+
+    let pixels = data.get(pixel_offset..).ok_or(ImageError::Truncated)?;
+    // Indices or pixel values in rows from the top, which are then turned into colors
+    let mut image = vec![Color::rgba(0, 0, 0, 0); width * height];
+    let row_index = |row: usize| if top_down { row } else { height - 1 - row };
+
+    match (compression, bit_count) {
+        (BI_RLE8, 8) | (BI_RLE4, 4) => {
+            if top_down {
+                return Err(ImageError::Malformed("top-down RLE bitmap"));
+            }
+            // Pixels an RLE bitmap skips are transparent
+            let indices = decode_rle(pixels, width, height, bit_count)?;
+            for (row, line) in indices.chunks(width).enumerate() {
+                for (x, index) in line.iter().enumerate() {
+                    if let Some(index) = index {
+                        image[row_index(row) * width + x] = palette.get(*index as usize).copied().unwrap_or(Color::black());
+                    }
+                }
+            }
+        }
+        (BI_RGB, 1 | 4 | 8) => {
+            let stride = (width * bit_count as usize).div_ceil(32) * 4;
+            for row in 0..height {
+                let line = pixels.get(row * stride..(row + 1) * stride).ok_or(ImageError::Truncated)?;
+                for x in 0..width {
+                    let bit = x * bit_count as usize;
+                    let index = (line[bit / 8] >> (8 - bit_count as usize - bit % 8)) & ((1u16 << bit_count) - 1) as u8;
+                    image[row_index(row) * width + x] = palette.get(index as usize).copied().unwrap_or(Color::black());
+                }
+            }
+        }
+        (BI_RGB | BI_BITFIELDS | BI_ALPHABITFIELDS, 16 | 24 | 32) => {
+            let masks = channel_masks(data, info, header_size, compression, bit_count)?;
+            let bytes = bit_count as usize / 8;
+            let stride = (width * bytes).div_ceil(4) * 4;
+            let mut any_alpha = false;
+            for row in 0..height {
+                let line = pixels.get(row * stride..(row + 1) * stride).ok_or(ImageError::Truncated)?;
+                for x in 0..width {
+                    let mut value = 0u32;
+                    for (i, byte) in line[x * bytes..(x + 1) * bytes].iter().enumerate() {
+                        value |= (*byte as u32) << (8 * i);
+                    }
+                    let [r, g, b, a] = masks;
+                    let color = Color::rgba(r.extract(value, 0), g.extract(value, 0), b.extract(value, 0), a.extract(value, 255));
+                    any_alpha |= a.max != 0 && color.a != 0;
+                    image[row_index(row) * width + x] = color;
+                }
+            }
+            // Many writers leave the alpha byte zero; an image with no alpha at all is opaque
+            if !any_alpha {
+                for color in image.iter_mut() {
+                    color.a = 255;
+                }
+            }
+        }
+        _ => return Err(ImageError::Unsupported("BMP compression or bit count")),
+    }
+    Ok(Bitmap::new(width as u32, height as u32, image))
+}
+
+// Red, green, blue and alpha masks
+fn channel_masks(data: &[u8], info: usize, header_size: usize, compression: u32, bit_count: u16) -> Result<[Mask; 4], ImageError> {
+    if compression == BI_RGB {
+        // 16-bit pixels default to 5-5-5, 32-bit ones to BGRX
+        return Ok(match bit_count {
+            16 => [Mask::new(0x7C00), Mask::new(0x03E0), Mask::new(0x001F), Mask::new(0)],
+            _ => [Mask::new(0xFF_0000), Mask::new(0xFF00), Mask::new(0xFF), Mask::new(if bit_count == 32 { 0xFF00_0000 } else { 0 })],
+        });
+    }
+    // The masks follow a BITMAPINFOHEADER, or are part of the later headers
+    let masks = info + INFO_HEADER_SIZE;
+    let alpha = if compression == BI_ALPHABITFIELDS || header_size >= V3_HEADER_SIZE {
+        u32_at(data, masks + 12)?
+    } else {
+        0
+    };
+    Ok([
+        Mask::new(u32_at(data, masks)?),
+        Mask::new(u32_at(data, masks + 4)?),
+        Mask::new(u32_at(data, masks + 8)?),
+        Mask::new(alpha),
+    ])
+}
+
+// Expand RLE8 or RLE4 data into palette indices, rows from the bottom; pixels the
+// data skips over are None
+fn decode_rle(data: &[u8], width: usize, height: usize, bit_count: u16) -> Result<Vec<Option<u8>>, ImageError> {
+    let mut indices = vec![None; width * height];
+    let (mut x, mut y) = (0usize, 0usize);
+    let mut position = 0;
+    let mut put = |x: &mut usize, y: usize, index: u8| {
+        if *x < width && y < height {
+            indices[y * width + *x] = Some(index);
+        }
+        *x += 1;
+    };
+    let byte = |position: usize| data.get(position).copied().ok_or(ImageError::Truncated);
+
+    loop {
+        let (count, value) = (byte(position)?, byte(position + 1)?);
+        position += 2;
+        if count > 0 {
+            // A run of `count` pixels; RLE4 alternates the two nibbles of the value
+            for i in 0..count {
+                let index = if bit_count == 4 { if i % 2 == 0 { value >> 4 } else { value & 0x0F } } else { value };
+                put(&mut x, y, index);
+            }
+            continue;
+        }
+        match value {
+            0 => {
+                x = 0;
+                y += 1;
+            }
+            1 => break,
+            2 => {
+                x += byte(position)? as usize;
+                y += byte(position + 1)? as usize;
+                position += 2;
+            }
+            literal => {
+                // Literal pixels, padded to a whole number of 16-bit words
+                let bytes = if bit_count == 4 { (literal as usize).div_ceil(2) } else { literal as usize };
+                for i in 0..literal as usize {
+                    let index = if bit_count == 4 {
+                        let value = byte(position + i / 2)?;
+                        if i % 2 == 0 { value >> 4 } else { value & 0x0F }
+                    } else {
+                        byte(position + i)?
+                    };
+                    put(&mut x, y, index);
+                }
+                position += bytes.div_ceil(2) * 2;
+            }
+        }
+        if y >= height {
+            break;
+        }
+    }
+    Ok(indices)
+}
@@ -1,17 +1,55 @@
-pub const BLACK: Color = Color { r: 0, g: 0, b: 0 };
-pub const WHITE: Color = Color { r: 255, g: 255, b: 255 };
-pub const RED: Color = Color { r: 255, g: 0, b: 0 };
-pub const GREEN: Color = Color { r: 0, g: 255, b: 0 };
-pub const BLUE: Color = Color { r: 0, g: 0, b: 255 };
+pub const BLACK: Color = Color { r: 0, g: 0, b: 0, a: 255 };
+pub const WHITE: Color = Color { r: 255, g: 255, b: 255, a: 255 };
+pub const RED: Color = Color { r: 255, g: 0, b: 0, a: 255 };
+pub const GREEN: Color = Color { r: 0, g: 255, b: 0, a: 255 };
+pub const BLUE: Color = Color { r: 0, g: 0, b: 255, a: 255 };
+pub const TRANSPARENT: Color = Color { r: 0, g: 0, b: 0, a: 0 };
 
+// A color with straight (not premultiplied) alpha; 255 is opaque
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub struct Color {
-    r: u8,
-    g: u8,
-    b: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
 }
 
 impl Color {
     pub fn new(r: u8, g: u8, b: u8) -> Self {
-        Self { r, g, b }
+        Self { r, g, b, a: 255 }
+    }
+
+    pub fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        Self::new(r, g, b)
+    }
+
+    pub fn white() -> Self {
+        WHITE
+    }
+
+    pub fn black() -> Self {
+        BLACK
+    }
+
+    pub fn dark_gray() -> Self {
+        Self::new(64, 64, 64)
+    }
+
+    pub fn with_alpha(self, a: u8) -> Self {
+        Self { a, ..self }
+    }
+
+    // Pack as 0xAARRGGBB
+    pub fn to_argb(self) -> u32 {
+        u32::from_be_bytes([self.a, self.r, self.g, self.b])
+    }
+
+    pub fn from_argb(argb: u32) -> Self {
+        let [a, r, g, b] = argb.to_be_bytes();
+        Self { r, g, b, a }
     }
 }
@@ -1,17 +1,1017 @@
-pub const BLACK: Color = Color { r: 0, g: 0, b: 0 };
-pub const WHITE: Color = Color { r: 255, g: 255, b: 255 };
-pub const RED: Color = Color { r: 255, g: 0, b: 0 };
-pub const GREEN: Color = Color { r: 0, g: 255, b: 0 };
-pub const BLUE: Color = Color { r: 0, g: 0, b: 255 };
+// Fonts
+// Two kinds of fonts are supported: PSF bitmap fonts, the format of the Linux
+// console, and TrueType outline fonts, which are rasterized with antialiasing. A
+// `Font` is a face at one pixel size and caches the glyphs it has rendered. An 8x16
+// console font and a sans serif UI font are built in; see `src/gui/fonts`.
 
-pub struct Color {
-    r: u8,
-    g: u8,
-    b: u8,
+use spin::{Mutex, Once};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub const BUILTIN_CONSOLE_FONT: &[u8] = include_bytes!("../fonts/console-8x16.psf");
+pub const BUILTIN_SANS_FONT: &[u8] = include_bytes!("../fonts/sans.ttf");
+
+// Size of the UI font when none is given
+pub const DEFAULT_FONT_SIZE: u32 = 16;
+
+// PSF headers
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02 | 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+// TrueType
+const SFNT_VERSION_TRUETYPE: u32 = 0x0001_0000;
+const SFNT_VERSION_APPLE: u32 = 0x7472_7565;
+const SFNT_VERSION_CFF: u32 = 0x4F54_544F;
+
+// Simple glyph point flags
+const ON_CURVE: u8 = 0x01;
+const X_SHORT: u8 = 0x02;
+const Y_SHORT: u8 = 0x04;
+const REPEAT: u8 = 0x08;
+const X_SAME_OR_POSITIVE: u8 = 0x10;
+const Y_SAME_OR_POSITIVE: u8 = 0x20;
+
+// Composite glyph component flags
+const ARGS_ARE_WORDS: u16 = 0x0001;
+const ARGS_ARE_XY_VALUES: u16 = 0x0002;
+const HAVE_SCALE: u16 = 0x0008;
+const MORE_COMPONENTS: u16 = 0x0020;
+const HAVE_XY_SCALE: u16 = 0x0040;
+const HAVE_TWO_BY_TWO: u16 = 0x0080;
+
+// Composite glyphs nest; deeper nesting than this is taken as a malformed font
+const MAX_COMPONENT_DEPTH: usize = 8;
+
+// Characters drawn in place of ones the font lacks, in order of preference
+const REPLACEMENT_CHARACTERS: [char; 2] = ['\u{FFFD}', '?'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FontError {
+    Truncated,
+    BadMagic,
+    MissingTable(&'static str),
+    Unsupported(&'static str),
+    Malformed(&'static str),
+}
+
+fn u8_at(data: &[u8], offset: usize) -> Result<u8, FontError> {
+    data.get(offset).copied().ok_or(FontError::Truncated)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, FontError> {
+    let bytes = data.get(offset..offset + 2).ok_or(FontError::Truncated)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn i16_at(data: &[u8], offset: usize) -> Result<i16, FontError> {
+    Ok(u16_at(data, offset)? as i16)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, FontError> {
+    let bytes = data.get(offset..offset + 4).ok_or(FontError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn u32_le_at(data: &[u8], offset: usize) -> Result<u32, FontError> {
+    let bytes = data.get(offset..offset + 4).ok_or(FontError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A PSF (version 1 or 2) bitmap font
+pub struct BitmapFace {
+    width: u32,
+    height: u32,
+    bytes_per_row: usize,
+    glyph_size: usize,
+    glyphs: Vec<u8>,
+    map: HashMap<char, usize>,
+}
+
+impl BitmapFace {
+    pub fn parse(data: &[u8]) -> Result<BitmapFace, FontError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(data: &[u8]) -> Result<BitmapFace, FontError> {
+        let mode = u8_at(data, 2)?;
+        let height = u8_at(data, 3)? as usize;
+        let count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs = data.get(4..4 + count * height).ok_or(FontError::Truncated)?.to_vec();
+
+        let mut map = HashMap::new();
+        if mode & PSF1_MODE_HAS_TABLE != 0 {
+            // Each glyph's code points as UCS-2, terminated by 0xFFFF; sequences of
+            // combining characters after 0xFFFE are skipped
+            let mut offset = 4 + count * height;
+            for glyph in 0..count {
+                let mut in_sequence = false;
+                loop {
+                    let value = u16::from_le_bytes([u8_at(data, offset)?, u8_at(data, offset + 1)?]);
+                    offset += 2;
+                    match value {
+                        PSF1_SEPARATOR => break,
+                        PSF1_START_SEQUENCE => in_sequence = true,
+                        _ if !in_sequence => {
+                            if let Some(c) = char::from_u32(value as u32) {
+                                map.entry(c).or_insert(glyph);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        } else {
+            map = identity_map(count);
+        }
+
+        Ok(BitmapFace {
+            width: 8,
+            height: height as u32,
+            bytes_per_row: 1,
+            glyph_size: height,
+            glyphs,
+            map,
+        })
+    }
+
+    fn parse_psf2(data: &[u8]) -> Result<BitmapFace, FontError> {
+        let header_size = u32_le_at(data, 8)? as usize;
+        let flags = u32_le_at(data, 12)?;
+        let count = u32_le_at(data, 16)? as usize;
+        let glyph_size = u32_le_at(data, 20)? as usize;
+        let height = u32_le_at(data, 24)?;
+        let width = u32_le_at(data, 28)?;
+        let bytes_per_row = (width as usize).div_ceil(8);
+        if width == 0 || height == 0 || glyph_size < bytes_per_row * height as usize {
+            return Err(FontError::Malformed("glyph size"));
+        }
+        let table_start = header_size + count * glyph_size;
+        let glyphs = data.get(header_size..table_start).ok_or(FontError::Truncated)?.to_vec();
+
+        let mut map = HashMap::new();
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            // Each glyph's code points as UTF-8, terminated by 0xFF; sequences of
+            // combining characters after 0xFE are skipped
+            let mut entries = data[table_start.min(data.len())..].split(|byte| *byte == PSF2_SEPARATOR);
+            for glyph in 0..count {
+                let entry = entries.next().ok_or(FontError::Truncated)?;
+                let singles = entry.split(|byte| *byte == PSF2_START_SEQUENCE).next().unwrap_or(&[]);
+                for c in String::from_utf8_lossy(singles).chars() {
+                    if c != char::REPLACEMENT_CHARACTER || singles.starts_with("\u{FFFD}".as_bytes()) {
+                        map.entry(c).or_insert(glyph);
+                    }
+                }
+            }
+        } else {
+            map = identity_map(count);
+        }
+
+        Ok(BitmapFace {
+            width,
+            height,
+            bytes_per_row,
+            glyph_size,
+            glyphs,
+            map,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn glyph_index(&self, c: char) -> Option<usize> {
+        self.map.get(&c).copied()
+    }
+
+    // Whether pixel (x, y) of a glyph is set
+    pub fn pixel(&self, glyph: usize, x: u32, y: u32) -> bool {
+        let row = glyph * self.glyph_size + y as usize * self.bytes_per_row;
+        self.glyphs[row + x as usize / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+// Without a Unicode table, glyph n is taken to be the character n
+fn identity_map(count: usize) -> HashMap<char, usize> {
+    (0..count as u32).filter_map(|code| Some((char::from_u32(code)?, code as usize))).collect()
+}
+
+// A point of a glyph outline; `on_curve` is false for quadratic control points
+#[derive(Debug, Clone, Copy)]
+struct OutlinePoint {
+    x: f32,
+    y: f32,
+    on_curve: bool,
+}
+
+type Contour = Vec<OutlinePoint>;
+
+// A TrueType font, with glyph outlines in the `glyf` table
+pub struct TrueTypeFace {
+    data: Vec<u8>,
+    units_per_em: u16,
+    ascent: i16,
+    descent: i16,
+    line_gap: i16,
+    glyph_count: u16,
+    metric_count: u16,
+    long_offsets: bool,
+    loca: usize,
+    glyf: usize,
+    glyf_len: usize,
+    hmtx: usize,
+    cmap: HashMap<char, u16>,
+    kerning: HashMap<(u16, u16), i16>,
 }
 
-impl Color {
-    pub fn new(r: u8, g: u8, b: u8) -> Self {
-        Self { r, g, b }
+impl TrueTypeFace {
+    pub fn parse(data: Vec<u8>) -> Result<TrueTypeFace, FontError> {
+        match u32_at(&data, 0)? {
+            SFNT_VERSION_TRUETYPE | SFNT_VERSION_APPLE => {}
+            SFNT_VERSION_CFF => return Err(FontError::Unsupported("CFF outlines")),
+            _ => return Err(FontError::BadMagic),
+        }
+
+        let mut tables = HashMap::new();
+        for i in 0..u16_at(&data, 4)? as usize {
+            let record = 12 + i * 16;
+            let tag = data.get(record..record + 4).ok_or(FontError::Truncated)?;
+            let offset = u32_at(&data, record + 8)? as usize;
+            let length = u32_at(&data, record + 12)? as usize;
+            if offset + length > data.len() {
+                return Err(FontError::Truncated);
+            }
+            tables.insert([tag[0], tag[1], tag[2], tag[3]], (offset, length));
+        }
+        let table = |tag: &'static str| -> Result<(usize, usize), FontError> {
+            let key = [tag.as_bytes()[0], tag.as_bytes()[1], tag.as_bytes()[2], tag.as_bytes()[3]];
+            tables.get(&key).copied().ok_or(FontError::MissingTable(tag))
+        };
+
+        let (head, _) = table("head")?;
+        let (hhea, _) = table("hhea")?;
+        let (maxp, _) = table("maxp")?;
+        let (loca, _) = table("loca")?;
+        let (glyf, glyf_len) = table("glyf")?;
+        let (hmtx, _) = table("hmtx")?;
+        let (cmap, cmap_len) = table("cmap")?;
+
+        let units_per_em = u16_at(&data, head + 18)?;
+        if units_per_em == 0 {
+            return Err(FontError::Malformed("units per em"));
+        }
+        let cmap = parse_cmap(&data[cmap..cmap + cmap_len])?;
+        let kerning = match table("kern") {
+            Ok((kern, kern_len)) => parse_kern(&data[kern..kern + kern_len])?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(TrueTypeFace {
+            units_per_em,
+            ascent: i16_at(&data, hhea + 4)?,
+            descent: i16_at(&data, hhea + 6)?,
+            line_gap: i16_at(&data, hhea + 8)?,
+            metric_count: u16_at(&data, hhea + 34)?.max(1),
+            glyph_count: u16_at(&data, maxp + 4)?,
+            long_offsets: i16_at(&data, head + 50)? != 0,
+            loca,
+            glyf,
+            glyf_len,
+            hmtx,
+            cmap,
+            kerning,
+            data,
+        })
+    }
+
+    pub fn units_per_em(&self) -> u16 {
+        self.units_per_em
+    }
+
+    pub fn glyph_index(&self, c: char) -> Option<u16> {
+        self.cmap.get(&c).copied()
+    }
+
+    // Advance width of a glyph in font units
+    pub fn advance(&self, glyph: u16) -> u16 {
+        let index = glyph.min(self.metric_count - 1) as usize;
+        u16_at(&self.data, self.hmtx + index * 4).unwrap_or(0)
+    }
+
+    // Adjustment between two glyphs in font units, usually negative
+    pub fn kerning(&self, left: u16, right: u16) -> i16 {
+        self.kerning.get(&(left, right)).copied().unwrap_or(0)
+    }
+
+    fn glyph_data(&self, glyph: u16) -> Result<&[u8], FontError> {
+        if glyph >= self.glyph_count {
+            return Err(FontError::Malformed("glyph index"));
+        }
+        let (start, end) = if self.long_offsets {
+            let entry = self.loca + glyph as usize * 4;
+            (u32_at(&self.data, entry)? as usize, u32_at(&self.data, entry + 4)? as usize)
+        } else {
+            let entry = self.loca + glyph as usize * 2;
+            (u16_at(&self.data, entry)? as usize * 2, u16_at(&self.data, entry + 2)? as usize * 2)
+        };
+        if start > end || end > self.glyf_len {
+            return Err(FontError::Malformed("glyph offset"));
+        }
+        Ok(&self.data[self.glyf + start..self.glyf + end])
+    }
+
+    // The contours of a glyph in font units, y up
+    fn outline(&self, glyph: u16, depth: usize) -> Result<Vec<Contour>, FontError> {
+        if depth > MAX_COMPONENT_DEPTH {
+            return Err(FontError::Malformed("composite glyph nesting"));
+        }
+        let data = self.glyph_data(glyph)?;
+        if data.is_empty() {
+            // Glyphs without outlines, such as the space
+            return Ok(Vec::new());
+        }
+        let contour_count = i16_at(data, 0)?;
+        if contour_count >= 0 {
+            parse_simple_glyph(data, contour_count as usize)
+        } else {
+            self.parse_composite_glyph(data, depth)
+        }
+    }
+
+    fn parse_composite_glyph(&self, data: &[u8], depth: usize) -> Result<Vec<Contour>, FontError> {
+        let mut contours = Vec::new();
+        let mut offset = 10;
+        loop {
+            let flags = u16_at(data, offset)?;
+            let component = u16_at(data, offset + 2)?;
+            offset += 4;
+            let (dx, dy) = if flags & ARGS_ARE_WORDS != 0 {
+                offset += 4;
+                (i16_at(data, offset - 4)? as f32, i16_at(data, offset - 2)? as f32)
+            } else {
+                offset += 2;
+                (u8_at(data, offset - 2)? as i8 as f32, u8_at(data, offset - 1)? as i8 as f32)
+            };
+            let fixed = |offset: usize| -> Result<f32, FontError> { Ok(i16_at(data, offset)? as f32 / 16384.0) };
+            let (a, b, c, d) = if flags & HAVE_SCALE != 0 {
+                offset += 2;
+                let scale = fixed(offset - 2)?;
+                (scale, 0.0, 0.0, scale)
+            } else if flags & HAVE_XY_SCALE != 0 {
+                offset += 4;
+                (fixed(offset - 4)?, 0.0, 0.0, fixed(offset - 2)?)
+            } else if flags & HAVE_TWO_BY_TWO != 0 {
+                offset += 8;
+                (fixed(offset - 8)?, fixed(offset - 6)?, fixed(offset - 4)?, fixed(offset - 2)?)
+            } else {
+                (1.0, 0.0, 0.0, 1.0)
+            };
+
+            // Components positioned by matching points are rare and not supported
+            if flags & ARGS_ARE_XY_VALUES != 0 {
+                for contour in self.outline(component, depth + 1)? {
+                    contours.push(
+                        contour
+                            .iter()
+                            .map(|point| OutlinePoint {
+                                x: a * point.x + c * point.y + dx,
+                                y: b * point.x + d * point.y + dy,
+                                on_curve: point.on_curve,
+                            })
+                            .collect(),
+                    );
+                }
+            }
+            if flags & MORE_COMPONENTS == 0 {
+                return Ok(contours);
+            }
+        }
+    }
+}
+
+fn parse_simple_glyph(data: &[u8], contour_count: usize) -> Result<Vec<Contour>, FontError> {
+    let mut ends = Vec::with_capacity(contour_count);
+    for i in 0..contour_count {
+        ends.push(u16_at(data, 10 + i * 2)? as usize);
+    }
+    let point_count = match ends.last() {
+        Some(last) => last + 1,
+        None => return Ok(Vec::new()),
+    };
+    // Hinting instructions are skipped
+    let instructions = 10 + contour_count * 2;
+    let mut offset = instructions + 2 + u16_at(data, instructions)? as usize;
+
+    let mut flags = Vec::with_capacity(point_count);
+    while flags.len() < point_count {
+        let flag = u8_at(data, offset)?;
+        offset += 1;
+        flags.push(flag);
+        if flag & REPEAT != 0 {
+            let repeat = u8_at(data, offset)?;
+            offset += 1;
+            flags.extend(std::iter::repeat_n(flag, repeat as usize));
+        }
+    }
+    flags.truncate(point_count);
+
+    // Coordinates are deltas, either a byte with a sign flag or a signed word
+    let mut read_coordinates = |short: u8, same_or_positive: u8| -> Result<Vec<f32>, FontError> {
+        let mut value = 0i32;
+        let mut values = Vec::with_capacity(point_count);
+        for flag in flags.iter() {
+            if flag & short != 0 {
+                let delta = u8_at(data, offset)? as i32;
+                offset += 1;
+                value += if flag & same_or_positive != 0 { delta } else { -delta };
+            } else if flag & same_or_positive == 0 {
+                value += i16_at(data, offset)? as i32;
+                offset += 2;
+            }
+            values.push(value as f32);
+        }
+        Ok(values)
+    };
+    let xs = read_coordinates(X_SHORT, X_SAME_OR_POSITIVE)?;
+    let ys = read_coordinates(Y_SHORT, Y_SAME_OR_POSITIVE)?;
+
+    let mut contours = Vec::with_capacity(contour_count);
+    let mut start = 0;
+    for end in ends {
+        if end < start || end >= point_count {
+            return Err(FontError::Malformed("contour end"));
+        }
+        contours.push(
+            (start..=end)
+                .map(|i| OutlinePoint {
+                    x: xs[i],
+                    y: ys[i],
+                    on_curve: flags[i] & ON_CURVE != 0,
+                })
+                .collect(),
+        );
+        start = end + 1;
+    }
+    Ok(contours)
+}
+
+// Map characters to glyphs, from the best Unicode subtable of the `cmap` table
+fn parse_cmap(cmap: &[u8]) -> Result<HashMap<char, u16>, FontError> {
+    let mut best: Option<(u32, usize)> = None;
+    for i in 0..u16_at(cmap, 2)? as usize {
+        let platform = u16_at(cmap, 4 + i * 8)?;
+        let encoding = u16_at(cmap, 6 + i * 8)?;
+        let offset = u32_at(cmap, 8 + i * 8)? as usize;
+        let format = u16_at(cmap, offset)?;
+        // Prefer full Unicode (format 12) over the Basic Multilingual Plane (format 4)
+        let rank = match (platform, encoding, format) {
+            (3, 10, 12) | (0, _, 12) => 3,
+            (3, 1, 4) => 2,
+            (0, _, 4) => 1,
+            _ => continue,
+        };
+        if best.is_none_or(|(best_rank, _)| rank > best_rank) {
+            best = Some((rank, offset));
+        }
+    }
+    let (_, offset) = best.ok_or(FontError::Unsupported("no Unicode character map"))?;
+    let subtable = &cmap[offset..];
+
+    let mut map = HashMap::new();
+    if u16_at(subtable, 0)? == 12 {
+        for group in 0..u32_at(subtable, 12)? as usize {
+            let record = 16 + group * 12;
+            let start = u32_at(subtable, record)?;
+            let end = u32_at(subtable, record + 4)?.min(char::MAX as u32);
+            let first_glyph = u32_at(subtable, record + 8)?;
+            for code in start..=end {
+                if let Some(c) = char::from_u32(code) {
+                    map.insert(c, (first_glyph + (code - start)) as u16);
+                }
+            }
+        }
+        return Ok(map);
+    }
+
+    let segments = u16_at(subtable, 6)? as usize / 2;
+    let ends = 14;
+    let starts = ends + segments * 2 + 2;
+    let deltas = starts + segments * 2;
+    let range_offsets = deltas + segments * 2;
+    for segment in 0..segments {
+        let end = u16_at(subtable, ends + segment * 2)?;
+        let start = u16_at(subtable, starts + segment * 2)?;
+        let delta = u16_at(subtable, deltas + segment * 2)?;
+        let range_offset = u16_at(subtable, range_offsets + segment * 2)? as usize;
+        for code in start..=end {
+            if code == 0xFFFF {
+                break;
+            }
+            let glyph = if range_offset == 0 {
+                code.wrapping_add(delta)
+            } else {
+                // The offset is relative to its own position in the array
+                let entry = range_offsets + segment * 2 + range_offset + (code - start) as usize * 2;
+                match u16_at(subtable, entry)? {
+                    0 => 0,
+                    glyph => glyph.wrapping_add(delta),
+                }
+            };
+            if let Some(c) = char::from_u32(code as u32) {
+                if glyph != 0 {
+                    map.insert(c, glyph);
+                }
+            }
+        }
+    }
+    Ok(map)
+}
+
+// Read the horizontal pairs of format 0 subtables of the `kern` table
+fn parse_kern(kern: &[u8]) -> Result<HashMap<(u16, u16), i16>, FontError> {
+    let mut pairs = HashMap::new();
+    let mut offset = 4;
+    for _ in 0..u16_at(kern, 2)? {
+        let length = u16_at(kern, offset + 2)? as usize;
+        let coverage = u16_at(kern, offset + 4)?;
+        // Format in the high byte; bit 0 set for horizontal kerning
+        if coverage >> 8 == 0 && coverage & 1 != 0 {
+            for pair in 0..u16_at(kern, offset + 6)? as usize {
+                let record = offset + 14 + pair * 6;
+                let left = u16_at(kern, record)?;
+                let right = u16_at(kern, record + 2)?;
+                pairs.insert((left, right), i16_at(kern, record + 4)?);
+            }
+        }
+        if length == 0 {
+            break;
+        }
+        offset += length;
+    }
+    Ok(pairs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+// Turn contours into line segments, approximating each quadratic curve closely
+// enough that the error stays well below a pixel
+fn flatten(contours: &[Contour], transform: impl Fn(&OutlinePoint) -> Point) -> Vec<(Point, Point)> {
+    let mut lines = Vec::new();
+    for contour in contours {
+        if contour.len() < 2 {
+            continue;
+        }
+        let points: Vec<(Point, bool)> = contour.iter().map(|point| (transform(point), point.on_curve)).collect();
+        // Start on a point on the curve; between two control points there is an implied one
+        let (first, rest): (Point, Vec<(Point, bool)>) = match points.iter().position(|(_, on)| *on) {
+            Some(start) => (
+                points[start].0,
+                points[start + 1..].iter().chain(points[..=start].iter()).copied().collect(),
+            ),
+            None => (
+                midpoint(points[0].0, points[1].0),
+                points[1..].iter().chain(points[..1].iter()).copied().collect(),
+            ),
+        };
+
+        let mut current = first;
+        let mut control: Option<Point> = None;
+        for (point, on_curve) in rest {
+            if on_curve {
+                match control.take() {
+                    Some(control) => quadratic(&mut lines, current, control, point),
+                    None => lines.push((current, point)),
+                }
+                current = point;
+            } else {
+                if let Some(previous) = control {
+                    let middle = midpoint(previous, point);
+                    quadratic(&mut lines, current, previous, middle);
+                    current = middle;
+                }
+                control = Some(point);
+            }
+        }
+        match control {
+            Some(control) => quadratic(&mut lines, current, control, first),
+            None if current != first => lines.push((current, first)),
+            None => {}
+        }
+    }
+    lines
+}
+
+fn midpoint(a: Point, b: Point) -> Point {
+    Point {
+        x: (a.x + b.x) / 2.0,
+        y: (a.y + b.y) / 2.0,
+    }
+}
+
+fn quadratic(lines: &mut Vec<(Point, Point)>, p0: Point, p1: Point, p2: Point) {
+    let ddx = p0.x - 2.0 * p1.x + p2.x;
+    let ddy = p0.y - 2.0 * p1.y + p2.y;
+    let deviation = ddx * ddx + ddy * ddy;
+    if deviation < 0.333 {
+        lines.push((p0, p2));
+        return;
+    }
+    let steps = 1 + (3.0 * deviation).sqrt().sqrt().floor() as usize;
+    let mut previous = p0;
+    for step in 1..=steps {
+        let t = step as f32 / steps as f32;
+        let mt = 1.0 - t;
+        let point = Point {
+            x: mt * mt * p0.x + 2.0 * mt * t * p1.x + t * t * p2.x,
+            y: mt * mt * p0.y + 2.0 * mt * t * p1.y + t * t * p2.y,
+        };
+        lines.push((previous, point));
+        previous = point;
+    }
+}
+
+// Exact-area coverage of a polygon: every edge adds the signed area it covers to
+// an accumulation buffer, and a running sum over each row yields the coverage
+struct Rasterizer {
+    width: usize,
+    height: usize,
+    accumulation: Vec<f32>,
+}
+
+impl Rasterizer {
+    fn new(width: usize, height: usize) -> Rasterizer {
+        Rasterizer {
+            width,
+            height,
+            // The last edge of a row may spill into the cell after it
+            accumulation: vec![0.0; width * height + 2],
+        }
+    }
+
+    fn draw_line(&mut self, p0: Point, p1: Point) {
+        if (p0.y - p1.y).abs() <= f32::EPSILON {
+            return;
+        }
+        let (direction, p0, p1) = if p0.y < p1.y { (1.0, p0, p1) } else { (-1.0, p1, p0) };
+        let dxdy = (p1.x - p0.x) / (p1.y - p0.y);
+        let mut x = p0.x;
+        if p0.y < 0.0 {
+            x -= p0.y * dxdy;
+        }
+        let first_row = p0.y.max(0.0) as usize;
+        let last_row = (p1.y.ceil().max(0.0) as usize).min(self.height);
+        for y in first_row..last_row {
+            let row = y * self.width;
+            let dy = ((y + 1) as f32).min(p1.y) - (y as f32).max(p0.y);
+            let x_next = x + dxdy * dy;
+            let d = dy * direction;
+            let (x0, x1) = if x < x_next { (x, x_next) } else { (x_next, x) };
+            let x0_floor = x0.floor();
+            let x0i = x0_floor as usize;
+            let x1_ceil = x1.ceil();
+            let x1i = x1_ceil as usize;
+            if x1i <= x0i + 1 {
+                // The edge stays within one pixel of this row
+                let middle = 0.5 * (x + x_next) - x0_floor;
+                self.accumulation[row + x0i] += d - d * middle;
+                self.accumulation[row + x0i + 1] += d * middle;
+            } else {
+                let slope = (x1 - x0).recip();
+                let x0_fraction = x0 - x0_floor;
+                let a0 = 0.5 * slope * (1.0 - x0_fraction) * (1.0 - x0_fraction);
+                let x1_fraction = x1 - x1_ceil + 1.0;
+                let am = 0.5 * slope * x1_fraction * x1_fraction;
+                self.accumulation[row + x0i] += d * a0;
+                if x1i == x0i + 2 {
+                    self.accumulation[row + x0i + 1] += d * (1.0 - a0 - am);
+                } else {
+                    let a1 = slope * (1.5 - x0_fraction);
+                    self.accumulation[row + x0i + 1] += d * (a1 - a0);
+                    for xi in x0i + 2..x1i - 1 {
+                        self.accumulation[row + xi] += d * slope;
+                    }
+                    let a2 = a1 + (x1i - x0i - 3) as f32 * slope;
+                    self.accumulation[row + x1i - 1] += d * (1.0 - a2 - am);
+                }
+                self.accumulation[row + x1i] += d * am;
+            }
+            x = x_next;
+        }
+    }
+
+    // Coverage of every pixel, 0 to 255
+    fn coverage(&self) -> Vec<u8> {
+        let mut sum = 0.0;
+        self.accumulation[..self.width * self.height]
+            .iter()
+            .map(|value| {
+                sum += value;
+                (sum.abs().min(1.0) * 255.0 + 0.5) as u8
+            })
+            .collect()
+    }
+}
+
+// A rendered glyph: an alpha mask and where to put it relative to the pen position
+// on the baseline
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glyph {
+    pub width: u32,
+    pub height: u32,
+    // Offset of the mask's left column from the pen position
+    pub left: i32,
+    // Offset of the mask's top row from the baseline; negative is above it
+    pub top: i32,
+    // Horizontal distance to the next pen position
+    pub advance: f32,
+    pub coverage: Vec<u8>,
+}
+
+impl Glyph {
+    fn empty(advance: f32) -> Glyph {
+        Glyph {
+            width: 0,
+            height: 0,
+            left: 0,
+            top: 0,
+            advance,
+            coverage: Vec::new(),
+        }
+    }
+}
+
+enum Face {
+    // Glyphs are scaled up by a whole number for larger sizes
+    Bitmap { face: Arc<BitmapFace>, scale: u32 },
+    TrueType { face: Arc<TrueTypeFace>, scale: f32 },
+}
+
+// A face at one size, with the glyphs rendered so far
+pub struct Font {
+    face: Face,
+    size: u32,
+    cache: Mutex<HashMap<char, Arc<Glyph>>>,
+}
+
+impl Font {
+    pub fn bitmap(face: Arc<BitmapFace>, scale: u32) -> Font {
+        let scale = scale.max(1);
+        Font {
+            size: face.height() * scale,
+            face: Face::Bitmap { face, scale },
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // `size` is the em size in pixels
+    pub fn truetype(face: Arc<TrueTypeFace>, size: u32) -> Font {
+        Font {
+            face: Face::TrueType {
+                scale: size as f32 / face.units_per_em() as f32,
+                face,
+            },
+            size,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    // Pixels from the baseline to the top of the tallest glyphs
+    pub fn ascent(&self) -> i32 {
+        match &self.face {
+            // PSF fonts do not record their baseline; a quarter of the cell is for descenders
+            Face::Bitmap { face, scale } => (face.height() - face.height() / 4) as i32 * *scale as i32,
+            Face::TrueType { face, scale } => (face.ascent as f32 * scale).ceil() as i32,
+        }
+    }
+
+    // Pixels from the baseline to the bottom of the lowest glyphs
+    pub fn descent(&self) -> i32 {
+        match &self.face {
+            Face::Bitmap { face, scale } => (face.height() / 4) as i32 * *scale as i32,
+            Face::TrueType { face, scale } => (-(face.descent as f32) * scale).ceil() as i32,
+        }
+    }
+
+    // Distance between the baselines of two lines
+    pub fn line_height(&self) -> u32 {
+        let gap = match &self.face {
+            Face::Bitmap { .. } => 0,
+            Face::TrueType { face, scale } => (face.line_gap.max(0) as f32 * scale).round() as i32,
+        };
+        (self.ascent() + self.descent() + gap) as u32
+    }
+
+    pub fn has_glyph(&self, c: char) -> bool {
+        match &self.face {
+            Face::Bitmap { face, .. } => face.glyph_index(c).is_some(),
+            Face::TrueType { face, .. } => face.glyph_index(c).is_some(),
+        }
+    }
+
+    // The glyph for `c`, or the replacement glyph if the font lacks it
+    pub fn glyph(&self, c: char) -> Arc<Glyph> {
+        if let Some(glyph) = self.cache.lock().get(&c) {
+            return glyph.clone();
+        }
+        let glyph = Arc::new(self.render(self.resolve(c)));
+        self.cache.lock().insert(c, glyph.clone());
+        glyph
+    }
+
+    fn resolve(&self, c: char) -> char {
+        if self.has_glyph(c) || c.is_control() {
+            return c;
+        }
+        REPLACEMENT_CHARACTERS.iter().copied().find(|c| self.has_glyph(*c)).unwrap_or(c)
+    }
+
+    fn render(&self, c: char) -> Glyph {
+        match &self.face {
+            Face::Bitmap { face, scale } => {
+                let advance = (face.width() * scale) as f32;
+                let index = match face.glyph_index(c) {
+                    Some(index) => index,
+                    None => return Glyph::empty(advance),
+                };
+                let (width, height) = (face.width() * scale, face.height() * scale);
+                let coverage = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x, y)))
+                    .map(|(x, y)| if face.pixel(index, x / scale, y / scale) { 255 } else { 0 })
+                    .collect();
+                Glyph {
+                    width,
+                    height,
+                    left: 0,
+                    top: -self.ascent(),
+                    advance,
+                    coverage,
+                }
+            }
+            Face::TrueType { face, scale } => {
+                let index = face.glyph_index(c).unwrap_or(0);
+                let advance = face.advance(index) as f32 * scale;
+                // A malformed glyph is drawn as blank rather than failing the whole text
+                let contours = face.outline(index, 0).unwrap_or_default();
+                render_outline(&contours, *scale, advance)
+            }
+        }
+    }
+
+    // Adjustment in pixels between two adjacent characters
+    pub fn kerning(&self, left: char, right: char) -> f32 {
+        match &self.face {
+            Face::Bitmap { .. } => 0.0,
+            Face::TrueType { face, scale } => match (face.glyph_index(left), face.glyph_index(right)) {
+                (Some(left), Some(right)) => face.kerning(left, right) as f32 * scale,
+                _ => 0.0,
+            },
+        }
+    }
+
+    // Glyphs of one line of text with their pen positions, and the line's width
+    pub fn layout_line(&self, line: &str) -> (Vec<(Arc<Glyph>, f32)>, f32) {
+        let mut pen = 0.0;
+        let mut previous: Option<char> = None;
+        let mut glyphs = Vec::with_capacity(line.len());
+        for c in line.chars() {
+            if let Some(previous) = previous {
+                pen += self.kerning(previous, c);
+            }
+            let glyph = self.glyph(c);
+            let advance = glyph.advance;
+            glyphs.push((glyph, pen));
+            pen += advance;
+            previous = Some(c);
+        }
+        (glyphs, pen)
+    }
+
+    // Size of a block of text: the width of its longest line, and the height of its lines
+    pub fn measure(&self, text: &str) -> (u32, u32) {
+        let mut width: f32 = 0.0;
+        let mut lines = 0;
+        for line in text.split('\n') {
+            width = width.max(self.layout_line(line).1);
+            lines += 1;
+        }
+        (width.ceil() as u32, lines * self.line_height())
+    }
+}
+
+fn render_outline(contours: &[Contour], scale: f32, advance: f32) -> Glyph {
+    let points = contours.iter().flatten();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+    for point in points {
+        // In pixels, with y pointing down from the baseline
+        let (x, y) = (point.x * scale, -point.y * scale);
+        min_x = min_x.min(x);
+        max_x = max_x.max(x);
+        min_y = min_y.min(y);
+        max_y = max_y.max(y);
+    }
+    if min_x > max_x {
+        return Glyph::empty(advance);
+    }
+
+    let (left, top) = (min_x.floor(), min_y.floor());
+    let width = (max_x.ceil() - left) as usize + 1;
+    let height = (max_y.ceil() - top) as usize + 1;
+    let lines = flatten(contours, |point| Point {
+        x: (point.x * scale - left).clamp(0.0, width as f32 - 1.0),
+        y: -point.y * scale - top,
+    });
+    let mut rasterizer = Rasterizer::new(width, height);
+    for (p0, p1) in lines {
+        rasterizer.draw_line(p0, p1);
+    }
+    Glyph {
+        width: width as u32,
+        height: height as u32,
+        left: left as i32,
+        top: top as i32,
+        advance,
+        coverage: rasterizer.coverage(),
+    }
+}
+
+static CONSOLE_FACE: Once<Arc<BitmapFace>> = Once::new();
+static SANS_FACE: Once<Option<Arc<TrueTypeFace>>> = Once::new();
+
+// The built-in 8x16 console font
+pub fn console_face() -> Arc<BitmapFace> {
+    CONSOLE_FACE
+        .call_once(|| Arc::new(BitmapFace::parse(BUILTIN_CONSOLE_FONT).expect("built-in console font is valid")))
+        .clone()
+}
+
+// The built-in UI font
+pub fn sans_face() -> Option<Arc<TrueTypeFace>> {
+    SANS_FACE
+        .call_once(|| TrueTypeFace::parse(BUILTIN_SANS_FONT.to_vec()).ok().map(Arc::new))
+        .clone()
+}
+
+// Fonts by size for one face, so each size's glyph cache is shared
+pub struct FontLibrary {
+    face: Option<Arc<TrueTypeFace>>,
+    fonts: HashMap<u32, Arc<Font>>,
+}
+
+impl FontLibrary {
+    // A library using the built-in UI font
+    pub fn new() -> FontLibrary {
+        FontLibrary {
+            face: sans_face(),
+            fonts: HashMap::new(),
+        }
+    }
+
+    pub fn set_face(&mut self, face: Arc<TrueTypeFace>) {
+        self.face = Some(face);
+        self.fonts.clear();
+    }
+
+    // The font at `size` pixels; without a TrueType face, the console font scaled to
+    // the nearest multiple
+    pub fn get(&mut self, size: u32) -> Arc<Font> {
+        let face = self.face.clone();
+        self.fonts
+            .entry(size)
+            .or_insert_with(|| {
+                Arc::new(match face {
+                    Some(face) => Font::truetype(face, size),
+                    None => {
+                        let console = console_face();
+                        let scale = (size + console.height() / 2) / console.height();
+                        Font::bitmap(console, scale)
+                    }
+                })
+            })
+            .clone()
     }
 }
@@ -1,5 +1,39 @@
-use std::path::Path;
+// Images
+// Icons and wallpapers are decoded from PNG, BMP or PPM files into a `Bitmap` of
+// straight-alpha RGBA pixels, which `Context::draw_image` draws. The format is
+// recognised from the data itself, not the file name.
 
+use crate::fs::vfs::Vfs;
+use crate::gui::utils::color::Color;
+use crate::gui::utils::inflate::InflateError;
+use crate::gui::utils::{bmp, png, ppm};
+
+// Larger images are refused rather than risking the memory: 64 megapixels
+pub const MAX_IMAGE_PIXELS: usize = 8192 * 8192;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageError {
+    Truncated,
+    UnknownFormat,
+    Unsupported(&'static str),
+    Malformed(&'static str),
+    Checksum,
+    TooLarge,
+    Inflate(InflateError),
+    NotFound,
+}
+
+impl From<InflateError> for ImageError {
+    fn from(error: InflateError) -> Self {
+        match error {
+            InflateError::Truncated => ImageError::Truncated,
+            InflateError::TooLarge => ImageError::TooLarge,
+            error => ImageError::Inflate(error),
+        }
+    }
+}
+
+// An image file, loaded on demand
 pub struct Image {
     name: String,
     path: String,
@@ -21,7 +55,134 @@ impl Image {
         &self.path
     }
 
-    pub fn load(&self) -> Result<tetra::graphics::Texture, tetra::TetraError> {
-        tetra::graphics::Texture::new(Path::new(&self.path))
+    pub fn load(&self, vfs: &Vfs) -> Result<Bitmap, ImageError> {
+        let data = vfs.read_file(&self.path).map_err(|_| ImageError::NotFound)?;
+        Bitmap::decode(&data)
+    }
+}
+
+// Decoded pixels, row by row from the top left
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>,
+}
+
+impl Bitmap {
+    pub fn new(width: u32, height: u32, pixels: Vec<Color>) -> Bitmap {
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        Bitmap { width, height, pixels }
+    }
+
+    // Decode a PNG, BMP or PPM file
+    pub fn decode(data: &[u8]) -> Result<Bitmap, ImageError> {
+        if data.starts_with(&png::SIGNATURE) {
+            png::decode(data)
+        } else if data.starts_with(bmp::SIGNATURE) {
+            bmp::decode(data)
+        } else if data.len() >= 2 && data[0] == b'P' && (b'1'..=b'6').contains(&data[1]) {
+            ppm::decode(data)
+        } else {
+            Err(ImageError::UnknownFormat)
+        }
     }
+
+    // Pixels from RGBA bytes, four per pixel
+    pub fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> Bitmap {
+        let pixels = rgba.chunks_exact(4).map(|p| Color::rgba(p[0], p[1], p[2], p[3])).collect();
+        Bitmap::new(width, height, pixels)
+    }
+
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|color| [color.r, color.g, color.b, color.a]).collect()
+    }
+
+    // Pixels as 0xAARRGGBB, the format of `Context`
+    pub fn to_argb(&self) -> Vec<u32> {
+        self.pixels.iter().map(|color| color.to_argb()).collect()
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Color {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    // The image resized to `width` x `height`. Each output pixel averages the source
+    // pixels it covers when shrinking, and interpolates between the nearest four when
+    // enlarging. Colors are weighted by alpha so transparent pixels do not darken edges.
+    pub fn scale(&self, width: u32, height: u32) -> Bitmap {
+        if width == 0 || height == 0 || self.width == 0 || self.height == 0 {
+            return Bitmap::new(width, height, vec![Color::rgba(0, 0, 0, 0); width as usize * height as usize]);
+        }
+        let columns = axis_weights(self.width, width);
+        let rows = axis_weights(self.height, height);
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+        for row in &rows {
+            for column in &columns {
+                // Premultiplied sums, scaled by the product of the weights
+                let (mut r, mut g, mut b, mut a, mut total) = (0u64, 0u64, 0u64, 0u64, 0u64);
+                for &(y, weight_y) in row {
+                    for &(x, weight_x) in column {
+                        let weight = weight_y as u64 * weight_x as u64;
+                        let color = self.pixel(x, y);
+                        let alpha = color.a as u64 * weight;
+                        r += color.r as u64 * alpha;
+                        g += color.g as u64 * alpha;
+                        b += color.b as u64 * alpha;
+                        a += alpha;
+                        total += weight;
+                    }
+                }
+                pixels.push(match a {
+                    0 => Color::rgba(0, 0, 0, 0),
+                    _ => Color::rgba(
+                        ((r + a / 2) / a) as u8,
+                        ((g + a / 2) / a) as u8,
+                        ((b + a / 2) / a) as u8,
+                        ((a + total / 2) / total) as u8,
+                    ),
+                });
+            }
+        }
+        Bitmap::new(width, height, pixels)
+    }
+}
+
+// Fixed-point precision of the scaling weights
+const WEIGHT_ONE: u32 = 256;
+
+// For each output position along one axis, the source positions it is made of and
+// their weights
+fn axis_weights(source: u32, target: u32) -> Vec<Vec<(u32, u32)>> {
+    (0..target)
+        .map(|i| {
+            if target < source {
+                // Box filter over the source span [i * source / target, (i + 1) * source / target)
+                let start = i as u64 * source as u64 * WEIGHT_ONE as u64 / target as u64;
+                let end = (i + 1) as u64 * source as u64 * WEIGHT_ONE as u64 / target as u64;
+                let first = (start / WEIGHT_ONE as u64) as u32;
+                let last = end.div_ceil(WEIGHT_ONE as u64).min(source as u64) as u32;
+                (first..last)
+                    .map(|x| {
+                        let left = start.max(x as u64 * WEIGHT_ONE as u64);
+                        let right = end.min((x + 1) as u64 * WEIGHT_ONE as u64);
+                        (x, (right - left) as u32)
+                    })
+                    .filter(|(_, weight)| *weight > 0)
+                    .collect()
+            } else {
+                // Linear interpolation between pixel centres
+                let center = ((2 * i + 1) as i64 * source as i64 * WEIGHT_ONE as i64 / (2 * target as i64)
+                    - WEIGHT_ONE as i64 / 2)
+                    .clamp(0, (source as i64 - 1) * WEIGHT_ONE as i64);
+                let x = (center / WEIGHT_ONE as i64) as u32;
+                let fraction = (center % WEIGHT_ONE as i64) as u32;
+                if fraction == 0 || x + 1 >= source {
+                    vec![(x, WEIGHT_ONE)]
+                } else {
+                    vec![(x, WEIGHT_ONE - fraction), (x + 1, fraction)]
+                }
+            }
+        })
+        .collect()
 }
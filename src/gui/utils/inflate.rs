@@ -0,0 +1,307 @@
+// Inflate
+// A decoder for deflate (RFC 1951) and the zlib wrapper around it (RFC 1950), as
+// used by PNG. Huffman codes are decoded canonically, one bit at a time by code
+// length, which is compact and fast enough for icons and wallpapers.
+
+use crate::gui::utils::png::adler32;
+
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_CODES: usize = 286;
+const MAX_DISTANCE_CODES: usize = 30;
+const FIXED_LITERAL_CODES: usize = 288;
+const END_OF_BLOCK: u16 = 256;
+
+// Base lengths and extra bits of length codes 257-285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+// Base distances and extra bits of distance codes 0-29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// The order code length code lengths are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// zlib
+const ZLIB_METHOD_DEFLATE: u8 = 8;
+const ZLIB_PRESET_DICTIONARY: u8 = 0x20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InflateError {
+    Truncated,
+    BadZlibHeader,
+    PresetDictionary,
+    BadBlockType,
+    BadStoredLength,
+    BadCodeLengths,
+    BadSymbol,
+    BadDistance,
+    Checksum,
+    // The output would exceed the limit given by the caller
+    TooLarge,
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            position: 0,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    // The next `count` bits, least significant first
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        while self.count < count {
+            let byte = *self.data.get(self.position).ok_or(InflateError::Truncated)?;
+            self.position += 1;
+            self.bits |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u32 << count) - 1);
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    // Drop the rest of the current byte, for stored blocks
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], InflateError> {
+        let bytes = self.data.get(self.position..self.position + count).ok_or(InflateError::Truncated)?;
+        self.position += count;
+        Ok(bytes)
+    }
+}
+
+// A canonical Huffman code: how many codes there are of each length, and the
+// symbols ordered by code
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // More codes of a length than fit means the lengths are invalid; fewer is
+        // allowed, e.g. a distance code with a single symbol
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::BadCodeLengths);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        // Codes are stored most significant bit first, so build the code bit by bit
+        // and compare it with the range of codes of each length
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::BadSymbol)
+    }
+}
+
+// Decompress a raw deflate stream, producing at most `limit` bytes
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    inflate_stream(&mut BitReader::new(data), limit)
+}
+
+// Decompress a zlib stream and check its checksum
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    if data.len() < 2 {
+        return Err(InflateError::Truncated);
+    }
+    let (method, flags) = (data[0], data[1]);
+    if method & 0x0F != ZLIB_METHOD_DEFLATE || method >> 4 > 7 || u16::from_be_bytes([method, flags]) % 31 != 0 {
+        return Err(InflateError::BadZlibHeader);
+    }
+    if flags & ZLIB_PRESET_DICTIONARY != 0 {
+        return Err(InflateError::PresetDictionary);
+    }
+    let mut reader = BitReader::new(&data[2..]);
+    let out = inflate_stream(&mut reader, limit)?;
+    // The checksum starts at the byte after the end of the deflate stream
+    reader.align();
+    let checksum = reader.bytes(4)?;
+    if u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) != adler32(&out) {
+        return Err(InflateError::Checksum);
+    }
+    Ok(out)
+}
+
+fn inflate_stream(reader: &mut BitReader, limit: usize) -> Result<Vec<u8>, InflateError> {
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => stored_block(reader, &mut out)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                codes_block(reader, &mut out, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(reader)?;
+                codes_block(reader, &mut out, &literals, &distances, limit)?;
+            }
+            _ => return Err(InflateError::BadBlockType),
+        }
+        if out.len() > limit {
+            return Err(InflateError::TooLarge);
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn stored_block(reader: &mut BitReader, out: &mut Vec<u8>) -> Result<(), InflateError> {
+    reader.align();
+    let header = reader.bytes(4)?;
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    if length != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err(InflateError::BadStoredLength);
+    }
+    out.extend_from_slice(reader.bytes(length as usize)?);
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; FIXED_LITERAL_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literals = Huffman::new(&lengths).expect("fixed literal code is complete");
+    let distances = Huffman::new(&[5; MAX_DISTANCE_CODES]).expect("fixed distance code is valid");
+    (literals, distances)
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > MAX_LITERAL_CODES || distance_count > MAX_DISTANCE_CODES {
+        return Err(InflateError::BadCodeLengths);
+    }
+
+    let mut code_length_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_length_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_length_lengths)?;
+
+    // Literal and distance code lengths form one sequence, so repeats can cross over
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_lengths.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..index].last().ok_or(InflateError::BadCodeLengths)?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err(InflateError::BadCodeLengths);
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(InflateError::BadCodeLengths);
+    }
+
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..])?;
+    Ok((literals, distances))
+}
+
+fn codes_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol < END_OF_BLOCK {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let index = (symbol - 257) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err(InflateError::BadSymbol);
+        }
+        let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let index = distances.decode(reader)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(InflateError::BadDistance);
+        }
+        let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+        if distance > out.len() {
+            return Err(InflateError::BadDistance);
+        }
+        if out.len() + length > limit {
+            return Err(InflateError::TooLarge);
+        }
+        // The match may overlap the bytes it produces, so copy byte by byte
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
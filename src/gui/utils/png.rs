@@ -0,0 +1,357 @@
+// PNG encoding and decoding
+// Images are written as 8-bit RGBA without compression: the zlib stream is made of
+// stored deflate blocks, which keeps the encoder small and its output byte-exact.
+// Used for screenshots and the golden images of the GUI tests. The decoder reads every
+// standard color type and bit depth, with or without interlacing.
+
+use crate::gui::utils::color::Color;
+use crate::gui::utils::image::{Bitmap, ImageError, MAX_IMAGE_PIXELS};
+use crate::gui::utils::inflate::zlib_decompress;
+
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Color type 6: RGBA, 8 bits per channel
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_GRAY: u8 = 0;
+const COLOR_TYPE_RGB: u8 = 2;
+const COLOR_TYPE_PALETTE: u8 = 3;
+const COLOR_TYPE_GRAY_ALPHA: u8 = 4;
+const COLOR_TYPE_RGBA: u8 = 6;
+
+// Row filters
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+
+// Adam7 interlacing: the starting column and row, and the steps, of each pass
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+// zlib header for deflate with a 32K window and no preset dictionary
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x01];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// Encode `width` x `height` RGBA pixels, four bytes each, row by row from the top left
+pub fn encode(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    assert_eq!(rgba.len(), width as usize * height as usize * 4);
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGBA, 0, 0, 0]);
+
+    // Every row starts with its filter type
+    let mut raw = Vec::with_capacity(rgba.len() + height as usize);
+    if width > 0 {
+        for row in rgba.chunks(width as usize * 4) {
+            raw.push(FILTER_NONE);
+            raw.extend_from_slice(row);
+        }
+    }
+
+    let mut out = SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Wrap `data` in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = ZLIB_HEADER.to_vec();
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // An empty stream still needs one final block
+        out.extend_from_slice(&[1, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// CRC-32 as used by PNG chunks (polynomial 0xEDB88320, reflected)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// Adler-32 checksum of the uncompressed zlib data
+pub fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % MODULUS;
+        b = (b + a) % MODULUS;
+    }
+    (b << 16) | a
+}
+
+// The layout of the pixels, from the IHDR chunk
+struct Header {
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color_type {
+            COLOR_TYPE_GRAY | COLOR_TYPE_PALETTE => 1,
+            COLOR_TYPE_GRAY_ALPHA => 2,
+            COLOR_TYPE_RGB => 3,
+            _ => 4,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.bit_depth as usize
+    }
+
+    // Bytes in a row of `width` pixels, not counting the filter type
+    fn row_bytes(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+}
+
+// Decode a PNG into 8-bit RGBA. 16-bit channels are reduced to their high byte.
+pub fn decode(data: &[u8]) -> Result<Bitmap, ImageError> {
+    if !data.starts_with(&SIGNATURE) {
+        return Err(ImageError::UnknownFormat);
+    }
+
+    let mut header = None;
+    let mut palette: Vec<Color> = Vec::new();
+    let mut transparency: Vec<u8> = Vec::new();
+    let mut stream = Vec::new();
+    let mut offset = SIGNATURE.len();
+    loop {
+        let length = data.get(offset..offset + 4).ok_or(ImageError::Truncated)?;
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let chunk = data.get(offset + 4..offset + 8 + length).ok_or(ImageError::Truncated)?;
+        let crc = data.get(offset + 8 + length..offset + 12 + length).ok_or(ImageError::Truncated)?;
+        if crc32(chunk) != u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err(ImageError::Checksum);
+        }
+        offset += length + 12;
+
+        let (kind, body) = chunk.split_at(4);
+        match kind {
+            b"IHDR" => header = Some(parse_header(body)?),
+            b"PLTE" => {
+                palette = body.chunks_exact(3).map(|rgb| Color::new(rgb[0], rgb[1], rgb[2])).collect();
+            }
+            b"tRNS" => transparency = body.to_vec(),
+            b"IDAT" => stream.extend_from_slice(body),
+            b"IEND" => break,
+            // Unknown chunks that are critical (upper case first letter) change how
+            // the image must be read
+            _ if kind[0].is_ascii_uppercase() => return Err(ImageError::Unsupported("critical PNG chunk")),
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or(ImageError::Malformed("no IHDR chunk"))?;
+    if header.color_type == COLOR_TYPE_PALETTE && palette.is_empty() {
+        return Err(ImageError::Malformed("no palette"));
+    }
+    for (entry, alpha) in palette.iter_mut().zip(&transparency) {
+        entry.a = *alpha;
+    }
+
+    let passes: Vec<(usize, usize, usize, usize)> = if header.interlaced {
+        ADAM7.to_vec()
+    } else {
+        vec![(0, 0, 1, 1)]
+    };
+    // The size of the filtered data, which is also a limit for decompression
+    let expected: usize = passes
+        .iter()
+        .map(|pass| pass_size(&header, pass))
+        .filter(|(width, height)| *width > 0 && *height > 0)
+        .map(|(width, height)| (header.row_bytes(width) + 1) * height)
+        .sum();
+    let raw = zlib_decompress(&stream, expected)?;
+    if raw.len() < expected {
+        return Err(ImageError::Truncated);
+    }
+
+    let mut pixels = vec![Color::rgba(0, 0, 0, 0); header.width * header.height];
+    let mut position = 0;
+    for pass in passes {
+        let (width, height) = pass_size(&header, &pass);
+        if width == 0 || height == 0 {
+            continue;
+        }
+        let size = (header.row_bytes(width) + 1) * height;
+        let rows = unfilter(&header, &raw[position..position + size], width)?;
+        position += size;
+
+        let (start_x, start_y, step_x, step_y) = pass;
+        for (y, row) in rows.chunks(header.row_bytes(width)).enumerate() {
+            for x in 0..width {
+                let pixel = (start_y + y * step_y) * header.width + start_x + x * step_x;
+                pixels[pixel] = read_pixel(&header, row, x, &palette, &transparency);
+            }
+        }
+    }
+    Ok(Bitmap::new(header.width as u32, header.height as u32, pixels))
+}
+
+fn parse_header(body: &[u8]) -> Result<Header, ImageError> {
+    if body.len() < 13 {
+        return Err(ImageError::Truncated);
+    }
+    let header = Header {
+        width: u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize,
+        height: u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize,
+        bit_depth: body[8],
+        color_type: body[9],
+        interlaced: body[12] == 1,
+    };
+    let valid_depth = match header.color_type {
+        COLOR_TYPE_GRAY => matches!(header.bit_depth, 1 | 2 | 4 | 8 | 16),
+        COLOR_TYPE_PALETTE => matches!(header.bit_depth, 1 | 2 | 4 | 8),
+        COLOR_TYPE_RGB | COLOR_TYPE_GRAY_ALPHA | COLOR_TYPE_RGBA => matches!(header.bit_depth, 8 | 16),
+        _ => return Err(ImageError::Malformed("color type")),
+    };
+    if !valid_depth {
+        return Err(ImageError::Malformed("bit depth"));
+    }
+    if body[10] != 0 || body[11] != 0 || body[12] > 1 {
+        return Err(ImageError::Unsupported("PNG compression, filter or interlace method"));
+    }
+    if header.width == 0 || header.height == 0 {
+        return Err(ImageError::Malformed("empty image"));
+    }
+    if header.width.saturating_mul(header.height) > MAX_IMAGE_PIXELS {
+        return Err(ImageError::TooLarge);
+    }
+    Ok(header)
+}
+
+// The size of the subimage an interlacing pass covers
+fn pass_size(header: &Header, &(start_x, start_y, step_x, step_y): &(usize, usize, usize, usize)) -> (usize, usize) {
+    let width = (header.width + step_x - 1 - start_x.min(header.width)) / step_x;
+    let height = (header.height + step_y - 1 - start_y.min(header.height)) / step_y;
+    (width, height)
+}
+
+// Undo the row filters of `width` pixels wide rows, dropping the filter types
+fn unfilter(header: &Header, data: &[u8], width: usize) -> Result<Vec<u8>, ImageError> {
+    let row_bytes = header.row_bytes(width);
+    // Filters work on whole bytes, comparing each with the same byte of the pixel before
+    let distance = header.bits_per_pixel().div_ceil(8);
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    for (y, row) in data.chunks(row_bytes + 1).enumerate() {
+        let (filter, row) = (row[0], &row[1..]);
+        let start = out.len();
+        for (x, &byte) in row.iter().enumerate() {
+            let left = if x >= distance { out[start + x - distance] } else { 0 };
+            let up = if y > 0 { out[start + x - row_bytes] } else { 0 };
+            let up_left = if y > 0 && x >= distance { out[start + x - row_bytes - distance] } else { 0 };
+            let predicted = match filter {
+                FILTER_NONE => 0,
+                FILTER_SUB => left,
+                FILTER_UP => up,
+                FILTER_AVERAGE => ((left as u16 + up as u16) / 2) as u8,
+                FILTER_PAETH => paeth(left, up, up_left),
+                _ => return Err(ImageError::Malformed("row filter")),
+            };
+            out.push(byte.wrapping_add(predicted));
+        }
+    }
+    Ok(out)
+}
+
+// Whichever of the three neighbours is closest to left + up - up_left
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (to_left, to_up, to_up_left) =
+        ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+fn read_pixel(header: &Header, row: &[u8], x: usize, palette: &[Color], transparency: &[u8]) -> Color {
+    let depth = header.bit_depth as usize;
+    // Sample `channel` of pixel x, at its bit depth
+    let sample = |channel: usize| -> u16 {
+        let index = x * header.channels() + channel;
+        match depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            _ => {
+                let bit = index * depth;
+                ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8) as u16
+            }
+        }
+    };
+    // Scale a sample to 8 bits
+    let to_8bit = |value: u16| -> u8 {
+        match depth {
+            16 => (value >> 8) as u8,
+            _ => (value as u32 * 255 / ((1 << depth) - 1)) as u8,
+        }
+    };
+    // tRNS gives gray and RGB images one color, at full sample depth, that is transparent
+    let key = |channel: usize| transparency.get(channel * 2..channel * 2 + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+    match header.color_type {
+        COLOR_TYPE_GRAY => {
+            let gray = sample(0);
+            let alpha = if key(0) == Some(gray) { 0 } else { 255 };
+            let gray = to_8bit(gray);
+            Color::rgba(gray, gray, gray, alpha)
+        }
+        COLOR_TYPE_RGB => {
+            let (r, g, b) = (sample(0), sample(1), sample(2));
+            let alpha = if (key(0), key(1), key(2)) == (Some(r), Some(g), Some(b)) { 0 } else { 255 };
+            Color::rgba(to_8bit(r), to_8bit(g), to_8bit(b), alpha)
+        }
+        COLOR_TYPE_PALETTE => palette.get(sample(0) as usize).copied().unwrap_or(Color::rgba(0, 0, 0, 255)),
+        COLOR_TYPE_GRAY_ALPHA => {
+            let gray = to_8bit(sample(0));
+            Color::rgba(gray, gray, gray, to_8bit(sample(1)))
+        }
+        _ => Color::rgba(to_8bit(sample(0)), to_8bit(sample(1)), to_8bit(sample(2)), to_8bit(sample(3))),
+    }
+}
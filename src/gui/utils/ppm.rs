@@ -0,0 +1,149 @@
+// PPM decoding
+// The Netpbm formats: bitmaps (P1, P4), gray maps (P2, P5) and pixel maps (P3, P6),
+// as text or binary, with samples of up to 16 bits.
+
+use crate::gui::utils::color::Color;
+use crate::gui::utils::image::{Bitmap, ImageError, MAX_IMAGE_PIXELS};
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    // Skip whitespace and comments, which run from '#' to the end of the line
+    fn skip_space(&mut self) {
+        while let Some(&byte) = self.data.get(self.position) {
+            if byte == b'#' {
+                while self.data.get(self.position).is_some_and(|byte| *byte != b'\n') {
+                    self.position += 1;
+                }
+            } else if byte.is_ascii_whitespace() {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, ImageError> {
+        self.skip_space();
+        let start = self.position;
+        while self.data.get(self.position).is_some_and(|byte| byte.is_ascii_digit()) {
+            self.position += 1;
+        }
+        if start == self.position {
+            return Err(if self.position >= self.data.len() {
+                ImageError::Truncated
+            } else {
+                ImageError::Malformed("PPM number")
+            });
+        }
+        core::str::from_utf8(&self.data[start..self.position])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or(ImageError::Malformed("PPM number"))
+    }
+
+    // A bit of a text bitmap, where the digits need not be separated
+    fn bit(&mut self) -> Result<u32, ImageError> {
+        self.skip_space();
+        match self.data.get(self.position) {
+            Some(digit @ (b'0' | b'1')) => {
+                self.position += 1;
+                Ok((digit - b'0') as u32)
+            }
+            Some(_) => Err(ImageError::Malformed("PBM bit")),
+            None => Err(ImageError::Truncated),
+        }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], ImageError> {
+        let bytes = self.data.get(self.position..self.position + count).ok_or(ImageError::Truncated)?;
+        self.position += count;
+        Ok(bytes)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Bitmap, ImageError> {
+    let kind = match data {
+        [b'P', kind @ b'1'..=b'6', ..] => *kind,
+        _ => return Err(ImageError::UnknownFormat),
+    };
+    let mut reader = Reader { data, position: 2 };
+    let width = reader.number()? as usize;
+    let height = reader.number()? as usize;
+    let max = if kind == b'1' || kind == b'4' { 1 } else { reader.number()? };
+    if width == 0 || height == 0 {
+        return Err(ImageError::Malformed("empty image"));
+    }
+    if width.saturating_mul(height) > MAX_IMAGE_PIXELS {
+        return Err(ImageError::TooLarge);
+    }
+    if max == 0 || max > 0xFFFF {
+        return Err(ImageError::Malformed("PPM maximum value"));
+    }
+    // Binary data starts after exactly one whitespace byte
+    if kind >= b'4' {
+        reader.position += 1;
+    }
+
+    let scale = |value: u32| -> u8 { (value.min(max) as u64 * 255 / max as u64) as u8 };
+    let mut pixels = Vec::with_capacity(width * height);
+    match kind {
+        // In bitmaps 1 is black
+        b'1' => {
+            for _ in 0..width * height {
+                let value = if reader.bit()? == 1 { 0 } else { 255 };
+                pixels.push(Color::new(value, value, value));
+            }
+        }
+        b'4' => {
+            let stride = width.div_ceil(8);
+            let bits = reader.bytes(stride * height)?;
+            for y in 0..height {
+                for x in 0..width {
+                    let set = bits[y * stride + x / 8] & (0x80 >> (x % 8)) != 0;
+                    let value = if set { 0 } else { 255 };
+                    pixels.push(Color::new(value, value, value));
+                }
+            }
+        }
+        b'2' | b'3' => {
+            let channels = if kind == b'2' { 1 } else { 3 };
+            for _ in 0..width * height {
+                let mut samples = [0u8; 3];
+                for sample in samples.iter_mut().take(channels) {
+                    *sample = scale(reader.number()?);
+                }
+                pixels.push(if channels == 1 {
+                    Color::new(samples[0], samples[0], samples[0])
+                } else {
+                    Color::new(samples[0], samples[1], samples[2])
+                });
+            }
+        }
+        _ => {
+            let channels = if kind == b'5' { 1 } else { 3 };
+            // Samples above 255 take two bytes, most significant first
+            let sample_size = if max > 0xFF { 2 } else { 1 };
+            let samples = reader.bytes(width * height * channels * sample_size)?;
+            let sample = |index: usize| -> u8 {
+                if sample_size == 2 {
+                    scale(u16::from_be_bytes([samples[index * 2], samples[index * 2 + 1]]) as u32)
+                } else {
+                    scale(samples[index] as u32)
+                }
+            };
+            for pixel in 0..width * height {
+                pixels.push(if channels == 1 {
+                    let gray = sample(pixel);
+                    Color::new(gray, gray, gray)
+                } else {
+                    Color::new(sample(pixel * 3), sample(pixel * 3 + 1), sample(pixel * 3 + 2))
+                });
+            }
+        }
+    }
+    Ok(Bitmap::new(width as u32, height as u32, pixels))
+}
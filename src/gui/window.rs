@@ -101,7 +101,7 @@ impl Window {
         let font_size = self.style.title_font_size.unwrap_or(16);
         let font = context.get_font(font_size);
         let title_text_rect = Rect::new(rect.x + 5, rect.y + 2, rect.width - 10, title_bar_height as u32 - 4);
-        context.draw_text(&self.title, font, title_text_rect, title_text_color, Align::Left);
+        context.draw_text(&self.title, &font, title_text_rect, title_text_color, Align::Left);
 
         // Draw the window's border.
         let border_color = self.style.border_color.unwrap_or(Color::black());
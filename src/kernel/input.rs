@@ -0,0 +1,567 @@
+//! Kernel input layer
+//!
+//! Input drivers register as sources and report events here. Each event is
+//! timestamped and copied into the ring buffer of every subscriber whose filter
+//! accepts it, so the console, the GUI and `/dev/input/eventN` readers each see
+//! their own stream. Event streams can be recorded and replayed later with the
+//! original timestamps, which keeps input-driven tests deterministic.
+
+use crate::drivers::keyboard::KeyEvent;
+use crate::gui::event::{EventType, MouseButton, MouseEvent};
+use crate::util::time;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::{Mutex, MutexGuard};
+
+// Events buffered per subscriber before the oldest are overwritten
+const SUBSCRIBER_QUEUE_SIZE: usize = 256;
+
+// Events reported from interrupt handlers that can wait for delivery; later ones are dropped
+const DEFERRED_QUEUE_SIZE: usize = 256;
+
+// Size of an event in the `/dev/input/eventN` wire format
+pub const EVENT_RECORD_SIZE: usize = 24;
+
+// Event types and codes of the `/dev/input/eventN` wire format (as on Linux)
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_SIDE: u16 = 0x113;
+const BTN_EXTRA: u16 = 0x114;
+const KEY_UNKNOWN: u16 = 240;
+
+pub type SourceId = u32;
+pub type SubscriberId = u32;
+
+/// What kind of device an input source is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Keyboard,
+    Mouse,
+}
+
+/// A registered input device, exposed as `/dev/input/event<id>`
+#[derive(Debug, Clone)]
+pub struct InputSource {
+    pub id: SourceId,
+    pub name: String,
+    pub kind: SourceKind,
+}
+
+/// The payload of an input event
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEventKind {
+    Key(KeyEvent),
+    Mouse(MouseEvent),
+}
+
+/// A timestamped event from an input source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub timestamp: Duration,
+    pub source: SourceId,
+    pub kind: InputEventKind,
+}
+
+/// Selects which events a subscriber receives
+#[derive(Debug, Clone)]
+pub struct EventFilter {
+    sources: Option<Vec<SourceId>>,
+    keys: bool,
+    mouse: bool,
+}
+
+impl EventFilter {
+    /// Accept every event from every source
+    pub fn all() -> EventFilter {
+        EventFilter {
+            sources: None,
+            keys: true,
+            mouse: true,
+        }
+    }
+
+    /// Accept only keyboard events
+    pub fn keys_only(mut self) -> EventFilter {
+        self.mouse = false;
+        self
+    }
+
+    /// Accept only mouse events
+    pub fn mouse_only(mut self) -> EventFilter {
+        self.keys = false;
+        self
+    }
+
+    /// Restrict the filter to a source; may be called repeatedly to allow several
+    pub fn source(mut self, source: SourceId) -> EventFilter {
+        self.sources.get_or_insert_with(Vec::new).push(source);
+        self
+    }
+
+    pub fn accepts(&self, event: &InputEvent) -> bool {
+        let kind_ok = match event.kind {
+            InputEventKind::Key(_) => self.keys,
+            InputEventKind::Mouse(_) => self.mouse,
+        };
+        let source_ok = self
+            .sources
+            .as_ref()
+            .map_or(true, |sources| sources.contains(&event.source));
+        kind_ok && source_ok
+    }
+}
+
+/// A fixed-capacity queue that overwrites its oldest entry when full
+pub struct RingBuffer<T> {
+    buffer: Vec<Option<T>>,
+    head: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl<T> RingBuffer<T> {
+    pub fn new(capacity: usize) -> RingBuffer<T> {
+        let mut buffer = Vec::with_capacity(capacity);
+        buffer.resize_with(capacity, || None);
+        RingBuffer {
+            buffer,
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        let capacity = self.buffer.len();
+        if capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        let tail = (self.head + self.len) % capacity;
+        self.buffer[tail] = Some(value);
+        if self.len == capacity {
+            self.head = (self.head + 1) % capacity;
+            self.dropped += 1;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.buffer[self.head].take();
+        self.head = (self.head + 1) % self.buffer.len();
+        self.len -= 1;
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of entries overwritten because the reader fell behind
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+struct Subscriber {
+    id: SubscriberId,
+    filter: EventFilter,
+    queue: RingBuffer<InputEvent>,
+}
+
+/// A recorded event stream that can be replayed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub events: Vec<InputEvent>,
+}
+
+/// The input layer: sources, subscribers and the optional recorder
+pub struct InputManager {
+    clock: fn() -> Duration,
+    sources: Vec<InputSource>,
+    subscribers: Vec<Subscriber>,
+    recording: Option<Recording>,
+    next_source: SourceId,
+    next_subscriber: SubscriberId,
+}
+
+impl InputManager {
+    /// Create an input layer that stamps events using `clock`
+    pub const fn new(clock: fn() -> Duration) -> InputManager {
+        InputManager {
+            clock,
+            sources: Vec::new(),
+            subscribers: Vec::new(),
+            recording: None,
+            next_source: 0,
+            next_subscriber: 0,
+        }
+    }
+
+    pub fn register_source(&mut self, name: &str, kind: SourceKind) -> SourceId {
+        let id = self.next_source;
+        self.next_source += 1;
+        self.sources.push(InputSource {
+            id,
+            name: name.to_string(),
+            kind,
+        });
+        id
+    }
+
+    pub fn unregister_source(&mut self, id: SourceId) {
+        self.sources.retain(|source| source.id != id);
+    }
+
+    pub fn sources(&self) -> &[InputSource] {
+        &self.sources
+    }
+
+    pub fn subscribe(&mut self, filter: EventFilter) -> SubscriberId {
+        let id = self.next_subscriber;
+        self.next_subscriber += 1;
+        self.subscribers.push(Subscriber {
+            id,
+            filter,
+            queue: RingBuffer::new(SUBSCRIBER_QUEUE_SIZE),
+        });
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriberId) {
+        self.subscribers.retain(|subscriber| subscriber.id != id);
+    }
+
+    /// Stamp an event from a source and deliver it
+    pub fn report(&mut self, source: SourceId, kind: InputEventKind) {
+        let event = InputEvent {
+            timestamp: (self.clock)(),
+            source,
+            kind,
+        };
+        self.inject(event);
+    }
+
+    /// Deliver an already-stamped event, e.g. one being replayed
+    pub fn inject(&mut self, event: InputEvent) {
+        if let Some(recording) = self.recording.as_mut() {
+            recording.events.push(event);
+        }
+        for subscriber in self.subscribers.iter_mut() {
+            if subscriber.filter.accepts(&event) {
+                subscriber.queue.push(event);
+            }
+        }
+    }
+
+    pub fn read(&mut self, id: SubscriberId) -> Option<InputEvent> {
+        self.subscribers
+            .iter_mut()
+            .find(|subscriber| subscriber.id == id)
+            .and_then(|subscriber| subscriber.queue.pop())
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::default());
+    }
+
+    pub fn stop_recording(&mut self) -> Recording {
+        self.recording.take().unwrap_or_default()
+    }
+
+    /// Replay a recording with its original timestamps and sources
+    pub fn replay(&mut self, recording: &Recording) {
+        for event in &recording.events {
+            self.inject(*event);
+        }
+    }
+}
+
+// A fixed-size queue that interrupt handlers push to without taking a lock. Slots are
+// claimed by advancing `tail`; each is marked ready once written, and the single
+// consumer (whoever holds INPUT) takes them in order from `head`.
+struct DeferredEvents {
+    slots: [DeferredSlot; DEFERRED_QUEUE_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+struct DeferredSlot {
+    ready: AtomicBool,
+    event: UnsafeCell<MaybeUninit<InputEvent>>,
+}
+
+// A slot's event is only written by the producer that claimed it and only read by the
+// consumer after `ready` is set
+unsafe impl Sync for DeferredEvents {}
+
+impl DeferredEvents {
+    const fn new() -> DeferredEvents {
+        DeferredEvents {
+            slots: [const {
+                DeferredSlot {
+                    ready: AtomicBool::new(false),
+                    event: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; DEFERRED_QUEUE_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // Queue an event; returns false if the queue is full
+    fn push(&self, event: InputEvent) -> bool {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= DEFERRED_QUEUE_SIZE {
+                return false;
+            }
+            match self.tail.compare_exchange_weak(tail, tail.wrapping_add(1), Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => tail = current,
+            }
+        }
+        let slot = &self.slots[tail % DEFERRED_QUEUE_SIZE];
+        unsafe { (*slot.event.get()).write(event) };
+        slot.ready.store(true, Ordering::Release);
+        true
+    }
+
+    // Take the oldest event; only called with INPUT held
+    fn pop(&self) -> Option<InputEvent> {
+        let head = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[head % DEFERRED_QUEUE_SIZE];
+        if !slot.ready.load(Ordering::Acquire) {
+            return None;
+        }
+        let event = unsafe { (*slot.event.get()).assume_init_read() };
+        slot.ready.store(false, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(event)
+    }
+}
+
+static INPUT: Mutex<InputManager> = Mutex::new(InputManager::new(time::current_time));
+
+// Events reported by interrupt handlers, which must not wait for INPUT: the code they
+// interrupted may be holding it
+static DEFERRED: DeferredEvents = DeferredEvents::new();
+
+// Lock the input layer, first delivering the events deferred by interrupt handlers
+fn input() -> MutexGuard<'static, InputManager> {
+    let mut input = INPUT.lock();
+    while let Some(event) = DEFERRED.pop() {
+        input.inject(event);
+    }
+    input
+}
+
+// Register an input device; returns its source ID
+pub fn register_source(name: &str, kind: SourceKind) -> SourceId {
+    input().register_source(name, kind)
+}
+
+pub fn unregister_source(id: SourceId) {
+    input().unregister_source(id);
+}
+
+// Return a snapshot of the registered sources
+pub fn sources() -> Vec<InputSource> {
+    input().sources().to_vec()
+}
+
+// Report an event from a driver. Safe to call from interrupt handlers: the event is
+// stamped now and delivered by the next call into the input layer.
+pub fn report(source: SourceId, kind: InputEventKind) {
+    let event = InputEvent {
+        timestamp: time::current_time(),
+        source,
+        kind,
+    };
+    // A full queue means nobody has read input for a while; drop the event
+    let _ = DEFERRED.push(event);
+}
+
+pub fn subscribe(filter: EventFilter) -> SubscriberId {
+    input().subscribe(filter)
+}
+
+pub fn unsubscribe(id: SubscriberId) {
+    input().unsubscribe(id);
+}
+
+// Take the next event queued for a subscriber
+pub fn read(id: SubscriberId) -> Option<InputEvent> {
+    input().read(id)
+}
+
+pub fn start_recording() {
+    input().start_recording();
+}
+
+pub fn stop_recording() -> Recording {
+    input().stop_recording()
+}
+
+pub fn replay(recording: &Recording) {
+    input().replay(recording);
+}
+
+// The device path at which a source's events can be read
+pub fn event_device_path(source: SourceId) -> String {
+    format!("/dev/input/event{}", source)
+}
+
+/// A reader of `/dev/input/eventN`: the events of one source in the wire format
+pub struct EventDevice {
+    source: SourceId,
+    subscriber: SubscriberId,
+    // Encoded records not yet copied out by `read`
+    pending: Vec<u8>,
+}
+
+impl EventDevice {
+    pub fn open(source: SourceId) -> Option<EventDevice> {
+        if !sources().iter().any(|s| s.id == source) {
+            return None;
+        }
+        Some(EventDevice {
+            source,
+            subscriber: subscribe(EventFilter::all().source(source)),
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn source(&self) -> SourceId {
+        self.source
+    }
+
+    // Copy whole records into `buffer`; returns the number of bytes written
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        while self.pending.len() < buffer.len() {
+            match read(self.subscriber) {
+                Some(event) => {
+                    for record in encode_event(&event) {
+                        self.pending.extend_from_slice(&record);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        let count = (buffer.len().min(self.pending.len()) / EVENT_RECORD_SIZE) * EVENT_RECORD_SIZE;
+        buffer[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        count
+    }
+}
+
+impl Drop for EventDevice {
+    fn drop(&mut self) {
+        unsubscribe(self.subscriber);
+    }
+}
+
+// Encode one wire record: seconds, microseconds, type, code, value
+fn encode_record(timestamp: Duration, event_type: u16, code: u16, value: i32) -> [u8; EVENT_RECORD_SIZE] {
+    let mut record = [0; EVENT_RECORD_SIZE];
+    record[0..8].copy_from_slice(&timestamp.as_secs().to_le_bytes());
+    record[8..16].copy_from_slice(&(timestamp.subsec_micros() as u64).to_le_bytes());
+    record[16..18].copy_from_slice(&event_type.to_le_bytes());
+    record[18..20].copy_from_slice(&code.to_le_bytes());
+    record[20..24].copy_from_slice(&value.to_le_bytes());
+    record
+}
+
+// The Linux `KEY_*` code of a key number. For the keys of the original AT keyboard
+// it is the set 1 make code; extended keys have codes of their own.
+fn key_code(key: u8) -> u16 {
+    match key {
+        0x01..=0x58 => key as u16,
+        0x9C => 96,  // KEY_KPENTER
+        0x9D => 97,  // KEY_RIGHTCTRL
+        0xB5 => 98,  // KEY_KPSLASH
+        0xB7 => 99,  // KEY_SYSRQ
+        0xB8 => 100, // KEY_RIGHTALT
+        0xC7 => 102, // KEY_HOME
+        0xC8 => 103, // KEY_UP
+        0xC9 => 104, // KEY_PAGEUP
+        0xCB => 105, // KEY_LEFT
+        0xCD => 106, // KEY_RIGHT
+        0xCF => 107, // KEY_END
+        0xD0 => 108, // KEY_DOWN
+        0xD1 => 109, // KEY_PAGEDOWN
+        0xD2 => 110, // KEY_INSERT
+        0xD3 => 111, // KEY_DELETE
+        0xC5 => 119, // KEY_PAUSE
+        0xDB => 125, // KEY_LEFTMETA
+        0xDC => 126, // KEY_RIGHTMETA
+        0xDD => 127, // KEY_COMPOSE
+        _ => KEY_UNKNOWN,
+    }
+}
+
+fn button_code(button: MouseButton) -> u16 {
+    match button {
+        MouseButton::Left => BTN_LEFT,
+        MouseButton::Right => BTN_RIGHT,
+        MouseButton::Middle => BTN_MIDDLE,
+        MouseButton::Back => BTN_SIDE,
+        MouseButton::Forward => BTN_EXTRA,
+    }
+}
+
+// Encode an event as wire records terminated by a SYN record. Mouse events always
+// carry the absolute position so a reader never has to track relative motion.
+pub fn encode_event(event: &InputEvent) -> Vec<[u8; EVENT_RECORD_SIZE]> {
+    let t = event.timestamp;
+    let mut records = Vec::new();
+    match event.kind {
+        InputEventKind::Key(key) => {
+            let value = if key.released {
+                0
+            } else if key.repeat {
+                2
+            } else {
+                1
+            };
+            records.push(encode_record(t, EV_KEY, key_code(key.key), value));
+        }
+        InputEventKind::Mouse(mouse) => {
+            records.push(encode_record(t, EV_ABS, ABS_X, mouse.x));
+            records.push(encode_record(t, EV_ABS, ABS_Y, mouse.y));
+            match mouse.event_type {
+                EventType::MouseDown | EventType::MouseUp => {
+                    if let Some(button) = mouse.button {
+                        let pressed = mouse.event_type == EventType::MouseDown;
+                        records.push(encode_record(t, EV_KEY, button_code(button), pressed as i32));
+                    }
+                }
+                EventType::MouseWheel => {
+                    records.push(encode_record(t, EV_REL, REL_WHEEL, mouse.wheel as i32));
+                }
+                _ => {}
+            }
+        }
+    }
+    records.push(encode_record(t, EV_SYN, 0, 0));
+    records
+}
@@ -5,21 +5,57 @@ mod x86_64;
 #[cfg(target_arch = "armv7")]
 mod armv7;
 
+use crate::drivers::ahci::{AhciController, AHCI_INTERRUPT_VECTOR};
+use crate::drivers::console;
+use crate::drivers::device::{self, Device, DeviceClass};
+use crate::drivers::keyboard::KeyboardDevice;
+use crate::drivers::mouse::MouseDevice;
+use crate::drivers::network::{E1000, E1000_INTERRUPT_VECTOR};
+use crate::drivers::virtio::VIRTIO_INTERRUPT_VECTOR;
+use crate::drivers::virtio_blk::VirtioBlk;
+use crate::drivers::virtio_net::VirtioNet;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use std::sync::Arc;
+
+// Frequency of the timer interrupt
+pub const TIMER_HZ: u64 = 100;
+
+// Timer interrupts since boot
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Interrupt handlers running, counting nested ones; the kernel runs on one CPU
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
 // Define an interrupt handler struct
 pub struct InterruptHandler {
     // Interrupt vector number
     vector: u8,
+    // Device or purpose, as listed in /proc/interrupts
+    name: &'static str,
     // Pointer to the interrupt handler function
     handler_func: fn(),
+    // Interrupts handled so far
+    count: AtomicU64,
+}
+
+// How often one interrupt vector has fired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptCount {
+    pub vector: u8,
+    pub name: &'static str,
+    pub count: u64,
 }
 
 // Implement methods for the interrupt handler struct
 impl InterruptHandler {
     // Initialize a new interrupt handler
-    pub fn new(vector: u8, handler_func: fn()) -> Self {
+    pub const fn new(vector: u8, name: &'static str, handler_func: fn()) -> Self {
         InterruptHandler {
             vector,
+            name,
             handler_func,
+            count: AtomicU64::new(0),
         }
     }
 
@@ -27,27 +63,141 @@ impl InterruptHandler {
     pub fn register(&self) {
         // Architecture-specific code could go here to register the interrupt handler
     }
+
+    // Count the interrupt and run the handler; called through dispatch
+    pub fn handle(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        (self.handler_func)();
+    }
 }
 
 // Define interrupt handler functions
 fn timer_interrupt_handler() {
     // Timer interrupt handling code goes here
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    console::tick();
+}
+
+// Time since the timer was started
+pub fn uptime() -> Duration {
+    let ticks = TIMER_TICKS.load(Ordering::Relaxed);
+    Duration::from_secs(ticks / TIMER_HZ) + Duration::from_nanos(ticks % TIMER_HZ * 1_000_000_000 / TIMER_HZ)
+}
+
+// The devices of a class a handler serves. The handler must not wait for the device
+// registry, which the code it interrupted may hold, so the interrupt goes unhandled
+// then; the references are dropped when the handler returns, leaving the devices free
+// to be removed.
+fn served(class: DeviceClass) -> Vec<Arc<dyn Device>> {
+    device::try_find_by_class(class).unwrap_or_default()
 }
 
 fn keyboard_interrupt_handler() {
-    // Keyboard interrupt handling code goes here
+    for input in served(DeviceClass::Input) {
+        if let Some(keyboard) = input.as_any().downcast_ref::<KeyboardDevice>() {
+            keyboard.handle_interrupt();
+        }
+    }
+}
+
+fn mouse_interrupt_handler() {
+    for input in served(DeviceClass::Input) {
+        if let Some(mouse) = input.as_any().downcast_ref::<MouseDevice>() {
+            mouse.handle_interrupt();
+        }
+    }
+}
+
+fn ahci_interrupt_handler() {
+    for controller in served(DeviceClass::Controller) {
+        if let Some(ahci) = controller.as_any().downcast_ref::<AhciController>() {
+            ahci.handle_interrupt();
+        }
+    }
+}
+
+fn virtio_interrupt_handler() {
+    for disk in served(DeviceClass::Block) {
+        if let Some(blk) = disk.as_any().downcast_ref::<VirtioBlk>() {
+            blk.handle_interrupt();
+        }
+    }
+    for net in served(DeviceClass::Network) {
+        if let Some(net) = net.as_any().downcast_ref::<VirtioNet>() {
+            net.handle_interrupt();
+        }
+    }
+}
+
+fn e1000_interrupt_handler() {
+    for net in served(DeviceClass::Network) {
+        if let Some(nic) = net.as_any().downcast_ref::<E1000>() {
+            nic.handle_interrupt();
+        }
+    }
 }
 
 // Define interrupt handler constants
 const TIMER_INTERRUPT_VECTOR: u8 = 32;
 const KEYBOARD_INTERRUPT_VECTOR: u8 = 33;
+const MOUSE_INTERRUPT_VECTOR: u8 = 44;
 
 // Initialize interrupt handlers
-static TIMER_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(TIMER_INTERRUPT_VECTOR, timer_interrupt_handler);
-static KEYBOARD_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(KEYBOARD_INTERRUPT_VECTOR, keyboard_interrupt_handler);
+static TIMER_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(TIMER_INTERRUPT_VECTOR, "timer", timer_interrupt_handler);
+static KEYBOARD_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(KEYBOARD_INTERRUPT_VECTOR, "keyboard", keyboard_interrupt_handler);
+static MOUSE_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(MOUSE_INTERRUPT_VECTOR, "mouse", mouse_interrupt_handler);
+static AHCI_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(AHCI_INTERRUPT_VECTOR, "ahci", ahci_interrupt_handler);
+static VIRTIO_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(VIRTIO_INTERRUPT_VECTOR, "virtio", virtio_interrupt_handler);
+static E1000_INTERRUPT_HANDLER: InterruptHandler = InterruptHandler::new(E1000_INTERRUPT_VECTOR, "e1000", e1000_interrupt_handler);
 
-// Register interrupt handlers
+static INTERRUPT_HANDLERS: [&InterruptHandler; 6] = [
+    &TIMER_INTERRUPT_HANDLER,
+    &KEYBOARD_INTERRUPT_HANDLER,
+    &MOUSE_INTERRUPT_HANDLER,
+    &AHCI_INTERRUPT_HANDLER,
+    &VIRTIO_INTERRUPT_HANDLER,
+    &E1000_INTERRUPT_HANDLER,
+];
+
+// Register interrupt handlers; the handlers find the devices they serve in the
+// registry each time, so devices added or removed later are served or left alone
 pub fn register_interrupt_handlers() {
-    TIMER_INTERRUPT_HANDLER.register();
-    KEYBOARD_INTERRUPT_HANDLER.register();
+    for handler in INTERRUPT_HANDLERS {
+        handler.register();
+    }
+}
+
+// Run the handler for an interrupt vector, returning false when none is registered.
+// The architecture's entry code calls this for every device interrupt; there is no
+// IDT yet, so until one routes the vectors here no interrupt is handled or counted.
+pub fn dispatch(vector: u8) -> bool {
+    match INTERRUPT_HANDLERS.iter().find(|handler| handler.vector == vector) {
+        Some(handler) => {
+            HANDLER_DEPTH.fetch_add(1, Ordering::SeqCst);
+            handler.handle();
+            HANDLER_DEPTH.fetch_sub(1, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+// Whether the caller runs inside an interrupt handler, for code that must only run
+// on the thread side because it takes locks the interrupted code may hold
+pub fn in_interrupt() -> bool {
+    HANDLER_DEPTH.load(Ordering::SeqCst) > 0
+}
+
+// Interrupts handled on each vector since boot, in vector order
+pub fn interrupt_counts() -> Vec<InterruptCount> {
+    let mut counts: Vec<InterruptCount> = INTERRUPT_HANDLERS
+        .iter()
+        .map(|handler| InterruptCount {
+            vector: handler.vector,
+            name: handler.name,
+            count: handler.count.load(Ordering::Relaxed),
+        })
+        .collect();
+    counts.sort_by_key(|count| count.vector);
+    counts
 }
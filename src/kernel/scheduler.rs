@@ -1,7 +1,9 @@
+use crate::process::process::{self, ProcessId};
 use crate::task::Task;
 
 pub struct Scheduler {
-    tasks: Vec<Task>,
+    // Each task runs on behalf of a process
+    tasks: Vec<(ProcessId, Task)>,
     current_task: usize,
 }
 
@@ -13,16 +15,21 @@ impl Scheduler {
         }
     }
 
-    pub fn add_task(&mut self, task: Task) {
-        self.tasks.push(task);
+    pub fn add_task(&mut self, process: ProcessId, task: Task) {
+        self.tasks.push((process, task));
     }
 
     pub fn run(&mut self) {
         while !self.tasks.is_empty() {
-            let task = &mut self.tasks[self.current_task];
+            let (process, task) = &mut self.tasks[self.current_task];
+            process::switch_to(*process);
             task.run();
             if task.is_done() {
+                process::exit(*process);
                 self.tasks.remove(self.current_task);
+                if self.current_task == self.tasks.len() {
+                    self.current_task = 0;
+                }
             } else {
                 self.current_task = (self.current_task + 1) % self.tasks.len();
             }
@@ -1,28 +1,138 @@
+use crate::drivers::console;
+use crate::drivers::keyboard::Keycode;
+use crate::kernel::input::{self, EventFilter, InputEventKind, SubscriberId};
 use core::fmt::{self, Write};
+use std::collections::VecDeque;
 
-pub struct Console;
+// The kernel console: output goes to the kernel log and the framebuffer console,
+// input comes from the keyboard through a line discipline
+pub struct Console {
+    discipline: Option<LineDiscipline>,
+}
 
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
-            self.write_byte(byte)?;
-        }
-        Ok(())
+        self.write_bytes(s.as_bytes())
     }
 }
 
 impl Console {
     pub fn new() -> Console {
-        Console {}
+        Console { discipline: None }
     }
 
     pub fn write_byte(&mut self, byte: u8) -> fmt::Result {
-        // write byte to console
+        self.write_bytes(&[byte])
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> fmt::Result {
+        console::write_bytes(bytes);
         Ok(())
     }
 
+    // Scroll the screen into the scrollback by `lines`, or back towards the live screen
+    // if negative
+    pub fn scroll_view(&mut self, lines: isize) {
+        console::scroll_view(lines);
+    }
+
+    // Wait for the next byte of input, which is available once its line is complete
     pub fn read_byte(&mut self) -> u8 {
-        // read byte from console
-        0
+        // The discipline echoes to this console, so it is taken out while polling
+        let mut discipline = self.discipline.take().unwrap_or_else(LineDiscipline::new);
+        let byte = loop {
+            discipline.poll(self);
+            if let Some(byte) = discipline.read_byte() {
+                break byte;
+            }
+            core::hint::spin_loop();
+        };
+        self.discipline = Some(discipline);
+        byte
+    }
+}
+
+// Canonical-mode line editing for the console: keyboard events from the input
+// layer are collected into a line, echoed, and handed to readers on Enter
+pub struct LineDiscipline {
+    subscriber: SubscriberId,
+    line: Vec<u8>,
+    ready: VecDeque<u8>,
+    echo: bool,
+}
+
+impl LineDiscipline {
+    pub fn new() -> LineDiscipline {
+        LineDiscipline {
+            subscriber: input::subscribe(EventFilter::all().keys_only()),
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            echo: true,
+        }
+    }
+
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    // Process pending key events, echoing to the console
+    pub fn poll(&mut self, console: &mut Console) {
+        while let Some(event) = input::read(self.subscriber) {
+            let key = match event.kind {
+                InputEventKind::Key(key) if !key.released => key,
+                _ => continue,
+            };
+
+            match key.keycode {
+                Keycode::Enter => {
+                    self.line.push(b'\n');
+                    self.ready.extend(self.line.drain(..));
+                    self.echo_bytes(console, b"\n");
+                }
+                // Shift+PageUp and Shift+PageDown page through the scrollback
+                Keycode::PageUp if key.modifiers.shift() => {
+                    console.scroll_view((console::rows() / 2) as isize);
+                }
+                Keycode::PageDown if key.modifiers.shift() => {
+                    console.scroll_view(-((console::rows() / 2) as isize));
+                }
+                Keycode::Backspace => {
+                    if self.line.pop().is_some() {
+                        self.echo_bytes(console, b"\x08 \x08");
+                    }
+                }
+                // Ctrl+U discards the line being edited
+                Keycode::Char('u') if key.modifiers.ctrl() => {
+                    for _ in 0..self.line.len() {
+                        self.echo_bytes(console, b"\x08 \x08");
+                    }
+                    self.line.clear();
+                }
+                Keycode::Char(c) if c.is_ascii() && !key.modifiers.ctrl() => {
+                    self.line.push(c as u8);
+                    self.echo_bytes(console, &[c as u8]);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn echo_bytes(&self, console: &mut Console, bytes: &[u8]) {
+        if self.echo {
+            for &byte in bytes {
+                let _ = console.write_byte(byte);
+            }
+        }
+    }
+
+    // Take the next byte of a completed line, if any
+    pub fn read_byte(&mut self) -> Option<u8> {
+        self.ready.pop_front()
+    }
+}
+
+impl Drop for LineDiscipline {
+    fn drop(&mut self) {
+        input::unsubscribe(self.subscriber);
     }
 }
@@ -0,0 +1,694 @@
+// Terminal emulation
+// A character grid driven by a byte stream, understanding the VT100/ANSI escape
+// sequences that shells and programs write: cursor movement, erasing, scroll regions
+// and SGR colors. The grid knows nothing about pixels; `drivers::console` draws it on
+// the framebuffer, using the damage the terminal records to redraw only what changed.
+
+use std::collections::VecDeque;
+
+pub const TAB_WIDTH: usize = 8;
+// Lines kept after they scroll off the top of the screen
+pub const SCROLLBACK_LINES: usize = 1000;
+
+// More parameters than this in one sequence are ignored
+const MAX_PARAMS: usize = 16;
+const MAX_PARAM_VALUE: u32 = 9999;
+
+// The 16 colors of the Linux console, as 0x00RRGGBB
+pub const PALETTE: [u32; 16] = [
+    0x000000, 0xAA0000, 0x00AA00, 0xAA5500, 0x0000AA, 0xAA00AA, 0x00AAAA, 0xAAAAAA, 0x555555, 0xFF5555, 0x55FF55,
+    0xFFFF55, 0x5555FF, 0xFF55FF, 0x55FFFF, 0xFFFFFF,
+];
+const DEFAULT_FOREGROUND: u8 = 7;
+const DEFAULT_BACKGROUND: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TermColor {
+    Default,
+    // 0-15 from the palette, 16-231 a 6x6x6 color cube, 232-255 a gray ramp
+    Indexed(u8),
+    Rgb(u32),
+}
+
+// The color of a 256-color index
+pub fn indexed_color(index: u8) -> u32 {
+    match index {
+        0..=15 => PALETTE[index as usize],
+        16..=231 => {
+            let level = |value: u8| if value == 0 { 0 } else { 55 + value as u32 * 40 };
+            let cube = index - 16;
+            (level(cube / 36) << 16) | (level(cube / 6 % 6) << 8) | level(cube % 6)
+        }
+        232..=255 => {
+            let gray = 8 + (index - 232) as u32 * 10;
+            (gray << 16) | (gray << 8) | gray
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub foreground: TermColor,
+    pub background: TermColor,
+    pub bold: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Default for Attributes {
+    fn default() -> Attributes {
+        Attributes {
+            foreground: TermColor::Default,
+            background: TermColor::Default,
+            bold: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+}
+
+impl Attributes {
+    // Foreground and background as 0x00RRGGBB. Bold text uses the bright version of
+    // the eight basic colors, as on the Linux console.
+    pub fn colors(&self) -> (u32, u32) {
+        let foreground = match self.foreground {
+            TermColor::Default if self.bold => PALETTE[DEFAULT_FOREGROUND as usize + 8],
+            TermColor::Default => PALETTE[DEFAULT_FOREGROUND as usize],
+            TermColor::Indexed(index) if self.bold && index < 8 => PALETTE[index as usize + 8],
+            TermColor::Indexed(index) => indexed_color(index),
+            TermColor::Rgb(rgb) => rgb,
+        };
+        let background = match self.background {
+            TermColor::Default => PALETTE[DEFAULT_BACKGROUND as usize],
+            TermColor::Indexed(index) => indexed_color(index),
+            TermColor::Rgb(rgb) => rgb,
+        };
+        if self.reverse {
+            (background, foreground)
+        } else {
+            (foreground, background)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub c: char,
+    pub attributes: Attributes,
+}
+
+impl Cell {
+    // An erased cell keeps the background color in effect, as on VT220 and later
+    fn blank(attributes: Attributes) -> Cell {
+        Cell {
+            c: ' ',
+            attributes: Attributes {
+                background: attributes.background,
+                ..Attributes::default()
+            },
+        }
+    }
+}
+
+// What changed since the last call to `take_damage`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    // Lines the whole screen moved up by, which can be redrawn by moving pixels
+    pub scrolled: usize,
+    // Rows to redraw, in current screen coordinates
+    pub rows: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    // ESC ( and friends designate a character set; the next byte is skipped
+    Charset,
+    Csi,
+}
+
+pub struct Terminal {
+    columns: usize,
+    rows: usize,
+    grid: Vec<Cell>,
+    scrollback: VecDeque<Vec<Cell>>,
+    // Lines of scrollback shown at the top of the screen
+    view_offset: usize,
+    cursor_row: usize,
+    cursor_column: usize,
+    // Set after writing the last column; the next character wraps first
+    wrap_pending: bool,
+    saved_cursor: (usize, usize, Attributes),
+    attributes: Attributes,
+    cursor_visible: bool,
+    // Rows scrolling happens in, top inclusive and bottom exclusive
+    scroll_top: usize,
+    scroll_bottom: usize,
+    state: State,
+    params: Vec<u32>,
+    private: bool,
+    // A partial UTF-8 character
+    utf8_value: u32,
+    utf8_remaining: u8,
+    dirty: Vec<bool>,
+    scrolled: usize,
+}
+
+impl Terminal {
+    pub fn new(columns: usize, rows: usize) -> Terminal {
+        let columns = columns.max(1);
+        let rows = rows.max(1);
+        Terminal {
+            columns,
+            rows,
+            grid: vec![Cell::blank(Attributes::default()); columns * rows],
+            scrollback: VecDeque::new(),
+            view_offset: 0,
+            cursor_row: 0,
+            cursor_column: 0,
+            wrap_pending: false,
+            saved_cursor: (0, 0, Attributes::default()),
+            attributes: Attributes::default(),
+            cursor_visible: true,
+            scroll_top: 0,
+            scroll_bottom: rows,
+            state: State::Ground,
+            params: Vec::new(),
+            private: false,
+            utf8_value: 0,
+            utf8_remaining: 0,
+            dirty: vec![true; rows],
+            scrolled: 0,
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    // Cursor position as (row, column)
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor_row, self.cursor_column)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.len()
+    }
+
+    pub fn view_offset(&self) -> usize {
+        self.view_offset
+    }
+
+    // The cell shown at (row, column), taking scrolling back into account
+    pub fn cell(&self, row: usize, column: usize) -> Cell {
+        if row < self.view_offset {
+            let line = &self.scrollback[self.scrollback.len() - self.view_offset + row];
+            line.get(column).copied().unwrap_or(Cell::blank(Attributes::default()))
+        } else {
+            self.grid[(row - self.view_offset) * self.columns + column]
+        }
+    }
+
+    // The text shown on a row, without trailing spaces
+    pub fn line(&self, row: usize) -> String {
+        let text: String = (0..self.columns).map(|column| self.cell(row, column).c).collect();
+        text.trim_end().to_string()
+    }
+
+    // Show older lines: positive `lines` scroll back into the history, negative ones
+    // towards the live screen
+    pub fn scroll_view(&mut self, lines: isize) {
+        let offset = (self.view_offset as isize + lines).clamp(0, self.scrollback.len() as isize) as usize;
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.damage_all();
+        }
+    }
+
+    pub fn take_damage(&mut self) -> Damage {
+        let rows = (0..self.rows).filter(|row| self.dirty[*row]).collect();
+        self.dirty.fill(false);
+        Damage {
+            scrolled: core::mem::take(&mut self.scrolled),
+            rows,
+        }
+    }
+
+    fn damage_all(&mut self) {
+        self.dirty.fill(true);
+    }
+
+    // Back to the power-on state, keeping the scrollback (ESC c)
+    pub fn reset(&mut self) {
+        self.attributes = Attributes::default();
+        self.grid.fill(Cell::blank(self.attributes));
+        self.cursor_row = 0;
+        self.cursor_column = 0;
+        self.wrap_pending = false;
+        self.saved_cursor = (0, 0, self.attributes);
+        self.cursor_visible = true;
+        self.scroll_top = 0;
+        self.scroll_bottom = self.rows;
+        self.state = State::Ground;
+        self.damage_all();
+    }
+
+    // Change the screen size, e.g. after a display mode change. Lines keep their text
+    // up to the new width; when the screen gets shorter, the lines above the cursor
+    // go to the scrollback so the cursor stays on screen.
+    pub fn resize(&mut self, columns: usize, rows: usize) {
+        let columns = columns.max(1);
+        let rows = rows.max(1);
+        if (columns, rows) == (self.columns, self.rows) {
+            return;
+        }
+
+        let shift = (self.cursor_row + 1).saturating_sub(rows);
+        for row in 0..shift {
+            if self.scrollback.len() == SCROLLBACK_LINES {
+                self.scrollback.pop_front();
+            }
+            self.scrollback.push_back(self.grid[row * self.columns..(row + 1) * self.columns].to_vec());
+        }
+        let mut grid = vec![Cell::blank(Attributes::default()); columns * rows];
+        for row in 0..rows.min(self.rows - shift) {
+            let kept = columns.min(self.columns);
+            let old = (row + shift) * self.columns;
+            grid[row * columns..row * columns + kept].copy_from_slice(&self.grid[old..old + kept]);
+        }
+
+        self.grid = grid;
+        self.columns = columns;
+        self.rows = rows;
+        self.view_offset = 0;
+        self.cursor_row -= shift;
+        self.cursor_column = self.cursor_column.min(columns - 1);
+        self.wrap_pending = false;
+        let (saved_row, saved_column, saved_attributes) = self.saved_cursor;
+        self.saved_cursor = (saved_row.min(rows - 1), saved_column.min(columns - 1), saved_attributes);
+        self.scroll_top = 0;
+        self.scroll_bottom = rows;
+        self.dirty = vec![true; rows];
+        self.scrolled = 0;
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        // New output brings the live screen back, as on the Linux console
+        self.scroll_view(-(self.view_offset as isize));
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if self.utf8_remaining > 0 {
+            if byte & 0xC0 == 0x80 {
+                self.utf8_value = (self.utf8_value << 6) | (byte & 0x3F) as u32;
+                self.utf8_remaining -= 1;
+                if self.utf8_remaining == 0 {
+                    let c = char::from_u32(self.utf8_value).unwrap_or(char::REPLACEMENT_CHARACTER);
+                    self.input(c);
+                }
+                return;
+            }
+            // A sequence cut short stands for one bad character
+            self.utf8_remaining = 0;
+            self.input(char::REPLACEMENT_CHARACTER);
+        }
+        match byte {
+            0x00..=0x7F => self.input(byte as char),
+            0xC0..=0xDF => self.start_utf8(byte & 0x1F, 1),
+            0xE0..=0xEF => self.start_utf8(byte & 0x0F, 2),
+            0xF0..=0xF7 => self.start_utf8(byte & 0x07, 3),
+            _ => self.input(char::REPLACEMENT_CHARACTER),
+        }
+    }
+
+    fn start_utf8(&mut self, value: u8, remaining: u8) {
+        self.utf8_value = value as u32;
+        self.utf8_remaining = remaining;
+    }
+
+    fn input(&mut self, c: char) {
+        // Control characters act in the middle of escape sequences too
+        match c {
+            '\x07' => {}
+            '\x08' => {
+                self.cursor_column = self.cursor_column.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            '\t' => {
+                let next = (self.cursor_column / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.cursor_column = next.min(self.columns - 1);
+                self.wrap_pending = false;
+            }
+            // The console translates a line feed into carriage return and line feed,
+            // like a tty with `onlcr`, so kernel messages need no '\r'
+            '\n' | '\x0B' | '\x0C' => {
+                self.cursor_column = 0;
+                self.line_feed();
+            }
+            '\r' => {
+                self.cursor_column = 0;
+                self.wrap_pending = false;
+            }
+            '\x18' | '\x1A' => self.state = State::Ground,
+            '\x1B' => self.state = State::Escape,
+            _ if c.is_control() => {}
+            _ => match self.state {
+                State::Ground => self.print(c),
+                State::Escape => self.escape(c),
+                State::Charset => self.state = State::Ground,
+                State::Csi => self.csi(c),
+            },
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        if self.wrap_pending {
+            self.cursor_column = 0;
+            self.line_feed();
+        }
+        self.grid[self.cursor_row * self.columns + self.cursor_column] = Cell {
+            c,
+            attributes: self.attributes,
+        };
+        self.dirty[self.cursor_row] = true;
+        if self.cursor_column + 1 == self.columns {
+            self.wrap_pending = true;
+        } else {
+            self.cursor_column += 1;
+        }
+    }
+
+    fn escape(&mut self, c: char) {
+        self.state = State::Ground;
+        match c {
+            '[' => {
+                self.state = State::Csi;
+                self.params.clear();
+                self.private = false;
+            }
+            '(' | ')' | '*' | '+' => self.state = State::Charset,
+            'c' => self.reset(),
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            'D' => self.line_feed(),
+            'E' => {
+                self.cursor_column = 0;
+                self.line_feed();
+            }
+            'M' => self.reverse_line_feed(),
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, c: char) {
+        match c {
+            '0'..='9' => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                if self.params.len() <= MAX_PARAMS {
+                    let param = self.params.last_mut().unwrap();
+                    *param = (*param * 10 + c as u32 - '0' as u32).min(MAX_PARAM_VALUE);
+                }
+            }
+            // Past MAX_PARAMS, one extra parameter is kept to take the digits of
+            // the ignored ones; it is dropped before the sequence is dispatched
+            ';' => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                if self.params.len() <= MAX_PARAMS {
+                    self.params.push(0);
+                }
+            }
+            '?' if self.params.is_empty() => self.private = true,
+            // Intermediate bytes select variants that are not supported
+            '\x20'..='\x2F' | '<'..='?' => {}
+            '\x40'..='\x7E' => {
+                self.state = State::Ground;
+                self.params.truncate(MAX_PARAMS);
+                self.dispatch(c);
+            }
+            _ => self.state = State::Ground,
+        }
+    }
+
+    // Parameter `index`, where 0 and a missing parameter mean `default`
+    fn param(&self, index: usize, default: usize) -> usize {
+        match self.params.get(index) {
+            Some(0) | None => default,
+            Some(value) => *value as usize,
+        }
+    }
+
+    fn dispatch(&mut self, c: char) {
+        if self.private {
+            // Only cursor visibility (DECTCEM) of the private modes is supported
+            if self.params.first() == Some(&25) {
+                match c {
+                    'h' => self.cursor_visible = true,
+                    'l' => self.cursor_visible = false,
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        let count = self.param(0, 1);
+        match c {
+            'A' => self.move_cursor(self.cursor_row.saturating_sub(count), self.cursor_column),
+            'B' => self.move_cursor(self.cursor_row + count, self.cursor_column),
+            'C' => self.move_cursor(self.cursor_row, self.cursor_column + count),
+            'D' => self.move_cursor(self.cursor_row, self.cursor_column.saturating_sub(count)),
+            'E' => self.move_cursor(self.cursor_row + count, 0),
+            'F' => self.move_cursor(self.cursor_row.saturating_sub(count), 0),
+            'G' | '`' => self.move_cursor(self.cursor_row, count - 1),
+            'd' => self.move_cursor(count - 1, self.cursor_column),
+            'H' | 'f' => self.move_cursor(count - 1, self.param(1, 1) - 1),
+            'J' => self.erase_display(self.param(0, 0)),
+            'K' => self.erase_line(self.param(0, 0)),
+            'm' => self.select_graphic_rendition(),
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            'S' => self.scroll_up(self.scroll_top, count),
+            'T' => self.scroll_down(self.scroll_top, count),
+            'L' if self.in_scroll_region() => self.scroll_down(self.cursor_row, count),
+            'M' if self.in_scroll_region() => self.scroll_up(self.cursor_row, count),
+            '@' => self.insert_characters(count),
+            'P' => self.delete_characters(count),
+            'X' => {
+                let end = (self.cursor_column + count).min(self.columns);
+                self.erase(self.cursor_row, self.cursor_column, end);
+            }
+            'r' => {
+                let top = self.param(0, 1) - 1;
+                let bottom = self.param(1, self.rows).min(self.rows);
+                if top + 1 < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.move_cursor(0, 0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn move_cursor(&mut self, row: usize, column: usize) {
+        self.cursor_row = row.min(self.rows - 1);
+        self.cursor_column = column.min(self.columns - 1);
+        self.wrap_pending = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = (self.cursor_row, self.cursor_column, self.attributes);
+    }
+
+    fn restore_cursor(&mut self) {
+        let (row, column, attributes) = self.saved_cursor;
+        self.move_cursor(row, column);
+        self.attributes = attributes;
+    }
+
+    fn in_scroll_region(&self) -> bool {
+        (self.scroll_top..self.scroll_bottom).contains(&self.cursor_row)
+    }
+
+    fn line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor_row + 1 == self.scroll_bottom {
+            self.scroll_up(self.scroll_top, 1);
+        } else if self.cursor_row + 1 < self.rows {
+            self.cursor_row += 1;
+        }
+    }
+
+    fn reverse_line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor_row == self.scroll_top {
+            self.scroll_down(self.scroll_top, 1);
+        } else if self.cursor_row > 0 {
+            self.cursor_row -= 1;
+        }
+    }
+
+    // Move the lines from `top` to the bottom of the scroll region up by `count`
+    fn scroll_up(&mut self, top: usize, count: usize) {
+        let bottom = self.scroll_bottom;
+        let count = count.min(bottom - top);
+        let whole_screen = top == 0 && bottom == self.rows;
+        if whole_screen {
+            for row in 0..count {
+                let line = self.grid[row * self.columns..(row + 1) * self.columns].to_vec();
+                if self.scrollback.len() == SCROLLBACK_LINES {
+                    self.scrollback.pop_front();
+                }
+                self.scrollback.push_back(line);
+            }
+        }
+        self.grid.copy_within((top + count) * self.columns..bottom * self.columns, top * self.columns);
+        self.grid[(bottom - count) * self.columns..bottom * self.columns].fill(Cell::blank(self.attributes));
+
+        if whole_screen {
+            // The pixels on screen can move with the text; only the new lines are drawn
+            self.dirty.rotate_left(count);
+            self.dirty[self.rows - count..].fill(true);
+            self.scrolled = (self.scrolled + count).min(self.rows);
+        } else {
+            self.dirty[top..bottom].fill(true);
+        }
+    }
+
+    // Move the lines from `top` to the bottom of the scroll region down by `count`
+    fn scroll_down(&mut self, top: usize, count: usize) {
+        let bottom = self.scroll_bottom;
+        let count = count.min(bottom - top);
+        self.grid.copy_within(top * self.columns..(bottom - count) * self.columns, (top + count) * self.columns);
+        self.grid[top * self.columns..(top + count) * self.columns].fill(Cell::blank(self.attributes));
+        self.dirty[top..bottom].fill(true);
+    }
+
+    // Blank columns `start..end` of a row
+    fn erase(&mut self, row: usize, start: usize, end: usize) {
+        let line = row * self.columns;
+        self.grid[line + start..line + end].fill(Cell::blank(self.attributes));
+        self.dirty[row] = true;
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        let (start, end) = match mode {
+            0 => (self.cursor_column, self.columns),
+            1 => (0, self.cursor_column + 1),
+            _ => (0, self.columns),
+        };
+        self.erase(self.cursor_row, start, end);
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for row in self.cursor_row + 1..self.rows {
+                    self.erase(row, 0, self.columns);
+                }
+            }
+            1 => {
+                for row in 0..self.cursor_row {
+                    self.erase(row, 0, self.columns);
+                }
+                self.erase_line(1);
+            }
+            _ => {
+                for row in 0..self.rows {
+                    self.erase(row, 0, self.columns);
+                }
+                // Mode 3 also clears the scrollback, as xterm does
+                if mode == 3 {
+                    self.scrollback.clear();
+                }
+            }
+        }
+    }
+
+    fn insert_characters(&mut self, count: usize) {
+        let line = self.cursor_row * self.columns;
+        let count = count.min(self.columns - self.cursor_column);
+        self.grid.copy_within(line + self.cursor_column..line + self.columns - count, line + self.cursor_column + count);
+        self.erase(self.cursor_row, self.cursor_column, self.cursor_column + count);
+    }
+
+    fn delete_characters(&mut self, count: usize) {
+        let line = self.cursor_row * self.columns;
+        let count = count.min(self.columns - self.cursor_column);
+        self.grid.copy_within(line + self.cursor_column + count..line + self.columns, line + self.cursor_column);
+        self.erase(self.cursor_row, self.columns - count, self.columns);
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        if self.params.is_empty() {
+            self.attributes = Attributes::default();
+            return;
+        }
+        let mut index = 0;
+        while index < self.params.len() {
+            let param = self.params[index];
+            match param {
+                0 => self.attributes = Attributes::default(),
+                1 => self.attributes.bold = true,
+                4 => self.attributes.underline = true,
+                7 => self.attributes.reverse = true,
+                22 => self.attributes.bold = false,
+                24 => self.attributes.underline = false,
+                27 => self.attributes.reverse = false,
+                30..=37 => self.attributes.foreground = TermColor::Indexed((param - 30) as u8),
+                39 => self.attributes.foreground = TermColor::Default,
+                40..=47 => self.attributes.background = TermColor::Indexed((param - 40) as u8),
+                49 => self.attributes.background = TermColor::Default,
+                90..=97 => self.attributes.foreground = TermColor::Indexed((param - 90 + 8) as u8),
+                100..=107 => self.attributes.background = TermColor::Indexed((param - 100 + 8) as u8),
+                38 | 48 => {
+                    let (color, used) = self.extended_color(index + 1);
+                    index += used;
+                    if let Some(color) = color {
+                        if param == 38 {
+                            self.attributes.foreground = color;
+                        } else {
+                            self.attributes.background = color;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            index += 1;
+        }
+    }
+
+    // The color after 38 or 48: `5;n` for the 256-color palette or `2;r;g;b`, and
+    // how many parameters it took
+    fn extended_color(&self, index: usize) -> (Option<TermColor>, usize) {
+        let value = |offset: usize| self.params.get(index + offset).map(|value| (*value).min(255));
+        match value(0) {
+            Some(5) => (value(1).map(|color| TermColor::Indexed(color as u8)), 2),
+            Some(2) => match (value(1), value(2), value(3)) {
+                (Some(r), Some(g), Some(b)) => (Some(TermColor::Rgb((r << 16) | (g << 8) | b)), 4),
+                _ => (None, self.params.len() - index),
+            },
+            _ => (None, 0),
+        }
+    }
+}
@@ -14,29 +14,29 @@ use crypto::{cipher, hash, hmac};
 use db::{postgres, redis, sqlite};
 
 // drivers
-use drivers::{gpu, keyboard, network, storage};
+use drivers::{ahci, console, device, dma, gpu, keyboard, mouse, network, pci, ps2, storage, virtio, virtio_blk, virtio_net};
 
 // fs
-use fs::{ext2, fat, nfts, vfs};
+use fs::{cpio, ext2, ext4, fat, initramfs, iso9660, jbd2, nfts, procfs, tmpfs, vfs};
 
 // gui
 use gui::{
-    button, components, event, images, label, layouts, menu, textbox, theme, themes, utils, widget,
+    button, components, context, event, images, label, layouts, menu, textbox, theme, themes, utils, widget,
     window,
 };
 use gui::components::{button as button_component, label as label_component, menu as menu_component, textbox as textbox_component};
 
 // kernel
-use kernel::{interrupts, memory, scheduler, syscall};
+use kernel::{input, interrupts, memory, scheduler, syscall};
 
 // lib
-use lib::{collections, io, math, sync};
+use lib::{collections, io, math, sync, terminal};
 
 // mm
 use mm::{allocator, paging, virtual};
 
 // net
-use net::{dns, ip, tcp, udp};
+use net::{dns, hosted, interface, ip, loopback, socket_table, tcp, udp};
 
 // process
 use process::{ipc, process, thread};
@@ -48,7 +48,7 @@ use security::{auth, firewall, tls};
 use storage::{block, inode, journal};
 
 // tests
-use tests::{keyboard_test, network_test, unit_test};
+use tests::{console_test, fs_test, graphics_test, input_test, keyboard_test, network_test, storage_test, unit_test};
 
 // util
 use util::{config, logging, time};
@@ -31,3 +31,30 @@ impl Allocator {
         }
     }
 }
+
+/// Memory managed by the frame allocator, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub total: usize,
+    pub used: usize,
+}
+
+impl MemoryStats {
+    pub fn free(&self) -> usize {
+        self.total - self.used
+    }
+}
+
+/// Usage of the frame allocator; all zero before `Allocator::init_heap`
+pub fn memory_stats() -> MemoryStats {
+    unsafe {
+        match ALLOCATOR.as_ref() {
+            Some(heap) => MemoryStats {
+                total: heap.stats_total_bytes(),
+                // Including what rounding up to a power of two wastes
+                used: heap.stats_alloc_actual(),
+            },
+            None => MemoryStats::default(),
+        }
+    }
+}
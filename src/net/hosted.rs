@@ -0,0 +1,207 @@
+// Interfaces for running the network stack on a development machine
+// `PipeInterface` connects two in-process interfaces back to back, which is enough
+// for tests that run the stack against itself. `TapInterface` attaches the stack to
+// a Linux TAP device, so it can talk to the host's network tools.
+
+use crate::net::interface::{
+    self, InterfaceState, InterfaceStats, MacAddress, NetError, NetInterface, ReceiveCallback, ETHERNET_MTU,
+};
+use spin::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+type FrameQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// One end of an in-process packet pipe
+pub struct PipeInterface {
+    mac: MacAddress,
+    inbound: FrameQueue,
+    outbound: FrameQueue,
+    connected: Mutex<bool>,
+    state: InterfaceState,
+}
+
+impl PipeInterface {
+    /// Create two interfaces connected to each other: frames sent on one are
+    /// received on the other
+    pub fn pair(mac_a: MacAddress, mac_b: MacAddress) -> (Arc<PipeInterface>, Arc<PipeInterface>) {
+        let a_to_b: FrameQueue = Arc::new(Mutex::new(VecDeque::new()));
+        let b_to_a: FrameQueue = Arc::new(Mutex::new(VecDeque::new()));
+        let a = PipeInterface {
+            mac: mac_a,
+            inbound: b_to_a.clone(),
+            outbound: a_to_b.clone(),
+            connected: Mutex::new(true),
+            state: InterfaceState::new(),
+        };
+        let b = PipeInterface {
+            mac: mac_b,
+            inbound: a_to_b,
+            outbound: b_to_a,
+            connected: Mutex::new(true),
+            state: InterfaceState::new(),
+        };
+        (Arc::new(a), Arc::new(b))
+    }
+
+    /// Simulate unplugging the cable: sends fail with `LinkDown` until reconnected
+    pub fn set_connected(&self, connected: bool) {
+        *self.connected.lock() = connected;
+    }
+}
+
+impl NetInterface for PipeInterface {
+    fn mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        *self.connected.lock()
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        let result = interface::check_frame(self, frame).and_then(|_| {
+            if !self.link_up() {
+                return Err(NetError::LinkDown);
+            }
+            self.outbound.lock().push_back(frame.to_vec());
+            Ok(())
+        });
+        self.state.sent(&result, frame.len());
+        result
+    }
+
+    fn set_receive_callback(&self, callback: Option<ReceiveCallback>) {
+        self.state.set_callback(callback);
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        self.poll();
+        self.state.take()
+    }
+
+    fn poll(&self) {
+        let frames: Vec<Vec<u8>> = self.inbound.lock().drain(..).collect();
+        for frame in frames {
+            self.state.deliver(frame);
+        }
+    }
+
+    fn stats(&self) -> InterfaceStats {
+        self.state.stats()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use tap::TapInterface;
+
+#[cfg(target_os = "linux")]
+mod tap {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    const TUN_DEVICE: &str = "/dev/net/tun";
+    const TUNSETIFF: libc::c_ulong = 0x4004_54CA;
+    const IFF_TAP: libc::c_short = 0x0002;
+    const IFF_NO_PI: libc::c_short = 0x1000;
+    const IFNAMSIZ: usize = 16;
+
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; IFNAMSIZ],
+        flags: libc::c_short,
+        _padding: [u8; 22],
+    }
+
+    /// An interface backed by a Linux TAP device. The device must exist and be up,
+    /// e.g. `ip tuntap add dev tap0 mode tap user $USER && ip link set tap0 up`.
+    pub struct TapInterface {
+        file: Mutex<File>,
+        mac: MacAddress,
+        state: InterfaceState,
+    }
+
+    impl TapInterface {
+        /// Attach to the TAP device `name`; `mac` is the address the stack uses,
+        /// which is distinct from the host side's address
+        pub fn open(name: &str, mac: MacAddress) -> Result<TapInterface, NetError> {
+            if name.len() >= IFNAMSIZ {
+                return Err(NetError::Io(ErrorKind::InvalidInput));
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(TUN_DEVICE)?;
+
+            let mut request = IfReq {
+                name: [0; IFNAMSIZ],
+                flags: IFF_TAP | IFF_NO_PI,
+                _padding: [0; 22],
+            };
+            request.name[..name.len()].copy_from_slice(name.as_bytes());
+            if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut request) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            Ok(TapInterface {
+                file: Mutex::new(file),
+                mac,
+                state: InterfaceState::new(),
+            })
+        }
+    }
+
+    impl NetInterface for TapInterface {
+        fn mtu(&self) -> usize {
+            ETHERNET_MTU
+        }
+
+        fn mac(&self) -> MacAddress {
+            self.mac
+        }
+
+        fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+            let result = interface::check_frame(self, frame).and_then(|_| {
+                // A TAP device takes exactly one frame per write, so this is a single write
+                self.file.lock().write_all(frame)?;
+                Ok(())
+            });
+            self.state.sent(&result, frame.len());
+            result
+        }
+
+        fn set_receive_callback(&self, callback: Option<ReceiveCallback>) {
+            self.state.set_callback(callback);
+        }
+
+        fn receive(&self) -> Option<Vec<u8>> {
+            self.poll();
+            self.state.take()
+        }
+
+        fn poll(&self) {
+            let mut buffer = vec![0u8; ETHERNET_MTU + interface::ETHERNET_HEADER_LEN];
+            loop {
+                // Each read returns one frame; the lock is not held while delivering
+                let read = self.file.lock().read(&mut buffer);
+                match read {
+                    Ok(len) if len > 0 => self.state.deliver(buffer[..len].to_vec()),
+                    Err(ref error) if error.kind() == ErrorKind::Interrupted => continue,
+                    _ => break,
+                }
+            }
+        }
+
+        fn stats(&self) -> InterfaceStats {
+            self.state.stats()
+        }
+    }
+}
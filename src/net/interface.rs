@@ -0,0 +1,231 @@
+//! Network interfaces
+//!
+//! Everything the IP layer sends or receives goes through a `NetInterface`, which
+//! hides whether frames end up on a NIC, loop back, or go to a TAP device on the
+//! development machine. Interfaces are registered by name (`lo`, `eth0`, ...) and
+//! hand received frames to a callback installed by the stack.
+
+use crate::drivers::device::DeviceError;
+use crate::kernel::interrupts;
+use spin::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+pub type MacAddress = [u8; 6];
+
+/// Length of an Ethernet header: destination, source and EtherType
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
+/// The standard Ethernet MTU
+pub const ETHERNET_MTU: usize = 1500;
+
+/// Frames kept for an interface without a receive callback before the oldest are dropped
+const RECEIVE_BACKLOG: usize = 256;
+
+/// Called with every frame an interface receives
+pub type ReceiveCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetError {
+    FrameTooLarge,
+    LinkDown,
+    /// The transmit queue is full; try again later
+    Busy,
+    NameInUse,
+    NotFound,
+    Device(DeviceError),
+    Io(std::io::ErrorKind),
+}
+
+impl From<DeviceError> for NetError {
+    fn from(error: DeviceError) -> Self {
+        match error {
+            DeviceError::Busy => NetError::Busy,
+            error => NetError::Device(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for NetError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            // A non-blocking device with no room for the frame, e.g. a full TAP queue
+            std::io::ErrorKind::WouldBlock => NetError::Busy,
+            kind => NetError::Io(kind),
+        }
+    }
+}
+
+/// Packet and byte counters of an interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+/// A link the network stack can send Ethernet frames through
+pub trait NetInterface: Send + Sync {
+    /// Largest payload of a frame, excluding the Ethernet header
+    fn mtu(&self) -> usize;
+
+    fn mac(&self) -> MacAddress;
+
+    fn link_up(&self) -> bool {
+        true
+    }
+
+    /// Queue a complete Ethernet frame for transmission
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Install the callback that receives incoming frames, or remove it with `None`.
+    /// Without a callback, frames are kept until read with `receive`.
+    fn set_receive_callback(&self, callback: Option<ReceiveCallback>);
+
+    /// Take the oldest frame received while no callback was installed
+    fn receive(&self) -> Option<Vec<u8>>;
+
+    /// Process frames that arrived since the last poll. Received frames are only
+    /// delivered from here, never from an interrupt handler; interrupt driven
+    /// interfaces just note in the handler that there is work for the next poll.
+    fn poll(&self) {}
+
+    fn stats(&self) -> InterfaceStats;
+}
+
+/// Receive callback, backlog and counters shared by the interface implementations
+pub struct InterfaceState {
+    callback: Mutex<Option<ReceiveCallback>>,
+    backlog: Mutex<VecDeque<Vec<u8>>>,
+    stats: Mutex<InterfaceStats>,
+}
+
+impl InterfaceState {
+    pub fn new() -> InterfaceState {
+        InterfaceState {
+            callback: Mutex::new(None),
+            backlog: Mutex::new(VecDeque::new()),
+            stats: Mutex::new(InterfaceStats::default()),
+        }
+    }
+
+    pub fn set_callback(&self, callback: Option<ReceiveCallback>) {
+        *self.callback.lock() = callback;
+    }
+
+    /// Hand a received frame to the callback, or keep it in the backlog.
+    ///
+    /// Must be called from thread context, normally from `NetInterface::poll`: the
+    /// callback runs the stack, whose locks are not interrupt safe, so delivering
+    /// from an interrupt handler could deadlock on a lock the interrupted code holds.
+    pub fn deliver(&self, frame: Vec<u8>) {
+        assert!(!interrupts::in_interrupt(), "network frames must not be delivered in interrupt context");
+        {
+            let mut stats = self.stats.lock();
+            stats.rx_packets += 1;
+            stats.rx_bytes += frame.len() as u64;
+        }
+        // The callback runs without any lock held, so it may send frames itself
+        let callback = self.callback.lock().clone();
+        match callback {
+            Some(callback) => callback(&frame),
+            None => {
+                let mut backlog = self.backlog.lock();
+                if backlog.len() == RECEIVE_BACKLOG {
+                    backlog.pop_front();
+                    self.stats.lock().rx_dropped += 1;
+                }
+                backlog.push_back(frame);
+            }
+        }
+    }
+
+    pub fn take(&self) -> Option<Vec<u8>> {
+        self.backlog.lock().pop_front()
+    }
+
+    /// Count a frame dropped before it could be delivered, e.g. for a receive error
+    pub fn dropped(&self) {
+        self.stats.lock().rx_dropped += 1;
+    }
+
+    /// Count the outcome of a send
+    pub fn sent(&self, result: &Result<(), NetError>, len: usize) {
+        let mut stats = self.stats.lock();
+        match result {
+            Ok(()) => {
+                stats.tx_packets += 1;
+                stats.tx_bytes += len as u64;
+            }
+            Err(_) => stats.tx_errors += 1,
+        }
+    }
+
+    pub fn stats(&self) -> InterfaceStats {
+        *self.stats.lock()
+    }
+}
+
+/// Check a frame against an interface's MTU before sending it
+pub fn check_frame(interface: &dyn NetInterface, frame: &[u8]) -> Result<(), NetError> {
+    if frame.len() > interface.mtu() + ETHERNET_HEADER_LEN {
+        return Err(NetError::FrameTooLarge);
+    }
+    Ok(())
+}
+
+static INTERFACES: Mutex<Vec<(String, Arc<dyn NetInterface>)>> = Mutex::new(Vec::new());
+
+/// Register an interface under a fixed name such as `lo`
+pub fn add_interface(name: &str, interface: Arc<dyn NetInterface>) -> Result<(), NetError> {
+    let mut interfaces = INTERFACES.lock();
+    if interfaces.iter().any(|(existing, _)| existing == name) {
+        return Err(NetError::NameInUse);
+    }
+    interfaces.push((name.to_string(), interface));
+    Ok(())
+}
+
+/// Register an Ethernet interface under the lowest free `ethN` name
+pub fn add_ethernet(interface: Arc<dyn NetInterface>) -> String {
+    let mut interfaces = INTERFACES.lock();
+    let name = (0..)
+        .map(|index| format!("eth{}", index))
+        .find(|name| !interfaces.iter().any(|(existing, _)| existing == name))
+        .unwrap();
+    interfaces.push((name.clone(), interface));
+    name
+}
+
+pub fn remove_interface(name: &str) -> Result<(), NetError> {
+    let mut interfaces = INTERFACES.lock();
+    let index = interfaces
+        .iter()
+        .position(|(existing, _)| existing == name)
+        .ok_or(NetError::NotFound)?;
+    interfaces.remove(index);
+    Ok(())
+}
+
+pub fn find_interface(name: &str) -> Option<Arc<dyn NetInterface>> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|(existing, _)| existing == name)
+        .map(|(_, interface)| interface.clone())
+}
+
+/// Every registered interface, in registration order
+pub fn interfaces() -> Vec<(String, Arc<dyn NetInterface>)> {
+    INTERFACES.lock().clone()
+}
+
+/// Poll every interface; called periodically by the stack
+pub fn poll_all() {
+    for (_, interface) in interfaces() {
+        interface.poll();
+    }
+}
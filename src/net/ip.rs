@@ -1,62 +1,404 @@
-use crate::net::ipv4::Ipv4Addr;
-use crate::net::ipv6::Ipv6Addr;
-use crate::net::socket::{Socket, SocketAddr};
+//! IPv4
+//!
+//! The IP layer sends and receives through the interfaces registered in
+//! `net::interface`. `configure` gives an interface an address and installs the
+//! receive callback that hands its frames to ARP and to the transport protocols.
+//! Packets leave through the interface whose subnet holds the destination, or
+//! through the default gateway; the Ethernet address of the next hop is resolved
+//! with ARP, and packets wait for the reply. Fragmented packets are dropped.
 
-pub enum IpAddr {
-    V4(Ipv4Addr),
-    V6(Ipv6Addr),
+use crate::net::interface::{self, MacAddress, NetError, NetInterface, ETHERNET_HEADER_LEN};
+use crate::net::{tcp, udp};
+use spin::Mutex;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// Length of an IPv4 header without options
+pub const IPV4_HEADER_LEN: usize = 20;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const BROADCAST_MAC: MacAddress = [0xFF; 6];
+const DEFAULT_TTL: u8 = 64;
+
+// Flags and fragment offset; anything but "don't fragment" means a fragment
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+
+const ARP_PACKET_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// Packets kept per next hop while its Ethernet address is being resolved
+const ARP_QUEUE_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpError {
+    /// No configured interface reaches the destination
+    NoRoute,
+    /// The packet does not fit the interface's MTU; packets are never fragmented
+    PacketTooLarge,
+    Interface(NetError),
+}
+
+impl From<NetError> for IpError {
+    fn from(error: NetError) -> Self {
+        IpError::Interface(error)
+    }
+}
+
+/// The fields of an IPv4 header the stack uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub id: u16,
+}
+
+impl Ipv4Header {
+    /// Split a packet into its header and payload. Returns `None` for a packet that is
+    /// truncated, fragmented or fails its checksum; options are skipped.
+    pub fn parse(packet: &[u8]) -> Option<(Ipv4Header, &[u8])> {
+        if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0x0F) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < IPV4_HEADER_LEN || total_len < header_len || total_len > packet.len() {
+            return None;
+        }
+        if checksum(&packet[..header_len]) != 0 {
+            return None;
+        }
+        if u16::from_be_bytes([packet[6], packet[7]]) & !FLAG_DONT_FRAGMENT != 0 {
+            return None;
+        }
+        let header = Ipv4Header {
+            id: u16::from_be_bytes([packet[4], packet[5]]),
+            ttl: packet[8],
+            protocol: packet[9],
+            source: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+            destination: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+        };
+        Some((header, &packet[header_len..total_len]))
+    }
+
+    /// Build a packet from the header and a payload
+    pub fn packet(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(IPV4_HEADER_LEN + payload.len());
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&((IPV4_HEADER_LEN + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        packet.extend_from_slice(&[self.ttl, self.protocol, 0, 0]);
+        packet.extend_from_slice(&self.source.octets());
+        packet.extend_from_slice(&self.destination.octets());
+        let sum = checksum(&packet);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
 }
 
-pub struct IpHeader {
-    version: u8,
-    header_len: u8,
-    tos: u8,
-    total_len: u16,
-    id: u16,
-    flags: u8,
-    frag_offset: u16,
-    ttl: u8,
-    protocol: u8,
-    checksum: u16,
-    src_addr: IpAddr,
-    dst_addr: IpAddr,
+// One's complement sum of 16-bit big-endian words, an odd last byte padded with zero
+fn sum_words(sum: u32, data: &[u8]) -> u32 {
+    data.chunks(2).fold(sum, |sum, word| {
+        sum + u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32
+    })
 }
 
-impl IpHeader {
-    // Functions for parsing and constructing IP headers
-    // ...
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum >> 16) + (sum & 0xFFFF);
+    }
+    !(sum as u16)
+}
 
-    // Function for calculating the IP header checksum
-    // ...
+/// The Internet checksum of `data`; a header that includes its checksum sums to 0
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum_words(0, data))
 }
 
-pub struct IpPacket {
-    header: IpHeader,
-    payload: Vec<u8>,
+/// The TCP or UDP checksum of a segment, covering the pseudo-header of its packet
+pub fn transport_checksum(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut sum = sum_words(0, &source.octets());
+    sum = sum_words(sum, &destination.octets());
+    sum += protocol as u32 + segment.len() as u32;
+    fold(sum_words(sum, segment))
 }
 
-impl IpPacket {
-    // Functions for parsing and constructing IP packets
-    // ...
+// An address assigned to an interface
+struct Address {
+    interface: String,
+    link: Arc<dyn NetInterface>,
+    address: Ipv4Addr,
+    prefix_len: u8,
 }
 
-pub struct IpPacketBuffer {
-    buffer: [u8; 1500],
-    len: usize,
+impl Address {
+    fn netmask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0)
+    }
+
+    fn contains(&self, address: Ipv4Addr) -> bool {
+        (u32::from(address) ^ u32::from(self.address)) & self.netmask() == 0
+    }
+
+    fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) | !self.netmask())
+    }
+}
+
+// Where a packet goes next
+struct Route {
+    link: Arc<dyn NetInterface>,
+    source: Ipv4Addr,
+    next_hop: Ipv4Addr,
+    broadcast: bool,
 }
 
-impl IpPacketBuffer {
-    pub fn new() -> Self {
-        Self {
-            buffer: [0; 1500],
-            len: 0,
+struct Stack {
+    addresses: Vec<Address>,
+    gateway: Option<Ipv4Addr>,
+    // Ethernet addresses learned through ARP
+    neighbors: BTreeMap<Ipv4Addr, MacAddress>,
+    // Packets waiting for the Ethernet address of their next hop
+    unresolved: BTreeMap<Ipv4Addr, Vec<Vec<u8>>>,
+    next_id: u16,
+}
+
+impl Stack {
+    // The interface for a destination: local addresses go through `lo` when it is
+    // configured, others through the most specific subnet, then the gateway
+    fn route(&self, destination: Ipv4Addr) -> Option<Route> {
+        if self.addresses.iter().any(|address| address.address == destination) {
+            if let Some(lo) = self.addresses.iter().find(|address| address.interface == "lo") {
+                return Some(Route {
+                    link: lo.link.clone(),
+                    source: destination,
+                    next_hop: destination,
+                    broadcast: false,
+                });
+            }
         }
+        let direct = self
+            .addresses
+            .iter()
+            .filter(|address| address.contains(destination))
+            .max_by_key(|address| address.prefix_len);
+        if let Some(address) = direct {
+            return Some(Route {
+                link: address.link.clone(),
+                source: address.address,
+                next_hop: destination,
+                broadcast: destination == address.broadcast(),
+            });
+        }
+        let gateway = self.gateway?;
+        let address = self.addresses.iter().find(|address| address.contains(gateway))?;
+        Some(Route {
+            link: address.link.clone(),
+            source: address.address,
+            next_hop: gateway,
+            broadcast: false,
+        })
+    }
+}
+
+static STACK: Mutex<Stack> = Mutex::new(Stack {
+    addresses: Vec::new(),
+    gateway: None,
+    neighbors: BTreeMap::new(),
+    unresolved: BTreeMap::new(),
+    next_id: 0,
+});
+
+/// Give the registered interface `name` an address and start handling its traffic,
+/// replacing any address it had
+pub fn configure(name: &str, address: Ipv4Addr, prefix_len: u8) -> Result<(), IpError> {
+    let link = interface::find_interface(name).ok_or(NetError::NotFound)?;
+    let interface = name.to_string();
+    link.set_receive_callback(Some(Arc::new(move |frame: &[u8]| receive(&interface, frame))));
+
+    let mut stack = STACK.lock();
+    stack.addresses.retain(|existing| existing.interface != name);
+    stack.addresses.push(Address {
+        interface: name.to_string(),
+        link,
+        address,
+        prefix_len: prefix_len.min(32),
+    });
+    Ok(())
+}
+
+/// Stop handling an interface's traffic and forget its address
+pub fn deconfigure(name: &str) {
+    STACK.lock().addresses.retain(|address| address.interface != name);
+    if let Some(link) = interface::find_interface(name) {
+        link.set_receive_callback(None);
     }
+}
+
+/// Send packets for destinations outside every configured subnet to `gateway`
+pub fn set_gateway(gateway: Option<Ipv4Addr>) {
+    STACK.lock().gateway = gateway;
+}
+
+/// The address of an interface
+pub fn address(name: &str) -> Option<Ipv4Addr> {
+    STACK
+        .lock()
+        .addresses
+        .iter()
+        .find(|address| address.interface == name)
+        .map(|address| address.address)
+}
+
+/// The address packets to `destination` are sent from
+pub fn source_for(destination: Ipv4Addr) -> Result<Ipv4Addr, IpError> {
+    STACK.lock().route(destination).map(|route| route.source).ok_or(IpError::NoRoute)
+}
+
+/// Send a packet carrying `payload` for `protocol` from `source`, which should be the
+/// address `source_for` picked; packets to unresolved next hops are queued for ARP
+pub fn send(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), IpError> {
+    let mut stack = STACK.lock();
+    let route = stack.route(destination).ok_or(IpError::NoRoute)?;
+    if IPV4_HEADER_LEN + payload.len() > route.link.mtu() {
+        return Err(IpError::PacketTooLarge);
+    }
+    let header = Ipv4Header {
+        source,
+        destination,
+        protocol,
+        ttl: DEFAULT_TTL,
+        id: stack.next_id,
+    };
+    stack.next_id = stack.next_id.wrapping_add(1);
+    let packet = header.packet(payload);
+
+    // Interfaces without an Ethernet address, such as loopback, need no resolution
+    let mac = if route.broadcast {
+        BROADCAST_MAC
+    } else if route.link.mac() == [0; 6] {
+        [0; 6]
+    } else if let Some(mac) = stack.neighbors.get(&route.next_hop) {
+        *mac
+    } else {
+        let queue = stack.unresolved.entry(route.next_hop).or_default();
+        let first = queue.is_empty();
+        if queue.len() < ARP_QUEUE_LEN {
+            queue.push(packet);
+        }
+        drop(stack);
+        if first {
+            let request = arp_packet(ARP_REQUEST, route.link.mac(), route.source, [0; 6], route.next_hop);
+            route.link.send(&ethernet_frame(BROADCAST_MAC, route.link.mac(), ETHERTYPE_ARP, &request))?;
+        }
+        return Ok(());
+    };
+    drop(stack);
+    route.link.send(&ethernet_frame(mac, route.link.mac(), ETHERTYPE_IPV4, &packet))?;
+    Ok(())
+}
 
-    // Functions for constructing IP packets in the buffer
-    // ...
+fn ethernet_frame(destination: MacAddress, source: MacAddress, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&destination);
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
 }
 
-// Functions for IP routing and sending packets to the network interface
-// ...
+fn arp_packet(
+    operation: u16,
+    sender_mac: MacAddress,
+    sender: Ipv4Addr,
+    target_mac: MacAddress,
+    target: Ipv4Addr,
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(ARP_PACKET_LEN);
+    // Ethernet hardware addresses and IPv4 protocol addresses
+    packet.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+    packet.extend_from_slice(&operation.to_be_bytes());
+    packet.extend_from_slice(&sender_mac);
+    packet.extend_from_slice(&sender.octets());
+    packet.extend_from_slice(&target_mac);
+    packet.extend_from_slice(&target.octets());
+    packet
+}
+
+// Handle a frame received on a configured interface
+fn receive(interface: &str, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => receive_arp(interface, payload),
+        ETHERTYPE_IPV4 => receive_ipv4(interface, payload),
+        _ => {}
+    }
+}
+
+// Learn the sender of an ARP packet aimed at us, answer requests, and send the
+// packets that were waiting for the sender's address
+fn receive_arp(interface: &str, packet: &[u8]) {
+    if packet.len() < ARP_PACKET_LEN || packet[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+        return;
+    }
+    let operation = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac: MacAddress = packet[8..14].try_into().unwrap();
+    let sender = Ipv4Addr::new(packet[14], packet[15], packet[16], packet[17]);
+    let target = Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]);
 
+    let mut stack = STACK.lock();
+    let link = match stack
+        .addresses
+        .iter()
+        .find(|address| address.interface == interface && address.address == target)
+    {
+        Some(address) => address.link.clone(),
+        None => return,
+    };
+    stack.neighbors.insert(sender, sender_mac);
+    let waiting = stack.unresolved.remove(&sender).unwrap_or_default();
+    drop(stack);
+
+    if operation == ARP_REQUEST {
+        let reply = arp_packet(ARP_REPLY, link.mac(), target, sender_mac, sender);
+        let _ = link.send(&ethernet_frame(sender_mac, link.mac(), ETHERTYPE_ARP, &reply));
+    }
+    for packet in waiting {
+        let _ = link.send(&ethernet_frame(sender_mac, link.mac(), ETHERTYPE_IPV4, &packet));
+    }
+}
+
+// Hand a packet addressed to this interface to its transport protocol
+fn receive_ipv4(interface: &str, packet: &[u8]) {
+    let (header, payload) = match Ipv4Header::parse(packet) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    // Any local address is accepted on any interface, as packets to this host's own
+    // addresses arrive through `lo`
+    let accepted = STACK.lock().addresses.iter().any(|address| {
+        header.destination == address.address
+            || (address.interface == interface
+                && (header.destination == address.broadcast() || header.destination == Ipv4Addr::BROADCAST))
+    });
+    if !accepted {
+        return;
+    }
+    match header.protocol {
+        PROTOCOL_UDP => udp::receive(&header, payload),
+        PROTOCOL_TCP => tcp::receive(&header, payload),
+        _ => {}
+    }
+}
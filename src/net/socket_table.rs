@@ -0,0 +1,60 @@
+//! Socket table
+//!
+//! The TCP and UDP layers record each socket they open here, with its addresses,
+//! state, queued bytes and owner, and drop the entry when it closes. The table is
+//! only for listing sockets, as `/proc/net/tcp` and `/proc/net/udp` do; the protocol
+//! code keeps its own state.
+
+use crate::process::process::ProcessId;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+pub type SocketId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// What the table knows about one socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketEntry {
+    pub protocol: Protocol,
+    pub local: SocketAddr,
+    /// The peer of a connected socket
+    pub remote: Option<SocketAddr>,
+    /// Connection state, such as `LISTEN` or `ESTABLISHED`; empty for UDP
+    pub state: &'static str,
+    pub send_queue: usize,
+    pub receive_queue: usize,
+    pub owner: ProcessId,
+}
+
+static SOCKETS: Mutex<BTreeMap<SocketId, SocketEntry>> = Mutex::new(BTreeMap::new());
+static NEXT_SOCKET: AtomicU64 = AtomicU64::new(1);
+
+/// Record a newly opened socket
+pub fn add_socket(entry: SocketEntry) -> SocketId {
+    let id = NEXT_SOCKET.fetch_add(1, Ordering::Relaxed);
+    SOCKETS.lock().insert(id, entry);
+    id
+}
+
+/// Change a socket's entry in place, after a state change or as its queues fill and drain
+pub fn update_socket(id: SocketId, change: impl FnOnce(&mut SocketEntry)) {
+    if let Some(entry) = SOCKETS.lock().get_mut(&id) {
+        change(entry);
+    }
+}
+
+pub fn remove_socket(id: SocketId) {
+    SOCKETS.lock().remove(&id);
+}
+
+/// The open sockets of one protocol, oldest first
+pub fn sockets(protocol: Protocol) -> Vec<SocketEntry> {
+    SOCKETS.lock().values().filter(|entry| entry.protocol == protocol).cloned().collect()
+}
@@ -1,48 +1,61 @@
-use crate::net::ipv4::{Ipv4Header, Ipv4Packet};
-use crate::net::socket::{Socket, SocketAddr, SocketError, SocketType};
+//! TCP
+//!
+//! Connections advance as the IP layer delivers their segments and as their sockets
+//! are used; no call blocks. A `TcpListener` queues connections once their handshake
+//! completes, and a `TcpStream` buffers data in both directions. Segments that arrive
+//! out of order are dropped and left to the peer to send again; this end sends the
+//! oldest unacknowledged segment again from `tick`, which the stack calls
+//! periodically. Every connection and listener is listed in the socket table.
 
-pub struct TcpHeader {
-    src_port: u16,
-    dst_port: u16,
-    seq_num: u32,
-    ack_num: u32,
-    data_offset: u8,
-    flags: u16,
-    window_size: u16,
-    checksum: u16,
-    urgent_ptr: u16,
-    options: [u8; 40],
-}
+use crate::net::ip::{self, IpError, Ipv4Header, PROTOCOL_TCP};
+use crate::net::socket_table::{self, Protocol, SocketEntry, SocketId};
+use crate::process::process::{self, ProcessId};
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddrV4};
 
-impl TcpHeader {
-    // Functions for parsing and constructing TCP headers
-    // ...
+pub const TCP_HEADER_LEN: usize = 20;
 
-    // Function for calculating the TCP header checksum
-    // ...
-}
+const FLAG_FIN: u8 = 0x01;
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_PSH: u8 = 0x08;
+const FLAG_ACK: u8 = 0x10;
 
-pub struct TcpPacket {
-    header: TcpHeader,
-    payload: Vec<u8>,
-}
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
 
-impl TcpPacket {
-    // Functions for parsing and constructing TCP packets
-    // ...
-}
+/// Segment size assumed when the peer does not announce one
+const DEFAULT_MSS: usize = 536;
 
-pub struct TcpSocket {
-    local_addr: SocketAddr,
-    remote_addr: SocketAddr,
-    state: TcpState,
-    receive_buffer: Vec<u8>,
-    send_buffer: Vec<u8>,
-}
+/// Largest segment this end accepts: an Ethernet MTU less the IP and TCP headers
+const LOCAL_MSS: usize = 1460;
 
-enum TcpState {
-    Closed,
-    Listen,
+/// Smallest segment size a peer may announce; smaller ones are raised to it
+const MIN_MSS: usize = 88;
+
+/// Bytes buffered per connection in each direction
+const BUFFER_SIZE: usize = 65535;
+
+/// Connections kept per listener until accepted
+const BACKLOG: usize = 16;
+
+/// Ticks without progress before the oldest unacknowledged segment is sent again
+const RETRANSMIT_TICKS: u32 = 10;
+
+/// Retransmissions before a connection is given up
+const MAX_RETRANSMITS: u32 = 8;
+
+/// Ticks a closed connection stays in TIME_WAIT
+const TIME_WAIT_TICKS: u32 = 60;
+
+/// Ports given to connecting sockets and to listeners bound to port 0
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
     SynSent,
     SynReceived,
     Established,
@@ -52,48 +65,741 @@ enum TcpState {
     Closing,
     LastAck,
     TimeWait,
+    Closed,
+}
+
+impl TcpState {
+    /// The name of the state in `/proc/net/tcp`
+    pub fn name(self) -> &'static str {
+        match self {
+            TcpState::SynSent => "SYN_SENT",
+            TcpState::SynReceived => "SYN_RECV",
+            TcpState::Established => "ESTABLISHED",
+            TcpState::FinWait1 => "FIN_WAIT1",
+            TcpState::FinWait2 => "FIN_WAIT2",
+            TcpState::CloseWait => "CLOSE_WAIT",
+            TcpState::Closing => "CLOSING",
+            TcpState::LastAck => "LAST_ACK",
+            TcpState::TimeWait => "TIME_WAIT",
+            TcpState::Closed => "CLOSE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TcpError {
+    AddressInUse,
+    NotConnected,
+    ConnectionRefused,
+    ConnectionReset,
+    TimedOut,
+    /// Nothing to accept or read, or no room to write; try again later
+    WouldBlock,
+    Ip(IpError),
+}
+
+impl From<IpError> for TcpError {
+    fn from(error: IpError) -> Self {
+        TcpError::Ip(error)
+    }
+}
+
+// Local and remote address of a connection
+type ConnectionKey = (SocketAddrV4, SocketAddrV4);
+
+struct Connection {
+    state: TcpState,
+    // Oldest unacknowledged and next sequence number to send
+    send_unacked: u32,
+    send_next: u32,
+    // Sequence number of the first byte in `send_buffer`
+    send_data: u32,
+    // Data written but not yet acknowledged, sent or not
+    send_buffer: VecDeque<u8>,
+    send_window: usize,
+    mss: usize,
+    receive_next: u32,
+    receive_buffer: VecDeque<u8>,
+    // The socket was closed for writing; a FIN follows the buffered data
+    closing: bool,
+    fin_sent: bool,
+    fin_received: bool,
+    // Ticks since the connection last made progress
+    idle: u32,
+    retransmits: u32,
+    error: Option<TcpError>,
+    // A `TcpStream` refers to the connection; otherwise it is removed once closed
+    handle: bool,
+    // The listener that accepts the connection once its handshake completes
+    listener: Option<u16>,
+    // The connection's entry in the socket table, removed with it
+    socket: SocketId,
+}
+
+struct Listener {
+    address: Ipv4Addr,
+    ready: VecDeque<ConnectionKey>,
+    owner: ProcessId,
+    socket: SocketId,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        socket_table::remove_socket(self.socket);
+    }
+}
+
+struct Tcp {
+    connections: BTreeMap<ConnectionKey, Connection>,
+    listeners: BTreeMap<u16, Listener>,
+}
+
+static TCP: Mutex<Tcp> = Mutex::new(Tcp {
+    connections: BTreeMap::new(),
+    listeners: BTreeMap::new(),
+});
+
+static NEXT_SEQUENCE: AtomicU32 = AtomicU32::new(0x1000);
+
+// Segments built while TCP is locked, sent once it is released
+type Outgoing = Vec<(Ipv4Addr, Ipv4Addr, Vec<u8>)>;
+
+// Whether `a` comes before `b` in sequence space
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn initial_sequence() -> u32 {
+    NEXT_SEQUENCE.fetch_add(64_000, Ordering::Relaxed)
+}
+
+fn segment(
+    key: &ConnectionKey,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: usize,
+    payload: &[u8],
+) -> (Ipv4Addr, Ipv4Addr, Vec<u8>) {
+    let (local, remote) = key;
+    // Connection requests announce the largest segment this end accepts
+    let options: &[u8] = if flags & FLAG_SYN != 0 {
+        &[OPTION_MSS, 4, (LOCAL_MSS >> 8) as u8, LOCAL_MSS as u8]
+    } else {
+        &[]
+    };
+    let mut segment = Vec::with_capacity(TCP_HEADER_LEN + options.len() + payload.len());
+    segment.extend_from_slice(&local.port().to_be_bytes());
+    segment.extend_from_slice(&remote.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[(((TCP_HEADER_LEN + options.len()) / 4) << 4) as u8, flags]);
+    segment.extend_from_slice(&(window.min(u16::MAX as usize) as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(payload);
+    let checksum = ip::transport_checksum(*local.ip(), *remote.ip(), PROTOCOL_TCP, &segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    (*local.ip(), *remote.ip(), segment)
+}
+
+fn send_all(outgoing: Outgoing) {
+    for (source, destination, segment) in outgoing {
+        let _ = ip::send(source, destination, PROTOCOL_TCP, &segment);
+    }
+}
+
+impl Connection {
+    fn new(
+        key: &ConnectionKey,
+        state: TcpState,
+        receive_next: u32,
+        listener: Option<u16>,
+        owner: ProcessId,
+    ) -> Connection {
+        let sequence = initial_sequence();
+        let socket = socket_table::add_socket(SocketEntry {
+            protocol: Protocol::Tcp,
+            local: key.0.into(),
+            remote: Some(key.1.into()),
+            state: state.name(),
+            send_queue: 0,
+            receive_queue: 0,
+            owner,
+        });
+        Connection {
+            state,
+            send_unacked: sequence,
+            // The SYN takes up the first sequence number
+            send_next: sequence.wrapping_add(1),
+            send_data: sequence.wrapping_add(1),
+            send_buffer: VecDeque::new(),
+            send_window: DEFAULT_MSS,
+            mss: DEFAULT_MSS,
+            receive_next,
+            receive_buffer: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            fin_received: false,
+            idle: 0,
+            retransmits: 0,
+            error: None,
+            handle: listener.is_none(),
+            listener,
+            socket,
+        }
+    }
+
+    // Bring the connection's socket table entry up to date
+    fn publish(&self) {
+        socket_table::update_socket(self.socket, |entry| {
+            entry.state = self.state.name();
+            entry.send_queue = self.send_buffer.len();
+            entry.receive_queue = self.receive_buffer.len();
+        });
+    }
+
+    fn window(&self) -> usize {
+        BUFFER_SIZE - self.receive_buffer.len()
+    }
+
+    fn control(&self, key: &ConnectionKey, seq: u32, flags: u8) -> (Ipv4Addr, Ipv4Addr, Vec<u8>) {
+        segment(key, seq, self.receive_next, flags, self.window(), &[])
+    }
+
+    fn data(&self, key: &ConnectionKey, seq: u32, payload: &[u8]) -> (Ipv4Addr, Ipv4Addr, Vec<u8>) {
+        segment(key, seq, self.receive_next, FLAG_ACK | FLAG_PSH, self.window(), payload)
+    }
+
+    // Bytes between `send_data` and `send_next` that were sent and not yet acknowledged
+    fn in_flight(&self) -> usize {
+        let sent = self.send_next.wrapping_sub(self.send_data) as usize;
+        sent - (self.fin_sent as usize)
+    }
+
+    // Send the buffered data the peer has room for, then the FIN once it is all out
+    fn output(&mut self, key: &ConnectionKey, outgoing: &mut Outgoing) {
+        if !matches!(self.state, TcpState::Established | TcpState::CloseWait) {
+            return;
+        }
+        loop {
+            let in_flight = self.in_flight();
+            let unsent = self.send_buffer.len() - in_flight;
+            let room = self.send_window.saturating_sub(in_flight);
+            let len = unsent.min(room).min(self.mss);
+            if len == 0 {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buffer.range(in_flight..in_flight + len).copied().collect();
+            outgoing.push(self.data(key, self.send_next, &payload));
+            self.send_next = self.send_next.wrapping_add(len as u32);
+        }
+        if self.closing && !self.fin_sent && self.in_flight() == self.send_buffer.len() {
+            outgoing.push(self.control(key, self.send_next, FLAG_FIN | FLAG_ACK));
+            self.send_next = self.send_next.wrapping_add(1);
+            self.fin_sent = true;
+            self.state = match self.state {
+                TcpState::Established => TcpState::FinWait1,
+                _ => TcpState::LastAck,
+            };
+        }
+    }
+
+    // Send the oldest unacknowledged segment again
+    fn retransmit(&self, key: &ConnectionKey, outgoing: &mut Outgoing) {
+        match self.state {
+            TcpState::SynSent => outgoing.push(self.control(key, self.send_unacked, FLAG_SYN)),
+            TcpState::SynReceived => outgoing.push(self.control(key, self.send_unacked, FLAG_SYN | FLAG_ACK)),
+            _ if self.in_flight() > 0 => {
+                let len = self.in_flight().min(self.mss);
+                let payload: Vec<u8> = self.send_buffer.range(..len).copied().collect();
+                outgoing.push(self.data(key, self.send_data, &payload));
+            }
+            _ if self.fin_sent => {
+                outgoing.push(self.control(key, self.send_next.wrapping_sub(1), FLAG_FIN | FLAG_ACK));
+            }
+            _ => {}
+        }
+    }
+
+    // Take note of an acknowledgement; returns whether it covered anything new
+    fn acknowledge(&mut self, ack: u32) -> bool {
+        if !before(self.send_unacked, ack) || before(self.send_next, ack) {
+            return false;
+        }
+        if before(self.send_data, ack) {
+            let acked = (ack.wrapping_sub(self.send_data) as usize).min(self.send_buffer.len());
+            self.send_buffer.drain(..acked);
+            self.send_data = self.send_data.wrapping_add(acked as u32);
+        }
+        self.send_unacked = ack;
+        self.idle = 0;
+        self.retransmits = 0;
+        true
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_sent && self.send_unacked == self.send_next
+    }
+
+    fn fail(&mut self, error: TcpError) {
+        self.error = Some(error);
+        self.state = TcpState::Closed;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        socket_table::remove_socket(self.socket);
+    }
+}
+
+// The fields of a received segment
+struct Segment<'a> {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: usize,
+    mss: Option<usize>,
+    payload: &'a [u8],
+}
+
+fn parse<'a>(header: &Ipv4Header, data: &'a [u8]) -> Option<(u16, u16, Segment<'a>)> {
+    if data.len() < TCP_HEADER_LEN {
+        return None;
+    }
+    if ip::transport_checksum(header.source, header.destination, PROTOCOL_TCP, data) != 0 {
+        return None;
+    }
+    let header_len = (data[12] >> 4) as usize * 4;
+    if header_len < TCP_HEADER_LEN || header_len > data.len() {
+        return None;
+    }
+
+    let mut mss = None;
+    let mut options = &data[TCP_HEADER_LEN..header_len];
+    while let Some(&kind) = options.first() {
+        match kind {
+            OPTION_END => break,
+            OPTION_NOP => options = &options[1..],
+            _ => {
+                let len = *options.get(1)? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    mss = Some(u16::from_be_bytes([options[2], options[3]]) as usize);
+                }
+                options = &options[len..];
+            }
+        }
+    }
+
+    let source_port = u16::from_be_bytes([data[0], data[1]]);
+    let destination_port = u16::from_be_bytes([data[2], data[3]]);
+    Some((source_port, destination_port, Segment {
+        seq: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        ack: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        flags: data[13],
+        window: u16::from_be_bytes([data[14], data[15]]) as usize,
+        mss,
+        payload: &data[header_len..],
+    }))
+}
+
+/// The segment size to send with, from what the peer announced. A peer announcing
+/// 0 is treated as announcing nothing, as no data could ever be sent to it.
+fn peer_mss(announced: Option<usize>) -> usize {
+    match announced {
+        Some(0) | None => DEFAULT_MSS,
+        Some(mss) => mss.clamp(MIN_MSS, LOCAL_MSS),
+    }
+}
+
+/// Handle a segment delivered by the IP layer
+pub fn receive(header: &Ipv4Header, data: &[u8]) {
+    let (source_port, destination_port, segment) = match parse(header, data) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let key = (
+        SocketAddrV4::new(header.destination, destination_port),
+        SocketAddrV4::new(header.source, source_port),
+    );
+
+    let mut outgoing = Vec::new();
+    let syn = segment.flags & (FLAG_SYN | FLAG_ACK | FLAG_RST) == FLAG_SYN;
+    let mut guard = TCP.lock();
+    let tcp = &mut *guard;
+    if let Some(connection) = tcp.connections.get_mut(&key) {
+        let established = process(connection, &key, &segment, &mut outgoing);
+        connection.publish();
+        if let Some(listener) = connection.listener.filter(|_| established) {
+            if let Some(listener) = tcp.listeners.get_mut(&listener) {
+                listener.ready.push_back(key);
+            }
+        }
+        if connection.state == TcpState::Closed && !connection.handle {
+            tcp.connections.remove(&key);
+        }
+    } else if let Some(owner) = listening(tcp, &key).filter(|_| syn) {
+        let receive_next = segment.seq.wrapping_add(1);
+        let mut connection = Connection::new(&key, TcpState::SynReceived, receive_next, Some(destination_port), owner);
+        connection.mss = peer_mss(segment.mss);
+        connection.send_window = segment.window;
+        outgoing.push(connection.control(&key, connection.send_unacked, FLAG_SYN | FLAG_ACK));
+        tcp.connections.insert(key, connection);
+    } else if segment.flags & FLAG_RST == 0 {
+        // Nothing here: refuse with a reset that the sender will accept
+        let consumed = segment.payload.len() as u32 + (segment.flags & (FLAG_SYN | FLAG_FIN) != 0) as u32;
+        outgoing.push(if segment.flags & FLAG_ACK != 0 {
+            self::segment(&key, segment.ack, 0, FLAG_RST, 0, &[])
+        } else {
+            self::segment(&key, 0, segment.seq.wrapping_add(consumed), FLAG_RST | FLAG_ACK, 0, &[])
+        });
+    }
+    drop(guard);
+    send_all(outgoing);
+}
+
+// Whether a listener takes a new connection to `key`'s local address; returns the
+// listener's owner, which the connection gets
+fn listening(tcp: &Tcp, key: &ConnectionKey) -> Option<ProcessId> {
+    let (local, _) = key;
+    let listener = match tcp.listeners.get(&local.port()) {
+        Some(listener) if listener.address.is_unspecified() || listener.address == *local.ip() => listener,
+        _ => return None,
+    };
+    let pending = tcp
+        .connections
+        .values()
+        .filter(|connection| connection.listener == Some(local.port()) && !connection.handle)
+        .count();
+    (pending + listener.ready.len() < BACKLOG).then_some(listener.owner)
 }
 
-impl TcpSocket {
-    pub fn bind(local_addr: SocketAddr) -> Result<Self, SocketError> {
-        // Function for binding a TCP socket to a local address
-        // ...
+// Advance a connection with a segment; returns whether it completed a passive open
+fn process(connection: &mut Connection, key: &ConnectionKey, segment: &Segment, outgoing: &mut Outgoing) -> bool {
+    if connection.state == TcpState::SynSent {
+        let acceptable = segment.flags & FLAG_ACK != 0 && segment.ack == connection.send_next;
+        if segment.flags & FLAG_RST != 0 {
+            if acceptable {
+                connection.fail(TcpError::ConnectionRefused);
+            }
+        } else if acceptable && segment.flags & FLAG_SYN != 0 {
+            connection.receive_next = segment.seq.wrapping_add(1);
+            connection.acknowledge(segment.ack);
+            connection.mss = peer_mss(segment.mss);
+            connection.send_window = segment.window;
+            connection.state = TcpState::Established;
+            outgoing.push(connection.control(key, connection.send_next, FLAG_ACK));
+            connection.output(key, outgoing);
+        }
+        return false;
+    }
+
+    if segment.flags & FLAG_RST != 0 {
+        if connection.state == TcpState::SynReceived {
+            connection.state = TcpState::Closed;
+        } else {
+            connection.fail(TcpError::ConnectionReset);
+        }
+        return false;
+    }
+    if segment.flags & FLAG_SYN != 0 {
+        // The peer did not see our SYN-ACK
+        if connection.state == TcpState::SynReceived {
+            connection.retransmit(key, outgoing);
+        }
+        return false;
+    }
+    if segment.flags & FLAG_ACK == 0 {
+        return false;
+    }
+
+    let mut established = false;
+    if connection.acknowledge(segment.ack) || segment.ack == connection.send_unacked {
+        connection.send_window = segment.window;
+    }
+    if connection.state == TcpState::SynReceived {
+        if connection.send_unacked != connection.send_next {
+            return false;
+        }
+        connection.state = TcpState::Established;
+        established = true;
+    }
+    if connection.fin_acked() {
+        connection.state = match connection.state {
+            TcpState::FinWait1 => TcpState::FinWait2,
+            TcpState::Closing => TcpState::TimeWait,
+            TcpState::LastAck => TcpState::Closed,
+            state => state,
+        };
     }
 
-    pub fn listen() -> Result<(), SocketError> {
-        // Function for putting a TCP socket in listen mode
-        // ...
+    // Only data that continues the stream is taken; anything else is answered with
+    // the acknowledgement the peer has to resend from
+    let mut reply = false;
+    if !segment.payload.is_empty() {
+        reply = true;
+        let receiving = matches!(connection.state, TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2);
+        if receiving && segment.seq == connection.receive_next {
+            let len = segment.payload.len().min(connection.window());
+            connection.receive_buffer.extend(&segment.payload[..len]);
+            connection.receive_next = connection.receive_next.wrapping_add(len as u32);
+        }
+    }
+    let fin_seq = segment.seq.wrapping_add(segment.payload.len() as u32);
+    if segment.flags & FLAG_FIN != 0 && fin_seq == connection.receive_next && !connection.fin_received {
+        reply = true;
+        connection.fin_received = true;
+        connection.receive_next = connection.receive_next.wrapping_add(1);
+        connection.state = match connection.state {
+            TcpState::Established => TcpState::CloseWait,
+            TcpState::FinWait1 if connection.fin_acked() => TcpState::TimeWait,
+            TcpState::FinWait1 => TcpState::Closing,
+            TcpState::FinWait2 => TcpState::TimeWait,
+            state => state,
+        };
+        connection.idle = 0;
     }
+    if reply {
+        outgoing.push(connection.control(key, connection.send_next, FLAG_ACK));
+    }
+    connection.output(key, outgoing);
+    established
+}
 
-    pub fn accept() -> Result<Self, SocketError> {
-        // Function for accepting an incoming TCP connection
-        // ...
+/// Retransmit what has gone unacknowledged for too long and expire TIME_WAIT;
+/// called periodically by the stack
+pub fn tick() {
+    let mut outgoing = Vec::new();
+    let mut tcp = TCP.lock();
+    for (key, connection) in tcp.connections.iter_mut() {
+        connection.idle += 1;
+        if connection.state == TcpState::TimeWait {
+            if connection.idle >= TIME_WAIT_TICKS {
+                connection.state = TcpState::Closed;
+            }
+            continue;
+        }
+        let waiting = connection.send_unacked != connection.send_next;
+        if connection.state == TcpState::Closed || !waiting || connection.idle < RETRANSMIT_TICKS {
+            continue;
+        }
+        if connection.retransmits == MAX_RETRANSMITS {
+            outgoing.push(connection.control(key, connection.send_next, FLAG_RST));
+            connection.fail(TcpError::TimedOut);
+            continue;
+        }
+        connection.retransmits += 1;
+        connection.idle = 0;
+        connection.retransmit(key, &mut outgoing);
     }
+    for connection in tcp.connections.values() {
+        connection.publish();
+    }
+    tcp.connections
+        .retain(|_, connection| connection.state != TcpState::Closed || connection.handle);
+    drop(tcp);
+    send_all(outgoing);
+}
 
-    pub fn connect(remote_addr: SocketAddr) -> Result<Self, SocketError> {
-        // Function for initiating a TCP connection to a remote address
-        // ...
+fn free_port(tcp: &Tcp) -> Option<u16> {
+    EPHEMERAL_PORTS.clone().find(|port| {
+        !tcp.listeners.contains_key(port) && !tcp.connections.keys().any(|(local, _)| local.port() == *port)
+    })
+}
+
+/// A socket accepting connections on a local port
+pub struct TcpListener {
+    local: SocketAddrV4,
+}
+
+impl TcpListener {
+    /// Listen on `local`; port 0 picks a free ephemeral port, and the unspecified
+    /// address accepts connections to any local address
+    pub fn bind(local: SocketAddrV4) -> Result<TcpListener, TcpError> {
+        let mut tcp = TCP.lock();
+        let port = match local.port() {
+            0 => free_port(&tcp).ok_or(TcpError::AddressInUse)?,
+            port if tcp.listeners.contains_key(&port) => return Err(TcpError::AddressInUse),
+            port => port,
+        };
+        let local = SocketAddrV4::new(*local.ip(), port);
+        let owner = process::current();
+        let socket = socket_table::add_socket(SocketEntry {
+            protocol: Protocol::Tcp,
+            local: local.into(),
+            remote: None,
+            state: "LISTEN",
+            send_queue: 0,
+            receive_queue: 0,
+            owner,
+        });
+        tcp.listeners.insert(port, Listener {
+            address: *local.ip(),
+            ready: VecDeque::new(),
+            owner,
+            socket,
+        });
+        Ok(TcpListener { local })
     }
 
-    pub fn send(&mut self, data: &[u8]) -> Result<usize, SocketError> {
-        // Function for sending data over a TCP connection
-        // ...
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.local
     }
 
-    pub fn receive(&mut self, data: &mut [u8]) -> Result<usize, SocketError> {
-        // Function for receiving data from a TCP connection
-        // ...
+    /// Take the oldest connection whose handshake has completed
+    pub fn accept(&self) -> Result<TcpStream, TcpError> {
+        let mut tcp = TCP.lock();
+        let tcp = &mut *tcp;
+        let listener = tcp.listeners.get_mut(&self.local.port()).ok_or(TcpError::NotConnected)?;
+        while let Some(key) = listener.ready.pop_front() {
+            if let Some(connection) = tcp.connections.get_mut(&key) {
+                connection.handle = true;
+                return Ok(TcpStream { key });
+            }
+        }
+        Err(TcpError::WouldBlock)
     }
+}
 
-    pub fn close(&mut self) -> Result<(), SocketError> {
-        // Function for closing a TCP connection
-        // ...
+impl Drop for TcpListener {
+    // Connections not yet accepted are reset
+    fn drop(&mut self) {
+        let port = self.local.port();
+        let mut outgoing = Vec::new();
+        let mut tcp = TCP.lock();
+        tcp.listeners.remove(&port);
+        tcp.connections.retain(|key, connection| {
+            let orphan = connection.listener == Some(port) && !connection.handle;
+            if orphan {
+                outgoing.push(connection.control(key, connection.send_next, FLAG_RST));
+            }
+            !orphan
+        });
+        drop(tcp);
+        send_all(outgoing);
     }
+}
 
-    // Functions for handling TCP protocol events
-    // ...
+/// One end of a connection
+pub struct TcpStream {
+    key: ConnectionKey,
 }
 
-// Functions for TCP protocol processing and sending/receiving packets
-// ...
+impl TcpStream {
+    /// Start connecting to `remote`; the stream is usable once `state` reports it
+    /// established
+    pub fn connect(remote: SocketAddrV4) -> Result<TcpStream, TcpError> {
+        let source = ip::source_for(*remote.ip())?;
+        let mut tcp = TCP.lock();
+        let port = free_port(&tcp).ok_or(TcpError::AddressInUse)?;
+        let key = (SocketAddrV4::new(source, port), remote);
+        let connection = Connection::new(&key, TcpState::SynSent, 0, None, process::current());
+        let syn = connection.control(&key, connection.send_unacked, FLAG_SYN);
+        tcp.connections.insert(key, connection);
+        drop(tcp);
+        send_all(vec![syn]);
+        Ok(TcpStream { key })
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.key.0
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.key.1
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.with(|connection, _| connection.state)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Connection, &mut Outgoing) -> R) -> R {
+        let mut outgoing = Vec::new();
+        let mut tcp = TCP.lock();
+        let connection = tcp.connections.get_mut(&self.key).unwrap();
+        let result = f(connection, &mut outgoing);
+        connection.publish();
+        drop(tcp);
+        send_all(outgoing);
+        result
+    }
+
+    /// Queue data for sending; returns how much fit in the send buffer
+    pub fn write(&self, data: &[u8]) -> Result<usize, TcpError> {
+        let key = self.key;
+        self.with(|connection, outgoing| {
+            if let Some(error) = connection.error.clone() {
+                return Err(error);
+            }
+            match connection.state {
+                TcpState::SynSent | TcpState::SynReceived => return Err(TcpError::WouldBlock),
+                TcpState::Established | TcpState::CloseWait if !connection.closing => {}
+                _ => return Err(TcpError::NotConnected),
+            }
+            let len = data.len().min(BUFFER_SIZE - connection.send_buffer.len());
+            if len == 0 {
+                return Err(TcpError::WouldBlock);
+            }
+            connection.send_buffer.extend(&data[..len]);
+            connection.output(&key, outgoing);
+            Ok(len)
+        })
+    }
+
+    /// Take received data; returns 0 once the peer has closed its side and
+    /// everything it sent has been read
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, TcpError> {
+        let key = self.key;
+        self.with(|connection, outgoing| {
+            if connection.receive_buffer.is_empty() {
+                return match connection.error.clone() {
+                    Some(error) => Err(error),
+                    None if connection.fin_received => Ok(0),
+                    None => Err(TcpError::WouldBlock),
+                };
+            }
+            let window_was_closed = connection.window() < connection.mss;
+            let len = buffer.len().min(connection.receive_buffer.len());
+            for (byte, received) in buffer.iter_mut().zip(connection.receive_buffer.drain(..len)) {
+                *byte = received;
+            }
+            // Tell a peer that was stopped by a full buffer that there is room again
+            if window_was_closed && connection.window() >= connection.mss {
+                outgoing.push(connection.control(&key, connection.send_next, FLAG_ACK));
+            }
+            Ok(len)
+        })
+    }
+
+    /// Stop sending: a FIN follows the data already written
+    pub fn close(&self) {
+        let key = self.key;
+        self.with(|connection, outgoing| {
+            match connection.state {
+                // Nothing was sent; there is no connection to close
+                TcpState::SynSent | TcpState::SynReceived => connection.state = TcpState::Closed,
+                _ => {
+                    connection.closing = true;
+                    connection.output(&key, outgoing);
+                }
+            }
+        });
+    }
+}
 
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+        let mut tcp = TCP.lock();
+        if let Some(connection) = tcp.connections.get_mut(&self.key) {
+            connection.handle = false;
+            if connection.state == TcpState::Closed {
+                tcp.connections.remove(&self.key);
+            }
+        }
+    }
+}
@@ -1,18 +1,146 @@
 // This module contains process and thread management code
 
+use crate::fs::vfs::{self, KERNEL_PROCESS};
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use std::collections::BTreeMap;
+
 // Process ID type
 pub type ProcessId = u32;
 
 // Thread ID type
 pub type ThreadId = u32;
 
+// Scheduling state of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Ready,
+    Blocked,
+    // Exited, but not yet reaped by its parent
+    Zombie,
+}
+
+// A range of a process's address space, end exclusive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    // File or purpose of the mapping, e.g. `[stack]`; may be empty
+    pub name: String,
+}
+
 // Process structure
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Process {
-    // TODO: Define fields
+    pub id: ProcessId,
+    pub parent: ProcessId,
+    pub name: String,
+    pub arguments: Vec<String>,
+    pub state: ProcessState,
+    pub regions: Vec<MemoryRegion>,
+    // Path of the file behind each open descriptor
+    pub files: BTreeMap<u32, String>,
 }
 
 impl Process {
-    // TODO: Define methods
+    pub fn new(id: ProcessId, parent: ProcessId, name: &str) -> Process {
+        Process {
+            id,
+            parent,
+            name: String::from(name),
+            arguments: vec![String::from(name)],
+            state: ProcessState::Ready,
+            regions: Vec::new(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    // Bytes of address space mapped by the process
+    pub fn virtual_size(&self) -> u64 {
+        self.regions.iter().map(|region| region.end - region.start).sum()
+    }
+}
+
+// Every live process, kept up to date by the scheduler and the system calls
+static PROCESSES: Mutex<BTreeMap<ProcessId, Process>> = Mutex::new(BTreeMap::new());
+
+// Add a process to the table, replacing any with the same ID
+pub fn add_process(process: Process) {
+    PROCESSES.lock().insert(process.id, process);
+}
+
+// Remove a reaped process from the table
+pub fn remove_process(id: ProcessId) -> Option<Process> {
+    PROCESSES.lock().remove(&id)
+}
+
+// Change a process in place; returns whether it exists
+pub fn update_process(id: ProcessId, change: impl FnOnce(&mut Process)) -> bool {
+    match PROCESSES.lock().get_mut(&id) {
+        Some(process) => {
+            change(process);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn find_process(id: ProcessId) -> Option<Process> {
+    PROCESSES.lock().get(&id).cloned()
+}
+
+// A snapshot of every process, in ID order
+pub fn processes() -> Vec<Process> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+// IDs given to new processes; the kernel is process 0 and the first one started is 1
+static NEXT_ID: AtomicU32 = AtomicU32::new(KERNEL_PROCESS + 1);
+
+// Create a process as a child of `parent`, with its working directory and open files
+pub fn spawn(parent: ProcessId, name: &str) -> ProcessId {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut process = Process::new(id, parent, name);
+    if let Some(parent) = find_process(parent) {
+        process.files = parent.files;
+    }
+    add_process(process);
+    vfs::vfs().fork_process(parent, id);
+    id
+}
+
+// The process running on this CPU
+pub fn current() -> ProcessId {
+    vfs::vfs().current_process()
+}
+
+// Make `id` the running process; called by the scheduler when it switches to it
+pub fn switch_to(id: ProcessId) {
+    update_process(current(), |process| {
+        if process.state == ProcessState::Running {
+            process.state = ProcessState::Ready;
+        }
+    });
+    update_process(id, |process| process.state = ProcessState::Running);
+    vfs::vfs().set_current_process(id);
+}
+
+// End a process, releasing what it holds; it stays in the table as a zombie until
+// its parent reaps it with `remove_process`
+pub fn exit(id: ProcessId) {
+    update_process(id, |process| {
+        process.state = ProcessState::Zombie;
+        process.regions.clear();
+        process.files.clear();
+    });
+    vfs::vfs().exit_process(id);
+    if current() == id {
+        vfs::vfs().set_current_process(KERNEL_PROCESS);
+    }
 }
 
 // Thread structure
@@ -1,10 +1,79 @@
-use std::io::{Read, Write};
+use std::io::{Read, Seek, Write};
 use std::fs::{File, OpenOptions};
 use std::path::Path;
-use crate::storage::Error;
+use crate::drivers::device::DeviceError;
+use spin::Mutex;
 
 pub const BLOCK_SIZE: usize = 4096;
 
+#[derive(Debug)]
+pub enum Error {
+    InvalidBlockId,
+    InvalidBufferSize,
+    ReadOnly,
+    Io(std::io::Error),
+    Device(DeviceError),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<DeviceError> for Error {
+    fn from(error: DeviceError) -> Self {
+        Error::Device(error)
+    }
+}
+
+/// A device addressed in fixed-size blocks. This is the interface filesystems
+/// use, whether the blocks come from a disk driver or from an image file.
+pub trait BlockDevice: Send + Sync {
+    /// Size of one block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u64;
+
+    /// Read whole blocks starting at `start`; `buffer` must be a multiple of the block size
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Write whole blocks starting at `start`; `buffer` must be a multiple of the block size
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Make previous writes durable
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Read `buffer.len()` bytes at a byte offset, which need not be block aligned
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let block_size = self.block_size() as u64;
+        let start = offset / block_size;
+        let end = (offset + buffer.len() as u64 + block_size - 1) / block_size;
+        let mut blocks = vec![0; ((end - start) * block_size) as usize];
+        self.read_blocks(start, &mut blocks)?;
+        let skip = (offset % block_size) as usize;
+        buffer.copy_from_slice(&blocks[skip..skip + buffer.len()]);
+        Ok(())
+    }
+
+    /// Write `buffer` at a byte offset, reading and merging partial blocks
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<(), Error> {
+        let block_size = self.block_size() as u64;
+        let start = offset / block_size;
+        let end = (offset + buffer.len() as u64 + block_size - 1) / block_size;
+        let mut blocks = vec![0; ((end - start) * block_size) as usize];
+        let skip = (offset % block_size) as usize;
+        if skip != 0 || buffer.len() % block_size as usize != 0 {
+            self.read_blocks(start, &mut blocks)?;
+        }
+        blocks[skip..skip + buffer.len()].copy_from_slice(buffer);
+        self.write_blocks(start, &blocks)
+    }
+}
+
 pub struct Block {
     id: u64,
     data: Vec<u8>,
@@ -37,51 +106,167 @@ impl Block {
     }
 }
 
-pub struct BlockDevice {
-    file: File,
+/// A block device backed by a file, e.g. a disk image on a hosted build
+pub struct FileBlockDevice {
+    file: Mutex<File>,
     num_blocks: u64,
 }
 
-impl BlockDevice {
+impl FileBlockDevice {
     pub fn new<P: AsRef<Path>>(path: P, num_blocks: u64) -> Result<Self, Error> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .open(path)?;
-        let mut bd = BlockDevice {
-            file,
+        let mut bd = FileBlockDevice {
+            file: Mutex::new(file),
             num_blocks,
         };
         bd.init()?;
         Ok(bd)
     }
 
+    /// Open an existing image, sizing the device from the file length
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let num_blocks = file.metadata()?.len() / BLOCK_SIZE as u64;
+        Ok(FileBlockDevice {
+            file: Mutex::new(file),
+            num_blocks,
+        })
+    }
+
     fn init(&mut self) -> Result<(), Error> {
-        let size = self.file.metadata()?.len();
+        let file = self.file.get_mut();
+        let size = file.metadata()?.len();
         let expected_size = self.num_blocks * BLOCK_SIZE as u64;
         if size < expected_size {
-            self.file.set_len(expected_size)?;
+            file.set_len(expected_size)?;
         }
         Ok(())
     }
 
-    pub fn get_block(&mut self, id: u64) -> Result<Block, Error> {
+    pub fn get_block(&self, id: u64) -> Result<Block, Error> {
         if id >= self.num_blocks {
             return Err(Error::InvalidBlockId);
         }
         let mut block = Block::new(id);
-        block.read(&mut self.file)?;
+        block.read(&mut self.file.lock())?;
         Ok(block)
     }
 
-    pub fn write_block(&mut self, block: &Block) -> Result<(), Error> {
+    pub fn write_block(&self, block: &Block) -> Result<(), Error> {
         if block.id() >= self.num_blocks {
             return Err(Error::InvalidBlockId);
         }
-        block.write(&mut self.file)?;
+        block.write(&mut self.file.lock())?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.len() % BLOCK_SIZE != 0 {
+            return Err(Error::InvalidBufferSize);
+        }
+        if start + (buffer.len() / BLOCK_SIZE) as u64 > self.num_blocks {
+            return Err(Error::InvalidBlockId);
+        }
+        let mut file = self.file.lock();
+        file.seek(std::io::SeekFrom::Start(start * BLOCK_SIZE as u64))?;
+        file.read_exact(buffer)?;
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), Error> {
+        if buffer.len() % BLOCK_SIZE != 0 {
+            return Err(Error::InvalidBufferSize);
+        }
+        if start + (buffer.len() / BLOCK_SIZE) as u64 > self.num_blocks {
+            return Err(Error::InvalidBlockId);
+        }
+        let mut file = self.file.lock();
+        file.seek(std::io::SeekFrom::Start(start * BLOCK_SIZE as u64))?;
+        file.write_all(buffer)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.file.lock().sync_data()?;
+        Ok(())
+    }
+}
+
+/// A block device kept in memory, e.g. a RAM disk or a filesystem image under test
+pub struct MemoryBlockDevice {
+    data: Mutex<Vec<u8>>,
+    block_size: usize,
+}
+
+impl MemoryBlockDevice {
+    pub fn new(block_size: usize, block_count: u64) -> Self {
+        MemoryBlockDevice {
+            data: Mutex::new(vec![0; block_size * block_count as usize]),
+            block_size,
+        }
+    }
+
+    /// A device holding `image`, padded with zeros to a whole number of blocks
+    pub fn from_image(mut image: Vec<u8>, block_size: usize) -> Self {
+        image.resize(image.len().div_ceil(block_size) * block_size, 0);
+        MemoryBlockDevice {
+            data: Mutex::new(image),
+            block_size,
+        }
+    }
+
+    /// A copy of everything on the device
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+
+    // The byte range of `length` bytes of whole blocks starting at `start`
+    fn range(&self, start: u64, length: usize) -> Result<core::ops::Range<usize>, Error> {
+        if !length.is_multiple_of(self.block_size) {
+            return Err(Error::InvalidBufferSize);
+        }
+        if start + (length / self.block_size) as u64 > self.block_count() {
+            return Err(Error::InvalidBlockId);
+        }
+        let offset = start as usize * self.block_size;
+        Ok(offset..offset + length)
+    }
+}
+
+impl BlockDevice for MemoryBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let range = self.range(start, buffer.len())?;
+        buffer.copy_from_slice(&self.data.lock()[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), Error> {
+        let range = self.range(start, buffer.len())?;
+        self.data.lock()[range].copy_from_slice(buffer);
         Ok(())
     }
 }
 
-// TODO: Implement the `Drop` trait for `BlockDevice`.
\ No newline at end of file
+// TODO: Implement the `Drop` trait for `FileBlockDevice`.
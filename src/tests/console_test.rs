@@ -0,0 +1,208 @@
+use crate::drivers::console::FramebufferConsole;
+use crate::drivers::gpu::{FramebufferInfo, Gpu, LinearFramebuffer, PixelFormat};
+use crate::gui::utils::font::{self, Font};
+use crate::lib::terminal::{indexed_color, TermColor, Terminal, PALETTE, SCROLLBACK_LINES};
+
+#[test]
+fn test_terminal_wraps_and_scrolls() {
+    let mut terminal = Terminal::new(10, 3);
+    terminal.write(b"hello\nworld\r\nabcdefghij");
+    assert_eq!(terminal.line(0), "hello");
+    assert_eq!(terminal.line(1), "world");
+    assert_eq!(terminal.line(2), "abcdefghij");
+    // Writing the last column leaves the cursor there until the next character
+    assert_eq!(terminal.cursor(), (2, 9));
+
+    terminal.take_damage();
+    terminal.write(b"k");
+    assert_eq!(terminal.line(0), "world");
+    assert_eq!(terminal.line(2), "k");
+    assert_eq!(terminal.scrollback_len(), 1);
+    let damage = terminal.take_damage();
+    assert_eq!(damage.scrolled, 1);
+    assert_eq!(damage.rows, vec![2]);
+
+    terminal.write(b"\tx\x08y");
+    assert_eq!(terminal.line(2), "k       y");
+}
+
+#[test]
+fn test_terminal_cursor_and_erase() {
+    let mut terminal = Terminal::new(10, 4);
+    terminal.write(b"0123456789\n0123456789\n0123456789");
+    terminal.write(b"\x1b[2;3H");
+    assert_eq!(terminal.cursor(), (1, 2));
+    terminal.write(b"\x1b[K");
+    assert_eq!(terminal.line(1), "01");
+    terminal.write(b"\x1b[A\x1b[2D\x1b[1K");
+    assert_eq!(terminal.line(0), " 123456789");
+    terminal.write(b"\x1b[3;5H\x1b[2P\x1b[@");
+    assert_eq!(terminal.line(2), "0123 6789");
+
+    // Out of range positions are clamped, and a missing parameter means 1
+    terminal.write(b"\x1b[99;99H");
+    assert_eq!(terminal.cursor(), (3, 9));
+    terminal.write(b"\x1b[;H\x1b[J");
+    assert!((0..4).all(|row| terminal.line(row).is_empty()));
+    assert_eq!(terminal.scrollback_len(), 0);
+
+    terminal.write(b"\x1b[?25l");
+    assert!(!terminal.cursor_visible());
+}
+
+#[test]
+fn test_terminal_colors() {
+    let mut terminal = Terminal::new(20, 2);
+    terminal.write(b"\x1b[31mr\x1b[1;44mb\x1b[0md\x1b[38;5;196mx\x1b[48;2;1;2;3my\x1b[7mz");
+    let attributes = |column| terminal.cell(0, column).attributes;
+    assert_eq!(attributes(0).foreground, TermColor::Indexed(1));
+    assert_eq!(attributes(0).colors(), (PALETTE[1], PALETTE[0]));
+    // Bold brightens the basic colors
+    assert_eq!(attributes(1).colors(), (PALETTE[9], PALETTE[4]));
+    assert_eq!(attributes(2).colors(), (PALETTE[7], PALETTE[0]));
+    assert_eq!(attributes(3).colors().0, 0xFF0000);
+    assert_eq!(attributes(4).colors(), (0xFF0000, 0x010203));
+    assert_eq!(attributes(5).colors(), (0x010203, 0xFF0000));
+    assert_eq!(indexed_color(244), 0x808080);
+
+    // Erasing fills with the current background
+    terminal.write(b"\x1b[0;42m\x1b[2K");
+    assert_eq!(terminal.cell(0, 0).attributes.colors().1, PALETTE[2]);
+
+    // Parameters past the 16th are ignored, however many there are
+    let mut sequence = b"\r\x1b[0;31".to_vec();
+    sequence.extend(b";22".repeat(10_000));
+    sequence.extend(b";32ms");
+    terminal.write(&sequence);
+    assert_eq!(terminal.cell(0, 0).attributes.foreground, TermColor::Indexed(1));
+}
+
+#[test]
+fn test_terminal_split_sequences_and_utf8() {
+    let mut terminal = Terminal::new(10, 2);
+    for byte in "a\x1b[3".bytes().chain("2mé─\u{FFFD}".bytes()) {
+        terminal.write(&[byte]);
+    }
+    assert_eq!(terminal.line(0), "aé─\u{FFFD}");
+    assert_eq!(terminal.cell(0, 1).attributes.foreground, TermColor::Indexed(2));
+
+    // A broken UTF-8 sequence becomes one replacement character
+    terminal.write(&[b'\r', 0xE2, 0x94, b'b']);
+    assert_eq!(terminal.line(0), "\u{FFFD}b─\u{FFFD}");
+}
+
+#[test]
+fn test_terminal_scroll_region_and_scrollback() {
+    let mut terminal = Terminal::new(4, 4);
+    terminal.write(b"a\nb\nc\nd");
+    terminal.write(b"\x1b[2;3r\x1b[3;1H\nx");
+    // Only the lines inside the region move, and nothing goes to the scrollback
+    assert_eq!((0..4).map(|row| terminal.line(row)).collect::<Vec<_>>(), ["a", "c", "x", "d"]);
+    assert_eq!(terminal.scrollback_len(), 0);
+
+    let mut terminal = Terminal::new(4, 2);
+    for line in 0..SCROLLBACK_LINES + 10 {
+        terminal.write(format!("\n{}", line % 10).as_bytes());
+    }
+    assert_eq!(terminal.scrollback_len(), SCROLLBACK_LINES);
+    terminal.scroll_view(1);
+    assert_eq!(terminal.line(0), "7");
+    assert_eq!(terminal.line(1), "8");
+    terminal.scroll_view(-5);
+    assert_eq!(terminal.view_offset(), 0);
+    terminal.scroll_view(3);
+    // New output returns to the live screen
+    terminal.write(b"!");
+    assert_eq!(terminal.view_offset(), 0);
+    assert_eq!(terminal.line(1), "9!");
+}
+
+// A framebuffer over `memory`, which stands in for video memory
+fn framebuffer(memory: &mut [u8], width: usize, height: usize) -> LinearFramebuffer {
+    assert!(memory.len() >= width * height * 4);
+    let info = FramebufferInfo {
+        address: memory.as_mut_ptr() as usize,
+        width,
+        height,
+        pitch: width * 4,
+        format: PixelFormat::XRGB8888,
+    };
+    unsafe { LinearFramebuffer::new(info) }
+}
+
+#[test]
+fn test_framebuffer_console_renders() {
+    let (width, height) = (64, 48);
+    let mut memory = vec![0u8; width * height * 4];
+    let mut gpu = framebuffer(&mut memory, width, height);
+    let mut console = FramebufferConsole::new(width, height, Font::bitmap(font::console_face(), 1)).unwrap();
+    assert_eq!((console.terminal().columns(), console.terminal().rows()), (8, 3));
+
+    console.write("\x1b[31;47m\u{2588}\x1b[0m ".as_bytes());
+    console.render(&mut gpu);
+    let pixel = |gpu: &mut LinearFramebuffer, x: usize, y: usize| gpu.framebuffer()[y * width + x];
+    // A full block is all foreground
+    assert_eq!(pixel(&mut gpu, 4, 8), PALETTE[1]);
+    // The cursor is drawn in reverse video on the next cell
+    assert_eq!(console.terminal().cursor(), (0, 2));
+    assert_eq!(pixel(&mut gpu, 16, 8), PALETTE[7]);
+    assert_eq!(pixel(&mut gpu, 8, 8), PALETTE[0]);
+
+    // Scrolling moves the pixels up and takes the cursor along
+    console.write(b"\n\n\n");
+    console.render(&mut gpu);
+    assert_eq!(pixel(&mut gpu, 4, 8), PALETTE[0]);
+    assert_eq!(pixel(&mut gpu, 16, 40), PALETTE[0]);
+    assert_eq!(pixel(&mut gpu, 0, 40), PALETTE[7]);
+
+    // The block scrolled off; looking back shows it again
+    console.scroll_view(1);
+    console.render(&mut gpu);
+    assert_eq!(pixel(&mut gpu, 4, 8), PALETTE[1]);
+}
+
+#[test]
+fn test_terminal_resize() {
+    let mut terminal = Terminal::new(6, 4);
+    terminal.write(b"one\ntwo\nthree\nfour");
+    terminal.write(b"\x1b[2;3r\x1b[4;5H");
+    terminal.resize(3, 2);
+    // The lines above the cursor go to the scrollback and the rest is cut to the width
+    assert_eq!((terminal.columns(), terminal.rows()), (3, 2));
+    assert_eq!((terminal.line(0), terminal.line(1)), ("thr".to_string(), "fou".to_string()));
+    assert_eq!(terminal.cursor(), (1, 2));
+    assert_eq!(terminal.scrollback_len(), 2);
+    terminal.write(b"\nx");
+    assert_eq!((terminal.line(0), terminal.line(1)), ("fou".to_string(), "x".to_string()));
+
+    terminal.resize(5, 3);
+    assert_eq!((0..3).map(|row| terminal.line(row)).collect::<Vec<_>>(), ["fou", "x", ""]);
+    assert_eq!(terminal.take_damage().rows, [0, 1, 2]);
+}
+
+#[test]
+fn test_framebuffer_console_follows_mode_changes() {
+    // A display smaller than one character cell cannot hold a console
+    let face = font::console_face();
+    assert!(FramebufferConsole::new(7, 48, Font::bitmap(face.clone(), 1)).is_err());
+    assert!(FramebufferConsole::new(64, 15, Font::bitmap(face.clone(), 1)).is_err());
+
+    let mut console = FramebufferConsole::new(64, 48, Font::bitmap(face, 1)).unwrap();
+    console.write(b"\x1b[31mhello world\x1b[0m\nbye");
+
+    // Rendering on a smaller mode fits the terminal to it instead of drawing past the end
+    let (width, height) = (24, 32);
+    let mut memory = vec![0u8; width * height * 4];
+    let mut gpu = framebuffer(&mut memory, width, height);
+    console.render(&mut gpu);
+    assert_eq!((console.terminal().columns(), console.terminal().rows()), (3, 2));
+    assert_eq!(console.terminal().line(1), "bye");
+    assert!(gpu.framebuffer()[..width * 16].iter().any(|pixel| *pixel == PALETTE[1]));
+
+    // Too small for a character: nothing is drawn and the terminal keeps its size
+    let mut memory = vec![0u8; 4 * 4 * 4];
+    let mut gpu = framebuffer(&mut memory, 4, 4);
+    console.render(&mut gpu);
+    assert_eq!((console.terminal().columns(), console.terminal().rows()), (3, 2));
+    assert!(gpu.framebuffer().iter().all(|pixel| *pixel == 0));
+}
@@ -0,0 +1,1566 @@
+use crate::fs::cpio::{self, Archive, CpioError, NEWC_CRC_MAGIC, NEWC_MAGIC};
+use crate::fs::ext2::Ext2FileSystem;
+use crate::fs::fat::{FatFileSystem, FatType};
+use crate::fs::initramfs::{self, BootModule};
+use crate::fs::iso9660::{Extension, Iso9660FileSystem};
+use crate::fs::nfts::NTFSFileSystem;
+use crate::fs::procfs::ProcFs;
+use crate::fs::tmpfs::TmpFs;
+use crate::fs::vfs::{self, FileSystem, FileSystemError, FileType, Metadata, MountOptions, OpenOptions, SetAttributes, Vfs, KERNEL_PROCESS};
+use crate::net::socket_table::{self, Protocol, SocketEntry};
+use crate::process::process::{self, MemoryRegion, Process, ProcessState};
+use crate::storage::block::MemoryBlockDevice;
+use core::time::Duration;
+use std::io::{ErrorKind, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::Arc;
+
+fn tmpfs_vfs() -> Vfs {
+    let vfs = Vfs::new();
+    vfs.mount(Arc::new(TmpFs::new()), "none", "/", MountOptions::default()).unwrap();
+    vfs
+}
+
+#[test]
+fn test_file_type_mode_bits() {
+    for file_type in [FileType::Regular, FileType::Directory, FileType::Symlink, FileType::Fifo] {
+        assert_eq!(FileType::from_mode(file_type.mode_bits() | 0o644), Some(file_type));
+    }
+    assert_eq!(FileType::from_mode(0o644), None);
+
+    let metadata = Metadata::new(7, FileType::Directory, 0o41755);
+    assert_eq!(metadata.permissions, 0o1755);
+    assert_eq!(metadata.mode(), 0o41755);
+}
+
+#[test]
+fn test_open_options() {
+    let vfs = tmpfs_vfs();
+    assert_eq!(vfs.open("/missing", &OpenOptions::new().read(true)).err(), Some(FileSystemError::NotFound));
+
+    vfs.write_file("/notes", b"hello").unwrap();
+    assert_eq!(
+        vfs.open("/notes", &OpenOptions::new().write(true).create_new(true)).err(),
+        Some(FileSystemError::AlreadyExists)
+    );
+
+    // Without write access, the handle refuses writes
+    let mut file = vfs.open("/notes", &OpenOptions::new().read(true)).unwrap();
+    assert_eq!(file.write(b"x"), Err(FileSystemError::PermissionDenied));
+
+    // Appends go to the end wherever the position is
+    let mut file = vfs.open("/notes", &OpenOptions::new().append(true)).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write(b", world").unwrap();
+    assert_eq!(vfs.read_file("/notes").unwrap(), b"hello, world");
+
+    vfs.open("/notes", &OpenOptions::new().write(true).truncate(true)).unwrap();
+    assert_eq!(vfs.metadata("/notes").unwrap().size, 0);
+}
+
+#[test]
+fn test_file_handle_seek() {
+    let vfs = tmpfs_vfs();
+    vfs.write_file("/data", b"0123456789").unwrap();
+    let mut file = vfs.open("/data", &OpenOptions::new().read(true).write(true)).unwrap();
+
+    let mut buffer = [0; 4];
+    assert_eq!(file.seek(SeekFrom::End(-4)), Ok(6));
+    assert_eq!(file.read(&mut buffer), Ok(4));
+    assert_eq!(&buffer, b"6789");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+    assert_eq!(file.seek(SeekFrom::Current(-20)), Err(FileSystemError::InvalidArgument));
+
+    // Writing past the end leaves a hole of zeros
+    file.seek(SeekFrom::Start(12)).unwrap();
+    file.write(b"!").unwrap();
+    assert_eq!(vfs.read_file("/data").unwrap(), b"0123456789\0\0!");
+    file.truncate(3).unwrap();
+    assert_eq!(file.stat().unwrap().size, 3);
+}
+
+#[test]
+fn test_vfs_directories() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/etc", 0o755).unwrap();
+    vfs.create_directory("/etc/my_os", 0o755).unwrap();
+    vfs.write_file("/etc/my_os/config.json", b"{}").unwrap();
+
+    assert_eq!(vfs.read_file("/etc/./my_os/../my_os/config.json").unwrap(), b"{}");
+    assert_eq!(vfs.read_file("/etc"), Err(FileSystemError::IsADirectory));
+    assert_eq!(vfs.read_file("/etc/my_os/config.json/x"), Err(FileSystemError::NotADirectory));
+    assert_eq!(vfs.create_directory("/etc/..", 0o755), Err(FileSystemError::InvalidPath));
+
+    let names: Vec<_> = vfs.read_directory("/etc/my_os").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["config.json"]);
+    vfs.remove_file("/etc/my_os/config.json").unwrap();
+    assert!(vfs.read_directory("/etc/my_os").unwrap().is_empty());
+    vfs.remove_directory("/etc/my_os").unwrap();
+    assert_eq!(vfs.remove_directory("/etc/my_os"), Err(FileSystemError::NotFound));
+}
+
+#[test]
+fn test_nested_mounts() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/mnt", 0o755).unwrap();
+    vfs.write_file("/mnt/hidden", b"root").unwrap();
+
+    // A mount hides what was in the directory, and mounts nest inside mounts
+    vfs.mount(Arc::new(TmpFs::new()), "disk0", "/mnt", MountOptions::default()).unwrap();
+    assert_eq!(vfs.read_file("/mnt/hidden"), Err(FileSystemError::NotFound));
+    vfs.create_directory("/mnt/usb", 0o755).unwrap();
+    vfs.mount(Arc::new(TmpFs::new()), "disk1", "/mnt/usb", MountOptions::default()).unwrap();
+    vfs.write_file("/mnt/usb/file", b"usb").unwrap();
+    assert_eq!(vfs.read_directory("/mnt").unwrap()[0].name, "usb");
+    assert_eq!(vfs.read_file("/mnt/usb/../usb/./file").unwrap(), b"usb");
+
+    // `..` at the root of a mount leaves it
+    assert_eq!(vfs.canonicalize("/mnt/usb/..").unwrap(), "/mnt");
+    assert_eq!(vfs.canonicalize("/mnt/usb/../..").unwrap(), "/");
+    assert_eq!(vfs.canonicalize("/..").unwrap(), "/");
+
+    let mounts: Vec<_> = vfs.mounts().into_iter().map(|mount| (mount.path, mount.source)).collect();
+    assert_eq!(
+        mounts,
+        [
+            (String::from("/"), String::from("none")),
+            (String::from("/mnt"), String::from("disk0")),
+            (String::from("/mnt/usb"), String::from("disk1")),
+        ]
+    );
+    assert_eq!(vfs.mount(Arc::new(TmpFs::new()), "disk2", "/mnt/usb", MountOptions::default()), Err(FileSystemError::Busy));
+    assert_eq!(vfs.rename("/mnt/usb/file", "/mnt/file"), Err(FileSystemError::CrossDevice));
+    assert_eq!(vfs.remove_directory("/mnt/usb"), Err(FileSystemError::Busy));
+
+    // Unmounting reveals the directory underneath again
+    assert_eq!(vfs.unmount("/mnt"), Err(FileSystemError::Busy));
+    vfs.unmount("/mnt/usb").unwrap();
+    vfs.unmount("/mnt").unwrap();
+    assert_eq!(vfs.read_file("/mnt/hidden").unwrap(), b"root");
+    assert_eq!(vfs.unmount("/mnt"), Err(FileSystemError::InvalidArgument));
+}
+
+#[test]
+fn test_unmount_busy() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/tmp", 0o755).unwrap();
+    vfs.mount(Arc::new(TmpFs::new()), "none", "/tmp", MountOptions::default()).unwrap();
+
+    // An open file keeps the mount busy until it is closed
+    let file = vfs.open("/tmp/log", &OpenOptions::new().write(true).create(true)).unwrap();
+    assert_eq!(vfs.unmount("/tmp"), Err(FileSystemError::Busy));
+    drop(file);
+
+    // So does a process working in it, until it exits
+    vfs.set_current_process(3);
+    vfs.change_directory("/tmp").unwrap();
+    vfs.set_current_process(KERNEL_PROCESS);
+    assert_eq!(vfs.unmount("/tmp"), Err(FileSystemError::Busy));
+    vfs.exit_process(3);
+    vfs.unmount("/tmp").unwrap();
+}
+
+#[test]
+fn test_symlinks() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/usr", 0o755).unwrap();
+    vfs.create_directory("/usr/lib", 0o755).unwrap();
+    vfs.write_file("/usr/lib/libc.so", b"elf").unwrap();
+    vfs.symlink("usr/lib", "/lib").unwrap();
+    vfs.symlink("/lib/libc.so", "/usr/libc").unwrap();
+    vfs.symlink("../lib/libc.so", "/usr/lib/libc.so.6").unwrap();
+
+    assert_eq!(vfs.read_file("/lib/libc.so").unwrap(), b"elf");
+    assert_eq!(vfs.read_file("/usr/libc").unwrap(), b"elf");
+    assert_eq!(vfs.canonicalize("/lib/libc.so.6").unwrap(), "/usr/lib/libc.so");
+    assert_eq!(vfs.canonicalize("/lib/..").unwrap(), "/usr");
+    assert_eq!(vfs.read_link("/usr/libc").unwrap(), "/lib/libc.so");
+    assert!(vfs.symlink_metadata("/usr/libc").unwrap().is_symlink());
+    assert!(vfs.metadata("/usr/libc").unwrap().is_file());
+
+    // Symlink loops end after a fixed number of links
+    vfs.symlink("/b", "/a").unwrap();
+    vfs.symlink("/a", "/b").unwrap();
+    assert_eq!(vfs.read_file("/a"), Err(FileSystemError::TooManyLinks));
+    vfs.symlink("loop/loop", "/loop").unwrap();
+    assert_eq!(vfs.metadata("/loop/x"), Err(FileSystemError::TooManyLinks));
+}
+
+#[test]
+fn test_working_directories() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/home", 0o755).unwrap();
+    vfs.create_directory("/home/user", 0o755).unwrap();
+    vfs.write_file("/home/user/notes", b"todo").unwrap();
+
+    vfs.set_current_process(1);
+    vfs.change_directory("/home/user").unwrap();
+    assert_eq!(vfs.read_file("notes").unwrap(), b"todo");
+    vfs.write_file("draft", b"").unwrap();
+    assert!(vfs.metadata("/home/user/draft").is_ok());
+    vfs.change_directory("..").unwrap();
+    assert_eq!(vfs.working_directory().unwrap(), "/home");
+    assert_eq!(vfs.change_directory("user/notes"), Err(FileSystemError::NotADirectory));
+
+    // A child starts where its parent is; other processes are unaffected
+    vfs.fork_process(1, 2);
+    vfs.set_current_process(2);
+    assert_eq!(vfs.read_file("user/notes").unwrap(), b"todo");
+    vfs.set_current_process(4);
+    assert_eq!(vfs.working_directory().unwrap(), "/");
+    assert_eq!(vfs.read_file("notes"), Err(FileSystemError::NotFound));
+}
+
+#[test]
+fn test_process_lifecycle() {
+    // Processes keep their working directories in the kernel's Vfs
+    let vfs = vfs::vfs();
+    vfs.mount(Arc::new(TmpFs::new()), "none", "/", MountOptions::default()).unwrap();
+    vfs.create_directory("/srv", 0o755).unwrap();
+
+    let shell = process::spawn(KERNEL_PROCESS, "sh");
+    process::update_process(shell, |shell| {
+        shell.files.insert(1, String::from("/dev/console"));
+    });
+    process::switch_to(shell);
+    assert_eq!(process::current(), shell);
+    assert_eq!(process::find_process(shell).unwrap().state, ProcessState::Running);
+    vfs.change_directory("/srv").unwrap();
+
+    // A child starts in its parent's directory, with its open files
+    let child = process::spawn(shell, "ls");
+    assert_eq!(process::find_process(child).unwrap().parent, shell);
+    assert_eq!(process::find_process(child).unwrap().files, process::find_process(shell).unwrap().files);
+    process::switch_to(child);
+    assert_eq!(process::find_process(shell).unwrap().state, ProcessState::Ready);
+    assert_eq!(vfs.working_directory().unwrap(), "/srv");
+
+    // An exited process keeps only its entry, until its parent reaps it
+    process::exit(child);
+    let zombie = process::find_process(child).unwrap();
+    assert_eq!(zombie.state, ProcessState::Zombie);
+    assert!(zombie.files.is_empty());
+    assert_eq!(process::current(), KERNEL_PROCESS);
+    vfs.set_current_process(child);
+    assert_eq!(vfs.working_directory().unwrap(), "/");
+    vfs.set_current_process(KERNEL_PROCESS);
+    process::remove_process(child);
+    process::exit(shell);
+    process::remove_process(shell);
+}
+
+#[test]
+fn test_bind_mounts() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/data", 0o755).unwrap();
+    vfs.create_directory("/data/www", 0o755).unwrap();
+    vfs.create_directory("/srv", 0o755).unwrap();
+    vfs.write_file("/data/www/index.html", b"<html>").unwrap();
+
+    vfs.bind_mount("/data/www", "/srv", MountOptions { read_only: true }).unwrap();
+    assert_eq!(vfs.read_file("/srv/index.html").unwrap(), b"<html>");
+    assert_eq!(vfs.canonicalize("/srv/..").unwrap(), "/");
+    assert_eq!(vfs.mounts()[1].source, "/data/www");
+
+    // The bind mount is read-only, the original is not
+    assert_eq!(vfs.write_file("/srv/index.html", b""), Err(FileSystemError::ReadOnly));
+    assert_eq!(vfs.create_directory("/srv/new", 0o755), Err(FileSystemError::ReadOnly));
+    vfs.write_file("/data/www/about.html", b"about").unwrap();
+    assert_eq!(vfs.read_file("/srv/about.html").unwrap(), b"about");
+
+    assert_eq!(vfs.bind_mount("/data/www/index.html", "/srv", MountOptions::default()), Err(FileSystemError::NotADirectory));
+}
+
+#[test]
+fn test_tmpfs_hard_links() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/a", 0o755).unwrap();
+    vfs.write_file("/a/file", b"shared").unwrap();
+    vfs.link("/a/file", "/copy").unwrap();
+
+    let original = vfs.metadata("/a/file").unwrap();
+    let copy = vfs.metadata("/copy").unwrap();
+    assert_eq!((original.inode, original.links), (copy.inode, 2));
+    vfs.write_file("/copy", b"changed").unwrap();
+    assert_eq!(vfs.read_file("/a/file").unwrap(), b"changed");
+
+    vfs.remove_file("/a/file").unwrap();
+    assert_eq!(vfs.metadata("/copy").unwrap().links, 1);
+    assert_eq!(vfs.link("/a", "/b"), Err(FileSystemError::PermissionDenied));
+    assert_eq!(vfs.remove_file("/a"), Err(FileSystemError::IsADirectory));
+
+    // Directories count their subdirectories' `..` links
+    vfs.create_directory("/a/b", 0o755).unwrap();
+    assert_eq!(vfs.metadata("/a").unwrap().links, 3);
+    vfs.remove_directory("/a/b").unwrap();
+    assert_eq!(vfs.metadata("/a").unwrap().links, 2);
+}
+
+#[test]
+fn test_tmpfs_rename() {
+    let vfs = tmpfs_vfs();
+    vfs.create_directory("/src", 0o755).unwrap();
+    vfs.create_directory("/src/sub", 0o755).unwrap();
+    vfs.create_directory("/dst", 0o755).unwrap();
+    vfs.write_file("/src/sub/file", b"data").unwrap();
+    vfs.write_file("/other", b"old").unwrap();
+
+    vfs.rename("/src/sub", "/dst/moved").unwrap();
+    assert_eq!(vfs.read_file("/dst/moved/file").unwrap(), b"data");
+    assert_eq!(vfs.canonicalize("/dst/moved/..").unwrap(), "/dst");
+    assert_eq!(vfs.metadata("/src").unwrap().links, 2);
+    assert_eq!(vfs.metadata("/dst").unwrap().links, 3);
+
+    // Files replace files, directories only empty directories
+    vfs.rename("/other", "/dst/moved/file").unwrap();
+    assert_eq!(vfs.read_file("/dst/moved/file").unwrap(), b"old");
+    assert_eq!(vfs.rename("/dst/moved/file", "/src"), Err(FileSystemError::IsADirectory));
+    assert_eq!(vfs.rename("/dst/moved", "/src"), Ok(()));
+    assert_eq!(vfs.rename("/dst", "/src/file"), Err(FileSystemError::NotADirectory));
+    vfs.create_directory("/dst/full", 0o755).unwrap();
+    vfs.write_file("/dst/full/x", b"").unwrap();
+    assert_eq!(vfs.rename("/src", "/dst/full"), Err(FileSystemError::DirectoryNotEmpty));
+
+    // A directory cannot move below itself
+    assert_eq!(vfs.rename("/dst", "/dst/full/inside"), Err(FileSystemError::InvalidArgument));
+}
+
+#[test]
+fn test_tmpfs_attributes() {
+    let vfs = tmpfs_vfs();
+    vfs.open("/script", &OpenOptions::new().write(true).create(true).permissions(0o700)).unwrap();
+    let created = vfs.metadata("/script").unwrap();
+    assert_eq!(created.mode(), FileType::Regular.mode_bits() | 0o700);
+    assert!(created.created.is_some());
+
+    let changes = SetAttributes {
+        permissions: Some(0o4755),
+        uid: Some(1000),
+        gid: Some(100),
+        modified: Some(Duration::from_secs(86400)),
+        ..SetAttributes::default()
+    };
+    vfs.set_attributes("/script", &changes).unwrap();
+    let metadata: Metadata = vfs.metadata("/script").unwrap();
+    assert_eq!((metadata.permissions, metadata.uid, metadata.gid), (0o4755, 1000, 100));
+    assert_eq!(metadata.modified, Duration::from_secs(86400));
+    assert!(metadata.changed >= created.changed);
+
+    // Writing moves the modification time forward again
+    vfs.write_file("/script", b"#!/bin/sh").unwrap();
+    assert!(vfs.metadata("/script").unwrap().modified > Duration::from_secs(86400));
+}
+
+#[test]
+fn test_tmpfs_size_limit() {
+    let tmpfs = Arc::new(TmpFs::with_size_limit(8192));
+    let vfs = Vfs::new();
+    vfs.mount(tmpfs.clone(), "none", "/", MountOptions::default()).unwrap();
+
+    vfs.write_file("/a", &[1; 6000]).unwrap();
+    // Only what fits is written, then the filesystem is full
+    let mut file = vfs.open("/b", &OpenOptions::new().write(true).create(true)).unwrap();
+    assert_eq!(file.write(&[2; 4000]), Ok(2192));
+    assert_eq!(file.write(&[2; 1]), Err(FileSystemError::NoSpace));
+    assert_eq!(file.truncate(3000), Err(FileSystemError::NoSpace));
+    assert_eq!(tmpfs.stats().unwrap().free_blocks, 0);
+
+    // An unlinked file keeps its space while it is open
+    let mut reader = vfs.open("/a", &OpenOptions::new().read(true)).unwrap();
+    vfs.remove_file("/a").unwrap();
+    assert_eq!(file.write(&[2; 1]), Err(FileSystemError::NoSpace));
+    let mut buffer = [0; 10];
+    assert_eq!(reader.read(&mut buffer), Ok(10));
+    drop(reader);
+    assert_eq!(file.write(&[2; 4000]), Ok(4000));
+    assert_eq!(tmpfs.stats().unwrap().inodes, 2);
+}
+
+// An image or archive from `fs_fixtures`
+fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/fs_fixtures").join(name)).unwrap()
+}
+
+// A newc entry with the given magic, name, mode and data
+fn newc_entry(magic: &[u8; 6], name: &str, mode: u32, data: &[u8]) -> Vec<u8> {
+    let checksum = data.iter().map(|byte| *byte as u32).sum::<u32>();
+    let fields = [1, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, checksum];
+    let mut entry = magic.to_vec();
+    for field in fields {
+        entry.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    entry.extend_from_slice(name.as_bytes());
+    entry.push(0);
+    entry.resize(entry.len().next_multiple_of(4), 0);
+    entry.extend_from_slice(data);
+    entry.resize(entry.len().next_multiple_of(4), 0);
+    entry
+}
+
+// initramfs.cpio was made from a small tree with `find . | sort | bsdcpio -o -H newc`
+#[test]
+fn test_cpio_entries() {
+    let archive = fixture("initramfs.cpio");
+    let entries: Vec<_> = Archive::new(&archive).collect::<Result<_, _>>().unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| entry.name).collect();
+    assert_eq!(
+        names,
+        [
+            ".", "./bin", "./empty", "./etc", "./etc/my_os", "./etc/my_os/config.json", "./init", "./run.fifo", "./usr",
+            "./usr/bin", "./usr/bin/busybox", "./usr/bin/sh"
+        ]
+    );
+
+    let config = &entries[5];
+    assert_eq!(config.file_type(), Some(FileType::Regular));
+    assert_eq!((config.permissions(), config.modified), (0o600, 981173106));
+    assert_eq!(config.data, b"{\"hostname\": \"initramfs\"}\n");
+    assert_eq!(entries[1].data, b"usr/bin");
+    assert_eq!(entries[7].file_type(), Some(FileType::Fifo));
+
+    // Hard links share an inode number, and only the last carries the data
+    let (busybox, sh) = (&entries[10], &entries[11]);
+    assert_eq!((busybox.inode, busybox.links, busybox.data.len()), (sh.inode, 2, 0));
+    assert_eq!(sh.data, b"busybox");
+}
+
+#[test]
+fn test_cpio_errors() {
+    let file = newc_entry(NEWC_MAGIC, "file", 0o100644, b"data");
+    let trailer = newc_entry(NEWC_MAGIC, "TRAILER!!!", 0, b"");
+
+    // Archives may be concatenated, with zero padding in between
+    let mut archive = [file.clone(), trailer.clone(), vec![0; 512]].concat();
+    archive.extend_from_slice(&[newc_entry(NEWC_CRC_MAGIC, "other", 0o100644, b"xyz"), trailer.clone()].concat());
+    let names: Vec<_> = Archive::new(&archive).map(|entry| entry.unwrap().name).collect();
+    assert_eq!(names, ["file", "other"]);
+
+    let mut corrupt = newc_entry(NEWC_CRC_MAGIC, "other", 0o100644, b"xyz");
+    let last = corrupt.len() - 2;
+    corrupt[last] = b'!';
+    assert_eq!(Archive::new(&corrupt).next(), Some(Err(CpioError::Checksum)));
+    assert_eq!(Archive::new(&file[..file.len() - 4]).next(), Some(Err(CpioError::Truncated)));
+    assert_eq!(Archive::new(b"070707000000").next(), Some(Err(CpioError::Truncated)));
+    let mut odc = file.clone();
+    odc[..6].copy_from_slice(b"070707");
+    assert_eq!(Archive::new(&odc).next(), Some(Err(CpioError::BadMagic)));
+
+    // Nothing may be unpacked outside the destination
+    let vfs = tmpfs_vfs();
+    let escape = [newc_entry(NEWC_MAGIC, "a/../../escape", 0o100644, b""), trailer.clone()].concat();
+    assert_eq!(cpio::unpack(&escape, &vfs, "/"), Err(CpioError::BadName));
+
+    // Nor through a symlink an earlier entry made, though links inside it can be followed
+    vfs.create_directory("/etc", 0o755).unwrap();
+    vfs.write_file("/etc/passwd", b"root").unwrap();
+    vfs.create_directory("/srv", 0o755).unwrap();
+    let symlink = |name, target: &str| newc_entry(NEWC_MAGIC, name, 0o120777, target.as_bytes());
+    let file = |name| newc_entry(NEWC_MAGIC, name, 0o100644, b"owned");
+    let inside = [symlink("lib", "usr/lib"), file("usr/lib/libc.so"), file("lib/libm.so"), trailer.clone()].concat();
+    assert_eq!(cpio::unpack(&inside, &vfs, "/srv"), Ok(3));
+    assert_eq!(vfs.read_file("/srv/usr/lib/libm.so").unwrap(), b"owned");
+    for target in ["/etc", "../etc", "/missing"] {
+        let escape = [symlink("out", target), file("out/passwd"), trailer.clone()].concat();
+        assert_eq!(cpio::unpack(&escape, &vfs, "/srv"), Err(CpioError::BadName));
+    }
+    let replace = [symlink("passwd", "/etc/passwd"), file("passwd"), trailer].concat();
+    assert_eq!(cpio::unpack(&replace, &vfs, "/srv"), Ok(2));
+    assert_eq!(vfs.read_file("/etc/passwd").unwrap(), b"root");
+    assert_eq!(vfs.read_file("/srv/passwd").unwrap(), b"owned");
+}
+
+#[test]
+fn test_unpack_initramfs() {
+    let vfs = Vfs::new();
+    assert_eq!(initramfs::mount_root(&vfs, Some(&fixture("initramfs.cpio"))), Ok(11));
+    assert_eq!(vfs.mounts()[0].filesystem, "tmpfs");
+
+    let config = vfs.metadata("/etc/my_os/config.json").unwrap();
+    assert_eq!((config.permissions, config.modified), (0o600, Duration::from_secs(981173106)));
+    assert_eq!(vfs.read_file("/etc/my_os/config.json").unwrap(), b"{\"hostname\": \"initramfs\"}\n");
+    assert_eq!(vfs.metadata("/empty").unwrap().permissions, 0o700);
+    assert!(vfs.symlink_metadata("/run.fifo").unwrap().file_type == FileType::Fifo);
+
+    // Symlinks and hard links survive
+    assert_eq!(vfs.read_link("/bin").unwrap(), "usr/bin");
+    assert_eq!(vfs.read_file("/bin/sh").unwrap(), b"busybox");
+    let (busybox, sh) = (vfs.metadata("/usr/bin/busybox").unwrap(), vfs.metadata("/usr/bin/sh").unwrap());
+    assert_eq!((busybox.inode, busybox.links, busybox.size), (sh.inode, 2, 7));
+
+    assert_eq!(initramfs::find_init(&vfs).unwrap(), "/init");
+    vfs.set_attributes("/init", &SetAttributes { permissions: Some(0o644), ..SetAttributes::default() }).unwrap();
+    assert_eq!(initramfs::find_init(&vfs), Err(FileSystemError::PermissionDenied));
+
+    // Unpacking again replaces what is there
+    vfs.write_file("/etc/my_os/config.json", b"{}").unwrap();
+    cpio::unpack(&fixture("initramfs.cpio"), &vfs, "/").unwrap();
+    assert_eq!(vfs.metadata("/etc/my_os/config.json").unwrap().size, 26);
+    assert_eq!(vfs.metadata("/usr/bin/sh").unwrap().links, 2);
+}
+
+#[test]
+fn test_multiboot2_module_tag() {
+    let mut tag = vec![0u8; 24];
+    tag[0..4].copy_from_slice(&3u32.to_le_bytes());
+    tag[4..8].copy_from_slice(&23u32.to_le_bytes());
+    tag[8..12].copy_from_slice(&0x0020_0000u32.to_le_bytes());
+    tag[12..16].copy_from_slice(&0x0020_0800u32.to_le_bytes());
+    tag[16..22].copy_from_slice(b"initrd");
+    let module = BootModule::from_multiboot2(&tag).unwrap();
+    assert_eq!((module.start, module.end), (0x0020_0000, 0x0020_0800));
+    assert_eq!(module.command_line, "initrd");
+
+    tag[0] = 8;
+    assert!(BootModule::from_multiboot2(&tag).is_none());
+}
+
+// ext2.img was made from a small tree with
+// `mkfs.ext2 -b 1024 -g 256 -N 64 -I 256 -m 0 -d <tree> ext2.img 384`, giving two
+// block groups. docs/big.bin is 20000 bytes, so it needs an indirect block.
+fn ext2_device() -> Arc<MemoryBlockDevice> {
+    Arc::new(MemoryBlockDevice::from_image(fixture("ext2.img"), 4096))
+}
+
+fn ext2_vfs(device: &Arc<MemoryBlockDevice>) -> Vfs {
+    let vfs = Vfs::new();
+    let filesystem = Ext2FileSystem::mount(device.clone(), false).unwrap();
+    vfs.mount(filesystem, "disk0", "/", MountOptions::default()).unwrap();
+    vfs
+}
+
+fn big_file() -> Vec<u8> {
+    (0..20000usize).map(|i| ((i * 7 + i / 1024) % 251) as u8).collect()
+}
+
+// Whether to leave out what needs the filesystem tools, e.g. on a host without them.
+// Otherwise a missing tool fails the test, so nothing is silently left unchecked.
+fn skip_tool(tool: &str, name: &str) -> bool {
+    let skip = std::env::var_os("FS_TEST_SKIP_TOOLS").is_some();
+    if skip {
+        eprintln!("{}: skipped for {} as FS_TEST_SKIP_TOOLS is set", tool, name);
+    }
+    skip
+}
+
+// Run a filesystem tool from `package`
+fn run_tool(command: &mut Command, package: &str) -> Output {
+    let tool = command.get_program().to_string_lossy().into_owned();
+    match command.output() {
+        Ok(output) => {
+            assert!(output.status.success(), "{}: {}", tool, String::from_utf8_lossy(&output.stdout));
+            output
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            panic!("{} is not installed; install {} or set FS_TEST_SKIP_TOOLS=1", tool, package)
+        }
+        Err(error) => panic!("{}: {}", tool, error),
+    }
+}
+
+// The path of a scratch image for test `name`
+fn temp_image(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("fs_test_{}_{}.img", std::process::id(), name))
+}
+
+// Check an image with a filesystem checker from `package`; an image that fails the
+// check is left in the temporary directory to be looked at
+fn fsck(tool: &str, package: &str, arguments: &[&str], name: &str, image: &[u8]) {
+    if skip_tool(tool, name) {
+        return;
+    }
+    let path = temp_image(name);
+    std::fs::write(&path, image).unwrap();
+    run_tool(Command::new(tool).args(arguments).arg(&path), package);
+    std::fs::remove_file(&path).unwrap();
+}
+
+fn e2fsck(name: &str, image: &[u8]) {
+    fsck("e2fsck", "e2fsprogs", &["-fn"], name, image);
+}
+
+#[test]
+fn test_ext2_read() {
+    let device = ext2_device();
+    let filesystem = Ext2FileSystem::mount(device, true).unwrap();
+    let vfs = Vfs::new();
+    vfs.mount(filesystem.clone(), "disk0", "/", MountOptions::default()).unwrap();
+
+    let mut names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    names.sort();
+    assert_eq!(names, ["docs", "hello.txt", "link", "longlink", "lost+found"]);
+    assert_eq!(vfs.read_file("/hello.txt").unwrap(), b"hello, ext2\n");
+    assert_eq!(vfs.read_file("/docs/big.bin").unwrap(), big_file());
+
+    let hello = vfs.metadata("/hello.txt").unwrap();
+    assert_eq!((hello.inode, hello.links, hello.permissions), (14, 2, 0o644));
+    assert_eq!(hello.modified, Duration::from_secs(981173106));
+    assert_eq!(vfs.metadata("/docs/hardlink").unwrap().inode, 14);
+    assert_eq!(vfs.metadata("/docs/nested").unwrap().permissions, 0o700);
+    assert_eq!(vfs.metadata("/docs/big.bin").unwrap().blocks, 42);
+
+    // Short symlink targets are kept in the inode, longer ones in a block
+    assert_eq!(vfs.read_link("/link").unwrap(), "hello.txt");
+    assert_eq!(vfs.read_file("/link").unwrap(), b"hello, ext2\n");
+    assert_eq!(vfs.read_link("/longlink").unwrap(), format!("docs/nested/{}", "x".repeat(70)));
+
+    let stats = filesystem.stats().unwrap();
+    assert_eq!((stats.block_size, stats.blocks, stats.free_blocks), (1024, 384, 226));
+    assert_eq!((stats.inodes, stats.free_inodes), (64, 47));
+    assert_eq!(vfs.write_file("/new", b"").err(), Some(FileSystemError::ReadOnly));
+}
+
+#[test]
+fn test_ext2_write() {
+    let device = ext2_device();
+    let vfs = ext2_vfs(&device);
+    vfs.create_directory("/data", 0o755).unwrap();
+    vfs.create_directory("/data/sub", 0o750).unwrap();
+    assert_eq!(vfs.metadata("/data").unwrap().links, 3);
+    vfs.write_file("/data/notes", b"first").unwrap();
+    vfs.symlink(&"long/".repeat(20), "/data/long").unwrap();
+    vfs.make_node("/data/fifo", FileType::Fifo, 0o600).unwrap();
+
+    // Sparse writes reach the double and triple indirect blocks without filling the volume
+    let mut file = vfs.open("/data/sparse", &OpenOptions::new().write(true).create(true)).unwrap();
+    let double = (12 + 256 + 10) * 1024;
+    let triple = (12 + 256 + 256 * 256 + 5) * 1024 + 100;
+    file.seek(SeekFrom::Start(double)).unwrap();
+    file.write(b"double").unwrap();
+    file.seek(SeekFrom::Start(triple)).unwrap();
+    file.write(b"triple").unwrap();
+    drop(file);
+    let mut file = vfs.open("/data/sparse", &OpenOptions::new().read(true)).unwrap();
+    let mut buffer = [1; 6];
+    file.seek(SeekFrom::Start(triple)).unwrap();
+    assert_eq!(file.read(&mut buffer).unwrap(), 6);
+    assert_eq!(&buffer, b"triple");
+    file.seek(SeekFrom::Start(double - 6)).unwrap();
+    assert_eq!(file.read(&mut buffer).unwrap(), 6);
+    assert_eq!(buffer, [0; 6]);
+    drop(file);
+    assert_eq!(vfs.metadata("/data/sparse").unwrap().size, triple + 6);
+
+    // Enough entries to spill the directory into more blocks
+    for i in 0..30 {
+        vfs.write_file(&format!("/data/sub/a-file-with-quite-a-long-name-{}", i), &[i as u8; 100]).unwrap();
+    }
+    assert!(vfs.metadata("/data/sub").unwrap().size > 1024);
+    for i in (0..30).step_by(2) {
+        vfs.remove_file(&format!("/data/sub/a-file-with-quite-a-long-name-{}", i)).unwrap();
+    }
+    assert_eq!(vfs.read_directory("/data/sub").unwrap().len(), 15);
+    assert_eq!(vfs.read_file("/data/sub/a-file-with-quite-a-long-name-29").unwrap(), [29; 100]);
+
+    vfs.rename("/data/notes", "/docs/notes").unwrap();
+    vfs.rename("/data/sub", "/docs/nested/sub").unwrap();
+    assert_eq!(vfs.metadata("/data").unwrap().links, 2);
+    assert_eq!(vfs.metadata("/docs/nested").unwrap().links, 3);
+    assert_eq!(vfs.rename("/docs", "/docs/nested/sub/docs"), Err(FileSystemError::InvalidArgument));
+    vfs.link("/docs/notes", "/data/notes").unwrap();
+    vfs.rename("/hello.txt", "/data/notes").unwrap();
+    assert_eq!(vfs.read_file("/data/notes").unwrap(), b"hello, ext2\n");
+    assert_eq!(vfs.metadata("/docs/notes").unwrap().links, 1);
+
+    // Shrinking frees blocks and zeros the tail; growing leaves a hole
+    vfs.write_file("/docs/big.bin", &big_file()).unwrap();
+    let file = vfs.lookup("/docs/big.bin").unwrap();
+    file.truncate(1500).unwrap();
+    file.truncate(3000).unwrap();
+    let mut contents = big_file()[..1500].to_vec();
+    contents.resize(3000, 0);
+    assert_eq!(vfs.read_file("/docs/big.bin").unwrap(), contents);
+    assert_eq!(vfs.metadata("/docs/big.bin").unwrap().blocks, 4);
+    drop(file);
+
+    assert_eq!(vfs.remove_directory("/docs/nested"), Err(FileSystemError::DirectoryNotEmpty));
+    vfs.remove_file("/data/sparse").unwrap();
+    vfs.sync().unwrap();
+    e2fsck("ext2_write", &device.contents());
+
+    // Everything is on the device
+    let vfs = ext2_vfs(&device);
+    assert_eq!(vfs.read_link("/data/long").unwrap(), "long/".repeat(20));
+    assert_eq!(vfs.metadata("/data/fifo").unwrap().file_type, FileType::Fifo);
+    assert_eq!(vfs.metadata("/docs/nested/sub").unwrap().permissions, 0o750);
+    assert_eq!(vfs.read_file("/docs/nested/sub/a-file-with-quite-a-long-name-27").unwrap(), [27; 100]);
+}
+
+#[test]
+fn test_ext2_free_space() {
+    let device = ext2_device();
+    let filesystem = Ext2FileSystem::mount(device.clone(), false).unwrap();
+    let vfs = Vfs::new();
+    vfs.mount(filesystem.clone(), "disk0", "/", MountOptions::default()).unwrap();
+    let before = filesystem.stats().unwrap();
+
+    // A file unlinked while open stays readable until it is closed
+    vfs.write_file("/open", b"still here").unwrap();
+    let mut file = vfs.open("/open", &OpenOptions::new().read(true)).unwrap();
+    vfs.remove_file("/open").unwrap();
+    assert_eq!(vfs.metadata("/open").err(), Some(FileSystemError::NotFound));
+    let mut buffer = [0; 10];
+    assert_eq!(file.read(&mut buffer).unwrap(), 10);
+    assert_eq!(&buffer, b"still here");
+    assert_eq!(filesystem.stats().unwrap().free_inodes, before.free_inodes - 1);
+    drop(file);
+    assert_eq!(filesystem.stats().unwrap(), before);
+
+    // A full volume takes what fits, then refuses
+    let mut file = vfs.open("/fill", &OpenOptions::new().write(true).create(true)).unwrap();
+    let written = file.write(&vec![7; 300 * 1024]).unwrap();
+    assert!(written > 200 * 1024 && written < 226 * 1024);
+    assert_eq!(file.write(b"more"), Err(FileSystemError::NoSpace));
+    assert_eq!(filesystem.stats().unwrap().free_blocks, 0);
+    assert_eq!(vfs.create_directory("/full", 0o755), Err(FileSystemError::NoSpace));
+    drop(file);
+    vfs.sync().unwrap();
+    e2fsck("ext2_full", &device.contents());
+
+    vfs.remove_file("/fill").unwrap();
+    assert_eq!(filesystem.stats().unwrap(), before);
+    vfs.sync().unwrap();
+    e2fsck("ext2_freed", &device.contents());
+}
+
+#[test]
+fn test_ext2_features() {
+    let mut image = fixture("ext2.img");
+    image[1024 + 56] = 0;
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    assert_eq!(Ext2FileSystem::mount(device, false).err(), Some(FileSystemError::InvalidArgument));
+
+    // An unknown incompatible feature refuses the volume, an unknown read-only one
+    // keeps it from being written
+    let mut image = fixture("ext2.img");
+    image[1024 + 97] |= 0x80;
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    assert_eq!(Ext2FileSystem::mount(device, false).err(), Some(FileSystemError::NotSupported));
+    let mut image = fixture("ext2.img");
+    image[1024 + 101] |= 0x20;
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    assert!(Ext2FileSystem::mount(device, false).unwrap().read_only());
+
+    // Mounting marks the volume as in use until it is synced
+    let device = ext2_device();
+    let filesystem = Ext2FileSystem::mount(device.clone(), false).unwrap();
+    assert_eq!(device.contents()[1024 + 58], 0);
+    filesystem.sync().unwrap();
+    assert_eq!(device.contents()[1024 + 58], 1);
+}
+
+// ext4.img was made from a small tree with
+// `mkfs.ext4 -b 1024 -N 256 -J size=1 -m 0 -d <tree> ext4.img 2304` and `e2fsck -fD`,
+// giving it extents, 64-bit group descriptors, flex_bg, metadata checksums and an
+// HTree index on `many`. debugfs then logged three transactions that were never
+// replayed: one filling the block of journaled.txt with B, one filling the block
+// of revoked.txt with D, and one revoking that block again.
+fn ext4_device() -> Arc<MemoryBlockDevice> {
+    Arc::new(MemoryBlockDevice::from_image(fixture("ext4.img"), 4096))
+}
+
+// Eight runs of three blocks, each followed by a hole: more extents than fit in the inode
+fn fragmented_file() -> Vec<u8> {
+    let mut contents = vec![0; 7 * 8192 + 3072];
+    for run in 0..8 {
+        contents[run * 8192..run * 8192 + 3072].fill(run as u8 + 1);
+    }
+    contents
+}
+
+#[test]
+fn test_ext4_read() {
+    let filesystem = Ext2FileSystem::mount(ext4_device(), false).unwrap();
+    assert_eq!(filesystem.name(), "ext4");
+    assert!(filesystem.read_only());
+    let vfs = Vfs::new();
+    vfs.mount(filesystem.clone(), "disk0", "/", MountOptions::default()).unwrap();
+
+    assert_eq!(vfs.read_file("/hello.txt").unwrap(), b"hello, ext4\n");
+    assert_eq!(vfs.read_link("/link").unwrap(), "hello.txt");
+    assert_eq!(vfs.read_file("/fragmented.bin").unwrap(), fragmented_file());
+    assert_eq!(vfs.metadata("/fragmented.bin").unwrap().blocks, 50);
+
+    // Lookups in `many` go through its index, listing it reads every block
+    assert_eq!(vfs.read_directory("/many").unwrap().len(), 150);
+    for i in 0..150 {
+        assert_eq!(vfs.read_file(&format!("/many/entry-{:03}", i)).unwrap(), format!("{}\n", i).as_bytes());
+    }
+    assert_eq!(vfs.metadata("/many/entry-150").err(), Some(FileSystemError::NotFound));
+    assert_eq!(vfs.metadata("/many/..").unwrap().inode, 2);
+
+    let stats = filesystem.stats().unwrap();
+    assert_eq!((stats.block_size, stats.blocks, stats.inodes), (1024, 2304, 256));
+    assert_eq!(vfs.write_file("/new", b"").err(), Some(FileSystemError::ReadOnly));
+}
+
+#[test]
+fn test_jbd2_replay() {
+    let device = ext4_device();
+    let journaled = 1150 * 1024;
+    assert_eq!(device.contents()[journaled..journaled + 1024], [b'A'; 1024]);
+    assert_ne!(device.contents()[1024 + 96] & 0x04, 0);
+
+    // Committed transactions are replayed in order, except for revoked blocks
+    let vfs = Vfs::new();
+    vfs.mount(Ext2FileSystem::mount(device.clone(), true).unwrap(), "disk0", "/", MountOptions::default()).unwrap();
+    assert_eq!(vfs.read_file("/journaled.txt").unwrap(), [b'B'; 1024]);
+    assert_eq!(vfs.read_file("/revoked.txt").unwrap(), [b'C'; 1024]);
+
+    // The journal is empty and the volume no longer needs recovery
+    assert_eq!(device.contents()[1024 + 96] & 0x04, 0);
+    e2fsck("ext4_replayed", &device.contents());
+    let replayed = device.contents();
+    Ext2FileSystem::mount(device.clone(), true).unwrap();
+    assert!(device.contents() == replayed);
+}
+
+#[test]
+fn test_ext4_checksums() {
+    // The superblock, an inode (hello.txt, at the start of block 55) and a leaf block
+    // of `many` each fail their checksum after a change
+    let mut image = fixture("ext4.img");
+    image[1024 + 0x20] ^= 1;
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    assert_eq!(Ext2FileSystem::mount(device, true).err(), Some(FileSystemError::Corrupted("superblock checksum")));
+
+    let mut image = fixture("ext4.img");
+    image[55 * 1024 + 16] ^= 1;
+    image[1201 * 1024 + 20] ^= 1;
+    let vfs = Vfs::new();
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    vfs.mount(Ext2FileSystem::mount(device, true).unwrap(), "disk0", "/", MountOptions::default()).unwrap();
+    assert_eq!(vfs.metadata("/hello.txt").err(), Some(FileSystemError::Corrupted("inode checksum")));
+    assert_eq!(vfs.read_directory("/many").err(), Some(FileSystemError::Corrupted("directory block checksum")));
+    assert_eq!(vfs.read_file("/fragmented.bin").unwrap(), fragmented_file());
+
+    // Features this driver cannot read refuse the volume, here inline data
+    let mut image = fixture("ext4.img");
+    image[1024 + 97] |= 0x80;
+    let checksum = crate::fs::ext4::crc32c(!0, &image[1024..1024 + 0x3FC]);
+    image[1024 + 0x3FC..1024 + 0x400].copy_from_slice(&checksum.to_le_bytes());
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    assert_eq!(Ext2FileSystem::mount(device, true).err(), Some(FileSystemError::NotSupported));
+}
+
+// fat12.img was written by a script to the layout `mkfs.fat -F 12 -s 1 -r 512` gives
+// a 256 KiB image, as the FAT tools were not at hand. It has a volume label, 8.3
+// names in upper case and in lower case (by the Windows NT case flags), long names,
+// a deleted entry, EFI/BOOT/BOOTX64.EFI on six clusters out of order, and `many`,
+// whose forty long names take several clusters. Everything is dated
+// 2024-05-01 12:34:56.
+fn fat12_device() -> Arc<MemoryBlockDevice> {
+    Arc::new(MemoryBlockDevice::from_image(fixture("fat12.img"), 4096))
+}
+
+fn fat_vfs(device: &Arc<MemoryBlockDevice>) -> Vfs {
+    let vfs = Vfs::new();
+    vfs.mount(FatFileSystem::mount(device.clone(), false).unwrap(), "disk0", "/", MountOptions::default()).unwrap();
+    vfs
+}
+
+fn boot_file() -> Vec<u8> {
+    (0..3000usize).map(|i| ((i * 7 + i / 512) % 251) as u8).collect()
+}
+
+// A blank volume of `sectors` 512-byte sectors and one sector per cluster, laid out
+// as `mkfs.fat -F <bits> -s 1` lays it out. FAT16 needs 4085 clusters, FAT32 65525.
+fn blank_fat(bits: u32, sectors: u32) -> Vec<u8> {
+    let fat32 = bits == 32;
+    let (reserved, root_entries) = if fat32 { (32, 0) } else { (1, 512) };
+    let fat_sectors = ((sectors + 2) * bits / 8).div_ceil(512);
+    let clusters = sectors - reserved - 2 * fat_sectors - root_entries * 32 / 512;
+    let mut image = vec![0u8; sectors as usize * 512];
+    let mut put = |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(0, &[0xEB, if fat32 { 0x58 } else { 0x3C }, 0x90]);
+    put(3, b"mkfs.fat");
+    put(11, &512u16.to_le_bytes());
+    put(13, &[1]);
+    put(14, &(reserved as u16).to_le_bytes());
+    put(16, &[2]);
+    put(17, &(root_entries as u16).to_le_bytes());
+    put(21, &[0xF8]);
+    put(32, &sectors.to_le_bytes());
+    let extended = if fat32 {
+        put(36, &fat_sectors.to_le_bytes());
+        put(44, &2u32.to_le_bytes());
+        put(48, &1u16.to_le_bytes());
+        put(50, &6u16.to_le_bytes());
+        64
+    } else {
+        put(22, &(fat_sectors as u16).to_le_bytes());
+        36
+    };
+    put(extended, &[0x80, 0, 0x29, 0x78, 0x56, 0x34, 0x12]);
+    put(extended + 7, b"NO NAME    ");
+    put(extended + 18, format!("FAT{}   ", bits).as_bytes());
+    put(510, &[0x55, 0xAA]);
+    for copy in 0..2 {
+        let fat = ((reserved + copy * fat_sectors) * 512) as usize;
+        match fat32 {
+            // The root directory takes cluster 2
+            true => put(fat, &[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F]),
+            false => put(fat, &[0xF8, 0xFF, 0xFF, 0xFF]),
+        }
+    }
+    if fat32 {
+        put(512, &0x41615252u32.to_le_bytes());
+        put(512 + 484, &0x61417272u32.to_le_bytes());
+        put(512 + 488, &(clusters - 1).to_le_bytes());
+        put(512 + 492, &3u32.to_le_bytes());
+        put(512 + 508, &0xAA550000u32.to_le_bytes());
+        // The backup boot sector and FSInfo
+        let first = image[..1024].to_vec();
+        image[6 * 512..8 * 512].copy_from_slice(&first);
+    }
+    image
+}
+
+fn fsck_fat(name: &str, image: &[u8]) {
+    fsck("fsck.fat", "dosfstools", &["-n"], name, image);
+}
+
+// A blank volume of `sectors` 512-byte sectors made by `mkfs.fat`, with one sector
+// per cluster like `blank_fat`; None when the tools are skipped
+fn mkfs_fat(bits: u32, sectors: u32) -> Option<Vec<u8>> {
+    let name = format!("mkfs_fat{}", bits);
+    if skip_tool("mkfs.fat", &name) {
+        return None;
+    }
+    let path = temp_image(&name);
+    let _ = std::fs::remove_file(&path);
+    // The size is given in KiB
+    let kib = (sectors / 2).to_string();
+    let mut command = Command::new("mkfs.fat");
+    command.args(["-C", "-F", &bits.to_string(), "-s", "1", "-S", "512", "-i", "12345678"]).arg(&path).arg(kib);
+    run_tool(&mut command, "dosfstools");
+    let image = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    Some(image)
+}
+
+#[test]
+fn test_fat_read() {
+    let filesystem = FatFileSystem::mount(fat12_device(), true).unwrap();
+    assert_eq!((filesystem.name(), filesystem.fat_type()), ("vfat", FatType::Fat12));
+    let vfs = Vfs::new();
+    vfs.mount(filesystem.clone(), "disk0", "/", MountOptions::default()).unwrap();
+
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["README.TXT", "notes.txt", "A long file name.txt", "EFI", "MANY", "Ünïcödé ñame.txt"]);
+
+    // Names match in any case, and by their short alias
+    assert_eq!(vfs.read_file("/a long FILE name.txt").unwrap(), b"long names work\n");
+    assert_eq!(vfs.read_file("/ALONGF~1.TXT").unwrap(), b"long names work\n");
+    assert_eq!(vfs.read_file("/NOTES.TXT").unwrap(), b"lower case short name\n");
+    assert_eq!(vfs.read_file("/Ünïcödé ñame.txt").unwrap(), b"unicode\n");
+    assert_eq!(vfs.read_file("/gone for good.txt").err(), Some(FileSystemError::NotFound));
+    assert_eq!(vfs.read_file("/efi/boot/bootx64.efi").unwrap(), boot_file());
+    assert_eq!(vfs.read_directory("/many").unwrap().len(), 40);
+    for i in 0..40 {
+        assert_eq!(vfs.read_file(&format!("/many/Entry number {:02}.dat", i)).unwrap(), format!("{}\n", i).as_bytes());
+    }
+
+    let readme = vfs.metadata("/README.TXT").unwrap();
+    assert_eq!((readme.permissions, readme.size, readme.blocks, readme.block_size), (0o644, 18, 1, 512));
+    assert_eq!(readme.modified, Duration::from_secs(1714566896));
+    assert_eq!(readme.accessed, Duration::from_secs(1714521600));
+    assert_eq!(readme.created, Some(Duration::from_secs(1714566896)));
+    assert_eq!(vfs.metadata("/").unwrap().links, 4);
+    assert_eq!(vfs.metadata("/EFI").unwrap().links, 3);
+
+    let stats = filesystem.stats().unwrap();
+    assert_eq!((stats.block_size, stats.blocks, stats.free_blocks), (512, 475, 415));
+    assert_eq!(vfs.write_file("/new", b"").err(), Some(FileSystemError::ReadOnly));
+}
+
+#[test]
+fn test_fat_write() {
+    let mut volumes = vec![
+        ("fat12", fixture("fat12.img"), FatType::Fat12),
+        ("fat16", blank_fat(16, 8192), FatType::Fat16),
+        ("fat32", blank_fat(32, 70000), FatType::Fat32),
+    ];
+    // The same on volumes formatted by the FAT tools themselves
+    let formatted = [
+        ("mkfs_fat12", 12, 4096, FatType::Fat12),
+        ("mkfs_fat16", 16, 8192, FatType::Fat16),
+        ("mkfs_fat32", 32, 70000, FatType::Fat32),
+    ];
+    for (name, bits, sectors, fat_type) in formatted {
+        if let Some(image) = mkfs_fat(bits, sectors) {
+            volumes.push((name, image, fat_type));
+        }
+    }
+    for (name, image, fat_type) in volumes {
+        let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+        assert_eq!(FatFileSystem::mount(device.clone(), true).unwrap().fat_type(), fat_type);
+        let vfs = fat_vfs(&device);
+        vfs.create_directory("/Program Files", 0o755).unwrap();
+        vfs.create_directory("/Program Files/Sub Dir", 0o755).unwrap();
+        vfs.create_directory("/Program Files/Nested", 0o755).unwrap();
+        assert_eq!(vfs.metadata("/program files").unwrap().links, 4);
+        vfs.write_file("/Program Files/Read Me First.txt", &big_file()).unwrap();
+        vfs.write_file("/UPPER.TXT", b"upper").unwrap();
+        vfs.write_file("/lower.txt", b"lower").unwrap();
+        assert_eq!(vfs.create_directory("/LOWER.TXT", 0o755), Err(FileSystemError::AlreadyExists));
+        assert_eq!(vfs.write_file("/what?", b""), Err(FileSystemError::InvalidArgument));
+        assert_eq!(vfs.make_node("/fifo", FileType::Fifo, 0o644), Err(FileSystemError::NotSupported));
+
+        // Enough long names to spill the directory into more clusters
+        for i in 0..30 {
+            vfs.write_file(&format!("/Program Files/Sub Dir/A file with quite a long name {}", i), &[i as u8; 100]).unwrap();
+        }
+        assert!(vfs.metadata("/Program Files/Sub Dir").unwrap().size > 2048);
+        for i in (0..30).step_by(2) {
+            vfs.remove_file(&format!("/Program Files/Sub Dir/A file with quite a long name {}", i)).unwrap();
+        }
+        assert_eq!(vfs.read_directory("/Program Files/Sub Dir").unwrap().len(), 15);
+        assert_eq!(vfs.read_file("/Program Files/Sub Dir/a file with quite a long name 29").unwrap(), [29; 100]);
+
+        // Shrinking frees clusters; growing, by truncating or by writing past the end,
+        // fills with zeros
+        let file = vfs.lookup("/Program Files/Read Me First.txt").unwrap();
+        file.truncate(1500).unwrap();
+        file.truncate(3000).unwrap();
+        file.write_at(4000, b"end").unwrap();
+        let mut contents = big_file()[..1500].to_vec();
+        contents.resize(4000, 0);
+        contents.extend_from_slice(b"end");
+        assert_eq!(vfs.read_file("/Program Files/Read Me First.txt").unwrap(), contents);
+        assert_eq!(vfs.metadata("/Program Files/Read Me First.txt").unwrap().blocks, 8);
+        drop(file);
+
+        // Renames change case, move directories and replace files
+        vfs.rename("/lower.txt", "/Lower.TXT").unwrap();
+        vfs.rename("/Program Files/Sub Dir", "/Moved Dir").unwrap();
+        vfs.rename("/Program Files/Nested", "/Moved Dir/Nested").unwrap();
+        assert_eq!(vfs.rename("/Moved Dir", "/Moved Dir/Nested/Inner"), Err(FileSystemError::InvalidArgument));
+        vfs.write_file("/Moved Dir/target", b"replaced").unwrap();
+        vfs.rename("/UPPER.TXT", "/Moved Dir/target").unwrap();
+        assert_eq!(vfs.read_file("/Moved Dir/TARGET").unwrap(), b"upper");
+        assert_eq!(vfs.metadata("/Program Files").unwrap().links, 2);
+        assert_eq!(vfs.remove_directory("/Moved Dir"), Err(FileSystemError::DirectoryNotEmpty));
+        vfs.remove_directory("/Moved Dir/Nested").unwrap();
+        vfs.sync().unwrap();
+
+        // Every copy of the FAT is the same
+        let image = device.contents();
+        let reserved = u16::from_le_bytes([image[14], image[15]]) as usize * 512;
+        let fat_size = match u16::from_le_bytes([image[22], image[23]]) {
+            0 => u32::from_le_bytes(image[36..40].try_into().unwrap()) as usize * 512,
+            sectors => sectors as usize * 512,
+        };
+        assert!(image[reserved..reserved + fat_size] == image[reserved + fat_size..reserved + 2 * fat_size], "{}", name);
+        fsck_fat(name, &image);
+
+        // Everything is on the device
+        let vfs = fat_vfs(&device);
+        let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert!(names.contains(&String::from("Lower.TXT")) && names.contains(&String::from("Moved Dir")), "{}", name);
+        assert_eq!(vfs.read_file("/lower.txt").unwrap(), b"lower");
+        assert_eq!(vfs.read_file("/Moved Dir/A file with quite a long name 27").unwrap(), [27; 100]);
+        assert_eq!(vfs.read_file("/Program Files/Read Me First.txt").unwrap(), contents);
+    }
+}
+
+#[test]
+fn test_fat_free_space() {
+    let device = Arc::new(MemoryBlockDevice::from_image(blank_fat(32, 70000), 4096));
+    let filesystem = FatFileSystem::mount(device.clone(), false).unwrap();
+    let vfs = Vfs::new();
+    vfs.mount(filesystem.clone(), "disk0", "/", MountOptions::default()).unwrap();
+    let before = filesystem.stats().unwrap();
+    assert_eq!((before.blocks, before.free_blocks), (68874, 68873));
+
+    // A file unlinked while open keeps its clusters until it is closed
+    vfs.write_file("/open", b"still here").unwrap();
+    let mut file = vfs.open("/open", &OpenOptions::new().read(true)).unwrap();
+    vfs.remove_file("/open").unwrap();
+    assert_eq!(vfs.metadata("/open").err(), Some(FileSystemError::NotFound));
+    let mut buffer = [0; 10];
+    assert_eq!(file.read(&mut buffer).unwrap(), 10);
+    assert_eq!(&buffer, b"still here");
+    assert_eq!(filesystem.stats().unwrap().free_blocks, before.free_blocks - 1);
+    drop(file);
+    assert_eq!(filesystem.stats().unwrap(), before);
+
+    // Mounting marks the volume as in use until it is synced, which also updates
+    // the free count in the FSInfo sector
+    vfs.write_file("/kept", &[1; 2000]).unwrap();
+    assert_eq!(device.contents()[65] & 1, 1);
+    vfs.sync().unwrap();
+    let image = device.contents();
+    assert_eq!(image[65] & 1, 0);
+    assert_eq!(u32::from_le_bytes(image[512 + 488..512 + 492].try_into().unwrap()), 68873 - 4);
+
+    // A full volume takes what fits, then refuses
+    let device = fat12_device();
+    let filesystem = FatFileSystem::mount(device.clone(), false).unwrap();
+    let vfs = Vfs::new();
+    vfs.mount(filesystem.clone(), "disk0", "/", MountOptions::default()).unwrap();
+    let before = filesystem.stats().unwrap();
+    let mut file = vfs.open("/fill", &OpenOptions::new().write(true).create(true)).unwrap();
+    assert_eq!(file.write(&vec![7; 300 * 1024]).unwrap(), 415 * 512);
+    assert_eq!(file.write(b"more"), Err(FileSystemError::NoSpace));
+    assert_eq!(filesystem.stats().unwrap().free_blocks, 0);
+    assert_eq!(vfs.create_directory("/full", 0o755), Err(FileSystemError::NoSpace));
+    drop(file);
+    vfs.sync().unwrap();
+    fsck_fat("fat_full", &device.contents());
+
+    vfs.remove_file("/fill").unwrap();
+    assert_eq!(filesystem.stats().unwrap(), before);
+}
+
+// Set an entry of the first FAT of fat12.img
+fn set_fat12_entry(image: &mut [u8], cluster: usize, value: u16) {
+    let offset = 512 + cluster * 3 / 2;
+    let pair = u16::from_le_bytes([image[offset], image[offset + 1]]);
+    let pair = match cluster % 2 {
+        1 => (pair & 0x000F) | value << 4,
+        _ => (pair & 0xF000) | value,
+    };
+    image[offset..offset + 2].copy_from_slice(&pair.to_le_bytes());
+}
+
+#[test]
+fn test_fat_corruption() {
+    let mount = |image: Vec<u8>| FatFileSystem::mount(Arc::new(MemoryBlockDevice::from_image(image, 4096)), true);
+    let mut image = fixture("fat12.img");
+    image[12] = 0;
+    assert_eq!(mount(image).err(), Some(FileSystemError::InvalidArgument));
+    let mut image = blank_fat(32, 70000);
+    image[42] = 1;
+    assert_eq!(mount(image).err(), Some(FileSystemError::NotSupported));
+
+    // BOOTX64.EFI is on clusters 40, 41, 45, 42, 50 and 51
+    for (cluster, value, error) in [(45, 0xFF0, "cluster chain"), (51, 40, "cluster chain loops")] {
+        let mut image = fixture("fat12.img");
+        set_fat12_entry(&mut image, cluster, value);
+        let vfs = Vfs::new();
+        vfs.mount(mount(image).unwrap(), "disk0", "/", MountOptions::default()).unwrap();
+        assert_eq!(vfs.read_file("/EFI/BOOT/BOOTX64.EFI").err(), Some(FileSystemError::Corrupted(error)));
+    }
+
+    // A long name whose checksum does not match its short entry is left out. The
+    // root directory follows the boot sector and two FATs of two sectors, and
+    // "A long file name.txt" starts in its seventh slot.
+    let mut image = fixture("fat12.img");
+    image[5 * 512 + 6 * 32 + 13] ^= 1;
+    let vfs = Vfs::new();
+    vfs.mount(mount(image).unwrap(), "disk0", "/", MountOptions::default()).unwrap();
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names[2], "ALONGF~1.TXT");
+    assert_eq!(vfs.read_file("/A long file name.txt").err(), Some(FileSystemError::NotFound));
+}
+
+// ntfs.img was written by a script to the layout `mkntfs -c 1024` gives a 2 MiB
+// volume, as the NTFS tools were not at hand. Its MFT is in two runs, hello.txt is
+// resident and hard linked as Documents/hardlink.txt, the long name has a DOS alias
+// and two runs, sparse.bin has a hole and stops being initialized at 8500 bytes,
+// fragmented.bin continues in an extension record through an attribute list, and
+// Documents indexes its 61 names in three blocks under the index root. Everything
+// was created at 2024-05-01 12:34:56.
+fn ntfs_vfs(image: Vec<u8>) -> Vfs {
+    let vfs = Vfs::new();
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    vfs.mount(NTFSFileSystem::mount(device).unwrap(), "disk0", "/", MountOptions::default()).unwrap();
+    vfs
+}
+
+fn ntfs_pattern(size: usize, seed: usize) -> Vec<u8> {
+    (0..size).map(|i| ((i * seed + i / 1024) % 251) as u8).collect()
+}
+
+// A 4 MiB volume made by `mkntfs` with 1024-byte clusters, with `files` copied into
+// its root by `ntfscp`, which writes without mounting the volume through FUSE; None
+// when the tools are skipped
+fn mkntfs(name: &str, files: &[(&str, &[u8])]) -> Option<Vec<u8>> {
+    if skip_tool("mkntfs", name) {
+        return None;
+    }
+    let path = temp_image(name);
+    std::fs::File::create(&path).unwrap().set_len(4 << 20).unwrap();
+    let mut command = Command::new("mkntfs");
+    command.args(["-F", "-Q", "-c", "1024", "-s", "512", "-p", "0", "-H", "0", "-S", "0", "-L", "fs_test"]).arg(&path);
+    run_tool(&mut command, "ntfs-3g");
+    for (file_name, contents) in files {
+        let source = temp_image(&format!("{}_{}", name, file_name));
+        std::fs::write(&source, contents).unwrap();
+        run_tool(Command::new("ntfscp").arg("-f").arg(&path).arg(&source).arg(file_name), "ntfs-3g");
+        std::fs::remove_file(&source).unwrap();
+    }
+    let image = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    Some(image)
+}
+
+#[test]
+fn test_ntfs_read() {
+    let device = Arc::new(MemoryBlockDevice::from_image(fixture("ntfs.img"), 4096));
+    let filesystem = NTFSFileSystem::mount(device).unwrap();
+    assert_eq!((filesystem.name(), filesystem.read_only()), ("ntfs", true));
+    // The backup boot sector in the last sector is left out of the volume
+    let stats = filesystem.stats().unwrap();
+    assert_eq!((stats.block_size, stats.blocks, stats.free_blocks), (1024, 2047, 2047 - 369));
+    assert_eq!((stats.inodes, stats.free_inodes), (128, 44));
+    let vfs = Vfs::new();
+    vfs.mount(filesystem, "disk0", "/", MountOptions::default()).unwrap();
+
+    // Neither the metadata files nor the DOS alias are listed
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(
+        names,
+        [
+            "compressed.bin",
+            "Documents",
+            "fragmented.bin",
+            "hello.txt",
+            "Long File Name With Spaces.txt",
+            "sparse.bin",
+            "Ünïcödé ñame.txt"
+        ]
+    );
+    assert_eq!(vfs.read_file("/hello.txt").unwrap(), b"Hello from NTFS!\n");
+    assert_eq!(vfs.read_file("/ÜNÏCÖDÉ ÑAME.TXT").unwrap(), b"unicode\n");
+
+    // Names match in any case, and by their DOS alias
+    let long_file = ntfs_pattern(5000, 7);
+    assert_eq!(vfs.read_file("/long file name with spaces.TXT").unwrap(), long_file);
+    assert_eq!(vfs.read_file("/longfi~1.txt").unwrap(), long_file);
+    let metadata = vfs.metadata("/Long File Name With Spaces.txt").unwrap();
+    assert_eq!((metadata.inode, metadata.size, metadata.links, metadata.blocks), (25, 5000, 1, 10));
+    assert_eq!(metadata.permissions, 0o644);
+    assert_eq!(metadata.created, Some(Duration::from_secs(1714566896)));
+    assert_eq!(metadata.modified, Duration::from_secs(1714566897));
+    assert_eq!(metadata.accessed, Duration::from_secs(1714566899));
+
+    // Holes and the uninitialized tail read as zeros
+    let mut sparse = ntfs_pattern(9216, 11);
+    sparse[3 * 1024..7 * 1024].fill(0);
+    sparse[8500..].fill(0);
+    assert_eq!(vfs.read_file("/sparse.bin").unwrap(), sparse);
+    assert_eq!(vfs.metadata("/sparse.bin").unwrap().blocks, 10);
+    assert_eq!(vfs.read_file("/fragmented.bin").unwrap(), ntfs_pattern(6144, 13));
+    assert_eq!(vfs.read_file("/compressed.bin").err(), Some(FileSystemError::NotSupported));
+
+    // Documents takes a trip down its B+ tree
+    let entries = vfs.read_directory("/Documents").unwrap();
+    assert_eq!(entries.len(), 61);
+    assert_eq!((entries[0].name.as_str(), entries[0].inode), ("hardlink.txt", 24));
+    assert!(entries[1..].iter().enumerate().all(|(i, entry)| entry.name == format!("Report number {i:02}.txt")));
+    assert_eq!(entries[0].file_type, FileType::Regular);
+    for i in [0, 20, 21, 22, 41, 42, 43, 59] {
+        let path = format!("/Documents/report NUMBER {i:02}.txt");
+        assert_eq!(vfs.read_file(&path).unwrap(), format!("report {i:02}\n").as_bytes());
+    }
+    assert_eq!(vfs.read_file("/Documents/Report number 60.txt").err(), Some(FileSystemError::NotFound));
+    assert_eq!(vfs.read_file("/Documents/hardlink.txt").unwrap(), b"Hello from NTFS!\n");
+    assert_eq!(vfs.metadata("/hello.txt").unwrap().links, 2);
+    assert_eq!(vfs.metadata("/Documents").unwrap().file_type, FileType::Directory);
+    assert_eq!(vfs.read_file("/Documents").err(), Some(FileSystemError::IsADirectory));
+    assert_eq!(vfs.write_file("/new", b"").err(), Some(FileSystemError::ReadOnly));
+}
+
+#[test]
+fn test_ntfs_mkntfs() {
+    let big = ntfs_pattern(100_000, 3);
+    let files: [(&str, &[u8]); 2] = [("hello.txt", b"Hello from mkntfs!\n"), ("big.bin", &big)];
+    let Some(image) = mkntfs("ntfs_mkntfs", &files) else {
+        return;
+    };
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    let filesystem = NTFSFileSystem::mount(device).unwrap();
+    assert_eq!(filesystem.stats().unwrap().block_size, 1024);
+    let vfs = Vfs::new();
+    vfs.mount(filesystem, "disk0", "/", MountOptions::default()).unwrap();
+
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert!(names.contains(&String::from("hello.txt")) && names.contains(&String::from("big.bin")), "{:?}", names);
+    assert_eq!(vfs.read_file("/hello.txt").unwrap(), b"Hello from mkntfs!\n");
+    assert_eq!(vfs.read_file("/BIG.BIN").unwrap(), big);
+    assert_eq!(vfs.metadata("/big.bin").unwrap().size, 100_000);
+}
+
+#[test]
+fn test_ntfs_corruption() {
+    let device = Arc::new(MemoryBlockDevice::from_image(fixture("fat12.img"), 4096));
+    assert_eq!(NTFSFileSystem::mount(device).err(), Some(FileSystemError::InvalidArgument));
+
+    // A torn write of hello.txt's record (24, in the first run of the MFT at cluster
+    // 32) and of the second block of Documents (at cluster 297) shows in the update
+    // sequence at the end of their first sectors
+    let mut image = fixture("ntfs.img");
+    image[32 * 1024 + 24 * 1024 + 510] ^= 1;
+    image[297 * 1024 + 510] ^= 1;
+    let vfs = ntfs_vfs(image);
+    let mismatch = Some(FileSystemError::Corrupted("update sequence mismatch"));
+    assert_eq!(vfs.read_file("/hello.txt").err(), mismatch);
+    assert_eq!(vfs.read_directory("/Documents").err(), mismatch);
+    assert_eq!(vfs.read_file("/Documents/Report number 00.txt").unwrap(), b"report 00\n");
+    assert_eq!(vfs.read_file("/Documents/Report number 30.txt").err(), mismatch);
+
+    // A run too long to have a byte size: sparse.bin's data runs (record 26, with the
+    // runs 64 bytes into its data attribute at 264) become one hole of 2^54 clusters
+    let mut image = fixture("ntfs.img");
+    let runs = 32 * 1024 + 26 * 1024 + 264 + 64;
+    image[runs..runs + 16].copy_from_slice(&[0x07, 0, 0, 0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let vfs = ntfs_vfs(image);
+    assert_eq!(vfs.read_file("/sparse.bin").err(), Some(FileSystemError::Corrupted("run outside the volume")));
+}
+
+// rockridge.iso and joliet.iso were written by `bsdtar --format iso9660`, the first
+// with Rock Ridge and Joliet, as user 1000 and group 100, and the second with Joliet
+// alone. Files are dated 2024-05-01 12:34:56.
+fn iso_vfs(image: Vec<u8>) -> (Arc<Iso9660FileSystem>, Vfs) {
+    let filesystem = Iso9660FileSystem::mount(Arc::new(MemoryBlockDevice::from_image(image, 4096))).unwrap();
+    let vfs = Vfs::new();
+    vfs.mount(filesystem.clone(), "cdrom0", "/", MountOptions::default()).unwrap();
+    (filesystem, vfs)
+}
+
+#[test]
+fn test_iso9660_rock_ridge() {
+    let (filesystem, vfs) = iso_vfs(fixture("rockridge.iso"));
+    assert_eq!(filesystem.name(), "iso9660");
+    assert_eq!((filesystem.extension(), filesystem.read_only()), (Extension::RockRidge, true));
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(
+        names,
+        [
+            "A rather long file name, with spaces.txt",
+            "boot",
+            "docs",
+            "hello.txt",
+            "kernel",
+            "rr_moved",
+            "Ünïcödé ñame.txt"
+        ]
+    );
+    assert_eq!(vfs.read_file("/hello.txt").unwrap(), b"Hello from ISO 9660!\n");
+    assert_eq!(vfs.read_file("/HELLO.TXT").err(), Some(FileSystemError::NotFound));
+    assert_eq!(vfs.read_file("/A rather long file name, with spaces.txt").unwrap(), b"long names work\n");
+    assert_eq!(vfs.read_file("/Ünïcödé ñame.txt").unwrap(), b"unicode\n");
+
+    // Modes, owners and times come from Rock Ridge
+    let metadata = vfs.metadata("/hello.txt").unwrap();
+    assert_eq!((metadata.file_type, metadata.permissions), (FileType::Regular, 0o600));
+    assert_eq!((metadata.uid, metadata.gid), (1000, 100));
+    assert_eq!((metadata.size, metadata.blocks, metadata.links), (21, 4, 1));
+    assert_eq!(metadata.modified, Duration::from_secs(1714566896));
+    assert_eq!(vfs.metadata("/boot/kernel.bin").unwrap().permissions, 0o755);
+    let kernel: Vec<u8> = (0..10000usize).map(|i| ((i * 7 + i / 2048) % 251) as u8).collect();
+    assert_eq!(vfs.read_file("/boot/kernel.bin").unwrap(), kernel);
+    let docs = vfs.metadata("/docs").unwrap();
+    assert_eq!((docs.file_type, docs.permissions, docs.links, docs.size), (FileType::Directory, 0o755, 3, 8192));
+
+    // Symlinks
+    assert_eq!(vfs.read_link("/kernel").unwrap(), "boot/kernel.bin");
+    assert_eq!(vfs.read_link("/docs/link.txt").unwrap(), "../hello.txt");
+    assert_eq!(vfs.read_file("/kernel").unwrap(), kernel);
+    assert_eq!(vfs.read_file("/docs/link.txt").unwrap(), b"Hello from ISO 9660!\n");
+
+    // A directory spanning four blocks, and one moved to rr_moved for being nine
+    // levels deep that is still found where it was
+    let entries = vfs.read_directory("/docs").unwrap();
+    assert_eq!(entries.len(), 42);
+    for i in [0, 17, 39] {
+        let path = format!("/docs/Document number {i:02}.txt");
+        assert_eq!(vfs.read_file(&path).unwrap(), format!("document {i:02}\n").as_bytes());
+    }
+    assert_eq!(vfs.read_file("/docs/deep/er/1/2/3/4/5/6/bottom.txt").unwrap(), b"very deep\n");
+    assert!(vfs.read_directory("/rr_moved").unwrap().is_empty());
+    let six = vfs.metadata("/docs/deep/er/1/2/3/4/5/6").unwrap();
+    assert_eq!(vfs.read_directory("/docs/deep/er/1/2/3/4/5").unwrap()[0].inode, six.inode);
+
+    assert_eq!(vfs.write_file("/new", b"").err(), Some(FileSystemError::ReadOnly));
+
+    // The path table must start with the root directory
+    let mut image = fixture("rockridge.iso");
+    let path_table = u32::from_le_bytes(image[16 * 2048 + 140..16 * 2048 + 144].try_into().unwrap()) as usize;
+    image[path_table * 2048 + 2] ^= 1;
+    let device = Arc::new(MemoryBlockDevice::from_image(image, 4096));
+    let error = Some(FileSystemError::Corrupted("path table does not start at the root"));
+    assert_eq!(Iso9660FileSystem::mount(device).err(), error);
+}
+
+#[test]
+fn test_iso9660_joliet() {
+    let (filesystem, vfs) = iso_vfs(fixture("joliet.iso"));
+    assert_eq!(filesystem.extension(), Extension::Joliet);
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["Program Files", "Ünïcödé ñame.txt"]);
+    assert_eq!(vfs.read_file("/program files/READ ME FIRST.TXT").unwrap(), b"Joliet names\n");
+    assert_eq!(vfs.read_file("/Program Files/Tools/tool.exe").unwrap(), b"tool\n");
+
+    // Without Rock Ridge everything is root's and readable, and directories count
+    // their subdirectories from the path table
+    let metadata = vfs.metadata("/Program Files").unwrap();
+    assert_eq!((metadata.permissions, metadata.uid, metadata.links), (0o555, 0, 3));
+    let metadata = vfs.metadata("/Ünïcödé ñame.txt").unwrap();
+    assert_eq!((metadata.permissions, metadata.size), (0o444, 8));
+    assert_eq!(metadata.modified, Duration::from_secs(1714566896));
+
+    // Without the Joliet descriptor the plain names are read, in lower case
+    let mut image = fixture("joliet.iso");
+    image[17 * 2048 + 88] = 0;
+    let (filesystem, vfs) = iso_vfs(image);
+    assert_eq!(filesystem.extension(), Extension::Plain);
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["program_", "__n__c__.txt"]);
+    assert_eq!(vfs.read_file("/__N__C__.TXT").unwrap(), b"unicode\n");
+
+    let device = Arc::new(MemoryBlockDevice::from_image(fixture("fat12.img"), 4096));
+    assert_eq!(Iso9660FileSystem::mount(device).err(), Some(FileSystemError::InvalidArgument));
+}
+
+// An image made by `xorriso -as mkisofs` with `options` from a small tree, with a
+// long name, a file of several blocks, a directory nested ten deep and a symlink;
+// None when the tools are skipped
+fn xorriso(name: &str, options: &[&str]) -> Option<Vec<u8>> {
+    if skip_tool("xorriso", name) {
+        return None;
+    }
+    let tree = std::env::temp_dir().join(format!("fs_test_{}_{}", std::process::id(), name));
+    let deep = tree.join("docs/1/2/3/4/5/6/7/8/9");
+    std::fs::create_dir_all(&deep).unwrap();
+    std::fs::write(tree.join("hello.txt"), b"Hello from xorriso!\n").unwrap();
+    std::fs::set_permissions(tree.join("hello.txt"), std::fs::Permissions::from_mode(0o600)).unwrap();
+    std::fs::write(tree.join("A rather long file name, with spaces.txt"), b"long names work\n").unwrap();
+    std::fs::write(tree.join("docs/big.bin"), big_file()).unwrap();
+    std::fs::write(deep.join("deep.txt"), b"deep\n").unwrap();
+    std::os::unix::fs::symlink("hello.txt", tree.join("link")).unwrap();
+
+    let path = temp_image(name);
+    let mut command = Command::new("xorriso");
+    command.args(["-as", "mkisofs"]).args(options).arg("-o").arg(&path).arg(&tree);
+    run_tool(&mut command, "xorriso");
+    std::fs::remove_dir_all(&tree).unwrap();
+    let image = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    Some(image)
+}
+
+#[test]
+fn test_iso9660_xorriso() {
+    let Some(image) = xorriso("iso_rock_ridge", &["-R", "-J"]) else {
+        return;
+    };
+    let (filesystem, vfs) = iso_vfs(image);
+    assert_eq!(filesystem.extension(), Extension::RockRidge);
+    let names: Vec<_> = vfs.read_directory("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    for name in ["A rather long file name, with spaces.txt", "docs", "hello.txt", "link"] {
+        assert!(names.iter().any(|entry| entry == name), "{} not in {:?}", name, names);
+    }
+    assert_eq!(vfs.read_file("/hello.txt").unwrap(), b"Hello from xorriso!\n");
+    assert_eq!(vfs.metadata("/hello.txt").unwrap().permissions, 0o600);
+    assert_eq!(vfs.read_file("/A rather long file name, with spaces.txt").unwrap(), b"long names work\n");
+    assert_eq!(vfs.read_file("/docs/big.bin").unwrap(), big_file());
+    assert_eq!(vfs.read_file("/docs/1/2/3/4/5/6/7/8/9/deep.txt").unwrap(), b"deep\n");
+    assert_eq!(vfs.read_link("/link").unwrap(), "hello.txt");
+    assert_eq!(vfs.read_file("/link").unwrap(), b"Hello from xorriso!\n");
+
+    // With Joliet alone the names are kept, but not the modes or the symlink; the deep
+    // directory cannot be moved to rr_moved without Rock Ridge, so deep paths are allowed
+    let Some(image) = xorriso("iso_joliet", &["-J", "-D"]) else {
+        return;
+    };
+    let (filesystem, vfs) = iso_vfs(image);
+    assert_eq!(filesystem.extension(), Extension::Joliet);
+    assert_eq!(vfs.read_file("/A rather long file name, with spaces.txt").unwrap(), b"long names work\n");
+    assert_eq!(vfs.read_file("/docs/big.bin").unwrap(), big_file());
+    assert_eq!(vfs.metadata("/hello.txt").unwrap().permissions, 0o444);
+}
+
+#[test]
+fn test_procfs() {
+    // `mounts` and `self` read the Vfs they are mounted in, which lives as long as the kernel
+    let vfs: &'static Vfs = Box::leak(Box::new(tmpfs_vfs()));
+    vfs.create_directory("/proc", 0o555).unwrap();
+    vfs.mount(ProcFs::new(vfs), "proc", "/proc", MountOptions { read_only: true }).unwrap();
+
+    let mut shell = Process::new(4242, 1, "sh");
+    shell.arguments = vec![String::from("sh"), String::from("-c"), String::from("echo hi")];
+    shell.state = ProcessState::Running;
+    shell.regions.push(MemoryRegion {
+        start: 0x400000,
+        end: 0x402000,
+        read: true,
+        write: false,
+        execute: true,
+        name: String::from("/bin/sh"),
+    });
+    shell.regions.push(MemoryRegion {
+        start: 0x7ff000,
+        end: 0x800000,
+        read: true,
+        write: true,
+        execute: false,
+        name: String::new(),
+    });
+    shell.files.insert(0, String::from("/dev/console"));
+    shell.files.insert(3, String::from("/proc/mounts"));
+    process::add_process(shell);
+
+    let names: Vec<_> = vfs.read_directory("/proc/4242").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["cmdline", "maps", "status", "fd"]);
+    assert_eq!(vfs.read_file("/proc/4242/cmdline").unwrap(), b"sh\0-c\0echo hi\0");
+    assert_eq!(
+        String::from_utf8(vfs.read_file("/proc/4242/maps").unwrap()).unwrap(),
+        "00400000-00402000 r-x /bin/sh\n007ff000-00800000 rw-\n"
+    );
+    let status = String::from_utf8(vfs.read_file("/proc/4242/status").unwrap()).unwrap();
+    assert_eq!(
+        status,
+        "Name:\tsh\nState:\tR (running)\nPid:\t4242\nPPid:\t1\nVmSize:\t      12 kB\nFDSize:\t2\n"
+    );
+    assert_eq!(vfs.metadata("/proc/4242/status").unwrap().size, status.len() as u64);
+
+    // Descriptors are symlinks to their files, and `self` follows the current process
+    let names: Vec<_> = vfs.read_directory("/proc/4242/fd").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["0", "3"]);
+    assert_eq!(vfs.read_link("/proc/4242/fd/0").unwrap(), "/dev/console");
+    vfs.set_current_process(4242);
+    assert_eq!(vfs.read_link("/proc/self").unwrap(), "4242");
+    assert_eq!(vfs.read_file("/proc/self/fd/3").unwrap(), b"none / tmpfs rw 0 0\nproc /proc proc ro 0 0\n");
+    assert_eq!(vfs.lookup("/proc/self/fd/1").err(), Some(FileSystemError::NotFound));
+    assert_eq!(vfs.lookup("/proc/04242").err(), Some(FileSystemError::NotFound));
+
+    // Files are generated on each read
+    process::update_process(4242, |shell| shell.state = ProcessState::Blocked);
+    assert!(String::from_utf8(vfs.read_file("/proc/self/status").unwrap()).unwrap().contains("State:\tS (sleeping)\n"));
+
+    let address = "10.0.2.15:68".parse().unwrap();
+    let socket = socket_table::add_socket(SocketEntry {
+        protocol: Protocol::Udp,
+        local: address,
+        remote: None,
+        state: "",
+        send_queue: 0,
+        receive_queue: 300,
+        owner: 4242,
+    });
+    let udp = String::from_utf8(vfs.read_file("/proc/net/udp").unwrap()).unwrap();
+    let columns: Vec<_> = udp.lines().nth(1).unwrap().split_whitespace().collect();
+    assert_eq!(columns, ["10.0.2.15:68", "*:*", "0", "300", "4242"]);
+    socket_table::remove_socket(socket);
+    assert_eq!(vfs.read_file("/proc/net/udp").unwrap().iter().filter(|&&byte| byte == b'\n').count(), 1);
+
+    let meminfo = String::from_utf8(vfs.read_file("/proc/meminfo").unwrap()).unwrap();
+    assert!(meminfo.starts_with("MemTotal:"));
+    let uptime = String::from_utf8(vfs.read_file("/proc/uptime").unwrap()).unwrap();
+    assert!(uptime.trim_end().parse::<f64>().is_ok());
+    let interrupts = String::from_utf8(vfs.read_file("/proc/interrupts").unwrap()).unwrap();
+    assert!(interrupts.lines().any(|line| line.trim_start().starts_with("32:") && line.ends_with("timer")));
+
+    assert_eq!(vfs.write_file("/proc/4242/cmdline", b"").err(), Some(FileSystemError::ReadOnly));
+    assert_eq!(vfs.create_directory("/proc/new", 0o755).err(), Some(FileSystemError::ReadOnly));
+
+    // A reaped process disappears, along with its files
+    process::remove_process(4242);
+    assert_eq!(vfs.read_file("/proc/self/status").err(), Some(FileSystemError::NotFound));
+    assert!(!vfs.read_directory("/proc").unwrap().iter().any(|entry| entry.name == "4242"));
+    vfs.set_current_process(KERNEL_PROCESS);
+}
@@ -0,0 +1,487 @@
+use crate::boot::grub;
+use crate::drivers::gpu::{FramebufferInfo, Gpu, LinearFramebuffer, PixelFormat, Rect};
+use crate::drivers::keyboard::{KeyEvent, Keycode};
+use crate::gui::components::textbox::{TextBox, TextBoxStyle};
+use crate::gui::context::Context;
+use crate::gui::utils::color::{Color, BLACK, WHITE};
+use crate::gui::utils::font::{self, BitmapFace, Font, FontError, TrueTypeFace};
+use crate::gui::utils::image::{Bitmap, ImageError};
+use crate::gui::utils::inflate::{self, InflateError};
+use crate::gui::utils::png;
+use crate::gui::window::{Align, Rect as GuiRect};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+// A framebuffer over `memory`, which stands in for video memory
+fn framebuffer(memory: &mut [u8], width: usize, height: usize, format: PixelFormat) -> LinearFramebuffer {
+    let pitch = width * format.bytes_per_pixel;
+    assert!(memory.len() >= pitch * height);
+    let info = FramebufferInfo {
+        address: memory.as_mut_ptr() as usize,
+        width,
+        height,
+        pitch,
+        format,
+    };
+    unsafe { LinearFramebuffer::new(info) }
+}
+
+#[test]
+fn test_flush_converts_pixel_format() {
+    let mut memory = vec![0u8; 4 * 2 * 2];
+    let mut gpu = framebuffer(&mut memory, 4, 2, PixelFormat::RGB565);
+    gpu.fill_rect(Rect::new(1, 0, 2, 1), 0xFF8000);
+
+    // Nothing reaches video memory until the back buffer is flushed
+    assert!(memory.iter().all(|byte| *byte == 0));
+    gpu.flush(Rect::new(0, 0, 2, 1));
+    assert_eq!(&memory[0..4], &[0x00, 0x00, 0x00, 0xFC]);
+    assert_eq!(&memory[4..6], &[0x00, 0x00]);
+
+    gpu.flush_all();
+    assert_eq!(&memory[4..6], &[0x00, 0xFC]);
+
+    let mut memory = vec![0u8; 3 * 2];
+    let mut gpu = framebuffer(&mut memory, 2, 1, PixelFormat::RGB888);
+    gpu.blit(0, 0, 2, &[0x123456, 0xABCDEF]);
+    gpu.flush_all();
+    assert_eq!(memory, [0x56, 0x34, 0x12, 0xEF, 0xCD, 0xAB]);
+}
+
+#[test]
+fn test_copy_rect_scrolls() {
+    let mut memory = vec![0u8; 4 * 3 * 4];
+    let mut gpu = framebuffer(&mut memory, 3, 4, PixelFormat::XRGB8888);
+    let rows: Vec<u32> = (0..12).collect();
+    gpu.blit(0, 0, 3, &rows);
+
+    // Scroll up by a row, then down by two, which overlap the source
+    gpu.copy_rect(Rect::new(0, 1, 3, 3), 0, 0);
+    assert_eq!(&gpu.framebuffer()[..9], &[3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    gpu.copy_rect(Rect::new(0, 0, 3, 2), 0, 2);
+    assert_eq!(&gpu.framebuffer()[6..], &[3, 4, 5, 6, 7, 8]);
+
+    // Blits are clipped to the screen
+    gpu.blit(2, 3, 2, &[100, 101, 102, 103]);
+    assert_eq!(gpu.framebuffer()[11], 100);
+}
+
+#[test]
+fn test_multiboot2_framebuffer_tag() {
+    let mut tag = vec![0u8; 38];
+    tag[0..4].copy_from_slice(&8u32.to_le_bytes());
+    tag[4..8].copy_from_slice(&38u32.to_le_bytes());
+    tag[8..16].copy_from_slice(&0xFD00_0000u64.to_le_bytes());
+    tag[16..20].copy_from_slice(&4096u32.to_le_bytes());
+    tag[20..24].copy_from_slice(&1024u32.to_le_bytes());
+    tag[24..28].copy_from_slice(&768u32.to_le_bytes());
+    tag[28] = 32;
+    tag[29] = 1;
+    tag[32..38].copy_from_slice(&[16, 8, 8, 8, 0, 8]);
+
+    let info = FramebufferInfo::from_multiboot2(&tag).unwrap();
+    assert_eq!(info.address, 0xFD00_0000);
+    assert_eq!((info.width, info.height, info.pitch), (1024, 768, 4096));
+    assert_eq!(info.format, PixelFormat::XRGB8888);
+
+    // Indexed color framebuffers are not supported
+    tag[29] = 0;
+    assert!(FramebufferInfo::from_multiboot2(&tag).is_none());
+}
+
+#[test]
+fn test_multiboot2_boot_information() {
+    // A command line tag, then the framebuffer tag padded to 8 bytes, then the end tag
+    let mut info = vec![0u8; 8];
+    info.extend_from_slice(&1u32.to_le_bytes());
+    info.extend_from_slice(&13u32.to_le_bytes());
+    info.extend_from_slice(b"quiet\0\0\0");
+    let mut tag = vec![0u8; 40];
+    tag[0..4].copy_from_slice(&8u32.to_le_bytes());
+    tag[4..8].copy_from_slice(&38u32.to_le_bytes());
+    tag[8..16].copy_from_slice(&0xE000_0000u64.to_le_bytes());
+    tag[16..20].copy_from_slice(&2560u32.to_le_bytes());
+    tag[20..24].copy_from_slice(&640u32.to_le_bytes());
+    tag[24..28].copy_from_slice(&480u32.to_le_bytes());
+    tag[28] = 32;
+    tag[29] = 1;
+    tag[32..38].copy_from_slice(&[16, 8, 8, 8, 0, 8]);
+    info.extend_from_slice(&tag);
+    info.extend_from_slice(&[0, 0, 0, 0, 8, 0, 0, 0]);
+    let total = info.len() as u32;
+    info[0..4].copy_from_slice(&total.to_le_bytes());
+
+    let tags: Vec<&[u8]> = grub::multiboot2_tags(&info).collect();
+    assert_eq!(tags.iter().map(|tag| tag.len()).collect::<Vec<_>>(), [13, 38]);
+    let framebuffer = grub::multiboot2_tags(&info).find_map(FramebufferInfo::from_multiboot2).unwrap();
+    assert_eq!((framebuffer.address, framebuffer.width, framebuffer.height), (0xE000_0000, 640, 480));
+
+    // Tags running past the total size are ignored
+    info[0..4].copy_from_slice(&40u32.to_le_bytes());
+    assert_eq!(grub::multiboot2_tags(&info).count(), 1);
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/golden").join(format!("{}.png", name))
+}
+
+fn test_image(name: &str) -> Bitmap {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/images").join(name);
+    Bitmap::decode(&std::fs::read(path).unwrap()).unwrap()
+}
+
+// Compare a canvas against its golden image. Set UPDATE_GOLDEN=1 to rewrite the
+// golden images after an intended change in rendering.
+fn assert_golden(name: &str, context: &Context) {
+    let path = golden_path(name);
+    let actual = context.to_rgba();
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::write(&path, png::encode(context.width(), context.height(), &actual)).unwrap();
+        return;
+    }
+
+    let golden = png::decode(&std::fs::read(&path).unwrap()).unwrap();
+    let (width, height, expected) = (golden.width, golden.height, golden.to_rgba());
+    assert_eq!((width, height), (context.width(), context.height()), "{}: size differs", name);
+    if let Some(index) = (0..actual.len() / 4).find(|i| actual[i * 4..i * 4 + 4] != expected[i * 4..i * 4 + 4]) {
+        let failed = path.with_extension("actual.png");
+        std::fs::write(&failed, png::encode(width, height, &actual)).unwrap();
+        panic!(
+            "{}: pixel ({}, {}) is {:?}, expected {:?}; rendering saved to {}",
+            name,
+            index as u32 % width,
+            index as u32 / width,
+            &actual[index * 4..index * 4 + 4],
+            &expected[index * 4..index * 4 + 4],
+            failed.display()
+        );
+    }
+}
+
+#[test]
+fn test_render_shapes() {
+    let mut context = Context::new(64, 48);
+    context.clear(WHITE);
+    context.fill_rect(GuiRect::new(4, 4, 20, 12), Color::new(255, 0, 0));
+    context.stroke_rect(GuiRect::new(28, 4, 32, 16), 2, Color::new(0, 0, 255));
+    context.fill_rect(GuiRect::new(12, 8, 20, 12), Color::rgba(0, 255, 0, 128));
+    context.draw_line(2, 44, 60, 24, BLACK);
+    context.fill_circle(16, 32, 10, Color::new(255, 128, 0));
+    context.stroke_circle(44, 34, 9, 2, Color::new(0, 0, 128));
+    context.fill_rounded_rect(GuiRect::new(30, 24, 14, 10), 4, Color::rgba(128, 0, 128, 160));
+    assert_golden("shapes", &context);
+}
+
+#[test]
+fn test_render_clipping_and_images() {
+    let mut context = Context::new(32, 32);
+    context.push_clip(GuiRect::new(8, 8, 16, 16));
+    context.fill_circle(16, 16, 12, Color::rgba(0, 0, 255, 200));
+    context.push_clip(GuiRect::new(0, 0, 12, 32));
+    assert_eq!(context.clip_rect().width, 4);
+    context.fill_rect(GuiRect::new(0, 0, 32, 32), Color::new(255, 0, 0));
+    context.pop_clip();
+    context.pop_clip();
+
+    context.stroke_rounded_rect(GuiRect::new(2, 2, 28, 28), 8, 1, BLACK);
+    let image = Bitmap::new(
+        2,
+        2,
+        vec![Color::new(255, 0, 0), Color::new(0, 255, 0), Color::new(0, 0, 255), WHITE.with_alpha(128)],
+    );
+    context.draw_image_scaled(&image, GuiRect::new(24, 24, 8, 8));
+    context.draw_image(&image, 0, 0);
+    assert_golden("clipping", &context);
+}
+
+#[test]
+fn test_alpha_blending() {
+    let mut context = Context::new(1, 1);
+    context.fill_rect(GuiRect::new(0, 0, 1, 1), Color::rgba(255, 0, 0, 128));
+    // Over a transparent pixel the color is kept and only the alpha is partial
+    assert_eq!(context.pixel(0, 0), Some(Color::rgba(255, 0, 0, 128)));
+    context.fill_rect(GuiRect::new(0, 0, 1, 1), Color::rgba(0, 0, 255, 128));
+    assert_eq!(context.pixel(0, 0), Some(Color::rgba(85, 0, 170, 192)));
+    assert_eq!(context.pixel(1, 0), None);
+}
+
+#[test]
+fn test_builtin_console_font() {
+    let face = font::console_face();
+    assert_eq!((face.width(), face.height()), (8, 16));
+    assert!(face.glyph_index('A').is_some());
+    assert!(face.glyph_index('\u{2500}').is_some());
+    assert!(face.glyph_index('\u{E9}').is_some());
+
+    let font = Font::bitmap(face.clone(), 2);
+    assert_eq!((font.size(), font.line_height()), (32, 32));
+    let glyph = font.glyph('H');
+    assert_eq!((glyph.width, glyph.height, glyph.advance), (16, 32, 16.0));
+    assert!(glyph.coverage.iter().all(|value| *value == 0 || *value == 255));
+    // Characters the font lacks are drawn as the replacement character
+    assert_eq!(font.glyph('\u{4E00}'), font.glyph('\u{FFFD}'));
+}
+
+#[test]
+fn test_psf1_font() {
+    // 256 glyphs of 2 rows each, followed by a Unicode table
+    let mut data = vec![0x36, 0x04, 0x02, 2];
+    data.extend((0..256).flat_map(|glyph| [glyph as u8, 0x81]));
+    for glyph in 0..256u16 {
+        if glyph == b'A' as u16 {
+            data.extend_from_slice(&0x391u16.to_le_bytes());
+        }
+        data.extend_from_slice(&glyph.to_le_bytes());
+        data.extend_from_slice(&0xFFFFu16.to_le_bytes());
+    }
+
+    let face = BitmapFace::parse(&data).unwrap();
+    assert_eq!((face.width(), face.height()), (8, 2));
+    // Greek capital alpha shares the glyph of A
+    assert_eq!(face.glyph_index('\u{391}'), Some(b'A' as usize));
+    assert!(face.pixel(b'A' as usize, 1, 0));
+    assert!(!face.pixel(b'A' as usize, 0, 0));
+    assert!(face.pixel(b'A' as usize, 7, 1));
+
+    assert_eq!(BitmapFace::parse(&data[..100]).err(), Some(FontError::Truncated));
+    assert_eq!(BitmapFace::parse(b"not a font").err(), Some(FontError::BadMagic));
+}
+
+#[test]
+fn test_truetype_metrics() {
+    let font = Font::truetype(font::sans_face().unwrap(), 16);
+    assert!(font.ascent() > 0 && font.descent() > 0);
+    assert_eq!(font.line_height() as i32, font.ascent() + font.descent());
+
+    // Kerning pulls the V under the A
+    let separate = font.glyph('A').advance + font.glyph('V').advance;
+    assert!(font.kerning('A', 'V') < 0.0);
+    assert!((font.measure("AV").0 as f32) < separate);
+
+    let (width, height) = font.measure("Hello\nWorld, again");
+    assert_eq!(width, font.measure("World, again").0);
+    assert_eq!(height, 2 * font.line_height());
+    assert_eq!(font.measure("").0, 0);
+
+    // Outlines are antialiased, and glyphs without outlines still advance
+    assert!(font.glyph('O').coverage.iter().any(|value| *value > 0 && *value < 255));
+    assert!(font.glyph(' ').coverage.is_empty() && font.glyph(' ').advance > 0.0);
+
+    assert_eq!(
+        TrueTypeFace::parse(b"OTTO\0\0\0\0".to_vec()).err(),
+        Some(FontError::Unsupported("CFF outlines"))
+    );
+}
+
+// The smallest rectangle around every pixel with any ink, as (left, top, right, bottom)
+fn ink_bounds(context: &Context) -> Option<(i32, i32, i32, i32)> {
+    let mut bounds: Option<(i32, i32, i32, i32)> = None;
+    for y in 0..context.height() as i32 {
+        for x in 0..context.width() as i32 {
+            if context.pixel(x, y).unwrap().a > 0 {
+                let (left, top, right, bottom) = bounds.unwrap_or((x, y, x, y));
+                bounds = Some((left.min(x), top.min(y), right.max(x), bottom.max(y)));
+            }
+        }
+    }
+    bounds
+}
+
+#[test]
+fn test_draw_text_alignment() {
+    let mut context = Context::new(80, 24);
+    let font = context.get_font(16);
+    let (width, height) = context.get_text_size("Text", &font);
+    assert_eq!(height, font.line_height());
+
+    let rect = GuiRect::new(10, 2, 60, 20);
+    context.draw_text("Text", &font, rect, BLACK, Align::Left);
+    let (left, top, right, _) = ink_bounds(&context).unwrap();
+    assert!((10..=12).contains(&left));
+    assert!(right < 10 + width as i32);
+    assert!(top >= 2);
+
+    let mut context = Context::new(80, 24);
+    context.draw_text("Text", &font, rect, BLACK, Align::Right);
+    let (_, _, right, _) = ink_bounds(&context).unwrap();
+    assert!((67..70).contains(&right));
+
+    let mut context = Context::new(80, 24);
+    context.draw_text("Text", &font, rect, BLACK, Align::Center);
+    let (left, _, right, _) = ink_bounds(&context).unwrap();
+    assert!(((left + right) / 2 - 40).abs() <= 2);
+}
+
+#[test]
+fn test_draw_text_is_clipped() {
+    let mut context = Context::new(40, 40);
+    let font = Font::bitmap(font::console_face(), 1);
+    // Three lines of text into a rect with room for one and a half
+    context.draw_text("MMMMMMMM\nMMMMMMMM\nMMMMMMMM", &font, GuiRect::new(4, 4, 20, 24), BLACK, Align::Left);
+    let (left, top, right, bottom) = ink_bounds(&context).unwrap();
+    assert!(left >= 4 && top >= 4);
+    assert_eq!((right, bottom), (23, 27));
+    // The clip rect is restored afterwards
+    assert_eq!(context.clip_rect().width, 40);
+}
+
+// The pattern the test images in src/tests/images were generated from
+fn gradient(x: u32, y: u32) -> Color {
+    Color::rgba((x * 19) as u8, (y * 36) as u8, (x * y * 7) as u8, 255 - x as u8 * 10)
+}
+
+fn assert_gradient(image: &Bitmap, alpha: bool) {
+    assert_eq!((image.width, image.height), (13, 7));
+    for y in 0..7 {
+        for x in 0..13 {
+            let expected = if alpha { gradient(x, y) } else { gradient(x, y).with_alpha(255) };
+            assert_eq!(image.pixel(x, y), expected, "pixel ({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_inflate() {
+    let text = b"abcabcabcabc hello hello";
+    // Fixed Huffman codes, then the same text stored
+    let fixed = [
+        0x78, 0x01, 0x4b, 0x4c, 0x4a, 0x4e, 0x84, 0x21, 0x85, 0x8c, 0xd4, 0x9c, 0x9c, 0x7c, 0x08, 0x09, 0x00, 0x70,
+        0x12, 0x09, 0x01,
+    ];
+    assert_eq!(inflate::zlib_decompress(&fixed, 1024).unwrap(), text);
+    let stored = png::encode(2, 3, &text[..24]);
+    assert_eq!(png::decode(&stored).unwrap().to_rgba(), &text[..24]);
+
+    assert_eq!(inflate::zlib_decompress(&fixed, 10), Err(InflateError::TooLarge));
+    assert_eq!(inflate::zlib_decompress(&fixed[..12], 1024), Err(InflateError::Truncated));
+    let mut corrupt = fixed;
+    corrupt[20] ^= 1;
+    assert_eq!(inflate::zlib_decompress(&corrupt, 1024), Err(InflateError::Checksum));
+}
+
+#[test]
+fn test_decode_png() {
+    // RGBA with every row filter, compressed with dynamic Huffman codes
+    assert_gradient(&test_image("gradient.png"), true);
+    // 16-bit RGB, interlaced
+    assert_gradient(&test_image("interlaced.png"), false);
+
+    // 4-bit palette, where the tRNS chunk makes the first entries translucent
+    let image = test_image("palette.png");
+    assert_eq!((image.width, image.height), (5, 3));
+    assert_eq!(image.pixel(0, 0), Color::rgba(0, 0, 0, 0));
+    assert_eq!(image.pixel(1, 0), Color::rgba(255, 0, 0, 128));
+    assert_eq!(image.pixel(3, 2), Color::new(255, 255, 255));
+
+    let mut data = png::encode(1, 1, &[1, 2, 3, 4]);
+    let last = data.len() - 1;
+    data[last] ^= 0xFF;
+    assert_eq!(png::decode(&data), Err(ImageError::Checksum));
+    assert_eq!(Bitmap::decode(b"GIF89a"), Err(ImageError::UnknownFormat));
+}
+
+#[test]
+fn test_decode_bmp() {
+    assert_gradient(&test_image("gradient24.bmp"), false);
+    // Top-down, with an alpha mask
+    assert_gradient(&test_image("gradient32.bmp"), true);
+
+    // RLE8 with runs, a literal and a jump; skipped pixels are transparent
+    let image = test_image("palette-rle8.bmp");
+    let (red, white, yellow) = (Color::new(255, 0, 0), Color::new(255, 255, 255), Color::new(255, 255, 0));
+    assert_eq!(image.pixel(0, 3), red);
+    assert_eq!(image.pixel(5, 3), red);
+    let row: Vec<Color> = (0..6).map(|x| image.pixel(x, 2)).collect();
+    assert_eq!(row, [Color::new(0, 255, 0), Color::new(0, 0, 255), yellow, white, white, white]);
+    assert_eq!((image.pixel(1, 1), image.pixel(2, 1)), (yellow, Color::rgba(0, 0, 0, 0)));
+    assert_eq!((image.pixel(3, 0), image.pixel(4, 0)), (Color::rgba(0, 0, 0, 0), red));
+}
+
+#[test]
+fn test_decode_ppm() {
+    let text = b"P3\n# a comment\n2 1\n15\n15 0 0  0 15 # red, then green\n0\n";
+    let image = Bitmap::decode(text).unwrap();
+    assert_eq!(image.pixels, [Color::new(255, 0, 0), Color::new(0, 255, 0)]);
+
+    let mut binary = b"P6 1 2 65535\n".to_vec();
+    binary.extend_from_slice(&[0xFF, 0xFF, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    let image = Bitmap::decode(&binary).unwrap();
+    assert_eq!(image.pixels, [Color::new(255, 127, 0), Color::new(0, 0, 0)]);
+
+    let image = Bitmap::decode(b"P1 3 1 010").unwrap();
+    assert_eq!(image.pixels, [WHITE, BLACK, WHITE]);
+    assert_eq!(Bitmap::decode(b"P5 2 2 255\n\x00"), Err(ImageError::Truncated));
+}
+
+#[test]
+fn test_scale_bitmap() {
+    let image = Bitmap::from_rgba(2, 2, &[255, 0, 0, 255, 0, 0, 255, 255, 0, 0, 0, 0, 0, 255, 0, 255]);
+    // Shrinking averages, ignoring the color of the transparent pixel
+    let small = image.scale(1, 1);
+    assert_eq!(small.pixel(0, 0), Color::rgba(85, 85, 85, 191));
+
+    // Enlarging keeps the corners and blends in between
+    let large = image.scale(4, 4);
+    assert_eq!(large.pixel(0, 0), Color::new(255, 0, 0));
+    assert_eq!(large.pixel(3, 0), Color::new(0, 0, 255));
+    assert_eq!(large.pixel(1, 0), Color::new(191, 0, 64));
+    assert_eq!(image.scale(2, 2), image);
+    assert_eq!(large.to_argb()[0], 0xFFFF_0000);
+}
+
+// Whether any pixel of `rect` has `color`
+fn has_color(context: &Context, rect: GuiRect, color: Color) -> bool {
+    (rect.y..rect.y + rect.height as i32)
+        .any(|y| (rect.x..rect.x + rect.width as i32).any(|x| context.pixel(x, y) == Some(color)))
+}
+
+#[test]
+fn test_render_textbox() {
+    let style = TextBoxStyle::default();
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let recorded = changes.clone();
+    let mut textbox = TextBox::new("name")
+        .placeholder("Name")
+        .on_change(move |text| recorded.borrow_mut().push(text.to_string()));
+    let mut context = Context::new(96, 32);
+    let (width, height) = textbox.preferred_size(&mut context);
+    assert!(width > 2 * (style.border_width + style.padding));
+    assert!(height <= 32);
+
+    // Unfocused and empty: the border, the background and the placeholder, no cursor
+    let rect = GuiRect::new(0, 0, 96, 32);
+    let inner = GuiRect::new(5, 5, 86, 22);
+    textbox.draw(&mut context, rect);
+    assert_eq!(context.pixel(0, 0), Some(style.border_color));
+    assert_eq!(context.pixel(95, 31), Some(style.border_color));
+    assert_eq!(context.pixel(90, 16), Some(style.background_color));
+    assert!(has_color(&context, inner, style.placeholder_color));
+    assert!(!has_color(&context, inner, style.text_color));
+    assert!(!textbox.handle_event(&KeyEvent::new(Keycode::Char('x'), false)));
+
+    // Typing while focused edits the text, moving the cursor past each character
+    textbox.set_focus(true);
+    for keycode in [Keycode::Char('H'), Keycode::Char('j'), Keycode::Backspace, Keycode::Char('i')] {
+        assert!(textbox.handle_event(&KeyEvent::new(keycode, false)));
+    }
+    assert!(!textbox.handle_event(&KeyEvent::new(Keycode::Char('!'), true)));
+    assert_eq!(textbox.text(), "Hi");
+    assert_eq!(*changes.borrow(), ["H", "Hj", "H", "Hi"]);
+
+    let mut context = Context::new(96, 32);
+    textbox.draw(&mut context, rect);
+    assert_eq!(context.pixel(90, 16), Some(style.focused_background_color));
+    assert!(!has_color(&context, inner, style.placeholder_color));
+    let font = context.get_font(style.font_size);
+    let (text_width, _) = context.get_text_size("Hi", &font);
+    let cursor = GuiRect::new(inner.x + text_width as i32, inner.y + inner.height as i32 - 2, 1, 2);
+    assert!(has_color(&context, cursor, style.text_color));
+
+    // Moving the cursor home puts it under the first character
+    assert!(textbox.handle_event(&KeyEvent::new(Keycode::Home, false)));
+    let mut context = Context::new(96, 32);
+    textbox.draw(&mut context, rect);
+    assert!(!has_color(&context, cursor, style.text_color));
+    assert!(has_color(&context, GuiRect::new(inner.x, cursor.y, 1, 2), style.text_color));
+}
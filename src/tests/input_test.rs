@@ -0,0 +1,139 @@
+use crate::drivers::keyboard::{KeyEvent, Keycode};
+use crate::drivers::mouse::{decode_packet, handle_hid_report, MouseState, MouseType};
+use crate::gui::event::{EventType, MouseButton, MouseEvent};
+use crate::kernel::input::{self, EventFilter, InputEvent, InputEventKind, InputManager, SourceKind};
+use core::time::Duration;
+
+fn fixed_clock() -> Duration {
+    Duration::from_millis(1500)
+}
+
+fn mouse_move(x: i32, y: i32) -> InputEventKind {
+    InputEventKind::Mouse(MouseEvent {
+        event_type: EventType::MouseMove,
+        x,
+        y,
+        button: None,
+        wheel: 0,
+    })
+}
+
+#[test]
+fn test_input_filtering() {
+    let mut input = InputManager::new(fixed_clock);
+    let keyboard = input.register_source("keyboard", SourceKind::Keyboard);
+    let mouse = input.register_source("mouse", SourceKind::Mouse);
+
+    let all = input.subscribe(EventFilter::all());
+    let keys = input.subscribe(EventFilter::all().keys_only());
+    let mouse_only = input.subscribe(EventFilter::all().source(mouse));
+
+    input.report(keyboard, InputEventKind::Key(KeyEvent::new(Keycode::Char('a'), false)));
+    input.report(mouse, mouse_move(10, 20));
+
+    // Every subscriber gets its own copy, stamped by the clock
+    let first = input.read(all).unwrap();
+    assert_eq!(first.source, keyboard);
+    assert_eq!(first.timestamp, Duration::from_millis(1500));
+    assert_eq!(input.read(all).unwrap().source, mouse);
+    assert!(input.read(all).is_none());
+
+    assert_eq!(input.read(keys).unwrap().source, keyboard);
+    assert!(input.read(keys).is_none());
+
+    assert_eq!(input.read(mouse_only).unwrap().kind, mouse_move(10, 20));
+    assert!(input.read(mouse_only).is_none());
+}
+
+#[test]
+fn test_input_record_and_replay() {
+    let mut input = InputManager::new(fixed_clock);
+    let mouse = input.register_source("mouse", SourceKind::Mouse);
+
+    input.start_recording();
+    input.report(mouse, mouse_move(1, 1));
+    input.report(mouse, mouse_move(2, 3));
+    let recording = input.stop_recording();
+    assert_eq!(recording.events.len(), 2);
+
+    // Replaying delivers exactly the recorded events, timestamps included
+    let subscriber = input.subscribe(EventFilter::all());
+    input.replay(&recording);
+    assert_eq!(input.read(subscriber), Some(recording.events[0]));
+    assert_eq!(input.read(subscriber), Some(recording.events[1]));
+    assert!(input.read(subscriber).is_none());
+}
+
+#[test]
+fn test_event_records() {
+    // Key records carry Linux key codes: KEY_A for 'a', KEY_UP for the extended up arrow
+    for (key, code) in [(0x1E, 30u16), (0xC8, 103), (0xDD, 127), (0x7F, 240)] {
+        let mut event = KeyEvent::new(Keycode::Unknown(key), false);
+        event.key = key;
+        let records = input::encode_event(&InputEvent {
+            timestamp: Duration::from_micros(2_000_005),
+            source: 0,
+            kind: InputEventKind::Key(event),
+        });
+        assert_eq!(records.len(), 2);
+        assert_eq!(&records[0][..8], &2u64.to_le_bytes());
+        assert_eq!(&records[0][8..16], &5u64.to_le_bytes());
+        assert_eq!(&records[0][16..24], &[1, 0, code as u8, (code >> 8) as u8, 1, 0, 0, 0]);
+        assert_eq!(&records[1][16..24], &[0; 8]);
+    }
+}
+
+#[test]
+fn test_mouse_packets() {
+    // Left button, 5 right and 3 up, which is 3 down in PS/2 orientation
+    assert_eq!(decode_packet(&[0x09, 5, 3], MouseType::Standard), Some((5, -3, 0x01, 0)));
+    // Sign bits extend the movement to 9 bits
+    assert_eq!(decode_packet(&[0x38, 0xFE, 0xF0], MouseType::Standard), Some((-2, 16, 0, 0)));
+    // Overflowed packets and bytes without the always-one bit are dropped
+    assert_eq!(decode_packet(&[0x48, 0xFF, 0], MouseType::Standard), None);
+    assert_eq!(decode_packet(&[0x01, 1, 1], MouseType::Standard), None);
+
+    // IntelliMouse: the fourth byte is the wheel, negative when turned away from the
+    // user, which is positive once decoded
+    assert_eq!(decode_packet(&[0x08, 0, 0, 0xFF], MouseType::Wheel), Some((0, 0, 0, 1)));
+    assert_eq!(decode_packet(&[0x08, 0, 0, 0x02], MouseType::Wheel), Some((0, 0, 0, -2)));
+    // Five buttons: a 4-bit wheel and buttons 4 and 5 share the fourth byte
+    assert_eq!(decode_packet(&[0x0C, 0, 0, 0x3F], MouseType::FiveButton), Some((0, 0, 0x1C, 1)));
+    assert_eq!(decode_packet(&[0x08, 0, 0, 0x11], MouseType::FiveButton), Some((0, 0, 0x08, -1)));
+
+    // HID reports the wheel turned away from the user as positive already
+    let mut state = MouseState::new(800, 600);
+    let wheel = |events: Vec<MouseEvent>| events.iter().map(|event| event.wheel).collect::<Vec<_>>();
+    assert_eq!(wheel(handle_hid_report(&mut state, &[0, 0, 0, 0x01])), [1]);
+    assert_eq!(wheel(handle_hid_report(&mut state, &[0, 0, 0, 0xFE])), [-2]);
+}
+
+#[test]
+fn test_mouse_state() {
+    let mut state = MouseState::new(800, 600);
+    assert_eq!(state.position(), (400, 300));
+
+    // The cursor stops at the edges, and a move that goes nowhere is not reported
+    let events = state.apply(1000, -1000, 0, 0);
+    assert_eq!(events.iter().map(|event| (event.x, event.y)).collect::<Vec<_>>(), [(799, 0)]);
+    assert!(state.apply(5, -5, 0, 0).is_empty());
+
+    // Button changes come after the move, and the wheel last
+    let events = state.apply(-9, 0, 0x01, 2);
+    let kinds: Vec<_> = events.iter().map(|event| (event.event_type, event.button, event.wheel)).collect();
+    assert_eq!(
+        kinds,
+        [
+            (EventType::MouseMove, None, 0),
+            (EventType::MouseDown, Some(MouseButton::Left), 0),
+            (EventType::MouseWheel, None, 2)
+        ]
+    );
+    assert_eq!(state.apply(0, 0, 0, 0)[0].event_type, EventType::MouseUp);
+
+    // A smaller screen pulls the cursor in
+    state.set_bounds(640, 480);
+    assert_eq!(state.position(), (639, 0));
+    state.apply(0, 1000, 0, 0);
+    assert_eq!(state.position(), (639, 479));
+}
@@ -1,4 +1,4 @@
-use crate::drivers::keyboard::{self, Keycode};
+use crate::drivers::keyboard::{self, Keycode, KeyboardState, ScancodeSet};
 
 fn test_keyboard() {
     let mut input_buffer = [0u8; 8];
@@ -36,3 +36,74 @@ fn test_keyboard() {
 fn test_keyboard_driver() {
     test_keyboard();
 }
+
+#[test]
+fn test_scancode_decoding() {
+    let mut state = KeyboardState::new(ScancodeSet::Set2, &keyboard::LAYOUT_US);
+
+    // 'a' press and release
+    assert_eq!(state.process(0x1C).0.map(|e| (e.keycode, e.released)), Some((Keycode::Char('a'), false)));
+    assert_eq!(state.process(0xF0).0, None);
+    assert_eq!(state.process(0x1C).0.map(|e| (e.keycode, e.released)), Some((Keycode::Char('a'), true)));
+
+    // Shift + '1' produces '!'
+    state.process(0x12);
+    assert_eq!(state.process(0x16).0.map(|e| e.keycode), Some(Keycode::Char('!')));
+    state.process(0xF0);
+    state.process(0x12);
+
+    // Extended arrow key
+    state.process(0xE0);
+    assert_eq!(state.process(0x75).0.map(|e| e.keycode), Some(Keycode::Up));
+
+    // Caps lock toggles the LEDs and upper-cases letters
+    assert!(state.process(0x58).1);
+    assert_eq!(state.process(0x1C).0.map(|e| (e.keycode, e.repeat)), Some((Keycode::Char('A'), false)));
+    assert_eq!(state.process(0x1C).0.map(|e| e.repeat), Some(true));
+}
+
+#[test]
+fn test_keyboard_layouts() {
+    // Set 1 make code 0x15 is 'y' on US and 'z' on DE
+    let mut us = KeyboardState::new(ScancodeSet::Set1, &keyboard::LAYOUT_US);
+    let mut de = KeyboardState::new(ScancodeSet::Set1, keyboard::layout_by_name("de").unwrap());
+    assert_eq!(us.process(0x15).0.map(|e| e.keycode), Some(Keycode::Char('y')));
+    assert_eq!(de.process(0x15).0.map(|e| e.keycode), Some(Keycode::Char('z')));
+
+    // AltGr + 'q' is '@' on DE
+    de.process(0xE0);
+    de.process(0x38);
+    assert_eq!(de.process(0x10).0.map(|e| e.keycode), Some(Keycode::Char('@')));
+    // AltGr + 'm' is 'µ'
+    assert_eq!(de.process(0x32).0.map(|e| e.keycode), Some(Keycode::Char('µ')));
+
+    // AltGr gives every vowel its acute accent on UK
+    let mut uk = KeyboardState::new(ScancodeSet::Set1, &keyboard::LAYOUT_UK);
+    uk.process(0xE0);
+    uk.process(0x38);
+    let accented: Vec<_> = [0x1E, 0x12, 0x17, 0x18, 0x16]
+        .into_iter()
+        .filter_map(|code| uk.process(code).0)
+        .map(|e| e.keycode)
+        .collect();
+    assert_eq!(accented, "áéíóú".chars().map(Keycode::Char).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_pause_key() {
+    // Pause sends its press and release together, so pressing it again is not a repeat
+    for (set, bytes) in [
+        (ScancodeSet::Set1, &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5][..]),
+        (ScancodeSet::Set2, &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77][..]),
+    ] {
+        let mut state = KeyboardState::new(set, &keyboard::LAYOUT_US);
+        for _ in 0..2 {
+            let events: Vec<_> = bytes
+                .iter()
+                .filter_map(|&byte| state.process(byte).0)
+                .map(|e| (e.keycode, e.released, e.repeat))
+                .collect();
+            assert_eq!(events, [(Keycode::Pause, false, false), (Keycode::Pause, true, false)]);
+        }
+    }
+}
@@ -1,4 +1,13 @@
-// Import necessary modules
+use crate::net::hosted::PipeInterface;
+use crate::net::interface::{self, NetError, NetInterface, ETHERNET_HEADER_LEN, ETHERNET_MTU};
+use crate::net::ip::{self, Ipv4Header, PROTOCOL_TCP, PROTOCOL_UDP};
+use crate::net::loopback::LoopbackInterface;
+use crate::net::socket_table::{self, Protocol, SocketEntry};
+use crate::net::tcp::{TcpError, TcpListener, TcpState, TcpStream};
+use crate::net::udp::UdpSocket;
+use spin::Mutex;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
 
 #[test]
 fn test_network_functionality() {
@@ -14,3 +23,337 @@ fn test_network_functionality() {
     // Tear down network configuration after test
     // e.g. close server and client connections
 }
+
+const MAC_A: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x01];
+const MAC_B: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x02];
+
+// A broadcast frame from `source` carrying `payload`
+fn frame(source: [u8; 6], payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0xFF; 6];
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn test_loopback_delivery() {
+    let lo = LoopbackInterface::new();
+    let sent = frame([0; 6], b"ping");
+    lo.send(&sent).unwrap();
+    assert_eq!(lo.receive(), Some(sent.clone()));
+    assert!(lo.receive().is_none());
+
+    // With a callback installed, frames go to it on the next poll instead
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = seen.clone();
+    lo.set_receive_callback(Some(Arc::new(move |frame: &[u8]| sink.lock().push(frame.to_vec()))));
+    lo.send(&sent).unwrap();
+    assert!(seen.lock().is_empty());
+    lo.poll();
+    assert_eq!(*seen.lock(), vec![sent]);
+
+    let stats = lo.stats();
+    assert_eq!((stats.tx_packets, stats.rx_packets), (2, 2));
+}
+
+#[test]
+fn test_pipe_exchange() {
+    let (a, b) = PipeInterface::pair(MAC_A, MAC_B);
+    assert_eq!(a.mac(), MAC_A);
+
+    let request = frame(MAC_A, b"request");
+    a.send(&request).unwrap();
+    assert!(a.receive().is_none());
+    assert_eq!(b.receive(), Some(request.clone()));
+
+    // Answer from inside the callback, as the stack does
+    let responder = b.clone();
+    b.set_receive_callback(Some(Arc::new(move |_: &[u8]| {
+        responder.send(&frame(MAC_B, b"reply")).unwrap();
+    })));
+    a.send(&request).unwrap();
+    b.poll();
+    assert_eq!(a.receive(), Some(frame(MAC_B, b"reply")));
+
+    let stats = a.stats();
+    assert_eq!(stats.tx_packets, 2);
+    assert_eq!(stats.tx_bytes, 2 * request.len() as u64);
+    assert_eq!(stats.rx_packets, 1);
+    assert_eq!(b.stats().rx_packets, 2);
+
+    b.set_connected(false);
+    assert_eq!(b.send(&request), Err(NetError::LinkDown));
+    assert_eq!(b.stats().tx_errors, 1);
+}
+
+#[test]
+fn test_frame_size_limit() {
+    let (a, b) = PipeInterface::pair(MAC_A, MAC_B);
+    let largest = vec![0u8; ETHERNET_MTU + ETHERNET_HEADER_LEN];
+    a.send(&largest).unwrap();
+    assert_eq!(a.send(&[0u8; ETHERNET_MTU + ETHERNET_HEADER_LEN + 1]), Err(NetError::FrameTooLarge));
+    assert_eq!(b.receive().map(|frame| frame.len()), Some(largest.len()));
+    assert!(b.receive().is_none());
+}
+
+// Register a loopback interface for the IP tests, each under its own name and subnet
+// as the stack is shared between tests
+fn ip_loopback(name: &str, address: Ipv4Addr) -> Arc<LoopbackInterface> {
+    let lo = Arc::new(LoopbackInterface::new());
+    interface::add_interface(name, lo.clone()).unwrap();
+    ip::configure(name, address, 24).unwrap();
+    lo
+}
+
+// Deliver queued frames until the interface is quiet
+fn settle(lo: &LoopbackInterface) {
+    while lo.stats().rx_packets != lo.stats().tx_packets {
+        lo.poll();
+    }
+}
+
+// The socket table's entry for a pair of addresses; each test binds its own
+fn socket_entry(protocol: Protocol, local: SocketAddrV4, remote: Option<SocketAddrV4>) -> Option<SocketEntry> {
+    socket_table::sockets(protocol)
+        .into_iter()
+        .find(|entry| entry.local == local.into() && entry.remote == remote.map(Into::into))
+}
+
+#[test]
+fn test_udp_over_loopback() {
+    let address = Ipv4Addr::new(10, 1, 0, 1);
+    let lo = ip_loopback("lo-udp", address);
+    let server = UdpSocket::bind(SocketAddrV4::new(address, 5001)).unwrap();
+    let client = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    assert!(UdpSocket::bind(SocketAddrV4::new(address, 5001)).is_err());
+
+    client.send_to(b"hello", server.local_addr()).unwrap();
+    assert!(server.recv_from().is_none());
+    settle(&lo);
+    assert_eq!(socket_entry(Protocol::Udp, server.local_addr(), None).unwrap().receive_queue, 5);
+    let (data, from) = server.recv_from().unwrap();
+    assert_eq!(data, b"hello");
+    assert_eq!(socket_entry(Protocol::Udp, server.local_addr(), None).unwrap().receive_queue, 0);
+    assert_eq!(from, SocketAddrV4::new(address, client.local_addr().port()));
+
+    server.send_to(b"world", from).unwrap();
+    settle(&lo);
+    assert_eq!(client.recv_from().map(|(data, _)| data), Some(b"world".to_vec()));
+    assert!(client.recv_from().is_none());
+
+    // Closing the socket takes it out of the table
+    let local = server.local_addr();
+    drop(server);
+    assert!(socket_entry(Protocol::Udp, local, None).is_none());
+}
+
+#[test]
+fn test_tcp_over_loopback() {
+    let address = Ipv4Addr::new(10, 2, 0, 1);
+    let lo = ip_loopback("lo-tcp", address);
+    let listener = TcpListener::bind(SocketAddrV4::new(address, 8080)).unwrap();
+    assert!(matches!(listener.accept(), Err(TcpError::WouldBlock)));
+
+    let client = TcpStream::connect(listener.local_addr()).unwrap();
+    assert_eq!(client.state(), TcpState::SynSent);
+    assert!(matches!(client.write(b"early"), Err(TcpError::WouldBlock)));
+    settle(&lo);
+    assert_eq!(client.state(), TcpState::Established);
+    let server = listener.accept().unwrap();
+    assert_eq!(server.peer_addr(), client.local_addr());
+    assert_eq!(socket_entry(Protocol::Tcp, listener.local_addr(), None).unwrap().state, "LISTEN");
+    let entry = socket_entry(Protocol::Tcp, client.local_addr(), Some(client.peer_addr())).unwrap();
+    assert_eq!(entry.state, "ESTABLISHED");
+
+    // More than a segment's worth each way
+    let request: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+    assert_eq!(client.write(&request), Ok(request.len()));
+    settle(&lo);
+    let entry = socket_entry(Protocol::Tcp, server.local_addr(), Some(server.peer_addr())).unwrap();
+    assert_eq!(entry.receive_queue, request.len());
+    let mut received = vec![0u8; 8192];
+    assert_eq!(server.read(&mut received), Ok(request.len()));
+    assert_eq!(&received[..request.len()], &request[..]);
+    assert!(matches!(server.read(&mut received), Err(TcpError::WouldBlock)));
+    server.write(b"response").unwrap();
+    settle(&lo);
+    assert_eq!(client.read(&mut received), Ok(8));
+    assert_eq!(&received[..8], b"response");
+
+    // The client closes first and ends in TIME_WAIT; the server sees end of stream
+    client.close();
+    settle(&lo);
+    assert_eq!(client.state(), TcpState::FinWait2);
+    assert_eq!(server.state(), TcpState::CloseWait);
+    let entry = socket_entry(Protocol::Tcp, client.local_addr(), Some(client.peer_addr())).unwrap();
+    assert_eq!(entry.state, "FIN_WAIT2");
+    assert_eq!(server.read(&mut received), Ok(0));
+    server.close();
+    settle(&lo);
+    assert_eq!(server.state(), TcpState::Closed);
+    assert_eq!(client.state(), TcpState::TimeWait);
+    assert!(matches!(client.write(b"late"), Err(TcpError::NotConnected)));
+
+    // Nothing listens on this port: the connection is refused with a reset
+    let refused = TcpStream::connect(SocketAddrV4::new(address, 8081)).unwrap();
+    settle(&lo);
+    assert_eq!(refused.state(), TcpState::Closed);
+    assert_eq!(refused.read(&mut received), Err(TcpError::ConnectionRefused));
+
+    // A closed connection leaves the socket table with its last handle
+    let (local, remote) = (refused.local_addr(), refused.peer_addr());
+    assert_eq!(socket_entry(Protocol::Tcp, local, Some(remote)).unwrap().state, "CLOSE");
+    drop(refused);
+    assert!(socket_entry(Protocol::Tcp, local, Some(remote)).is_none());
+}
+
+// An ARP packet between the stack (A) and the test acting as a host (B)
+fn arp(operation: u16, sender: ([u8; 6], Ipv4Addr), target: ([u8; 6], Ipv4Addr)) -> Vec<u8> {
+    let mut packet = vec![0, 1, 0x08, 0x00, 6, 4];
+    packet.extend_from_slice(&operation.to_be_bytes());
+    packet.extend_from_slice(&sender.0);
+    packet.extend_from_slice(&sender.1.octets());
+    packet.extend_from_slice(&target.0);
+    packet.extend_from_slice(&target.1.octets());
+    packet
+}
+
+fn ethernet(destination: [u8; 6], source: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = destination.to_vec();
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn test_ip_over_pipe() {
+    let (ours, theirs) = (Ipv4Addr::new(10, 3, 0, 1), Ipv4Addr::new(10, 3, 0, 2));
+    let (a, b) = PipeInterface::pair(MAC_A, MAC_B);
+    interface::add_interface("pipe-ip", a.clone()).unwrap();
+    ip::configure("pipe-ip", ours, 24).unwrap();
+    assert_eq!(ip::address("pipe-ip"), Some(ours));
+    assert_eq!(ip::source_for(theirs), Ok(ours));
+    assert!(ip::source_for(Ipv4Addr::new(192, 0, 2, 1)).is_err());
+
+    // The first datagram waits for the neighbour's address to be resolved
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    socket.send_to(b"ping", SocketAddrV4::new(theirs, 7)).unwrap();
+    let request = b.receive().unwrap();
+    assert_eq!(request[..12], [[0xFF; 6], MAC_A].concat());
+    assert_eq!(request[ETHERNET_HEADER_LEN..], arp(1, (MAC_A, ours), ([0; 6], theirs)));
+    assert!(b.receive().is_none());
+
+    b.send(&ethernet(MAC_A, MAC_B, 0x0806, &arp(2, (MAC_B, theirs), (MAC_A, ours)))).unwrap();
+    a.poll();
+    let frame = b.receive().unwrap();
+    assert_eq!(frame[..14], [&MAC_B[..], &MAC_A[..], &[0x08, 0x00]].concat());
+    let (header, datagram) = Ipv4Header::parse(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!((header.source, header.destination, header.protocol), (ours, theirs, PROTOCOL_UDP));
+    assert_eq!(ip::transport_checksum(ours, theirs, PROTOCOL_UDP, datagram), 0);
+    assert_eq!(&datagram[2..4], &7u16.to_be_bytes());
+    assert_eq!(&datagram[8..], b"ping");
+
+    // Answer from port 7; the copy with a broken checksum is dropped
+    let port = socket.local_addr().port();
+    let mut reply = [&7u16.to_be_bytes()[..], &port.to_be_bytes(), &[0, 12, 0, 0], b"pong"].concat();
+    let checksum = ip::transport_checksum(theirs, ours, PROTOCOL_UDP, &reply);
+    reply[6..8].copy_from_slice(&checksum.to_be_bytes());
+    let header = Ipv4Header { source: theirs, destination: ours, protocol: PROTOCOL_UDP, ttl: 64, id: 1 };
+    let mut corrupted = reply.clone();
+    corrupted[8] ^= 0xFF;
+    b.send(&ethernet(MAC_A, MAC_B, 0x0800, &header.packet(&corrupted))).unwrap();
+    b.send(&ethernet(MAC_A, MAC_B, 0x0800, &header.packet(&reply))).unwrap();
+    a.poll();
+    assert_eq!(socket.recv_from(), Some((b"pong".to_vec(), SocketAddrV4::new(theirs, 7))));
+    assert!(socket.recv_from().is_none());
+
+    // The neighbour is known now, so the next datagram goes out directly
+    socket.send_to(b"again", SocketAddrV4::new(theirs, 7)).unwrap();
+    assert_eq!(b.receive().map(|frame| frame[12..14].to_vec()), Some(vec![0x08, 0x00]));
+}
+
+// A TCP segment from the test acting as a host, with its checksum filled in
+fn tcp(addresses: (Ipv4Addr, Ipv4Addr), ports: (u16, u16), seq: u32, ack: u32, flags: u8, options: &[u8]) -> Vec<u8> {
+    let mut segment = [ports.0.to_be_bytes(), ports.1.to_be_bytes()].concat();
+    segment.extend_from_slice(&[seq.to_be_bytes(), ack.to_be_bytes()].concat());
+    segment.extend_from_slice(&[(((20 + options.len()) / 4) << 4) as u8, flags, 0xFF, 0xFF, 0, 0, 0, 0]);
+    segment.extend_from_slice(options);
+    let checksum = ip::transport_checksum(addresses.0, addresses.1, PROTOCOL_TCP, &segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+#[test]
+fn test_tcp_zero_mss() {
+    let (ours, theirs) = (Ipv4Addr::new(10, 4, 0, 1), Ipv4Addr::new(10, 4, 0, 2));
+    let (a, b) = PipeInterface::pair(MAC_A, MAC_B);
+    interface::add_interface("pipe-mss", a.clone()).unwrap();
+    ip::configure("pipe-mss", ours, 24).unwrap();
+    let listener = TcpListener::bind(SocketAddrV4::new(ours, 9000)).unwrap();
+
+    // Introduce the host, so the stack can answer without waiting for ARP
+    b.send(&ethernet(MAC_A, MAC_B, 0x0806, &arp(1, (MAC_B, theirs), ([0; 6], ours)))).unwrap();
+    a.poll();
+    assert_eq!(b.receive().map(|frame| frame[12..14].to_vec()), Some(vec![0x08, 0x06]));
+
+    // The peer announces a segment size of 0 in its SYN
+    let header = Ipv4Header { source: theirs, destination: ours, protocol: PROTOCOL_TCP, ttl: 64, id: 1 };
+    let syn = tcp((theirs, ours), (4000, 9000), 100, 0, 0x02, &[2, 4, 0, 0]);
+    b.send(&ethernet(MAC_A, MAC_B, 0x0800, &header.packet(&syn))).unwrap();
+    a.poll();
+    let frame = b.receive().unwrap();
+    let (_, syn_ack) = Ipv4Header::parse(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(syn_ack[13], 0x12);
+    let seq = u32::from_be_bytes([syn_ack[4], syn_ack[5], syn_ack[6], syn_ack[7]]);
+    let ack = tcp((theirs, ours), (4000, 9000), 101, seq.wrapping_add(1), 0x10, &[]);
+    b.send(&ethernet(MAC_A, MAC_B, 0x0800, &header.packet(&ack))).unwrap();
+    a.poll();
+
+    // Data still goes out, in segments of the default size
+    let server = listener.accept().unwrap();
+    let data = vec![7u8; 1000];
+    assert_eq!(server.write(&data), Ok(data.len()));
+    let frame = b.receive().unwrap();
+    let (_, segment) = Ipv4Header::parse(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+    let header_len = (segment[12] >> 4) as usize * 4;
+    assert_eq!(segment[header_len..], data[..536]);
+}
+
+// Ask the host end of a TAP device for its Ethernet address with ARP and wait for the
+// answer. Needs a TAP device set up as in the README; the test is skipped unless
+// TAP_DEVICE names it. TAP_HOST is the host's address on it, 10.0.2.1 by default.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_tap_roundtrip() {
+    use crate::net::hosted::TapInterface;
+    use std::time::{Duration, Instant};
+
+    let Ok(name) = std::env::var("TAP_DEVICE") else {
+        eprintln!("TAP_DEVICE is not set; skipping the TAP test");
+        return;
+    };
+    let host: Ipv4Addr = std::env::var("TAP_HOST").map_or(Ipv4Addr::new(10, 0, 2, 1), |host| host.parse().unwrap());
+    // The stack takes .15 on the host's subnet, as under QEMU's user networking
+    let [a, b, c, _] = host.octets();
+    let ours = Ipv4Addr::new(a, b, c, 15);
+    let tap = TapInterface::open(&name, MAC_A).unwrap();
+    assert_eq!(tap.mac(), MAC_A);
+
+    tap.send(&ethernet([0xFF; 6], MAC_A, 0x0806, &arp(1, (MAC_A, ours), ([0; 6], host)))).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let reply = loop {
+        // The host may send other traffic, such as IPv6 router solicitations
+        match tap.receive() {
+            Some(frame) if frame[12..14] == [0x08, 0x06] && frame[20..22] == [0, 2] => break frame,
+            Some(_) => continue,
+            None if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            None => panic!("no ARP reply from {} on {}", host, name),
+        }
+    };
+    assert_eq!(reply[..6], MAC_A);
+    assert_eq!(reply[ETHERNET_HEADER_LEN + 14..ETHERNET_HEADER_LEN + 18], host.octets());
+    assert_eq!(reply[ETHERNET_HEADER_LEN + 24..], ours.octets());
+    assert_eq!(tap.stats().tx_packets, 1);
+}
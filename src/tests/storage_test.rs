@@ -0,0 +1,76 @@
+use crate::drivers::storage::{check_transfer, decode_status, AtaError, CommandError, IdentifyData};
+use std::path::PathBuf;
+
+// The IDENTIFY DEVICE block QEMU returns for a 64 MiB raw image on the IDE controller
+// (`-drive file=disk.img,format=raw,if=ide`). Inside a Linux guest started the same
+// way, `hdparm --Istdout /dev/sda` prints it as hex words.
+fn qemu_identify() -> [u16; 256] {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/storage_fixtures/qemu-harddisk.identify");
+    let bytes = std::fs::read(path).unwrap();
+    let mut words = [0u16; 256];
+    for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+        *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    words
+}
+
+#[test]
+fn test_ata_identify() {
+    let info = IdentifyData::parse(&qemu_identify());
+    assert_eq!(info.model, "QEMU HARDDISK");
+    assert_eq!(info.serial, "QM00001");
+    assert_eq!(info.firmware, "2.5+");
+    assert!(info.lba48);
+    assert!(info.flush_cache_ext);
+    assert_eq!(info.sectors, 64 * 1024 * 1024 / 512);
+    assert!(!info.ncq);
+    assert_eq!(info.queue_depth, 1);
+
+    // Without LBA48 the size comes from the 28-bit count in words 60-61
+    let mut words = qemu_identify();
+    words[83] &= !(1 << 10);
+    words[100] = 0xFFFF;
+    words[60] = 0x1000;
+    words[61] = 0x0001;
+    let info = IdentifyData::parse(&words);
+    assert!(!info.lba48);
+    assert_eq!(info.sectors, 0x1_1000);
+
+    // An NCQ drive reports its queue depth minus one
+    words[76] |= 1 << 8;
+    words[75] = 31;
+    let info = IdentifyData::parse(&words);
+    assert!(info.ncq);
+    assert_eq!(info.queue_depth, 32);
+}
+
+#[test]
+fn test_ata_check_transfer() {
+    assert_eq!(check_transfer(0, 512, false), Ok(1));
+    assert_eq!(check_transfer(0, 256 * 512, false), Ok(256));
+    assert_eq!(check_transfer(0, 0, false), Err(AtaError::InvalidBuffer));
+    assert_eq!(check_transfer(0, 513, false), Err(AtaError::InvalidBuffer));
+    assert_eq!(check_transfer(0, 257 * 512, false), Err(AtaError::InvalidBuffer));
+    assert_eq!(check_transfer(0, 257 * 512, true), Ok(257));
+    assert_eq!(check_transfer(0, 65537 * 512, true), Err(AtaError::InvalidBuffer));
+
+    // LBA28 addresses end at 2^28 sectors, LBA48 ones at 2^48
+    assert_eq!(check_transfer((1 << 28) - 1, 512, false), Ok(1));
+    assert_eq!(check_transfer((1 << 28) - 1, 1024, false), Err(AtaError::OutOfRange));
+    assert_eq!(check_transfer(1 << 28, 1024, true), Ok(2));
+    assert_eq!(check_transfer((1 << 48) - 1, 1024, true), Err(AtaError::OutOfRange));
+}
+
+#[test]
+fn test_ata_errors() {
+    assert_eq!(decode_status(0x50, || unreachable!()), Ok(()));
+    assert_eq!(decode_status(0x70, || unreachable!()), Err(AtaError::DeviceFault));
+    assert_eq!(decode_status(0x51, || 0x04), Err(AtaError::Command(CommandError::Aborted)));
+    assert_eq!(decode_status(0x51, || 0x10), Err(AtaError::Command(CommandError::IdNotFound)));
+
+    // The most specific bit wins when the drive sets several
+    assert_eq!(CommandError::from_register(0x44), CommandError::Uncorrectable);
+    assert_eq!(CommandError::from_register(0x84), CommandError::BadBlock);
+    assert_eq!(CommandError::from_register(0x0C), CommandError::MediaChangeRequest);
+    assert_eq!(CommandError::from_register(0x00), CommandError::Unknown(0));
+}